@@ -0,0 +1,31 @@
+//! Throw arbitrary bytes at the GDB packet decoder.  Run with
+//! `cargo fuzz run rsp_decode` from the `usb` directory.
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+// Pull the module in directly rather than building the whole adapter
+#[path = "../../src/rsp.rs"]
+#[allow(dead_code)]
+mod rsp;
+
+use rsp::{Decoder, Event};
+
+fuzz_target!(|data: &[u8]| {
+    let mut decoder = Decoder::default();
+    for &byte in data {
+        if let Some(Event::Packet(payload)) = decoder.push(byte) {
+            assert!(payload.len() <= rsp::MAX_PACKET_SIZE);
+            // Whatever got through must survive being sent back
+            let mut echo = Decoder::default();
+            let events: Vec<Event> = rsp::frame(&payload)
+                .iter()
+                .filter_map(|&b| echo.push(b))
+                .collect();
+            assert_eq!(events, vec![Event::Packet(payload.clone())]);
+            let escaped = rsp::escape(&payload);
+            assert_eq!(rsp::rle_decode(&rsp::rle_encode(&escaped)), escaped);
+            assert_eq!(rsp::unescape(&rsp::escape(&payload)), payload);
+        }
+    }
+});
@@ -0,0 +1,643 @@
+//! The `litex-usb-wishbone-bridge` command: parse the arguments, connect
+//! to the target, then run whichever server or one-off operation was
+//! asked for.
+
+#[cfg(all(feature = "usbfs", target_os = "linux"))]
+use super::usbfs_bridge;
+use super::{
+    bridge, capabilities, cli, config, csr_map, events, framebuffer, history, image, lock, logging,
+    memory, mmu, power, riscv, steptrace, trace, ui,
+};
+#[cfg(feature = "server")]
+use super::{
+    gdb, gdbinit, http, kernel, proxy, sanity, session, target, terminal, transport, wishbone,
+};
+
+use bridge::{Bridge, BridgeKind};
+use config::{Config, Profile};
+use csr_map::CsrMap;
+use events::TargetEvent;
+use framebuffer::Framebuffer;
+use image::{ImageHash, Manifest};
+#[cfg(feature = "server")]
+use kernel::KernelSymbols;
+use memory::{LoadState, MemoryWriter};
+use power::PowerSwitch;
+
+use rand::prelude::*;
+use riscv::RiscvCpu;
+#[cfg(feature = "server")]
+use session::Session;
+#[cfg(feature = "server")]
+use target::{Clients, TargetState};
+use ui::Event;
+
+#[cfg(feature = "server")]
+use std::thread;
+#[cfg(any(feature = "usb", feature = "server"))]
+use std::time::Duration;
+
+#[cfg(feature = "usb")]
+fn list_usb() -> Result<(), libusb::Error> {
+    let usb_ctx = libusb::Context::new().unwrap();
+    let devices = usb_ctx.devices().unwrap();
+    ui_result!("Devices:");
+    for device in devices.iter() {
+        let device_desc = device.device_descriptor().unwrap();
+        let mut line = format!(
+            "[{:04x}:{:04x}] - ",
+            device_desc.vendor_id(),
+            device_desc.product_id()
+        );
+        if let Ok(usb) = device.open() {
+            if let Ok(langs) = usb.read_languages(Duration::from_secs(1)) {
+                let product =
+                    match usb.read_product_string(langs[0], &device_desc, Duration::from_secs(1)) {
+                        Ok(s) => s,
+                        Err(_) => "(unknown product)".to_owned(),
+                    };
+                let manufacturer = match usb.read_manufacturer_string(
+                    langs[0],
+                    &device_desc,
+                    Duration::from_secs(1),
+                ) {
+                    Ok(s) => s,
+                    Err(_) => "(unknown manufacturer)".to_owned(),
+                };
+                line.push_str(&format!("{} - {}", product, manufacturer));
+            } else {
+                line.push_str("(no strings found)");
+            }
+        } else {
+            line.push_str("(couldn't open device)");
+        }
+        ui_result!("    {}", line);
+    }
+    Ok(())
+}
+
+fn read_manifest_key(cfg: &Config) -> Option<Vec<u8>> {
+    let path = cfg.manifest_key.as_ref()?;
+    match std::fs::read(path) {
+        Ok(key) => Some(key),
+        Err(e) => {
+            ui_error!("Couldn't read manifest key {}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// List USB devices with the backend that was asked for, or the first one
+/// this build has
+fn list_devices(backend: Option<&str>) {
+    #[cfg(feature = "usb")]
+    {
+        if backend != Some("usbfs") {
+            if list_usb().is_err() {
+                ui_error!("USB is not properly configured");
+            }
+            return;
+        }
+    }
+    #[cfg(all(feature = "usbfs", target_os = "linux"))]
+    {
+        if backend != Some("libusb") {
+            list_usbfs();
+            return;
+        }
+    }
+    ui_error!(
+        "This build doesn't include {} support",
+        backend.unwrap_or("USB")
+    );
+}
+
+#[cfg(all(feature = "usbfs", target_os = "linux"))]
+fn list_usbfs() {
+    let devices = match usbfs_bridge::list_devices() {
+        Ok(devices) => devices,
+        Err(e) => {
+            ui_error!("Couldn't list USB devices: {}", e);
+            return;
+        }
+    };
+    ui_result!("Devices:");
+    for device in devices {
+        ui_result!(
+            "    [{:04x}:{:04x}] - {} - {}",
+            device.vid,
+            device.pid,
+            device.product.as_deref().unwrap_or("(unknown product)"),
+            device
+                .manufacturer
+                .as_deref()
+                .unwrap_or("(unknown manufacturer)")
+        );
+    }
+}
+
+fn load_file(cfg: &Config, bridge: &Bridge, path: &str, addr: u32) {
+    let data = match std::fs::read(path) {
+        Ok(d) => d,
+        Err(e) => {
+            ui_error!("Couldn't read {}: {}", path, e);
+            return;
+        }
+    };
+    if let Some(ref hash) = cfg.load_hash {
+        if let Err(e) = hash.verify(&data) {
+            ui_error!("Not loading {}: {}", path, e);
+            return;
+        }
+    }
+    let manifest = match (cfg.manifest_address, read_manifest_key(cfg)) {
+        (Some(manifest_addr), Some(key)) => {
+            Some((manifest_addr, Manifest::new(addr, &data).to_bytes(&key)))
+        }
+        (Some(_), None) => return,
+        (None, _) => None,
+    };
+
+    let mut state = LoadState::new(addr, &data);
+    let start = match cfg.load_state {
+        Some(ref path) => match LoadState::load(path) {
+            Ok(saved) => saved
+                .and_then(|saved| saved.resume_offset(addr, &data))
+                .unwrap_or(0),
+            Err(e) => {
+                ui_error!("Couldn't read {}: {}", path, e);
+                return;
+            }
+        },
+        None => 0,
+    };
+    if start > 0 {
+        ui_info!(
+            "Carrying on from {:08x}, {} of {} bytes already written",
+            addr.wrapping_add(start as u32),
+            start,
+            data.len()
+        );
+    }
+    let mut checkpoint = |done: usize| {
+        state.done = done as u32;
+        events::emit(TargetEvent::FlashProgress {
+            done: done as u32,
+            total: data.len() as u32,
+        });
+        if let Some(ref path) = cfg.load_state {
+            if let Err(e) = state.save(path) {
+                ui_error!("Couldn't save the load state to {}: {}", path, e);
+            }
+        }
+    };
+
+    checkpoint(start);
+
+    let mut writer = MemoryWriter::new(cfg);
+    if let Err(e) = writer
+        .write_chunked(bridge, addr, &data, start, &mut checkpoint)
+        .and_then(|_| match manifest {
+            Some((manifest_addr, ref bytes)) => writer.write(bridge, manifest_addr, bytes),
+            None => Ok(()),
+        })
+        .and_then(|_| writer.flush(bridge))
+    {
+        ui_error!("Load failed: {}", e);
+        history::dump(&format!("load failed: {}", e));
+        if let Some(ref path) = cfg.load_state {
+            ui_info!("Run the same command again to carry on from where it stopped, which is saved in {}", path);
+        }
+        return;
+    }
+    if let Some(ref path) = cfg.load_state {
+        let _ = std::fs::remove_file(path);
+    }
+    if let Some(ref hash) = cfg.load_hash {
+        if let Err(e) = verify_loaded(bridge, hash, addr, data.len() as u32) {
+            ui_error!("Load failed verification: {}", e);
+            return;
+        }
+        ui_info!("Verified {}", hash);
+    }
+    if let Some((manifest_addr, _)) = manifest {
+        ui_info!("Wrote manifest to {:08x}", manifest_addr);
+    }
+    match writer.flash_sectors_written() {
+        0 => ui_info!("Wrote {} bytes to {:08x}", data.len(), addr),
+        sectors => ui_event!(
+            Event::FlashComplete,
+            "Wrote {} bytes to {:08x}, programming {} flash sectors",
+            data.len(),
+            addr,
+            sectors
+        ),
+    }
+}
+
+/// Read back what was just loaded and check it hashes the same as the file
+fn verify_loaded(
+    bridge: &Bridge,
+    hash: &ImageHash,
+    addr: u32,
+    len: u32,
+) -> Result<(), image::ImageError> {
+    let loaded = mmu::read_physical(bridge, addr, len)?;
+    hash.verify(&loaded)
+}
+
+fn save_framebuffer(fb: &Framebuffer, bridge: &Bridge, path: &str) {
+    let png = match fb.grab_png(bridge) {
+        Ok(png) => png,
+        Err(e) => {
+            ui_error!("Couldn't read the {}: {:?}", fb, e);
+            return;
+        }
+    };
+    match std::fs::write(path, png) {
+        Ok(()) => ui_info!("Saved the {} to {}", fb, path),
+        Err(e) => ui_error!("Couldn't write {}: {}", path, e),
+    }
+}
+
+fn verify_manifest(cfg: &Config, bridge: &Bridge, addr: u32) {
+    let key = match read_manifest_key(cfg) {
+        Some(key) => key,
+        None => return,
+    };
+    let result = Manifest::read(bridge, addr, &key).and_then(|manifest| {
+        manifest.verify(bridge)?;
+        Ok(manifest)
+    });
+    match result {
+        Ok(manifest) => ui_result!(
+            "Image at {:08x} ({} bytes) matches the manifest at {:08x}",
+            manifest.addr,
+            manifest.len,
+            addr
+        ),
+        Err(e) => ui_error!("Manifest at {:08x} doesn't check out: {}", addr, e),
+    }
+}
+
+fn compare_csr(old_path: &str, new_path: &str) {
+    let load = |path: &str| match CsrMap::load(path) {
+        Ok(map) => Some(map),
+        Err(e) => {
+            ui_error!("Couldn't load {}: {}", path, e);
+            None
+        }
+    };
+    if let (Some(old), Some(new)) = (load(old_path), load(new_path)) {
+        let changes = csr_map::diff(&old, &new);
+        for change in &changes {
+            ui_result!("{}", change);
+        }
+        if changes.is_empty() {
+            ui_info!("No differences");
+        }
+    }
+}
+
+fn compare_steps(old_path: &str, new_path: &str) {
+    let load = |path: &str| match steptrace::Trace::load(path) {
+        Ok(trace) => Some(trace),
+        Err(e) => {
+            ui_error!("Couldn't load {}: {}", path, e);
+            None
+        }
+    };
+    if let (Some(old), Some(new)) = (load(old_path), load(new_path)) {
+        if old.hart != new.hart {
+            ui_error!(
+                "Warning: the old run stepped hart {} and the new one hart {}",
+                old.hart,
+                new.hart
+            );
+        }
+        ui_result!("{}", steptrace::compare(&old, &new));
+    }
+}
+
+/// Write a GDB command file for this SoC that connects to `listener`
+#[cfg(feature = "server")]
+fn emit_gdbinit(cfg: &Config, listener: &transport::GdbListener, path: &str) {
+    let csr_map = match cfg.csr_csv {
+        Some(ref csv) => match CsrMap::load(csv) {
+            Ok(map) => Some(map),
+            Err(e) => {
+                ui_error!("Couldn't load {}: {}", csv, e);
+                None
+            }
+        },
+        None => None,
+    };
+    let target = match listener.target() {
+        Ok(target) => Some(target),
+        Err(e) => {
+            ui_error!("Couldn't tell where GDB should connect: {}", e);
+            None
+        }
+    };
+    let text = gdbinit::generate(&cfg.memory_map, csr_map.as_ref(), target.as_deref());
+    match std::fs::write(path, text) {
+        Ok(()) => ui_info!("GDB can load the SoC's settings with \"gdb -x {}\"", path),
+        Err(e) => ui_error!("Couldn't write {}: {}", path, e),
+    }
+}
+
+/// Make sure the csr.csv we were given is for the bitstream that's
+/// actually running, since debugging with a stale map is confusing
+fn check_csr_csv(bridge: &Bridge, path: &str) {
+    let map = match CsrMap::load(path) {
+        Ok(map) => map,
+        Err(e) => {
+            ui_error!("Couldn't load {}: {}", path, e);
+            return;
+        }
+    };
+    match map.check_identifier(bridge) {
+        Ok(Some(warning)) => ui_error!("Warning: {}", warning),
+        Ok(None) => (),
+        Err(e) => ui_error!("Couldn't read the identifier ROM: {:?}", e),
+    }
+}
+
+/// Check the target is fit for the server that's about to start, and
+/// exit if it isn't, unless `--force` says to carry on.  Returns `false`
+/// without checking anything if no server is being started.
+#[cfg(feature = "server")]
+fn startup_checks(cfg: &Config, cpu: &RiscvCpu, bridge: &Bridge) -> bool {
+    let check_cpu = match cfg.bridge_kind {
+        BridgeKind::GDB | BridgeKind::Http => true,
+        BridgeKind::Wishbone | BridgeKind::Terminal => false,
+        _ => return false,
+    };
+    let report = sanity::run(cfg, cpu, bridge, check_cpu);
+    report.print();
+    if !report.passed() {
+        if !cfg.force {
+            ui_error!(
+                "Not starting the server.  Fix the problems above, or start it anyway with --force"
+            );
+            std::process::exit(1);
+        }
+        ui_error!("Starting anyway, since --force was given");
+    }
+    true
+}
+
+#[cfg(not(feature = "server"))]
+fn startup_checks(_cfg: &Config, _cpu: &RiscvCpu, _bridge: &Bridge) -> bool {
+    false
+}
+
+/// Load a session saved by `monitor save-session`.  A session that can't
+/// be used is reported and otherwise ignored.
+#[cfg(feature = "server")]
+fn restore_session(cpu: &RiscvCpu, bridge: &Bridge, path: &str) -> Option<Session> {
+    let mut session = match Session::load(path) {
+        Ok(session) => session,
+        Err(e) => {
+            ui_error!("Couldn't restore session from {}: {}", path, e);
+            return None;
+        }
+    };
+    if session.hart >= cpu.hart_count() {
+        ui_error!("Saved hart {} doesn't exist, using hart 0", session.hart);
+        session.hart = 0;
+    }
+    if !session.catch_causes.is_empty() {
+        if let Err(e) = cpu.set_trap_catching(bridge, true) {
+            ui_error!("Couldn't set up the trap breakpoint: {:?}", e);
+        }
+    }
+    ui_info!(
+        "Restored session from {} with {} breakpoints",
+        path,
+        session.breakpoints.len()
+    );
+    Some(session)
+}
+
+/// Run the adapter with the process's arguments
+pub fn run() {
+    let matches = cli::app().get_matches();
+
+    if matches.is_present("list") {
+        list_devices(matches.value_of("usb-backend"));
+        return;
+    }
+
+    let show_capabilities = matches.is_present("capabilities");
+
+    let cfg = Config::parse(matches).unwrap();
+    ui::init(&cfg);
+    logging::init(&cfg).unwrap();
+    if let Err(e) = trace::init(&cfg) {
+        ui_error!("Couldn't start the bus trace: {}", e);
+        return;
+    }
+    lock::init(&cfg);
+    history::init(&cfg);
+    if let Err(e) = events::init(&cfg) {
+        ui_error!("Couldn't start the event stream: {}", e);
+        return;
+    }
+    if cfg.profile != Profile::Balanced {
+        ui_info!("Using the {} profile", cfg.profile.name());
+    }
+    for region in &cfg.memory_map {
+        ui_info!("{}", region);
+    }
+    if let Some(flash) = cfg.flash {
+        ui_info!("{}", flash);
+    }
+    if let Some((ref old, ref new)) = cfg.compare_csr {
+        compare_csr(old, new);
+        return;
+    }
+    if let Some((ref old, ref new)) = cfg.compare_steps {
+        compare_steps(old, new);
+        return;
+    }
+    let cpu = RiscvCpu::new(&cfg).unwrap();
+
+    if let (true, Some(spec)) = (cfg.power_cycle_on_start, &cfg.power) {
+        ui_info!("Cycling power with {}", spec);
+        if let Err(e) = PowerSwitch::new(spec).cycle() {
+            ui_error!("Couldn't cycle power: {}", e);
+            return;
+        }
+    }
+
+    let bridge = Bridge::new(&cfg).unwrap();
+    bridge.connect().unwrap();
+    if show_capabilities {
+        ui_result!("{}", capabilities::report(&cpu, &bridge));
+        return;
+    }
+    if !startup_checks(&cfg, &cpu, &bridge) {
+        if let Some(ref path) = cfg.csr_csv {
+            check_csr_csv(&bridge, path);
+        }
+    }
+
+    #[cfg(feature = "server")]
+    let mut session = cfg
+        .restore_session
+        .as_ref()
+        .and_then(|path| restore_session(&cpu, &bridge, path));
+
+    #[cfg(feature = "server")]
+    let kernel = match cfg.kernel_symbols {
+        Some(ref path) => match KernelSymbols::load(path) {
+            Ok(symbols) => Some(symbols),
+            Err(e) => {
+                ui_error!("Couldn't load kernel symbols from {}: {}", path, e);
+                return;
+            }
+        },
+        None => None,
+    };
+
+    #[cfg(feature = "server")]
+    let target = TargetState::new_shared();
+
+    // A server started with --load serves the image once it's in place
+    match (&cfg.load_file, cfg.memory_address) {
+        (Some(path), Some(addr)) => load_file(&cfg, &bridge, path, addr),
+        (Some(_), None) => {
+            ui_error!("--load needs the address to load to");
+            return;
+        }
+        _ => (),
+    }
+
+    match cfg.bridge_kind {
+        #[cfg(feature = "server")]
+        BridgeKind::GDB => {
+            let listener = transport::GdbListener::new(&cfg).unwrap();
+            if let Some(ref path) = cfg.emit_gdbinit {
+                emit_gdbinit(&cfg, &listener, path);
+            }
+            if let Some(ref upstream) = cfg.gdb_proxy {
+                let mut proxy = proxy::GdbProxy::new(&cfg, upstream);
+                loop {
+                    if let Err(e) = proxy.serve(&listener, &bridge) {
+                        ui_error!("GDB proxy: {}", e);
+                    }
+                    ui_event!(Event::Detach, "GDB disconnected");
+                }
+            }
+            // One GDB in control and any number watching, for as long as
+            // the adapter runs
+            let clients = Clients::new_shared();
+            thread::scope(|scope| loop {
+                let mut gdb = match gdb::GdbServer::new(
+                    &cfg,
+                    &listener,
+                    target.clone(),
+                    &clients,
+                    &mut session,
+                    kernel.as_ref(),
+                ) {
+                    Ok(gdb) => gdb,
+                    Err(e) => {
+                        ui_error!("Couldn't accept a GDB connection: {:?}", e);
+                        thread::sleep(Duration::from_secs(1));
+                        continue;
+                    }
+                };
+                let (cpu, bridge) = (&cpu, &bridge);
+                let client = scope.spawn(move || {
+                    if let Err(e) = gdb.install_breakpoints(cpu, bridge) {
+                        ui_error!("Couldn't restore the session's breakpoints: {:?}", e);
+                    }
+                    loop {
+                        if let Err(e) = gdb.process(cpu, bridge) {
+                            log_adapter!("Error in GDB server: {:?}", e);
+                            if e.is_bridge_failure() {
+                                history::dump(&format!("GDB session ended by {:?}", e));
+                            }
+                            gdb.end_session(cpu, bridge, &e);
+                            ui_event!(Event::Detach, "GDB disconnected");
+                            break;
+                        }
+                    }
+                });
+                // A pty has room for one GDB, so wait for it to finish
+                if !listener.is_shared() {
+                    let _ = client.join();
+                }
+            });
+        }
+        #[cfg(feature = "server")]
+        BridgeKind::Wishbone => {
+            let result = wishbone::EthServer::new(&cfg).and_then(|s| s.serve(&bridge));
+            if let Err(e) = result {
+                ui_error!("Etherbone server error: {}", e);
+            }
+        }
+        #[cfg(feature = "server")]
+        BridgeKind::Http => {
+            let mut http = match http::HttpServer::new(&cfg, target.clone()) {
+                Ok(http) => http,
+                Err(e) => {
+                    ui_error!("Couldn't start the HTTP server: {}", e);
+                    return;
+                }
+            };
+            loop {
+                if let Err(e) = http.process(&cpu, &bridge) {
+                    log_adapter!("Error in HTTP server: {}", e);
+                }
+            }
+        }
+        #[cfg(feature = "server")]
+        BridgeKind::Terminal => {
+            let result = terminal::Terminal::new(&cfg).and_then(|mut t| t.run(&bridge));
+            if let Err(e) = result {
+                ui_error!("Terminal error: {}", e);
+            }
+        }
+        BridgeKind::RandomTest => {
+            let mut loop_counter: u32 = 0;
+            loop {
+                let val = random::<u32>();
+                bridge.poke(0x10000000, val).unwrap();
+                let cmp = bridge.peek(0x10000000).unwrap();
+                if cmp != val {
+                    panic!("Loop {}: Expected {}, got {}", loop_counter, val, cmp);
+                }
+                if loop_counter.is_multiple_of(1000) {
+                    ui_info!("loop: {} ({:08x})", loop_counter, val);
+                }
+                loop_counter = loop_counter.wrapping_add(1);
+            }
+        }
+        #[cfg(not(feature = "server"))]
+        BridgeKind::GDB | BridgeKind::Wishbone | BridgeKind::Http | BridgeKind::Terminal => {
+            ui_error!("This build doesn't include the servers");
+        }
+        BridgeKind::None => {
+            if let Some(addr) = cfg.verify_manifest {
+                verify_manifest(&cfg, &bridge, addr);
+            } else if let Some(ref fb) = cfg.framebuffer {
+                save_framebuffer(fb, &bridge, &cfg.framebuffer_png);
+            } else if cfg.load_file.is_some() {
+                // Already loaded
+            } else if let Some(addr) = cfg.memory_address {
+                if let Some(value) = cfg.memory_value {
+                    bridge.poke(addr, value).unwrap();
+                } else {
+                    let val = bridge.peek(addr).unwrap();
+                    ui_result!("Value at {:08x}: {:08x}", addr, val);
+                }
+            } else {
+                ui_error!("No operation and no address specified!");
+                ui_error!("Try specifying an address such as \"0x10000000\".  See --help for more information");
+            }
+        }
+    }
+}
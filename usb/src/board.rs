@@ -0,0 +1,211 @@
+use std::fmt;
+
+/// What lives in a region of the address space, as far as GDB cares
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryKind {
+    Ram,
+    Rom,
+    Flash,
+
+    /// Peripheral registers
+    Io,
+}
+
+#[derive(Clone, Debug)]
+pub struct MemoryRegion {
+    pub name: String,
+    pub base: u32,
+    pub size: u32,
+    pub kind: MemoryKind,
+}
+
+impl MemoryRegion {
+    fn new(name: &str, base: u32, size: u32, kind: MemoryKind) -> MemoryRegion {
+        MemoryRegion {
+            name: name.to_owned(),
+            base,
+            size,
+            kind,
+        }
+    }
+}
+
+/// Layout of the SPI flash, which has to be erased a sector at a time and
+/// programmed a page at a time
+#[derive(Clone, Copy, Debug)]
+pub struct FlashGeometry {
+    /// Where the flash is mapped on the bus
+    pub base: u32,
+
+    /// Total size in bytes
+    pub size: u32,
+
+    /// Smallest erasable unit
+    pub sector_size: u32,
+
+    /// Largest programmable unit
+    pub page_size: u32,
+}
+
+/// Where the CSRs of LiteX's bit-banged `spiflash` controller are, as
+/// csr.csv gives them.  Their addresses depend on what else the SoC has,
+/// so a board can't know them.
+#[derive(Clone, Copy, Debug)]
+pub struct SpiFlashRegisters {
+    /// `spiflash_bitbang`: MOSI, CLK and CS_N in bits 0 to 2
+    pub bitbang: u32,
+
+    /// `spiflash_miso`
+    pub miso: u32,
+
+    /// `spiflash_bitbang_en`, which takes the flash away from the
+    /// memory-mapped interface while it's 1
+    pub bitbang_en: u32,
+}
+
+/// Everything needed to talk to a particular board out of the box
+#[derive(Clone, Debug)]
+pub struct Board {
+    pub usb_vid: u16,
+    pub usb_pid: u16,
+    pub debug_offset: u32,
+    pub memory_map: Vec<MemoryRegion>,
+    pub flash: Option<FlashGeometry>,
+}
+
+/// Names accepted by `--board`
+pub const BOARD_NAMES: &[&str] = &["fomu", "orangecrab", "arty-litex"];
+
+impl Board {
+    pub fn by_name(name: &str) -> Option<Board> {
+        use MemoryKind::*;
+        match name {
+            "fomu" => Some(Board {
+                usb_vid: 0x1209,
+                usb_pid: 0x5bf0,
+                debug_offset: 0xf00f_0000,
+                memory_map: vec![
+                    MemoryRegion::new("rom", 0x0000_0000, 0x0000_2000, Rom),
+                    MemoryRegion::new("sram", 0x1000_0000, 0x0002_0000, Ram),
+                    MemoryRegion::new("spiflash", 0x2000_0000, 0x0020_0000, Flash),
+                    MemoryRegion::new("csr", 0xe000_0000, 0x0001_0000, Io),
+                    MemoryRegion::new("vexriscv_debug", 0xf00f_0000, 0x0000_0100, Io),
+                ],
+                flash: Some(FlashGeometry {
+                    base: 0x2000_0000,
+                    size: 0x0020_0000,
+                    sector_size: 0x1000,
+                    page_size: 0x100,
+                }),
+            }),
+            "orangecrab" => Some(Board {
+                usb_vid: 0x1209,
+                usb_pid: 0x5af0,
+                debug_offset: 0xf00f_0000,
+                memory_map: vec![
+                    MemoryRegion::new("rom", 0x0000_0000, 0x0000_8000, Rom),
+                    MemoryRegion::new("sram", 0x1000_0000, 0x0000_2000, Ram),
+                    MemoryRegion::new("spiflash", 0x2000_0000, 0x0100_0000, Flash),
+                    MemoryRegion::new("main_ram", 0x4000_0000, 0x0800_0000, Ram),
+                    MemoryRegion::new("csr", 0xe000_0000, 0x0001_0000, Io),
+                    MemoryRegion::new("vexriscv_debug", 0xf00f_0000, 0x0000_0100, Io),
+                ],
+                flash: Some(FlashGeometry {
+                    base: 0x2000_0000,
+                    size: 0x0100_0000,
+                    sector_size: 0x1000,
+                    page_size: 0x100,
+                }),
+            }),
+            "arty-litex" => Some(Board {
+                usb_vid: 0x0403,
+                usb_pid: 0x6010,
+                debug_offset: 0xf00f_0000,
+                memory_map: vec![
+                    MemoryRegion::new("rom", 0x0000_0000, 0x0000_8000, Rom),
+                    MemoryRegion::new("sram", 0x0100_0000, 0x0000_2000, Ram),
+                    MemoryRegion::new("spiflash", 0x2000_0000, 0x0100_0000, Flash),
+                    MemoryRegion::new("main_ram", 0x4000_0000, 0x1000_0000, Ram),
+                    MemoryRegion::new("csr", 0xe000_0000, 0x0001_0000, Io),
+                    MemoryRegion::new("vexriscv_debug", 0xf00f_0000, 0x0000_0100, Io),
+                ],
+                flash: Some(FlashGeometry {
+                    base: 0x2000_0000,
+                    size: 0x0100_0000,
+                    sector_size: 0x1_0000,
+                    page_size: 0x100,
+                }),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MemoryKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryKind::Ram => write!(f, "ram"),
+            MemoryKind::Rom => write!(f, "rom"),
+            MemoryKind::Flash => write!(f, "flash"),
+            MemoryKind::Io => write!(f, "io"),
+        }
+    }
+}
+
+/// What `name` stands for in an expression, going by the names LiteX gives
+/// a region in its headers: `<region>_base` or just `<region>` for where it
+/// starts, and `<region>_size` for how big it is
+pub fn region_symbol(regions: &[MemoryRegion], name: &str) -> Option<u32> {
+    let find = |region: &str| regions.iter().find(|r| r.name == region);
+    if let Some(region) = name.strip_suffix("_size").and_then(find) {
+        return Some(region.size);
+    }
+    find(name.strip_suffix("_base").unwrap_or(name)).map(|r| r.base)
+}
+
+/// The memory map as the XML GDB reads with `qXfer:memory-map:read`.  GDB
+/// won't touch anything that isn't in the map, so peripherals are listed
+/// as RAM.  ROM and flash are read-only to GDB, which also has it use
+/// hardware breakpoints there without being asked.
+#[cfg(feature = "server")]
+pub fn memory_map_xml(regions: &[MemoryRegion]) -> String {
+    let mut xml = "<?xml version=\"1.0\"?>\n<!DOCTYPE memory-map PUBLIC \"+//IDN gnu.org//DTD GDB Memory Map V1.0//EN\" \"http://sourceware.org/gdb/gdb-memory-map.dtd\">\n<memory-map>\n".to_owned();
+    for region in regions {
+        let kind = match region.kind {
+            MemoryKind::Ram | MemoryKind::Io => "ram",
+            MemoryKind::Rom | MemoryKind::Flash => "rom",
+        };
+        xml.push_str(&format!(
+            "<memory type=\"{}\" start=\"0x{:x}\" length=\"0x{:x}\"/>\n",
+            kind, region.base, region.size
+        ));
+    }
+    xml.push_str("</memory-map>\n");
+    xml
+}
+
+impl fmt::Display for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<16} {:08x}-{:08x} {}",
+            self.name,
+            self.base,
+            self.base.wrapping_add(self.size).wrapping_sub(1),
+            self.kind
+        )
+    }
+}
+
+impl fmt::Display for FlashGeometry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "flash at {:08x}: {} KiB, {} KiB sectors, {} byte pages",
+            self.base,
+            self.size / 1024,
+            self.sector_size / 1024,
+            self.page_size
+        )
+    }
+}
@@ -0,0 +1,250 @@
+//! Choosing where the SoC boots from.  A SoC built to be told where to
+//! boot has a CSR holding the address the CPU starts at after a reset,
+//! which `--boot-register` names.  Picking a boot mode writes that mode's
+//! address there and resets the SoC with `ctrl_reset`.
+//!
+//! Flash boot starts at csr.csv's `flash_boot_address`, or the start of
+//! the flash.  Serial boot starts the BIOS in `rom`, which asks for a
+//! serialboot before anything else.  There's nowhere network boot starts
+//! on every SoC, so its address has to be given.
+
+use std::fmt;
+
+use super::board::{MemoryKind, MemoryRegion};
+use super::bridge::Bridge;
+use super::config::ConfigError;
+use super::csr_map::{CsrMap, CsrMapError};
+use super::expr;
+
+/// Writing 1 here resets the SoC
+const RESET_REGISTER: &str = "ctrl_reset";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BootMode {
+    Flash,
+    Serial,
+    Netboot,
+}
+
+impl BootMode {
+    pub const ALL: [BootMode; 3] = [BootMode::Flash, BootMode::Serial, BootMode::Netboot];
+
+    pub fn from_name(name: &str) -> Option<BootMode> {
+        BootMode::ALL.iter().copied().find(|m| m.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            BootMode::Flash => "flash",
+            BootMode::Serial => "serial",
+            BootMode::Netboot => "netboot",
+        }
+    }
+}
+
+impl fmt::Display for BootMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The register that says where to boot, given with `--boot-register`
+#[derive(Clone, Debug, PartialEq)]
+pub struct BootControl {
+    pub register: String,
+
+    /// Where each mode starts, for the modes there's an address for
+    addresses: Vec<(BootMode, u32)>,
+}
+
+impl BootControl {
+    /// Parse `NAME[:MODE=ADDR,...]`, where `NAME` is a register in
+    /// `csr_map` and each `ADDR` can be an expression using `lookup`.
+    /// Modes that aren't given an address get one from csr.csv and the
+    /// memory map if there is one.
+    pub fn parse(
+        spec: &str,
+        lookup: &dyn Fn(&str) -> Option<u64>,
+        csr_map: Option<&CsrMap>,
+        memory_map: &[MemoryRegion],
+    ) -> Result<BootControl, ConfigError> {
+        let invalid = |why: &str| ConfigError::InvalidBootRegister(format!("{}: {}", spec, why));
+        let (register, given) = match spec.split_once(':') {
+            Some((register, given)) => (register.trim(), Some(given)),
+            None => (spec.trim(), None),
+        };
+        let map = csr_map.ok_or_else(|| invalid("needs --csr-csv"))?;
+        if !map.is_writable(register) {
+            return Err(invalid("not a writable register in csr.csv"));
+        }
+        if !map.registers.contains_key(RESET_REGISTER) {
+            return Err(invalid("csr.csv has no ctrl_reset to reboot with"));
+        }
+
+        let mut addresses = vec![];
+        for address in given.into_iter().flat_map(|g| g.split(',')) {
+            let (mode, addr) = address
+                .split_once('=')
+                .and_then(|(mode, addr)| Some((BootMode::from_name(mode.trim())?, addr)))
+                .ok_or_else(|| invalid("addresses are flash=, serial= or netboot="))?;
+            addresses.push((mode, expr::eval_u32(addr, lookup)?));
+        }
+        let region = |kind: MemoryKind, name: Option<&str>| {
+            memory_map
+                .iter()
+                .find(|r| r.kind == kind && name.is_none_or(|n| r.name == n))
+                .map(|r| r.base)
+        };
+        let defaults = [
+            (
+                BootMode::Flash,
+                expr::eval_u32("flash_boot_address", lookup)
+                    .ok()
+                    .or_else(|| region(MemoryKind::Flash, None)),
+            ),
+            (BootMode::Serial, region(MemoryKind::Rom, Some("rom"))),
+        ];
+        for (mode, addr) in defaults.iter() {
+            if let (Some(addr), false) = (addr, addresses.iter().any(|(m, _)| m == mode)) {
+                addresses.push((*mode, *addr));
+            }
+        }
+        Ok(BootControl {
+            register: register.to_owned(),
+            addresses,
+        })
+    }
+
+    /// Where `mode` starts, if we know
+    pub fn address(&self, mode: BootMode) -> Option<u32> {
+        self.addresses
+            .iter()
+            .find(|(m, _)| *m == mode)
+            .map(|(_, addr)| *addr)
+    }
+
+    /// Where the SoC will boot next, and the mode that starts there if
+    /// there is one
+    pub fn current(
+        &self,
+        map: &CsrMap,
+        bridge: &Bridge,
+    ) -> Result<(u64, Option<BootMode>), CsrMapError> {
+        let addr = map.read_register(bridge, &self.register)?;
+        let mode = self
+            .addresses
+            .iter()
+            .find(|(_, a)| *a as u64 == addr)
+            .map(|(m, _)| *m);
+        Ok((addr, mode))
+    }
+
+    /// Point the CPU at `addr`, then reset the SoC so it boots from there.
+    /// The reset can take the bridge down with it before the write is
+    /// answered, so a failure there is only logged.
+    pub fn reboot(&self, map: &CsrMap, bridge: &Bridge, addr: u32) -> Result<(), CsrMapError> {
+        map.write_register(bridge, &self.register, addr as u64)?;
+        if let Err(e) = map.write_register(bridge, RESET_REGISTER, 1) {
+            log_adapter!("No answer to the reset, which is to be expected: {}", e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BootControl, BootMode};
+    use crate::bridge::Bridge;
+    use crate::config::{Config, ConfigError};
+    use crate::csr_map::CsrMap;
+
+    const CSR_CSV: &str = "\
+csr_register,ctrl_reset,0xf0000000,1,rw
+csr_register,ctrl_boot_address,0xf0000010,1,rw
+csr_register,ctrl_bus_errors,0xf0000008,1,ro
+constant,flash_boot_address,0x20100000,,
+memory_region,rom,0x00000000,0x00008000,cached
+memory_region,spiflash,0x20000000,0x01000000,cached
+memory_region,main_ram,0x40000000,0x01000000,cached
+";
+
+    fn parse(spec: &str, csv: &str) -> Result<BootControl, ConfigError> {
+        let map = CsrMap::parse(csv).unwrap();
+        let memory_map = map.memory_map();
+        BootControl::parse(spec, &|name| map.lookup(name), Some(&map), &memory_map)
+    }
+
+    #[test]
+    fn addresses() {
+        let control = parse("ctrl_boot_address", CSR_CSV).unwrap();
+        assert_eq!(control.register, "ctrl_boot_address");
+        assert_eq!(control.address(BootMode::Flash), Some(0x2010_0000));
+        assert_eq!(control.address(BootMode::Serial), Some(0));
+        assert_eq!(control.address(BootMode::Netboot), None);
+
+        let control = parse(
+            "ctrl_boot_address:netboot=main_ram_base + 0x100,flash=0x20000000",
+            CSR_CSV,
+        )
+        .unwrap();
+        assert_eq!(control.address(BootMode::Flash), Some(0x2000_0000));
+        assert_eq!(control.address(BootMode::Serial), Some(0));
+        assert_eq!(control.address(BootMode::Netboot), Some(0x4000_0100));
+
+        // Without flash_boot_address, flash boot starts at the flash
+        let csv = CSR_CSV.replace("constant,flash_boot_address,0x20100000,,\n", "");
+        let control = parse("ctrl_boot_address", &csv).unwrap();
+        assert_eq!(control.address(BootMode::Flash), Some(0x2000_0000));
+    }
+
+    #[test]
+    fn bad_specs() {
+        for spec in [
+            "ctrl_boot_mode",
+            "ctrl_bus_errors",
+            "ctrl_boot_address:jtag=0",
+            "ctrl_boot_address:netboot",
+        ] {
+            assert!(
+                matches!(
+                    parse(spec, CSR_CSV),
+                    Err(ConfigError::InvalidBootRegister(_))
+                ),
+                "{}",
+                spec
+            );
+        }
+        assert!(parse("ctrl_boot_address:netboot=nowhere", CSR_CSV).is_err());
+        let no_reset = CSR_CSV.replace("csr_register,ctrl_reset,0xf0000000,1,rw\n", "");
+        assert!(parse("ctrl_boot_address", &no_reset).is_err());
+        let memory_map = vec![];
+        assert!(BootControl::parse("ctrl_boot_address", &|_| None, None, &memory_map).is_err());
+    }
+
+    #[test]
+    fn reboots() {
+        let cfg = Config::from_args(["bootmode", "--mock"]).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        let map = CsrMap::parse(CSR_CSV).unwrap();
+        let control = parse("ctrl_boot_address", CSR_CSV).unwrap();
+
+        control.reboot(&map, &bridge, 0x2010_0000).unwrap();
+        assert_eq!(bridge.peek(0xf000_0010).unwrap(), 0x2010_0000);
+        assert_eq!(bridge.peek(0xf000_0000).unwrap(), 1);
+        assert_eq!(
+            control.current(&map, &bridge).unwrap(),
+            (0x2010_0000, Some(BootMode::Flash))
+        );
+        bridge.poke(0xf000_0010, 0x1234).unwrap();
+        assert_eq!(control.current(&map, &bridge).unwrap(), (0x1234, None));
+    }
+
+    #[test]
+    fn mode_names() {
+        for mode in BootMode::ALL.iter() {
+            assert_eq!(BootMode::from_name(mode.name()), Some(*mode));
+        }
+        assert_eq!(BootMode::from_name("jtag"), None);
+    }
+}
@@ -1,8 +1,27 @@
+use std::time::{Duration, Instant};
+
 use super::config::{Config, ConfigError};
+#[cfg(feature = "ethernet")]
+use super::etherbone_bridge::EtherboneBridge;
+use super::fault_bridge::FaultBridge;
+use super::history;
+use super::mmap_bridge::MmapBridge;
+#[cfg(test)]
+use super::mock_bridge::Device;
+use super::mock_bridge::MockBridge;
+#[cfg(feature = "serial")]
+use super::serial_bridge::SerialBridge;
+#[cfg(feature = "server")]
+use super::stats::{self, Phase};
+use super::trace::{self, Access};
+#[cfg(feature = "usb")]
 use super::usb_bridge::UsbBridge;
+#[cfg(all(feature = "usbfs", target_os = "linux"))]
+use super::usbfs_bridge::UsbfsBridge;
 
+#[allow(clippy::upper_case_acronyms)]
 pub enum BridgeKind {
-    /// Wishbone bridge
+    /// Etherbone server, for litex_server clients
     Wishbone,
 
     /// GDB server
@@ -11,12 +30,56 @@ pub enum BridgeKind {
     /// Send random data back and forth
     RandomTest,
 
+    /// HTTP server for memory, CSR and run control
+    Http,
+
+    /// Interactive console on the crossover UART
+    Terminal,
+
     /// No server
     None,
 }
 
+/// Names accepted by `--server-kind`
+#[cfg(feature = "server")]
+pub const SERVER_KINDS: &[&str] = &["gdb", "wishbone", "random-test", "http", "terminal"];
+#[cfg(not(feature = "server"))]
+pub const SERVER_KINDS: &[&str] = &["random-test"];
+
+/// What the bridge is connected to
+pub enum BridgeBackend {
+    /// A real device over USB, through libusb
+    Usb,
+
+    /// A real device over USB, through Linux's usbfs directly
+    Usbfs,
+
+    /// Simulated memory, for testing without hardware
+    Mock,
+
+    /// A shared-memory file laid out like the SoC's address space
+    Mmap,
+
+    /// A LiteX Etherbone core over UDP
+    Etherbone,
+
+    /// A LiteX `uartwishbone` core over a serial port
+    Serial,
+}
+
+#[allow(clippy::enum_variant_names)]
 pub enum Bridge {
+    #[cfg(feature = "usb")]
     UsbBridge(UsbBridge),
+    #[cfg(all(feature = "usbfs", target_os = "linux"))]
+    UsbfsBridge(UsbfsBridge),
+    MockBridge(MockBridge),
+    MmapBridge(MmapBridge),
+    #[cfg(feature = "ethernet")]
+    EtherboneBridge(EtherboneBridge),
+    #[cfg(feature = "serial")]
+    SerialBridge(SerialBridge),
+    FaultBridge(FaultBridge),
 }
 
 #[derive(Debug)]
@@ -25,29 +88,263 @@ pub enum BridgeError {
     LengthError(usize, usize),
 
     /// USB subsystem returned an error
+    #[cfg(feature = "usb")]
     USBError(libusb::Error),
 
+    /// The operating system returned an error
+    IoError(std::io::Error),
+
     /// Attempted to communicate with the bridge, but it wasn't connected
     NotConnected,
 
+    /// The host went to sleep, taking the USB bus with it, and the device
+    /// didn't come back after it woke up
+    Suspended,
+
     /// We got something weird back from the bridge
     WrongResponse,
+
+    /// The target terminated the cycle with RTY, so it may succeed if reissued
+    Retry,
+
+    /// The target terminated the cycle with ERR
+    BusError,
+
+    /// The backend was left out of this build
+    NotBuiltIn(&'static str),
+
+    /// Part of the target is in use by something else, such as the flash
+    /// while it's being programmed
+    ResourceBusy(String),
+}
+
+/// One transaction in a batch
+#[derive(Clone, Copy, Debug)]
+pub enum BatchOp {
+    Write(u32 /* addr */, u32 /* value */),
+    Read(u32 /* addr */),
 }
 
+/// A sequence of transactions to be sent in as few round trips as the
+/// backend allows.  Build one with `Bridge::batch()`:
+///
+/// ```ignore
+/// let values = bridge.batch().write(a, 1).read(b).commit()?;
+/// ```
+///
+/// Transactions are issued in order, and `commit()` returns the value of
+/// each read in the order they were added.  The batch stops at the first
+/// transaction that fails.
+///
+/// How far a batch is coalesced depends on the backend.  A serial port
+/// gets the whole batch as one write of burst frames, whose answers are
+/// read back together.  Etherbone and USB only merge runs of consecutive
+/// reads or writes, since the LiteX Etherbone core handles one record per
+/// packet and a USB control transfer carries one address.  Mock and mmap
+/// targets have no link to save trips on, and run each transaction in
+/// turn.
+pub struct Batch<'a> {
+    bridge: &'a Bridge,
+    ops: Vec<BatchOp>,
+}
+
+impl<'a> Batch<'a> {
+    pub fn write(mut self, addr: u32, value: u32) -> Self {
+        self.ops.push(BatchOp::Write(addr, value));
+        self
+    }
+
+    pub fn read(mut self, addr: u32) -> Self {
+        self.ops.push(BatchOp::Read(addr));
+        self
+    }
+
+    pub fn commit(self) -> Result<Vec<u32>, BridgeError> {
+        self.bridge.execute(&self.ops)
+    }
+}
+
+/// Run a batch one transaction at a time, for backends with nothing better
+/// to offer
+pub fn execute_each(
+    ops: &[BatchOp],
+    mut peek: impl FnMut(u32) -> Result<u32, BridgeError>,
+    mut poke: impl FnMut(u32, u32) -> Result<(), BridgeError>,
+) -> Result<Vec<u32>, BridgeError> {
+    let mut results = vec![];
+    for op in ops {
+        match *op {
+            BatchOp::Write(addr, value) => poke(addr, value)?,
+            BatchOp::Read(addr) => results.push(peek(addr)?),
+        }
+    }
+    Ok(results)
+}
+
+/// Run a batch as bursts of up to `max_words` consecutive reads or writes,
+/// for backends that can move more than one word at a time.  `read` is
+/// given the first address and a word count, and `write` the first address
+/// and the words.
+#[cfg(any(feature = "usb", all(feature = "usbfs", target_os = "linux")))]
+pub fn execute_bursts(
+    ops: &[BatchOp],
+    max_words: usize,
+    mut read: impl FnMut(u32, usize) -> Result<Vec<u32>, BridgeError>,
+    mut write: impl FnMut(u32, &[u32]) -> Result<(), BridgeError>,
+) -> Result<Vec<u32>, BridgeError> {
+    let mut results = vec![];
+    let mut idx = 0;
+    while idx < ops.len() {
+        match ops[idx] {
+            BatchOp::Write(base, _) => {
+                let mut values = vec![];
+                while let Some(&BatchOp::Write(addr, value)) = ops.get(idx) {
+                    if values.len() == max_words
+                        || addr != base.wrapping_add(4 * values.len() as u32)
+                    {
+                        break;
+                    }
+                    values.push(value);
+                    idx += 1;
+                }
+                write(base, &values)?;
+            }
+            BatchOp::Read(base) => {
+                let mut count = 0;
+                while let Some(&BatchOp::Read(addr)) = ops.get(idx) {
+                    if count == max_words || addr != base.wrapping_add(4 * count as u32) {
+                        break;
+                    }
+                    count += 1;
+                    idx += 1;
+                }
+                results.extend(read(base, count)?);
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// Whether a USB device has shown that it takes more than one word in a
+/// control transfer.  Gateware that doesn't answers a longer read with a
+/// single word, and can't be trusted with a longer write.
+#[cfg(any(feature = "usb", all(feature = "usbfs", target_os = "linux")))]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UsbBursts {
+    Unknown,
+    Supported,
+    Unsupported,
+}
+
+/// Run a batch over a USB device's debug interface, given control
+/// transfers in each direction that return how many bytes they moved.
+/// Consecutive words go in one transfer of up to `max_words` once the
+/// device has shown it can take them; until then writes go a word at a
+/// time, and the first read of more than one word finds out.
+#[cfg(any(feature = "usb", all(feature = "usbfs", target_os = "linux")))]
+pub fn execute_usb(
+    ops: &[BatchOp],
+    bursts: &mut UsbBursts,
+    max_words: usize,
+    mut control_read: impl FnMut(u32, &mut [u8]) -> Result<usize, BridgeError>,
+    mut control_write: impl FnMut(u32, &[u8]) -> Result<usize, BridgeError>,
+) -> Result<Vec<u32>, BridgeError> {
+    let state = std::cell::Cell::new(*bursts);
+    let mut read_words = |addr: u32, count: usize| -> Result<Vec<u32>, BridgeError> {
+        let mut data = vec![0; count * 4];
+        let mut got = 0;
+        if count > 1 && state.get() != UsbBursts::Unsupported {
+            match control_read(addr, &mut data) {
+                Ok(len) if len == data.len() => {
+                    got = len;
+                    state.set(UsbBursts::Supported);
+                }
+                Ok(4) if state.get() == UsbBursts::Unknown => {
+                    log_bridge!(@Verbose, "usb: device only reads a word at a time");
+                    got = 4;
+                    state.set(UsbBursts::Unsupported);
+                }
+                Ok(len) => return Err(BridgeError::LengthError(data.len(), len)),
+                // Some gateware stalls a request longer than it expects
+                // rather than answering it short
+                Err(ref e) if is_stall(e) => {
+                    log_bridge!(@Verbose, "usb: device stalled a burst, reading a word at a time");
+                    state.set(UsbBursts::Unsupported);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        while got < data.len() {
+            let word = addr.wrapping_add(got as u32);
+            match control_read(word, &mut data[got..got + 4])? {
+                4 => got += 4,
+                len => return Err(BridgeError::LengthError(4, len)),
+            }
+        }
+        Ok(data
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect())
+    };
+    let mut write_words = |addr: u32, values: &[u32]| -> Result<(), BridgeError> {
+        let data: Vec<u8> = values.iter().flat_map(|v| v.to_le_bytes()).collect();
+        let piece = if state.get() == UsbBursts::Supported {
+            data.len()
+        } else {
+            4
+        };
+        for (idx, chunk) in data.chunks(piece).enumerate() {
+            let len = control_write(addr.wrapping_add((idx * piece) as u32), chunk)?;
+            if len != chunk.len() {
+                return Err(BridgeError::LengthError(chunk.len(), len));
+            }
+        }
+        Ok(())
+    };
+    let result = execute_bursts(ops, max_words, &mut read_words, &mut write_words);
+    *bursts = state.get();
+    result
+}
+
+/// Whether a control transfer failed because the device stalled it,
+/// which is how it refuses a request it doesn't take
+#[cfg(any(feature = "usb", all(feature = "usbfs", target_os = "linux")))]
+fn is_stall(e: &BridgeError) -> bool {
+    match e {
+        #[cfg(feature = "usb")]
+        BridgeError::USBError(libusb::Error::Pipe) => true,
+        BridgeError::IoError(e) => e.raw_os_error() == Some(libc::EPIPE),
+        _ => false,
+    }
+}
+
+#[cfg(feature = "usb")]
 impl std::convert::From<libusb::Error> for BridgeError {
     fn from(e: libusb::Error) -> BridgeError {
         BridgeError::USBError(e)
     }
 }
 
+impl std::convert::From<std::io::Error> for BridgeError {
+    fn from(e: std::io::Error) -> BridgeError {
+        BridgeError::IoError(e)
+    }
+}
+
 impl BridgeKind {
     pub fn from_string(item: &Option<&str>) -> Result<BridgeKind, ConfigError> {
         match item {
             None => Ok(BridgeKind::None),
             Some(k) => match *k {
+                #[cfg(feature = "server")]
                 "gdb" => Ok(BridgeKind::GDB),
+                #[cfg(feature = "server")]
                 "wishbone" => Ok(BridgeKind::Wishbone),
                 "random-test" => Ok(BridgeKind::RandomTest),
+                #[cfg(feature = "server")]
+                "http" => Ok(BridgeKind::Http),
+                #[cfg(feature = "server")]
+                "terminal" => Ok(BridgeKind::Terminal),
                 unknown => Err(ConfigError::UnknownBridgeKind(unknown.to_owned())),
             },
         }
@@ -56,34 +353,330 @@ impl BridgeKind {
 
 impl Bridge {
     pub fn new(cfg: &Config) -> Result<Bridge, BridgeError> {
-        Ok(Bridge::UsbBridge(UsbBridge::new(cfg)?))
+        let bridge = match cfg.bridge_backend {
+            #[cfg(feature = "usb")]
+            BridgeBackend::Usb => Bridge::UsbBridge(UsbBridge::new(cfg)?),
+            #[cfg(not(feature = "usb"))]
+            BridgeBackend::Usb => return Err(BridgeError::NotBuiltIn("usb")),
+            #[cfg(all(feature = "usbfs", target_os = "linux"))]
+            BridgeBackend::Usbfs => Bridge::UsbfsBridge(UsbfsBridge::new(cfg)?),
+            #[cfg(not(all(feature = "usbfs", target_os = "linux")))]
+            BridgeBackend::Usbfs => return Err(BridgeError::NotBuiltIn("usbfs")),
+            BridgeBackend::Mock => Bridge::MockBridge(MockBridge::new(cfg)?),
+            BridgeBackend::Mmap => Bridge::MmapBridge(MmapBridge::new(cfg)?),
+            #[cfg(feature = "ethernet")]
+            BridgeBackend::Etherbone => Bridge::EtherboneBridge(EtherboneBridge::new(cfg)?),
+            #[cfg(not(feature = "ethernet"))]
+            BridgeBackend::Etherbone => return Err(BridgeError::NotBuiltIn("etherbone")),
+            #[cfg(feature = "serial")]
+            BridgeBackend::Serial => Bridge::SerialBridge(SerialBridge::new(cfg)?),
+            #[cfg(not(feature = "serial"))]
+            BridgeBackend::Serial => return Err(BridgeError::NotBuiltIn("serial")),
+        };
+        match cfg.fault_injection {
+            Some(ref faults) => Ok(Bridge::FaultBridge(FaultBridge::new(bridge, faults)?)),
+            None => Ok(bridge),
+        }
     }
 
     pub fn connect(&self) -> Result<(), BridgeError> {
         match self {
+            #[cfg(feature = "usb")]
             Bridge::UsbBridge(b) => b.connect(),
+            #[cfg(all(feature = "usbfs", target_os = "linux"))]
+            Bridge::UsbfsBridge(b) => b.connect(),
+            Bridge::MockBridge(b) => b.connect(),
+            Bridge::MmapBridge(b) => b.connect(),
+            #[cfg(feature = "ethernet")]
+            Bridge::EtherboneBridge(b) => b.connect(),
+            #[cfg(feature = "serial")]
+            Bridge::SerialBridge(b) => b.connect(),
+            Bridge::FaultBridge(b) => b.connect(),
+        }
+    }
+
+    /// The number of bytes worth reading in one go.  Callers moving large
+    /// blocks should split them into pieces no bigger than this.
+    pub fn max_burst(&self) -> usize {
+        match self {
+            #[cfg(feature = "usb")]
+            Bridge::UsbBridge(b) => b.max_burst(),
+            #[cfg(all(feature = "usbfs", target_os = "linux"))]
+            Bridge::UsbfsBridge(b) => b.max_burst(),
+            Bridge::MockBridge(b) => b.max_burst(),
+            Bridge::MmapBridge(b) => b.max_burst(),
+            #[cfg(feature = "ethernet")]
+            Bridge::EtherboneBridge(b) => b.max_burst(),
+            #[cfg(feature = "serial")]
+            Bridge::SerialBridge(b) => b.max_burst(),
+            Bridge::FaultBridge(b) => b.max_burst(),
+        }
+    }
+
+    /// What the bridge talks to, ignoring any fault injection
+    pub fn backend_name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "usb")]
+            Bridge::UsbBridge(_) => "usb",
+            #[cfg(all(feature = "usbfs", target_os = "linux"))]
+            Bridge::UsbfsBridge(_) => "usbfs",
+            Bridge::MockBridge(_) => "mock",
+            Bridge::MmapBridge(_) => "mmap",
+            #[cfg(feature = "ethernet")]
+            Bridge::EtherboneBridge(_) => "etherbone",
+            #[cfg(feature = "serial")]
+            Bridge::SerialBridge(_) => "serial",
+            Bridge::FaultBridge(b) => b.backend_name(),
+        }
+    }
+
+    /// Read `count` consecutive words starting at `addr`, in bursts as big
+    /// as the backend allows
+    pub fn read_block(&self, addr: u32, count: usize) -> Result<Vec<u32>, BridgeError> {
+        let burst = (self.max_burst() / 4).max(1);
+        let mut values = Vec::with_capacity(count);
+        while values.len() < count {
+            let base = addr.wrapping_add(4 * values.len() as u32);
+            let ops: Vec<BatchOp> = (0..(count - values.len()).min(burst))
+                .map(|word| BatchOp::Read(base.wrapping_add(4 * word as u32)))
+                .collect();
+            values.extend(self.execute(&ops)?);
+        }
+        Ok(values)
+    }
+
+    /// Write `values` to consecutive words starting at `addr`, in bursts as
+    /// big as the backend allows
+    pub fn write_block(&self, addr: u32, values: &[u32]) -> Result<(), BridgeError> {
+        let burst = (self.max_burst() / 4).max(1);
+        for (idx, chunk) in values.chunks(burst).enumerate() {
+            let base = addr.wrapping_add((idx * burst * 4) as u32);
+            let ops: Vec<BatchOp> = chunk
+                .iter()
+                .enumerate()
+                .map(|(word, &value)| BatchOp::Write(base.wrapping_add(4 * word as u32), value))
+                .collect();
+            self.execute(&ops)?;
+        }
+        Ok(())
+    }
+
+    pub fn batch(&self) -> Batch<'_> {
+        Batch {
+            bridge: self,
+            ops: vec![],
+        }
+    }
+
+    fn execute(&self, ops: &[BatchOp]) -> Result<Vec<u32>, BridgeError> {
+        let start = Instant::now();
+        let result = match self {
+            #[cfg(feature = "usb")]
+            Bridge::UsbBridge(b) => b.execute(ops),
+            #[cfg(all(feature = "usbfs", target_os = "linux"))]
+            Bridge::UsbfsBridge(b) => b.execute(ops),
+            Bridge::MockBridge(b) => b.execute(ops),
+            Bridge::MmapBridge(b) => b.execute(ops),
+            #[cfg(feature = "ethernet")]
+            Bridge::EtherboneBridge(b) => b.execute(ops),
+            #[cfg(feature = "serial")]
+            Bridge::SerialBridge(b) => b.execute(ops),
+            // The wrapped bridge does its own logging and timing
+            Bridge::FaultBridge(b) => return b.execute(ops),
+        };
+        let took = start.elapsed();
+        #[cfg(feature = "server")]
+        stats::charge(Phase::Bridge, took);
+        match result {
+            Ok(ref values) => {
+                let mut values = values.iter();
+                // The whole batch took one round trip, which is charged to
+                // the first of it
+                let mut took = Some(took);
+                for op in ops {
+                    match *op {
+                        BatchOp::Write(addr, value) => {
+                            log_bridge!("-> W {:08x}: {:08x}", addr, value);
+                            record(Access::Write(addr, value), took.take(), None);
+                        }
+                        BatchOp::Read(addr) => {
+                            let value = *values.next().unwrap_or(&0);
+                            log_bridge!("<- R {:08x}: {:08x}", addr, value);
+                            record(Access::Read(addr, value), took.take(), None);
+                        }
+                    }
+                }
+            }
+            Err(ref e) => {
+                log_bridge!("<> batch of {}: {:?}", ops.len(), e);
+                // There's no telling which op failed, so blame the first
+                let access = match ops.first() {
+                    Some(BatchOp::Write(addr, _)) => Access::Error(*addr, true),
+                    Some(BatchOp::Read(addr)) => Access::Error(*addr, false),
+                    None => return result,
+                };
+                record(access, Some(took), Some(e));
+            }
+        }
+        result
+    }
+
+    /// Put `device` in front of the mock bridge's memory
+    #[cfg(test)]
+    pub fn attach(&self, device: Box<dyn Device>) {
+        match self {
+            Bridge::MockBridge(b) => b.attach(device),
+            _ => panic!("only the mock bridge takes devices"),
         }
     }
 
     pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        let start = Instant::now();
         let result = match self {
+            #[cfg(feature = "usb")]
             Bridge::UsbBridge(b) => b.peek(addr),
+            #[cfg(all(feature = "usbfs", target_os = "linux"))]
+            Bridge::UsbfsBridge(b) => b.peek(addr),
+            Bridge::MockBridge(b) => b.peek(addr),
+            Bridge::MmapBridge(b) => b.peek(addr),
+            #[cfg(feature = "ethernet")]
+            Bridge::EtherboneBridge(b) => b.peek(addr),
+            #[cfg(feature = "serial")]
+            Bridge::SerialBridge(b) => b.peek(addr),
+            // The wrapped bridge does its own logging and timing
+            Bridge::FaultBridge(b) => return b.peek(addr),
         };
-        // match result {
-        //     Ok(v) => println!("<- R {:08x}: {:08x}", addr, v),
-        //     Err(ref e) => println!("<- R {:08x}: {:?}", addr, e),
-        // }
+        let took = start.elapsed();
+        #[cfg(feature = "server")]
+        stats::charge(Phase::Bridge, took);
+        match result {
+            Ok(v) => {
+                log_bridge!("<- R {:08x}: {:08x}", addr, v);
+                record(Access::Read(addr, v), Some(took), None);
+            }
+            Err(ref e) => {
+                log_bridge!("<- R {:08x}: {:?}", addr, e);
+                record(Access::Error(addr, false), Some(took), Some(e));
+            }
+        }
         result
     }
 
     pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        let start = Instant::now();
         let result = match self {
+            #[cfg(feature = "usb")]
             Bridge::UsbBridge(b) => b.poke(addr, value),
+            #[cfg(all(feature = "usbfs", target_os = "linux"))]
+            Bridge::UsbfsBridge(b) => b.poke(addr, value),
+            Bridge::MockBridge(b) => b.poke(addr, value),
+            Bridge::MmapBridge(b) => b.poke(addr, value),
+            #[cfg(feature = "ethernet")]
+            Bridge::EtherboneBridge(b) => b.poke(addr, value),
+            #[cfg(feature = "serial")]
+            Bridge::SerialBridge(b) => b.poke(addr, value),
+            // The wrapped bridge does its own logging and timing
+            Bridge::FaultBridge(b) => return b.poke(addr, value),
         };
-        // match result {
-        //     Ok(()) => println!("-> W {:08x}: {:08x}", addr, value),
-        //     Err(ref e) => println!("-> W {:08x}: {:?}", addr, e),
-        // }
+        let took = start.elapsed();
+        #[cfg(feature = "server")]
+        stats::charge(Phase::Bridge, took);
+        match result {
+            Ok(()) => {
+                log_bridge!("-> W {:08x}: {:08x}", addr, value);
+                record(Access::Write(addr, value), Some(took), None);
+            }
+            Err(ref e) => {
+                log_bridge!("-> W {:08x}: {:?}", addr, e);
+                record(Access::Error(addr, true), Some(took), Some(e));
+            }
+        }
         result
     }
 }
+
+/// Pass a finished transaction on to the bus trace and the history
+fn record(access: Access, took: Option<Duration>, error: Option<&BridgeError>) {
+    trace::record(access);
+    history::record(access, took, error);
+}
+
+#[cfg(all(
+    test,
+    any(feature = "usb", all(feature = "usbfs", target_os = "linux"))
+))]
+mod test {
+    use std::io;
+
+    use super::{execute_usb, BatchOp, BridgeError, UsbBursts};
+
+    /// Run `ops` on a device whose memory holds each word's address,
+    /// where `burst` answers a control read of more than one word
+    fn run(
+        ops: &[BatchOp],
+        bursts: &mut UsbBursts,
+        burst: impl Fn(&mut [u8]) -> Result<usize, BridgeError>,
+    ) -> Result<Vec<u32>, BridgeError> {
+        execute_usb(
+            ops,
+            bursts,
+            16,
+            |addr, data| match data.len() {
+                4 => {
+                    data.copy_from_slice(&addr.to_le_bytes());
+                    Ok(4)
+                }
+                _ => burst(data),
+            },
+            |_, data| Ok(data.len()),
+        )
+    }
+
+    fn reads(count: u32) -> Vec<BatchOp> {
+        (0..count)
+            .map(|word| BatchOp::Read(0x100 + word * 4))
+            .collect()
+    }
+
+    #[test]
+    fn stalled_bursts_fall_back_to_words() {
+        let stall = |_: &mut [u8]| Err(io::Error::from_raw_os_error(libc::EPIPE).into());
+        let mut bursts = UsbBursts::Unknown;
+        assert_eq!(
+            run(&reads(3), &mut bursts, stall).unwrap(),
+            vec![0x100, 0x104, 0x108]
+        );
+        assert_eq!(bursts, UsbBursts::Unsupported);
+        // Once it's known, bursts aren't tried again
+        let unreachable = |_: &mut [u8]| -> Result<usize, BridgeError> { panic!("burst tried") };
+        assert_eq!(
+            run(&reads(2), &mut bursts, unreachable).unwrap(),
+            vec![0x100, 0x104]
+        );
+    }
+
+    #[test]
+    fn short_bursts_fall_back_to_words() {
+        let mut bursts = UsbBursts::Unknown;
+        let short = |data: &mut [u8]| {
+            data[..4].copy_from_slice(&0x100u32.to_le_bytes());
+            Ok(4)
+        };
+        assert_eq!(
+            run(&reads(2), &mut bursts, short).unwrap(),
+            vec![0x100, 0x104]
+        );
+        assert_eq!(bursts, UsbBursts::Unsupported);
+    }
+
+    #[test]
+    fn other_errors_are_passed_on() {
+        let mut bursts = UsbBursts::Unknown;
+        let broken = |_: &mut [u8]| Err(BridgeError::WrongResponse);
+        assert!(matches!(
+            run(&reads(2), &mut bursts, broken),
+            Err(BridgeError::WrongResponse)
+        ));
+        assert_eq!(bursts, UsbBursts::Unknown);
+    }
+}
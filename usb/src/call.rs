@@ -0,0 +1,297 @@
+//! Calling a function on the target while it's halted.  The hart's state
+//! is saved, the arguments go where the RISC-V calling convention puts
+//! them, and the return address points at an `ebreak` in scratch RAM, so
+//! the hart halts again as soon as the function returns.  The return value
+//! is read out of `a0` and `a1`, and the hart is put back the way it was.
+//!
+//! GDB's own `print my_func(3)` doesn't come through here.  GDB writes the
+//! arguments itself, points `ra` at the program's entry point with a
+//! breakpoint there, and resumes the hart with the same packets as any
+//! other continue, which the server already handles.  `monitor call` is
+//! for functions GDB has no symbols or types for.  Its `ebreak` goes in
+//! the scratch area, which is only ever RAM set aside with `--scratch` or
+//! a "scratch" memory region, never the firmware's stack.
+
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::bridge::{Bridge, BridgeError};
+use super::riscv::{register_number, RiscvCpu, RiscvCpuError};
+use super::scratch::{Scratch, ScratchError};
+
+/// How long a function gets to return before it's halted
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait between looking to see if the hart has halted
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// `ebreak`, which halts the hart when it gets back
+const EBREAK: u32 = 0x0010_0073;
+
+/// Arguments are passed in a0-a7, then on the stack
+const ARG_REGISTERS: usize = 8;
+
+/// The stack pointer is kept 16-byte aligned at calls
+const STACK_ALIGN: u32 = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arg {
+    Word(u32),
+
+    /// Passed in a pair of registers, low half first
+    Double(u64),
+}
+
+impl Arg {
+    /// A value typed by hand, which takes two registers if it doesn't fit
+    /// in one
+    pub fn from_value(value: u64) -> Arg {
+        if value > u32::MAX as u64 {
+            Arg::Double(value)
+        } else {
+            Arg::Word(value as u32)
+        }
+    }
+}
+
+/// Where everything goes for a call
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    /// Words for a0 onwards
+    pub registers: Vec<u32>,
+
+    /// sp for the call
+    pub sp: u32,
+
+    /// Words to write from the new sp upwards
+    pub stack: Vec<u32>,
+}
+
+/// Lay out `args` for a call made with the stack pointer at `sp`.  Each
+/// word goes in the next free argument register.  A 64-bit value takes two
+/// of them, and if only a7 is left it's split between a7 and the stack.
+/// Once the registers run out, arguments go on the stack in order, with
+/// 64-bit ones aligned to 8 bytes.
+pub fn lay_out(args: &[Arg], sp: u32) -> Frame {
+    let mut registers = vec![];
+    let mut stack: Vec<u32> = vec![];
+    for arg in args {
+        match *arg {
+            Arg::Word(value) if registers.len() < ARG_REGISTERS => registers.push(value),
+            Arg::Word(value) => stack.push(value),
+            Arg::Double(value) => {
+                let (low, high) = (value as u32, (value >> 32) as u32);
+                if registers.len() + 2 <= ARG_REGISTERS {
+                    registers.push(low);
+                    registers.push(high);
+                } else if registers.len() < ARG_REGISTERS {
+                    registers.push(low);
+                    stack.push(high);
+                } else {
+                    if !stack.len().is_multiple_of(2) {
+                        stack.push(0);
+                    }
+                    stack.push(low);
+                    stack.push(high);
+                }
+            }
+        }
+    }
+    let size = stack.len() as u32 * 4;
+    let sp = sp.wrapping_sub(size) & !(STACK_ALIGN - 1);
+    Frame {
+        registers,
+        sp,
+        stack,
+    }
+}
+
+#[derive(Debug)]
+pub enum CallError {
+    /// The function didn't return in time, and was halted here
+    TimedOut(u32),
+
+    /// The hart halted somewhere other than the return address, such as at
+    /// a breakpoint or trap inside the function
+    Stopped(u32),
+
+    ScratchError(ScratchError),
+
+    CpuError(RiscvCpuError),
+
+    BridgeError(BridgeError),
+}
+
+impl std::convert::From<ScratchError> for CallError {
+    fn from(e: ScratchError) -> CallError {
+        CallError::ScratchError(e)
+    }
+}
+
+impl std::convert::From<BridgeError> for CallError {
+    fn from(e: BridgeError) -> CallError {
+        CallError::BridgeError(e)
+    }
+}
+
+impl std::convert::From<RiscvCpuError> for CallError {
+    fn from(e: RiscvCpuError) -> CallError {
+        CallError::CpuError(e)
+    }
+}
+
+impl fmt::Display for CallError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use CallError::*;
+        match self {
+            TimedOut(pc) => write!(
+                f,
+                "didn't return within {} seconds, halted at {:08x}",
+                TIMEOUT.as_secs(),
+                pc
+            ),
+            Stopped(pc) => write!(f, "stopped at {:08x} before returning", pc),
+            ScratchError(e) => write!(f, "{}", e),
+            CpuError(e) => write!(f, "cpu error: {:?}", e),
+            BridgeError(e) => write!(f, "bridge error: {:?}", e),
+        }
+    }
+}
+
+/// Call the function at `addr` on `hart` with `args`, and return what it
+/// left in a0 and a1.  The hart must be halted, and is put back the way
+/// it was whether or not the function returns.
+pub fn call(
+    cpu: &RiscvCpu,
+    bridge: &Bridge,
+    hart: usize,
+    scratch: &mut Scratch,
+    addr: u32,
+    args: &[Arg],
+) -> Result<(u32, u32), CallError> {
+    let context = cpu.save_context(bridge, hart)?;
+    let ret = scratch.alloc(bridge, 4)?;
+    let result = run(cpu, bridge, hart, ret, addr, args, context.registers[2]);
+    // Put everything back even if the call went wrong, and report the
+    // first thing that failed
+    let restored = cpu.load_context(bridge, hart, &context);
+    let freed = scratch.free(bridge, ret);
+    let result = result?;
+    restored?;
+    freed?;
+    Ok(result)
+}
+
+fn run(
+    cpu: &RiscvCpu,
+    bridge: &Bridge,
+    hart: usize,
+    ret: u32,
+    addr: u32,
+    args: &[Arg],
+    sp: u32,
+) -> Result<(u32, u32), CallError> {
+    let frame = lay_out(args, sp);
+    cpu.write_memory(bridge, hart, ret, &EBREAK.to_le_bytes())?;
+    if !frame.stack.is_empty() {
+        let bytes: Vec<u8> = frame.stack.iter().flat_map(|w| w.to_le_bytes()).collect();
+        cpu.write_memory(bridge, hart, frame.sp, &bytes)?;
+    }
+    let a0 = register_number("a0").unwrap();
+    for (i, &value) in frame.registers.iter().enumerate() {
+        cpu.set_register(bridge, hart, a0 + i as u32, value)?;
+    }
+    cpu.set_register(bridge, hart, register_number("sp").unwrap(), frame.sp)?;
+    cpu.set_register(bridge, hart, register_number("ra").unwrap(), ret)?;
+    cpu.set_register(bridge, hart, register_number("pc").unwrap(), addr)?;
+
+    log_adapter!(
+        "Calling {:08x} on hart {} with {:?}, returning to {:08x}",
+        addr,
+        hart,
+        frame,
+        ret
+    );
+    cpu.resume_single(bridge, hart)?;
+    let started = Instant::now();
+    let timed_out = loop {
+        if cpu.is_halted(bridge, hart)? {
+            break false;
+        }
+        if started.elapsed() > TIMEOUT {
+            cpu.halt_hart(bridge, hart)?;
+            break true;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    let pc = cpu.read_register(bridge, hart, register_number("pc").unwrap())?;
+    if timed_out {
+        return Err(CallError::TimedOut(pc));
+    }
+    if pc != ret {
+        return Err(CallError::Stopped(pc));
+    }
+    Ok((
+        cpu.read_register(bridge, hart, a0)?,
+        cpu.read_register(bridge, hart, a0 + 1)?,
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{lay_out, Arg, Frame};
+
+    #[test]
+    fn words_in_registers() {
+        let args = [Arg::Word(1), Arg::Word(2), Arg::Word(3)];
+        assert_eq!(
+            lay_out(&args, 0x4000_1000),
+            Frame {
+                registers: vec![1, 2, 3],
+                sp: 0x4000_1000,
+                stack: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn words_spill_to_stack() {
+        let args: Vec<Arg> = (0..10).map(Arg::Word).collect();
+        let frame = lay_out(&args, 0x4000_1004);
+        assert_eq!(frame.registers, [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert_eq!(frame.stack, [8, 9]);
+        // Made room, then aligned
+        assert_eq!(frame.sp, 0x4000_0ff0);
+    }
+
+    #[test]
+    fn doubles_take_pairs() {
+        let args = [Arg::Word(1), Arg::Double(0x1_2345_6789)];
+        let frame = lay_out(&args, 0x4000_1000);
+        assert_eq!(frame.registers, [1, 0x2345_6789, 1]);
+    }
+
+    #[test]
+    fn double_split_across_a7() {
+        let mut args: Vec<Arg> = (0..7).map(Arg::Word).collect();
+        args.push(Arg::Double(0xaaaa_aaaa_bbbb_bbbb));
+        let frame = lay_out(&args, 0x4000_1000);
+        assert_eq!(frame.registers[7], 0xbbbb_bbbb);
+        assert_eq!(frame.stack, [0xaaaa_aaaa]);
+    }
+
+    #[test]
+    fn doubles_aligned_on_stack() {
+        let mut args: Vec<Arg> = (0..9).map(Arg::Word).collect();
+        args.push(Arg::Double(0x2_0000_0001));
+        let frame = lay_out(&args, 0x4000_1000);
+        assert_eq!(frame.stack, [8, 0, 1, 2]);
+    }
+
+    #[test]
+    fn values_sized_by_hand() {
+        assert_eq!(Arg::from_value(5), Arg::Word(5));
+        assert_eq!(Arg::from_value(1 << 32), Arg::Double(1 << 32));
+    }
+}
@@ -0,0 +1,127 @@
+use super::bridge::{Bridge, SERVER_KINDS};
+#[cfg(feature = "server")]
+use super::gdb::{MONITOR_COMMANDS, SUPPORTED_FEATURES};
+use super::riscv::RiscvCpu;
+
+#[cfg(not(feature = "server"))]
+const MONITOR_COMMANDS: &[&str] = &[];
+#[cfg(not(feature = "server"))]
+const SUPPORTED_FEATURES: &str = "";
+
+/// Backends this build can talk to
+const BACKENDS: &[(&str, bool)] = &[
+    ("usb", cfg!(feature = "usb")),
+    ("usbfs", cfg!(all(feature = "usbfs", target_os = "linux"))),
+    ("mock", true),
+    ("mmap", true),
+    ("etherbone", cfg!(feature = "ethernet")),
+    ("serial", cfg!(feature = "serial")),
+];
+
+/// CPU debug units this build can drive
+const CPU_CONTROLLERS: &[&str] = &["vexriscv"];
+
+/// Optional subsystems, and whether this build has them
+const SUBSYSTEMS: &[(&str, bool)] = &[
+    ("flash", cfg!(feature = "flash")),
+    ("csr-map", true),
+    ("mmu", cfg!(feature = "server")),
+    ("linux-kernel", cfg!(feature = "server")),
+    ("fault-injection", true),
+    ("serialboot", cfg!(feature = "server")),
+    ("xmodem", cfg!(feature = "server")),
+    ("ymodem", cfg!(feature = "server")),
+    ("file-agent", cfg!(feature = "server")),
+    ("litescope", false),
+    ("rtt", false),
+];
+
+/// Quote a string for JSON
+fn string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn string_array<'a>(items: impl IntoIterator<Item = &'a str>) -> String {
+    let items: Vec<String> = items.into_iter().map(string).collect();
+    format!("[{}]", items.join(", "))
+}
+
+/// Extension letters set in `misa`
+fn extensions(misa: u32) -> String {
+    (0..26)
+        .filter(|bit| misa & (1 << bit) != 0)
+        .map(|bit| (b'A' + bit as u8) as char)
+        .collect()
+}
+
+/// Describe what the target says about itself.  Anything that can't be
+/// read is left out rather than failing the whole report.
+fn target(cpu: &RiscvCpu, bridge: &Bridge) -> String {
+    let mut fields = vec![
+        format!("\"backend\": {}", string(bridge.backend_name())),
+        format!("\"max_burst\": {}", bridge.max_burst()),
+        format!("\"harts\": {}", cpu.hart_count()),
+    ];
+    if let Ok(misa) = cpu.read_misa(bridge) {
+        fields.push(format!("\"misa\": \"0x{:08x}\"", misa));
+        fields.push(format!("\"extensions\": {}", string(&extensions(misa))));
+    }
+    if let Ok(vlenb) = cpu.vector_length(bridge) {
+        fields.push(match vlenb {
+            Some(vlenb) => format!("\"vector\": {{\"vlenb\": {}}}", vlenb),
+            None => "\"vector\": null".to_owned(),
+        });
+    }
+    format!("{{{}}}", fields.join(", "))
+}
+
+/// A JSON description of what this build supports and what the connected
+/// target supports, for front ends that want to adapt to both
+pub fn report(cpu: &RiscvCpu, bridge: &Bridge) -> String {
+    let subsystems: Vec<String> = SUBSYSTEMS
+        .iter()
+        .map(|(name, present)| format!("{}: {}", string(name), present))
+        .collect();
+    let fields = [
+        format!("\"version\": {}", string(env!("CARGO_PKG_VERSION"))),
+        format!(
+            "\"backends\": {}",
+            string_array(
+                BACKENDS
+                    .iter()
+                    .filter(|(_, present)| *present)
+                    .map(|(name, _)| *name)
+            )
+        ),
+        format!(
+            "\"servers\": {}",
+            string_array(SERVER_KINDS.iter().cloned())
+        ),
+        format!(
+            "\"cpu_controllers\": {}",
+            string_array(CPU_CONTROLLERS.iter().cloned())
+        ),
+        format!(
+            "\"gdb_features\": {}",
+            string_array(SUPPORTED_FEATURES.split(';').filter(|f| !f.is_empty()))
+        ),
+        format!(
+            "\"monitor_commands\": {}",
+            string_array(MONITOR_COMMANDS.iter().cloned())
+        ),
+        format!("\"subsystems\": {{{}}}", subsystems.join(", ")),
+        format!("\"target\": {}", target(cpu, bridge)),
+    ];
+    format!("{{{}}}", fields.join(", "))
+}
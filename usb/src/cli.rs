@@ -0,0 +1,545 @@
+//! The command line.  The binary parses its arguments with this, and so
+//! can a program embedding the bridge that wants to take the same options.
+
+use clap::{App, Arg};
+
+use super::board;
+use super::bridge;
+
+/// Every option the adapter takes
+pub fn app() -> App<'static, 'static> {
+    App::new("Wishbone USB Adapter")
+        .version("1.0")
+        .author("Sean Cross <sean@xobs.io>")
+        .about("Bridge Wishbone over USB")
+        .arg(
+            Arg::with_name("list")
+                .short("l")
+                .long("list")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("board")
+                .short("b")
+                .long("board")
+                .value_name("BOARD")
+                .help("Use the USB IDs, debug address and memory map of a known board.  Other options override it")
+                .possible_values(board::BOARD_NAMES)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pid")
+                .short("p")
+                .long("pid")
+                .value_name("USB_PID")
+                .help("USB PID to match")
+                .default_value("0x5bf0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("vid")
+                .short("v")
+                .long("vid")
+                .value_name("USB_VID")
+                .help("USB VID to match")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no-autosuspend")
+                .long("no-autosuspend")
+                .help("Stop Linux from suspending the USB device when it's idle, by setting its power/control to \"on\" in sysfs.  This needs write access to sysfs, and lasts until the device is unplugged.")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("address")
+                .index(1)
+                .required(false)
+                .help("address to read/write, which can be an expression using names from csr.csv such as \"uart_base + 4\""),
+        )
+        .arg(
+            Arg::with_name("value")
+                .index(2)
+                .required(false)
+                .help("value to write, which can also be an expression"),
+        )
+        .arg(
+            Arg::with_name("load")
+                .long("load")
+                .value_name("FILE")
+                .help("Write the contents of FILE starting at the given address, before starting any server.  Flash is erased and programmed as needed, through the spiflash registers in --csr-csv")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("load-hash")
+                .long("load-hash")
+                .value_name("ALGORITHM:HEX")
+                .help("Only load a file with this hash, and check it was written correctly, e.g. \"sha256:9f86...\" or \"crc32:cbf43926\"")
+                .requires("load")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("load-state")
+                .long("load-state")
+                .value_name("FILE")
+                .help("Record how far the load has got in FILE, and carry on from there if it's the same load that failed last time")
+                .requires("load")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("manifest")
+                .long("manifest")
+                .value_name("ADDR")
+                .help("After loading, write a signed manifest describing the image at ADDR")
+                .requires_all(&["load", "manifest-key"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("manifest-key")
+                .long("manifest-key")
+                .value_name("FILE")
+                .help("Sign and check manifests with the key in FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("verify-manifest")
+                .long("verify-manifest")
+                .value_name("ADDR")
+                .help("Check the manifest at ADDR and the image it describes, then exit")
+                .requires("manifest-key")
+                .conflicts_with("load")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("kernel")
+                .long("kernel")
+                .value_name("FILE")
+                .help("Boot FILE through the LiteX BIOS serialboot over the crossover UART.  Implies \"-s terminal\"")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("kernel-adr")
+                .long("kernel-adr")
+                .value_name("ADDRESS")
+                .help("Where to load the --kernel image")
+                .default_value("0x40000000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("csr-csv")
+                .long("csr-csv")
+                .value_name("CSR_CSV")
+                .help("LiteX csr.csv describing the gateware on the device")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("compare-csr")
+                .long("compare-csr")
+                .value_names(&["OLD_CSV", "NEW_CSV"])
+                .help("Report registers added, removed or moved between two csr.csv files, then exit")
+                .number_of_values(2),
+        )
+        .arg(
+            Arg::with_name("compare-steps")
+                .long("compare-steps")
+                .value_names(&["OLD_TRACE", "NEW_TRACE"])
+                .help("Report the first step where two traces from \"monitor step-record\" differ, then exit")
+                .number_of_values(2),
+        )
+        .arg(
+            Arg::with_name("framebuffer")
+                .long("framebuffer")
+                .value_names(&["ADDR", "WIDTHxHEIGHT", "FORMAT"])
+                .help("Save the framebuffer at ADDR as a PNG, then exit.  FORMAT is rgb888 or rgb565.  With \"-s http\", show it live at /framebuffer instead")
+                .number_of_values(3),
+        )
+        .arg(
+            Arg::with_name("framebuffer-png")
+                .long("framebuffer-png")
+                .value_name("FILE")
+                .help("Where --framebuffer saves the PNG [default: framebuffer.png]")
+                .requires("framebuffer")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("capabilities")
+                .long("capabilities")
+                .help("Print what this build and the connected target support, as JSON, then exit")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("restore-session")
+                .long("restore-session")
+                .value_name("FILE")
+                .help("Restore breakpoints and settings saved with \"monitor save-session\"")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("kernel-symbols")
+                .long("kernel-symbols")
+                .value_name("FILE")
+                .help("Addresses and task_struct offsets of a Linux kernel, for \"monitor ps\", \"monitor dmesg\" and \"monitor kthreads\"")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("emit-gdbinit")
+                .long("emit-gdbinit")
+                .value_name("FILE")
+                .help("Write a GDB command file that connects to the GDB server and sets up the memory map, CSR names and commands for the SoC.  Load it with \"gdb -x FILE\"")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gdb-pty")
+                .long("gdb-pty")
+                .help("Serve GDB on a new pseudo-terminal instead of a TCP port")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("bind-addr")
+                .short("a")
+                .long("bind-addr")
+                .value_name("IP_ADDRESS")
+                .help("IP address to bind to")
+                .default_value("0.0.0.0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("port")
+                .short("n")
+                .long("port")
+                .value_name("PORT_NUMBER")
+                .help("Port number to listen on")
+                .default_value("1234")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bus-width")
+                .long("bus-width")
+                .value_name("BITS")
+                .help("Data width of the SoC bus, as declared to Wishbone server clients")
+                .possible_values(&["32", "64"])
+                .default_value("32")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bus-endian")
+                .long("bus-endian")
+                .value_name("ENDIANNESS")
+                .help("Which half of a 64-bit bus word lives at the lower address")
+                .possible_values(&["little", "big"])
+                .default_value("little")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bridge-kind")
+                .short("s")
+                .long("server-kind")
+                .alias("server")
+                .value_name("KIND")
+                .help("Server to run.  \"wishbone\" speaks Etherbone over TCP and UDP, for tools made for litex_server")
+                .takes_value(true)
+                .possible_values(bridge::SERVER_KINDS),
+        )
+        .arg(
+            Arg::with_name("mock")
+                .long("mock")
+                .help("Use a simulated bridge instead of a real device")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("mmap-file")
+                .long("mmap-file")
+                .value_name("FILE")
+                .help("Use a shared-memory file laid out like the SoC address space instead of a real device")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mmap-base")
+                .long("mmap-base")
+                .value_name("ADDRESS")
+                .help("Bus address that the start of --mmap-file corresponds to")
+                .default_value("0")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("usb-backend")
+                .long("usb-backend")
+                .value_name("BACKEND")
+                .help("How to reach USB devices: through libusb, or through usbfs (Linux only)")
+                .possible_values(&["libusb", "usbfs"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("etherbone")
+                .long("etherbone")
+                .value_name("HOST[:PORT]")
+                .help("Talk to a LiteX Etherbone core over UDP instead of a USB device")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("etherbone-timeout")
+                .long("etherbone-timeout")
+                .value_name("MILLISECONDS")
+                .help("How long to wait for an Etherbone reply before resending")
+                .default_value("500")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("etherbone-keepalive")
+                .long("etherbone-keepalive")
+                .value_name("SECONDS")
+                .help("Probe an idle Etherbone link this often, reopening it if it has gone away.  0 disables")
+                .default_value("10")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("serial")
+                .long("serial")
+                .value_name("PORT")
+                .help("Talk to a LiteX uartwishbone core on this serial port instead of a USB device")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("baud")
+                .long("baud")
+                .value_name("RATE")
+                .help("Baud rate of the --serial port")
+                .default_value("115200")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("inject-faults")
+                .long("inject-faults")
+                .value_name("SPEC")
+                .help("Randomly inject bridge faults, e.g. \"delay=0.05,max-delay=20,retry=0.01,error=0.001,seed=1\"")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("power")
+                .long("power")
+                .value_name("SWITCH")
+                .help("What switches the board's power: \"gpio:NUM\" for a sysfs GPIO, \"relay:CHANNEL\" for a HID relay board, or \"ftdi:BIT\" for an FTDI CBUS pin.  USB switches take \"@VID:PID\" on the end if they aren't the usual ones.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("events")
+                .long("events")
+                .value_name("SOCKET")
+                .help("Stream target events as JSON lines to anything that connects to SOCKET, which is \"unix:PATH\" or \"tcp:[HOST:]PORT\"")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("doorbell")
+                .long("doorbell")
+                .value_name("ADDR[:ACTIONS]")
+                .help("While the target runs, watch for firmware writing something other than zero to ADDR, then clear it and act on it.  ACTIONS are any of \"log\", \"gdb\" and \"dump=PATH\" separated by commas [default: log,gdb].  If csr.csv has an event manager's _ev_pending register at ADDR, the bits that were set are written back instead")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("boot-register")
+                .long("boot-register")
+                .value_name("CSR[:MODE=ADDR,...]")
+                .help("The register in --csr-csv holding the address the CPU boots from, for \"monitor bootmode\" to set before resetting the SoC with ctrl_reset.  Flash boot starts at flash_boot_address or the start of the flash, and serial boot at the BIOS in rom.  MODE is \"flash\", \"serial\" or \"netboot\", and network boot needs an address given")
+                .requires("csr-csv")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("power-cycle-on-start")
+                .long("power-cycle-on-start")
+                .help("Cycle the board's power before connecting to it")
+                .requires("power")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("no-rle")
+                .long("no-rle")
+                .help("Don't run-length encode packets sent to gdb")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("gdb-proxy")
+                .long("gdb-proxy")
+                .value_name("HOST:PORT")
+                .help("Relay GDB to another GDB server, serving memory accesses in the proxy ranges from the bridge")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("proxy-range")
+                .long("proxy-range")
+                .value_name("BASE:SIZE[,...]")
+                .help("Address ranges that --gdb-proxy serves from the bridge.  Defaults to the board's I/O regions")
+                .requires("gdb-proxy")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("file-agent")
+                .long("file-agent")
+                .value_name("ADDR")
+                .help("Address of the mailbox of a file agent in the target's firmware, which GDB's \"remote put\" and \"remote get\" go through")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scratch")
+                .long("scratch")
+                .value_name("BASE:SIZE")
+                .help("Target RAM the adapter may borrow, which is put back when GDB detaches.  Without this, a region named \"scratch\" in the memory map is used, if there is one.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("force")
+                .long("force")
+                .help("Start the server even if the startup checks fail")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("wait-for-flash")
+                .long("wait-for-flash")
+                .help("When the flash is being programmed, by this adapter or another one on the same board, wait for it to finish instead of failing reads of it")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("low-latency")
+                .long("low-latency")
+                .help("Answer each request as quickly as possible, for single-stepping by hand: no batching or caching, no Nagle on sockets, and short poll intervals")
+                .conflicts_with("throughput")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("throughput")
+                .long("throughput")
+                .help("Move data as quickly as possible, for bulk loads: the largest bursts the bridge allows, no prefetching, and longer poll intervals")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("no-prefetch")
+                .long("no-prefetch")
+                .help("Don't read the code around pc and the top of the stack when the CPU halts")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("no-escape")
+                .long("no-escape")
+                .help("Don't escape binary data sent to gdb")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("breakpoint-fallback")
+                .long("breakpoint-fallback")
+                .value_name("REGIONS")
+                .help("When GDB runs out of hardware breakpoints, patch in software ones instead within these memory map regions, separated by commas.  \"ram\" means every RAM region")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("debug-offset")
+                .long("debug-offset")
+                .value_name("ADDRESSES")
+                .help("Address of each hart's debug unit, separated by commas")
+                .default_value("0xf00f0000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("virtual-register")
+                .long("virtual-register")
+                .value_name("NAME=ADDR[:BITS]")
+                .help("Show the 8, 16 or 32-bit variable at ADDR to GDB as a register called NAME.  May be given more than once")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("smp-group")
+                .long("smp-group")
+                .value_name("HARTS")
+                .help("Harts that halt and resume together, e.g. \"0,1\".  May be given more than once.  By default all harts are in one group")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Only print results and errors")
+                .conflicts_with("verbose"),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .long("verbose")
+                .help("Print adapter diagnostics.  Give it twice to also print GDB packets")
+                .multiple(true),
+        )
+        .arg(
+            Arg::with_name("output-format")
+                .long("output-format")
+                .value_name("FORMAT")
+                .help("Print plain text for people, or tab-separated records for scripts")
+                .possible_values(&["human", "machine"])
+                .default_value("human")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-gdb")
+                .long("log-gdb")
+                .value_name("FILE")
+                .help("Write GDB packet traces to this file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-bridge")
+                .long("log-bridge")
+                .value_name("FILE")
+                .help("Write every bridge transaction to this file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trace-vcd")
+                .long("trace-vcd")
+                .value_name("FILE")
+                .help("Record every bridge transaction as a VCD waveform, for lining up with logic analyzer captures in PulseView or GTKWave")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-terminal")
+                .long("log-terminal")
+                .value_name("FILE")
+                .help("Write target terminal output to this file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-adapter")
+                .long("log-adapter")
+                .value_name("FILE")
+                .help("Write adapter diagnostics to this file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-max-size")
+                .long("log-max-size")
+                .value_name("BYTES")
+                .help("Rotate log files once they reach this size (0 to never rotate)")
+                .default_value("0x1000000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("postmortem")
+                .long("postmortem")
+                .value_name("FILE")
+                .help("Keep the last bridge transactions in memory, and write them to FILE when something fails for good")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("postmortem-depth")
+                .long("postmortem-depth")
+                .value_name("COUNT")
+                .help("Number of bridge transactions to keep for --postmortem (0 to keep none)")
+                .default_value("1000")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("log-keep")
+                .long("log-keep")
+                .value_name("COUNT")
+                .help("Number of rotated log files to keep")
+                .default_value("4")
+                .takes_value(true),
+        )
+}
@@ -0,0 +1,313 @@
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::bridge::{Bridge, BridgeError};
+use super::csr_map::{CsrMap, CsrMapError};
+use super::riscv::RiscvCpu;
+use super::utils::{parse_u32, parse_u64};
+
+/// Default amount of time to let the target run between samples
+const DEFAULT_INTERVAL_MS: u32 = 500;
+
+/// `mcycle` and `mcycleh`
+const CSR_MCYCLE: u32 = 0xb00;
+const CSR_MCYCLEH: u32 = 0xb80;
+
+#[derive(Debug)]
+pub enum ClockError {
+    /// The bridge failed while sampling
+    BridgeError(BridgeError),
+
+    /// The arguments didn't make sense
+    InvalidArguments(String),
+
+    /// The counter didn't move during the interval
+    CounterStopped,
+}
+
+impl std::convert::From<BridgeError> for ClockError {
+    fn from(e: BridgeError) -> Self {
+        ClockError::BridgeError(e)
+    }
+}
+
+impl fmt::Display for ClockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClockError::BridgeError(e) => write!(f, "bridge error: {:?}", e),
+            ClockError::InvalidArguments(s) => write!(f, "{}", s),
+            ClockError::CounterStopped => write!(f, "counter did not change"),
+        }
+    }
+}
+
+pub struct ClockMeasurement {
+    /// Where the ticks came from
+    source: String,
+
+    /// How many ticks elapsed on the target
+    ticks: u64,
+
+    /// How much host time elapsed
+    elapsed: Duration,
+}
+
+impl ClockMeasurement {
+    pub fn frequency(&self) -> f64 {
+        self.ticks as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+impl fmt::Display for ClockMeasurement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{}: {} ticks in {:.3} ms = {:.3} MHz",
+            self.source,
+            self.ticks,
+            self.elapsed.as_secs_f64() * 1000.0,
+            self.frequency() / 1_000_000.0
+        )
+    }
+}
+
+fn read_mcycle(cpu: &RiscvCpu, bridge: &Bridge) -> Result<u64, BridgeError> {
+    // Re-read the high half in case the low half wrapped between reads
+    loop {
+        let high = cpu.read_csr(bridge, 0, CSR_MCYCLEH)?;
+        let low = cpu.read_csr(bridge, 0, CSR_MCYCLE)?;
+        if cpu.read_csr(bridge, 0, CSR_MCYCLEH)? == high {
+            return Ok(((high as u64) << 32) | low as u64);
+        }
+    }
+}
+
+/// Let the CPU run for `interval` and count how many cycles went by
+/// according to `mcycle` on the first hart.  CSRs can only be read while
+/// the CPU is halted, so the CPU is halted around each sample and left in
+/// whatever state it started in.
+pub fn measure_mcycle(
+    cpu: &RiscvCpu,
+    bridge: &Bridge,
+    interval: Duration,
+) -> Result<ClockMeasurement, ClockError> {
+    let was_running = !cpu.is_halted(bridge, 0)?;
+    if was_running {
+        cpu.halt(bridge)?;
+    }
+
+    let start_cycles = read_mcycle(cpu, bridge)?;
+    cpu.resume(bridge)?;
+    let start_time = Instant::now();
+    thread::sleep(interval);
+    cpu.halt(bridge)?;
+    let elapsed = start_time.elapsed();
+    let end_cycles = read_mcycle(cpu, bridge)?;
+
+    if was_running {
+        cpu.resume(bridge)?;
+    }
+
+    let ticks = end_cycles.wrapping_sub(start_cycles);
+    if ticks == 0 {
+        return Err(ClockError::CounterStopped);
+    }
+    Ok(ClockMeasurement {
+        source: "mcycle".to_owned(),
+        ticks,
+        elapsed,
+    })
+}
+
+/// Sample a free-running 32-bit counter directly over the bridge, without
+/// involving the CPU.  LiteX timers need their value latched by writing to
+/// an `update_value` register first, which is what `latch_addr` is for.
+/// Counters may count either up or down.
+pub fn measure_timer(
+    bridge: &Bridge,
+    value_addr: u32,
+    latch_addr: Option<u32>,
+    interval: Duration,
+) -> Result<ClockMeasurement, ClockError> {
+    let sample = || -> Result<(u32, Instant), BridgeError> {
+        if let Some(latch) = latch_addr {
+            bridge.poke(latch, 1)?;
+        }
+        let value = bridge.peek(value_addr)?;
+        Ok((value, Instant::now()))
+    };
+
+    let (start_value, start_time) = sample()?;
+    thread::sleep(interval);
+    let (end_value, end_time) = sample()?;
+
+    let mut ticks = end_value.wrapping_sub(start_value);
+    if ticks > 0x8000_0000 {
+        // Counting down
+        ticks = start_value.wrapping_sub(end_value);
+    }
+    if ticks == 0 {
+        return Err(ClockError::CounterStopped);
+    }
+    Ok(ClockMeasurement {
+        source: format!("timer at {:08x}", value_addr),
+        ticks: ticks as u64,
+        elapsed: end_time.duration_since(start_time),
+    })
+}
+
+/// The uptime counter LiteX adds to `timer0` when the SoC is built with
+/// `--timer-uptime`.  It counts system clock cycles since reset, whether or
+/// not the CPU is halted, so it's the same clock the firmware's own
+/// timestamps come from.
+pub struct Uptime {
+    /// Register that copies the count into `cycles` when written
+    latch: String,
+    cycles: String,
+
+    /// System clock in Hz, if csr.csv says what it is
+    frequency: Option<u64>,
+}
+
+/// A sample of the uptime counter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TargetTime {
+    pub cycles: u64,
+    frequency: Option<u64>,
+}
+
+impl fmt::Display for TargetTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.frequency {
+            Some(hz) => {
+                let micros = (self.cycles % hz) as u128 * 1_000_000 / hz as u128;
+                write!(f, "{}.{:06}s", self.cycles / hz, micros)
+            }
+            None => write!(f, "{} cycles", self.cycles),
+        }
+    }
+}
+
+impl Uptime {
+    /// Find a timer with uptime registers in csr.csv
+    pub fn detect(map: &CsrMap) -> Option<Uptime> {
+        let cycles = map
+            .registers
+            .keys()
+            .find(|name| name.ends_with("_uptime_cycles"))?;
+        let latch = format!("{}_latch", cycles.trim_end_matches("_cycles"));
+        if !map.registers.contains_key(&latch) {
+            return None;
+        }
+        Some(Uptime {
+            latch,
+            cycles: cycles.clone(),
+            frequency: map
+                .constants
+                .get("config_clock_frequency")
+                .and_then(|hz| parse_u64(hz).ok())
+                .filter(|&hz| hz > 0),
+        })
+    }
+
+    pub fn sample(&self, map: &CsrMap, bridge: &Bridge) -> Result<TargetTime, CsrMapError> {
+        map.write_register(bridge, &self.latch, 1)?;
+        Ok(TargetTime {
+            cycles: map.read_register(bridge, &self.cycles)?,
+            frequency: self.frequency,
+        })
+    }
+}
+
+impl fmt::Display for Uptime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.cycles.trim_end_matches("_cycles"))?;
+        if let Some(hz) = self.frequency {
+            write!(f, " at {} Hz", hz)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_arg(arg: &str) -> Result<u32, ClockError> {
+    parse_u32(arg).map_err(|_| ClockError::InvalidArguments(format!("invalid number: {}", arg)))
+}
+
+/// Handle `monitor clockspeed [interval_ms]` and
+/// `monitor clockspeed timer <value_addr> [latch_addr] [interval_ms]`.
+pub fn monitor_clockspeed(
+    cpu: &RiscvCpu,
+    bridge: &Bridge,
+    args: &[&str],
+) -> Result<String, ClockError> {
+    if args.first() == Some(&"timer") {
+        let value_addr = match args.get(1) {
+            Some(a) => parse_arg(a)?,
+            None => {
+                return Err(ClockError::InvalidArguments(
+                    "usage: clockspeed timer <value_addr> [latch_addr] [interval_ms]".to_owned(),
+                ))
+            }
+        };
+        let latch_addr = match args.get(2) {
+            Some(a) => Some(parse_arg(a)?),
+            None => None,
+        };
+        let interval = match args.get(3) {
+            Some(a) => parse_arg(a)?,
+            None => DEFAULT_INTERVAL_MS,
+        };
+        let m = measure_timer(
+            bridge,
+            value_addr,
+            latch_addr,
+            Duration::from_millis(interval as u64),
+        )?;
+        Ok(m.to_string())
+    } else {
+        let interval = match args.first() {
+            Some(a) => parse_arg(a)?,
+            None => DEFAULT_INTERVAL_MS,
+        };
+        let m = measure_mcycle(cpu, bridge, Duration::from_millis(interval as u64))?;
+        Ok(m.to_string())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{TargetTime, Uptime};
+    use crate::csr_map::CsrMap;
+
+    #[test]
+    fn detect_uptime() {
+        let map = CsrMap::parse(
+            "csr_register,timer0_uptime_latch,0xf0002820,1,rw\n\
+             csr_register,timer0_uptime_cycles,0xf0002824,2,ro\n\
+             constant,config_clock_frequency,100000000,,\n",
+        )
+        .unwrap();
+        let uptime = Uptime::detect(&map).unwrap();
+        assert_eq!(uptime.latch, "timer0_uptime_latch");
+        assert_eq!(uptime.to_string(), "timer0_uptime at 100000000 Hz");
+
+        let no_latch = CsrMap::parse("csr_register,timer0_uptime_cycles,0xf0002824,2,ro\n");
+        assert!(Uptime::detect(&no_latch.unwrap()).is_none());
+    }
+
+    #[test]
+    fn target_time_display() {
+        let time = |cycles, frequency| TargetTime { cycles, frequency };
+        assert_eq!(
+            time(250_000_000, Some(100_000_000)).to_string(),
+            "2.500000s"
+        );
+        assert_eq!(time(12_345, Some(1_000_000)).to_string(), "0.012345s");
+        assert_eq!(
+            time(u64::MAX, Some(3)).to_string(),
+            "6148914691236517205.000000s"
+        );
+        assert_eq!(time(42, None).to_string(), "42 cycles");
+    }
+}
@@ -1,15 +1,206 @@
+use std::time::Duration;
+
 use clap::ArgMatches;
-use super::bridge::BridgeKind;
+use super::board::{self, Board, FlashGeometry, MemoryKind, MemoryRegion, SpiFlashRegisters};
+use super::bootmode::BootControl;
+use super::bridge::{BridgeBackend, BridgeKind};
+use super::csr_map::CsrMap;
+use super::doorbell::Doorbell;
+use super::events::EventSink;
+use super::expr::{self, ExprError};
+use super::fault_bridge::FaultConfig;
+use super::framebuffer::Framebuffer;
+use super::image::ImageHash;
+use super::power::PowerSpec;
+use super::ui::{OutputFormat, Verbosity};
 use super::utils::{parse_u16, parse_u32};
+use super::virtual_register::VirtualRegister;
 
 pub struct Config {
     pub usb_pid: Option<u16>,
     pub usb_vid: Option<u16>,
+
+    /// Turn off the kernel's autosuspend for the USB device, as asked for
+    /// with `--no-autosuspend`
+    pub no_autosuspend: bool,
     pub memory_address: Option<u32>,
     pub memory_value: Option<u32>,
     pub bridge_kind: BridgeKind,
+    pub bridge_backend: BridgeBackend,
+    pub fault_injection: Option<FaultConfig>,
+    pub power: Option<PowerSpec>,
+    pub power_cycle_on_start: bool,
+
+    /// Where to send the JSON event stream, given with `--events`
+    pub events: Option<EventSink>,
+
+    /// The word firmware writes to get the adapter's attention
+    pub doorbell: Option<Doorbell>,
+
+    /// The register that says where the SoC boots from
+    pub boot_control: Option<BootControl>,
+    pub mmap_file: Option<String>,
+    pub mmap_base: u32,
+    pub etherbone: Option<String>,
+    pub etherbone_timeout: Duration,
+    pub etherbone_keepalive: Option<Duration>,
+    pub serial: Option<String>,
+    pub serial_baud: u32,
+    pub gdb_rle: bool,
+    pub gdb_escaping: bool,
+    pub gdb_pty: bool,
+    pub gdb_proxy: Option<String>,
+    pub file_agent: Option<u32>,
+    pub scratch: Option<(u32, u32)>,
+    pub proxy_ranges: Vec<(u32, u32)>,
+    pub hart_debug_offsets: Vec<u32>,
+    pub smp_groups: Vec<Vec<usize>>,
+    pub memory_map: Vec<MemoryRegion>,
+
+    /// Where a hardware breakpoint may become a software one when the
+    /// comparators run out.  Empty if it never may.
+    pub breakpoint_fallback: Vec<MemoryRegion>,
+
+    /// Variables in memory to show GDB as registers
+    pub virtual_registers: Vec<VirtualRegister>,
+    pub flash: Option<FlashGeometry>,
+
+    /// How to program the flash, from csr.csv
+    pub spiflash: Option<SpiFlashRegisters>,
+    pub load_file: Option<String>,
+    pub load_hash: Option<ImageHash>,
+
+    /// Framebuffer to save as a PNG, or to show over HTTP
+    pub framebuffer: Option<Framebuffer>,
+
+    /// Where to save the framebuffer
+    pub framebuffer_png: String,
+
+    /// Where to keep track of how far `--load` got, so a failed load can
+    /// be carried on
+    pub load_state: Option<String>,
+    pub manifest_address: Option<u32>,
+    pub manifest_key: Option<String>,
+    pub verify_manifest: Option<u32>,
+    pub csr_csv: Option<String>,
+    pub force: bool,
+    pub wait_for_flash: bool,
+    pub profile: Profile,
+    pub tuning: Tuning,
+    pub compare_csr: Option<(String, String)>,
+    pub compare_steps: Option<(String, String)>,
+    pub restore_session: Option<String>,
+    pub kernel_symbols: Option<String>,
+    pub emit_gdbinit: Option<String>,
+    pub kernel: Option<String>,
+    pub kernel_address: u32,
     pub bind_addr: String,
     pub bind_port: u32,
+    pub bus_data_width: u32,
+    pub bus_big_endian: bool,
+    pub log_gdb: Option<String>,
+    pub log_bridge: Option<String>,
+    pub trace_vcd: Option<String>,
+    pub log_terminal: Option<String>,
+    pub log_adapter: Option<String>,
+    pub log_max_size: u64,
+    pub log_keep: u32,
+
+    /// Where the last bridge transactions are written when something
+    /// fails for good, if they're kept at all, and how many of them to keep
+    pub postmortem: Option<String>,
+    pub postmortem_depth: usize,
+
+    pub verbosity: Verbosity,
+    pub output_format: OutputFormat,
+}
+
+/// How the adapter trades latency against throughput
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Profile {
+    /// Batch and cache where it helps, without going out of the way for
+    /// either
+    Balanced,
+
+    /// Answer each request as soon as possible, for single-stepping by
+    /// hand.  Nothing is held back to be sent with something else, and
+    /// nothing is read that wasn't asked for.
+    LowLatency,
+
+    /// Move as much data as possible, for bulk loads and dumps.  Bursts
+    /// are as big as the backend allows and idle loops poll less often.
+    Throughput,
+}
+
+impl Profile {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Profile::Balanced => "balanced",
+            Profile::LowLatency => "low-latency",
+            Profile::Throughput => "throughput",
+        }
+    }
+}
+
+/// The bridge scheduling parameters a profile sets.  They're chosen
+/// together, since each one on its own only moves the bottleneck: small
+/// bursts do little for latency if replies then sit in a socket buffer
+/// or a poll loop.
+pub struct Tuning {
+    /// The most bytes to put in one burst, below what the backend allows.
+    /// `None` leaves it to the backend.
+    pub burst_limit: Option<usize>,
+
+    /// Keep registers and memory read while a hart is halted, so GDB
+    /// asking again doesn't go over the bridge
+    pub caching: bool,
+
+    /// Read the code around pc and the top of the stack when a hart halts
+    pub prefetch: bool,
+
+    /// Turn off Nagle's algorithm on server sockets, so short replies go
+    /// out straight away
+    pub nodelay: bool,
+
+    /// How long loops waiting on the target sleep when there's nothing to
+    /// do
+    pub poll_interval: Duration,
+
+    /// How long to wait before looking for a device again after it went
+    /// away
+    pub reconnect_interval: Duration,
+}
+
+impl Tuning {
+    pub fn new(profile: Profile) -> Tuning {
+        match profile {
+            Profile::Balanced => Tuning {
+                burst_limit: None,
+                caching: true,
+                prefetch: true,
+                nodelay: false,
+                poll_interval: Duration::from_millis(10),
+                reconnect_interval: Duration::from_millis(500),
+            },
+            Profile::LowLatency => Tuning {
+                burst_limit: Some(4),
+                caching: false,
+                prefetch: false,
+                nodelay: true,
+                poll_interval: Duration::from_millis(1),
+                reconnect_interval: Duration::from_millis(50),
+            },
+            // Prefetching after each halt only slows down scripted loads
+            Profile::Throughput => Tuning {
+                burst_limit: None,
+                caching: true,
+                prefetch: false,
+                nodelay: false,
+                poll_interval: Duration::from_millis(50),
+                reconnect_interval: Duration::from_millis(500),
+            },
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -19,6 +210,41 @@ pub enum ConfigError {
 
     /// Specified a bridge kind that we didn't recognize
     UnknownBridgeKind(String),
+
+    /// The fault injection specification couldn't be parsed
+    InvalidFaultSpec(String),
+
+    /// An image hash wasn't `ALGORITHM:HEX` with an algorithm we know
+    InvalidHashSpec(String),
+
+    /// A power switch wasn't one of the kinds we know
+    InvalidPowerSpec(String),
+
+    /// `--events` wasn't `unix:PATH` or `tcp:[HOST:]PORT`
+    InvalidEventSink(String),
+
+    /// `--doorbell` asked for an action we don't know
+    InvalidDoorbell(String),
+
+    /// `--boot-register` didn't name a register we can boot with, or gave
+    /// an address for a mode we don't know
+    InvalidBootRegister(String),
+
+    /// An address or value on the command line didn't evaluate
+    InvalidExpression(ExprError),
+
+    /// `--framebuffer` wasn't given a size and format we understand
+    InvalidFramebuffer(String),
+
+    /// `--virtual-register` wasn't `NAME=ADDR[:BITS]`, or named a register
+    /// that already exists
+    InvalidVirtualRegister(String),
+
+    /// A region named on the command line isn't in the memory map
+    UnknownRegion(String),
+
+    /// The arguments given to `from_args` weren't ones the adapter takes
+    InvalidArguments(clap::Error),
 }
 
 impl std::convert::From<std::num::ParseIntError> for ConfigError {
@@ -27,54 +253,494 @@ impl std::convert::From<std::num::ParseIntError> for ConfigError {
     }
 }
 
+impl std::convert::From<ExprError> for ConfigError {
+    fn from(e: ExprError) -> Self {
+        ConfigError::InvalidExpression(e)
+    }
+}
+
 impl Config {
+    /// Build a configuration from the same arguments the adapter takes on
+    /// its command line, for programs that embed it.  The first argument
+    /// is the program name, as it is in `std::env::args()`.
+    pub fn from_args<I, T>(args: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<std::ffi::OsString> + Clone,
+    {
+        let matches = super::cli::app()
+            .get_matches_from_safe(args)
+            .map_err(ConfigError::InvalidArguments)?;
+        Config::parse(matches)
+    }
+
     pub fn parse(matches: ArgMatches) -> Result<Self, ConfigError> {
-        let usb_vid = if let Some(vid) = matches.value_of("vid") {
+        // A board preset fills in anything that wasn't given explicitly
+        let board = matches.value_of("board").and_then(Board::by_name);
+        let explicit = |name| matches.occurrences_of(name) > 0;
+
+        let usb_vid = if let (false, Some(board)) = (explicit("vid"), &board) {
+            Some(board.usb_vid)
+        } else if let Some(vid) = matches.value_of("vid") {
             Some(parse_u16(vid)?)
         } else {
             None
         };
 
-        let usb_pid = if let Some(pid) = matches.value_of("pid") {
+        let usb_pid = if let (false, Some(board)) = (explicit("pid"), &board) {
+            Some(board.usb_pid)
+        } else if let Some(pid) = matches.value_of("pid") {
             Some(parse_u16(pid)?)
         } else {
             None
         };
 
-        let memory_address = if let Some(addr) = matches.value_of("address") {
+        let no_autosuspend = matches.is_present("no-autosuspend");
+
+        let bind_port = if let Some(port) = matches.value_of("port") {
+            parse_u32(port)?
+        } else {
+            3333
+        };
+
+        let bind_addr = if let Some(addr) = matches.value_of("bind-addr") {
+            addr.to_owned()
+        } else {
+            "127.0.0.1".to_owned()
+        };
+
+        let bus_data_width = if let Some(width) = matches.value_of("bus-width") {
+            parse_u32(width)?
+        } else {
+            32
+        };
+
+        let bus_big_endian = matches.value_of("bus-endian") == Some("big");
+
+        let kernel = matches.value_of("kernel").map(|s| s.to_owned());
+
+        let kernel_address = if let Some(addr) = matches.value_of("kernel-adr") {
+            parse_u32(addr)?
+        } else {
+            0x4000_0000
+        };
+
+        // Booting a kernel needs the terminal, so that's the default then
+        let bridge_kind = if kernel.is_some() && !matches.is_present("bridge-kind") {
+            BridgeKind::Terminal
+        } else {
+            BridgeKind::from_string(&matches.value_of("bridge-kind"))?
+        };
+
+        let mmap_file = matches.value_of("mmap-file").map(|s| s.to_owned());
+
+        let mmap_base = if let Some(base) = matches.value_of("mmap-base") {
+            parse_u32(base)?
+        } else {
+            0
+        };
+
+        let etherbone = matches.value_of("etherbone").map(|s| s.to_owned());
+
+        let etherbone_timeout = if let Some(ms) = matches.value_of("etherbone-timeout") {
+            Duration::from_millis(parse_u32(ms)? as u64)
+        } else {
+            Duration::from_millis(500)
+        };
+
+        // A keep-alive interval of zero turns them off
+        let etherbone_keepalive = if let Some(secs) = matches.value_of("etherbone-keepalive") {
+            match parse_u32(secs)? {
+                0 => None,
+                secs => Some(Duration::from_secs(secs as u64)),
+            }
+        } else {
+            Some(Duration::from_secs(10))
+        };
+
+        let serial = matches.value_of("serial").map(|s| s.to_owned());
+
+        let serial_baud = if let Some(baud) = matches.value_of("baud") {
+            parse_u32(baud)?
+        } else {
+            115_200
+        };
+
+        let bridge_backend = if matches.is_present("mock") {
+            BridgeBackend::Mock
+        } else if mmap_file.is_some() {
+            BridgeBackend::Mmap
+        } else if etherbone.is_some() {
+            BridgeBackend::Etherbone
+        } else if serial.is_some() {
+            BridgeBackend::Serial
+        } else {
+            match matches.value_of("usb-backend") {
+                Some("usbfs") => BridgeBackend::Usbfs,
+                Some(_) => BridgeBackend::Usb,
+                None if cfg!(feature = "usb") => BridgeBackend::Usb,
+                None => BridgeBackend::Usbfs,
+            }
+        };
+
+        let fault_injection = if let Some(spec) = matches.value_of("inject-faults") {
+            Some(FaultConfig::parse(spec)?)
+        } else {
+            None
+        };
+
+        let power = if let Some(spec) = matches.value_of("power") {
+            Some(PowerSpec::parse(spec)?)
+        } else {
+            None
+        };
+        let power_cycle_on_start = matches.is_present("power-cycle-on-start");
+
+        let events = match matches.value_of("events") {
+            Some(spec) => Some(EventSink::parse(spec)?),
+            None => None,
+        };
+
+        let gdb_rle = !matches.is_present("no-rle");
+        let gdb_escaping = !matches.is_present("no-escape");
+
+        let gdb_pty = matches.is_present("gdb-pty");
+        let force = matches.is_present("force");
+        let wait_for_flash = matches.is_present("wait-for-flash");
+
+        let profile = if matches.is_present("low-latency") {
+            Profile::LowLatency
+        } else if matches.is_present("throughput") {
+            Profile::Throughput
+        } else {
+            Profile::Balanced
+        };
+        let mut tuning = Tuning::new(profile);
+        if matches.is_present("no-prefetch") {
+            tuning.prefetch = false;
+        }
+
+        let gdb_proxy = matches.value_of("gdb-proxy").map(|s| s.to_owned());
+        let mut proxy_ranges = vec![];
+        if let Some(ranges) = matches.value_of("proxy-range") {
+            for range in ranges.split(',') {
+                let mut fields = range.splitn(2, ':');
+                let base = parse_u32(fields.next().unwrap_or_default())?;
+                let size = parse_u32(fields.next().unwrap_or_default())?;
+                proxy_ranges.push((base, size));
+            }
+        }
+
+        let file_agent = if let Some(addr) = matches.value_of("file-agent") {
             Some(parse_u32(addr)?)
         } else {
             None
         };
 
-        let memory_value = if let Some(v) = matches.value_of("value") {
-            Some(parse_u32(v)?)
+        let scratch = if let Some(region) = matches.value_of("scratch") {
+            let mut fields = region.splitn(2, ':');
+            let base = parse_u32(fields.next().unwrap_or_default())?;
+            let size = parse_u32(fields.next().unwrap_or_default())?;
+            Some((base, size))
         } else {
             None
         };
 
-        let bind_port = if let Some(port) = matches.value_of("port") {
-            parse_u32(port)?
+        let mut hart_debug_offsets = vec![];
+        if let (false, Some(board)) = (explicit("debug-offset"), &board) {
+            hart_debug_offsets.push(board.debug_offset);
+        } else if let Some(offsets) = matches.value_of("debug-offset") {
+            for offset in offsets.split(',') {
+                hart_debug_offsets.push(parse_u32(offset)?);
+            }
+        }
+        if hart_debug_offsets.is_empty() {
+            hart_debug_offsets.push(0xf00f_0000);
+        }
+
+        let mut smp_groups = vec![];
+        if let Some(groups) = matches.values_of("smp-group") {
+            for group in groups {
+                let mut harts = vec![];
+                for hart in group.split(',') {
+                    harts.push(parse_u32(hart)? as usize);
+                }
+                smp_groups.push(harts);
+            }
+        }
+
+        let csr_csv = matches.value_of("csr-csv").map(|s| s.to_owned());
+        // A csr.csv that can't be loaded is reported by the startup checks
+        let csr_map = csr_csv.as_deref().and_then(|path| CsrMap::load(path).ok());
+
+        // Without a board, csr.csv says where memory is
+        let (memory_map, flash) = match (board, &csr_map) {
+            (Some(board), _) => (board.memory_map, board.flash),
+            (None, Some(map)) => (map.memory_map(), None),
+            (None, None) => (vec![], None),
+        };
+        let spiflash = csr_map.as_ref().and_then(CsrMap::spiflash_registers);
+
+        let breakpoint_fallback = match matches.value_of("breakpoint-fallback") {
+            Some(names) => fallback_regions(names, &memory_map)?,
+            None => vec![],
+        };
+
+        // The address and value can be expressions using names from
+        // csr.csv or the memory map
+        let lookup = |name: &str| {
+            csr_map
+                .as_ref()
+                .and_then(|map| map.lookup(name))
+                .or_else(|| board::region_symbol(&memory_map, name).map(|v| v as u64))
+        };
+        let memory_address = match matches.value_of("address") {
+            Some(addr) => Some(expr::eval_u32(addr, &lookup)?),
+            None => None,
+        };
+        let memory_value = match matches.value_of("value") {
+            Some(v) => Some(expr::eval_u32(v, &lookup)?),
+            None => None,
+        };
+
+        let mut virtual_registers: Vec<VirtualRegister> = vec![];
+        for spec in matches.values_of("virtual-register").into_iter().flatten() {
+            let reg = VirtualRegister::parse(spec, &lookup)?;
+            if virtual_registers.iter().any(|r| r.name == reg.name) {
+                return Err(ConfigError::InvalidVirtualRegister(format!(
+                    "{}: given more than once",
+                    reg.name
+                )));
+            }
+            virtual_registers.push(reg);
+        }
+
+        let doorbell = match matches.value_of("doorbell") {
+            Some(spec) => Some(Doorbell::parse(spec, &lookup, csr_map.as_ref())?),
+            None => None,
+        };
+
+        let boot_control = match matches.value_of("boot-register") {
+            Some(spec) => Some(BootControl::parse(
+                spec,
+                &lookup,
+                csr_map.as_ref(),
+                &memory_map,
+            )?),
+            None => None,
+        };
+
+        let load_file = matches.value_of("load").map(|s| s.to_owned());
+        let framebuffer = match matches.values_of("framebuffer") {
+            Some(values) => {
+                let values: Vec<&str> = values.collect();
+                let addr = expr::eval_u32(values[0], &lookup)?;
+                Some(Framebuffer::parse(addr, values[1], values[2])?)
+            }
+            None => None,
+        };
+        let framebuffer_png = matches
+            .value_of("framebuffer-png")
+            .unwrap_or("framebuffer.png")
+            .to_owned();
+        let load_state = matches.value_of("load-state").map(|s| s.to_owned());
+        let load_hash = match matches.value_of("load-hash") {
+            Some(spec) => Some(ImageHash::parse(spec)?),
+            None => None,
+        };
+        let manifest_address = match matches.value_of("manifest") {
+            Some(addr) => Some(parse_u32(addr)?),
+            None => None,
+        };
+        let manifest_key = matches.value_of("manifest-key").map(|s| s.to_owned());
+        let verify_manifest = match matches.value_of("verify-manifest") {
+            Some(addr) => Some(parse_u32(addr)?),
+            None => None,
+        };
+
+        let compare_csr = matches.values_of("compare-csr").map(|mut files| {
+            let old = files.next().unwrap_or_default().to_owned();
+            let new = files.next().unwrap_or_default().to_owned();
+            (old, new)
+        });
+
+        let compare_steps = matches.values_of("compare-steps").map(|mut files| {
+            let old = files.next().unwrap_or_default().to_owned();
+            let new = files.next().unwrap_or_default().to_owned();
+            (old, new)
+        });
+
+        let restore_session = matches.value_of("restore-session").map(|s| s.to_owned());
+
+        let kernel_symbols = matches.value_of("kernel-symbols").map(|s| s.to_owned());
+
+        let emit_gdbinit = matches.value_of("emit-gdbinit").map(|s| s.to_owned());
+
+        let log_gdb = matches.value_of("log-gdb").map(|s| s.to_owned());
+        let log_bridge = matches.value_of("log-bridge").map(|s| s.to_owned());
+        let trace_vcd = matches.value_of("trace-vcd").map(|s| s.to_owned());
+        let log_terminal = matches.value_of("log-terminal").map(|s| s.to_owned());
+        let log_adapter = matches.value_of("log-adapter").map(|s| s.to_owned());
+
+        let log_max_size = if let Some(size) = matches.value_of("log-max-size") {
+            parse_u32(size)? as u64
         } else {
-            3333
+            0
         };
 
-        let bind_addr = if let Some(addr) = matches.value_of("bind-addr") {
-            addr.to_owned()
+        let log_keep = if let Some(keep) = matches.value_of("log-keep") {
+            parse_u32(keep)?
         } else {
-            "127.0.0.1".to_owned()
+            0
+        };
+
+        let postmortem = matches.value_of("postmortem").map(|s| s.to_owned());
+        let postmortem_depth = match matches.value_of("postmortem-depth") {
+            Some(depth) => parse_u32(depth)? as usize,
+            None => 0,
         };
 
-        let bridge_kind = BridgeKind::from_string(&matches.value_of("bridge-kind"))?;
+        let verbosity = if matches.is_present("quiet") {
+            Verbosity::Quiet
+        } else {
+            match matches.occurrences_of("verbose") {
+                0 => Verbosity::Normal,
+                1 => Verbosity::Verbose,
+                _ => Verbosity::Debug,
+            }
+        };
+
+        let output_format = match matches.value_of("output-format") {
+            Some("machine") => OutputFormat::Machine,
+            _ => OutputFormat::Human,
+        };
 
         Ok(Config {
             usb_pid,
             usb_vid,
+            no_autosuspend,
             memory_address,
             memory_value,
             bridge_kind,
+            bridge_backend,
+            fault_injection,
+            power,
+            power_cycle_on_start,
+            events,
+            doorbell,
+            boot_control,
+            mmap_file,
+            mmap_base,
+            etherbone,
+            etherbone_timeout,
+            etherbone_keepalive,
+            serial,
+            serial_baud,
+            gdb_rle,
+            gdb_escaping,
+            gdb_pty,
+            gdb_proxy,
+            file_agent,
+            scratch,
+            proxy_ranges,
+            hart_debug_offsets,
+            smp_groups,
+            memory_map,
+            breakpoint_fallback,
+            virtual_registers,
+            flash,
+            spiflash,
+            load_file,
+            load_hash,
+            load_state,
+            framebuffer,
+            framebuffer_png,
+            manifest_address,
+            manifest_key,
+            verify_manifest,
+            csr_csv,
+            force,
+            wait_for_flash,
+            profile,
+            tuning,
+            compare_csr,
+            compare_steps,
+            restore_session,
+            kernel_symbols,
+            emit_gdbinit,
+            kernel,
+            kernel_address,
             bind_port,
+            bus_data_width,
+            bus_big_endian,
             bind_addr,
+            log_gdb,
+            log_bridge,
+            trace_vcd,
+            log_terminal,
+            log_adapter,
+            log_max_size,
+            log_keep,
+            postmortem,
+            postmortem_depth,
+            verbosity,
+            output_format,
         })
     }
-}
\ No newline at end of file
+}
+
+/// The regions named in `--breakpoint-fallback`, separated by commas.
+/// "ram" stands for every RAM region.
+fn fallback_regions(
+    names: &str,
+    memory_map: &[MemoryRegion],
+) -> Result<Vec<MemoryRegion>, ConfigError> {
+    let mut fallback = vec![];
+    for name in names.split(',') {
+        let regions: Vec<&MemoryRegion> = memory_map
+            .iter()
+            .filter(|r| r.name == name || (name == "ram" && r.kind == MemoryKind::Ram))
+            .collect();
+        if regions.is_empty() {
+            return Err(ConfigError::UnknownRegion(name.to_owned()));
+        }
+        fallback.extend(regions.into_iter().cloned());
+    }
+    Ok(fallback)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{fallback_regions, ConfigError};
+    use crate::board::{MemoryKind, MemoryRegion};
+
+    #[test]
+    fn breakpoint_fallback_regions() {
+        let region = |name: &str, base, kind| MemoryRegion {
+            name: name.to_owned(),
+            base,
+            size: 0x1000,
+            kind,
+        };
+        let map = [
+            region("rom", 0, MemoryKind::Rom),
+            region("sram", 0x1000_0000, MemoryKind::Ram),
+            region("main_ram", 0x4000_0000, MemoryKind::Ram),
+        ];
+        let names = |names: &str| -> Vec<String> {
+            fallback_regions(names, &map)
+                .unwrap()
+                .into_iter()
+                .map(|r| r.name)
+                .collect()
+        };
+        assert_eq!(names("sram"), vec!["sram"]);
+        assert_eq!(names("ram"), vec!["sram", "main_ram"]);
+        assert_eq!(names("main_ram,rom"), vec!["main_ram", "rom"]);
+        match fallback_regions("sram,flash", &map) {
+            Err(ConfigError::UnknownRegion(name)) => assert_eq!(name, "flash"),
+            other => panic!("{:?}", other.map(|r| r.len())),
+        }
+        assert!(fallback_regions("", &map).is_err());
+    }
+}
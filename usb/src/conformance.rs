@@ -0,0 +1,318 @@
+//! The GDB server checked against the remote serial protocol as GDB's
+//! manual lays it out, over the same stream `embed` gives other programs.
+//! The mock bridge has a model of one halted hart behind it, whose
+//! registers and memory hold whatever the tests left there.  What's checked
+//! is the shape of each reply: how it's framed and encoded, and whether
+//! it's the `OK`, the `E NN`, the empty reply or the data the spec calls
+//! for.
+
+use std::io::{Read, Write};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use futures::io::AllowStdIo;
+
+use crate::bridge::Bridge;
+use crate::config::Config;
+use crate::embed::pipe::{pipe, PipeReader, PipeWriter};
+use crate::embed::serve_gdb;
+use crate::mock_cpu::MockHart;
+use crate::rsp;
+
+/// The kind of reply the spec calls for
+#[derive(Debug)]
+enum Reply {
+    /// Nothing, which tells GDB the packet isn't supported
+    Empty,
+    Ok,
+
+    /// `E` and two hex digits, or `E.` and a message
+    Error,
+    Is(&'static str),
+    Prefix(&'static str),
+
+    /// Pairs of hex digits, as memory and registers are sent
+    Hex,
+
+    /// A stop reply: `S` or `T` and a signal number, or `W` or `X` and an
+    /// exit status
+    Stop,
+}
+
+impl Reply {
+    fn matches(&self, reply: &[u8]) -> bool {
+        let is_hex = |digits: &[u8]| digits.iter().all(|b| b.is_ascii_hexdigit());
+        match self {
+            Reply::Empty => reply.is_empty(),
+            Reply::Ok => reply == b"OK",
+            Reply::Error => match reply {
+                [b'E', b'.', ..] => true,
+                [b'E', digits @ ..] => digits.len() == 2 && is_hex(digits),
+                _ => false,
+            },
+            Reply::Is(text) => reply == text.as_bytes(),
+            Reply::Prefix(text) => reply.starts_with(text.as_bytes()),
+            Reply::Hex => !reply.is_empty() && reply.len().is_multiple_of(2) && is_hex(reply),
+            Reply::Stop => match reply {
+                [b'S' | b'T' | b'W' | b'X', a, b, ..] => is_hex(&[*a, *b]),
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Packets that can be sent in any order to a halted target.  Later ones
+/// also show that the malformed ones before them didn't end the session.
+const CASES: &[(&[u8], Reply)] = &[
+    // Anything not understood gets an empty reply, and GDB itself checks
+    // that vMustReplyEmpty does
+    (b"vMustReplyEmpty", Reply::Empty),
+    (b"qNoSuchQuery", Reply::Empty),
+    (b"QNoSuchSetting:1", Reply::Empty),
+    (b"qTStatus", Reply::Empty),
+    (b"Z9,40000000,4", Reply::Empty),
+    (b"z9,40000000,4", Reply::Empty),
+    // Status and threads
+    (b"?", Reply::Stop),
+    (b"qAttached", Reply::Is("1")),
+    (b"qC", Reply::Prefix("QC")),
+    (b"qfThreadInfo", Reply::Prefix("m")),
+    (b"qsThreadInfo", Reply::Is("l")),
+    (b"Hg0", Reply::Ok),
+    (b"Hc-1", Reply::Ok),
+    (b"vCont?", Reply::Prefix("vCont;")),
+    (b"qOffsets", Reply::Prefix("Text=")),
+    (b"qSymbol::", Reply::Ok),
+    // Registers
+    (b"g", Reply::Hex),
+    (b"p20", Reply::Hex),
+    (b"p1", Reply::Hex),
+    (b"p7fffffff", Reply::Error),
+    (b"pzz", Reply::Error),
+    (b"P1=0102", Reply::Error),
+    // Memory
+    (b"m40000000,4", Reply::Hex),
+    (b"M40000000,4:01020304", Reply::Ok),
+    (b"X40000000,0:", Reply::Ok),
+    (b"m40000000", Reply::Error),
+    (b"mzz,4", Reply::Error),
+    (b"M40000000,4:010203", Reply::Error),
+    (b"M40000000,2:zzzz", Reply::Error),
+    (b"X40000000,4:\x01", Reply::Error),
+    // Breakpoints
+    (b"Z0,40000000,4", Reply::Ok),
+    (b"z0,40000000,4", Reply::Ok),
+    (b"Z0,40000000", Reply::Error),
+    (b"Z0", Reply::Error),
+    // Transfers
+    (b"qXfer:features:read:target.xml:0,20", Reply::Prefix("m")),
+    (b"qXfer:features:read:no-such.xml:0,20", Reply::Error),
+    (b"qXfer:features:read:target.xml", Reply::Error),
+    (b"qXfer:threads:read::0", Reply::Error),
+    // Still here after all of that
+    (b"qAttached", Reply::Is("1")),
+];
+
+/// A GDB speaking to the server over a pair of pipes
+struct Client {
+    from_server: PipeReader,
+    to_server: PipeWriter,
+    server: JoinHandle<bool>,
+    acks: bool,
+}
+
+impl Client {
+    fn start() -> Client {
+        let cfg = Config::from_args(["conformance", "--mock"]).unwrap();
+        let (server_in, to_server) = pipe();
+        let (mut from_server, server_out) = pipe();
+        let server = thread::spawn(move || {
+            let bridge = Bridge::new(&cfg).unwrap();
+            bridge.connect().unwrap();
+            MockHart::new(0x4000_0100, 4).attach(&bridge);
+            serve_gdb(
+                &cfg,
+                &bridge,
+                AllowStdIo::new(server_in),
+                AllowStdIo::new(server_out),
+            )
+            .is_ok()
+        });
+        from_server.set_timeout(Duration::from_secs(10));
+        Client {
+            from_server,
+            to_server,
+            server,
+            acks: true,
+        }
+    }
+
+    fn byte(&mut self) -> u8 {
+        let mut byte = [0; 1];
+        self.from_server.read_exact(&mut byte).unwrap();
+        byte[0]
+    }
+
+    fn send_raw(&mut self, bytes: &[u8]) {
+        self.to_server.write_all(bytes).unwrap();
+    }
+
+    /// Read a packet, checking it's framed and run-length encoded as the
+    /// spec says, and return what it decodes to
+    fn packet(&mut self) -> Vec<u8> {
+        assert_eq!(self.byte(), b'$', "a reply has to start with $");
+        let mut body = vec![];
+        loop {
+            match self.byte() {
+                b'#' => break,
+                b'$' => panic!("raw $ in {:?}", String::from_utf8_lossy(&body)),
+                byte => body.push(byte),
+            }
+        }
+        let checksum = [self.byte(), self.byte()];
+        let checksum = std::str::from_utf8(&checksum).unwrap();
+        assert_eq!(
+            u8::from_str_radix(checksum, 16).ok(),
+            Some(rsp::checksum(&body)),
+            "bad checksum on {:?}",
+            String::from_utf8_lossy(&body)
+        );
+        // A run count has to be printable and can't be # or $, which a
+        // body ending in * would amount to
+        for (idx, _) in body.iter().enumerate().filter(|(_, &b)| b == b'*') {
+            if idx > 0 && body[idx - 1] != b'}' {
+                let count = *body.get(idx + 1).expect("run with no count");
+                assert!((29 + 3..=126).contains(&count), "bad run count {}", count);
+            }
+        }
+        if self.acks {
+            self.send_raw(b"+");
+        }
+        rsp::rle_decode(&body)
+    }
+
+    /// Send a packet and return the reply
+    fn request(&mut self, packet: &[u8]) -> Vec<u8> {
+        self.send_raw(&rsp::frame(packet));
+        if self.acks {
+            assert_eq!(self.byte(), b'+', "{:?} wasn't acked", packet);
+        }
+        self.packet()
+    }
+
+    /// Hang up, which the server should take as the end of the session
+    fn finish(self) {
+        drop(self.to_server);
+        assert!(self.server.join().unwrap());
+    }
+}
+
+#[test]
+fn packets() {
+    let mut client = Client::start();
+    let mut wrong = vec![];
+    for (packet, reply) in CASES {
+        let got = client.request(packet);
+        if !reply.matches(&got) {
+            wrong.push(format!(
+                "{:?}: wanted {:?}, got {:?}",
+                String::from_utf8_lossy(packet),
+                reply,
+                String::from_utf8_lossy(&got)
+            ));
+        }
+    }
+    client.finish();
+    assert!(wrong.is_empty(), "\n{}", wrong.join("\n"));
+}
+
+#[test]
+fn acks() {
+    let mut client = Client::start();
+
+    // A corrupted packet is NAKed, and goes through when it's sent again
+    client.send_raw(b"$qAttached#00");
+    assert_eq!(client.byte(), b'-');
+    assert_eq!(client.request(b"qAttached"), b"1");
+
+    // A packet too long to take is acked and refused, not NAKed, since
+    // GDB would only send it again
+    assert_eq!(
+        client.request(&vec![b'X'; rsp::MAX_PACKET_SIZE + 1])[..2],
+        *b"E."
+    );
+
+    // Stray acks between packets are ignored
+    client.send_raw(b"++-");
+    assert_eq!(client.request(b"qAttached"), b"1");
+
+    // QStartNoAckMode is itself acked, and nothing after it is
+    assert_eq!(client.request(b"QStartNoAckMode"), b"OK");
+    client.acks = false;
+    assert_eq!(client.request(b"qAttached"), b"1");
+    assert_eq!(client.request(b"vMustReplyEmpty"), b"");
+
+    // A new qSupported starts over with acks, as a new GDB would expect
+    client.acks = true;
+    assert!(client.request(b"qSupported").starts_with(b"PacketSize="));
+    assert_eq!(client.request(b"qAttached"), b"1");
+    client.finish();
+}
+
+#[test]
+fn supported_features() {
+    let mut client = Client::start();
+    let reply = client.request(b"qSupported:multiprocess+;swbreak+;xmlRegisters=i386;fork-events?");
+    let reply = String::from_utf8(reply).unwrap();
+    for feature in reply.split(';') {
+        let well_formed = match feature.split_once('=') {
+            Some((name, value)) => !name.is_empty() && !value.is_empty(),
+            None => feature.len() > 1 && feature.ends_with(&['+', '-', '?'][..]),
+        };
+        assert!(well_formed, "{:?} in {:?}", feature, reply);
+    }
+    let packet_size = reply
+        .split(';')
+        .find_map(|f| f.strip_prefix("PacketSize="))
+        .expect("PacketSize is required");
+    assert_eq!(
+        usize::from_str_radix(packet_size, 16),
+        Ok(rsp::MAX_PACKET_SIZE)
+    );
+    // Everything advertised has to work
+    assert_eq!(client.request(b"vCont?").first(), Some(&b'v'));
+    let threads = client.request(b"qXfer:threads:read::0,100");
+    assert!(threads.starts_with(b"m") || threads.starts_with(b"l"));
+    assert_eq!(client.request(b"QStartNoAckMode"), b"OK");
+    client.finish();
+}
+
+#[test]
+fn binary_data() {
+    let mut client = Client::start();
+
+    // Escaped bytes count once each towards the length
+    let mut packet = b"X40000000,4:".to_vec();
+    packet.extend_from_slice(&rsp::escape(b"}#$*"));
+    assert_eq!(client.request(&packet), b"OK");
+
+    // A transfer is read in pieces, each escaped, until one starts with l
+    let mut document = vec![];
+    loop {
+        let reply = client
+            .request(format!("qXfer:features:read:target.xml:{:x},40", document.len()).as_bytes());
+        let (more, data) = reply.split_first().expect("empty qXfer reply");
+        let data = rsp::unescape(data);
+        assert!(data.len() <= 0x40);
+        document.extend_from_slice(&data);
+        match more {
+            b'm' => assert!(!data.is_empty()),
+            b'l' => break,
+            other => panic!("qXfer reply starting with {}", *other as char),
+        }
+    }
+    let document = String::from_utf8(document).unwrap();
+    assert!(document.starts_with("<?xml"));
+    assert!(document.trim_end().ends_with("</target>"));
+    client.finish();
+}
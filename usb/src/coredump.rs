@@ -0,0 +1,319 @@
+use std::fmt;
+use std::fs;
+use std::io;
+
+use super::board::{MemoryKind, MemoryRegion};
+use super::bridge::{Bridge, BridgeError};
+use super::mmu;
+use super::riscv::{RiscvCpu, RiscvCpuError};
+
+const ELF_HEADER_LENGTH: usize = 52;
+const PROGRAM_HEADER_LENGTH: usize = 32;
+const ET_CORE: u16 = 4;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+
+/// `NT_PRSTATUS`, owned by "CORE"
+const NT_PRSTATUS: u32 = 1;
+
+/// The memory map, one "base size kind name" line per region, in a note
+/// owned by "LiteX".  Some tools go by the type alone in core files, so it
+/// stays clear of the small numbers the standard notes use.
+const NT_LITEX_MEMORY_MAP: u32 = 0x4d4d_584c;
+
+/// `struct elf_prstatus` for 32-bit RISC-V Linux, which is what GDB and
+/// other tools expect to find registers in: the signal at 12, the thread
+/// ID at 24, then pc and x1 to x31 at 72
+const PRSTATUS_LENGTH: usize = 204;
+const PRSTATUS_CURSIG: usize = 12;
+const PRSTATUS_PID: usize = 24;
+const PRSTATUS_REGS: usize = 72;
+
+/// GDB's number for pc
+const PC_REGNUM: u32 = 32;
+
+#[derive(Debug)]
+pub enum CoreError {
+    /// Couldn't write the file
+    IoError(io::Error),
+
+    /// Memory couldn't be read
+    BridgeError(BridgeError),
+
+    /// Registers couldn't be read
+    CpuError(RiscvCpuError),
+
+    /// A region was asked for that isn't in the memory map
+    UnknownRegion(String),
+}
+
+impl std::convert::From<io::Error> for CoreError {
+    fn from(e: io::Error) -> Self {
+        CoreError::IoError(e)
+    }
+}
+
+impl std::convert::From<BridgeError> for CoreError {
+    fn from(e: BridgeError) -> Self {
+        CoreError::BridgeError(e)
+    }
+}
+
+impl std::convert::From<RiscvCpuError> for CoreError {
+    fn from(e: RiscvCpuError) -> Self {
+        CoreError::CpuError(e)
+    }
+}
+
+impl fmt::Display for CoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CoreError::IoError(e) => write!(f, "{}", e),
+            CoreError::BridgeError(e) => write!(f, "couldn't read memory: {:?}", e),
+            CoreError::CpuError(e) => write!(f, "couldn't read registers: {:?}", e),
+            CoreError::UnknownRegion(name) => {
+                write!(f, "no region called {} in the memory map", name)
+            }
+        }
+    }
+}
+
+/// The registers of one hart as it stopped
+pub struct HartRegisters {
+    /// GDB's thread ID for the hart
+    pub tid: u32,
+
+    /// The signal GDB was told the hart stopped with
+    pub signal: u8,
+
+    /// x0 to x31, then pc
+    pub registers: [u32; 33],
+}
+
+/// Save the registers of every hart, plus the contents of the `regions`
+/// named, as an ELF core file that GDB and crash tools can load without
+/// the adapter.  With no regions named, every RAM region is saved.  The
+/// whole memory map goes in a note either way, so the dump says which
+/// addresses were left out.  Returns the number of bytes of memory saved.
+pub fn save(
+    cpu: &RiscvCpu,
+    bridge: &Bridge,
+    memory_map: &[MemoryRegion],
+    path: &str,
+    regions: &[&str],
+) -> Result<usize, CoreError> {
+    let mut selected = vec![];
+    for name in regions {
+        match memory_map.iter().find(|r| r.name == *name) {
+            Some(region) => selected.push(region),
+            None => return Err(CoreError::UnknownRegion((*name).to_owned())),
+        }
+    }
+    if regions.is_empty() {
+        selected.extend(memory_map.iter().filter(|r| r.kind == MemoryKind::Ram));
+    }
+
+    let mut harts = vec![];
+    for hart in 0..cpu.hart_count() {
+        let mut registers = [0; 33];
+        for (regnum, value) in registers.iter_mut().enumerate() {
+            *value = cpu.read_register(bridge, hart, regnum as u32)?;
+        }
+        harts.push(HartRegisters {
+            tid: hart as u32 + 1,
+            signal: cpu.halt_reason(hart).map_or(0, |r| r.signal()),
+            registers,
+        });
+    }
+
+    let mut contents = vec![];
+    for region in selected {
+        contents.push((
+            region,
+            mmu::read_physical(bridge, region.base, region.size)?,
+        ));
+    }
+    let saved = contents.iter().map(|(_, data)| data.len()).sum();
+    fs::write(path, build(&harts, memory_map, &contents))?;
+    Ok(saved)
+}
+
+/// Lay out the core file: the ELF header, a program header for the notes
+/// and one for each region, the notes, then the regions' contents
+pub fn build(
+    harts: &[HartRegisters],
+    memory_map: &[MemoryRegion],
+    contents: &[(&MemoryRegion, Vec<u8>)],
+) -> Vec<u8> {
+    let mut notes = vec![];
+    for hart in harts {
+        let mut prstatus = vec![0; PRSTATUS_LENGTH];
+        prstatus[PRSTATUS_CURSIG..PRSTATUS_CURSIG + 2]
+            .copy_from_slice(&(hart.signal as u16).to_le_bytes());
+        prstatus[PRSTATUS_PID..PRSTATUS_PID + 4].copy_from_slice(&hart.tid.to_le_bytes());
+        // pr_reg starts with pc where x0 would be
+        for reg in 0..32 {
+            let value = if reg == 0 {
+                hart.registers[PC_REGNUM as usize]
+            } else {
+                hart.registers[reg]
+            };
+            let offset = PRSTATUS_REGS + reg * 4;
+            prstatus[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        push_note(&mut notes, b"CORE", NT_PRSTATUS, &prstatus);
+    }
+    let map: String = memory_map
+        .iter()
+        .map(|r| format!("{:08x} {:08x} {} {}\n", r.base, r.size, r.kind, r.name))
+        .collect();
+    push_note(&mut notes, b"LiteX", NT_LITEX_MEMORY_MAP, map.as_bytes());
+
+    let phnum = 1 + contents.len();
+    let notes_offset = ELF_HEADER_LENGTH + phnum * PROGRAM_HEADER_LENGTH;
+    let mut data_offset = align4(notes_offset + notes.len());
+
+    let mut out = Vec::with_capacity(data_offset);
+    out.extend_from_slice(b"\x7fELF\x01\x01\x01\0\0\0\0\0\0\0\0\0");
+    for half in &[ET_CORE, EM_RISCV] {
+        out.extend_from_slice(&half.to_le_bytes());
+    }
+    // e_version, e_entry, e_phoff, e_shoff, e_flags
+    for word in &[1, 0, ELF_HEADER_LENGTH as u32, 0, 0] {
+        out.extend_from_slice(&word.to_le_bytes());
+    }
+    // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum, e_shstrndx
+    for half in &[
+        ELF_HEADER_LENGTH as u16,
+        PROGRAM_HEADER_LENGTH as u16,
+        phnum as u16,
+        0,
+        0,
+        0,
+    ] {
+        out.extend_from_slice(&half.to_le_bytes());
+    }
+
+    let mut program_header = |kind, offset: usize, addr, len: usize, flags, align| {
+        for word in &[
+            kind,
+            offset as u32,
+            addr,
+            addr,
+            len as u32,
+            len as u32,
+            flags,
+            align,
+        ] {
+            out.extend_from_slice(&word.to_le_bytes());
+        }
+    };
+    program_header(PT_NOTE, notes_offset, 0, notes.len(), 0, 4);
+    for (region, data) in contents {
+        let flags = match region.kind {
+            MemoryKind::Ram => 7,
+            MemoryKind::Io => 6,
+            MemoryKind::Rom | MemoryKind::Flash => 5,
+        };
+        program_header(PT_LOAD, data_offset, region.base, data.len(), flags, 4);
+        data_offset = align4(data_offset + data.len());
+    }
+
+    out.extend_from_slice(&notes);
+    for (_, data) in contents {
+        out.resize(align4(out.len()), 0);
+        out.extend_from_slice(data);
+    }
+    out
+}
+
+fn push_note(notes: &mut Vec<u8>, owner: &[u8], kind: u32, desc: &[u8]) {
+    for word in &[owner.len() as u32 + 1, desc.len() as u32, kind] {
+        notes.extend_from_slice(&word.to_le_bytes());
+    }
+    notes.extend_from_slice(owner);
+    notes.push(0);
+    notes.resize(align4(notes.len()), 0);
+    notes.extend_from_slice(desc);
+    notes.resize(align4(notes.len()), 0);
+}
+
+fn align4(n: usize) -> usize {
+    (n + 3) & !3
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn u16_at(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([data[offset], data[offset + 1]])
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        let mut word = [0; 4];
+        word.copy_from_slice(&data[offset..offset + 4]);
+        u32::from_le_bytes(word)
+    }
+
+    #[test]
+    fn core_layout() {
+        let region = |name: &str, base, size, kind| MemoryRegion {
+            name: name.to_owned(),
+            base,
+            size,
+            kind,
+        };
+        let map = vec![
+            region("sram", 0x1000_0000, 6, MemoryKind::Ram),
+            region("csr", 0xe000_0000, 0x1_0000, MemoryKind::Io),
+        ];
+        let mut registers = [0; 33];
+        for (idx, reg) in registers.iter_mut().enumerate() {
+            *reg = 0x100 + idx as u32;
+        }
+        let harts = [HartRegisters {
+            tid: 1,
+            signal: 5,
+            registers,
+        }];
+        let contents = vec![(&map[0], b"abcdef".to_vec())];
+        let core = build(&harts, &map, &contents);
+
+        assert_eq!(&core[..4], b"\x7fELF");
+        assert_eq!(u16_at(&core, 16), ET_CORE);
+        assert_eq!(u16_at(&core, 18), EM_RISCV);
+        assert_eq!(u16_at(&core, 44), 2);
+
+        // The notes: prstatus, then the memory map
+        let notes = u32_at(&core, 52 + 4) as usize;
+        assert_eq!(u32_at(&core, 52), PT_NOTE);
+        assert_eq!(u32_at(&core, notes), 5);
+        assert_eq!(u32_at(&core, notes + 4), PRSTATUS_LENGTH as u32);
+        assert_eq!(u32_at(&core, notes + 8), NT_PRSTATUS);
+        assert_eq!(&core[notes + 12..notes + 17], b"CORE\0");
+        let prstatus = notes + 20;
+        assert_eq!(u16_at(&core, prstatus + PRSTATUS_CURSIG), 5);
+        assert_eq!(u32_at(&core, prstatus + PRSTATUS_PID), 1);
+        assert_eq!(u32_at(&core, prstatus + PRSTATUS_REGS), 0x120);
+        assert_eq!(u32_at(&core, prstatus + PRSTATUS_REGS + 4), 0x101);
+        let map_note = prstatus + PRSTATUS_LENGTH;
+        assert_eq!(u32_at(&core, map_note + 8), NT_LITEX_MEMORY_MAP);
+        let desc = map_note + 20;
+        let len = u32_at(&core, map_note + 4) as usize;
+        assert_eq!(
+            &core[desc..desc + len],
+            &b"10000000 00000006 ram sram\ne0000000 00010000 io csr\n"[..]
+        );
+
+        // The one region that was saved
+        let load = 52 + 32;
+        assert_eq!(u32_at(&core, load), PT_LOAD);
+        assert_eq!(u32_at(&core, load + 8), 0x1000_0000);
+        assert_eq!(u32_at(&core, load + 16), 6);
+        let offset = u32_at(&core, load + 4) as usize;
+        assert_eq!(offset % 4, 0);
+        assert_eq!(&core[offset..offset + 6], b"abcdef");
+    }
+}
@@ -0,0 +1,480 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io;
+
+use super::board::{self, MemoryKind, MemoryRegion, SpiFlashRegisters};
+use super::bridge::{Bridge, BridgeError};
+use super::utils::{parse_u32, parse_u64};
+
+/// Longest identifier string LiteX will put in the identifier ROM
+const MAX_IDENTIFIER_LEN: u32 = 256;
+
+/// How far apart the csr.csv and identifier timestamps can be and still
+/// count as the same build, in seconds
+const BUILD_TIME_SLACK: i64 = 10 * 60;
+
+#[derive(Debug)]
+pub enum CsrMapError {
+    /// Couldn't read the file
+    IoError(io::Error),
+
+    /// A line didn't make sense
+    ParseError(usize /* line */, String),
+
+    /// There's no register by that name
+    UnknownRegister(String),
+
+    /// The bridge failed while accessing a register
+    BridgeError(BridgeError),
+}
+
+impl std::convert::From<io::Error> for CsrMapError {
+    fn from(e: io::Error) -> Self {
+        CsrMapError::IoError(e)
+    }
+}
+
+impl std::convert::From<BridgeError> for CsrMapError {
+    fn from(e: BridgeError) -> Self {
+        CsrMapError::BridgeError(e)
+    }
+}
+
+impl fmt::Display for CsrMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CsrMapError::IoError(e) => write!(f, "{}", e),
+            CsrMapError::ParseError(line, text) => write!(f, "line {}: {}", line, text),
+            CsrMapError::UnknownRegister(name) => write!(f, "no register named {}", name),
+            CsrMapError::BridgeError(e) => write!(f, "bridge error: {:?}", e),
+        }
+    }
+}
+
+/// Something with a name, address and size in csr.csv
+#[derive(Clone, Debug, PartialEq)]
+pub struct CsrEntry {
+    pub addr: u32,
+
+    /// Number of CSR words for registers, bytes for memory regions
+    pub size: u32,
+}
+
+/// The contents of a LiteX `csr.csv`
+#[derive(Default)]
+pub struct CsrMap {
+    /// Comment lines from the top of the file
+    header: Vec<String>,
+    pub bases: BTreeMap<String, u32>,
+    pub registers: BTreeMap<String, CsrEntry>,
+    pub constants: BTreeMap<String, String>,
+    pub memory_regions: BTreeMap<String, CsrEntry>,
+
+    /// Memory regions marked `io`, which hold peripherals
+    io_regions: BTreeSet<String>,
+
+    /// Registers marked `ro`, which can only be read
+    read_only: BTreeSet<String>,
+}
+
+impl CsrMap {
+    pub fn load(path: &str) -> Result<CsrMap, CsrMapError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parse lines of the form `kind,name,value,size,mode`
+    pub fn parse(text: &str) -> Result<CsrMap, CsrMapError> {
+        let mut map = CsrMap::default();
+        for (line_idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                map.header
+                    .push(line.trim_start_matches('#').trim().to_owned());
+                continue;
+            }
+            if line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split(',').collect();
+            let error = || CsrMapError::ParseError(line_idx + 1, line.to_owned());
+            if fields.len() < 3 {
+                return Err(error());
+            }
+            let name = fields[1].to_owned();
+            let number = |s: &str| parse_u32(s.trim()).map_err(|_| error());
+            match fields[0] {
+                "csr_base" => {
+                    map.bases.insert(name, number(fields[2])?);
+                }
+                "csr_register" | "memory_region" => {
+                    let entry = CsrEntry {
+                        addr: number(fields[2])?,
+                        size: number(fields.get(3).ok_or_else(error)?)?,
+                    };
+                    if fields[0] == "csr_register" {
+                        if fields.get(4).map(|m| m.trim()) == Some("ro") {
+                            map.read_only.insert(name.clone());
+                        }
+                        map.registers.insert(name, entry);
+                    } else {
+                        if fields.get(4).map(|m| m.trim()) == Some("io") {
+                            map.io_regions.insert(name.clone());
+                        }
+                        map.memory_regions.insert(name, entry);
+                    }
+                }
+                "constant" => {
+                    // Constants may be strings with commas in them, followed
+                    // by the two empty size and mode fields
+                    let end = if fields.len() >= 5 {
+                        fields.len() - 2
+                    } else {
+                        fields.len()
+                    };
+                    map.constants.insert(name, fields[2..end].join(","));
+                }
+                _ => return Err(error()),
+            }
+        }
+        Ok(map)
+    }
+
+    /// Number of bits held in each CSR word
+    fn data_width(&self) -> u32 {
+        self.constants
+            .get("config_csr_data_width")
+            .and_then(|w| parse_u32(w).ok())
+            .unwrap_or(32)
+    }
+
+    fn register(&self, name: &str) -> Result<&CsrEntry, CsrMapError> {
+        self.registers
+            .get(name)
+            .ok_or_else(|| CsrMapError::UnknownRegister(name.to_owned()))
+    }
+
+    /// Read a register that may be split across several CSR words, most
+    /// significant word first
+    pub fn read_register(&self, bridge: &Bridge, name: &str) -> Result<u64, CsrMapError> {
+        let reg = self.register(name)?;
+        let width = self.data_width();
+        let mut value: u64 = 0;
+        for word in 0..reg.size {
+            let part = bridge.peek(reg.addr + word * 4)? as u64;
+            value = (value << width) | (part & mask(width));
+        }
+        Ok(value)
+    }
+
+    pub fn write_register(
+        &self,
+        bridge: &Bridge,
+        name: &str,
+        value: u64,
+    ) -> Result<(), CsrMapError> {
+        let reg = self.register(name)?;
+        let width = self.data_width();
+        for word in 0..reg.size {
+            let shift = (reg.size - 1 - word) * width;
+            let part = value.checked_shr(shift).unwrap_or(0) & mask(width);
+            bridge.poke(reg.addr + word * 4, part as u32)?;
+        }
+        Ok(())
+    }
+
+    /// Whether csr.csv says the register can be written
+    pub fn is_writable(&self, name: &str) -> bool {
+        self.registers.contains_key(name) && !self.read_only.contains(name)
+    }
+
+    /// The name of the register at `addr`, if there is one
+    pub fn register_at(&self, addr: u32) -> Option<&str> {
+        self.registers
+            .iter()
+            .find(|(_, reg)| reg.addr == addr)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The bit-bang registers of the `spiflash` controller, if the SoC
+    /// has one
+    pub fn spiflash_registers(&self) -> Option<SpiFlashRegisters> {
+        let addr = |name: &str| self.registers.get(name).map(|reg| reg.addr);
+        Some(SpiFlashRegisters {
+            bitbang: addr("spiflash_bitbang")?,
+            miso: addr("spiflash_miso")?,
+            bitbang_en: addr("spiflash_bitbang_en")?,
+        })
+    }
+
+    /// What `name` stands for in an expression: a register's address, a
+    /// CSR bank's base as `<bank>_base` or `<bank>`, a memory region's base
+    /// or size, or a numeric constant
+    pub fn lookup(&self, name: &str) -> Option<u64> {
+        if let Some(reg) = self.registers.get(name) {
+            return Some(reg.addr as u64);
+        }
+        if let Some(base) = self.bases.get(name.strip_suffix("_base").unwrap_or(name)) {
+            return Some(*base as u64);
+        }
+        if let Some(value) = board::region_symbol(&self.memory_map(), name) {
+            return Some(value as u64);
+        }
+        self.constants.get(name).and_then(|c| parse_u64(c).ok())
+    }
+
+    /// The memory regions as a memory map.  csr.csv doesn't say what kind
+    /// of memory each one is, so that's worked out from the `io` mode and
+    /// the names LiteX gives them.
+    pub fn memory_map(&self) -> Vec<MemoryRegion> {
+        let mut regions: Vec<MemoryRegion> = self
+            .memory_regions
+            .iter()
+            .map(|(name, entry)| {
+                let kind = if self.io_regions.contains(name) || name == "csr" {
+                    MemoryKind::Io
+                } else if name.contains("flash") {
+                    MemoryKind::Flash
+                } else if name == "rom" {
+                    MemoryKind::Rom
+                } else {
+                    MemoryKind::Ram
+                };
+                MemoryRegion {
+                    name: name.clone(),
+                    base: entry.addr,
+                    size: entry.size,
+                    kind,
+                }
+            })
+            .collect();
+        regions.sort_by_key(|r| r.base);
+        regions
+    }
+
+    /// When the gateware this map belongs to was built, in seconds.  LiteX
+    /// puts a timestamp in the header comment.
+    fn build_time(&self) -> Option<i64> {
+        self.header.iter().filter_map(|l| find_timestamp(l)).next()
+    }
+
+    /// Read the identifier string out of the SoC's identifier ROM, which
+    /// holds one character per CSR word
+    pub fn read_identifier(&self, bridge: &Bridge) -> Result<Option<String>, BridgeError> {
+        let base = match self.bases.get("identifier_mem") {
+            Some(&b) => b,
+            None => return Ok(None),
+        };
+        let mut identifier = String::new();
+        for idx in 0..MAX_IDENTIFIER_LEN {
+            let c = bridge.peek(base + idx * 4)? as u8;
+            if c == 0 {
+                break;
+            }
+            identifier.push(c as char);
+        }
+        Ok(Some(identifier))
+    }
+
+    /// Compare the map against the identifier of the running bitstream.
+    /// Returns a warning if they look like they came from different builds.
+    pub fn check_identifier(&self, bridge: &Bridge) -> Result<Option<String>, BridgeError> {
+        let identifier = match self.read_identifier(bridge)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        if let Some(expected) = self.constants.get("identifier") {
+            if expected.trim_matches('"') != identifier {
+                return Ok(Some(format!(
+                    "csr.csv is for \"{}\", but the device is running \"{}\"",
+                    expected.trim_matches('"'),
+                    identifier
+                )));
+            }
+            return Ok(None);
+        }
+        match (self.build_time(), find_timestamp(&identifier)) {
+            (Some(map_time), Some(device_time))
+                if (map_time - device_time).abs() > BUILD_TIME_SLACK =>
+            {
+                Ok(Some(format!(
+                    "csr.csv doesn't match the bitstream on the device (\"{}\")",
+                    identifier
+                )))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+fn mask(width: u32) -> u64 {
+    if width >= 64 {
+        !0
+    } else {
+        (1 << width) - 1
+    }
+}
+
+/// A difference between two CSR maps
+pub enum CsrChange {
+    Added(String, CsrEntry),
+    Removed(String, CsrEntry),
+    Moved(String, CsrEntry, CsrEntry),
+}
+
+impl fmt::Display for CsrChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CsrChange::Added(name, e) => write!(f, "+ {:<32} {:08x} ({})", name, e.addr, e.size),
+            CsrChange::Removed(name, e) => {
+                write!(f, "- {:<32} {:08x} ({})", name, e.addr, e.size)
+            }
+            CsrChange::Moved(name, old, new) => write!(
+                f,
+                "~ {:<32} {:08x} ({}) -> {:08x} ({})",
+                name, old.addr, old.size, new.addr, new.size
+            ),
+        }
+    }
+}
+
+fn diff_entries(
+    old: &BTreeMap<String, CsrEntry>,
+    new: &BTreeMap<String, CsrEntry>,
+) -> Vec<CsrChange> {
+    let mut changes = vec![];
+    for (name, old_entry) in old {
+        match new.get(name) {
+            None => changes.push(CsrChange::Removed(name.clone(), old_entry.clone())),
+            Some(new_entry) if new_entry != old_entry => changes.push(CsrChange::Moved(
+                name.clone(),
+                old_entry.clone(),
+                new_entry.clone(),
+            )),
+            Some(_) => (),
+        }
+    }
+    for (name, new_entry) in new {
+        if !old.contains_key(name) {
+            changes.push(CsrChange::Added(name.clone(), new_entry.clone()));
+        }
+    }
+    changes
+}
+
+/// Registers and memory regions that were added, removed or moved between
+/// two builds
+pub fn diff(old: &CsrMap, new: &CsrMap) -> Vec<CsrChange> {
+    let mut changes = diff_entries(&old.registers, &new.registers);
+    changes.extend(diff_entries(&old.memory_regions, &new.memory_regions));
+    changes
+}
+
+/// Find a `YYYY-MM-DD HH:MM:SS` timestamp in a string and turn it into
+/// seconds since 1970
+fn find_timestamp(s: &str) -> Option<i64> {
+    let bytes = s.as_bytes();
+    const PATTERN: &[u8] = b"dddd-dd-dd dd:dd:dd";
+    (0..bytes.len().saturating_sub(PATTERN.len() - 1)).find_map(|start| {
+        let candidate = &bytes[start..start + PATTERN.len()];
+        let matches = candidate.iter().zip(PATTERN).all(|(&c, &p)| match p {
+            b'd' => c.is_ascii_digit(),
+            p => c == p,
+        });
+        if !matches {
+            return None;
+        }
+        let field = |range: std::ops::Range<usize>| -> i64 {
+            std::str::from_utf8(&candidate[range])
+                .unwrap()
+                .parse()
+                .unwrap()
+        };
+        let days = days_from_civil(field(0..4), field(5..7), field(8..10));
+        Some(days * 86400 + field(11..13) * 3600 + field(14..16) * 60 + field(17..19))
+    })
+}
+
+/// Days since 1970-01-01 for a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month_index = (month + 9) % 12;
+    let day_of_year = (153 * month_index + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod test {
+    use super::CsrMap;
+    use crate::board::MemoryKind;
+    use crate::bridge::Bridge;
+    use crate::config::Config;
+
+    const CSR_CSV: &str = "\
+csr_base,ctrl,0xf0000000,,
+csr_register,ctrl_scratch,0xf0000004,1,rw
+csr_register,timer0_load,0xf0002800,4,rw
+constant,config_csr_data_width,8,,
+memory_region,main_ram,0x40000000,0x01000000,cached
+memory_region,csr,0xf0000000,0x00010000,io
+memory_region,rom,0x00000000,0x00008000,cached
+memory_region,spiflash,0x20000000,0x01000000,cached
+memory_region,ethmac,0x80000000,0x00002000,io
+memory_region,sram,0x10000000,0x00002000,cached
+";
+
+    #[test]
+    fn memory_map() {
+        let map = CsrMap::parse(CSR_CSV).unwrap();
+        let regions = map.memory_map();
+        let regions: Vec<(&str, u32, u32, MemoryKind)> = regions
+            .iter()
+            .map(|r| (r.name.as_str(), r.base, r.size, r.kind))
+            .collect();
+        assert_eq!(
+            regions,
+            [
+                ("rom", 0, 0x8000, MemoryKind::Rom),
+                ("sram", 0x1000_0000, 0x2000, MemoryKind::Ram),
+                ("spiflash", 0x2000_0000, 0x100_0000, MemoryKind::Flash),
+                ("main_ram", 0x4000_0000, 0x100_0000, MemoryKind::Ram),
+                ("ethmac", 0x8000_0000, 0x2000, MemoryKind::Io),
+                ("csr", 0xf000_0000, 0x1_0000, MemoryKind::Io),
+            ]
+        );
+        assert_eq!(map.lookup("main_ram_size"), Some(0x100_0000));
+        assert_eq!(map.lookup("ctrl"), Some(0xf000_0000));
+    }
+
+    #[test]
+    fn registers_span_words() {
+        let cfg = Config::from_args(["csr", "--mock"]).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        let map = CsrMap::parse(CSR_CSV).unwrap();
+
+        // Eight bits to a word, most significant first
+        map.write_register(&bridge, "timer0_load", 0x1234_5678)
+            .unwrap();
+        let words: Vec<u32> = (0..4)
+            .map(|i| bridge.peek(0xf000_2800 + 4 * i).unwrap())
+            .collect();
+        assert_eq!(words, [0x12, 0x34, 0x56, 0x78]);
+        assert_eq!(
+            map.read_register(&bridge, "timer0_load").unwrap(),
+            0x1234_5678
+        );
+
+        // Bits that don't fit are dropped on the way out
+        bridge.poke(0xf000_2800, 0xffff_ff9a).unwrap();
+        assert_eq!(
+            map.read_register(&bridge, "timer0_load").unwrap(),
+            0x9a34_5678
+        );
+
+        assert!(map.read_register(&bridge, "timer1_load").is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! The value of every CSR in csr.csv at one moment, saved with
+//! `monitor csr-dump` and put back with `monitor csr-restore`.  The file is
+//! one `name value` line per register in name order, so the dumps from a
+//! good and a bad boot can be compared with `diff`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+
+use super::bridge::Bridge;
+use super::csr_map::{CsrMap, CsrMapError};
+use super::utils::parse_u64;
+
+/// Registers whose writes do something rather than store a value, such
+/// as clearing events, sending a byte or issuing a DRAM command, by the end
+/// of their name.  Putting back what was read from them would repeat the
+/// action, so they're only restored when asked for by name.
+/// `sdram_dfii_control` hands DRAM between the controller and software,
+/// which would pull it out from under anything running from it.
+const ACTION_SUFFIXES: &[&str] = &[
+    "_ev_pending",
+    "_issue",
+    "_reset",
+    "_rxtx",
+    "_update_value",
+    "sdram_dfii_control",
+];
+
+#[derive(Debug, Default, PartialEq)]
+pub struct CsrSnapshot {
+    pub values: BTreeMap<String, u64>,
+}
+
+/// What restoring a snapshot over the registers as they are now takes
+#[derive(Debug, Default, PartialEq)]
+pub struct RestorePlan {
+    /// Registers to write, with the value they have now and the one saved
+    pub writes: Vec<(String, u64, u64)>,
+
+    /// Registers that differ but won't be written, and why
+    pub skipped: Vec<(String, &'static str)>,
+
+    /// Number of registers that already hold the saved value
+    pub unchanged: usize,
+}
+
+impl fmt::Display for CsrSnapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (name, value) in &self.values {
+            writeln!(f, "{} 0x{:x}", name, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl CsrSnapshot {
+    /// Read every register in `map`
+    pub fn capture(map: &CsrMap, bridge: &Bridge) -> Result<CsrSnapshot, CsrMapError> {
+        let mut values = BTreeMap::new();
+        for name in map.registers.keys() {
+            values.insert(name.clone(), map.read_register(bridge, name)?);
+        }
+        Ok(CsrSnapshot { values })
+    }
+
+    pub fn load(path: &str) -> Result<CsrSnapshot, CsrMapError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), CsrMapError> {
+        Ok(fs::write(path, self.to_string())?)
+    }
+
+    /// Parse `name value` lines, skipping blank ones and `#` comments
+    pub fn parse(text: &str) -> Result<CsrSnapshot, CsrMapError> {
+        let mut values = BTreeMap::new();
+        for (line_idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || CsrMapError::ParseError(line_idx + 1, line.to_owned());
+            let mut fields = line.split_whitespace();
+            let (name, value) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(name), Some(value), None) => (name, value),
+                _ => return Err(error()),
+            };
+            values.insert(name.to_owned(), parse_u64(value).map_err(|_| error())?);
+        }
+        Ok(CsrSnapshot { values })
+    }
+
+    /// Work out which registers to write to go from `current` back to this
+    /// snapshot.  With `only` empty that's every writable register that
+    /// differs, other than ones in [`ACTION_SUFFIXES`].  Otherwise it's
+    /// just the registers named, or in the peripherals named, in `only`.
+    /// Registers are written in address order, which is the order each
+    /// peripheral lays out its configuration before the registers that act
+    /// on it.
+    pub fn plan_restore(&self, map: &CsrMap, current: &CsrSnapshot, only: &[&str]) -> RestorePlan {
+        let mut plan = RestorePlan::default();
+        let named = |name: &str| {
+            only.iter().any(|o| {
+                name == *o
+                    || name
+                        .strip_prefix(o)
+                        .is_some_and(|rest| rest.starts_with('_'))
+            })
+        };
+        let mut names: Vec<&String> = self.values.keys().collect();
+        names.sort_by_key(|name| map.registers.get(*name).map(|reg| reg.addr));
+        for name in names {
+            let saved = self.values[name];
+            if !only.is_empty() && !named(name) {
+                continue;
+            }
+            let now = match current.values.get(name) {
+                Some(&now) => now,
+                None => {
+                    plan.skipped.push((name.clone(), "not in this csr.csv"));
+                    continue;
+                }
+            };
+            if now == saved {
+                plan.unchanged += 1;
+            } else if !map.is_writable(name) {
+                plan.skipped.push((name.clone(), "read-only"));
+            } else if !only.contains(&name.as_str())
+                && ACTION_SUFFIXES.iter().any(|s| name.ends_with(s))
+            {
+                plan.skipped.push((
+                    name.clone(),
+                    "writing it is an action; name it to restore it",
+                ));
+            } else {
+                plan.writes.push((name.clone(), now, saved));
+            }
+        }
+        plan
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CsrSnapshot;
+    use crate::csr_map::CsrMap;
+
+    const CSR_CSV: &str = "csr_register,ctrl_scratch,0xf0000004,1,rw\n\
+                           csr_register,ctrl_bus_errors,0xf0000008,1,ro\n\
+                           csr_register,timer0_load,0xf0002800,1,rw\n\
+                           csr_register,timer0_reload,0xf0002804,1,rw\n\
+                           csr_register,timer0_ev_pending,0xf0002818,1,rw\n\
+                           csr_register,uart_rxtx,0xf0001000,1,rw\n\
+                           csr_register,sdram_dfii_control,0xf0003000,1,rw\n";
+
+    #[test]
+    fn text_round_trip() {
+        let snapshot =
+            CsrSnapshot::parse("# a comment\nctrl_scratch 0x12345678\n\ntimer0_load 0\n").unwrap();
+        assert_eq!(snapshot.values.len(), 2);
+        assert_eq!(CsrSnapshot::parse(&snapshot.to_string()).unwrap(), snapshot);
+        assert!(CsrSnapshot::parse("ctrl_scratch").is_err());
+        assert!(CsrSnapshot::parse("ctrl_scratch zero").is_err());
+    }
+
+    #[test]
+    fn restore_plan() {
+        let map = CsrMap::parse(CSR_CSV).unwrap();
+        let saved = CsrSnapshot::parse(
+            "ctrl_scratch 1\nctrl_bus_errors 0\ntimer0_load 5\ntimer0_reload 7\n\
+             timer0_ev_pending 1\nuart_rxtx 0x41\ngone 3\nsdram_dfii_control 1\n",
+        )
+        .unwrap();
+        let current = CsrSnapshot::parse(
+            "ctrl_scratch 2\nctrl_bus_errors 4\ntimer0_load 5\ntimer0_reload 0\n\
+             timer0_ev_pending 0\nuart_rxtx 0\nsdram_dfii_control 0xe\n",
+        )
+        .unwrap();
+
+        let plan = saved.plan_restore(&map, &current, &[]);
+        assert_eq!(
+            plan.writes,
+            vec![
+                ("ctrl_scratch".to_owned(), 2, 1),
+                ("timer0_reload".to_owned(), 0, 7)
+            ]
+        );
+        let skipped: Vec<&str> = plan.skipped.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(
+            skipped,
+            vec![
+                "gone",
+                "ctrl_bus_errors",
+                "uart_rxtx",
+                "timer0_ev_pending",
+                "sdram_dfii_control"
+            ]
+        );
+        assert_eq!(plan.unchanged, 1);
+
+        let plan = saved.plan_restore(&map, &current, &["timer0", "uart_rxtx"]);
+        let written: Vec<&str> = plan.writes.iter().map(|(n, _, _)| n.as_str()).collect();
+        assert_eq!(written, vec!["uart_rxtx", "timer0_reload"]);
+        assert_eq!(plan.skipped.len(), 1);
+    }
+}
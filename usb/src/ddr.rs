@@ -0,0 +1,529 @@
+//! Checking on LiteDRAM bring-up.  The BIOS initializes the DRAM at boot
+//! with `sdram_dfii_control` in software mode, trains the PHY's read
+//! delays, then hands the memory to the controller.  Reads of DRAM that
+//! come back as garbage are often down to that going wrong, and the
+//! registers involved say as much.
+//!
+//! Read delays are trained again here through the bridge: for each module
+//! every bitslip and delay is tried on a pattern written to the start of
+//! `main_ram`, and the middle of the longest run of good reads is kept.
+//! Byte `n` of DRAM belongs to module `n % modules`, which is how LiteDRAM
+//! lays out the data bus.
+
+use std::fmt;
+
+use super::bridge::Bridge;
+use super::csr_map::{CsrMap, CsrMapError};
+
+/// Who drives the DRAM: software through the DFI, or the controller
+const DFII_CONTROL: &str = "sdram_dfii_control";
+
+/// Bits in `sdram_dfii_control`
+const CONTROL_SEL: u64 = 0x01;
+const CONTROL_CKE: u64 = 0x02;
+const CONTROL_ODT: u64 = 0x04;
+const CONTROL_RESET_N: u64 = 0x08;
+
+/// One bit per module, picking which ones the delay registers act on
+const DLY_SEL: &str = "ddrphy_dly_sel";
+const RDLY_RST: &str = "ddrphy_rdly_dq_rst";
+const RDLY_INC: &str = "ddrphy_rdly_dq_inc";
+const BITSLIP_RST: &str = "ddrphy_rdly_dq_bitslip_rst";
+const BITSLIP_INC: &str = "ddrphy_rdly_dq_bitslip";
+
+/// Set by ECP5 PHYs for each module that has seen a read burst come back
+const BURSTDET_CLR: &str = "ddrphy_burstdet_clr";
+const BURSTDET_SEEN: &str = "ddrphy_burstdet_seen";
+
+/// The region LiteX puts DRAM in
+const DRAM_REGION: &str = "main_ram";
+
+/// Words written and read back to check the data bus.  Every byte lane
+/// sees both values of every bit.
+const PATTERN: [u32; 8] = [
+    0x5555_5555,
+    0xaaaa_aaaa,
+    0x0000_ffff,
+    0xffff_0000,
+    0x1234_5678,
+    0xedcb_a987,
+    0x0f0f_f0f0,
+    0xf0f0_0f0f,
+];
+
+/// How many delay taps and bitslips a PHY has
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Taps {
+    delays: usize,
+    bitslips: usize,
+}
+
+/// ECP5 PHYs have far fewer taps than the 7-series ones
+const ECP5_TAPS: Taps = Taps {
+    delays: 8,
+    bitslips: 4,
+};
+const SERIES7_TAPS: Taps = Taps {
+    delays: 32,
+    bitslips: 8,
+};
+
+/// A LiteDRAM controller found in csr.csv
+pub struct DdrControl {
+    /// Where DRAM starts, to check reads against
+    pub ram: Option<u32>,
+
+    /// The read delays can be trained
+    trainable: bool,
+
+    /// There's a bitslip to train along with the delays
+    has_bitslip: bool,
+
+    /// The PHY reports whether each module has seen a read burst
+    has_burstdet: bool,
+}
+
+/// What the controller has to say for itself
+#[derive(Debug, PartialEq)]
+pub struct DdrStatus {
+    /// `sdram_dfii_control`
+    pub control: u64,
+
+    /// A bit for each module that has seen a read burst since the bits
+    /// were last cleared, on PHYs that say
+    pub bursts_seen: Option<u64>,
+}
+
+impl DdrStatus {
+    /// The controller has the memory, so the BIOS got to the end of
+    /// initializing it
+    pub fn initialized(&self) -> bool {
+        self.control & CONTROL_SEL != 0
+    }
+}
+
+impl fmt::Display for DdrStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.initialized() {
+            writeln!(f, "DRAM is under the controller, so it was initialized")?;
+        } else {
+            writeln!(
+                f,
+                "DRAM is under software control: the BIOS didn't finish initializing it"
+            )?;
+            let pins = [
+                (CONTROL_CKE, "CKE"),
+                (CONTROL_ODT, "ODT"),
+                (CONTROL_RESET_N, "RESET_N"),
+            ];
+            let high: Vec<&str> = pins
+                .iter()
+                .filter(|(bit, _)| self.control & bit != 0)
+                .map(|(_, name)| *name)
+                .collect();
+            writeln!(
+                f,
+                "{} holds {:#x}, with {} high",
+                DFII_CONTROL,
+                self.control,
+                if high.is_empty() {
+                    "nothing".to_owned()
+                } else {
+                    high.join(", ")
+                }
+            )?;
+        }
+        if let Some(seen) = self.bursts_seen {
+            writeln!(
+                f,
+                "{} holds {:#x}, a bit for each module that has seen a read burst since it was cleared",
+                BURSTDET_SEEN, seen
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// What writing a pattern to DRAM and reading it back showed
+#[derive(Debug, PartialEq)]
+pub struct DdrCheck {
+    /// A bit for each module that read the pattern back wrong
+    pub bad: u32,
+
+    /// Whether each module saw a read burst while the pattern was read
+    /// back, on PHYs that say
+    pub bursts_seen: Option<Vec<bool>>,
+}
+
+impl fmt::Display for DdrCheck {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.bad == 0 {
+            writeln!(f, "The pattern read back right")?;
+        } else {
+            writeln!(
+                f,
+                "The pattern read back wrong for module mask {:#x}; try ddr retrain",
+                self.bad
+            )?;
+        }
+        if let Some(ref seen) = self.bursts_seen {
+            for (module, seen) in seen.iter().enumerate() {
+                writeln!(
+                    f,
+                    "Module {}: {}",
+                    module,
+                    if *seen {
+                        "read bursts seen"
+                    } else {
+                        "no read burst seen, so reads aren't calibrated"
+                    }
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How training went for one module
+#[derive(Debug, PartialEq)]
+pub struct ModuleTraining {
+    pub module: usize,
+
+    /// The bitslip and delay that were kept, and how many delays around
+    /// them read back correctly, or `None` if none did
+    pub setting: Option<(usize, usize, usize)>,
+}
+
+impl fmt::Display for ModuleTraining {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.setting {
+            Some((bitslip, delay, window)) => write!(
+                f,
+                "Module {}: bitslip {}, delay {} ({} good delays)",
+                self.module, bitslip, delay, window
+            ),
+            None => write!(f, "Module {}: no setting reads back correctly", self.module),
+        }
+    }
+}
+
+impl DdrControl {
+    /// Find the controller in csr.csv
+    pub fn detect(map: &CsrMap) -> Option<DdrControl> {
+        if !map.registers.contains_key(DFII_CONTROL) {
+            return None;
+        }
+        let has = |name: &str| map.registers.contains_key(name);
+        Some(DdrControl {
+            ram: map.memory_regions.get(DRAM_REGION).map(|r| r.addr),
+            trainable: has(DLY_SEL) && has(RDLY_RST) && has(RDLY_INC),
+            has_bitslip: has(BITSLIP_RST) && has(BITSLIP_INC),
+            has_burstdet: has(BURSTDET_CLR) && has(BURSTDET_SEEN),
+        })
+    }
+
+    /// Whether `retrain` has anything to work with
+    pub fn trainable(&self) -> bool {
+        self.trainable && self.ram.is_some()
+    }
+
+    fn taps(&self) -> Taps {
+        if self.has_burstdet {
+            ECP5_TAPS
+        } else {
+            SERIES7_TAPS
+        }
+    }
+
+    /// Work out how many modules there are from how many bits of the
+    /// module select register stick
+    fn modules(&self, map: &CsrMap, bridge: &Bridge) -> Result<usize, CsrMapError> {
+        if !self.trainable && !self.has_burstdet {
+            return Ok(0);
+        }
+        if !map.registers.contains_key(DLY_SEL) {
+            return Ok(1);
+        }
+        map.write_register(bridge, DLY_SEL, 0xffff_ffff)?;
+        let selected = map.read_register(bridge, DLY_SEL)?;
+        map.write_register(bridge, DLY_SEL, 0)?;
+        Ok((selected as u32).count_ones().max(1) as usize)
+    }
+
+    /// Read the controller's registers, without writing any
+    pub fn status(&self, map: &CsrMap, bridge: &Bridge) -> Result<DdrStatus, CsrMapError> {
+        let control = map.read_register(bridge, DFII_CONTROL)?;
+        let bursts_seen = if self.has_burstdet {
+            Some(map.read_register(bridge, BURSTDET_SEEN)?)
+        } else {
+            None
+        };
+        Ok(DdrStatus {
+            control,
+            bursts_seen,
+        })
+    }
+
+    /// Write a pattern to the start of DRAM and read it back, noting which
+    /// modules got something wrong and, where the PHY says, which saw a
+    /// read burst.  What was there is put back afterwards.
+    pub fn check(&self, map: &CsrMap, bridge: &Bridge) -> Result<DdrCheck, CsrMapError> {
+        let ram = match self.ram {
+            Some(ram) => ram,
+            None => {
+                return Ok(DdrCheck {
+                    bad: 0,
+                    bursts_seen: None,
+                })
+            }
+        };
+        let modules = self.modules(map, bridge)?.max(1);
+        let saved = bridge.read_block(ram, PATTERN.len())?;
+        if self.has_burstdet {
+            map.write_register(bridge, BURSTDET_CLR, 1)?;
+        }
+        let bad = test_pattern(bridge, ram, 0, modules);
+        let seen = if self.has_burstdet {
+            Some(map.read_register(bridge, BURSTDET_SEEN))
+        } else {
+            None
+        };
+        bridge.write_block(ram, &saved)?;
+        Ok(DdrCheck {
+            bad: bad?,
+            bursts_seen: match seen {
+                Some(seen) => {
+                    let seen = seen?;
+                    Some((0..modules).map(|m| seen & (1 << m) != 0).collect())
+                }
+                None => None,
+            },
+        })
+    }
+
+    /// Train the read delays again, one module at a time.  Whatever was
+    /// at the start of DRAM is read before and written back after, so it
+    /// only survives if reads were working to begin with.
+    pub fn retrain(
+        &self,
+        map: &CsrMap,
+        bridge: &Bridge,
+    ) -> Result<Vec<ModuleTraining>, CsrMapError> {
+        let ram = match self.ram {
+            Some(ram) if self.trainable => ram,
+            _ => return Ok(vec![]),
+        };
+        let taps = self.taps();
+        let modules = self.modules(map, bridge)?;
+        let saved = bridge.read_block(ram, PATTERN.len())?;
+        let mut results = vec![];
+        let mut round = 0;
+        for module in 0..modules {
+            map.write_register(bridge, DLY_SEL, 1 << module)?;
+            let mut best: Option<(usize, usize, usize)> = None;
+            let bitslips = if self.has_bitslip { taps.bitslips } else { 1 };
+            for bitslip in 0..bitslips {
+                self.set_bitslip(map, bridge, bitslip)?;
+                map.write_register(bridge, RDLY_RST, 1)?;
+                let mut good = vec![];
+                for _ in 0..taps.delays {
+                    round += 1;
+                    let bad = test_pattern(bridge, ram, round, modules)?;
+                    good.push(bad & (1 << module) == 0);
+                    map.write_register(bridge, RDLY_INC, 1)?;
+                }
+                if let Some((start, len)) = best_window(&good) {
+                    if best.is_none_or(|(_, _, window)| len > window) {
+                        best = Some((bitslip, start + len / 2, len));
+                    }
+                }
+            }
+            if let Some((bitslip, delay, _)) = best {
+                self.set_bitslip(map, bridge, bitslip)?;
+                map.write_register(bridge, RDLY_RST, 1)?;
+                for _ in 0..delay {
+                    map.write_register(bridge, RDLY_INC, 1)?;
+                }
+            }
+            log_adapter!("DDR module {} trained to {:?}", module, best);
+            results.push(ModuleTraining {
+                module,
+                setting: best,
+            });
+        }
+        map.write_register(bridge, DLY_SEL, 0)?;
+        bridge.write_block(ram, &saved)?;
+        Ok(results)
+    }
+
+    fn set_bitslip(
+        &self,
+        map: &CsrMap,
+        bridge: &Bridge,
+        bitslip: usize,
+    ) -> Result<(), CsrMapError> {
+        if !self.has_bitslip {
+            return Ok(());
+        }
+        map.write_register(bridge, BITSLIP_RST, 1)?;
+        for _ in 0..bitslip {
+            map.write_register(bridge, BITSLIP_INC, 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Write the pattern, changed a little each `round` so a read can't pass
+/// by returning what an earlier round left behind, and see which modules
+/// read it back wrong
+fn test_pattern(bridge: &Bridge, ram: u32, round: u32, modules: usize) -> Result<u32, CsrMapError> {
+    let salt = round.wrapping_mul(0x9e37_79b9);
+    let expected: Vec<u32> = PATTERN.iter().map(|w| w ^ salt).collect();
+    bridge.write_block(ram, &expected)?;
+    let got = bridge.read_block(ram, expected.len())?;
+    Ok(bad_modules(&expected, &got, modules))
+}
+
+/// A bit for each module with a byte that doesn't match
+fn bad_modules(expected: &[u32], got: &[u32], modules: usize) -> u32 {
+    let mut bad = 0;
+    for (word, (e, g)) in expected.iter().zip(got).enumerate() {
+        let diff = (e ^ g).to_le_bytes();
+        for (byte, d) in diff.iter().enumerate() {
+            if *d != 0 {
+                bad |= 1 << ((word * 4 + byte) % modules);
+            }
+        }
+    }
+    bad
+}
+
+/// The start and length of the longest run of good settings
+fn best_window(good: &[bool]) -> Option<(usize, usize)> {
+    let mut best: Option<(usize, usize)> = None;
+    let mut start = 0;
+    for (i, &ok) in good.iter().chain(&[false]).enumerate() {
+        if ok {
+            continue;
+        }
+        let len = i - start;
+        if len > 0 && best.is_none_or(|(_, l)| len > l) {
+            best = Some((start, len));
+        }
+        start = i + 1;
+    }
+    best
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        bad_modules, best_window, DdrCheck, DdrControl, DdrStatus, ECP5_TAPS, SERIES7_TAPS,
+    };
+    use crate::bridge::Bridge;
+    use crate::config::Config;
+    use crate::csr_map::CsrMap;
+
+    #[test]
+    fn detect_phy() {
+        let map = CsrMap::parse("csr_register,ddrphy_dly_sel,0xf0001000,1,rw\n").unwrap();
+        assert!(DdrControl::detect(&map).is_none());
+
+        let map = CsrMap::parse(
+            "csr_register,sdram_dfii_control,0xf0003000,1,rw\n\
+             csr_register,ddrphy_dly_sel,0xf0001000,1,rw\n\
+             csr_register,ddrphy_rdly_dq_rst,0xf0001004,1,rw\n\
+             csr_register,ddrphy_rdly_dq_inc,0xf0001008,1,rw\n\
+             csr_register,ddrphy_burstdet_clr,0xf000100c,1,rw\n\
+             csr_register,ddrphy_burstdet_seen,0xf0001010,1,ro\n\
+             memory_region,main_ram,0x40000000,0x10000000,cached\n",
+        )
+        .unwrap();
+        let ddr = DdrControl::detect(&map).unwrap();
+        assert!(ddr.trainable());
+        assert!(!ddr.has_bitslip);
+        assert_eq!(ddr.ram, Some(0x4000_0000));
+        assert_eq!(ddr.taps(), ECP5_TAPS);
+
+        let map = CsrMap::parse(
+            "csr_register,sdram_dfii_control,0xf0003000,1,rw\n\
+             csr_register,ddrphy_dly_sel,0xf0001000,1,rw\n",
+        )
+        .unwrap();
+        let ddr = DdrControl::detect(&map).unwrap();
+        assert!(!ddr.trainable());
+        assert_eq!(ddr.taps(), SERIES7_TAPS);
+    }
+
+    #[test]
+    fn bytes_belong_to_modules() {
+        let expected = [0x1122_3344, 0x5566_7788];
+        assert_eq!(bad_modules(&expected, &expected, 2), 0);
+        // Bytes 1 and 5
+        let got = [0x1122_0044, 0x5566_0088];
+        assert_eq!(bad_modules(&expected, &got, 2), 0b10);
+        assert_eq!(bad_modules(&expected, &got, 4), 0b10);
+        assert_eq!(bad_modules(&expected, &got, 8), 0b10_0010);
+        assert_eq!(bad_modules(&expected, &[0x1122_3345, 0x5566_7788], 1), 1);
+    }
+
+    #[test]
+    fn longest_window() {
+        assert_eq!(best_window(&[false, false]), None);
+        assert_eq!(best_window(&[true, false, true, true, false]), Some((2, 2)));
+        assert_eq!(best_window(&[false, true, true, true]), Some((1, 3)));
+        // The first of two the same length
+        assert_eq!(best_window(&[true, true, false, true, true]), Some((0, 2)));
+    }
+
+    #[test]
+    fn status_report() {
+        let status = DdrStatus {
+            control: 0x0e,
+            bursts_seen: Some(0b01),
+        };
+        assert!(!status.initialized());
+        let text = status.to_string();
+        assert!(text.contains("with CKE, ODT, RESET_N high"));
+        assert!(text.contains("ddrphy_burstdet_seen holds 0x1"));
+
+        let check = DdrCheck {
+            bad: 0b10,
+            bursts_seen: Some(vec![true, false]),
+        };
+        let text = check.to_string();
+        assert!(text.contains("wrong for module mask 0x2"));
+        assert!(text.contains("Module 1: no read burst seen"));
+    }
+
+    #[test]
+    fn status_only_reads() {
+        let map = CsrMap::parse(
+            "csr_register,sdram_dfii_control,0xf0003000,1,rw\n\
+             csr_register,ddrphy_dly_sel,0xf0001000,1,rw\n\
+             csr_register,ddrphy_rdly_dq_rst,0xf0001004,1,rw\n\
+             csr_register,ddrphy_rdly_dq_inc,0xf0001008,1,rw\n\
+             csr_register,ddrphy_burstdet_clr,0xf000100c,1,rw\n\
+             csr_register,ddrphy_burstdet_seen,0xf0001010,1,ro\n\
+             memory_region,main_ram,0x40000000,0x10000000,cached\n",
+        )
+        .unwrap();
+        let cfg = Config::from_args(["ddr", "--mock"]).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        bridge.poke(0xf000_3000, 1).unwrap();
+        bridge.poke(0xf000_1000, 0x5a).unwrap();
+        bridge.poke(0xf000_1010, 0b11).unwrap();
+        let ddr = DdrControl::detect(&map).unwrap();
+        assert_eq!(
+            ddr.status(&map, &bridge).unwrap(),
+            DdrStatus {
+                control: 1,
+                bursts_seen: Some(0b11)
+            }
+        );
+        // Neither the module select nor the burst bits were touched
+        assert_eq!(bridge.peek(0xf000_1000).unwrap(), 0x5a);
+        assert_eq!(bridge.peek(0xf000_100c).unwrap(), 0);
+    }
+}
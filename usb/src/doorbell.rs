@@ -0,0 +1,177 @@
+//! A way for firmware to get the debugger's attention without halting.
+//! The firmware writes something other than zero to a word the adapter
+//! polls while the target runs, and the adapter acts on it and clears it
+//! for the next ring.  The word can be a mailbox in RAM or a scratch CSR,
+//! which is cleared by writing zero, or a LiteX event manager's
+//! `<name>_ev_pending` register, which is cleared by writing back the bits
+//! that were set.  Which one it is comes from what csr.csv says is at the
+//! address.
+
+use std::fmt;
+
+use super::bridge::{Bridge, BridgeError};
+use super::config::ConfigError;
+use super::csr_map::CsrMap;
+use super::expr;
+
+/// What the adapter does when the doorbell rings
+#[derive(Clone, Debug, PartialEq)]
+pub enum DoorbellAction {
+    /// Say so on the console
+    Log,
+
+    /// Tell GDB with console output, which it shows while the target runs
+    Gdb,
+
+    /// Save a core file to this path, halting the target just long enough
+    /// to read its registers
+    Dump(String),
+}
+
+/// How a ring is acknowledged
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ack {
+    /// Write zero over it
+    Clear,
+
+    /// Write back the bits that were set, as an `ev_pending` register wants
+    WriteBack,
+}
+
+/// The word the firmware rings, given with `--doorbell`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Doorbell {
+    pub addr: u32,
+    pub ack: Ack,
+    pub actions: Vec<DoorbellAction>,
+}
+
+impl fmt::Display for Doorbell {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x}", self.addr)
+    }
+}
+
+impl Doorbell {
+    /// Parse `ADDR[:ACTIONS]`, where `ADDR` can be an expression using
+    /// `lookup` and `ACTIONS` is any of `log`, `gdb` and `dump=PATH`
+    /// separated by commas, defaulting to `log,gdb`.  It's acknowledged
+    /// by writing the bits back if `csr_map` has an event manager's pending
+    /// register there.
+    pub fn parse(
+        spec: &str,
+        lookup: &dyn Fn(&str) -> Option<u64>,
+        csr_map: Option<&CsrMap>,
+    ) -> Result<Doorbell, ConfigError> {
+        let (addr, actions) = match spec.split_once(':') {
+            Some((addr, actions)) => (addr.trim(), actions),
+            None => (spec.trim(), "log,gdb"),
+        };
+        let actions = actions
+            .split(',')
+            .map(|action| match action {
+                "log" => Ok(DoorbellAction::Log),
+                "gdb" => Ok(DoorbellAction::Gdb),
+                _ => match action.strip_prefix("dump=") {
+                    Some(path) if !path.is_empty() => Ok(DoorbellAction::Dump(path.to_owned())),
+                    _ => Err(ConfigError::InvalidDoorbell(spec.to_owned())),
+                },
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let addr = expr::eval_u32(addr, lookup)?;
+        let register = csr_map.and_then(|map| map.register_at(addr));
+        let ack = if register.is_some_and(|name| name.ends_with("_ev_pending")) {
+            Ack::WriteBack
+        } else {
+            Ack::Clear
+        };
+        Ok(Doorbell { addr, ack, actions })
+    }
+
+    /// What the firmware rang with, if it did, acknowledging it
+    pub fn check(&self, bridge: &Bridge) -> Result<Option<u32>, BridgeError> {
+        let value = bridge.peek(self.addr)?;
+        if value == 0 {
+            return Ok(None);
+        }
+        bridge.poke(
+            self.addr,
+            match self.ack {
+                Ack::Clear => 0,
+                Ack::WriteBack => value,
+            },
+        )?;
+        Ok(Some(value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Ack, Doorbell, DoorbellAction};
+    use crate::bridge::Bridge;
+    use crate::config::{Config, ConfigError};
+    use crate::csr_map::CsrMap;
+
+    const CSR_CSV: &str = "csr_register,ctrl_scratch,0xf0000004,1,rw\n\
+                           csr_register,mailbox_ev_pending,0xf0006010,1,rw\n";
+
+    fn parse(spec: &str) -> Result<Doorbell, ConfigError> {
+        let map = CsrMap::parse(CSR_CSV).unwrap();
+        Doorbell::parse(spec, &|name| map.lookup(name), Some(&map))
+    }
+
+    #[test]
+    fn parse_specs() {
+        assert_eq!(
+            parse("ctrl_scratch").unwrap(),
+            Doorbell {
+                addr: 0xf000_0004,
+                ack: Ack::Clear,
+                actions: vec![DoorbellAction::Log, DoorbellAction::Gdb],
+            }
+        );
+        assert_eq!(
+            parse("mailbox_ev_pending:dump=ring.core").unwrap(),
+            Doorbell {
+                addr: 0xf000_6010,
+                ack: Ack::WriteBack,
+                actions: vec![DoorbellAction::Dump("ring.core".to_owned())],
+            }
+        );
+        assert!(parse("ctrl_scratch:beep").is_err());
+        assert!(parse("ctrl_scratch:dump=").is_err());
+        assert!(parse("nowhere").is_err());
+    }
+
+    #[test]
+    fn ack_follows_the_register_not_the_spelling() {
+        // The same register by address is still an event manager's
+        assert_eq!(parse("0xf0006010").unwrap().ack, Ack::WriteBack);
+        assert_eq!(parse("mailbox_ev_pending + 4").unwrap().ack, Ack::Clear);
+        // RAM is cleared, and so is everything without a csr.csv
+        assert_eq!(parse("0x40000000").unwrap().ack, Ack::Clear);
+        let ram = Doorbell::parse("0xf0006010", &|_| None, None).unwrap();
+        assert_eq!(ram.ack, Ack::Clear);
+    }
+
+    #[test]
+    fn rings_are_acknowledged() {
+        let cfg = Config::from_args(["doorbell", "--mock"]).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        let mut doorbell = parse("0x40000000").unwrap();
+        assert_eq!(doorbell.check(&bridge).unwrap(), None);
+
+        bridge.poke(0x4000_0000, 0x12).unwrap();
+        assert_eq!(doorbell.check(&bridge).unwrap(), Some(0x12));
+        assert_eq!(bridge.peek(0x4000_0000).unwrap(), 0);
+        assert_eq!(doorbell.check(&bridge).unwrap(), None);
+
+        // An event manager clears the bits written back to it; the mock
+        // bridge just keeps them, which shows what was written
+        doorbell.ack = Ack::WriteBack;
+        bridge.poke(0x4000_0000, 0x5).unwrap();
+        assert_eq!(doorbell.check(&bridge).unwrap(), Some(0x5));
+        assert_eq!(bridge.peek(0x4000_0000).unwrap(), 0x5);
+    }
+}
@@ -0,0 +1,144 @@
+//! Error counters from LiteDRAM's ECC frontend.  Each port with ECC has a
+//! count of single-bit errors it corrected and double-bit errors it could
+//! only detect, as `<port>_sec_errors` and `<port>_ded_errors` in csr.csv,
+//! along with a `<port>_clear` register that zeroes them.  Counts going up
+//! while the debugger reads DRAM point at marginal memory rather than a
+//! bug in the firmware.
+
+use std::fmt;
+use std::ops::Sub;
+
+use super::board::MemoryRegion;
+use super::bridge::Bridge;
+use super::csr_map::{CsrMap, CsrMapError};
+
+/// The region LiteX puts DRAM in
+const DRAM_REGION: &str = "main_ram";
+
+/// One port's counters
+pub struct EccBank {
+    /// What the registers' names start with
+    pub name: String,
+    has_clear: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EccCounts {
+    /// Single-bit errors that were corrected
+    pub corrected: u64,
+
+    /// Double-bit errors that were detected and passed on
+    pub uncorrectable: u64,
+}
+
+impl Sub for EccCounts {
+    type Output = EccCounts;
+
+    /// How many more errors there are than in `earlier`.  The counters
+    /// only go down when they're cleared, which counts as no new errors.
+    fn sub(self, earlier: EccCounts) -> EccCounts {
+        EccCounts {
+            corrected: self.corrected.saturating_sub(earlier.corrected),
+            uncorrectable: self.uncorrectable.saturating_sub(earlier.uncorrectable),
+        }
+    }
+}
+
+impl EccCounts {
+    pub fn is_zero(&self) -> bool {
+        *self == EccCounts::default()
+    }
+}
+
+impl fmt::Display for EccCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} corrected, {} uncorrectable",
+            self.corrected, self.uncorrectable
+        )
+    }
+}
+
+impl EccBank {
+    /// Find every port with ECC counters in csr.csv
+    pub fn detect(map: &CsrMap) -> Vec<EccBank> {
+        map.registers
+            .keys()
+            .filter_map(|name| name.strip_suffix("_sec_errors"))
+            .filter(|port| map.registers.contains_key(&format!("{}_ded_errors", port)))
+            .map(|port| EccBank {
+                name: port.to_owned(),
+                has_clear: map.registers.contains_key(&format!("{}_clear", port)),
+            })
+            .collect()
+    }
+
+    pub fn read(&self, map: &CsrMap, bridge: &Bridge) -> Result<EccCounts, CsrMapError> {
+        Ok(EccCounts {
+            corrected: map.read_register(bridge, &format!("{}_sec_errors", self.name))?,
+            uncorrectable: map.read_register(bridge, &format!("{}_ded_errors", self.name))?,
+        })
+    }
+
+    /// Zero the counters, returning whether the port can do that
+    pub fn clear(&self, map: &CsrMap, bridge: &Bridge) -> Result<bool, CsrMapError> {
+        if !self.has_clear {
+            return Ok(false);
+        }
+        map.write_register(bridge, &format!("{}_clear", self.name), 1)?;
+        Ok(true)
+    }
+}
+
+/// The counts of every bank added together
+pub fn total(banks: &[EccBank], map: &CsrMap, bridge: &Bridge) -> Result<EccCounts, CsrMapError> {
+    let mut sum = EccCounts::default();
+    for bank in banks {
+        let counts = bank.read(map, bridge)?;
+        sum.corrected += counts.corrected;
+        sum.uncorrectable += counts.uncorrectable;
+    }
+    Ok(sum)
+}
+
+/// Whether any of `addr` to `addr + len` is in DRAM
+pub fn touches_dram(regions: &[MemoryRegion], addr: u32, len: u32) -> bool {
+    let end = addr as u64 + len as u64;
+    regions.iter().any(|r| {
+        r.name == DRAM_REGION
+            && (addr as u64) < r.base as u64 + r.size as u64
+            && end > r.base as u64
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EccBank, EccCounts};
+    use crate::csr_map::CsrMap;
+
+    #[test]
+    fn detect_banks() {
+        let map = CsrMap::parse(
+            "csr_register,sdram_ecc_clear,0xf0003000,1,rw\n\
+             csr_register,sdram_ecc_sec_errors,0xf0003004,1,ro\n\
+             csr_register,sdram_ecc_ded_errors,0xf0003008,1,ro\n\
+             csr_register,other_sec_errors,0xf0004000,1,ro\n",
+        )
+        .unwrap();
+        let banks = EccBank::detect(&map);
+        assert_eq!(banks.len(), 1);
+        assert_eq!(banks[0].name, "sdram_ecc");
+        assert!(banks[0].has_clear);
+    }
+
+    #[test]
+    fn new_errors() {
+        let counts = |corrected, uncorrectable| EccCounts {
+            corrected,
+            uncorrectable,
+        };
+        assert_eq!(counts(5, 1) - counts(3, 1), counts(2, 0));
+        assert!((counts(0, 0) - counts(3, 1)).is_zero());
+    }
+}
@@ -0,0 +1,199 @@
+//! Running the GDB server inside another program.  A debugger front end
+//! that links this crate can hand over its own pipe, socket or in-process
+//! channel rather than going through a TCP port:
+//!
+//! ```ignore
+//! let cfg = Config::from_args(&["gui", "--mock"])?;
+//! let bridge = Bridge::new(&cfg)?;
+//! bridge.connect()?;
+//! let (reader, writer) = stream.split();
+//! thread::spawn(move || serve_gdb(&cfg, &bridge, reader, writer));
+//! // ... and speak GDB's protocol on the other end of `stream`
+//! ```
+//!
+//! The reader and writer are `futures` `AsyncRead` and `AsyncWrite`, which
+//! are polled on the server's own threads with `futures`' executor rather
+//! than any particular runtime's.  Blocking `Read` and `Write` can be
+//! passed in `futures::io::AllowStdIo`.
+
+use std::io::{self, Read, Write};
+
+use futures::executor::block_on;
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::bridge::Bridge;
+use super::config::Config;
+use super::gdb::{GdbServer, GdbServerError};
+use super::riscv::RiscvCpu;
+use super::target::TargetState;
+use super::transport::Connection;
+use super::ui::Event;
+
+/// Serve one GDB session over `reader` and `writer`, returning once the
+/// other end closes `reader`.  Breakpoints are taken out and the target
+/// is left running when it does, the same as when GDB hangs up on the
+/// network server.  A session that ends in an error leaves the harts as
+/// they are.
+pub fn serve_gdb(
+    cfg: &Config,
+    bridge: &Bridge,
+    reader: impl AsyncRead + Unpin + Send + 'static,
+    writer: impl AsyncWrite + Unpin + Send + 'static,
+) -> Result<(), GdbServerError> {
+    let cpu = RiscvCpu::new(cfg)?;
+    let connection = Connection::from_stream(Blocking(reader), Blocking(writer));
+    ui_event!(Event::Attach, "GDB connected over a stream");
+    let mut gdb =
+        GdbServer::with_connection(cfg, connection, TargetState::new_shared(), None, None);
+    let result = loop {
+        if let Err(e) = gdb.process(&cpu, bridge) {
+            break e;
+        }
+    };
+    gdb.end_session(&cpu, bridge, &result);
+    ui_event!(Event::Detach, "GDB disconnected");
+    match result {
+        GdbServerError::ConnectionClosed => Ok(()),
+        e => Err(e),
+    }
+}
+
+/// An async reader or writer waited on in place, for the connection's
+/// threads
+struct Blocking<T>(T);
+
+impl<R: AsyncRead + Unpin> Read for Blocking<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        block_on(self.0.read(buf))
+    }
+}
+
+impl<W: AsyncWrite + Unpin> Write for Blocking<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        block_on(self.0.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        block_on(self.0.flush())
+    }
+}
+
+/// Both ends of an in-memory pipe, for talking to the server in tests
+/// without depending on the platform's sockets
+#[cfg(test)]
+pub mod pipe {
+    use std::io::{self, Read, Write};
+    use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+    use std::time::Duration;
+
+    pub struct PipeReader {
+        chunks: Receiver<Vec<u8>>,
+        buffer: Vec<u8>,
+        timeout: Option<Duration>,
+    }
+
+    pub struct PipeWriter(Sender<Vec<u8>>);
+
+    pub fn pipe() -> (PipeReader, PipeWriter) {
+        let (tx, rx) = channel();
+        (
+            PipeReader {
+                chunks: rx,
+                buffer: vec![],
+                timeout: None,
+            },
+            PipeWriter(tx),
+        )
+    }
+
+    impl PipeReader {
+        /// Fail a read that waits longer than `timeout`, as a socket's
+        /// read timeout would
+        pub fn set_timeout(&mut self, timeout: Duration) {
+            self.timeout = Some(timeout);
+        }
+    }
+
+    impl Read for PipeReader {
+        /// Reads end once every writer has gone
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.buffer.is_empty() {
+                let chunk = match self.timeout {
+                    Some(timeout) => self.chunks.recv_timeout(timeout),
+                    None => self
+                        .chunks
+                        .recv()
+                        .map_err(|_| RecvTimeoutError::Disconnected),
+                };
+                match chunk {
+                    Ok(chunk) => self.buffer = chunk,
+                    Err(RecvTimeoutError::Disconnected) => return Ok(0),
+                    Err(RecvTimeoutError::Timeout) => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "pipe read timed out",
+                        ))
+                    }
+                }
+            }
+            let len = buf.len().min(self.buffer.len());
+            buf[..len].copy_from_slice(&self.buffer[..len]);
+            self.buffer.drain(..len);
+            Ok(len)
+        }
+    }
+
+    impl Write for PipeWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0
+                .send(buf.to_vec())
+                .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::pipe::pipe;
+    use super::serve_gdb;
+    use crate::bridge::Bridge;
+    use crate::config::Config;
+    use futures::io::AllowStdIo;
+    use std::io::{Read, Write};
+    use std::thread;
+
+    #[test]
+    fn session_over_pipes() {
+        let cfg = Config::from_args(["embed", "--mock"]).unwrap();
+        let (server_in, mut to_server) = pipe();
+        let (mut from_server, server_out) = pipe();
+        let server = thread::spawn(move || {
+            let bridge = Bridge::new(&cfg).unwrap();
+            bridge.connect().unwrap();
+            serve_gdb(
+                &cfg,
+                &bridge,
+                AllowStdIo::new(server_in),
+                AllowStdIo::new(server_out),
+            )
+            .is_ok()
+        });
+
+        to_server.write_all(b"$qEcho:hi#fb").unwrap();
+        let mut reply = vec![];
+        let mut byte = [0; 1];
+        while !reply.ends_with(b"#") {
+            from_server.read_exact(&mut byte).unwrap();
+            reply.push(byte[0]);
+        }
+        assert_eq!(reply, b"+$hi#");
+
+        drop(to_server);
+        assert!(server.join().unwrap());
+    }
+}
@@ -0,0 +1,503 @@
+use std::io;
+use std::net::{ToSocketAddrs, UdpSocket};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::bridge::{BatchOp, BridgeError};
+use super::config::Config;
+
+/// Port that LiteX Etherbone cores listen on unless told otherwise
+pub const DEFAULT_PORT: u16 = 1234;
+
+const MAGIC: [u8; 2] = [0x4e, 0x6f];
+
+/// Version 1, with the probe flags in the low bits
+const VERSION: u8 = 0x10;
+const FLAG_PROBE: u8 = 0x01;
+const FLAG_PROBE_REPLY: u8 = 0x02;
+
+/// 32-bit addresses and 32-bit data
+const SIZES: u8 = 0x44;
+
+const HEADER_LENGTH: usize = 8;
+const RECORD_LENGTH: usize = 4;
+
+/// A record counts its writes and reads in a byte each
+const MAX_RECORD_OPS: usize = 255;
+
+/// How many times a request is resent before the link is considered dead
+const MAX_RESENDS: u32 = 3;
+
+/// The UDP conversation with the target.  Every read record carries a
+/// fresh sequence number as its return address, which the target echoes
+/// back as the write address of its reply, so replies to requests that
+/// already timed out can be recognised and thrown away.  The sequence
+/// carries on across reopened sockets, so stragglers from an old socket
+/// are still recognised as stale.
+struct Link {
+    /// `None` until connected, and after the target stops answering
+    socket: Option<UdpSocket>,
+    sequence: u32,
+    last_used: Instant,
+}
+
+/// The state shared between the bridge and its keep-alive thread
+struct Shared {
+    target: String,
+    timeout: Duration,
+    link: Mutex<Link>,
+}
+
+/// A bridge to a LiteX Etherbone core over UDP.  Lost packets are resent,
+/// idle links are kept open with probe packets so NAT and sleepy network
+/// stacks don't forget about them, and a link that stops answering is
+/// reopened from a new socket.
+pub struct EtherboneBridge {
+    shared: Arc<Shared>,
+    keepalive: Option<Duration>,
+
+    /// The profile's cap on burst sizes, in bytes
+    burst_limit: usize,
+}
+
+impl EtherboneBridge {
+    pub fn new(cfg: &Config) -> Result<Self, BridgeError> {
+        let target = match cfg.etherbone {
+            Some(ref t) if t.contains(':') => t.clone(),
+            Some(ref t) => format!("{}:{}", t, DEFAULT_PORT),
+            None => return Err(BridgeError::NotConnected),
+        };
+        Ok(EtherboneBridge {
+            shared: Arc::new(Shared {
+                target,
+                timeout: cfg.etherbone_timeout,
+                link: Mutex::new(Link {
+                    socket: None,
+                    sequence: 0,
+                    last_used: Instant::now(),
+                }),
+            }),
+            keepalive: cfg.etherbone_keepalive,
+            burst_limit: cfg.tuning.burst_limit.unwrap_or(usize::MAX),
+        })
+    }
+
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        let mut link = self.shared.link.lock().unwrap();
+        if link.socket.is_some() {
+            return Ok(());
+        }
+        self.shared.open(&mut link)?;
+        if let Some(interval) = self.keepalive {
+            let shared = Arc::downgrade(&self.shared);
+            thread::spawn(move || keepalive_thread(shared, interval));
+        }
+        Ok(())
+    }
+
+    /// One record's worth of reads, which keeps a reply inside a single
+    /// Ethernet frame
+    pub fn max_burst(&self) -> usize {
+        self.burst_limit.min(MAX_RECORD_OPS * 4)
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        self.execute(&[BatchOp::Write(addr, value)]).map(|_| ())
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        Ok(self.execute(&[BatchOp::Read(addr)])?[0])
+    }
+
+    /// Runs of writes to consecutive addresses go out as one record, as do
+    /// runs of reads.  Each record is its own packet, since that's all the
+    /// LiteX core handles.
+    pub fn execute(&self, ops: &[BatchOp]) -> Result<Vec<u32>, BridgeError> {
+        let mut results = vec![];
+        let mut idx = 0;
+        while idx < ops.len() {
+            match ops[idx] {
+                BatchOp::Write(base, _) => {
+                    let mut values = vec![];
+                    while let Some(&BatchOp::Write(addr, value)) = ops.get(idx) {
+                        if values.len() == MAX_RECORD_OPS
+                            || addr != base.wrapping_add(4 * values.len() as u32)
+                        {
+                            break;
+                        }
+                        values.push(value);
+                        idx += 1;
+                    }
+                    self.shared.write(base, &values)?;
+                }
+                BatchOp::Read(_) => {
+                    let mut addrs = vec![];
+                    while let Some(&BatchOp::Read(addr)) = ops.get(idx) {
+                        if addrs.len() == MAX_RECORD_OPS {
+                            break;
+                        }
+                        addrs.push(addr);
+                        idx += 1;
+                    }
+                    results.extend(self.shared.read(&addrs)?);
+                }
+            }
+        }
+        Ok(results)
+    }
+}
+
+impl Shared {
+    /// Open a new socket and make sure the target answers on it
+    fn open(&self, link: &mut Link) -> Result<(), BridgeError> {
+        link.socket = None;
+        let addr = self
+            .target
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, self.target.clone()))?;
+        let bind = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.connect(addr)?;
+        socket.set_read_timeout(Some(self.timeout))?;
+        link.socket = Some(socket);
+        if let Err(e) = link.probe() {
+            link.socket = None;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Run `f` on the link, reopening it once if it has stopped answering
+    fn with_link<T>(
+        &self,
+        mut f: impl FnMut(&mut Link) -> Result<T, BridgeError>,
+    ) -> Result<T, BridgeError> {
+        let mut link = self.link.lock().unwrap();
+        for attempt in 0..2 {
+            if link.socket.is_none() {
+                self.open(&mut link)?;
+                if attempt > 0 {
+                    ui_info!("Etherbone link to {} reopened", self.target);
+                }
+            }
+            match f(&mut link) {
+                Err(BridgeError::NotConnected) => {
+                    log_bridge!(@Verbose, "etherbone: {} stopped answering, reopening", self.target);
+                    link.socket = None;
+                }
+                result => return result,
+            }
+        }
+        Err(BridgeError::NotConnected)
+    }
+
+    fn write(&self, base: u32, values: &[u32]) -> Result<(), BridgeError> {
+        let mut packet = record(values.len(), 0);
+        let mut word = [0; 4];
+        for &value in [base].iter().chain(values) {
+            BigEndian::write_u32(&mut word, value);
+            packet.extend_from_slice(&word);
+        }
+        // The target doesn't acknowledge writes, so there's nothing to
+        // resend.  A link that has died is caught by the next read or probe.
+        self.with_link(|link| link.send(&packet))
+    }
+
+    fn read(&self, addrs: &[u32]) -> Result<Vec<u32>, BridgeError> {
+        self.with_link(|link| {
+            let sequence = link.next_sequence();
+            let mut packet = record(0, addrs.len());
+            let mut word = [0; 4];
+            for &addr in [sequence].iter().chain(addrs) {
+                BigEndian::write_u32(&mut word, addr);
+                packet.extend_from_slice(&word);
+            }
+            for _ in 0..MAX_RESENDS {
+                link.send(&packet)?;
+                if let Some(values) = link.receive_reply(sequence, addrs.len())? {
+                    return Ok(values);
+                }
+            }
+            Err(BridgeError::NotConnected)
+        })
+    }
+}
+
+impl Link {
+    fn next_sequence(&mut self) -> u32 {
+        self.sequence = self.sequence.wrapping_add(1);
+        self.sequence
+    }
+
+    fn socket(&self) -> Result<&UdpSocket, BridgeError> {
+        self.socket.as_ref().ok_or(BridgeError::NotConnected)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> Result<(), BridgeError> {
+        self.socket()?.send(packet)?;
+        self.last_used = Instant::now();
+        Ok(())
+    }
+
+    /// Wait for the reply to read record `sequence`.  Returns `None` if it
+    /// didn't turn up in time.
+    fn receive_reply(
+        &mut self,
+        sequence: u32,
+        count: usize,
+    ) -> Result<Option<Vec<u32>>, BridgeError> {
+        let mut buffer = [0; 2048];
+        loop {
+            let len = match self.socket()?.recv(&mut buffer) {
+                Ok(len) => len,
+                Err(ref e) if is_silence(e) => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            let packet = &buffer[..len];
+            if len < HEADER_LENGTH + RECORD_LENGTH + 4 || packet[0..2] != MAGIC {
+                continue;
+            }
+            if packet[2] & FLAG_PROBE_REPLY != 0 {
+                continue;
+            }
+            let record = &packet[HEADER_LENGTH..];
+            let wcount = record[2] as usize;
+            let base = BigEndian::read_u32(&record[RECORD_LENGTH..]);
+            if base != sequence {
+                log_bridge!(
+                    "etherbone: dropping stale reply {:08x} (want {:08x})",
+                    base,
+                    sequence
+                );
+                continue;
+            }
+            let data = &record[RECORD_LENGTH + 4..];
+            if wcount != count || data.len() < count * 4 {
+                return Err(BridgeError::LengthError(
+                    count * 4,
+                    data.len().min(wcount * 4),
+                ));
+            }
+            return Ok(Some(
+                data.chunks(4)
+                    .take(count)
+                    .map(BigEndian::read_u32)
+                    .collect(),
+            ));
+        }
+    }
+
+    /// Check the target is still there with a probe packet, which it
+    /// answers without touching the bus
+    fn probe(&mut self) -> Result<(), BridgeError> {
+        let mut packet = header(FLAG_PROBE);
+        packet.extend_from_slice(&[0; RECORD_LENGTH]);
+        let mut buffer = [0; 2048];
+        for _ in 0..MAX_RESENDS {
+            self.send(&packet)?;
+            loop {
+                match self.socket()?.recv(&mut buffer) {
+                    Ok(len) if len >= 3 && buffer[0..2] == MAGIC => {
+                        if buffer[2] & FLAG_PROBE_REPLY != 0 {
+                            return Ok(());
+                        }
+                    }
+                    Ok(_) => (),
+                    Err(ref e) if is_silence(e) => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Err(BridgeError::NotConnected)
+    }
+}
+
+fn header(flags: u8) -> Vec<u8> {
+    vec![MAGIC[0], MAGIC[1], VERSION | flags, SIZES, 0, 0, 0, 0]
+}
+
+/// The start of a packet holding one record with these counts
+fn record(writes: usize, reads: usize) -> Vec<u8> {
+    let mut packet = header(0);
+    packet.extend_from_slice(&[0, 0x0f, writes as u8, reads as u8]);
+    packet
+}
+
+/// Whether a failed receive just means nothing came back.  A refused
+/// connection is the ICMP error from a target that's rebooting, which is
+/// worth waiting out like any other silence.
+fn is_silence(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::ConnectionRefused
+    )
+}
+
+/// Probe the target whenever the link has been quiet for `interval`, and
+/// reopen it if it has gone away.  Exits once the bridge is dropped.
+fn keepalive_thread(shared: Weak<Shared>, interval: Duration) {
+    loop {
+        thread::sleep(interval / 2);
+        let shared = match shared.upgrade() {
+            Some(s) => s,
+            None => return,
+        };
+        let mut link = shared.link.lock().unwrap();
+        if link.socket.is_some() {
+            if link.last_used.elapsed() < interval || link.probe().is_ok() {
+                continue;
+            }
+            log_bridge!(@Verbose, "etherbone: keep-alive to {} went unanswered", shared.target);
+        }
+        if shared.open(&mut link).is_ok() {
+            ui_info!("Etherbone link to {} reopened", shared.target);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::net::UdpSocket;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    use byteorder::{BigEndian, ByteOrder};
+
+    use super::{record, EtherboneBridge, FLAG_PROBE, FLAG_PROBE_REPLY, MAGIC};
+    use crate::bridge::BatchOp;
+    use crate::config::Config;
+
+    /// A LiteX Etherbone core in front of some memory, which ignores the
+    /// first `lost` read requests and answers each read with a stale reply
+    /// ahead of the real one.  Every packet it gets is kept.
+    struct FakeTarget {
+        port: u16,
+        packets: Arc<Mutex<Vec<Vec<u8>>>>,
+    }
+
+    impl FakeTarget {
+        fn start(mut lost: usize) -> FakeTarget {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_secs(2)))
+                .unwrap();
+            let port = socket.local_addr().unwrap().port();
+            let packets = Arc::new(Mutex::new(vec![]));
+            let seen = packets.clone();
+            thread::spawn(move || {
+                let mut memory: HashMap<u32, u32> = HashMap::new();
+                let mut buffer = [0; 2048];
+                while let Ok((len, from)) = socket.recv_from(&mut buffer) {
+                    let packet = buffer[..len].to_vec();
+                    seen.lock().unwrap().push(packet.clone());
+                    if packet[2] & FLAG_PROBE != 0 {
+                        let mut reply = vec![MAGIC[0], MAGIC[1], 0x10 | FLAG_PROBE_REPLY, 0x44];
+                        reply.extend_from_slice(&[0; 8]);
+                        socket.send_to(&reply, from).unwrap();
+                        continue;
+                    }
+                    let (writes, reads) = (packet[10] as usize, packet[11] as usize);
+                    let words: Vec<u32> = packet[12..].chunks(4).map(BigEndian::read_u32).collect();
+                    if writes > 0 {
+                        for (n, &value) in words[1..=writes].iter().enumerate() {
+                            memory.insert(words[0] + 4 * n as u32, value);
+                        }
+                        continue;
+                    }
+                    if lost > 0 {
+                        lost -= 1;
+                        continue;
+                    }
+                    let values: Vec<u32> = words[1..=reads]
+                        .iter()
+                        .map(|addr| memory.get(addr).copied().unwrap_or(0))
+                        .collect();
+                    // The stale reply has the wrong values in it
+                    for (sequence, flip) in [(words[0].wrapping_sub(1), !0), (words[0], 0)] {
+                        let mut reply = record(reads, 0);
+                        reply.extend_from_slice(&sequence.to_be_bytes());
+                        for value in &values {
+                            reply.extend_from_slice(&(value ^ flip).to_be_bytes());
+                        }
+                        socket.send_to(&reply, from).unwrap();
+                    }
+                }
+            });
+            FakeTarget { port, packets }
+        }
+
+        fn bridge(&self) -> EtherboneBridge {
+            let target = format!("127.0.0.1:{}", self.port);
+            let cfg = Config::from_args([
+                "etherbone",
+                "--etherbone",
+                &target,
+                "--etherbone-timeout",
+                "100",
+                "--etherbone-keepalive",
+                "0",
+            ])
+            .unwrap();
+            let bridge = EtherboneBridge::new(&cfg).unwrap();
+            bridge.connect().unwrap();
+            bridge
+        }
+    }
+
+    #[test]
+    fn records() {
+        assert_eq!(
+            record(2, 0),
+            [0x4e, 0x6f, 0x10, 0x44, 0, 0, 0, 0, 0, 0x0f, 2, 0]
+        );
+        assert_eq!(record(0, 255)[10..], [0, 255]);
+    }
+
+    #[test]
+    fn runs_share_a_record() {
+        let target = FakeTarget::start(0);
+        let bridge = target.bridge();
+        let ops = [
+            BatchOp::Write(0x100, 1),
+            BatchOp::Write(0x104, 2),
+            BatchOp::Write(0x200, 3),
+            BatchOp::Read(0x100),
+            BatchOp::Read(0x104),
+            BatchOp::Read(0x200),
+        ];
+        assert_eq!(bridge.execute(&ops).unwrap(), [1, 2, 3]);
+
+        // After the probe: the run of two writes, the write on its own, and
+        // every read together
+        let packets = target.packets.lock().unwrap();
+        let counts: Vec<(u8, u8)> = packets[1..].iter().map(|p| (p[10], p[11])).collect();
+        assert_eq!(counts, [(2, 0), (1, 0), (0, 3)]);
+        assert_eq!(BigEndian::read_u32(&packets[1][12..]), 0x100);
+    }
+
+    #[test]
+    fn lost_and_stale_replies() {
+        let target = FakeTarget::start(2);
+        let bridge = target.bridge();
+        bridge.poke(0x40, 0xdead_beef).unwrap();
+        assert_eq!(bridge.peek(0x40).unwrap(), 0xdead_beef);
+        // The same read went out three times, with the same sequence number
+        let packets = target.packets.lock().unwrap();
+        let reads: Vec<&Vec<u8>> = packets
+            .iter()
+            .filter(|p| p.len() > 11 && p[11] == 1)
+            .collect();
+        assert_eq!(reads.len(), 3);
+        assert!(reads.iter().all(|p| *p == reads[0]));
+    }
+}
@@ -0,0 +1,349 @@
+//! What the target is doing, as a stream of JSON objects one per line, so
+//! IDE plugins and dashboards can follow along without speaking the GDB
+//! protocol.  Anything that connects to the socket given with `--events`
+//! gets every event from then on.  Events are written out on a thread of
+//! their own, so a slow listener never holds the adapter up: one that
+//! stops reading is dropped, and events that pile up past
+//! [`QUEUE_DEPTH`] are thrown away.
+//!
+//!   {"event":"halted","time":1700000000.123,"hart":0,"pc":1073742080,"reason":"breakpoint"}
+
+use std::fs;
+use std::io::{self, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+#[cfg(feature = "server")]
+use super::bridge::Bridge;
+use super::config::{Config, ConfigError};
+use super::logging;
+#[cfg(feature = "server")]
+use super::riscv::{register_number, HaltReason, RiscvCpu};
+
+/// How long a listener gets to take an event before it's dropped
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How many events can be waiting to be written before more are dropped
+const QUEUE_DEPTH: usize = 256;
+
+/// Where to listen for event stream connections: `unix:PATH`, or
+/// `tcp:[HOST:]PORT`, which listens on localhost if no host is given
+#[derive(Clone, Debug)]
+pub enum EventSink {
+    Unix(String),
+    Tcp(String),
+}
+
+impl EventSink {
+    pub fn parse(spec: &str) -> Result<EventSink, ConfigError> {
+        let invalid = || ConfigError::InvalidEventSink(spec.to_owned());
+        let mut fields = spec.splitn(2, ':');
+        let kind = fields.next().unwrap_or_default();
+        let rest = fields
+            .next()
+            .filter(|r| !r.is_empty())
+            .ok_or_else(invalid)?;
+        match kind {
+            "unix" => Ok(EventSink::Unix(rest.to_owned())),
+            "tcp" if rest.contains(':') => Ok(EventSink::Tcp(rest.to_owned())),
+            "tcp" => Ok(EventSink::Tcp(format!("127.0.0.1:{}", rest))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Without the server, only loads have anything to report
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub enum TargetEvent {
+    /// A hart stopped, and why
+    Halted {
+        hart: usize,
+        pc: u32,
+        reason: &'static str,
+    },
+
+    /// The target was let run
+    Resumed,
+
+    /// A hart stopped at a breakpoint, which also gets a `Halted`
+    BreakpointHit { hart: usize, addr: u32 },
+
+    /// How far a load has got
+    FlashProgress { done: u32, total: u32 },
+
+    /// The USB device came back after the host slept
+    #[cfg(any(feature = "usb", all(feature = "usbfs", target_os = "linux")))]
+    UsbReconnect,
+
+    /// Firmware rang the doorbell with this value
+    Doorbell { value: u32 },
+}
+
+impl TargetEvent {
+    fn name(self) -> &'static str {
+        match self {
+            TargetEvent::Halted { .. } => "halted",
+            TargetEvent::Resumed => "resumed",
+            TargetEvent::BreakpointHit { .. } => "breakpoint-hit",
+            TargetEvent::FlashProgress { .. } => "flash-progress",
+            #[cfg(any(feature = "usb", all(feature = "usbfs", target_os = "linux")))]
+            TargetEvent::UsbReconnect => "usb-reconnect",
+            TargetEvent::Doorbell { .. } => "doorbell",
+        }
+    }
+
+    /// The event as one line of JSON.  Every string in it is one of ours,
+    /// so nothing needs escaping.
+    fn json_line(self, time: &str) -> String {
+        let fields = match self {
+            TargetEvent::Halted { hart, pc, reason } => {
+                format!(",\"hart\":{},\"pc\":{},\"reason\":\"{}\"", hart, pc, reason)
+            }
+            TargetEvent::BreakpointHit { hart, addr } => {
+                format!(",\"hart\":{},\"addr\":{}", hart, addr)
+            }
+            TargetEvent::FlashProgress { done, total } => {
+                format!(",\"done\":{},\"total\":{}", done, total)
+            }
+            TargetEvent::Doorbell { value } => format!(",\"value\":{}", value),
+            #[cfg(any(feature = "usb", all(feature = "usbfs", target_os = "linux")))]
+            TargetEvent::UsbReconnect => String::new(),
+            TargetEvent::Resumed => String::new(),
+        };
+        format!(
+            "{{\"event\":\"{}\",\"time\":{}{}}}\n",
+            self.name(),
+            time,
+            fields
+        )
+    }
+}
+
+/// Where events go to be written, once there's an event stream
+static QUEUE: Mutex<Option<SyncSender<String>>> = Mutex::new(None);
+static LISTENERS: Mutex<Vec<Box<dyn Write + Send>>> = Mutex::new(Vec::new());
+static LISTENER_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Start listening for event stream connections, if the config asks for it
+pub fn init(cfg: &Config) -> io::Result<()> {
+    match cfg.events {
+        Some(EventSink::Tcp(ref addr)) => {
+            let listener = TcpListener::bind(addr)?;
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    if stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_ok() {
+                        add_listener(Box::new(stream));
+                    }
+                }
+            });
+        }
+        #[cfg(unix)]
+        Some(EventSink::Unix(ref path)) => {
+            // A socket left behind by an earlier run would stop the bind
+            if let Ok(meta) = fs::symlink_metadata(path) {
+                use std::os::unix::fs::FileTypeExt;
+                if meta.file_type().is_socket() {
+                    fs::remove_file(path)?;
+                }
+            }
+            let listener = UnixListener::bind(path)?;
+            thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    if stream.set_write_timeout(Some(WRITE_TIMEOUT)).is_ok() {
+                        add_listener(Box::new(stream));
+                    }
+                }
+            });
+        }
+        #[cfg(not(unix))]
+        Some(EventSink::Unix(_)) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Unix sockets are only supported on unix",
+            ))
+        }
+        None => return Ok(()),
+    }
+    let (queue, lines) = mpsc::sync_channel(QUEUE_DEPTH);
+    thread::spawn(move || write_events(lines));
+    *QUEUE.lock().unwrap() = Some(queue);
+    Ok(())
+}
+
+fn add_listener(listener: Box<dyn Write + Send>) {
+    log_adapter!("Event stream listener connected");
+    let mut listeners = LISTENERS.lock().unwrap();
+    listeners.push(listener);
+    LISTENER_COUNT.store(listeners.len(), Ordering::Relaxed);
+}
+
+/// Send each event to everyone listening, dropping anyone who isn't
+fn write_events(lines: Receiver<String>) {
+    for line in lines {
+        let mut listeners = LISTENERS.lock().unwrap();
+        listeners.retain_mut(|listener| {
+            let sent = listener.write_all(line.as_bytes()).is_ok();
+            if !sent {
+                log_adapter!("Event stream listener went away");
+            }
+            sent
+        });
+        LISTENER_COUNT.store(listeners.len(), Ordering::Relaxed);
+    }
+}
+
+/// Whether there's an event stream at all, so events that take work to
+/// put together can be skipped when there isn't
+#[cfg(feature = "server")]
+pub fn enabled() -> bool {
+    QUEUE.lock().unwrap().is_some()
+}
+
+/// Whether anyone is listening to the event stream right now
+fn listening() -> bool {
+    LISTENER_COUNT.load(Ordering::Relaxed) > 0
+}
+
+/// Queue an event for everyone listening.  This never waits: if the
+/// writer has fallen too far behind, the event is dropped.
+pub fn emit(event: TargetEvent) {
+    let queue = QUEUE.lock().unwrap();
+    let queue = match *queue {
+        Some(ref queue) if listening() => queue,
+        _ => return,
+    };
+    let line = event.json_line(&logging::timestamp());
+    if let Err(TrySendError::Full(_)) = queue.try_send(line) {
+        log_adapter!(@Verbose, "Event stream is behind, dropping a {} event", event.name());
+    }
+}
+
+/// Report that `hart` halted, saying where and why
+#[cfg(feature = "server")]
+pub fn halted(cpu: &RiscvCpu, bridge: &Bridge, hart: usize) {
+    // Reading pc is a round trip of its own unless something else already
+    // needed it since the hart stopped, which isn't worth making for
+    // nobody
+    if !enabled() || !listening() {
+        return;
+    }
+    let pc = match cpu.cached_pc(hart) {
+        Some(pc) => pc,
+        None => match cpu.read_register(bridge, hart, register_number("pc").unwrap()) {
+            Ok(pc) => pc,
+            Err(e) => {
+                log_adapter!("Couldn't read pc for the event stream: {:?}", e);
+                return;
+            }
+        },
+    };
+    let reason = cpu.halt_reason(hart);
+    emit(TargetEvent::Halted {
+        hart,
+        pc,
+        reason: reason.map_or("unknown", HaltReason::name),
+    });
+    if reason == Some(HaltReason::Breakpoint) {
+        emit(TargetEvent::BreakpointHit { hart, addr: pc });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Write};
+    use std::sync::atomic::Ordering;
+    use std::sync::{mpsc, Arc, Mutex};
+
+    use super::{add_listener, write_events, EventSink, TargetEvent, LISTENERS, LISTENER_COUNT};
+
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct HungUp;
+
+    impl Write for HungUp {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn listeners_that_hang_up_are_dropped() {
+        let listener = Shared::default();
+        add_listener(Box::new(listener.clone()));
+        add_listener(Box::new(HungUp));
+        let (queue, lines) = mpsc::sync_channel(2);
+        queue.try_send("one\n".to_owned()).unwrap();
+        queue.try_send("two\n".to_owned()).unwrap();
+        drop(queue);
+        write_events(lines);
+        assert_eq!(&listener.0.lock().unwrap()[..], b"one\ntwo\n");
+        assert_eq!(LISTENER_COUNT.load(Ordering::Relaxed), 1);
+        LISTENERS.lock().unwrap().clear();
+    }
+
+    #[test]
+    fn events_as_json() {
+        let halted = TargetEvent::Halted {
+            hart: 1,
+            pc: 0x4000_0100,
+            reason: "breakpoint",
+        };
+        assert_eq!(
+            halted.json_line("12.500"),
+            "{\"event\":\"halted\",\"time\":12.500,\"hart\":1,\"pc\":1073742080,\"reason\":\"breakpoint\"}\n"
+        );
+        assert_eq!(
+            TargetEvent::Resumed.json_line("1.000"),
+            "{\"event\":\"resumed\",\"time\":1.000}\n"
+        );
+        assert_eq!(
+            TargetEvent::FlashProgress {
+                done: 4096,
+                total: 65536
+            }
+            .json_line("1.000"),
+            "{\"event\":\"flash-progress\",\"time\":1.000,\"done\":4096,\"total\":65536}\n"
+        );
+    }
+
+    #[test]
+    fn sink_specs() {
+        assert!(matches!(
+            EventSink::parse("tcp:4000"),
+            Ok(EventSink::Tcp(ref a)) if a == "127.0.0.1:4000"
+        ));
+        assert!(matches!(
+            EventSink::parse("tcp:0.0.0.0:4000"),
+            Ok(EventSink::Tcp(ref a)) if a == "0.0.0.0:4000"
+        ));
+        assert!(matches!(
+            EventSink::parse("unix:/tmp/events.sock"),
+            Ok(EventSink::Unix(ref p)) if p == "/tmp/events.sock"
+        ));
+        assert!(EventSink::parse("udp:4000").is_err());
+        assert!(EventSink::parse("unix:").is_err());
+    }
+}
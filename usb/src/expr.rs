@@ -0,0 +1,258 @@
+//! Arithmetic on addresses and values typed by hand, such as
+//! `main_ram_base + 0x100` or `$sp - 16`.  Numbers are written the same
+//! way as everywhere else (`0x` for hex, `0b` for binary, a leading `0`
+//! for octal), names are looked up by the caller, and the operators and
+//! their precedence are C's.  Everything is 64 bits wide and wraps.
+
+use std::convert::TryFrom;
+use std::fmt;
+
+use super::utils::parse_u64;
+
+#[derive(Debug, PartialEq)]
+pub enum ExprError {
+    /// Something other than what was expected, at this byte offset
+    Syntax(usize),
+
+    /// A name that the lookup didn't know
+    UnknownName(String),
+
+    /// A number that didn't parse
+    BadNumber(String),
+
+    DivideByZero,
+
+    /// A value that was wanted as a 32-bit word but doesn't fit in one
+    TooLarge(u64),
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExprError::Syntax(offset) => write!(f, "syntax error at offset {}", offset),
+            ExprError::UnknownName(name) => write!(f, "don't know what {} is", name),
+            ExprError::BadNumber(text) => write!(f, "{} isn't a number", text),
+            ExprError::DivideByZero => write!(f, "division by zero"),
+            ExprError::TooLarge(value) => write!(f, "0x{:x} doesn't fit in 32 bits", value),
+        }
+    }
+}
+
+/// Evaluate `text`, asking `lookup` for the value of each name in it.
+/// Names are letters, digits, `_` and `.`, not starting with a digit, and
+/// may start with `$` as GDB's register names do.
+pub fn eval(text: &str, lookup: &dyn Fn(&str) -> Option<u64>) -> Result<u64, ExprError> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        pos: 0,
+        lookup,
+    };
+    let value = parser.binary(0)?;
+    parser.skip_space();
+    if parser.pos != parser.text.len() {
+        return Err(ExprError::Syntax(parser.pos));
+    }
+    Ok(value)
+}
+
+/// Evaluate `text` as an address or a word
+pub fn eval_u32(text: &str, lookup: &dyn Fn(&str) -> Option<u64>) -> Result<u32, ExprError> {
+    eval(text, lookup).and_then(word)
+}
+
+/// Narrow a result to 32 bits.  Negative results that fit are fine, so
+/// `-4` is `0xfffffffc`, but anything else wider than 32 bits is an error
+/// rather than being cut short.
+pub fn word(value: u64) -> Result<u32, ExprError> {
+    u32::try_from(value)
+        .or_else(|_| i32::try_from(value as i64).map(|v| v as u32))
+        .map_err(|_| ExprError::TooLarge(value))
+}
+
+/// Binary operators from loosest to tightest binding
+const LEVELS: &[&[&str]] = &[
+    &["|"],
+    &["^"],
+    &["&"],
+    &["<<", ">>"],
+    &["+", "-"],
+    &["*", "/", "%"],
+];
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+    lookup: &'a dyn Fn(&str) -> Option<u64>,
+}
+
+impl<'a> Parser<'a> {
+    fn skip_space(&mut self) {
+        while self
+            .text
+            .get(self.pos)
+            .is_some_and(|c| c.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+    }
+
+    /// Take `op` if it's next
+    fn take(&mut self, op: &str) -> bool {
+        self.skip_space();
+        if self.text[self.pos..].starts_with(op.as_bytes()) {
+            self.pos += op.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Operators at `level` and tighter, left to right
+    fn binary(&mut self, level: usize) -> Result<u64, ExprError> {
+        let ops = match LEVELS.get(level) {
+            Some(ops) => *ops,
+            None => return self.unary(),
+        };
+        let mut value = self.binary(level + 1)?;
+        'next: loop {
+            for op in ops {
+                if self.take(op) {
+                    let rhs = self.binary(level + 1)?;
+                    value = apply(op, value, rhs)?;
+                    continue 'next;
+                }
+            }
+            return Ok(value);
+        }
+    }
+
+    fn unary(&mut self) -> Result<u64, ExprError> {
+        if self.take("-") {
+            Ok(self.unary()?.wrapping_neg())
+        } else if self.take("~") {
+            Ok(!self.unary()?)
+        } else if self.take("+") {
+            self.unary()
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<u64, ExprError> {
+        if self.take("(") {
+            let value = self.binary(0)?;
+            if !self.take(")") {
+                return Err(ExprError::Syntax(self.pos));
+            }
+            return Ok(value);
+        }
+        let start = self.pos;
+        if self.text.get(self.pos) == Some(&b'$') {
+            self.pos += 1;
+        }
+        while self
+            .text
+            .get(self.pos)
+            .is_some_and(|&c| c.is_ascii_alphanumeric() || c == b'_' || c == b'.')
+        {
+            self.pos += 1;
+        }
+        let word = String::from_utf8_lossy(&self.text[start..self.pos]).into_owned();
+        match word.bytes().next() {
+            None => Err(ExprError::Syntax(start)),
+            Some(c) if c.is_ascii_digit() => {
+                parse_u64(&word).map_err(|_| ExprError::BadNumber(word.clone()))
+            }
+            Some(_) => (self.lookup)(&word).ok_or(ExprError::UnknownName(word)),
+        }
+    }
+}
+
+fn apply(op: &str, lhs: u64, rhs: u64) -> Result<u64, ExprError> {
+    Ok(match op {
+        "|" => lhs | rhs,
+        "^" => lhs ^ rhs,
+        "&" => lhs & rhs,
+        "<<" => lhs.checked_shl(rhs as u32).unwrap_or(0),
+        ">>" => lhs.checked_shr(rhs as u32).unwrap_or(0),
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "*" => lhs.wrapping_mul(rhs),
+        "/" => lhs.checked_div(rhs).ok_or(ExprError::DivideByZero)?,
+        _ => lhs.checked_rem(rhs).ok_or(ExprError::DivideByZero)?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(name: &str) -> Option<u64> {
+        match name {
+            "main_ram_base" => Some(0x4000_0000),
+            "uart_base" => Some(0xf000_1000),
+            "$sp" => Some(0x4000_ff00),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(eval("0x10", &names), Ok(16));
+        assert_eq!(eval("10", &names), Ok(10));
+        assert_eq!(eval("0b101", &names), Ok(5));
+        assert_eq!(eval("010", &names), Ok(8));
+        assert_eq!(eval("0xg", &names), Err(ExprError::BadNumber("0xg".into())));
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3", &names), Ok(7));
+        assert_eq!(eval("(1 + 2) * 3", &names), Ok(9));
+        assert_eq!(eval("1 << 4 | 1", &names), Ok(17));
+        assert_eq!(eval("10 - 4 - 3", &names), Ok(3));
+        assert_eq!(eval("0xff & ~0xf", &names), Ok(0xf0));
+        assert_eq!(eval("-1", &names), Ok(u64::MAX));
+        assert_eq!(eval("7 % 4 ^ 1", &names), Ok(2));
+    }
+
+    #[test]
+    fn lookups() {
+        assert_eq!(eval("main_ram_base + 0x100", &names), Ok(0x4000_0100));
+        assert_eq!(eval("uart_base+4", &names), Ok(0xf000_1004));
+        assert_eq!(eval("$sp - 16", &names), Ok(0x4000_fef0));
+        assert_eq!(
+            eval("rom_base", &names),
+            Err(ExprError::UnknownName("rom_base".into()))
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(eval("1 / 0", &names), Err(ExprError::DivideByZero));
+        assert_eq!(eval("(1", &names), Err(ExprError::Syntax(2)));
+        assert_eq!(eval("1 2", &names), Err(ExprError::Syntax(2)));
+        assert_eq!(eval("", &names), Err(ExprError::Syntax(0)));
+        assert_eq!(eval("1 +", &names), Err(ExprError::Syntax(3)));
+    }
+
+    #[test]
+    fn words() {
+        assert_eq!(eval_u32("uart_base + 4", &names), Ok(0xf000_1004));
+        assert_eq!(eval_u32("0xffffffff", &names), Ok(0xffff_ffff));
+        assert_eq!(eval_u32("-4", &names), Ok(0xffff_fffc));
+        assert_eq!(eval_u32("-0x80000000", &names), Ok(0x8000_0000));
+        assert_eq!(
+            eval_u32("0x100000000", &names),
+            Err(ExprError::TooLarge(1 << 32))
+        );
+        assert_eq!(
+            eval_u32("main_ram_base << 4", &names),
+            Err(ExprError::TooLarge(0x4_0000_0000))
+        );
+        assert_eq!(
+            eval_u32("-0x80000001", &names),
+            Err(ExprError::TooLarge(0xffff_ffff_7fff_ffff))
+        );
+    }
+}
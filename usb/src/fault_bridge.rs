@@ -0,0 +1,211 @@
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use rand::prelude::*;
+use rand::rngs::SmallRng;
+
+use super::bridge::{self, BatchOp, Bridge, BridgeError};
+use super::config::ConfigError;
+use super::history;
+#[cfg(feature = "server")]
+use super::stats::{self, Phase};
+use super::trace::Access;
+
+/// How often each kind of fault should be injected, parsed from a string
+/// such as `delay=0.05,max-delay=20,retry=0.01,error=0.001,seed=42`.
+#[derive(Clone, Debug, Default)]
+pub struct FaultConfig {
+    /// Probability that a transaction is delayed before being issued
+    pub delay: f64,
+
+    /// Longest delay to inject, in milliseconds
+    pub max_delay_ms: u32,
+
+    /// Probability that a transaction is terminated with RTY
+    pub retry: f64,
+
+    /// Probability that a transaction is terminated with ERR
+    pub error: f64,
+
+    /// Seed for the random number generator, for reproducible runs
+    pub seed: Option<u64>,
+}
+
+impl FaultConfig {
+    pub fn parse(spec: &str) -> Result<FaultConfig, ConfigError> {
+        let mut cfg = FaultConfig {
+            max_delay_ms: 100,
+            ..Default::default()
+        };
+        for item in spec.split(',').filter(|s| !s.is_empty()) {
+            let mut kv = item.splitn(2, '=');
+            let key = kv.next().unwrap_or("");
+            let value = kv
+                .next()
+                .ok_or_else(|| ConfigError::InvalidFaultSpec(item.to_owned()))?;
+            let probability = || -> Result<f64, ConfigError> {
+                match value.parse::<f64>() {
+                    Ok(p) if (0.0..=1.0).contains(&p) => Ok(p),
+                    _ => Err(ConfigError::InvalidFaultSpec(item.to_owned())),
+                }
+            };
+            match key {
+                "delay" => cfg.delay = probability()?,
+                "retry" => cfg.retry = probability()?,
+                "error" => cfg.error = probability()?,
+                "max-delay" => cfg.max_delay_ms = value.parse()?,
+                "seed" => cfg.seed = Some(value.parse()?),
+                _ => return Err(ConfigError::InvalidFaultSpec(item.to_owned())),
+            }
+        }
+        Ok(cfg)
+    }
+}
+
+/// Wraps another bridge and randomly delays transactions or terminates
+/// them with errors, so error paths in the layers above can be exercised.
+pub struct FaultBridge {
+    inner: Box<Bridge>,
+    cfg: FaultConfig,
+    rng: Mutex<SmallRng>,
+}
+
+impl FaultBridge {
+    pub fn new(inner: Bridge, cfg: &FaultConfig) -> Result<Self, BridgeError> {
+        let rng = match cfg.seed {
+            Some(seed) => SmallRng::seed_from_u64(seed),
+            None => SmallRng::from_entropy(),
+        };
+        Ok(FaultBridge {
+            inner: Box::new(inner),
+            cfg: cfg.clone(),
+            rng: Mutex::new(rng),
+        })
+    }
+
+    /// Roll the dice for a transaction, sleeping or returning the error
+    /// that should be reported in place of the real result.  An injected
+    /// error goes in the history like a real one would.
+    fn inject(&self, addr: u32, write: bool) -> Result<(), BridgeError> {
+        let result = self.roll(addr);
+        if let Err(ref e) = result {
+            history::record(
+                Access::Error(addr, write),
+                Some(Duration::default()),
+                Some(e),
+            );
+        }
+        result
+    }
+
+    fn roll(&self, addr: u32) -> Result<(), BridgeError> {
+        let mut rng = self.rng.lock().unwrap();
+        if self.cfg.delay > 0.0 && rng.gen_bool(self.cfg.delay) {
+            let ms = rng.gen_range(0, self.cfg.max_delay_ms + 1);
+            log_bridge!("fault: delaying access to {:08x} by {} ms", addr, ms);
+            let delay = Duration::from_millis(ms as u64);
+            thread::sleep(delay);
+            // It stands in for a slow bridge, so it's timed as one
+            #[cfg(feature = "server")]
+            stats::charge(Phase::Bridge, delay);
+        }
+        if self.cfg.retry > 0.0 && rng.gen_bool(self.cfg.retry) {
+            log_bridge!("fault: terminating access to {:08x} with RTY", addr);
+            return Err(BridgeError::Retry);
+        }
+        if self.cfg.error > 0.0 && rng.gen_bool(self.cfg.error) {
+            log_bridge!("fault: terminating access to {:08x} with ERR", addr);
+            return Err(BridgeError::BusError);
+        }
+        Ok(())
+    }
+
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        self.inner.connect()
+    }
+
+    pub fn max_burst(&self) -> usize {
+        self.inner.max_burst()
+    }
+
+    pub fn backend_name(&self) -> &'static str {
+        self.inner.backend_name()
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        self.inject(addr, true)?;
+        self.inner.poke(addr, value)
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        self.inject(addr, false)?;
+        self.inner.peek(addr)
+    }
+
+    /// Faults are injected per transaction, so a batch is broken back up
+    pub fn execute(&self, ops: &[BatchOp]) -> Result<Vec<u32>, BridgeError> {
+        bridge::execute_each(ops, |a| self.peek(a), |a, v| self.poke(a, v))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FaultConfig;
+    use crate::bridge::{Bridge, BridgeError};
+    use crate::config::Config;
+
+    fn bridge(spec: &str) -> Bridge {
+        let cfg = Config::from_args(["fault", "--mock", "--inject-faults", spec]).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        bridge
+    }
+
+    #[test]
+    fn specs() {
+        let cfg = FaultConfig::parse("").unwrap();
+        assert_eq!((cfg.delay, cfg.retry, cfg.error), (0.0, 0.0, 0.0));
+        assert_eq!((cfg.max_delay_ms, cfg.seed), (100, None));
+
+        let cfg =
+            FaultConfig::parse("delay=0.05,max-delay=20,retry=0.01,error=0.001,seed=42").unwrap();
+        assert_eq!((cfg.delay, cfg.retry, cfg.error), (0.05, 0.01, 0.001));
+        assert_eq!((cfg.max_delay_ms, cfg.seed), (20, Some(42)));
+
+        for spec in [
+            "delay=1.5",
+            "error=-0.1",
+            "retry",
+            "bogus=1",
+            "max-delay=x",
+            "seed=-1",
+        ] {
+            assert!(FaultConfig::parse(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn faults_are_injected() {
+        let clean = bridge("seed=1");
+        clean.poke(0x4000_0000, 0x1234).unwrap();
+        assert_eq!(clean.peek(0x4000_0000).unwrap(), 0x1234);
+
+        assert!(matches!(bridge("retry=1").peek(0), Err(BridgeError::Retry)));
+        let failing = bridge("error=1");
+        assert!(matches!(failing.poke(0, 1), Err(BridgeError::BusError)));
+        assert!(matches!(
+            failing.batch().read(0).read(4).commit(),
+            Err(BridgeError::BusError)
+        ));
+    }
+
+    #[test]
+    fn seeds_repeat() {
+        let outcomes =
+            |bridge: Bridge| -> Vec<bool> { (0..64).map(|n| bridge.peek(n * 4).is_ok()).collect() };
+        let first = outcomes(bridge("error=0.5,seed=3"));
+        assert_eq!(first, outcomes(bridge("error=0.5,seed=3")));
+        assert!(first.contains(&true) && first.contains(&false));
+    }
+}
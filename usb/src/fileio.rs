@@ -0,0 +1,225 @@
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::bridge::{Bridge, BridgeError};
+use super::mmu;
+use super::riscv::RiscvCpu;
+use super::stats::{self, Phase};
+
+/// "LXFA", which the agent writes to the start of its mailbox once it's
+/// ready for requests
+const AGENT_MAGIC: u32 = 0x4146_584c;
+
+/// Where each word of the mailbox is, relative to its base.  The agent
+/// fills in `BUFFER_SIZE` along with the magic.  A request is made by
+/// filling in the arguments and buffer, then writing `COMMAND` last.  The
+/// agent answers by writing `RESULT` and then clearing `COMMAND`.
+const MAGIC: u32 = 0x00;
+const COMMAND: u32 = 0x04;
+const RESULT: u32 = 0x08;
+const ARGS: u32 = 0x0c;
+const BUFFER_SIZE: u32 = 0x1c;
+const BUFFER: u32 = 0x20;
+
+/// Requests the agent understands.  Paths go in the buffer with a NUL
+/// after them.  Open flags and modes are GDB's File-I/O values, and a
+/// negative result is one of GDB's errno values.
+const CMD_OPEN: u32 = 1; // path, flags, mode -> fd
+const CMD_CLOSE: u32 = 2; // fd
+const CMD_PREAD: u32 = 3; // fd, offset, count -> count, data in the buffer
+const CMD_PWRITE: u32 = 4; // fd, offset, count, data in the buffer -> count
+const CMD_UNLINK: u32 = 5; // path
+
+/// How long the agent gets to answer.  Writes may have to erase flash.
+const AGENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// GDB File-I/O errno values sent when the agent didn't get to answer
+pub const EINTR: i32 = 4;
+pub const ENODEV: i32 = 19;
+pub const ENAMETOOLONG: i32 = 91;
+pub const EUNKNOWN: i32 = 9999;
+
+#[derive(Debug)]
+pub enum FileError {
+    /// The mailbox couldn't be reached
+    BridgeError(BridgeError),
+
+    /// The request failed, with this GDB errno
+    Errno(i32),
+}
+
+impl std::convert::From<BridgeError> for FileError {
+    fn from(e: BridgeError) -> Self {
+        FileError::BridgeError(e)
+    }
+}
+
+impl fmt::Display for FileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FileError::BridgeError(e) => write!(f, "bridge error: {:?}", e),
+            FileError::Errno(errno) => write!(f, "errno {}", errno),
+        }
+    }
+}
+
+/// Files on the target, reached through an agent in its firmware that
+/// answers requests left in a mailbox in RAM.  The mailbox is accessed
+/// over the bus, so the agent must not cache it.
+///
+/// The agent can only answer while the CPU runs.  When GDB sends a file
+/// request the harts are normally halted, so they're let go until the
+/// agent has answered and then halted again.
+pub struct FileAgent {
+    base: u32,
+}
+
+impl FileAgent {
+    pub fn new(base: u32) -> FileAgent {
+        FileAgent { base }
+    }
+
+    pub fn open(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        path: &[u8],
+        flags: u32,
+        mode: u32,
+    ) -> Result<u32, FileError> {
+        let mut request = path.to_vec();
+        request.push(0);
+        self.call(cpu, bridge, CMD_OPEN, &[0, 0, flags, mode], &request, true)
+            .map(|(fd, _)| fd)
+    }
+
+    pub fn close(&self, cpu: &RiscvCpu, bridge: &Bridge, fd: u32) -> Result<(), FileError> {
+        self.call(cpu, bridge, CMD_CLOSE, &[fd, 0, 0, 0], &[], false)
+            .map(|_| ())
+    }
+
+    /// Read up to `count` bytes at `offset`.  Fewer come back if they
+    /// won't fit in the mailbox.
+    pub fn pread(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        fd: u32,
+        offset: u32,
+        count: u32,
+    ) -> Result<Vec<u8>, FileError> {
+        let count = count.min(self.buffer_size(bridge)?);
+        self.call(cpu, bridge, CMD_PREAD, &[fd, offset, count, 0], &[], false)
+            .map(|(_, data)| data)
+    }
+
+    /// Write as much of `data` at `offset` as fits in the mailbox, and
+    /// return how much of it the agent wrote
+    pub fn pwrite(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        fd: u32,
+        offset: u32,
+        data: &[u8],
+    ) -> Result<u32, FileError> {
+        let len = data.len().min(self.buffer_size(bridge)? as usize);
+        let args = [fd, offset, len as u32, 0];
+        self.call(cpu, bridge, CMD_PWRITE, &args, &data[..len], false)
+            .map(|(count, _)| count)
+    }
+
+    pub fn unlink(&self, cpu: &RiscvCpu, bridge: &Bridge, path: &[u8]) -> Result<(), FileError> {
+        let mut request = path.to_vec();
+        request.push(0);
+        self.call(cpu, bridge, CMD_UNLINK, &[0; 4], &request, true)
+            .map(|_| ())
+    }
+
+    /// How much the mailbox's buffer holds, which is also how to tell
+    /// whether there's an agent there at all
+    fn buffer_size(&self, bridge: &Bridge) -> Result<u32, FileError> {
+        if bridge.peek(self.base + MAGIC)? != AGENT_MAGIC {
+            return Err(FileError::Errno(ENODEV));
+        }
+        Ok(bridge.peek(self.base + BUFFER_SIZE)?)
+    }
+
+    /// Make a request and wait for the answer.  Returns the result, along
+    /// with that many bytes of the buffer for a read.  `is_path` marks a
+    /// buffer holding a path, which is refused if it doesn't fit rather
+    /// than being cut short.
+    fn call(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        command: u32,
+        args: &[u32; 4],
+        data: &[u8],
+        is_path: bool,
+    ) -> Result<(u32, Vec<u8>), FileError> {
+        let buffer_size = self.buffer_size(bridge)?;
+        if is_path && data.len() > buffer_size as usize {
+            return Err(FileError::Errno(ENAMETOOLONG));
+        }
+
+        let mut batch = bridge.batch();
+        for (idx, &arg) in args.iter().enumerate() {
+            batch = batch.write(self.base + ARGS + idx as u32 * 4, arg);
+        }
+        batch.commit()?;
+        let words: Vec<u32> = data
+            .chunks(4)
+            .map(|chunk| {
+                let mut word = [0; 4];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u32::from_le_bytes(word)
+            })
+            .collect();
+        bridge.write_block(self.base + BUFFER, &words)?;
+        bridge.poke(self.base + RESULT, 0)?;
+        bridge.poke(self.base + COMMAND, command)?;
+
+        let was_halted = cpu.is_halted(bridge, 0)?;
+        if was_halted {
+            cpu.resume(bridge)?;
+        }
+        let answered = self.wait(cpu, bridge);
+        if was_halted {
+            cpu.halt(bridge)?;
+        }
+        answered?;
+
+        let result = bridge.peek(self.base + RESULT)? as i32;
+        if result < 0 {
+            return Err(FileError::Errno(-result));
+        }
+        let data = if command == CMD_PREAD {
+            let len = (result as u32).min(args[2]);
+            mmu::read_physical(bridge, self.base + BUFFER, len)?
+        } else {
+            vec![]
+        };
+        Ok((result as u32, data))
+    }
+
+    /// Wait for the agent to clear the command word.  A hart stopping on
+    /// a breakpoint in the meantime ends the wait, since the agent won't
+    /// get any further.
+    fn wait(&self, cpu: &RiscvCpu, bridge: &Bridge) -> Result<(), FileError> {
+        let start = Instant::now();
+        while bridge.peek(self.base + COMMAND)? != 0 {
+            if cpu.poll_halted(bridge)?.is_some() {
+                log_adapter!("file agent: hart stopped while waiting for an answer");
+                return Err(FileError::Errno(EINTR));
+            }
+            if start.elapsed() > AGENT_TIMEOUT {
+                log_adapter!("file agent at {:08x} didn't answer", self.base);
+                return Err(FileError::Errno(EUNKNOWN));
+            }
+            stats::timed(Phase::Target, || thread::sleep(Duration::from_millis(1)));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,498 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::board::{FlashGeometry, SpiFlashRegisters};
+use super::bridge::{Bridge, BridgeError};
+use super::lock;
+
+/// SPI flash commands
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_SECTOR_ERASE_4K: u8 = 0x20;
+const CMD_BLOCK_ERASE_64K: u8 = 0xd8;
+
+/// Status register bit that's set while an erase or program is running
+const STATUS_WIP: u8 = 1 << 0;
+
+/// Bits in LiteX's `spiflash_bitbang` register
+const BITBANG_MOSI: u32 = 1 << 0;
+const BITBANG_CLK: u32 = 1 << 1;
+const BITBANG_CS_N: u32 = 1 << 2;
+
+/// Sector erases take tens of milliseconds and 64K block erases can take
+/// a couple of seconds
+const ERASE_TIMEOUT: Duration = Duration::from_secs(5);
+const PROGRAM_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub enum FlashError {
+    /// The bridge failed while talking to the flash controller
+    BridgeError(BridgeError),
+
+    /// The address isn't inside the flash
+    OutOfRange(u32),
+
+    /// The flash stayed busy for too long
+    Timeout,
+
+    /// The sector size isn't one the flash can erase
+    UnsupportedSectorSize(u32),
+}
+
+impl std::convert::From<BridgeError> for FlashError {
+    fn from(e: BridgeError) -> Self {
+        FlashError::BridgeError(e)
+    }
+}
+
+impl fmt::Display for FlashError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FlashError::BridgeError(BridgeError::ResourceBusy(why)) => write!(f, "{}", why),
+            FlashError::BridgeError(e) => write!(f, "bridge error: {:?}", e),
+            FlashError::OutOfRange(addr) => write!(f, "{:08x} is outside the flash", addr),
+            FlashError::Timeout => write!(f, "flash stayed busy"),
+            FlashError::UnsupportedSectorSize(size) => {
+                write!(f, "can't erase {} byte sectors", size)
+            }
+        }
+    }
+}
+
+/// One sector's worth of pending changes
+struct Sector {
+    /// Bus address of the start of the sector
+    base: u32,
+
+    /// What's in the flash now
+    original: Vec<u8>,
+
+    /// What should be in the flash once this is written back
+    contents: Vec<u8>,
+}
+
+/// Writes to SPI flash by bit-banging LiteX's `spiflash` controller.
+/// Writes are gathered up a sector at a time, so a `load` that arrives in
+/// many small pieces only erases each sector once.  Call `flush()` once
+/// there's nothing more to write.
+pub struct FlashWriter {
+    geometry: FlashGeometry,
+    registers: SpiFlashRegisters,
+
+    /// The sector currently being filled in, if any
+    pending: Option<Sector>,
+
+    /// Number of sectors actually erased and programmed
+    sectors_written: u32,
+}
+
+impl FlashWriter {
+    pub fn new(geometry: FlashGeometry, registers: SpiFlashRegisters) -> FlashWriter {
+        FlashWriter {
+            geometry,
+            registers,
+            pending: None,
+            sectors_written: 0,
+        }
+    }
+
+    pub fn contains(&self, addr: u32) -> bool {
+        addr.checked_sub(self.geometry.base)
+            .is_some_and(|offset| offset < self.geometry.size)
+    }
+
+    /// Merge `data` into the flash starting at `addr`.  Sectors are written
+    /// back as soon as a write moves past them.
+    pub fn write(&mut self, bridge: &Bridge, addr: u32, data: &[u8]) -> Result<(), FlashError> {
+        let sector_size = self.geometry.sector_size;
+        let mut offset = 0;
+        while offset < data.len() {
+            let addr = addr.wrapping_add(offset as u32);
+            if !self.contains(addr) {
+                return Err(FlashError::OutOfRange(addr));
+            }
+            let base = addr - (addr - self.geometry.base) % sector_size;
+            if self.pending.as_ref().map(|s| s.base) != Some(base) {
+                self.flush(bridge)?;
+                let original = self.read_sector(bridge, base)?;
+                self.pending = Some(Sector {
+                    base,
+                    contents: original.clone(),
+                    original,
+                });
+            }
+            let sector = self.pending.as_mut().unwrap();
+            let start = (addr - base) as usize;
+            let count = (sector_size as usize - start).min(data.len() - offset);
+            sector.contents[start..start + count].copy_from_slice(&data[offset..offset + count]);
+            offset += count;
+        }
+        Ok(())
+    }
+
+    /// Write back the sector being filled in, if it changed.  A sector that
+    /// fails to program is kept, so that trying again starts from the same
+    /// place.
+    pub fn flush(&mut self, bridge: &Bridge) -> Result<(), FlashError> {
+        let sector = match self.pending.take() {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        if sector.contents == sector.original {
+            return Ok(());
+        }
+
+        // Programming can only clear bits, so only erase if something
+        // needs to go from 0 back to 1
+        let needs_erase = sector
+            .original
+            .iter()
+            .zip(&sector.contents)
+            .any(|(old, new)| !old & new != 0);
+
+        let result = self.write_back(bridge, &sector, needs_erase);
+        if result.is_err() {
+            self.pending = Some(sector);
+            return result;
+        }
+        self.sectors_written += 1;
+        Ok(())
+    }
+
+    fn write_back(
+        &self,
+        bridge: &Bridge,
+        sector: &Sector,
+        needs_erase: bool,
+    ) -> Result<(), FlashError> {
+        let _lock = lock::hold_flash(&format!("programming the sector at {:08x}", sector.base))?;
+        let result = self.program_sector(bridge, sector, needs_erase);
+        // Always hand the flash back to the memory-mapped interface
+        bridge.poke(self.registers.bitbang_en, 0)?;
+        result
+    }
+
+    pub fn sectors_written(&self) -> u32 {
+        self.sectors_written
+    }
+
+    fn program_sector(
+        &self,
+        bridge: &Bridge,
+        sector: &Sector,
+        needs_erase: bool,
+    ) -> Result<(), FlashError> {
+        bridge.poke(self.registers.bitbang_en, 1)?;
+        let flash_addr = sector.base - self.geometry.base;
+        if needs_erase {
+            let cmd = match self.geometry.sector_size {
+                0x1000 => CMD_SECTOR_ERASE_4K,
+                0x1_0000 => CMD_BLOCK_ERASE_64K,
+                other => return Err(FlashError::UnsupportedSectorSize(other)),
+            };
+            self.write_enable(bridge)?;
+            self.command(bridge, cmd, flash_addr, &[])?;
+            self.wait_ready(bridge, ERASE_TIMEOUT)?;
+        }
+
+        let page_size = self.geometry.page_size as usize;
+        for (page, data) in sector.contents.chunks(page_size).enumerate() {
+            let original = &sector.original[page * page_size..page * page_size + data.len()];
+            // An erased page that should stay erased needs no programming,
+            // and neither does an untouched page that wasn't erased
+            if (needs_erase && data.iter().all(|&b| b == 0xff))
+                || (!needs_erase && data == original)
+            {
+                continue;
+            }
+            self.write_enable(bridge)?;
+            self.command(
+                bridge,
+                CMD_PAGE_PROGRAM,
+                flash_addr + (page * page_size) as u32,
+                data,
+            )?;
+            self.wait_ready(bridge, PROGRAM_TIMEOUT)?;
+        }
+        Ok(())
+    }
+
+    /// Read a sector through the memory-mapped interface
+    fn read_sector(&self, bridge: &Bridge, base: u32) -> Result<Vec<u8>, BridgeError> {
+        bridge.poke(self.registers.bitbang_en, 0)?;
+        let mut data = Vec::with_capacity(self.geometry.sector_size as usize);
+        for offset in (0..self.geometry.sector_size).step_by(4) {
+            data.extend_from_slice(&bridge.peek(base + offset)?.to_le_bytes());
+        }
+        Ok(data)
+    }
+
+    fn write_enable(&self, bridge: &Bridge) -> Result<(), BridgeError> {
+        self.select(bridge)?;
+        self.transfer(bridge, CMD_WRITE_ENABLE)?;
+        self.deselect(bridge)
+    }
+
+    /// Send a command with a 24-bit address followed by some data
+    fn command(&self, bridge: &Bridge, cmd: u8, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        self.select(bridge)?;
+        self.transfer(bridge, cmd)?;
+        self.transfer(bridge, (addr >> 16) as u8)?;
+        self.transfer(bridge, (addr >> 8) as u8)?;
+        self.transfer(bridge, addr as u8)?;
+        for &byte in data {
+            self.transfer(bridge, byte)?;
+        }
+        self.deselect(bridge)
+    }
+
+    fn wait_ready(&self, bridge: &Bridge, timeout: Duration) -> Result<(), FlashError> {
+        let start = Instant::now();
+        loop {
+            self.select(bridge)?;
+            self.transfer(bridge, CMD_READ_STATUS)?;
+            let status = self.transfer(bridge, 0)?;
+            self.deselect(bridge)?;
+            if status & STATUS_WIP == 0 {
+                return Ok(());
+            }
+            if start.elapsed() > timeout {
+                return Err(FlashError::Timeout);
+            }
+        }
+    }
+
+    fn select(&self, bridge: &Bridge) -> Result<(), BridgeError> {
+        bridge.poke(self.registers.bitbang, 0)
+    }
+
+    fn deselect(&self, bridge: &Bridge) -> Result<(), BridgeError> {
+        bridge.poke(self.registers.bitbang, BITBANG_CS_N)
+    }
+
+    /// Clock a byte out on MOSI, most significant bit first, and return
+    /// what came back on MISO.  This is SPI mode 0: data is set up while
+    /// the clock is low and sampled on the rising edge.
+    fn transfer(&self, bridge: &Bridge, out: u8) -> Result<u8, BridgeError> {
+        let mut batch = bridge.batch();
+        for bit in (0..8).rev() {
+            let mosi = if out & (1 << bit) != 0 {
+                BITBANG_MOSI
+            } else {
+                0
+            };
+            batch = batch
+                .write(self.registers.bitbang, mosi)
+                .write(self.registers.bitbang, mosi | BITBANG_CLK)
+                .read(self.registers.miso);
+        }
+        let miso = batch.write(self.registers.bitbang, 0).commit()?;
+        Ok(miso
+            .iter()
+            .fold(0, |byte, bit| (byte << 1) | (bit & 1) as u8))
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::collections::HashMap;
+
+    use super::{
+        FlashError, FlashWriter, BITBANG_CLK, BITBANG_CS_N, BITBANG_MOSI, CMD_BLOCK_ERASE_64K,
+        CMD_PAGE_PROGRAM, CMD_READ_STATUS, CMD_SECTOR_ERASE_4K, CMD_WRITE_ENABLE,
+    };
+    use crate::board::{FlashGeometry, SpiFlashRegisters};
+    use crate::bridge::Bridge;
+    use crate::config::Config;
+    use crate::mock_bridge::Device;
+
+    pub const GEOMETRY: FlashGeometry = FlashGeometry {
+        base: 0x2000_0000,
+        size: 0x2000,
+        sector_size: 0x1000,
+        page_size: 0x100,
+    };
+
+    pub const REGISTERS: SpiFlashRegisters = SpiFlashRegisters {
+        bitbang: 0xe000_5000,
+        miso: 0xe000_5004,
+        bitbang_en: 0xe000_5008,
+    };
+
+    /// A SPI flash chip behind the bit-bang registers, also mapped for
+    /// reading at `GEOMETRY.base`.  Like the real thing, programming can
+    /// only clear bits, and nothing is changed without a write enable
+    /// first.
+    pub struct Chip {
+        contents: Vec<u8>,
+        selected: bool,
+        clk: bool,
+        bits: u32,
+        shift_in: u8,
+        shift_out: u8,
+        miso: u32,
+        received: Vec<u8>,
+        write_enabled: bool,
+    }
+
+    impl Chip {
+        pub fn new(contents: Vec<u8>) -> Chip {
+            Chip {
+                contents,
+                selected: false,
+                clk: false,
+                bits: 0,
+                shift_in: 0,
+                shift_out: 0,
+                miso: 0,
+                received: vec![],
+                write_enabled: false,
+            }
+        }
+
+        fn bitbang(&mut self, value: u32) {
+            if value & BITBANG_CS_N != 0 {
+                if self.selected {
+                    self.finish_command();
+                }
+                self.selected = false;
+                return;
+            }
+            if !self.selected {
+                self.selected = true;
+                self.received.clear();
+                self.bits = 0;
+            }
+            let clk = value & BITBANG_CLK != 0;
+            if clk && !self.clk {
+                if self.bits == 0 {
+                    self.shift_out = match self.received[..] {
+                        [CMD_READ_STATUS] => 0,
+                        _ => 0xff,
+                    };
+                }
+                self.miso = (self.shift_out >> (7 - self.bits) & 1) as u32;
+                self.shift_in = self.shift_in << 1 | (value & BITBANG_MOSI) as u8;
+                self.bits += 1;
+                if self.bits == 8 {
+                    self.received.push(self.shift_in);
+                    self.bits = 0;
+                }
+            }
+            self.clk = clk;
+        }
+
+        fn finish_command(&mut self) {
+            let (cmd, args) = match self.received.split_first() {
+                Some((&cmd, args)) => (cmd, args),
+                None => return,
+            };
+            if cmd == CMD_WRITE_ENABLE {
+                self.write_enabled = true;
+                return;
+            }
+            if !self.write_enabled || args.len() < 3 {
+                return;
+            }
+            self.write_enabled = false;
+            let addr = (args[0] as usize) << 16 | (args[1] as usize) << 8 | args[2] as usize;
+            let erase = |contents: &mut Vec<u8>, size: usize| {
+                let base = addr & !(size - 1);
+                contents[base..base + size].fill(0xff);
+            };
+            match cmd {
+                CMD_SECTOR_ERASE_4K => erase(&mut self.contents, 0x1000),
+                CMD_BLOCK_ERASE_64K => erase(&mut self.contents, 0x1_0000),
+                CMD_PAGE_PROGRAM => {
+                    let page = addr & !0xff;
+                    for (idx, byte) in args[3..].iter().enumerate() {
+                        self.contents[page + (addr + idx) % 0x100] &= byte;
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+
+    impl Device for Chip {
+        fn read(&mut self, _memory: &mut HashMap<u32, u32>, addr: u32) -> Option<u32> {
+            if addr == REGISTERS.miso {
+                return Some(self.miso);
+            }
+            let offset = addr.checked_sub(GEOMETRY.base)? as usize;
+            let word = self.contents.get(offset..offset + 4)?;
+            Some(u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+        }
+
+        fn write(&mut self, _memory: &mut HashMap<u32, u32>, addr: u32, value: u32) -> bool {
+            match addr {
+                a if a == REGISTERS.bitbang => self.bitbang(value),
+                a if a == REGISTERS.bitbang_en || a == REGISTERS.miso => (),
+                _ => return false,
+            }
+            true
+        }
+    }
+
+    /// A mock bridge with a flash chip holding `contents`
+    pub fn bridge_with_flash(contents: Vec<u8>) -> Bridge {
+        let cfg = Config::from_args(["flash", "--mock"]).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        bridge.attach(Box::new(Chip::new(contents)));
+        bridge
+    }
+
+    fn read_back(bridge: &Bridge, addr: u32, len: u32) -> Vec<u8> {
+        (addr..addr + len)
+            .step_by(4)
+            .flat_map(|a| bridge.peek(a).unwrap().to_le_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn sectors_are_merged_and_programmed() {
+        let mut contents = vec![0xff; GEOMETRY.size as usize];
+        contents[0x10..0x20].fill(0x00);
+        let bridge = bridge_with_flash(contents);
+        let mut writer = FlashWriter::new(GEOMETRY, REGISTERS);
+
+        // Needs an erase, since zeros go back to ones, and what was there
+        // around it has to survive that
+        writer.write(&bridge, 0x2000_0014, &[0xaa; 8]).unwrap();
+        writer.write(&bridge, 0x2000_0100, &[0x12, 0x34]).unwrap();
+        // Moving to the next sector writes back the first
+        writer.write(&bridge, 0x2000_1ffc, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(writer.sectors_written(), 1);
+        writer.flush(&bridge).unwrap();
+        assert_eq!(writer.sectors_written(), 2);
+
+        let mut expected = vec![0x00; 0x10];
+        expected[4..12].fill(0xaa);
+        assert_eq!(read_back(&bridge, 0x2000_0010, 0x10), expected);
+        assert_eq!(read_back(&bridge, 0x2000_0100, 4), [0x12, 0x34, 0xff, 0xff]);
+        assert_eq!(read_back(&bridge, 0x2000_1ffc, 4), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn unchanged_sectors_are_left_alone() {
+        let bridge = bridge_with_flash(vec![0xff; GEOMETRY.size as usize]);
+        let mut writer = FlashWriter::new(GEOMETRY, REGISTERS);
+        writer.write(&bridge, 0x2000_0000, &[0xff; 0x40]).unwrap();
+        writer.flush(&bridge).unwrap();
+        assert_eq!(writer.sectors_written(), 0);
+    }
+
+    #[test]
+    fn writes_stay_inside_the_flash() {
+        let bridge = bridge_with_flash(vec![0xff; GEOMETRY.size as usize]);
+        let mut writer = FlashWriter::new(GEOMETRY, REGISTERS);
+        assert!(matches!(
+            writer.write(&bridge, 0x2000_1ffe, &[0; 4]),
+            Err(FlashError::OutOfRange(0x2000_2000))
+        ));
+        assert!(!writer.contains(0x1fff_ffff));
+        assert!(writer.contains(0x2000_1fff));
+    }
+}
@@ -0,0 +1,221 @@
+//! Pictures of what a headless board would be showing.  The framebuffer is
+//! read straight out of memory over the bridge and turned into a PNG, so
+//! there's nothing for the firmware to do.
+
+use std::fmt;
+
+use super::bridge::{Bridge, BridgeError};
+use super::config::ConfigError;
+use super::image::crc32;
+use super::mmu;
+
+/// How pixels are laid out in memory.  These are the formats LiteX's
+/// `VideoFramebuffer` scans out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PixelFormat {
+    /// A 32-bit word per pixel, with red in bits 23:16, green in 15:8 and
+    /// blue in 7:0
+    Rgb888,
+
+    /// A 16-bit halfword per pixel, with red in bits 15:11, green in 10:5
+    /// and blue in 4:0
+    Rgb565,
+}
+
+impl PixelFormat {
+    pub fn parse(name: &str) -> Option<PixelFormat> {
+        match name {
+            "rgb888" => Some(PixelFormat::Rgb888),
+            "rgb565" => Some(PixelFormat::Rgb565),
+            _ => None,
+        }
+    }
+
+    fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Rgb888 => 4,
+            PixelFormat::Rgb565 => 2,
+        }
+    }
+
+    /// Turn one pixel as it is in memory into red, green and blue
+    fn rgb(self, pixel: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::Rgb888 => [pixel[2], pixel[1], pixel[0]],
+            PixelFormat::Rgb565 => {
+                let p = u16::from_le_bytes([pixel[0], pixel[1]]);
+                let (r, g, b) = ((p >> 11) as u8, (p >> 5) as u8 & 0x3f, p as u8 & 0x1f);
+                // Copy the top bits into the bottom so white comes out white
+                [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+            }
+        }
+    }
+}
+
+/// Where the framebuffer is and what's in it, as given to `--framebuffer`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Framebuffer {
+    pub addr: u32,
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+}
+
+impl fmt::Display for Framebuffer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}x{} framebuffer at {:08x}",
+            self.width, self.height, self.addr
+        )
+    }
+}
+
+impl Framebuffer {
+    /// Take the size as `<width>x<height>` and the format by name.  The
+    /// whole framebuffer has to fit in the address space.
+    pub fn parse(addr: u32, size: &str, format: &str) -> Result<Framebuffer, ConfigError> {
+        let bad = || ConfigError::InvalidFramebuffer(format!("{} {}", size, format));
+        let (width, height) = size.split_once('x').ok_or_else(bad)?;
+        let (width, height): (u32, u32) = (
+            width.parse().map_err(|_| bad())?,
+            height.parse().map_err(|_| bad())?,
+        );
+        if width == 0 || height == 0 {
+            return Err(bad());
+        }
+        let pixels = PixelFormat::parse(format).ok_or_else(bad)?;
+        let len = width as u64 * height as u64 * pixels.bytes_per_pixel() as u64;
+        if addr as u64 + len > 1 << 32 {
+            return Err(ConfigError::InvalidFramebuffer(format!(
+                "{} {} runs past the end of memory from {:08x}",
+                size, format, addr
+            )));
+        }
+        Ok(Framebuffer {
+            addr,
+            width,
+            height,
+            format: pixels,
+        })
+    }
+
+    /// How many bytes the framebuffer takes up, which `parse` has made sure
+    /// fits
+    fn len(&self) -> u32 {
+        self.width * self.height * self.format.bytes_per_pixel() as u32
+    }
+
+    /// Read the whole framebuffer and encode it as a PNG
+    pub fn grab_png(&self, bridge: &Bridge) -> Result<Vec<u8>, BridgeError> {
+        let raw = mmu::read_physical(bridge, self.addr, self.len())?;
+        Ok(self.encode_png(&raw))
+    }
+
+    fn encode_png(&self, raw: &[u8]) -> Vec<u8> {
+        let bpp = self.format.bytes_per_pixel();
+        let row_len = self.width as usize * bpp;
+        let mut scanlines =
+            Vec::with_capacity(self.height as usize * (self.width as usize * 3 + 1));
+        for row in raw.chunks(row_len) {
+            // Each scanline starts with its filter type, and none is used
+            scanlines.push(0);
+            for pixel in row.chunks(bpp) {
+                scanlines.extend_from_slice(&self.format.rgb(pixel));
+            }
+        }
+
+        let mut header = vec![];
+        header.extend_from_slice(&self.width.to_be_bytes());
+        header.extend_from_slice(&self.height.to_be_bytes());
+        // 8 bits per channel, RGB, deflate, standard filters, no interlace
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut png, b"IHDR", &header);
+        png_chunk(&mut png, b"IDAT", &zlib_stored(&scanlines));
+        png_chunk(&mut png, b"IEND", &[]);
+        png
+    }
+}
+
+fn png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wrap `data` up as a zlib stream without compressing it.  Framebuffers
+/// are read over the bridge far slower than they'd compress, so it isn't
+/// worth carrying a deflate implementation for.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&(b << 16 | a).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::{zlib_stored, Framebuffer, PixelFormat};
+
+    #[test]
+    fn parse_spec() {
+        let fb = Framebuffer::parse(0x4000_0000, "640x480", "rgb565").unwrap();
+        assert_eq!(
+            (fb.width, fb.height, fb.format),
+            (640, 480, PixelFormat::Rgb565)
+        );
+        assert!(Framebuffer::parse(0, "640", "rgb565").is_err());
+        assert!(Framebuffer::parse(0, "0x480", "rgb565").is_err());
+        assert!(Framebuffer::parse(0, "640x480", "yuv").is_err());
+        assert!(Framebuffer::parse(0, "65536x65536", "rgb565").is_err());
+        assert!(Framebuffer::parse(0xffff_0000, "129x128", "rgb888").is_err());
+        let fb = Framebuffer::parse(0xffff_0000, "128x128", "rgb565").unwrap();
+        assert_eq!(fb.len(), 0x8000);
+    }
+
+    #[test]
+    fn pixels() {
+        assert_eq!(
+            PixelFormat::Rgb888.rgb(&[0x33, 0x22, 0x11, 0]),
+            [0x11, 0x22, 0x33]
+        );
+        assert_eq!(PixelFormat::Rgb565.rgb(&[0xff, 0xff]), [0xff, 0xff, 0xff]);
+        assert_eq!(PixelFormat::Rgb565.rgb(&[0x00, 0xf8]), [0xff, 0, 0]);
+    }
+
+    #[test]
+    fn zlib_adler() {
+        // Adler-32 of "Wikipedia" is the usual known answer
+        let z = zlib_stored(b"Wikipedia");
+        assert_eq!(&z[z.len() - 4..], &0x11e6_0398u32.to_be_bytes());
+        assert_eq!(&z[2..7], &[1, 9, 0, 0xf6, 0xff]);
+    }
+
+    #[test]
+    fn png_layout() {
+        let fb = Framebuffer::parse(0, "2x1", "rgb888").unwrap();
+        let png = fb.encode_png(&[0, 0, 0xff, 0, 0xff, 0, 0, 0]);
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR"));
+        assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+    }
+}
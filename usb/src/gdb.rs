@@ -1,20 +1,218 @@
 extern crate byteorder;
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::io;
 use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use super::board::{self, MemoryRegion};
+use super::bootmode::{BootControl, BootMode};
 use super::bridge::{Bridge, BridgeError};
-use super::riscv::{RiscvCpu, RiscvCpuError};
+use super::call::{self, Arg};
+use super::capabilities;
+use super::clock::{self, TargetTime, Uptime};
+use super::coredump;
+use super::csr_map::{CsrMap, CsrMapError};
+use super::csr_snapshot::CsrSnapshot;
+use super::ddr::DdrControl;
+use super::doorbell::{Doorbell, DoorbellAction};
+use super::ecc::{self, EccBank, EccCounts};
+use super::events::{self, TargetEvent};
+use super::expr::{self, ExprError};
+use super::fileio::{FileAgent, FileError};
+use super::kernel::{KernelSymbols, Task, TASK_TID_BASE};
+use super::logging::{self, Filter, LogChannel};
+use super::mmu::{self, MmuError};
+use super::poll_watch::{Condition, PollWatches};
+use super::power::PowerSwitch;
+use super::riscv::{
+    self, CpuContext, HaltReason, RiscvCpu, RiscvCpuError, Trigger, WatchKind, TRAP_CAUSES,
+};
+use super::rsp::{self, Decoder};
+use super::scratch::{Scratch, ScratchError};
+use super::session::{Breakpoint, Session};
+use super::stats::{self, CommandTiming, CommandTimings, LatencyStats, Phase};
+use super::steptrace::{self, TraceWriter};
+use super::target::{Clients, Role, RunState, SharedClients, SharedTargetState};
+use super::transport::{Connection, GdbListener};
+use super::ui::{self, Event, Verbosity};
+use super::utils::parse_u32;
 use super::Config;
 
 use crate::gdb::byteorder::ByteOrder;
-use byteorder::{BigEndian, NativeEndian};
+use byteorder::{BigEndian, LittleEndian, NativeEndian};
+
+/// Features advertised in reply to `qSupported`
+pub const SUPPORTED_FEATURES: &str = "PacketSize=3fff;qXfer:memory-map:read+;qXfer:features:read+;qXfer:threads:read+;QStartNoAckMode+;vContSupported+;QPassSignals+;QProgramSignals+;QCatchSyscalls+;QNonStop+";
+
+/// Everything `monitor` understands
+pub const MONITOR_COMMANDS: &[&str] = &[
+    "bootmode",
+    "breakpoints",
+    "cache",
+    "call",
+    "capabilities",
+    "catch",
+    "clockspeed",
+    "context",
+    "coredump",
+    "csr-dump",
+    "csr-restore",
+    "ddr",
+    "dmesg",
+    "ecc",
+    "encoding",
+    "kthreads",
+    "latency",
+    "mmu",
+    "power",
+    "ps",
+    "read",
+    "save-session",
+    "scratch",
+    "set",
+    "step-record",
+    "timings",
+    "uptime",
+    "watch",
+    "wb",
+    "write",
+];
+
+/// Most words `monitor wb read` shows at once, which keeps the reply well
+/// inside a packet
+const MAX_WB_WORDS: u32 = 256;
+
+/// GDB's number for a7, which holds the syscall number on `ecall`
+const A7_REGNUM: u32 = 17;
 
 pub struct GdbServer {
-    connection: TcpStream,
+    connection: Connection,
     no_ack_mode: bool,
-    is_alive: bool,
-    last_signal: u8,
+
+    /// Run state and last stop, shared with everything else watching the target
+    target: SharedTargetState,
+
+    /// Hart used for register and memory accesses, as set by `Hg`
+    current_hart: usize,
+
+    /// Hart that `c` and `s` apply to, as set by `Hc`.  `None` means all.
+    continue_hart: Option<usize>,
+
+    /// How long it takes to handle a packet once it has arrived
+    packet_latency: LatencyStats,
+
+    /// Where the time went for each kind of packet, for `monitor timings`
+    timings: CommandTimings,
+
+    /// Run-length encode outgoing packets
+    use_rle: bool,
+
+    /// Escape special characters in binary replies
+    use_escaping: bool,
+
+    /// Breakpoints and watchpoints GDB has asked for, so they can be saved
+    /// with the session
+    breakpoints: Vec<Breakpoint>,
+
+    /// Software breakpoints that are patched into memory, as (address,
+    /// the instruction bytes they replaced)
+    patched: Vec<(u32, Vec<u8>)>,
+
+    /// Where a hardware breakpoint may be patched in as a software one
+    /// once the comparators run out, from `--breakpoint-fallback`
+    fallback_regions: Vec<MemoryRegion>,
+
+    /// Hardware breakpoints GDB asked for that were patched in instead
+    fallbacks: Vec<u32>,
+
+    /// Signals that GDB doesn't want to hear about, as set by
+    /// `QPassSignals`.  A hart that stops with one of these is resumed.
+    pass_signals: Vec<u8>,
+
+    /// `mcause` values to stop on, as set by `monitor catch`
+    catch_causes: Vec<u32>,
+
+    /// Syscall numbers to stop on, as set by `QCatchSyscalls`.  An empty
+    /// list means every syscall, and `None` means none of them.
+    catch_syscalls: Option<Vec<u32>>,
+
+    /// Treat memory addresses as virtual when the hart has paging on, as
+    /// set by `monitor mmu`
+    translate_addresses: bool,
+
+    /// Where things are in a Linux kernel running on the target
+    kernel: Option<KernelSymbols>,
+
+    /// List kernel tasks as threads, as set by `monitor kthreads`
+    kernel_threads: bool,
+
+    /// A kernel task selected with `Hg`, whose registers are the ones it
+    /// saved when it was switched out
+    current_task: Option<Task>,
+
+    /// Firmware on the target that `vFile` packets are passed to
+    file_agent: Option<FileAgent>,
+
+    /// Target RAM borrowed for the adapter's own use, given back on detach
+    scratch: Option<Scratch>,
+
+    /// What switches the board's power, for `monitor power`
+    power: Option<PowerSwitch>,
+
+    /// Register names from csr.csv, for `monitor read` and `monitor write`
+    csr_map: Option<CsrMap>,
+
+    /// The SoC's uptime counter, found in csr.csv, for stamping halts with
+    /// the target's idea of the time
+    uptime: Option<Uptime>,
+
+    /// Hart contexts saved by name with `monitor context save`
+    contexts: Vec<(String, CpuContext)>,
+
+    /// ECC counters found in csr.csv
+    ecc_banks: Vec<EccBank>,
+
+    /// The LiteDRAM controller, found in csr.csv
+    ddr: Option<DdrControl>,
+
+    /// The register that says where the SoC boots from
+    boot_control: Option<BootControl>,
+
+    /// Documents GDB reads in chunks with `qXfer`
+    xfer_cache: XferCache,
+
+    /// What firmware writes to get our attention, checked while it runs
+    doorbell: Option<Doorbell>,
+
+    /// Report ECC errors that show up while GDB reads DRAM, as turned on
+    /// with `monitor ecc annotate on`
+    ecc_annotate: bool,
+
+    /// GDB resumed the target and is waiting to hear that it stopped
+    awaiting_stop: bool,
+
+    /// GDB turned on non-stop mode with `QNonStop:1`, so it carries on
+    /// sending packets while the target runs and hears about stops
+    /// through notifications
+    non_stop: bool,
+
+    /// Stop replies that GDB hasn't collected with `vStopped` yet
+    stops: rsp::Notifications,
+
+    /// Words sampled while the target runs, standing in for triggers the
+    /// debug unit doesn't have
+    poll_watches: PollWatches,
+
+    /// How often to look at a running target while GDB is quiet
+    poll_interval: Duration,
+
+    /// Everyone else connected, to take turns with
+    clients: SharedClients,
+
+    /// Whether this client may run and change the target, or only look
+    role: Role,
 }
 
 #[derive(Debug)]
@@ -35,6 +233,26 @@ pub enum GdbServerError {
     BridgeError(BridgeError),
 }
 
+impl GdbServerError {
+    /// Whether the session ended because the bridge failed, rather than
+    /// because GDB went away
+    pub fn is_bridge_failure(&self) -> bool {
+        matches!(
+            self,
+            GdbServerError::BridgeError(_)
+                | GdbServerError::CpuError(RiscvCpuError::BridgeError(_))
+        )
+    }
+
+    /// Whether the session ended because GDB went away
+    pub fn is_disconnect(&self) -> bool {
+        matches!(
+            self,
+            GdbServerError::ConnectionClosed | GdbServerError::IoError(_)
+        )
+    }
+}
+
 impl std::convert::From<BridgeError> for GdbServerError {
     fn from(e: BridgeError) -> Self {
         GdbServerError::BridgeError(e)
@@ -59,40 +277,71 @@ impl std::convert::From<std::num::ParseIntError> for GdbServerError {
     }
 }
 
-#[derive(Debug)]
+/// The type field of `Z` and `z` packets
+#[derive(Clone, Copy, Debug, PartialEq)]
 enum BreakPointType {
-    BreakSoft,
-    BreakHard,
-    WatchWrite,
-    WatchRead,
-    WatchAccess,
+    BreakSoft = 0,
+    BreakHard = 1,
+    WatchWrite = 2,
+    WatchRead = 3,
+    WatchAccess = 4,
+}
+
+impl TryFrom<u32> for BreakPointType {
+    type Error = GdbServerError;
+
+    fn try_from(kind: u32) -> Result<BreakPointType, GdbServerError> {
+        match kind {
+            0 => Ok(BreakPointType::BreakSoft),
+            1 => Ok(BreakPointType::BreakHard),
+            2 => Ok(BreakPointType::WatchWrite),
+            3 => Ok(BreakPointType::WatchRead),
+            4 => Ok(BreakPointType::WatchAccess),
+            _ => Err(GdbServerError::ParseIntError),
+        }
+    }
 }
 
 impl BreakPointType {
     fn from_str(r: &str) -> Result<BreakPointType, GdbServerError> {
-        match r {
-            "0" => Ok(BreakPointType::BreakSoft),
-            "1" => Ok(BreakPointType::BreakHard),
-            "2" => Ok(BreakPointType::WatchWrite),
-            "3" => Ok(BreakPointType::WatchRead),
-            "4" => Ok(BreakPointType::WatchAccess),
-            _ => Err(GdbServerError::ParseIntError),
+        BreakPointType::try_from(r.parse::<u32>()?)
+    }
+
+    /// What a trigger for this has to fire on
+    fn watch_kind(self) -> WatchKind {
+        match self {
+            BreakPointType::BreakSoft | BreakPointType::BreakHard => WatchKind::Execute,
+            BreakPointType::WatchWrite => WatchKind::Write,
+            BreakPointType::WatchRead => WatchKind::Read,
+            BreakPointType::WatchAccess => WatchKind::Access,
         }
     }
 }
 
+#[derive(Debug)]
+enum VContAction {
+    /// c or C##
+    Continue,
+
+    /// s or S##
+    Step,
+
+    /// t, which only non-stop mode uses
+    Stop,
+}
+
 #[derive(Debug)]
 enum GdbCommand {
     Unknown(String),
 
     /// qSupported
-    SupportedQueries(String),
+    SupportedQueries,
 
     /// QStartNoAckMode
     StartNoAckMode,
 
-    /// Hg#
-    SetCurrentThread(u64),
+    /// Hg# (# may be -1)
+    SetCurrentThread(i64),
 
     /// Hc# (# may be -1)
     ContinueThread(i32),
@@ -103,6 +352,9 @@ enum GdbCommand {
     /// qfThreadInfo
     GetThreadInfo,
 
+    /// qsThreadInfo
+    GetThreadInfoNext,
+
     /// qC
     GetCurrentThreadId,
 
@@ -118,20 +370,35 @@ enum GdbCommand {
     /// qSymbol::
     SymbolsReady,
 
+    /// QPassSignals:#;#
+    PassSignals(Vec<u8>),
+
+    /// QProgramSignals:#;#
+    ProgramSignals,
+
+    /// QCatchSyscalls:1;#;# or QCatchSyscalls:0
+    CatchSyscalls(Option<Vec<u32>>),
+
+    /// qEcho:...
+    Echo(String),
+
     /// m#,#
     ReadMemory(u32 /* addr */, u32 /* length */),
 
-    /// vCont?
-    VContQuery,
+    /// M#,#:XX... or X#,#:binary
+    WriteMemory(u32 /* addr */, Vec<u8> /* data */),
 
-    /// vCont;c
-    VContContinue,
+    /// G XX...
+    WriteRegisters(Vec<u32>),
 
-    /// vCont;C04:0;c
-    VContContinueFromSignal(String),
+    /// P#=XX...
+    WriteRegister(u32 /* regnum */, u32 /* value */),
+
+    /// vCont?
+    VContQuery,
 
-    /// vCont;s:0;c
-    VContStepFromSignal(String),
+    /// vCont;s:1;c -- each action, and the thread it applies to if any
+    VCont(Vec<(VContAction, Option<i64>)>),
 
     /// c
     Continue,
@@ -142,6 +409,18 @@ enum GdbCommand {
     /// Ctrl-C
     Interrupt,
 
+    /// vCtrlC, which is how GDB interrupts in non-stop mode
+    CtrlC,
+
+    /// QNonStop:0 or QNonStop:1
+    NonStop(bool),
+
+    /// vStopped
+    StoppedAck,
+
+    /// D or D;pid
+    Detach,
+
     /// qRcmd,
     MonitorCommand(String),
 
@@ -162,6 +441,9 @@ enum GdbCommand {
     /// qOffsets
     GetOffsets,
 
+    /// qXfer:memory-map:read::0,1000
+    ReadMemoryMap(u32 /* offset */, u32 /* len */),
+
     /// qXfer:features:read:target.xml:0,1000
     ReadFeature(
         String, /* filename */
@@ -171,32 +453,202 @@ enum GdbCommand {
 
     /// qXfer:threads:read::0,1000
     ReadThreads(u32 /* offset */, u32 /* len */),
+
+    /// vFile:...
+    File(FileRequest),
+}
+
+impl GdbCommand {
+    /// Whether the command runs the target or changes it, which only the
+    /// controller may do
+    fn changes_target(&self) -> bool {
+        matches!(
+            self,
+            GdbCommand::CatchSyscalls(_)
+                | GdbCommand::WriteMemory(..)
+                | GdbCommand::WriteRegisters(_)
+                | GdbCommand::WriteRegister(..)
+                | GdbCommand::VCont(_)
+                | GdbCommand::Continue
+                | GdbCommand::Step
+                | GdbCommand::Interrupt
+                | GdbCommand::CtrlC
+                | GdbCommand::AddBreakpoint(..)
+                | GdbCommand::RemoveBreakpoint(..)
+        )
+    }
+}
+
+/// The operation of a `vFile` packet
+#[derive(Debug)]
+enum FileRequest {
+    /// vFile:setfs:pid.  There's only the one filesystem, so the pid
+    /// doesn't matter.
+    SetFs,
+
+    /// vFile:open:path,flags,mode
+    Open(Vec<u8>, u32, u32),
+
+    /// vFile:close:fd
+    Close(u32),
+
+    /// vFile:pread:fd,count,offset
+    Pread(u32, u32, u32),
+
+    /// vFile:pwrite:fd,offset,data
+    Pwrite(u32, u32, Vec<u8>),
+
+    /// vFile:unlink:path
+    Unlink(Vec<u8>),
 }
 
 impl GdbServer {
-    pub fn new(cfg: &Config) -> Result<GdbServer, GdbServerError> {
-        let listener = TcpListener::bind(format!("{}:{}", cfg.bind_addr, cfg.bind_port))?;
+    /// Wait for GDB to connect.  It controls the target unless another of
+    /// `clients` already does, in which case it only observes.  A saved
+    /// `session` is taken by the first controller, which picks up where the
+    /// last adapter left off; later ones start afresh, as GDB expects.
+    pub fn new(
+        cfg: &Config,
+        listener: &GdbListener,
+        target: SharedTargetState,
+        clients: &SharedClients,
+        session: &mut Option<Session>,
+        kernel: Option<&KernelSymbols>,
+    ) -> Result<GdbServer, GdbServerError> {
+        let (connection, peer) = listener.accept()?;
+        let role = clients.join();
+        // The session belongs to whoever is in control
+        let session = match role {
+            Role::Controller => {
+                ui_event!(Event::Attach, "GDB connected from {}", peer);
+                session.take()
+            }
+            Role::Observer => {
+                ui_event!(Event::Attach, "GDB connected from {} as an observer", peer);
+                None
+            }
+        };
+        Ok(GdbServer::for_client(
+            cfg,
+            connection,
+            target,
+            clients.clone(),
+            role,
+            session,
+            kernel,
+        ))
+    }
 
-        // accept connections and process them serially
-        println!(
-            "Accepting connections on {}:{}",
-            cfg.bind_addr, cfg.bind_port
-        );
-        let (connection, _sockaddr) = listener.accept()?;
-        println!("Connection from {:?}", connection.peer_addr()?);
-        Ok(GdbServer {
+    /// Serve GDB over a connection that's already open
+    pub fn with_connection(
+        cfg: &Config,
+        connection: Connection,
+        target: SharedTargetState,
+        session: Option<Session>,
+        kernel: Option<&KernelSymbols>,
+    ) -> GdbServer {
+        let clients = Clients::new_shared();
+        let role = clients.join();
+        GdbServer::for_client(cfg, connection, target, clients, role, session, kernel)
+    }
+
+    fn for_client(
+        cfg: &Config,
+        connection: Connection,
+        target: SharedTargetState,
+        clients: SharedClients,
+        role: Role,
+        session: Option<Session>,
+        kernel: Option<&KernelSymbols>,
+    ) -> GdbServer {
+        // Observers mustn't borrow anything from the target
+        let controls = role == Role::Controller;
+        let mut server = GdbServer {
             connection,
             no_ack_mode: false,
-            is_alive: true,
-            last_signal: 0,
-        })
+            target,
+            current_hart: 0,
+            continue_hart: None,
+            packet_latency: LatencyStats::new(),
+            timings: CommandTimings::default(),
+            use_rle: cfg.gdb_rle,
+            use_escaping: cfg.gdb_escaping,
+            breakpoints: vec![],
+            patched: vec![],
+            fallback_regions: cfg.breakpoint_fallback.clone(),
+            fallbacks: vec![],
+            pass_signals: vec![],
+            catch_causes: vec![],
+            catch_syscalls: None,
+            translate_addresses: false,
+            kernel: kernel.cloned(),
+            kernel_threads: false,
+            current_task: None,
+            file_agent: cfg.file_agent.filter(|_| controls).map(FileAgent::new),
+            scratch: Scratch::new(cfg).filter(|_| controls),
+            power: cfg
+                .power
+                .as_ref()
+                .filter(|_| controls)
+                .map(PowerSwitch::new),
+            csr_map: None,
+            uptime: None,
+            contexts: vec![],
+            ecc_banks: vec![],
+            ddr: None,
+            boot_control: cfg.boot_control.clone().filter(|_| controls),
+            xfer_cache: XferCache::default(),
+            doorbell: cfg.doorbell.clone(),
+            ecc_annotate: false,
+            awaiting_stop: false,
+            non_stop: false,
+            stops: rsp::Notifications::new("Stop"),
+            poll_watches: PollWatches::default(),
+            poll_interval: cfg.tuning.poll_interval,
+            clients,
+            role,
+        };
+        if let Some(ref path) = cfg.csr_csv {
+            match CsrMap::load(path) {
+                Ok(map) => server.csr_map = Some(map),
+                Err(e) => ui_error!("Couldn't load {}: {}", path, e),
+            }
+        }
+        server.uptime = server.csr_map.as_ref().and_then(Uptime::detect);
+        if let Some(ref map) = server.csr_map {
+            server.ecc_banks = EccBank::detect(map);
+            server.ddr = DdrControl::detect(map);
+        }
+        if let Some(ref uptime) = server.uptime {
+            log_adapter!("Stamping halts with the uptime from {}", uptime);
+        }
+        if let Some(session) = session {
+            server.current_hart = session.hart;
+            server.use_rle = session.use_rle;
+            server.use_escaping = session.use_escaping;
+            server.breakpoints = session.breakpoints;
+            server.catch_causes = session.catch_causes;
+        }
+        server
     }
 
     fn packet_to_command(&self, pkt: &[u8]) -> Result<GdbCommand, GdbServerError> {
+        // vFile:pwrite carries binary data, so it's parsed before the
+        // packet is turned into a string
+        if pkt.starts_with(b"vFile:") {
+            if let Some(request) = parse_file_request(&pkt[6..])? {
+                return Ok(GdbCommand::File(request));
+            }
+        }
+        // So does X
+        if let Some(args) = pkt.strip_prefix(b"X") {
+            let (addr, data) = parse_memory_write(args, true)?;
+            return Ok(GdbCommand::WriteMemory(addr, data));
+        }
         let pkt = String::from_utf8_lossy(pkt).to_string();
 
         if pkt == "qSupported" || pkt.starts_with("qSupported:") {
-            Ok(GdbCommand::SupportedQueries(pkt))
+            Ok(GdbCommand::SupportedQueries)
         } else if pkt == "QStartNoAckMode" {
             Ok(GdbCommand::StartNoAckMode)
         } else if pkt == "qAttached" {
@@ -206,42 +658,55 @@ impl GdbServer {
         } else if pkt.starts_with("qXfer:features:read:") {
             let pkt = pkt.trim_start_matches("qXfer:features:read:");
             let fields: Vec<&str> = pkt.split(':').collect();
-            let offsets: Vec<&str> = fields[1].split(',').collect();
+            let offsets: Vec<&str> = fields.get(1).unwrap_or(&"").split(',').collect();
             let offset = u32::from_str_radix(offsets[0], 16)?;
-            let len = u32::from_str_radix(offsets[1], 16)?;
+            let len = u32::from_str_radix(offsets.get(1).unwrap_or(&""), 16)?;
             Ok(GdbCommand::ReadFeature(fields[0].to_string(), offset, len))
+        } else if let Some(offsets) = pkt.strip_prefix("qXfer:memory-map:read::") {
+            let offsets: Vec<&str> = offsets.split(',').collect();
+            let offset = u32::from_str_radix(offsets[0], 16)?;
+            let len = u32::from_str_radix(offsets.get(1).unwrap_or(&""), 16)?;
+            Ok(GdbCommand::ReadMemoryMap(offset, len))
         } else if pkt.starts_with("qXfer:threads:read::") {
             let pkt = pkt.trim_start_matches("qXfer:threads:read::");
             let offsets: Vec<&str> = pkt.split(',').collect();
             let offset = u32::from_str_radix(offsets[0], 16)?;
-            let len = u32::from_str_radix(offsets[1], 16)?;
+            let len = u32::from_str_radix(offsets.get(1).unwrap_or(&""), 16)?;
             Ok(GdbCommand::ReadThreads(offset, len))
-        } else if pkt.starts_with("Z") {
-            let pkt = pkt.trim_start_matches("Z");
-            let fields: Vec<&str> = pkt.split(',').collect();
-            let bptype = BreakPointType::from_str(fields[0])?;
-            let address = u32::from_str_radix(fields[1], 16)?;
-            let size = u32::from_str_radix(fields[2], 16)?;
+        } else if let Some(args) = pkt.strip_prefix('Z') {
+            let fields: Vec<&str> = args.split(',').collect();
+            // Types we don't know get an empty reply, which is how GDB
+            // learns they aren't supported
+            let bptype = match BreakPointType::from_str(fields[0]) {
+                Ok(bptype) => bptype,
+                Err(_) => return Ok(GdbCommand::Unknown(pkt)),
+            };
+            let address = u32::from_str_radix(fields.get(1).unwrap_or(&""), 16)?;
+            let size = u32::from_str_radix(fields.get(2).unwrap_or(&""), 16)?;
             Ok(GdbCommand::AddBreakpoint(bptype, address, size))
-        } else if pkt.starts_with("z") {
-            let pkt = pkt.trim_start_matches("z");
-            let fields: Vec<&str> = pkt.split(',').collect();
-            let bptype = BreakPointType::from_str(fields[0])?;
-            let address = u32::from_str_radix(fields[1], 16)?;
-            let size = u32::from_str_radix(fields[2], 16)?;
+        } else if let Some(args) = pkt.strip_prefix('z') {
+            let fields: Vec<&str> = args.split(',').collect();
+            // Types we don't know get an empty reply, which is how GDB
+            // learns they aren't supported
+            let bptype = match BreakPointType::from_str(fields[0]) {
+                Ok(bptype) => bptype,
+                Err(_) => return Ok(GdbCommand::Unknown(pkt)),
+            };
+            let address = u32::from_str_radix(fields.get(1).unwrap_or(&""), 16)?;
+            let size = u32::from_str_radix(fields.get(2).unwrap_or(&""), 16)?;
             Ok(GdbCommand::RemoveBreakpoint(bptype, address, size))
         } else if pkt.starts_with("qRcmd,") {
             let pkt = pkt.trim_start_matches("qRcmd,");
             let pkt_bytes = pkt.as_bytes();
             let mut tmp1 = Vec::new();
             let mut acc = 0;
-            for i in 0..pkt.len() {
-                let nybble = if pkt_bytes[i] >= 0x30 && pkt_bytes[i] <= 0x39 {
-                    pkt_bytes[i] - 0x30
-                } else if pkt_bytes[i] >= 0x61 && pkt_bytes[i] <= 0x66 {
-                    pkt_bytes[i] + 10 - 0x61
-                } else if pkt_bytes[i] >= 0x41 && pkt_bytes[i] <= 0x46 {
-                    pkt_bytes[i] + 10 - 0x41
+            for (i, &byte) in pkt_bytes.iter().enumerate() {
+                let nybble = if (0x30..=0x39).contains(&byte) {
+                    byte - 0x30
+                } else if (0x61..=0x66).contains(&byte) {
+                    byte + 10 - 0x61
+                } else if (0x41..=0x46).contains(&byte) {
+                    byte + 10 - 0x41
                 } else {
                     0
                 };
@@ -255,6 +720,8 @@ impl GdbServer {
             Ok(GdbCommand::MonitorCommand(
                 String::from_utf8_lossy(&tmp1).to_string(),
             ))
+        } else if pkt == "D" || pkt.starts_with("D;") {
+            Ok(GdbCommand::Detach)
         } else if pkt == "g" {
             Ok(GdbCommand::GetRegisters)
         } else if pkt == "c" {
@@ -265,15 +732,35 @@ impl GdbServer {
             let pkt = pkt.trim_start_matches("m").to_string();
             let v: Vec<&str> = pkt.split(',').collect();
             let addr = u32::from_str_radix(v[0], 16)?;
-            let length = u32::from_str_radix(v[1], 16)?;
+            let length = u32::from_str_radix(v.get(1).unwrap_or(&""), 16)?;
             Ok(GdbCommand::ReadMemory(addr, length))
+        } else if let Some(args) = pkt.strip_prefix('M') {
+            let (addr, data) = parse_memory_write(args.as_bytes(), false)?;
+            Ok(GdbCommand::WriteMemory(addr, data))
+        } else if let Some(args) = pkt.strip_prefix('G') {
+            let data = rsp::decode_hex(args.as_bytes()).ok_or(GdbServerError::ParseIntError)?;
+            Ok(GdbCommand::WriteRegisters(
+                data.chunks_exact(4).map(LittleEndian::read_u32).collect(),
+            ))
+        } else if let Some(args) = pkt.strip_prefix('P') {
+            let mut fields = args.splitn(2, '=');
+            let regnum = u32::from_str_radix(fields.next().unwrap_or(""), 16)?;
+            let data = fields
+                .next()
+                .and_then(|v| rsp::decode_hex(v.as_bytes()))
+                .filter(|d| d.len() == 4)
+                .ok_or(GdbServerError::ParseIntError)?;
+            Ok(GdbCommand::WriteRegister(
+                regnum,
+                LittleEndian::read_u32(&data),
+            ))
         } else if pkt.starts_with("p") {
             Ok(GdbCommand::GetRegister(u32::from_str_radix(
-                pkt.trim_start_matches("r"),
+                pkt.trim_start_matches("p"),
                 16,
             )?))
         } else if pkt.starts_with("Hg") {
-            Ok(GdbCommand::SetCurrentThread(u64::from_str_radix(
+            Ok(GdbCommand::SetCurrentThread(i64::from_str_radix(
                 pkt.trim_start_matches("Hg"),
                 16,
             )?))
@@ -288,30 +775,73 @@ impl GdbServer {
             Ok(GdbCommand::LastSignalPacket)
         } else if pkt == "qfThreadInfo" {
             Ok(GdbCommand::GetThreadInfo)
+        } else if pkt == "qsThreadInfo" {
+            Ok(GdbCommand::GetThreadInfoNext)
+        } else if pkt == "QNonStop:0" || pkt == "QNonStop:1" {
+            Ok(GdbCommand::NonStop(pkt.ends_with('1')))
+        } else if pkt == "vStopped" {
+            Ok(GdbCommand::StoppedAck)
+        } else if pkt == "vCtrlC" {
+            Ok(GdbCommand::CtrlC)
         } else if pkt == "vCont?" {
             Ok(GdbCommand::VContQuery)
-        } else if pkt == "vCont;c" {
-            Ok(GdbCommand::VContContinue)
-        } else if pkt.starts_with("vCont;C") {
-            //vCont;C04:0;c
-            let pkt = pkt.trim_start_matches("vCont;C").to_string();
-            let v: Vec<&str> = pkt.split(',').collect();
-            Ok(GdbCommand::VContContinueFromSignal(pkt))
-        } else if pkt.starts_with("vCont;s") {
-            let pkt = pkt.trim_start_matches("vCont;s").to_string();
-            Ok(GdbCommand::VContStepFromSignal(pkt))
+        } else if pkt.starts_with("vCont;") {
+            let mut actions = vec![];
+            for action in pkt.trim_start_matches("vCont;").split(';') {
+                let mut fields = action.splitn(2, ':');
+                let verb = fields.next().unwrap_or("");
+                let thread = match fields.next() {
+                    Some(t) => Some(i64::from_str_radix(t, 16)?),
+                    None => None,
+                };
+                // Signals to deliver (C## and S##) are ignored
+                let verb = match verb.chars().next() {
+                    Some('c') | Some('C') => VContAction::Continue,
+                    Some('s') | Some('S') => VContAction::Step,
+                    Some('t') => VContAction::Stop,
+                    _ => return Ok(GdbCommand::Unknown(pkt)),
+                };
+                actions.push((verb, thread));
+            }
+            Ok(GdbCommand::VCont(actions))
+        } else if pkt.starts_with("QPassSignals:") {
+            Ok(GdbCommand::PassSignals(parse_signal_list(
+                pkt.trim_start_matches("QPassSignals:"),
+            )?))
+        } else if pkt.starts_with("QProgramSignals:") {
+            parse_signal_list(pkt.trim_start_matches("QProgramSignals:"))?;
+            Ok(GdbCommand::ProgramSignals)
+        } else if pkt == "QCatchSyscalls:0" {
+            Ok(GdbCommand::CatchSyscalls(None))
+        } else if pkt.starts_with("QCatchSyscalls:1") {
+            let mut syscalls = vec![];
+            for num in pkt
+                .trim_start_matches("QCatchSyscalls:1")
+                .split(';')
+                .filter(|s| !s.is_empty())
+            {
+                syscalls.push(u32::from_str_radix(num, 16)?);
+            }
+            Ok(GdbCommand::CatchSyscalls(Some(syscalls)))
         } else if pkt == "qSymbol::" {
             Ok(GdbCommand::SymbolsReady)
+        } else if pkt.starts_with("qEcho:") {
+            Ok(GdbCommand::Echo(
+                pkt.trim_start_matches("qEcho:").to_string(),
+            ))
         } else {
             Ok(GdbCommand::Unknown(pkt))
         }
     }
 
-    fn get_command(&mut self) -> Result<GdbCommand, GdbServerError> {
-        let mut buffer = [0; 16384];
+    /// Wait for the next command, and return it along with the name of
+    /// the packet it came in and how long that took to parse
+    fn get_command(&mut self) -> Result<(GdbCommand, String, Duration), GdbServerError> {
+        let mut decoder = Decoder::default();
         let mut byte = [0; 1];
-        let mut remote_checksum = [0; 2];
-        let mut buffer_offset = 0;
+
+        // Nothing should be left waiting while we wait for GDB
+        self.connection.flush()?;
 
         // XXX Replace this with a BufReader for performance
         loop {
@@ -320,170 +850,3475 @@ impl GdbServer {
                 return Err(GdbServerError::ConnectionClosed);
             }
 
-            match byte[0] {
-                0x24 /*'$'*/ => {
-                    let mut checksum: u8 = 0;
-                    loop {
-                        let len = self.connection.read(&mut byte)?;
-                        if len == 0 {
-                            return Err(GdbServerError::ConnectionClosed);
-                        }
-                        match byte[0] as char {
-                            '#' => {
-                                // There's got to be a better way to compare the checksum
-                                self.connection.read(&mut remote_checksum)?;
-                                let checksum_str = format!("{:02x}", checksum);
-                                if checksum_str != String::from_utf8_lossy(&remote_checksum) {
-                                    println!(
-                                        "Checksum mismatch: Calculated {:?} vs {}",
-                                        checksum_str,
-                                        String::from_utf8_lossy(&remote_checksum)
-                                    );
-                                    self.gdb_send_nak()?;
-                                } else {
-                                    if !self.no_ack_mode {
-                                        self.gdb_send_ack()?;
-                                    }
-                                }
-                                let (buffer, _remainder) = buffer.split_at(buffer_offset);
-                                println!("<- Read packet ${:?}#{:#?}", String::from_utf8_lossy(buffer), String::from_utf8_lossy(&remote_checksum));
-                                return self.packet_to_command(&buffer);
-                            }
-                            other => {
-                                buffer[buffer_offset] = other as u8;
-                                buffer_offset = buffer_offset + 1;
-                                checksum = checksum.wrapping_add(other as u8);
-                            }
+            match decoder.push(byte[0]) {
+                None | Some(rsp::Event::Ack) | Some(rsp::Event::Nak) => {}
+                Some(rsp::Event::Packet(pkt)) => {
+                    log_gdb!("<- Read packet ${:?}", String::from_utf8_lossy(&pkt));
+                    // A new GDB on a pty that stayed open starts out
+                    // expecting acks again
+                    if pkt.starts_with(b"qSupported") {
+                        self.no_ack_mode = false;
+                        self.non_stop = false;
+                        self.stops.clear();
+                        self.xfer_cache.clear();
+                    }
+                    if !self.no_ack_mode {
+                        self.gdb_send_ack()?;
+                    }
+                    let start = Instant::now();
+                    match self.packet_to_command(&pkt) {
+                        Ok(cmd) => return Ok((cmd, packet_name(&pkt), start.elapsed())),
+                        // A packet we know that doesn't make sense gets an
+                        // error, the same as one that can't be carried out,
+                        // rather than ending the session
+                        Err(GdbServerError::ParseIntError) => {
+                            log_gdb!(@Verbose, "Couldn't parse ${:?}", String::from_utf8_lossy(&pkt));
+                            self.gdb_send(b"E01")?;
+                            self.connection.flush()?;
                         }
+                        Err(e) => return Err(e),
+                    }
+                }
+                Some(rsp::Event::BadChecksum(pkt)) => {
+                    log_gdb!(
+                        @Verbose,
+                        "Checksum mismatch on ${:?}, calculated {:02x}",
+                        String::from_utf8_lossy(&pkt),
+                        rsp::checksum(&pkt)
+                    );
+                    self.gdb_send_nak()?;
+                }
+                Some(rsp::Event::Overflow) => {
+                    log_gdb!(@Verbose, "Packet longer than {} bytes dropped", rsp::MAX_PACKET_SIZE);
+                    // A NAK would only have GDB send the same packet
+                    // again, and without acks it goes unheard, so the
+                    // packet is taken and refused either way
+                    if !self.no_ack_mode {
+                        self.gdb_send_ack()?;
                     }
+                    self.gdb_send(b"E.packet longer than PacketSize")?;
+                    self.connection.flush()?;
+                }
+                Some(rsp::Event::Interrupt) => {
+                    return Ok((GdbCommand::Interrupt, "^C".to_owned(), Duration::default()))
+                }
+                Some(rsp::Event::Junk(other)) => {
+                    log_gdb!(@Verbose, "Warning: unrecognied byte received: {}", other)
                 }
-                0x2b /*'+'*/ => {}
-                0x2d /*'-'*/ => {}
-                0x3 => return Ok(GdbCommand::Interrupt),
-                other => println!("Warning: unrecognied byte received: {}", other),
             }
         }
     }
 
     pub fn process(&mut self, cpu: &RiscvCpu, bridge: &Bridge) -> Result<(), GdbServerError> {
-        let cmd = self.get_command()?;
+        // While the target runs, keep an eye on it between packets so GDB
+        // hears about a halt without having to send ^C
+        if self.awaiting_stop {
+            self.connection.flush()?;
+            if !self.connection.wait_readable(self.poll_interval)? {
+                let clients = self.clients.clone();
+                let _turn = clients.take_turn();
+                return self.poll_for_stop(cpu, bridge);
+            }
+        }
+        let (cmd, name, parse) = self.get_command()?;
+        let clients = self.clients.clone();
+        let _turn = clients.take_turn();
+        let start = Instant::now();
+        let bridge_time = stats::phase_time(Phase::Bridge);
+        let target_time = stats::phase_time(Phase::Target);
 
-        println!("<- Read packet {:?}", cmd);
+        log_gdb!("<- Read packet {:?}", cmd);
+        let running = self.target.read().unwrap().run_state == RunState::Running;
+        let may_change = match cmd {
+            GdbCommand::MonitorCommand(ref cmd) => {
+                self.role != Role::Observer && monitor_may_change_target(cmd)
+            }
+            _ => cmd.changes_target() && self.role != Role::Observer,
+        };
+        if may_change {
+            self.target.write().unwrap().changed();
+        }
         match cmd {
-            GdbCommand::SupportedQueries(_) => self.gdb_send(b"PacketSize=3fff;qXfer:memory-map:read+;qXfer:features:read+;qXfer:threads:read+;QStartNoAckMode+;vContSupported+")?,
+            _ if self.role == Role::Observer && cmd.changes_target() => {
+                self.gdb_send(b"E.an observer can't run or change the target")?
+            }
+            GdbCommand::GetRegisters | GdbCommand::GetRegister(_)
+                if self.role == Role::Observer && running =>
+            {
+                self.gdb_send(b"E.the target is running, so its registers can't be read")?
+            }
+            GdbCommand::SupportedQueries => self.gdb_send(SUPPORTED_FEATURES.as_bytes())?,
             GdbCommand::StartNoAckMode => { self.no_ack_mode = true; self.gdb_send(b"OK")?},
-            GdbCommand::SetCurrentThread(_) => self.gdb_send(b"OK")?,
-            GdbCommand::ContinueThread(_) => self.gdb_send(b"OK")?,
-            GdbCommand::AddBreakpoint(_, _, _) => self.gdb_send(b"OK")?,
-            GdbCommand::RemoveBreakpoint(_, _, _) => self.gdb_send(b"OK")?,
+            GdbCommand::SetCurrentThread(tid) if tid >= TASK_TID_BASE => {
+                match self
+                    .kernel_tasks(cpu, bridge)
+                    .into_iter()
+                    .find(|t| t.tid() == tid)
+                {
+                    Some(task) => {
+                        self.current_task = Some(task);
+                        self.gdb_send(b"OK")?
+                    }
+                    None => self.gdb_send(b"E01")?,
+                }
+            }
+            GdbCommand::SetCurrentThread(tid) => match self.thread_to_hart(cpu, tid) {
+                Some(Some(hart)) => {
+                    self.current_hart = hart;
+                    self.current_task = None;
+                    self.gdb_send(b"OK")?
+                }
+                Some(None) => self.gdb_send(b"OK")?,
+                None => self.gdb_send(b"E01")?,
+            },
+            GdbCommand::ContinueThread(tid) => match self.thread_to_hart(cpu, tid as i64) {
+                Some(hart) => { self.continue_hart = hart; self.gdb_send(b"OK")? }
+                None => self.gdb_send(b"E01")?,
+            },
+            GdbCommand::AddBreakpoint(bptype, addr, len) => {
+                let reply = self.add_breakpoint(cpu, bridge, bptype, addr, len)?;
+                self.gdb_send(reply.as_bytes())?
+            }
+            GdbCommand::RemoveBreakpoint(BreakPointType::BreakSoft, addr, kind) => {
+                // A hardware breakpoint may have been patched in at the
+                // same place, and that stays
+                let result = if self.fallbacks.contains(&addr) {
+                    Ok(())
+                } else {
+                    self.remove_soft_breakpoint(cpu, bridge, addr)
+                };
+                match result {
+                    Ok(()) => {
+                        self.forget_breakpoint(BreakPointType::BreakSoft, addr, kind);
+                        self.gdb_send(b"OK")?
+                    }
+                    Err(MmuError::PageFault(_)) => self.gdb_send(b"E0e")?,
+                    Err(MmuError::BridgeError(e)) => return Err(e.into()),
+                }
+            }
+            GdbCommand::RemoveBreakpoint(BreakPointType::BreakHard, addr, kind)
+                if self.fallbacks.contains(&addr) =>
+            {
+                self.fallbacks.retain(|&a| a != addr);
+                let soft = Breakpoint {
+                    kind: BreakPointType::BreakSoft as u32,
+                    addr,
+                    len: kind,
+                };
+                if !self.breakpoints.contains(&soft) {
+                    match self.remove_soft_breakpoint(cpu, bridge, addr) {
+                        Ok(()) => {}
+                        Err(MmuError::PageFault(_)) => {
+                            self.gdb_send(b"E0e")?;
+                            return Ok(());
+                        }
+                        Err(MmuError::BridgeError(e)) => return Err(e.into()),
+                    }
+                }
+                self.forget_breakpoint(BreakPointType::BreakHard, addr, kind);
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::RemoveBreakpoint(BreakPointType::BreakHard, addr, kind) => {
+                cpu.remove_hw_breakpoint(bridge, addr)?;
+                self.forget_breakpoint(BreakPointType::BreakHard, addr, kind);
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::RemoveBreakpoint(bptype, addr, len) => {
+                // A write watch that ran out of triggers is being polled
+                // for instead, and has no trigger to take out
+                let polled =
+                    bptype == BreakPointType::WatchWrite && self.poll_watches.remove_gdb(addr, len);
+                if !polled {
+                    let trigger = Trigger {
+                        kind: bptype.watch_kind(),
+                        addr,
+                        len,
+                    };
+                    cpu.remove_trigger(bridge, trigger)?;
+                }
+                self.forget_breakpoint(bptype, addr, len);
+                self.gdb_send(b"OK")?
+            }
+            // In non-stop mode every halted hart is reported, and GDB
+            // collects the rest with vStopped
+            GdbCommand::LastSignalPacket if self.non_stop => {
+                self.stops.clear();
+                for hart in 0..cpu.hart_count() {
+                    if cpu.is_halted(bridge, hart)? {
+                        let reply = self.stop_reply(cpu, bridge, hart)?;
+                        self.stops.push(reply.into_bytes());
+                    }
+                }
+                match self.stops.first() {
+                    Some(reply) => {
+                        let reply = reply.to_vec();
+                        self.gdb_send(&reply)?
+                    }
+                    None => self.gdb_send(b"OK")?,
+                }
+            }
+            // GDB asking again isn't a new stop, so it isn't an event
             GdbCommand::LastSignalPacket => {
-                let sig_str = format!("S{:02x}", self.last_signal);
-                self.gdb_send(if self.is_alive { sig_str.as_bytes() } else { b"W00" })?
+                if self.target.read().unwrap().is_alive {
+                    let reply = self.stop_reply(cpu, bridge, self.current_hart)?;
+                    self.report_stop(cpu, bridge, reply)?
+                } else {
+                    self.gdb_send(b"W00")?
+                }
             },
-            GdbCommand::GetThreadInfo => self.gdb_send(b"l")?,
-            GdbCommand::GetCurrentThreadId => self.gdb_send(b"QC0")?,
+            GdbCommand::GetThreadInfo => {
+                let mut threads: Vec<String> = (1..=cpu.hart_count()).map(|t| format!("{:x}", t)).collect();
+                for task in self.kernel_tasks(cpu, bridge) {
+                    threads.push(format!("{:x}", task.tid()));
+                }
+                self.gdb_send(format!("m{}", threads.join(",")).as_bytes())?
+            }
+            GdbCommand::GetThreadInfoNext => self.gdb_send(b"l")?,
+            GdbCommand::GetCurrentThreadId => {
+                let tid = match self.current_task {
+                    Some(ref task) => task.tid(),
+                    None => self.current_hart as i64 + 1,
+                };
+                self.gdb_send(format!("QC{:x}", tid).as_bytes())?
+            }
             GdbCommand::CheckIsAttached => self.gdb_send(b"1")?,
-            GdbCommand::GetRegisters => {
-                let mut register_list = String::new();
-                for i in 0..33 {
-                    register_list.push_str(format!("{:08x}", i).as_str());
+            GdbCommand::GetRegisters if self.current_task.is_some() => {
+                let values = self.task_registers(cpu, bridge)?;
+                self.gdb_send_saved_registers(&values)?
+            }
+            GdbCommand::GetRegister(regnum) if self.current_task.is_some() => {
+                match self.task_registers(cpu, bridge)?.get(regnum as usize) {
+                    Some(value) => self.gdb_send_saved_registers(&[*value])?,
+                    None => self.gdb_send(b"E01")?,
                 }
-                self.gdb_send(register_list.as_bytes())?
             }
-            GdbCommand::GetRegister(_) => self.gdb_send(b"12345678")?,
-            GdbCommand::SymbolsReady => self.gdb_send(b"OK")?,
-            GdbCommand::ReadMemory(addr, len) => {
+            // A task's registers are what it saved when it was switched
+            // out, which GDB has no business changing
+            GdbCommand::WriteRegisters(_) | GdbCommand::WriteRegister(..)
+                if self.current_task.is_some() =>
+            {
+                self.gdb_send(b"E01")?
+            }
+            GdbCommand::WriteRegisters(values) => {
+                for (regnum, value) in values.into_iter().enumerate().take(33) {
+                    cpu.set_register(bridge, self.current_hart, regnum as u32, value)?;
+                }
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::WriteRegister(regnum, value) => {
+                match cpu.set_register(bridge, self.current_hart, regnum, value) {
+                    Ok(()) => self.gdb_send(b"OK")?,
+                    Err(RiscvCpuError::InvalidRegister(_)) => self.gdb_send(b"E01")?,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            GdbCommand::GetRegisters => {
                 let mut values = vec![];
-                for offset in (0 .. len).step_by(4) {
-                    values.push(cpu.read_memory(bridge, addr + offset, 4)?);
+                for regnum in 0..33 {
+                    values.push(cpu.read_register(bridge, self.current_hart, regnum)?);
                 }
                 self.gdb_send_u32(values)?
+            }
+            GdbCommand::GetRegister(regnum) => match cpu.read_register_words(bridge, self.current_hart, regnum) {
+                Ok(values) => self.gdb_send_u32(values)?,
+                Err(RiscvCpuError::InvalidRegister(_)) => self.gdb_send(b"E01")?,
+                Err(e) => return Err(e.into()),
             },
-            GdbCommand::VContQuery => self.gdb_send(b"vCont;c;C;s;S")?,
-            GdbCommand::VContContinue => cpu.resume(bridge)?,
-            GdbCommand::VContContinueFromSignal(_) => cpu.resume(bridge)?,
-            GdbCommand::VContStepFromSignal(_) => {
-                cpu.step(bridge)?;
-                self.gdb_send(format!("S{:02x}", self.last_signal).as_bytes())?;
-            },
+            GdbCommand::SymbolsReady => self.gdb_send(b"OK")?,
+            GdbCommand::PassSignals(signals) => {
+                self.pass_signals = signals;
+                self.gdb_send(b"OK")?
+            }
+            // Firmware has no signal handlers to deliver signals to, so
+            // there's nothing to set up
+            GdbCommand::ProgramSignals => self.gdb_send(b"OK")?,
+            GdbCommand::CatchSyscalls(syscalls) => {
+                self.catch_syscalls = syscalls;
+                self.update_trap_catching(cpu, bridge)?;
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::Echo(data) => self.gdb_send(data.as_bytes())?,
+            // GDB checks whether X is supported with an empty one
+            GdbCommand::WriteMemory(_, ref data) if data.is_empty() => self.gdb_send(b"OK")?,
+            GdbCommand::WriteMemory(addr, data) => {
+                match self.write_memory(cpu, bridge, addr, &data) {
+                    Ok(()) => self.gdb_send(b"OK")?,
+                    Err(MmuError::PageFault(_)) => self.gdb_send(b"E0e")?,
+                    Err(MmuError::BridgeError(e)) => return Err(e.into()),
+                }
+            }
+            GdbCommand::ReadMemory(addr, len) => {
+                match self.read_memory_checked(cpu, bridge, addr, len) {
+                    Ok(data) => self.gdb_send_hex(&data)?,
+                    Err(MmuError::PageFault(_)) => self.gdb_send(b"E0e")?,
+                    // Say why, since GDB only says it can't access the memory
+                    Err(MmuError::BridgeError(BridgeError::ResourceBusy(why))) => {
+                        ui_error!("Can't read {:08x}: {}", addr, why);
+                        self.gdb_send(b"E10")?
+                    }
+                    Err(MmuError::BridgeError(e)) => return Err(e.into()),
+                }
+            }
+            GdbCommand::VContQuery => self.gdb_send(b"vCont;c;C;s;S;t")?,
+            GdbCommand::VCont(actions) => self.vcont(cpu, bridge, &actions)?,
             GdbCommand::GetOffsets => self.gdb_send(b"Text=0;Data=0;Bss=0")?,
-            GdbCommand::Continue => cpu.resume(&bridge)?,
-            GdbCommand::Step => cpu.step(&bridge)?,
-            GdbCommand::MonitorCommand(_) => self.gdb_send(b"OK")?,
+            GdbCommand::Continue => {
+                self.poll_watches.rearm(bridge)?;
+                match self.continue_hart {
+                    Some(hart) => cpu.resume_hart(bridge, hart)?,
+                    None => cpu.resume(bridge)?,
+                }
+                self.set_run_state(RunState::Running);
+                self.awaiting_stop = true;
+            }
+            GdbCommand::Step => {
+                let hart = self.continue_hart.unwrap_or(self.current_hart);
+                cpu.step_hart(bridge, hart)?;
+                self.set_run_state(RunState::Halted);
+                self.gdb_send_stop_reply(cpu, bridge, hart)?;
+            }
+            GdbCommand::MonitorCommand(cmd) => {
+                let output = self.process_monitor(cpu, bridge, &cmd);
+                self.gdb_send_monitor_output(&output)?
+            }
+            // With no memory map GDB is better off not having one than an
+            // empty one, which would make all of memory off limits
+            GdbCommand::ReadMemoryMap(_, _) if cpu.memory_map().is_empty() => self.gdb_send(b"")?,
+            GdbCommand::ReadMemoryMap(offset, len) => {
+                let xml = self.xfer_document("memory-map", |_| {
+                    Ok::<_, GdbServerError>(board::memory_map_xml(cpu.memory_map()).into_bytes())
+                })?;
+                self.gdb_send_file(&xml, offset, len)?
+            }
             GdbCommand::ReadFeature(filename, offset, len) => {
-                self.gdb_send_file(cpu.get_feature(&filename)?, offset, len)?
-            },
-            GdbCommand::ReadThreads(offset, len) => self.gdb_send_file(cpu.get_threads()?, offset, len)?,
-            GdbCommand::Interrupt => {
-                self.last_signal = 2;
-                cpu.halt(bridge)?;
-                self.gdb_send(format!("S{:02x}", self.last_signal).as_bytes())?
+                let name = format!("features:{}", filename);
+                match self.xfer_document(&name, |_| cpu.get_feature(bridge, &filename)) {
+                    Ok(xml) => self.gdb_send_file(&xml, offset, len)?,
+                    Err(RiscvCpuError::UnrecognizedFile(_)) => self.gdb_send(b"E00")?,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            GdbCommand::ReadThreads(offset, len) => {
+                // Kernel tasks come and go while the target runs, which
+                // doesn't count as a change, so a fresh read starts over
+                if offset == 0 {
+                    self.xfer_cache.forget("threads");
+                }
+                let xml = self.xfer_document("threads", |server| {
+                    let tasks: Vec<(i64, String)> = server
+                        .kernel_tasks(cpu, bridge)
+                        .into_iter()
+                        .map(|t| (t.tid(), format!("{} [{}]", t.comm, t.pid)))
+                        .collect();
+                    cpu.get_threads(&tasks)
+                })?;
+                self.gdb_send_file(&xml, offset, len)?
+            }
+            GdbCommand::Interrupt => self.interrupt(cpu, bridge)?,
+            GdbCommand::CtrlC => {
+                self.gdb_send(b"OK")?;
+                self.interrupt(cpu, bridge)?
+            }
+            GdbCommand::NonStop(enabled) => {
+                self.non_stop = enabled;
+                self.stops.clear();
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::StoppedAck => match self.stops.ack() {
+                Some(reply) => {
+                    let reply = reply.to_vec();
+                    self.gdb_send(&reply)?
+                }
+                None => self.gdb_send(b"OK")?,
             },
-            GdbCommand::Unknown(_) => self.gdb_send(b"")?,
+            GdbCommand::Detach => {
+                self.release_target(cpu, bridge, true);
+                self.gdb_send(b"OK")?
+            }
+            GdbCommand::Unknown(pkt) => {
+                log_gdb!(@Verbose, "unsupported packet: {}", pkt);
+                self.gdb_send(b"")?
+            }
+            GdbCommand::File(request) => self.file_request(cpu, bridge, request)?,
+        };
+        self.packet_latency.record(start.elapsed());
+
+        let timing = CommandTiming {
+            parse,
+            bridge: stats::phase_time(Phase::Bridge) - bridge_time,
+            target: stats::phase_time(Phase::Target) - target_time,
+            total: parse + start.elapsed(),
         };
+        log_adapter!("{} took {}", name, timing);
+        self.timings.record(&name, &timing);
         Ok(())
     }
 
-    fn gdb_send_ack(&mut self) -> io::Result<usize> {
-        self.connection.write(&['+' as u8])
+    /// Stop the target because GDB asked, unless a hart already stopped
+    /// by itself
+    fn interrupt(&mut self, cpu: &RiscvCpu, bridge: &Bridge) -> Result<(), GdbServerError> {
+        // In non-stop mode GDB hears about every hart that was running,
+        // and about nothing if none were
+        let running = if self.non_stop {
+            self.running_harts(cpu, bridge)?
+        } else {
+            vec![]
+        };
+        if self.non_stop && running.is_empty() {
+            return Ok(());
+        }
+        // If a hart already stopped on a breakpoint, report that
+        // rather than the interrupt.
+        let stopped = match self.poll_halted(cpu, bridge)? {
+            Some(hart) if !self.wants_stop(cpu, bridge, hart)? => {
+                log_gdb!("Letting hart {} carry on after {:?}", hart, cpu.halt_reason(hart));
+                cpu.resume_hart(bridge, hart)?;
+                None
+            }
+            other => other,
+        };
+        let hart = match stopped {
+            Some(hart) => {
+                announce_halt(cpu, hart, self.target_time(bridge));
+                hart
+            }
+            // Nothing to do if it's already known to be stopped
+            None if self.target.read().unwrap().run_state == RunState::Halted => self.current_hart,
+            None => {
+                cpu.halt(bridge)?;
+                self.current_hart
+            }
+        };
+        self.set_run_state(RunState::Halted);
+        self.gdb_send_stop_reply(cpu, bridge, hart)?;
+        self.report_other_stops(cpu, bridge, hart, running)
     }
 
-    fn gdb_send_nak(&mut self) -> io::Result<usize> {
-        self.connection.write(&['-' as u8])
+    /// Whether GDB wants to hear about `hart` stopping.  Every trap halts
+    /// the hart while any are being caught, so traps nobody asked for are
+    /// let through, as are signals GDB said to pass.
+    fn wants_stop(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        hart: usize,
+    ) -> Result<bool, GdbServerError> {
+        let reason = match cpu.halt_reason(hart) {
+            Some(reason) => reason,
+            None => return Ok(true),
+        };
+        if self.caught_syscall(cpu, bridge, hart)?.is_some() {
+            return Ok(true);
+        }
+        if let HaltReason::Trap(mcause) = reason {
+            if !self.catch_causes.contains(&mcause) {
+                return Ok(false);
+            }
+        }
+        Ok(!self.pass_signals.contains(&reason.signal()))
     }
 
-    fn gdb_send_u32(&mut self, vals: Vec<u32>) -> io::Result<()> {
-        let mut out_str = String::new();
-        for val in vals {
-            let mut buf = [0; 4];
-            BigEndian::write_u32(&mut buf, val);
-            out_str.push_str(&format!("{:08x}", NativeEndian::read_u32(&buf)));
+    /// If `hart` stopped on an `ecall` that GDB is catching, the syscall
+    /// number, which by convention is in a7
+    fn caught_syscall(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        hart: usize,
+    ) -> Result<Option<u32>, RiscvCpuError> {
+        let syscalls = match (&self.catch_syscalls, cpu.halt_reason(hart)) {
+            (Some(syscalls), Some(HaltReason::Trap(8 | 9 | 11))) => syscalls,
+            _ => return Ok(None),
+        };
+        let num = cpu.read_register(bridge, hart, A7_REGNUM)?;
+        if syscalls.is_empty() || syscalls.contains(&num) {
+            Ok(Some(num))
+        } else {
+            Ok(None)
         }
-        self.gdb_send(out_str.as_bytes())
     }
 
-    fn gdb_send(&mut self, inp: &[u8]) -> io::Result<()> {
-        let mut buffer = [0; 16388];
-        let mut checksum: u8 = 0;
-        buffer[0] = '$' as u8;
-        for i in 0..inp.len() {
-            buffer[i + 1] = inp[i];
-            checksum = checksum.wrapping_add(inp[i]);
-        }
-        let checksum_str = &format!("{:02x}", checksum);
-        let checksum_bytes = checksum_str.as_bytes();
-        buffer[inp.len() + 1] = '#' as u8;
-        buffer[inp.len() + 2] = checksum_bytes[0];
-        buffer[inp.len() + 3] = checksum_bytes[1];
-        let (to_write, _rest) = buffer.split_at(inp.len() + 4);
-        println!(
-            "-> Writing {} bytes: {}",
-            to_write.len(),
-            String::from_utf8_lossy(&to_write)
-        );
-        self.connection.write(&to_write)?;
-        Ok(())
+    /// Only keep the trap breakpoint around while something wants it
+    fn update_trap_catching(&self, cpu: &RiscvCpu, bridge: &Bridge) -> Result<(), BridgeError> {
+        cpu.set_trap_catching(
+            bridge,
+            !self.catch_causes.is_empty() || self.catch_syscalls.is_some(),
+        )
     }
 
-    fn gdb_send_file(&mut self, mut data: Vec<u8>, offset: u32, len: u32) -> io::Result<()> {
-        let offset = offset as usize;
-        let len = len as usize;
-        let mut end = offset + len;
-        if offset > data.len() {
-            self.gdb_send(b"l")?;
-        } else {
-            if end > data.len() {
-                end = data.len();
+    fn set_run_state(&self, run_state: RunState) {
+        let previous = {
+            let mut target = self.target.write().unwrap();
+            if target.run_state != run_state {
+                target.changed();
             }
-            let mut trimmed_features: Vec<u8> = data.drain(offset..end).collect();
-            if trimmed_features.len() >= len {
-                // XXX should this be <= or < ?
-                trimmed_features.insert(0, 'm' as u8);
-            } else {
-                trimmed_features.insert(0, 'l' as u8);
+            std::mem::replace(&mut target.run_state, run_state)
+        };
+        if run_state == RunState::Running && previous != RunState::Running {
+            events::emit(TargetEvent::Resumed);
+        }
+    }
+
+    /// Convert a GDB thread ID into a hart number.  Returns `None` if the
+    /// thread doesn't exist, or `Some(None)` for "any" (0) or "all" (-1).
+    fn thread_to_hart(&self, cpu: &RiscvCpu, tid: i64) -> Option<Option<usize>> {
+        match tid {
+            -1 | 0 => Some(None),
+            t if t > 0 && (t as usize) <= cpu.hart_count() => Some(Some(t as usize - 1)),
+            _ => None,
+        }
+    }
+
+    /// Apply a set of vCont actions.  Each hart takes the first action that
+    /// names it, or the first action without a thread.  Stepped harts step
+    /// alone, while continued harts resume along with their whole group.
+    fn vcont(
+        &mut self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        actions: &[(VContAction, Option<i64>)],
+    ) -> Result<(), GdbServerError> {
+        // In non-stop mode the packet is answered straight away, and stops
+        // are notified
+        if self.non_stop {
+            self.gdb_send(b"OK")?;
+        }
+        let mut step = vec![];
+        let mut resume = vec![];
+        let mut stop = vec![];
+        for hart in 0..cpu.hart_count() {
+            let tid = hart as i64 + 1;
+            let action = actions.iter().find(|(_, thread)| match thread {
+                None | Some(-1) => true,
+                Some(t) => *t == tid,
+            });
+            match action {
+                Some((VContAction::Step, _)) => step.push(hart),
+                Some((VContAction::Stop, _)) => stop.push(hart),
+                Some((VContAction::Continue, _)) => {
+                    for h in cpu.group_of(hart) {
+                        if !resume.contains(&h) {
+                            resume.push(h);
+                        }
+                    }
+                }
+                None => (),
+            }
+        }
+
+        if !resume.is_empty() {
+            self.poll_watches.rearm(bridge)?;
+        }
+        for hart in resume.iter().filter(|h| !step.contains(h)) {
+            cpu.resume_single(bridge, *hart)?;
+        }
+        if !resume.is_empty() {
+            self.set_run_state(RunState::Running);
+            self.awaiting_stop = true;
+        }
+        for hart in &step {
+            cpu.step_hart(bridge, *hart)?;
+        }
+        if let Some(hart) = step.first() {
+            self.set_run_state(RunState::Halted);
+            self.gdb_send_stop_reply(cpu, bridge, *hart)?;
+            self.report_other_stops(cpu, bridge, *hart, step.clone())?;
+        }
+
+        // Stopping a hart stops its group.  Each one that was running is
+        // reported as stopped with signal 0, as GDB expects.
+        let mut stopped = vec![];
+        for hart in stop {
+            for h in cpu.group_of(hart) {
+                if !stopped.contains(&h) && !cpu.is_halted(bridge, h)? {
+                    stopped.push(h);
+                }
             }
-            self.gdb_send(&trimmed_features)?;
+            cpu.halt_hart(bridge, hart)?;
+        }
+        for hart in stopped {
+            self.report_stop(cpu, bridge, format!("T00thread:{:x};", hart + 1))?;
         }
         Ok(())
     }
+
+    /// Tell GDB that `hart` stopped, and why.  GDB switches to that thread,
+    /// so we do too.
+    fn gdb_send_stop_reply(
+        &mut self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        hart: usize,
+    ) -> Result<(), GdbServerError> {
+        self.awaiting_stop = false;
+        let reply = self.stop_reply(cpu, bridge, hart)?;
+        events::halted(cpu, bridge, hart);
+        self.report_stop(cpu, bridge, reply)
+    }
+
+    fn stop_reply(
+        &mut self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        hart: usize,
+    ) -> Result<String, GdbServerError> {
+        self.current_hart = hart;
+        self.current_task = None;
+        cpu.prefetch(bridge, hart)?;
+        // Caught syscalls are reported as a SIGTRAP that says which syscall
+        if let Some(num) = self.caught_syscall(cpu, bridge, hart)? {
+            self.target.write().unwrap().last_signal = 5;
+            return Ok(format!("T05syscall_entry:{:x};thread:{:x};", num, hart + 1));
+        }
+        let signal = {
+            let mut target = self.target.write().unwrap();
+            if let Some(reason) = cpu.halt_reason(hart) {
+                target.last_signal = reason.signal();
+            }
+            target.last_signal
+        };
+        // Watchpoints say which address they were watching, so GDB knows
+        // which one it was
+        let watch = match cpu.halt_reason(hart) {
+            Some(HaltReason::Watchpoint(kind, addr)) => {
+                let name = match kind {
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                    _ => "watch",
+                };
+                format!("{}:{:x};", name, addr)
+            }
+            _ => String::new(),
+        };
+        Ok(format!("T{:02x}{}thread:{:x};", signal, watch, hart + 1))
+    }
+
+    /// Send a stop reply.  In non-stop mode it's queued for GDB to collect
+    /// instead, with a notification if nothing else was waiting, and GDB
+    /// keeps waiting on whichever harts are still running.
+    fn report_stop(
+        &mut self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        reply: String,
+    ) -> Result<(), GdbServerError> {
+        if !self.non_stop {
+            return Ok(self.gdb_send(reply.as_bytes())?);
+        }
+        if let Some(notification) = self.stops.push(reply.into_bytes()) {
+            log_gdb!("-> Notifying {}", String::from_utf8_lossy(&notification));
+            self.connection.write_all(&notification)?;
+        }
+        // A hart stopped even if others are still running
+        self.target.write().unwrap().changed();
+        let running = !self.running_harts(cpu, bridge)?.is_empty();
+        self.awaiting_stop = running;
+        self.set_run_state(if running {
+            RunState::Running
+        } else {
+            RunState::Halted
+        });
+        Ok(())
+    }
+
+    /// In non-stop mode every hart that stops is reported on its own, so
+    /// the ones of `harts` that stopped along with `first`, such as the
+    /// rest of its group, are reported as stopped by request
+    fn report_other_stops(
+        &mut self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        first: usize,
+        harts: Vec<usize>,
+    ) -> Result<(), GdbServerError> {
+        if !self.non_stop {
+            return Ok(());
+        }
+        for hart in harts.into_iter().filter(|&h| h != first) {
+            if cpu.is_halted(bridge, hart)? {
+                self.report_stop(cpu, bridge, format!("T00thread:{:x};", hart + 1))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// A hart that stopped on its own.  In non-stop mode harts GDB already
+    /// heard about stay halted while others run, and mustn't be found again.
+    fn poll_halted(&self, cpu: &RiscvCpu, bridge: &Bridge) -> Result<Option<usize>, BridgeError> {
+        if self.non_stop {
+            cpu.poll_newly_halted(bridge)
+        } else {
+            cpu.poll_halted(bridge)
+        }
+    }
+
+    fn running_harts(&self, cpu: &RiscvCpu, bridge: &Bridge) -> Result<Vec<usize>, BridgeError> {
+        let mut running = vec![];
+        for hart in 0..cpu.hart_count() {
+            if !cpu.is_halted(bridge, hart)? {
+                running.push(hart);
+            }
+        }
+        Ok(running)
+    }
+
+    /// See whether a hart stopped while GDB was waiting on it, or whether
+    /// a polled watch fired.  A hart that stopped for something GDB doesn't
+    /// care about is sent on its way, and anything else is reported
+    /// straight away.
+    fn poll_for_stop(&mut self, cpu: &RiscvCpu, bridge: &Bridge) -> Result<(), GdbServerError> {
+        if self.role == Role::Controller {
+            self.answer_doorbell(cpu, bridge)?;
+        }
+        let hart = match self.poll_halted(cpu, bridge)? {
+            Some(hart) => hart,
+            None => match self.poll_watches.check(bridge)? {
+                Some(addr) => {
+                    let hart = self.continue_hart.unwrap_or(self.current_hart);
+                    cpu.halt_on_watch(bridge, hart, addr)?;
+                    hart
+                }
+                None => return Ok(()),
+            },
+        };
+        if !self.wants_stop(cpu, bridge, hart)? {
+            log_gdb!("Letting hart {} carry on after {:?}", hart, cpu.halt_reason(hart));
+            cpu.resume_hart(bridge, hart)?;
+            return Ok(());
+        }
+        announce_halt(cpu, hart, self.target_time(bridge));
+        self.set_run_state(RunState::Halted);
+        self.gdb_send_stop_reply(cpu, bridge, hart)?;
+        self.report_other_stops(cpu, bridge, hart, cpu.group_of(hart))
+    }
+
+    /// Do what was asked for if the firmware rang the doorbell.  GDB can
+    /// only be told in all-stop mode, where console output is allowed while
+    /// it waits for the target to stop.
+    fn answer_doorbell(&mut self, cpu: &RiscvCpu, bridge: &Bridge) -> Result<(), GdbServerError> {
+        let (value, actions) = match self.doorbell {
+            Some(ref doorbell) => match doorbell.check(bridge)? {
+                Some(value) => (value, doorbell.actions.clone()),
+                None => return Ok(()),
+            },
+            None => return Ok(()),
+        };
+        events::emit(TargetEvent::Doorbell { value });
+        for action in actions {
+            match action {
+                DoorbellAction::Log => {
+                    ui_event!(
+                        Event::Doorbell,
+                        "Firmware rang the doorbell with {:#x}",
+                        value
+                    )
+                }
+                DoorbellAction::Gdb if !self.non_stop => {
+                    self.gdb_send_console(&format!("Doorbell rang with {:#x}\n", value))?
+                }
+                DoorbellAction::Gdb => (),
+                DoorbellAction::Dump(path) => {
+                    // Only the harts that were running are let go again
+                    let running = self.running_harts(cpu, bridge)?;
+                    for &hart in &running {
+                        cpu.halt_hart(bridge, hart)?;
+                    }
+                    let saved = coredump::save(cpu, bridge, cpu.memory_map(), &path, &[]);
+                    for &hart in &running {
+                        cpu.resume_single(bridge, hart)?;
+                    }
+                    match saved {
+                        Ok(bytes) => log_adapter!(
+                            "Saved {} bytes of memory to {} for the doorbell",
+                            bytes,
+                            path
+                        ),
+                        Err(e) => ui_error!("Couldn't save {} for the doorbell: {}", path, e),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Tidy up after [`process`](Self::process) failed with `e`.  When GDB
+    /// went away everything that was borrowed from the target is put back
+    /// and it's let run, as for `D`.  When the bridge
+    /// failed the target can't be reached to undo anything, and after any
+    /// other error the breakpoints are taken out but the harts are left as
+    /// they are, so whatever went wrong can still be looked at.
+    pub fn end_session(&mut self, cpu: &RiscvCpu, bridge: &Bridge, e: &GdbServerError) {
+        if e.is_bridge_failure() {
+            ui_error!("Leaving the target as it is, since the bridge failed");
+            return;
+        }
+        let clients = self.clients.clone();
+        let _turn = clients.take_turn();
+        self.release_target(cpu, bridge, e.is_disconnect());
+    }
+
+    /// Undo everything this client did to the target, and resume the CPU
+    /// if `resume`.  This is called for `D` and again when the connection
+    /// drops, which only resumes the CPU the second time.  An observer did
+    /// nothing, and leaves the target to the controller.
+    fn release_target(&mut self, cpu: &RiscvCpu, bridge: &Bridge, resume: bool) {
+        if self.role == Role::Observer {
+            return;
+        }
+        if let Err(e) = self.remove_all_breakpoints(cpu, bridge) {
+            ui_error!("Couldn't remove breakpoints: {}", e);
+        }
+        if let Some(scratch) = self.scratch.as_mut() {
+            if let Err(e) = scratch.release_all(bridge) {
+                ui_error!("Couldn't restore scratch RAM: {}", e);
+            }
+        }
+        self.awaiting_stop = false;
+        if !resume {
+            return;
+        }
+        match cpu.resume(bridge) {
+            Ok(()) => self.set_run_state(RunState::Running),
+            Err(e) => ui_error!("Couldn't resume the CPU: {:?}", e),
+        }
+    }
+
+    /// Take out every breakpoint and watchpoint, so nothing stops the
+    /// target once nobody is listening.  Software breakpoints are patched
+    /// in through a hart, so a running target is halted for that first.
+    fn remove_all_breakpoints(&mut self, cpu: &RiscvCpu, bridge: &Bridge) -> Result<(), MmuError> {
+        if !self.patched.is_empty() && self.target.read().unwrap().run_state != RunState::Halted {
+            cpu.halt(bridge)?;
+            self.set_run_state(RunState::Halted);
+        }
+        while let Some(&(addr, _)) = self.patched.last() {
+            self.remove_soft_breakpoint(cpu, bridge, addr)?;
+        }
+        self.fallbacks.clear();
+        for addr in cpu.hw_breakpoints_in_use() {
+            cpu.remove_hw_breakpoint(bridge, addr)?;
+        }
+        for trigger in cpu.triggers_in_use() {
+            cpu.remove_trigger(bridge, trigger)?;
+        }
+        self.poll_watches.clear();
+        Ok(())
+    }
+
+    /// Run a `monitor` command and return the text to show the user.
+    /// Errors are reported as text rather than tearing down the session.
+    fn process_monitor(&mut self, cpu: &RiscvCpu, bridge: &Bridge, cmd: &str) -> String {
+        let args: Vec<&str> = cmd.split_whitespace().collect();
+        let (name, args) = match args.split_first() {
+            Some((name, args)) => (*name, args),
+            None => return String::new(),
+        };
+        if self.role == Role::Observer && !observer_may_run(name, args) {
+            return format!("An observer can't use monitor {}\n", name);
+        }
+        match name {
+            "clockspeed" => match clock::monitor_clockspeed(cpu, bridge, args) {
+                Ok(s) => s,
+                Err(e) => format!("clockspeed failed: {}\n", e),
+            },
+            "latency" => self.monitor_latency(cpu, bridge, args),
+            "timings" => self.monitor_timings(args),
+            "encoding" => self.monitor_encoding(args),
+            "save-session" => self.monitor_save_session(args),
+            "step-record" => self.monitor_step_record(cpu, bridge, args),
+            "catch" => self.monitor_catch(cpu, bridge, args),
+            "mmu" => self.monitor_mmu(cpu, bridge, args),
+            "power" => self.monitor_power(cpu, args),
+            "ps" => self.monitor_ps(cpu, bridge),
+            "read" => self.monitor_read(cpu, bridge, args),
+            "write" => self.monitor_write(cpu, bridge, args),
+            "dmesg" => self.monitor_dmesg(cpu, bridge),
+            "kthreads" => self.monitor_kthreads(args),
+            "cache" => self.monitor_cache(cpu, args),
+            "breakpoints" => self.monitor_breakpoints(cpu, bridge),
+            "coredump" => self.monitor_coredump(cpu, bridge, args),
+            "csr-dump" => self.monitor_csr_dump(bridge, args),
+            "csr-restore" => self.monitor_csr_restore(bridge, args),
+            "capabilities" => format!("{}\n", capabilities::report(cpu, bridge)),
+            "scratch" => self.monitor_scratch(bridge, args),
+            "watch" => self.monitor_watch(cpu, bridge, args),
+            "uptime" => self.monitor_uptime(bridge),
+            "ecc" => self.monitor_ecc(bridge, args),
+            "wb" => self.monitor_wb(cpu, bridge, args),
+            "ddr" => self.monitor_ddr(cpu, bridge, args),
+            "bootmode" => self.monitor_bootmode(cpu, bridge, args),
+            "context" => self.monitor_context(cpu, bridge, args),
+            "call" => self.monitor_call(cpu, bridge, args),
+            "set" => monitor_set(args),
+            unknown => format!(
+                "Unrecognized monitor command: {}\nCommands: {}\n",
+                unknown,
+                MONITOR_COMMANDS.join(" ")
+            ),
+        }
+    }
+
+    /// Report how long packets take to handle, and time a burst of bridge
+    /// round trips, so slow stepping can be blamed on the right layer.
+    fn monitor_latency(&mut self, cpu: &RiscvCpu, bridge: &Bridge, args: &[&str]) -> String {
+        let count = match args.first().map(|a| a.parse::<u32>()) {
+            None => 100,
+            Some(Ok(n)) if n > 0 => n,
+            Some(_) => return "usage: latency [count]\n".to_owned(),
+        };
+        let mut bridge_latency = LatencyStats::new();
+        for _ in 0..count {
+            let start = Instant::now();
+            if let Err(e) = cpu.is_halted(bridge, 0) {
+                return format!("bridge error while measuring latency: {:?}\n", e);
+            }
+            bridge_latency.record(start.elapsed());
+        }
+        format!(
+            "Packet handling: {}\nBridge round trip: {}\n",
+            self.packet_latency, bridge_latency
+        )
+    }
+
+    /// Show where the time went for each kind of packet, split into
+    /// parsing it, the adapter's own work, the bridge, and waiting on the
+    /// target: `timings [reset]`
+    fn monitor_timings(&mut self, args: &[&str]) -> String {
+        match args {
+            [] => self.timings.to_string(),
+            ["reset"] => {
+                self.timings.clear();
+                "Timings cleared\n".to_owned()
+            }
+            _ => "usage: timings [reset]\n".to_owned(),
+        }
+    }
+
+    /// Show or change which encoder features are used for this session:
+    /// `encoding [rle|escape] [on|off]`
+    fn monitor_encoding(&mut self, args: &[&str]) -> String {
+        let enable = match args.get(1) {
+            Some(&"on") => Some(true),
+            Some(&"off") => Some(false),
+            None => None,
+            Some(_) => return "usage: encoding [rle|escape] [on|off]\n".to_owned(),
+        };
+        match (args.first(), enable) {
+            (None, _) => (),
+            (Some(&"rle"), Some(e)) => self.use_rle = e,
+            (Some(&"escape"), Some(e)) => self.use_escaping = e,
+            (Some(&"rle"), None) | (Some(&"escape"), None) => (),
+            _ => return "usage: encoding [rle|escape] [on|off]\n".to_owned(),
+        }
+        let on_off = |b| if b { "on" } else { "off" };
+        format!(
+            "Run-length encoding: {}\nEscaping: {}\n",
+            on_off(self.use_rle),
+            on_off(self.use_escaping)
+        )
+    }
+
+    /// Choose which traps stop the target: `catch [off|<cause>...]`.  Causes
+    /// can be given by name or by `mcause` number.
+    fn monitor_catch(&mut self, cpu: &RiscvCpu, bridge: &Bridge, args: &[&str]) -> String {
+        let cause_name = |cause: u32| {
+            TRAP_CAUSES
+                .iter()
+                .find(|(c, _)| *c == cause)
+                .map(|(_, name)| name.to_string())
+                .unwrap_or_else(|| format!("{}", cause))
+        };
+        if args == ["off"] {
+            self.catch_causes.clear();
+        } else if !args.is_empty() {
+            let mut causes = vec![];
+            for arg in args {
+                let cause = TRAP_CAUSES
+                    .iter()
+                    .find(|(_, name)| name == arg)
+                    .map(|(c, _)| *c)
+                    .or_else(|| arg.parse().ok());
+                match cause {
+                    Some(c) => causes.push(c),
+                    None => {
+                        let names: Vec<&str> = TRAP_CAUSES.iter().map(|(_, n)| *n).collect();
+                        return format!("usage: catch [off|<cause>...], where a cause is an mcause number or one of:\n  {}\n", names.join(" "));
+                    }
+                }
+            }
+            self.catch_causes = causes;
+        }
+        if let Err(e) = self.update_trap_catching(cpu, bridge) {
+            return format!("couldn't set up the trap breakpoint: {:?}\n", e);
+        }
+        if self.catch_causes.is_empty() {
+            "Not catching any traps\n".to_owned()
+        } else {
+            let names: Vec<String> = self.catch_causes.iter().map(|&c| cause_name(c)).collect();
+            format!("Catching: {}\n", names.join(" "))
+        }
+    }
+
+    /// Turn address translation on or off, or translate one address:
+    /// `mmu [on|off|<address>]`
+    fn monitor_mmu(&mut self, cpu: &RiscvCpu, bridge: &Bridge, args: &[&str]) -> String {
+        let satp = match cpu.read_csr(bridge, self.current_hart, mmu::CSR_SATP) {
+            Ok(satp) => satp,
+            Err(e) => return format!("couldn't read satp: {:?}\n", e),
+        };
+        match args {
+            [] => (),
+            ["on"] => self.translate_addresses = true,
+            ["off"] => self.translate_addresses = false,
+            [addr] => {
+                let va = match parse_u32(addr) {
+                    Ok(va) => va,
+                    Err(_) => return "usage: mmu [on|off|<address>]\n".to_owned(),
+                };
+                return match mmu::translate(bridge, satp, va) {
+                    Ok(pa) => format!("{:08x} -> {:08x}\n", va, pa),
+                    Err(e) => format!("{}\n", e),
+                };
+            }
+            _ => return "usage: mmu [on|off|<address>]\n".to_owned(),
+        }
+        format!(
+            "Address translation: {}\nsatp: {:08x} (paging {})\n",
+            if self.translate_addresses {
+                "on"
+            } else {
+                "off"
+            },
+            satp,
+            if mmu::is_enabled(satp) { "on" } else { "off" }
+        )
+    }
+
+    /// Read memory as GDB addresses it.  With translation on and paging
+    /// enabled on the hart, the address is virtual and the page tables are
+    /// walked to find it.  Otherwise the hart does the loads itself.
+    fn read_memory(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        addr: u32,
+        len: u32,
+    ) -> Result<Vec<u8>, MmuError> {
+        let hart = self.current_hart;
+        if self.translate_addresses {
+            let satp = cpu.read_csr(bridge, hart, mmu::CSR_SATP)?;
+            if mmu::is_enabled(satp) {
+                return mmu::read_virtual(bridge, satp, addr, len);
+            }
+        }
+        Ok(cpu.read_memory_range(bridge, hart, addr, len)?)
+    }
+
+    /// How many comparators the debug unit has.  They're probed for the
+    /// first time there's a word of scratch to do it in; stepping anywhere
+    /// else would run the firmware's code.
+    fn hw_breakpoint_count(
+        &mut self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+    ) -> Result<u32, GdbServerError> {
+        if let (false, Some(scratch)) = (cpu.hw_breakpoints_probed(), self.scratch.as_mut()) {
+            match scratch.alloc(bridge, 4) {
+                Ok(nop_at) => {
+                    let probed = cpu.probe_hw_breakpoints(bridge, nop_at);
+                    let freed = scratch.free(bridge, nop_at);
+                    probed?;
+                    if let Err(ScratchError::BridgeError(e)) = freed {
+                        return Err(e.into());
+                    }
+                }
+                Err(ScratchError::BridgeError(e)) => return Err(e.into()),
+                Err(e) => log_gdb!(@Verbose, "not probing for comparators: {}", e),
+            }
+        }
+        Ok(cpu.hw_breakpoint_count())
+    }
+
+    /// Put in a breakpoint or watchpoint for `Z`, returning the reply:
+    /// `OK`, or an error saying why it couldn't go in
+    fn add_breakpoint(
+        &mut self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        bptype: BreakPointType,
+        addr: u32,
+        len: u32,
+    ) -> Result<String, GdbServerError> {
+        let patched = |result: Result<Patch, MmuError>| match result {
+            Ok(Patch::Patched) => Ok(None),
+            Ok(Patch::BadKind) => Ok(Some("E16".to_owned())),
+            Ok(Patch::Unwritable) => Ok(Some(unwritable_breakpoint(addr))),
+            Err(MmuError::PageFault(_)) => Ok(Some("E0e".to_owned())),
+            Err(MmuError::BridgeError(e)) => Err(GdbServerError::from(e)),
+        };
+        match bptype {
+            BreakPointType::BreakSoft => {
+                if let Some(error) = patched(self.insert_soft_breakpoint(cpu, bridge, addr, len))? {
+                    return Ok(error);
+                }
+            }
+            BreakPointType::BreakHard => {
+                let available = self.hw_breakpoint_count(cpu, bridge)?.saturating_sub(1);
+                let triggers = cpu.trigger_count(bridge)?;
+                if cpu.add_hw_breakpoint(bridge, addr)? {
+                    log_gdb!(
+                        @Verbose,
+                        "hardware breakpoint at {:08x}, {} of {} comparators and {} of {} triggers in use",
+                        addr,
+                        cpu.hw_breakpoints_in_use().len(),
+                        available,
+                        cpu.triggers_in_use().len(),
+                        triggers
+                    );
+                } else if self.may_fall_back(addr, len) {
+                    if let Some(error) =
+                        patched(self.insert_soft_breakpoint(cpu, bridge, addr, len))?
+                    {
+                        return Ok(error);
+                    }
+                    log_gdb!(
+                        @Verbose,
+                        "No comparator free for {:08x}, patched in a software breakpoint instead",
+                        addr
+                    );
+                    self.fallbacks.push(addr);
+                } else {
+                    return Ok(format!(
+                        "E.no hardware breakpoints free, the CPU has {} comparators and {} triggers for GDB",
+                        available, triggers
+                    ));
+                }
+            }
+            _ => {
+                let trigger = Trigger {
+                    kind: bptype.watch_kind(),
+                    addr,
+                    len,
+                };
+                if !cpu.add_trigger(bridge, trigger)? {
+                    if bptype != BreakPointType::WatchWrite {
+                        return Ok(format!(
+                            "E.can't watch {} bytes at {:08x}: {} of {} triggers in use, and each covers a byte or an aligned power of two",
+                            len,
+                            addr,
+                            cpu.triggers_in_use().len(),
+                            cpu.trigger_count(bridge)?
+                        ));
+                    }
+                    // Out of triggers, but a write can still be caught by
+                    // looking at the memory while the target runs
+                    self.poll_watches.add_gdb(bridge, addr, len)?;
+                    log_gdb!(
+                        @Verbose,
+                        "No trigger free for {} bytes at {:08x}, polling it instead",
+                        len,
+                        addr
+                    );
+                }
+            }
+        }
+        self.remember_breakpoint(bptype, addr, len);
+        Ok("OK".to_owned())
+    }
+
+    /// Put in the breakpoints and watchpoints of a restored session, which
+    /// GDB expects to be there already.  A running target is halted while
+    /// they go in, and resumed afterwards whatever happened.  Ones that
+    /// can't be put back are reported and dropped, since GDB has no way to
+    /// hear about them.  If the bridge fails, the ones it didn't get to
+    /// are kept.
+    pub fn install_breakpoints(
+        &mut self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+    ) -> Result<(), GdbServerError> {
+        if self.breakpoints.is_empty() {
+            return Ok(());
+        }
+        let clients = self.clients.clone();
+        let _turn = clients.take_turn();
+        let was_running = self.target.read().unwrap().run_state != RunState::Halted;
+        if was_running {
+            cpu.halt(bridge)?;
+        }
+        let mut pending = std::mem::take(&mut self.breakpoints).into_iter();
+        let mut failure = None;
+        for bp in pending.by_ref() {
+            let result = BreakPointType::try_from(bp.kind)
+                .and_then(|bptype| self.add_breakpoint(cpu, bridge, bptype, bp.addr, bp.len));
+            let why = match result {
+                Ok(ref reply) if reply == "OK" => continue,
+                Ok(reply) => reply.trim_start_matches("E.").to_owned(),
+                Err(e) if e.is_bridge_failure() => {
+                    self.breakpoints.push(bp);
+                    failure = Some(e);
+                    break;
+                }
+                Err(e) => format!("{:?}", e),
+            };
+            ui_error!("Couldn't restore the breakpoint at {:08x}: {}", bp.addr, why);
+        }
+        self.breakpoints.extend(pending);
+        if was_running {
+            if let Err(e) = cpu.resume(bridge) {
+                failure.get_or_insert(e.into());
+            }
+        }
+        failure.map_or(Ok(()), Err)
+    }
+
+    /// Whether a hardware breakpoint at `addr` may be patched in as a
+    /// software one, which it may if all of it is in a fallback region
+    fn may_fall_back(&self, addr: u32, len: u32) -> bool {
+        within_regions(&self.fallback_regions, addr, len)
+    }
+
+    /// Record a breakpoint or watchpoint so that it's saved with the session
+    fn remember_breakpoint(&mut self, bptype: BreakPointType, addr: u32, len: u32) {
+        let bp = Breakpoint { kind: bptype as u32, addr, len };
+        if !self.breakpoints.contains(&bp) {
+            self.breakpoints.push(bp);
+        }
+    }
+
+    fn forget_breakpoint(&mut self, bptype: BreakPointType, addr: u32, len: u32) {
+        let bp = Breakpoint { kind: bptype as u32, addr, len };
+        self.breakpoints.retain(|b| *b != bp);
+    }
+
+    /// Patch a break instruction over the one at `addr`, keeping the
+    /// original so it can be put back.  The `Z0` kind is the length of the
+    /// instruction being replaced, so compressed code gets `c.ebreak` and
+    /// the instruction after it is left alone.  The patch is read back,
+    /// since ROM and flash take the write without complaint and keep what
+    /// they had.
+    fn insert_soft_breakpoint(
+        &mut self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        addr: u32,
+        kind: u32,
+    ) -> Result<Patch, MmuError> {
+        let ebreak = match break_instruction(kind) {
+            Some(ebreak) => ebreak,
+            None => return Ok(Patch::BadKind),
+        };
+        if self.patched.iter().any(|(a, _)| *a == addr) {
+            return Ok(Patch::Patched);
+        }
+        let original = self.read_memory(cpu, bridge, addr, kind)?;
+        self.write_memory(cpu, bridge, addr, ebreak)?;
+        if self.read_memory(cpu, bridge, addr, kind)? != ebreak {
+            // Whatever did stick mustn't be left there
+            self.write_memory(cpu, bridge, addr, &original)?;
+            return Ok(Patch::Unwritable);
+        }
+        self.patched.push((addr, original));
+        Ok(Patch::Patched)
+    }
+
+    /// Put back the instruction a software breakpoint replaced.  Removing
+    /// one that isn't there is fine, since GDB may do that after a restart.
+    fn remove_soft_breakpoint(
+        &mut self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        addr: u32,
+    ) -> Result<(), MmuError> {
+        if let Some(idx) = self.patched.iter().position(|(a, _)| *a == addr) {
+            let (_, original) = self.patched.remove(idx);
+            self.write_memory(cpu, bridge, addr, &original)?;
+        }
+        Ok(())
+    }
+
+    /// Write memory through the selected hart, translating the address
+    /// of each page first if GDB is using virtual addresses
+    fn write_memory(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<(), MmuError> {
+        let hart = self.current_hart;
+        let satp = if self.translate_addresses {
+            cpu.read_csr(bridge, hart, mmu::CSR_SATP)?
+        } else {
+            0
+        };
+        let mut offset = 0;
+        while offset < data.len() {
+            let va = addr.wrapping_add(offset as u32);
+            let left = (mmu::PAGE_SIZE - va % mmu::PAGE_SIZE) as usize;
+            let chunk = &data[offset..data.len().min(offset + left)];
+            let pa = mmu::translate(bridge, satp, va)?;
+            cpu.write_memory(bridge, hart, pa, chunk)?;
+            offset += chunk.len();
+        }
+        Ok(())
+    }
+
+    /// Pass a `vFile` request to the file agent, and send GDB the result.
+    /// Without an agent the packets aren't supported at all.
+    fn file_request(
+        &mut self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        request: FileRequest,
+    ) -> Result<(), GdbServerError> {
+        let agent = match self.file_agent {
+            Some(ref agent) => agent,
+            None => return Ok(self.gdb_send(b"")?),
+        };
+        let result = match request {
+            FileRequest::SetFs => Ok((0, vec![])),
+            FileRequest::Open(path, flags, mode) => agent
+                .open(cpu, bridge, &path, flags, mode)
+                .map(|fd| (fd, vec![])),
+            FileRequest::Close(fd) => agent.close(cpu, bridge, fd).map(|_| (0, vec![])),
+            FileRequest::Pread(fd, count, offset) => agent
+                .pread(cpu, bridge, fd, offset, count)
+                .map(|data| (data.len() as u32, data)),
+            FileRequest::Pwrite(fd, offset, data) => agent
+                .pwrite(cpu, bridge, fd, offset, &data)
+                .map(|count| (count, vec![])),
+            FileRequest::Unlink(path) => agent.unlink(cpu, bridge, &path).map(|_| (0, vec![])),
+        };
+        match result {
+            Ok((value, data)) if data.is_empty() => {
+                self.gdb_send(format!("F{:x}", value).as_bytes())?
+            }
+            Ok((value, data)) => {
+                // The attachment is binary, so it's always escaped
+                let mut reply = format!("F{:x};", value).into_bytes();
+                reply.extend_from_slice(&rsp::escape(&data));
+                self.gdb_send(&reply)?
+            }
+            Err(FileError::Errno(errno)) => self.gdb_send(format!("F-1,{:x}", errno).as_bytes())?,
+            Err(FileError::BridgeError(e)) => return Err(e.into()),
+        }
+        Ok(())
+    }
+
+    /// `satp` of the selected hart, which kernel addresses are translated with
+    fn kernel_satp(&self, cpu: &RiscvCpu, bridge: &Bridge) -> Result<u32, BridgeError> {
+        cpu.read_csr(bridge, self.current_hart, mmu::CSR_SATP)
+    }
+
+    /// Kernel tasks to show as threads, leaving out any that are running
+    /// on a hart, since they're already shown as that hart.  Anything that
+    /// goes wrong just means no tasks are shown.
+    fn kernel_tasks(&self, cpu: &RiscvCpu, bridge: &Bridge) -> Vec<Task> {
+        let kernel = match self.kernel {
+            Some(ref k) if self.kernel_threads => k,
+            _ => return vec![],
+        };
+        let tasks = self
+            .kernel_satp(cpu, bridge)
+            .map_err(|e| format!("{:?}", e))
+            .and_then(|satp| kernel.tasks(bridge, satp).map_err(|e| e.to_string()));
+        let tasks = match tasks {
+            Ok(tasks) => tasks,
+            Err(e) => {
+                log_gdb!(@Verbose, "Couldn't list kernel tasks: {}", e);
+                return vec![];
+            }
+        };
+        // The kernel keeps the current task in tp
+        let running: Vec<u32> = (0..cpu.hart_count())
+            .filter_map(|hart| cpu.read_register(bridge, hart, 4).ok())
+            .collect();
+        tasks
+            .into_iter()
+            .filter(|t| !running.contains(&t.addr))
+            .collect()
+    }
+
+    /// The saved registers of the selected kernel task
+    fn task_registers(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+    ) -> Result<Vec<Option<u32>>, GdbServerError> {
+        let (kernel, task) = match (&self.kernel, &self.current_task) {
+            (Some(kernel), Some(task)) => (kernel, task),
+            _ => return Ok(vec![None; 33]),
+        };
+        let satp = self.kernel_satp(cpu, bridge)?;
+        match kernel.saved_registers(bridge, satp, task) {
+            Ok(registers) => Ok(registers),
+            Err(e) => {
+                log_gdb!(@Verbose, "Couldn't read registers of task {}: {}", task.pid, e);
+                Ok(vec![None; 33])
+            }
+        }
+    }
+
+    /// List the kernel's tasks: `ps`
+    fn monitor_ps(&self, cpu: &RiscvCpu, bridge: &Bridge) -> String {
+        let kernel = match self.kernel {
+            Some(ref k) => k,
+            None => return "no kernel symbols; start with --kernel-symbols\n".to_owned(),
+        };
+        let tasks = match self.kernel_satp(cpu, bridge) {
+            Ok(satp) => kernel.tasks(bridge, satp),
+            Err(e) => return format!("couldn't read satp: {:?}\n", e),
+        };
+        match tasks {
+            Ok(tasks) => {
+                let mut out = format!("{:>6}  {:8}  {}\n", "PID", "TASK", "COMMAND");
+                for task in tasks {
+                    out.push_str(&format!(
+                        "{:>6}  {:08x}  {}\n",
+                        task.pid, task.addr, task.comm
+                    ));
+                }
+                out
+            }
+            Err(e) => format!("couldn't walk the task list: {}\n", e),
+        }
+    }
+
+    /// Print the kernel log buffer: `dmesg`
+    fn monitor_dmesg(&self, cpu: &RiscvCpu, bridge: &Bridge) -> String {
+        let kernel = match self.kernel {
+            Some(ref k) => k,
+            None => return "no kernel symbols; start with --kernel-symbols\n".to_owned(),
+        };
+        let log = match self.kernel_satp(cpu, bridge) {
+            Ok(satp) => kernel.dmesg(bridge, satp),
+            Err(e) => return format!("couldn't read satp: {:?}\n", e),
+        };
+        match log {
+            Ok(log) => log,
+            Err(e) => format!("couldn't read the kernel log: {}\n", e),
+        }
+    }
+
+    /// Turn the register and memory caches on or off, flush them, or
+    /// report how well they're doing: `cache [on|off|stats|flush]`
+    fn monitor_cache(&mut self, cpu: &RiscvCpu, args: &[&str]) -> String {
+        match args {
+            [] | ["stats"] => (),
+            ["on"] => cpu.set_caching(true),
+            ["off"] => {
+                cpu.set_caching(false);
+                cpu.flush_cache();
+            }
+            ["flush"] => {
+                cpu.flush_cache();
+                return "Register and memory caches flushed\n".to_owned();
+            }
+            _ => return "usage: cache [on|off|stats|flush]\n".to_owned(),
+        }
+        let stats = cpu.cache_stats();
+        format!(
+            "Caching: {}\nRegisters: {} hits, {} misses\nMemory: {} hits, {} misses, {} bytes prefetched\n",
+            if cpu.caching() { "on" } else { "off" },
+            stats.register_hits,
+            stats.register_misses,
+            stats.memory_hits,
+            stats.memory_misses,
+            stats.prefetched_bytes
+        )
+    }
+
+    /// Show how many hardware breakpoints the CPU has and where they are
+    fn monitor_breakpoints(&mut self, cpu: &RiscvCpu, bridge: &Bridge) -> String {
+        let count = self.hw_breakpoint_count(cpu, bridge);
+        let (count, triggers) = match (count, cpu.trigger_count(bridge)) {
+            (Ok(count), Ok(triggers)) => (count, triggers),
+            (Err(e), _) => return format!("couldn't probe for hardware breakpoints: {:?}\n", e),
+            (_, Err(e)) => return format!("couldn't probe for hardware breakpoints: {:?}\n", e),
+        };
+        let in_use = cpu.hw_breakpoints_in_use();
+        let mut out = format!(
+            "Hardware breakpoints: {} available to GDB, {} in use\n",
+            count.saturating_sub(1),
+            in_use.len()
+        );
+        if count > 0 {
+            out.push_str("One more is kept for catching traps\n");
+        } else if self.scratch.is_none() {
+            out.push_str("Comparators are only probed for with a scratch area\n");
+        }
+        for addr in in_use {
+            out.push_str(&format!("  {:08x}\n", addr));
+        }
+        out.push_str(&format!(
+            "Software breakpoints: {} patched in\n",
+            self.patched.len()
+        ));
+        for (addr, original) in &self.patched {
+            let note = if self.fallbacks.contains(addr) {
+                ", asked for as hardware"
+            } else {
+                ""
+            };
+            out.push_str(&format!(
+                "  {:08x} {} bytes{}\n",
+                addr,
+                original.len(),
+                note
+            ));
+        }
+        if !self.fallback_regions.is_empty() {
+            let names: Vec<&str> = self
+                .fallback_regions
+                .iter()
+                .map(|r| r.name.as_str())
+                .collect();
+            out.push_str(&format!(
+                "Hardware breakpoints fall back to software in {}\n",
+                names.join(", ")
+            ));
+        }
+        let set = cpu.triggers_in_use();
+        out.push_str(&format!("Triggers: {}, {} in use\n", triggers, set.len()));
+        for trigger in set {
+            let kind = match trigger.kind {
+                WatchKind::Execute => "execute",
+                WatchKind::Write => "write",
+                WatchKind::Read => "read",
+                WatchKind::Access => "access",
+            };
+            out.push_str(&format!(
+                "  {:08x} {:>7}, {} bytes\n",
+                trigger.addr, kind, trigger.len
+            ));
+        }
+        out
+    }
+
+    /// List the polled watches, or set or clear one by hand:
+    /// `watch [<address> [mask <mask>] [value <value>] | clear [n]]`.  The
+    /// target halts when the masked word at the address changes, or with
+    /// `value`, when it becomes that value.  This works on any target, but
+    /// only sees the value each time it's sampled while GDB waits for the
+    /// target to stop.
+    fn monitor_watch(&mut self, cpu: &RiscvCpu, bridge: &Bridge, args: &[&str]) -> String {
+        let usage = "usage: watch [<address> [mask <mask>] [value <value>] | clear [n]]\n";
+        match args {
+            [] => {
+                if self.poll_watches.is_empty() {
+                    return "No polled watches\n".to_owned();
+                }
+                let mut out = String::new();
+                for (idx, w) in self.poll_watches.iter().enumerate() {
+                    out.push_str(&format!("  {}: {}\n", idx, w));
+                }
+                return out;
+            }
+            ["clear"] => {
+                self.poll_watches.clear();
+                return "Cleared all polled watches\n".to_owned();
+            }
+            ["clear", n] => {
+                return match n.parse::<usize>() {
+                    Ok(idx) => {
+                        if self.poll_watches.remove(idx) {
+                            format!("Cleared watch {}\n", idx)
+                        } else {
+                            format!("no watch {}\n", idx)
+                        }
+                    }
+                    Err(_) => format!("no watch {}\n", n),
+                };
+            }
+            _ => (),
+        }
+        // The address runs up to the first keyword
+        let split = args
+            .iter()
+            .position(|a| *a == "mask" || *a == "value")
+            .unwrap_or(args.len());
+        let mut mask = 0xffff_ffff;
+        let mut condition = Condition::Changed;
+        let mut rest = &args[split..];
+        while let [keyword, tail @ ..] = rest {
+            let end = tail
+                .iter()
+                .position(|a| *a == "mask" || *a == "value")
+                .unwrap_or(tail.len());
+            if end == 0 {
+                return usage.to_owned();
+            }
+            let value = match self.eval(cpu, bridge, &tail[..end].join(" ")).and_then(expr::word) {
+                Ok(value) => value,
+                Err(e) => return format!("{}\n", e),
+            };
+            if *keyword == "mask" {
+                mask = value;
+            } else {
+                condition = Condition::Matches(value);
+            }
+            rest = &tail[end..];
+        }
+        if split == 0 {
+            return usage.to_owned();
+        }
+        let addr = match self.eval(cpu, bridge, &args[..split].join(" ")).and_then(expr::word) {
+            Ok(addr) => addr,
+            Err(e) => return format!("{}\n", e),
+        };
+        match self.poll_watches.add(bridge, addr, mask, condition) {
+            Ok(()) => format!("Polling {:08x} while the target runs\n", addr & !3),
+            Err(e) => format!("couldn't read {:08x}: {:?}\n", addr, e),
+        }
+    }
+
+    /// Show the scratch area and what's allocated from it, or allocate and
+    /// free by hand: `scratch [alloc <len>|free <addr>]`
+    fn monitor_scratch(&mut self, bridge: &Bridge, args: &[&str]) -> String {
+        let scratch = match self.scratch.as_mut() {
+            Some(s) => s,
+            None => return format!("{}\n", ScratchError::NoScratch),
+        };
+        let usage = "usage: scratch [alloc <len>|free <addr>]\n";
+        match args {
+            [] => (),
+            ["alloc", len] => match parse_u32(len).map(|len| scratch.alloc(bridge, len)) {
+                Ok(Ok(addr)) => return format!("Allocated {:08x}\n", addr),
+                Ok(Err(e)) => return format!("{}\n", e),
+                Err(_) => return usage.to_owned(),
+            },
+            ["free", addr] => match parse_u32(addr).map(|addr| scratch.free(bridge, addr)) {
+                Ok(Ok(())) => return "Freed\n".to_owned(),
+                Ok(Err(e)) => return format!("{}\n", e),
+                Err(_) => return usage.to_owned(),
+            },
+            _ => return usage.to_owned(),
+        }
+        let (base, size) = scratch.region();
+        let in_use = scratch.in_use();
+        let mut out = format!(
+            "Scratch: {:08x}-{:08x}, {} of {} bytes in use\n",
+            base,
+            base + size,
+            in_use.iter().map(|(_, len)| len).sum::<u32>(),
+            size
+        );
+        for (addr, len) in in_use {
+            out.push_str(&format!("  {:08x} {} bytes\n", addr, len));
+        }
+        out
+    }
+
+    /// Read a CSR by its name in csr.csv, or a word by its address:
+    /// `read <register|address>`.  The address can be an expression, such
+    /// as `read main_ram_base + 0x100`.
+    fn monitor_read(&mut self, cpu: &RiscvCpu, bridge: &Bridge, args: &[&str]) -> String {
+        if args.is_empty() {
+            return "usage: read <register|address>\n".to_owned();
+        }
+        let text = args.join(" ");
+        if let Some(map) = self.csr_register_map(&text) {
+            return match map.read_register(bridge, &text) {
+                Ok(value) => format!("{} = 0x{:x}\n", text, value),
+                Err(e) => format!("{}\n", e),
+            };
+        }
+        let addr = match self.eval(cpu, bridge, &text).and_then(expr::word) {
+            Ok(addr) => addr,
+            Err(e) => return format!("{}\n", e),
+        };
+        match bridge.peek(addr) {
+            Ok(value) => format!("{:08x}: 0x{:08x}\n", addr, value),
+            Err(e) => format!("couldn't read {:08x}: {:?}\n", addr, e),
+        }
+    }
+
+    /// Write a CSR by its name in csr.csv, or a word by its address:
+    /// `write <register|address> <value>`.  Both can be expressions, but
+    /// the address has to be written without spaces.
+    fn monitor_write(&mut self, cpu: &RiscvCpu, bridge: &Bridge, args: &[&str]) -> String {
+        let (name, value) = match args {
+            [name, value @ ..] if !value.is_empty() => (*name, value.join(" ")),
+            _ => return "usage: write <register|address> <value>\n".to_owned(),
+        };
+        let value = match self.eval(cpu, bridge, &value) {
+            Ok(value) => value,
+            Err(e) => return format!("{}\n", e),
+        };
+        if let Some(map) = self.csr_register_map(name) {
+            return match map.write_register(bridge, name, value) {
+                Ok(()) => format!("{} = 0x{:x}\n", name, value),
+                Err(e) => format!("{}\n", e),
+            };
+        }
+        let (addr, value) = match self.eval(cpu, bridge, name).and_then(expr::word) {
+            Ok(addr) => match expr::word(value) {
+                Ok(value) => (addr, value),
+                Err(e) => return format!("{}\n", e),
+            },
+            Err(e) => return format!("{}\n", e),
+        };
+        match bridge.poke(addr, value) {
+            Ok(()) => format!("{:08x}: 0x{:08x}\n", addr, value),
+            Err(e) => format!("couldn't write {:08x}: {:?}\n", addr, e),
+        }
+    }
+
+    /// Raw bus cycles, whatever the CPU would see at the same address:
+    /// `wb read <addr> [count]` and `wb write <addr> <value>...`.  Values
+    /// go to consecutive words, and each argument can be an expression
+    /// written without spaces.
+    fn monitor_wb(&mut self, cpu: &RiscvCpu, bridge: &Bridge, args: &[&str]) -> String {
+        const USAGE: &str = "usage: wb read <addr> [count] | wb write <addr> <value>...\n";
+        let (op, addr, rest) = match args {
+            [op, addr, rest @ ..] => (*op, *addr, rest),
+            _ => return USAGE.to_owned(),
+        };
+        let mut numbers = vec![];
+        for text in std::iter::once(&addr).chain(rest) {
+            match self.eval(cpu, bridge, text).and_then(expr::word) {
+                Ok(n) => numbers.push(n),
+                Err(e) => return format!("{}: {}\n", text, e),
+            }
+        }
+        let addr = numbers[0];
+        if addr & 3 != 0 {
+            return format!("{:08x} isn't word aligned\n", addr);
+        }
+        match (op, &numbers[1..]) {
+            ("read", []) | ("read", [_]) => {
+                let count = numbers.get(1).copied().unwrap_or(1);
+                if count == 0 || count > MAX_WB_WORDS {
+                    return format!("count must be from 1 to {}\n", MAX_WB_WORDS);
+                }
+                let words = match bridge.read_block(addr, count as usize) {
+                    Ok(words) => words,
+                    Err(e) => return format!("couldn't read {:08x}: {:?}\n", addr, e),
+                };
+                let mut out = String::new();
+                for (line, chunk) in words.chunks(4).enumerate() {
+                    out.push_str(&format!("{:08x}:", addr.wrapping_add(line as u32 * 16)));
+                    for word in chunk {
+                        out.push_str(&format!(" {:08x}", word));
+                    }
+                    out.push('\n');
+                }
+                out
+            }
+            ("write", values) if !values.is_empty() => {
+                match bridge.write_block(addr, values) {
+                    Ok(()) => format!("wrote {} words at {:08x}\n", values.len(), addr),
+                    Err(e) => format!("couldn't write {:08x}: {:?}\n", addr, e),
+                }
+            }
+            _ => USAGE.to_owned(),
+        }
+    }
+
+    /// How long the SoC has been up, if it has an uptime counter.  A failed
+    /// read only costs the annotation.
+    fn target_time(&self, bridge: &Bridge) -> Option<TargetTime> {
+        let (uptime, map) = (self.uptime.as_ref()?, self.csr_map.as_ref()?);
+        match uptime.sample(map, bridge) {
+            Ok(time) => Some(time),
+            Err(e) => {
+                log_adapter!("Couldn't read the uptime from {}: {}", uptime, e);
+                None
+            }
+        }
+    }
+
+    /// Read memory for GDB, noting any ECC errors that turn up while doing
+    /// so if that's been asked for.  It's the counters going up that's
+    /// noticed, so an error the CPU ran into at the same time is blamed on
+    /// the read too.
+    fn read_memory_checked(
+        &mut self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        addr: u32,
+        len: u32,
+    ) -> Result<Vec<u8>, MmuError> {
+        if !self.ecc_annotate || !ecc::touches_dram(cpu.memory_map(), addr, len) {
+            return self.read_memory(cpu, bridge, addr, len);
+        }
+        let before = self.ecc_counts(bridge);
+        let data = self.read_memory(cpu, bridge, addr, len)?;
+        match (before, self.ecc_counts(bridge)) {
+            (Ok(before), Ok(after)) if !(after - before).is_zero() => ui_event!(
+                Event::MemoryError,
+                "ECC errors reading {:08x}..{:08x}: {}",
+                addr,
+                addr.wrapping_add(len),
+                after - before
+            ),
+            (Err(e), _) | (_, Err(e)) => log_adapter!("Couldn't read the ECC counters: {}", e),
+            _ => (),
+        }
+        Ok(data)
+    }
+
+    /// Every ECC counter added up
+    fn ecc_counts(&self, bridge: &Bridge) -> Result<EccCounts, CsrMapError> {
+        match self.csr_map {
+            Some(ref map) => ecc::total(&self.ecc_banks, map, bridge),
+            None => Ok(EccCounts::default()),
+        }
+    }
+
+    /// Show or clear the ECC error counters, and say whether GDB's reads of
+    /// DRAM are checked for new ones: `ecc [clear | annotate [on|off]]`
+    fn monitor_ecc(&mut self, bridge: &Bridge, args: &[&str]) -> String {
+        let map = match self.csr_map {
+            Some(ref map) => map,
+            None => {
+                return "no csr.csv to find ECC counters in; give one with --csr-csv\n".to_owned()
+            }
+        };
+        if self.ecc_banks.is_empty() {
+            return "csr.csv has no ECC counters\n".to_owned();
+        }
+        let mut out = String::new();
+        match args {
+            [] => {
+                for bank in &self.ecc_banks {
+                    match bank.read(map, bridge) {
+                        Ok(counts) => out.push_str(&format!("{}: {}\n", bank.name, counts)),
+                        Err(e) => out.push_str(&format!("{}: {}\n", bank.name, e)),
+                    }
+                }
+                out.push_str(&format!(
+                    "Reads of DRAM are {}checked for new errors\n",
+                    if self.ecc_annotate { "" } else { "not " }
+                ));
+            }
+            ["clear"] => {
+                for bank in &self.ecc_banks {
+                    match bank.clear(map, bridge) {
+                        Ok(true) => out.push_str(&format!("{}: cleared\n", bank.name)),
+                        Ok(false) => out.push_str(&format!("{}: can't be cleared\n", bank.name)),
+                        Err(e) => out.push_str(&format!("{}: {}\n", bank.name, e)),
+                    }
+                }
+            }
+            ["annotate"] => {
+                let state = if self.ecc_annotate { "on" } else { "off" };
+                out = format!("Checking reads of DRAM for ECC errors is {}\n", state);
+            }
+            ["annotate", "on"] => self.ecc_annotate = true,
+            ["annotate", "off"] => self.ecc_annotate = false,
+            _ => out = "usage: ecc [clear | annotate [on|off]]\n".to_owned(),
+        }
+        out
+    }
+
+    /// Save the current hart's registers and trap CSRs under a name, and
+    /// put them back later, so a script can call into the firmware with
+    /// made-up arguments and carry on as if it never had
+    fn monitor_context(&mut self, cpu: &RiscvCpu, bridge: &Bridge, args: &[&str]) -> String {
+        let hart = self.current_hart;
+        let running = self.target.read().unwrap().run_state == RunState::Running;
+        match args {
+            ["save", _] | ["restore", _] if running => {
+                "the target is running; interrupt it first\n".to_owned()
+            }
+            [] => {
+                let mut out = format!("{} saved contexts\n", self.contexts.len());
+                for (name, context) in &self.contexts {
+                    out.push_str(&format!(
+                        "  {}: pc {:08x}, sp {:08x}\n",
+                        name,
+                        context.pc(),
+                        context.registers[2]
+                    ));
+                }
+                out
+            }
+            ["save", name] => match cpu.save_context(bridge, hart) {
+                Ok(context) => {
+                    let out = format!(
+                        "Saved hart {} as {}, with pc at {:08x}\n",
+                        hart,
+                        name,
+                        context.pc()
+                    );
+                    self.contexts.retain(|(n, _)| n != name);
+                    self.contexts.push((name.to_string(), context));
+                    out
+                }
+                Err(e) => format!("couldn't save hart {}: {:?}\n", hart, e),
+            },
+            ["restore", name] => {
+                let context = match self.contexts.iter().find(|(n, _)| n == name) {
+                    Some((_, context)) => context,
+                    None => return format!("no context saved as {}\n", name),
+                };
+                match cpu.load_context(bridge, hart, context) {
+                    // GDB holds on to the registers it read before
+                    Ok(()) => format!(
+                        "Restored hart {} from {}.  Run \"maintenance flush register-cache\" so GDB sees it.\n",
+                        hart, name
+                    ),
+                    Err(e) => format!("couldn't restore hart {}: {:?}\n", hart, e),
+                }
+            }
+            ["forget", name] => {
+                let before = self.contexts.len();
+                self.contexts.retain(|(n, _)| n != name);
+                if self.contexts.len() < before {
+                    format!("Forgot {}\n", name)
+                } else {
+                    format!("no context saved as {}\n", name)
+                }
+            }
+            _ => "usage: context [save|restore|forget <name>]\n".to_owned(),
+        }
+    }
+
+    /// Call a function on the current hart: `call <function> [args...]`.
+    /// The function and its arguments are expressions, and an argument
+    /// too big for 32 bits is passed as a 64-bit value.  The return address
+    /// is borrowed from the scratch area, and the hart is put back the way
+    /// it was afterwards, so GDB's view of it stays right.
+    fn monitor_call(&mut self, cpu: &RiscvCpu, bridge: &Bridge, args: &[&str]) -> String {
+        let (function, args) = match args.split_first() {
+            Some((function, args)) => (*function, args),
+            None => return "usage: call <function> [args...]\n".to_owned(),
+        };
+        if self.target.read().unwrap().run_state == RunState::Running {
+            return "the target is running; interrupt it first\n".to_owned();
+        }
+        let addr = match self.eval(cpu, bridge, function).and_then(expr::word) {
+            Ok(addr) => addr,
+            Err(e) => return format!("{}: {}\n", function, e),
+        };
+        let mut values = vec![];
+        for arg in args {
+            match self.eval(cpu, bridge, arg) {
+                Ok(value) => values.push(Arg::from_value(value)),
+                Err(e) => return format!("{}: {}\n", arg, e),
+            }
+        }
+        let hart = self.current_hart;
+        let scratch = match self.scratch.as_mut() {
+            Some(s) => s,
+            None => return format!("{}\n", ScratchError::NoScratch),
+        };
+        match call::call(cpu, bridge, hart, scratch, addr, &values) {
+            Ok((a0, a1)) => format!(
+                "{} returned {} (0x{:08x}), with a1 0x{:08x}\n",
+                function, a0 as i32, a0, a1
+            ),
+            Err(e) => format!("{} {}; hart {} is back where it was\n", function, e, hart),
+        }
+    }
+
+    /// Show where the SoC boots from next, or pick another way and reboot
+    /// it: `bootmode [flash|serial|netboot]`.  Breakpoints are taken out
+    /// first, since the reset clears the CPU's and may load code over the
+    /// ones patched into RAM.  GDB puts them back when it next resumes.
+    fn monitor_bootmode(&mut self, cpu: &RiscvCpu, bridge: &Bridge, args: &[&str]) -> String {
+        let usage = "usage: bootmode [flash|serial|netboot]\n";
+        let control = match (&self.csr_map, &self.boot_control) {
+            (Some(_), Some(control)) => control.clone(),
+            _ => return "no boot register; give one with --boot-register\n".to_owned(),
+        };
+        let mode = match args {
+            [] => {
+                let map = self.csr_map.as_ref().unwrap();
+                return match control.current(map, bridge) {
+                    Ok((addr, Some(mode))) => format!("Boots from {} at {:08x}\n", mode, addr),
+                    Ok((addr, None)) => format!("Boots from {:08x}\n", addr),
+                    Err(e) => format!("couldn't read {}: {}\n", control.register, e),
+                };
+            }
+            [name] => match BootMode::from_name(name) {
+                Some(mode) => mode,
+                None => return usage.to_owned(),
+            },
+            _ => return usage.to_owned(),
+        };
+        let addr = match control.address(mode) {
+            Some(addr) => addr,
+            None => {
+                return format!("no address for {} boot; give one with --boot-register\n", mode)
+            }
+        };
+        if let Err(e) = self.remove_all_breakpoints(cpu, bridge) {
+            return format!("couldn't remove breakpoints: {}\n", e);
+        }
+        let map = self.csr_map.as_ref().unwrap();
+        if let Err(e) = control.reboot(map, bridge, addr) {
+            return format!("couldn't set {}: {}\n", control.register, e);
+        }
+        cpu.flush_cache();
+        self.awaiting_stop = false;
+        self.set_run_state(RunState::Unknown);
+        ui_event!(Event::Reset, "Rebooting to boot from {} at {:08x}", mode, addr);
+        format!("Rebooting to boot from {} at {:08x}\n", mode, addr)
+    }
+
+    /// Look into DRAM bring-up: `ddr [status|check|retrain]`.  The status
+    /// only reads the controller's registers.  `check` writes a pattern to
+    /// DRAM and reads it back, so it needs the target halted so nothing
+    /// else is using it.
+    fn monitor_ddr(&self, cpu: &RiscvCpu, bridge: &Bridge, args: &[&str]) -> String {
+        let (map, ddr) = match (&self.csr_map, &self.ddr) {
+            (Some(map), Some(ddr)) => (map, ddr),
+            (None, _) => return "no csr.csv; give one with --csr-csv\n".to_owned(),
+            (_, None) => return "csr.csv has no LiteDRAM controller\n".to_owned(),
+        };
+        let running = self.target.read().unwrap().run_state == RunState::Running;
+        let status = match ddr.status(map, bridge) {
+            Ok(status) => status,
+            Err(e) => return format!("couldn't read the controller: {}\n", e),
+        };
+        match args {
+            [] | ["status"] => {
+                let mut out = status.to_string();
+                if let Some(ram) = ddr.ram.filter(|_| status.initialized()) {
+                    out.push_str(&format!(
+                        "ddr check tests reads of DRAM at {:08x}, with the target halted\n",
+                        ram
+                    ));
+                }
+                out
+            }
+            ["check"] => {
+                let ram = match ddr.ram {
+                    Some(ram) => ram,
+                    None => return "there's no main_ram to check\n".to_owned(),
+                };
+                if running {
+                    return "the target is running and may be using DRAM; halt it first\n"
+                        .to_owned();
+                }
+                if !status.initialized() {
+                    return "DRAM was never initialized, so it can't be checked\n".to_owned();
+                }
+                match ddr.check(map, bridge) {
+                    Ok(check) => format!("Checked DRAM at {:08x}\n{}", ram, check),
+                    Err(e) => format!("couldn't check reads of DRAM: {}\n", e),
+                }
+            }
+            ["retrain"] => {
+                if running {
+                    return "the target is running and may be using DRAM; halt it first\n"
+                        .to_owned();
+                }
+                if !ddr.trainable() {
+                    return "the PHY has no read delays to train, or there's no main_ram\n"
+                        .to_owned();
+                }
+                if !status.initialized() {
+                    return "DRAM was never initialized, so it can't be read to train with\n"
+                        .to_owned();
+                }
+                let result = ddr.retrain(map, bridge);
+                // Anything read from DRAM before may have been wrong
+                cpu.flush_cache();
+                match result {
+                    Ok(modules) => modules.iter().map(|m| format!("{}\n", m)).collect(),
+                    Err(e) => format!("training failed: {}\n", e),
+                }
+            }
+            _ => "usage: ddr [status|check|retrain]\n".to_owned(),
+        }
+    }
+
+    /// Show the target's uptime alongside the host's clock: `uptime`
+    fn monitor_uptime(&self, bridge: &Bridge) -> String {
+        let uptime = match self.uptime {
+            Some(ref uptime) => uptime,
+            None if self.csr_map.is_none() => {
+                return "no csr.csv to find the uptime counter in; give one with --csr-csv\n"
+                    .to_owned()
+            }
+            None => {
+                return "the SoC has no uptime counter; build it with --timer-uptime\n".to_owned()
+            }
+        };
+        match self.target_time(bridge) {
+            Some(time) => format!(
+                "{} ({} cycles from {}) at host time {}\n",
+                time,
+                time.cycles,
+                uptime,
+                logging::timestamp()
+            ),
+            None => format!("couldn't read {}\n", uptime),
+        }
+    }
+
+    /// The csr.csv map, if `name` is one of its registers
+    fn csr_register_map(&self, name: &str) -> Option<&CsrMap> {
+        self.csr_map
+            .as_ref()
+            .filter(|map| map.registers.contains_key(name))
+    }
+
+    /// Evaluate an expression typed into a `monitor` command.  Names can be
+    /// a register of the current hart with a `$` in front, anything in
+    /// csr.csv or the memory map, or a kernel symbol.
+    fn eval(&self, cpu: &RiscvCpu, bridge: &Bridge, text: &str) -> Result<u64, ExprError> {
+        expr::eval(text, &|name| {
+            if let Some(reg) = name.strip_prefix('$') {
+                let regnum = riscv::register_number(reg)?;
+                return cpu
+                    .read_register(bridge, self.current_hart, regnum)
+                    .ok()
+                    .map(|v| v as u64);
+            }
+            self.csr_map
+                .as_ref()
+                .and_then(|map| map.lookup(name))
+                .or_else(|| board::region_symbol(cpu.memory_map(), name).map(|v| v as u64))
+                .or_else(|| self.kernel.as_ref()?.lookup(name).map(|v| v as u64))
+        })
+    }
+
+    /// Switch the board's power: `power [on|off|cycle]`.  Whatever was known
+    /// about the harts is forgotten once the power has been touched.
+    fn monitor_power(&mut self, cpu: &RiscvCpu, args: &[&str]) -> String {
+        let power = match self.power {
+            Some(ref power) => power,
+            None => return "no power switch; give one with --power\n".to_owned(),
+        };
+        let result = match args {
+            [] => power
+                .is_on()
+                .map(|on| format!("{} is {}\n", power.spec(), if on { "on" } else { "off" })),
+            ["on"] => power.set(true).map(|_| "Power on\n".to_owned()),
+            ["off"] => power.set(false).map(|_| "Power off\n".to_owned()),
+            ["cycle"] => power.cycle().map(|_| "Power cycled\n".to_owned()),
+            _ => return "usage: power [on|off|cycle]\n".to_owned(),
+        };
+        match result {
+            Ok(out) => {
+                if !args.is_empty() {
+                    cpu.flush_cache();
+                    self.set_run_state(RunState::Unknown);
+                    ui_event!(Event::Power, "{}", out.trim_end());
+                }
+                out
+            }
+            Err(e) => format!("{} failed: {}\n", power.spec(), e),
+        }
+    }
+
+    /// Show kernel tasks as threads alongside the harts: `kthreads [on|off]`
+    fn monitor_kthreads(&mut self, args: &[&str]) -> String {
+        if self.kernel.is_none() {
+            return "no kernel symbols; start with --kernel-symbols\n".to_owned();
+        }
+        match args {
+            [] => (),
+            ["on"] => self.kernel_threads = true,
+            ["off"] => {
+                self.kernel_threads = false;
+                self.current_task = None;
+            }
+            _ => return "usage: kthreads [on|off]\n".to_owned(),
+        }
+        format!(
+            "Kernel threads: {}\n",
+            if self.kernel_threads { "on" } else { "off" }
+        )
+    }
+
+    /// Write an ELF core file of the harts and memory for post-mortem
+    /// debugging: `coredump <file> [region...]`.  RAM is saved unless
+    /// regions are named.
+    fn monitor_coredump(&self, cpu: &RiscvCpu, bridge: &Bridge, args: &[&str]) -> String {
+        let (path, regions) = match args.split_first() {
+            Some((path, regions)) => (path, regions),
+            None => return "usage: coredump <file> [region...]\n".to_owned(),
+        };
+        match coredump::save(cpu, bridge, cpu.memory_map(), path, regions) {
+            Ok(saved) => format!(
+                "Saved {} harts and {} bytes of memory to {}\n",
+                cpu.hart_count(),
+                saved,
+                path
+            ),
+            Err(e) => format!("coredump failed: {}\n", e),
+        }
+    }
+
+    /// Single-step the selected hart and write its registers after each
+    /// step to a trace: `step-record <count> <file>`.  Two traces of the
+    /// same code can then be compared with `--compare-steps`.
+    fn monitor_step_record(&self, cpu: &RiscvCpu, bridge: &Bridge, args: &[&str]) -> String {
+        let (count, path) = match args {
+            [count, path] => match count.parse::<usize>() {
+                Ok(count) if count > 0 => (count, path),
+                _ => return "usage: step-record <count> <file>\n".to_owned(),
+            },
+            _ => return "usage: step-record <count> <file>\n".to_owned(),
+        };
+        if self.target.read().unwrap().run_state == RunState::Running {
+            return "the target is running; halt it first\n".to_owned();
+        }
+        let hart = self.current_hart;
+        let registers = || -> Result<steptrace::Registers, RiscvCpuError> {
+            let mut registers = [0; steptrace::REGISTER_COUNT];
+            for (regnum, value) in registers.iter_mut().enumerate() {
+                *value = cpu.read_register(bridge, hart, regnum as u32)?;
+            }
+            Ok(registers)
+        };
+        let file = match std::fs::File::create(path) {
+            Ok(file) => io::BufWriter::new(file),
+            Err(e) => return format!("couldn't create {}: {}\n", path, e),
+        };
+        let start = match registers() {
+            Ok(start) => start,
+            Err(e) => return format!("couldn't read registers: {:?}\n", e),
+        };
+        let mut trace = match TraceWriter::new(file, hart as u8, &start) {
+            Ok(trace) => trace,
+            Err(e) => return format!("couldn't write {}: {}\n", path, e),
+        };
+        let mut steps = 0;
+        let mut error = None;
+        while steps < count {
+            if let Err(e) = cpu.step_hart(bridge, hart) {
+                error = Some(format!("{:?}", e));
+                break;
+            }
+            match registers() {
+                Ok(now) => {
+                    if let Err(e) = trace.step(&now) {
+                        error = Some(e.to_string());
+                        break;
+                    }
+                }
+                Err(e) => {
+                    error = Some(format!("{:?}", e));
+                    break;
+                }
+            }
+            steps += 1;
+        }
+        if let Err(e) = trace.finish() {
+            error.get_or_insert(e.to_string());
+        }
+        // GDB still has the registers from before the steps
+        let mut out = format!(
+            "Recorded {} steps of hart {} to {}; run \"maint flush register-cache\" to see where it stopped\n",
+            steps, hart, path
+        );
+        if let Some(e) = error {
+            out.push_str(&format!("Stopped early: {}\n", e));
+        }
+        out
+    }
+
+    /// Save the value of every CSR in csr.csv: `csr-dump <file>`
+    fn monitor_csr_dump(&self, bridge: &Bridge, args: &[&str]) -> String {
+        let path = match args {
+            [path] => path,
+            _ => return "usage: csr-dump <file>\n".to_owned(),
+        };
+        let map = match self.csr_map {
+            Some(ref map) => map,
+            None => return "no csr.csv; give one with --csr-csv\n".to_owned(),
+        };
+        let snapshot = match CsrSnapshot::capture(map, bridge) {
+            Ok(snapshot) => snapshot,
+            Err(e) => return format!("couldn't read the registers: {}\n", e),
+        };
+        match snapshot.save(path) {
+            Ok(()) => format!("Saved {} registers to {}\n", snapshot.values.len(), path),
+            Err(e) => format!("couldn't save {}: {}\n", path, e),
+        }
+    }
+
+    /// Write back registers saved with `csr-dump` that have changed since:
+    /// `csr-restore <file> [register|peripheral...]`.  Registers that act
+    /// when written, such as `ev_pending`, are only written when named.
+    fn monitor_csr_restore(&self, bridge: &Bridge, args: &[&str]) -> String {
+        let (path, only) = match args.split_first() {
+            Some((path, only)) => (path, only),
+            None => return "usage: csr-restore <file> [register|peripheral...]\n".to_owned(),
+        };
+        let map = match self.csr_map {
+            Some(ref map) => map,
+            None => return "no csr.csv; give one with --csr-csv\n".to_owned(),
+        };
+        let saved = match CsrSnapshot::load(path) {
+            Ok(saved) => saved,
+            Err(e) => return format!("couldn't load {}: {}\n", path, e),
+        };
+        let current = match CsrSnapshot::capture(map, bridge) {
+            Ok(current) => current,
+            Err(e) => return format!("couldn't read the registers: {}\n", e),
+        };
+        let plan = saved.plan_restore(map, &current, only);
+        let mut out = String::new();
+        for (name, now, value) in &plan.writes {
+            match map.write_register(bridge, name, *value) {
+                Ok(()) => out.push_str(&format!("{} = 0x{:x} (was 0x{:x})\n", name, value, now)),
+                Err(e) => return format!("{}couldn't write {}: {}\n", out, name, e),
+            }
+        }
+        for (name, why) in &plan.skipped {
+            out.push_str(&format!("{} left alone: {}\n", name, why));
+        }
+        out.push_str(&format!(
+            "Restored {} registers; {} already matched\n",
+            plan.writes.len(),
+            plan.unchanged
+        ));
+        out
+    }
+
+    /// Save the selected hart, encoder settings and breakpoints so that
+    /// `--restore-session` can bring them back: `save-session <file>`
+    fn monitor_save_session(&self, args: &[&str]) -> String {
+        let path = match args {
+            [path] => path,
+            _ => return "usage: save-session <file>\n".to_owned(),
+        };
+        let session = Session {
+            hart: self.current_hart,
+            use_rle: self.use_rle,
+            use_escaping: self.use_escaping,
+            breakpoints: self.breakpoints.clone(),
+            catch_causes: self.catch_causes.clone(),
+        };
+        match session.save(path) {
+            Ok(()) => format!(
+                "Saved session with {} breakpoints to {}\n",
+                session.breakpoints.len(),
+                path
+            ),
+            Err(e) => format!("couldn't save session: {}\n", e),
+        }
+    }
+
+    /// Send the output of a monitor command as console output, followed
+    /// by the final `OK`.
+    fn gdb_send_monitor_output(&mut self, output: &str) -> io::Result<()> {
+        if !output.is_empty() {
+            self.gdb_send_console(output)?;
+        }
+        self.gdb_send(b"OK")
+    }
+
+    /// Send text for GDB to print on its console
+    fn gdb_send_console(&mut self, text: &str) -> io::Result<()> {
+        let mut packet = "O".to_owned();
+        for byte in text.as_bytes() {
+            packet.push_str(&format!("{:02x}", byte));
+        }
+        self.gdb_send(packet.as_bytes())
+    }
+
+    fn gdb_send_ack(&mut self) -> io::Result<()> {
+        self.connection.send(b"+")
+    }
+
+    fn gdb_send_nak(&mut self) -> io::Result<()> {
+        self.connection.send(b"-")
+    }
+
+    fn gdb_send_u32(&mut self, vals: Vec<u32>) -> io::Result<()> {
+        let mut out_str = String::new();
+        for val in vals {
+            let mut buf = [0; 4];
+            BigEndian::write_u32(&mut buf, val);
+            out_str.push_str(&format!("{:08x}", NativeEndian::read_u32(&buf)));
+        }
+        self.gdb_send(out_str.as_bytes())
+    }
+
+    /// Send register values, marking the ones that weren't saved as
+    /// unavailable
+    fn gdb_send_saved_registers(&mut self, vals: &[Option<u32>]) -> io::Result<()> {
+        let mut out_str = String::new();
+        for val in vals {
+            match val {
+                Some(val) => {
+                    for byte in &val.to_le_bytes() {
+                        out_str.push_str(&format!("{:02x}", byte));
+                    }
+                }
+                None => out_str.push_str("xxxxxxxx"),
+            }
+        }
+        self.gdb_send(out_str.as_bytes())
+    }
+
+    fn gdb_send_hex(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut out_str = String::with_capacity(data.len() * 2);
+        for byte in data {
+            out_str.push_str(&format!("{:02x}", byte));
+        }
+        self.gdb_send(out_str.as_bytes())
+    }
+
+    fn gdb_send(&mut self, inp: &[u8]) -> io::Result<()> {
+        let to_write = if self.use_rle {
+            rsp::frame(&rsp::rle_encode(inp))
+        } else {
+            rsp::frame(inp)
+        };
+        log_gdb!(
+            "-> Writing {} bytes: {}",
+            to_write.len(),
+            String::from_utf8_lossy(&to_write)
+        );
+        self.connection.write_all(&to_write)?;
+        Ok(())
+    }
+
+    /// Send a reply containing binary data, escaping it if enabled
+    fn gdb_send_binary(&mut self, inp: &[u8]) -> io::Result<()> {
+        if self.use_escaping {
+            self.gdb_send(&rsp::escape(inp))
+        } else {
+            self.gdb_send(inp)
+        }
+    }
+
+    fn gdb_send_file(&mut self, data: &[u8], offset: u32, len: u32) -> io::Result<()> {
+        self.gdb_send_binary(&xfer_chunk(data, offset as usize, len as usize))
+    }
+
+    /// A document for `qXfer` to read, built with `build` unless it was
+    /// already built since the target last changed
+    fn xfer_document<E>(
+        &mut self,
+        name: &str,
+        build: impl FnOnce(&mut Self) -> Result<Vec<u8>, E>,
+    ) -> Result<Arc<[u8]>, E> {
+        let generation = self.target.read().unwrap().generation;
+        if let Some(document) = self.xfer_cache.get(generation, name) {
+            return Ok(document);
+        }
+        let document = build(self)?;
+        Ok(self.xfer_cache.insert(generation, name, document))
+    }
+}
+
+impl Drop for GdbServer {
+    fn drop(&mut self) {
+        self.clients.leave(self.role);
+    }
+}
+
+/// Whether an observer may use `monitor name args`, which it can if the
+/// command only looks
+fn observer_may_run(name: &str, args: &[&str]) -> bool {
+    match name {
+        "breakpoints" | "capabilities" | "dmesg" | "encoding" | "latency" | "ps" | "read"
+        | "timings" | "uptime" => true,
+        "ecc" => args.first() != Some(&"clear"),
+        "ddr" => matches!(args, [] | ["status"]),
+        "bootmode" => args.is_empty(),
+        // Logging is shared by every session, so observers may only look
+        "set" => args.is_empty(),
+        "wb" => args.first() == Some(&"read"),
+        _ => false,
+    }
+}
+
+/// Whether `monitor cmd` might leave the target, or how it's shown to GDB,
+/// different.  Anything an observer can't run might, other than the
+/// commands that only write files or change the logging.
+fn monitor_may_change_target(cmd: &str) -> bool {
+    let args: Vec<&str> = cmd.split_whitespace().collect();
+    match args.split_first() {
+        Some((&("save-session" | "csr-dump" | "coredump" | "set"), _)) => false,
+        Some((name, args)) => !observer_may_run(name, args),
+        None => false,
+    }
+}
+
+/// What `monitor set` was asked to do
+#[derive(Debug, PartialEq)]
+enum SetCommand<'a> {
+    Show,
+    Verbosity(Verbosity),
+    Filter(LogChannel, Filter),
+    Console(LogChannel),
+    File(LogChannel, &'a str),
+}
+
+const SET_USAGE: &str = "usage: set [verbosity quiet|normal|verbose|debug]\n       \
+                         set debug <channel> on|off|default|console|file <path>\n       \
+                         set debug <channel> quiet|normal|verbose|debug\n";
+
+fn parse_set<'a>(args: &[&'a str]) -> Result<SetCommand<'a>, String> {
+    let (name, setting) = match args {
+        [] => return Ok(SetCommand::Show),
+        ["verbosity", level] => {
+            return Verbosity::from_name(level)
+                .map(SetCommand::Verbosity)
+                .ok_or_else(|| SET_USAGE.to_owned())
+        }
+        ["debug", name, setting @ ..] => (name, setting),
+        _ => return Err(SET_USAGE.to_owned()),
+    };
+    let channel = match LogChannel::from_name(name) {
+        Some(channel) => channel,
+        None => {
+            return Err(format!(
+                "no channel called {}; there's {}\n",
+                name,
+                LogChannel::ALL
+                    .iter()
+                    .map(|c| c.name())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+        }
+    };
+    match setting {
+        ["on"] => Ok(SetCommand::Filter(channel, Filter::Level(Verbosity::Debug))),
+        ["off"] => Ok(SetCommand::Filter(channel, Filter::Off)),
+        ["default"] => Ok(SetCommand::Filter(channel, Filter::Default)),
+        ["console"] => Ok(SetCommand::Console(channel)),
+        ["file", path] => Ok(SetCommand::File(channel, path)),
+        [level] => match Verbosity::from_name(level) {
+            Some(level) => Ok(SetCommand::Filter(channel, Filter::Level(level))),
+            None => Err(SET_USAGE.to_owned()),
+        },
+        _ => Err(SET_USAGE.to_owned()),
+    }
+}
+
+/// Change what's logged while running, so a trace can be caught without
+/// restarting and losing whatever went wrong: `set verbosity <level>`,
+/// `set debug <channel> on|off|default|console`, `set debug <channel> file
+/// <path>`, or `set debug <channel> <level>` to log that channel's entries
+/// up to `level` whatever the verbosity.  `remote` is the GDB channel, as
+/// in GDB's own `set debug remote`.
+fn monitor_set(args: &[&str]) -> String {
+    let result = match parse_set(args) {
+        Ok(SetCommand::Show) => {
+            let mut out = format!("Verbosity: {}\n", ui::verbosity().name());
+            for channel in LogChannel::ALL.iter() {
+                out.push_str(&format!(
+                    "  {}: {}\n",
+                    channel.name(),
+                    logging::describe(*channel)
+                ));
+            }
+            return out;
+        }
+        Ok(SetCommand::Verbosity(level)) => {
+            ui::set_verbosity(level);
+            return format!("Verbosity is now {}\n", level.name());
+        }
+        Ok(SetCommand::Filter(channel, filter)) => {
+            logging::set_filter(channel, filter);
+            Ok(channel)
+        }
+        Ok(SetCommand::Console(channel)) => logging::set_file(channel, None).map(|()| channel),
+        Ok(SetCommand::File(channel, path)) => logging::set_file(channel, Some(path)).map(|()| {
+            // Asking for a file means wanting what goes in it
+            logging::set_filter(channel, Filter::Level(Verbosity::Debug));
+            channel
+        }),
+        Err(e) => return e,
+    };
+    match result {
+        Ok(channel) => format!(
+            "{} logging: {}\n",
+            channel.name(),
+            logging::describe(channel)
+        ),
+        Err(e) => format!("couldn't open the log file: {}\n", e),
+    }
+}
+
+/// Whether `len` bytes at `addr` all lie in one of `regions`
+fn within_regions(regions: &[MemoryRegion], addr: u32, len: u32) -> bool {
+    regions
+        .iter()
+        .any(|r| addr >= r.base && addr as u64 + len as u64 <= r.base as u64 + r.size as u64)
+}
+
+/// Parse the `;`-separated hex signal numbers of `QPassSignals` and
+/// `QProgramSignals`.  An empty list is allowed.
+fn parse_signal_list(list: &str) -> Result<Vec<u8>, GdbServerError> {
+    let mut signals = vec![];
+    for sig in list.split(';').filter(|s| !s.is_empty()) {
+        signals.push(u8::from_str_radix(sig, 16)?);
+    }
+    Ok(signals)
+}
+
+/// Let the user know why `hart` stopped
+fn announce_halt(cpu: &RiscvCpu, hart: usize, when: Option<TargetTime>) {
+    let at = match when {
+        Some(time) => {
+            log_adapter!("Hart {} halted at target uptime {}", hart, time);
+            format!(" at uptime {}", time)
+        }
+        None => String::new(),
+    };
+    match cpu.halt_reason(hart) {
+        Some(HaltReason::Trap(mcause)) => ui_event!(
+            Event::Halt,
+            "Hart {} took a trap (mcause {:08x}){}",
+            hart,
+            mcause,
+            at
+        ),
+        Some(HaltReason::Watchpoint(_, addr)) => ui_event!(
+            Event::Halt,
+            "Hart {} hit the watchpoint on {:08x}{}",
+            hart,
+            addr,
+            at
+        ),
+        _ => ui_event!(Event::Halt, "Hart {} stopped at a breakpoint{}", hart, at),
+    }
+}
+
+/// What a packet is called, for sorting out timings: the word naming a
+/// `q`, `Q` or `v` packet, or the letter for any other
+fn packet_name(pkt: &[u8]) -> String {
+    let len = match pkt.first() {
+        Some(b'q') | Some(b'Q') | Some(b'v') => pkt
+            .iter()
+            .position(|b| !b.is_ascii_alphabetic())
+            .unwrap_or(pkt.len()),
+        Some(_) => 1,
+        None => 0,
+    };
+    String::from_utf8_lossy(&pkt[..len]).into_owned()
+}
+
+/// What became of patching in a software breakpoint
+#[derive(Debug, PartialEq)]
+enum Patch {
+    Patched,
+
+    /// The kind wasn't 2 or 4
+    BadKind,
+
+    /// The break instruction didn't read back, as from ROM or flash
+    Unwritable,
+}
+
+/// The error for a breakpoint that couldn't be patched in
+fn unwritable_breakpoint(addr: u32) -> String {
+    format!(
+        "E.can't write a breakpoint at {:08x}; use a hardware breakpoint",
+        addr
+    )
+}
+
+/// The break instruction for a `Z0` of the given kind: `c.ebreak` in place
+/// of a compressed instruction, or `ebreak` in place of a full-size one
+fn break_instruction(kind: u32) -> Option<&'static [u8]> {
+    match kind {
+        2 => Some(&[0x02, 0x90]),
+        4 => Some(&[0x73, 0x00, 0x10, 0x00]),
+        _ => None,
+    }
+}
+
+/// Parse the `addr,length:data` of an `M` or `X` packet.  `M` carries
+/// the data as hex and `X` as escaped binary.
+fn parse_memory_write(args: &[u8], binary: bool) -> Result<(u32, Vec<u8>), GdbServerError> {
+    let colon = args
+        .iter()
+        .position(|&b| b == b':')
+        .ok_or(GdbServerError::ParseIntError)?;
+    let header = String::from_utf8_lossy(&args[..colon]);
+    let mut fields = header.splitn(2, ',');
+    let addr = u32::from_str_radix(fields.next().unwrap_or(""), 16)?;
+    let len = usize::from_str_radix(fields.next().unwrap_or(""), 16)?;
+    let data = if binary {
+        rsp::unescape(&args[colon + 1..])
+    } else {
+        rsp::decode_hex(&args[colon + 1..]).ok_or(GdbServerError::ParseIntError)?
+    };
+    if data.len() != len {
+        return Err(GdbServerError::ParseIntError);
+    }
+    Ok((addr, data))
+}
+
+/// Parse what follows `vFile:`.  Operations other than the ones needed to
+/// copy files to and from the target are `None`.
+fn parse_file_request(pkt: &[u8]) -> Result<Option<FileRequest>, GdbServerError> {
+    let colon = match pkt.iter().position(|&b| b == b':') {
+        Some(idx) => idx,
+        None => return Ok(None),
+    };
+    let (op, args) = (&pkt[..colon], &pkt[colon + 1..]);
+    // Binary data can hold commas, so it's never split up
+    let fields: Vec<&[u8]> = args.splitn(3, |&b| b == b',').collect();
+    let number = |idx: usize| -> Result<u32, GdbServerError> {
+        let field = fields.get(idx).ok_or(GdbServerError::ParseIntError)?;
+        Ok(u32::from_str_radix(&String::from_utf8_lossy(field), 16)?)
+    };
+    let path = || rsp::decode_hex(fields[0]).ok_or(GdbServerError::ParseIntError);
+    Ok(Some(match op {
+        b"setfs" => number(0).map(|_| FileRequest::SetFs)?,
+        b"open" => FileRequest::Open(path()?, number(1)?, number(2)?),
+        b"close" => FileRequest::Close(number(0)?),
+        b"pread" => FileRequest::Pread(number(0)?, number(1)?, number(2)?),
+        b"pwrite" => match fields.get(2) {
+            Some(data) => FileRequest::Pwrite(number(0)?, number(1)?, rsp::unescape(data)),
+            None => return Err(GdbServerError::ParseIntError),
+        },
+        b"unlink" => FileRequest::Unlink(path()?),
+        _ => return Ok(None),
+    }))
+}
+
+/// Documents read with `qXfer`, kept for as long as the target stays the
+/// same so that reading one a chunk at a time doesn't build it again for
+/// every chunk
+#[derive(Default)]
+struct XferCache {
+    /// The target generation the documents were built in
+    generation: u64,
+    documents: HashMap<String, Arc<[u8]>>,
+}
+
+impl XferCache {
+    fn get(&self, generation: u64, name: &str) -> Option<Arc<[u8]>> {
+        if generation != self.generation {
+            return None;
+        }
+        self.documents.get(name).cloned()
+    }
+
+    /// Keep `document`, forgetting any built in an earlier generation
+    fn insert(&mut self, generation: u64, name: &str, document: Vec<u8>) -> Arc<[u8]> {
+        if generation != self.generation {
+            self.documents.clear();
+            self.generation = generation;
+        }
+        let document: Arc<[u8]> = document.into();
+        self.documents.insert(name.to_owned(), document.clone());
+        document
+    }
+
+    fn forget(&mut self, name: &str) {
+        self.documents.remove(name);
+    }
+
+    fn clear(&mut self) {
+        self.documents.clear();
+    }
+}
+
+/// Build the reply to a `qXfer` read of `len` bytes at `offset` into
+/// `data`.  The reply starts with `m` if there's more to read after this
+/// chunk, or `l` if this is the last of it.  A chunk that ends exactly at
+/// the end of the data is the last one.  GDB doesn't allow `m` with no
+/// data, so a read at or past the end, or of zero bytes, is just `l`.
+fn xfer_chunk(data: &[u8], offset: usize, len: usize) -> Vec<u8> {
+    let start = offset.min(data.len());
+    let end = offset.saturating_add(len).min(data.len());
+    let chunk = &data[start..end];
+    let mut reply = Vec::with_capacity(chunk.len() + 1);
+    if !chunk.is_empty() && end < data.len() {
+        reply.push(b'm');
+    } else {
+        reply.push(b'l');
+    }
+    reply.extend_from_slice(chunk);
+    reply
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::time::Duration;
+
+    use super::{
+        break_instruction, monitor_may_change_target, observer_may_run, packet_name,
+        parse_file_request, parse_memory_write, parse_set, within_regions, xfer_chunk,
+        BreakPointType, FileRequest, GdbServer, GdbServerError, Patch, SetCommand, XferCache,
+        SUPPORTED_FEATURES,
+    };
+    use crate::board::{MemoryKind, MemoryRegion};
+    use crate::bridge::{Bridge, BridgeError};
+    use crate::config::Config;
+    use crate::embed::pipe::{pipe, PipeReader, PipeWriter};
+    use crate::logging::{Filter, LogChannel};
+    use crate::mock_cpu::MockHart;
+    use crate::riscv::{RiscvCpu, RiscvCpuError};
+    use crate::rsp::{frame, rle_decode, MAX_PACKET_SIZE};
+    use crate::session::{Breakpoint, Session};
+    use crate::target::TargetState;
+    use crate::transport::Connection;
+    use crate::ui::Verbosity;
+
+    /// A server on the mock bridge, with its CPU, and GDB's end of the
+    /// connection: the stream it reads replies from, and the one it
+    /// sends packets down
+    fn server(args: &[&str]) -> (GdbServer, RiscvCpu, Bridge, PipeReader, PipeWriter) {
+        server_with_session(args, None)
+    }
+
+    fn server_with_session(
+        args: &[&str],
+        session: Option<&Session>,
+    ) -> (GdbServer, RiscvCpu, Bridge, PipeReader, PipeWriter) {
+        let cfg = Config::from_args(["gdb", "--mock"].iter().chain(args)).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        let cpu = RiscvCpu::new(&cfg).unwrap();
+        let (server_in, to_server) = pipe();
+        let (mut from_server, server_out) = pipe();
+        from_server.set_timeout(Duration::from_millis(200));
+        let connection = Connection::from_stream(server_in, server_out);
+        let target = TargetState::new_shared();
+        let gdb = GdbServer::with_connection(&cfg, connection, target, session.cloned(), None);
+        (gdb, cpu, bridge, from_server, to_server)
+    }
+
+    /// The body of the next packet the server sent, if it sent one
+    fn sent(from_server: &mut PipeReader) -> Option<Vec<u8>> {
+        let mut frame = vec![];
+        let mut byte = [0; 1];
+        while frame.len() < 3 || frame[frame.len() - 3] != b'#' {
+            from_server.read_exact(&mut byte).ok()?;
+            frame.push(byte[0]);
+        }
+        Some(frame[1..frame.len() - 3].to_vec())
+    }
+
+    #[test]
+    fn packet_size_advertised() {
+        let advertised = format!("PacketSize={:x}", MAX_PACKET_SIZE);
+        assert!(SUPPORTED_FEATURES.split(';').any(|f| f == advertised));
+    }
+
+    #[test]
+    fn file_request_parsing() {
+        match parse_file_request(b"open:2f746d702f61,241,1a4") {
+            Ok(Some(FileRequest::Open(path, 0x241, 0o644))) => assert_eq!(path, b"/tmp/a"),
+            other => panic!("{:?}", other),
+        }
+        match parse_file_request(b"pwrite:3,10,a,}]}\x03") {
+            Ok(Some(FileRequest::Pwrite(3, 0x10, data))) => assert_eq!(data, b"a,}#"),
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(parse_file_request(b"fstat:3"), Ok(None)));
+        assert!(parse_file_request(b"pread:3").is_err());
+    }
+
+    #[test]
+    fn memory_write_parsing() {
+        match parse_memory_write(b"10000000,3:0a0b0c", false) {
+            Ok((0x1000_0000, data)) => assert_eq!(data, [0x0a, 0x0b, 0x0c]),
+            other => panic!("{:?}", other),
+        }
+        match parse_memory_write(b"20,4:a:}]}\x03", true) {
+            Ok((0x20, data)) => assert_eq!(data, b"a:}#"),
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(parse_memory_write(b"20,0:", true), Ok((0x20, ref d)) if d.is_empty()));
+        assert!(parse_memory_write(b"20,4:0a0b", false).is_err());
+        assert!(parse_memory_write(b"20,1", false).is_err());
+    }
+
+    #[test]
+    fn break_instruction_matches_kind() {
+        assert_eq!(break_instruction(2), Some(&0x9002u16.to_le_bytes()[..]));
+        assert_eq!(
+            break_instruction(4),
+            Some(&0x0010_0073u32.to_le_bytes()[..])
+        );
+        assert_eq!(break_instruction(3), None);
+    }
+
+    #[test]
+    fn observers_only_look() {
+        assert!(observer_may_run("read", &["pc"]));
+        assert!(observer_may_run("ecc", &[]));
+        assert!(!observer_may_run("ecc", &["clear"]));
+        assert!(observer_may_run("wb", &["read", "0x40000000"]));
+        assert!(!observer_may_run("wb", &["write", "0x40000000", "1"]));
+        assert!(!observer_may_run("write", &["pc", "0"]));
+        assert!(!observer_may_run("power", &["cycle"]));
+        assert!(observer_may_run("ddr", &[]));
+        assert!(observer_may_run("ddr", &["status"]));
+        assert!(!observer_may_run("ddr", &["check"]));
+        assert!(!observer_may_run("ddr", &["retrain"]));
+        // The dump is written on the adapter's host
+        assert!(!observer_may_run("csr-dump", &["/tmp/csrs.txt"]));
+        assert!(observer_may_run("set", &[]));
+        assert!(!observer_may_run("set", &["debug", "remote", "on"]));
+        assert!(!observer_may_run("set", &["verbosity", "debug"]));
+    }
+
+    #[test]
+    fn monitor_commands_that_change_the_target() {
+        assert!(monitor_may_change_target("write pc 0"));
+        assert!(monitor_may_change_target("kthreads on"));
+        assert!(monitor_may_change_target("wb write 0x40000000 1"));
+        assert!(!monitor_may_change_target("wb read 0x40000000"));
+        assert!(!monitor_may_change_target("breakpoints"));
+        assert!(!monitor_may_change_target("save-session /tmp/session.txt"));
+        assert!(!monitor_may_change_target("set debug gdb on"));
+        assert!(!monitor_may_change_target(""));
+    }
+
+    #[test]
+    fn wb_arguments() {
+        let (mut gdb, cpu, bridge, _, _) = server(&[]);
+        let usage = "usage: wb read <addr> [count] | wb write <addr> <value>...\n";
+        let malformed = [
+            "wb",
+            "wb read",
+            "wb write 0x40000000",
+            "wb read 0x40000000 1 2",
+            "wb poke 0 1",
+        ];
+        for args in malformed {
+            assert_eq!(gdb.process_monitor(&cpu, &bridge, args), usage, "{}", args);
+        }
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "wb read 0x40000002"),
+            "40000002 isn't word aligned\n"
+        );
+        for count in ["0", "257", "-1"] {
+            assert_eq!(
+                gdb.process_monitor(&cpu, &bridge, &format!("wb read 0x40000000 {}", count)),
+                "count must be from 1 to 256\n"
+            );
+        }
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "wb write 0x40000000 zz"),
+            "zz: don't know what zz is\n"
+        );
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "wb write 0x40000000 0x100000000"),
+            "0x100000000: 0x100000000 doesn't fit in 32 bits\n"
+        );
+
+        // Each value is an expression of its own, and they go to
+        // consecutive words
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "wb write 0x40000000 1 0x10+2 -1 4 5"),
+            "wrote 5 words at 40000000\n"
+        );
+        assert_eq!(bridge.peek(0x4000_0008).unwrap(), 0xffff_ffff);
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "wb read 0x40000000 5"),
+            "40000000: 00000001 00000012 ffffffff 00000004\n40000010: 00000005\n"
+        );
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "wb read 0x40000004"),
+            "40000004: 00000012\n"
+        );
+    }
+
+    #[test]
+    fn packet_names() {
+        assert_eq!(packet_name(b"mdeadbeef,4"), "m");
+        assert_eq!(
+            packet_name(b"qXfer:features:read:target.xml:0,fff"),
+            "qXfer"
+        );
+        assert_eq!(packet_name(b"vCont;c"), "vCont");
+        assert_eq!(packet_name(b"QStartNoAckMode"), "QStartNoAckMode");
+        assert_eq!(packet_name(b"?"), "?");
+    }
+
+    #[test]
+    fn xfer_cached_per_generation() {
+        let mut cache = XferCache::default();
+        assert!(cache.get(0, "threads").is_none());
+        cache.insert(0, "threads", b"<threads/>".to_vec());
+        cache.insert(0, "memory-map", b"<memory-map/>".to_vec());
+        assert_eq!(&*cache.get(0, "threads").unwrap(), b"<threads/>");
+        assert!(cache.get(1, "threads").is_none());
+        // Building one again in a new generation drops the rest
+        cache.insert(1, "threads", b"<threads></threads>".to_vec());
+        assert_eq!(&*cache.get(1, "threads").unwrap(), b"<threads></threads>");
+        assert!(cache.get(1, "memory-map").is_none());
+        cache.insert(1, "memory-map", b"<memory-map/>".to_vec());
+        cache.forget("threads");
+        assert!(cache.get(1, "threads").is_none());
+        assert!(cache.get(1, "memory-map").is_some());
+    }
+
+    #[test]
+    fn xfer_whole_file() {
+        assert_eq!(xfer_chunk(b"abcdef", 0, 0x1000), b"labcdef");
+    }
+
+    #[test]
+    fn xfer_exact_boundary() {
+        assert_eq!(xfer_chunk(b"abcdef", 0, 6), b"labcdef");
+        assert_eq!(xfer_chunk(b"abcdef", 2, 4), b"lcdef");
+    }
+
+    #[test]
+    fn xfer_offset_at_end() {
+        assert_eq!(xfer_chunk(b"abcdef", 6, 4), b"l");
+        assert_eq!(xfer_chunk(b"abcdef", 7, 4), b"l");
+        assert_eq!(xfer_chunk(b"", 0, 4), b"l");
+    }
+
+    #[test]
+    fn xfer_zero_length() {
+        assert_eq!(xfer_chunk(b"abcdef", 0, 0), b"l");
+        assert_eq!(xfer_chunk(b"abcdef", 3, 0), b"l");
+    }
+
+    #[test]
+    fn xfer_huge_length() {
+        assert_eq!(xfer_chunk(b"abcdef", 4, usize::MAX), b"lef");
+    }
+
+    #[test]
+    fn xfer_multiple_chunks() {
+        let data: Vec<u8> = (0..100).collect();
+        for len in 1..=101 {
+            let mut offset = 0;
+            let mut read = vec![];
+            loop {
+                let reply = xfer_chunk(&data, offset, len);
+                assert!(reply.len() <= len + 1);
+                read.extend_from_slice(&reply[1..]);
+                offset += reply.len() - 1;
+                match reply[0] {
+                    b'm' => assert_eq!(reply.len(), len + 1),
+                    b'l' => break,
+                    other => panic!("unexpected reply type {}", other),
+                }
+            }
+            assert_eq!(read, data, "reading {} bytes at a time", len);
+        }
+    }
+
+    #[test]
+    fn set_parsing() {
+        assert_eq!(parse_set(&[]), Ok(SetCommand::Show));
+        assert_eq!(
+            parse_set(&["verbosity", "debug"]),
+            Ok(SetCommand::Verbosity(Verbosity::Debug))
+        );
+        assert_eq!(
+            parse_set(&["debug", "remote", "on"]),
+            Ok(SetCommand::Filter(
+                LogChannel::Gdb,
+                Filter::Level(Verbosity::Debug)
+            ))
+        );
+        assert_eq!(
+            parse_set(&["debug", "bridge", "verbose"]),
+            Ok(SetCommand::Filter(
+                LogChannel::Bridge,
+                Filter::Level(Verbosity::Verbose)
+            ))
+        );
+        assert_eq!(
+            parse_set(&["debug", "terminal", "default"]),
+            Ok(SetCommand::Filter(LogChannel::Terminal, Filter::Default))
+        );
+        assert_eq!(
+            parse_set(&["debug", "adapter", "file", "/tmp/adapter.log"]),
+            Ok(SetCommand::File(LogChannel::Adapter, "/tmp/adapter.log"))
+        );
+        assert_eq!(
+            parse_set(&["debug", "gdb", "console"]),
+            Ok(SetCommand::Console(LogChannel::Gdb))
+        );
+        assert!(parse_set(&["debug", "usb", "on"])
+            .unwrap_err()
+            .starts_with("no channel called usb"));
+        assert!(parse_set(&["verbosity", "loud"]).is_err());
+        assert!(parse_set(&["debug", "gdb"]).is_err());
+        assert!(parse_set(&["debug", "gdb", "file"]).is_err());
+        assert!(parse_set(&["debug", "gdb", "on", "off"]).is_err());
+        assert!(parse_set(&["color", "on"]).is_err());
+    }
+
+    #[test]
+    fn fallback_regions() {
+        let region = |name: &str, base, size| MemoryRegion {
+            name: name.to_owned(),
+            base,
+            size,
+            kind: MemoryKind::Ram,
+        };
+        let regions = [
+            region("sram", 0x1000_0000, 0x2000),
+            region("high", 0xffff_f000, 0x1000),
+        ];
+        assert!(within_regions(&regions, 0x1000_0000, 4));
+        assert!(within_regions(&regions, 0x1000_1ffc, 4));
+        // Straddling the end, or the start
+        assert!(!within_regions(&regions, 0x1000_1ffe, 4));
+        assert!(!within_regions(&regions, 0x0fff_fffe, 4));
+        // Right up to the top of the address space, but not past it
+        assert!(within_regions(&regions, 0xffff_fffc, 4));
+        assert!(!within_regions(&regions, 0xffff_fffe, 4));
+        assert!(!within_regions(&[], 0x1000_0000, 4));
+    }
+
+    #[test]
+    fn doorbell_is_answered_once() {
+        let (mut gdb, cpu, bridge, mut from_server, _) =
+            server(&["--doorbell", "0x40000000:log,gdb"]);
+        gdb.answer_doorbell(&cpu, &bridge).unwrap();
+        gdb.connection.flush().unwrap();
+        assert_eq!(sent(&mut from_server), None);
+
+        bridge.poke(0x4000_0000, 0x2a).unwrap();
+        gdb.answer_doorbell(&cpu, &bridge).unwrap();
+        gdb.connection.flush().unwrap();
+        let console: String = "Doorbell rang with 0x2a\n"
+            .bytes()
+            .map(|b| format!("{:02x}", b))
+            .collect();
+        assert_eq!(
+            sent(&mut from_server),
+            Some(format!("O{}", console).into_bytes())
+        );
+        assert_eq!(bridge.peek(0x4000_0000).unwrap(), 0);
+        gdb.answer_doorbell(&cpu, &bridge).unwrap();
+        gdb.connection.flush().unwrap();
+        assert_eq!(sent(&mut from_server), None);
+
+        // GDB can't be sent console output in non-stop mode
+        gdb.non_stop = true;
+        bridge.poke(0x4000_0000, 1).unwrap();
+        gdb.answer_doorbell(&cpu, &bridge).unwrap();
+        gdb.connection.flush().unwrap();
+        assert_eq!(bridge.peek(0x4000_0000).unwrap(), 0);
+        assert_eq!(sent(&mut from_server), None);
+    }
+
+    #[test]
+    fn breakpoints_have_to_stick() {
+        let (mut gdb, cpu, bridge, _, _) = server(&[]);
+        let mut hart = MockHart::new(0x4000_0100, 0);
+        hart.read_only.push(0x2000_0000..0x2000_1000);
+        hart.attach(&bridge);
+        // addi x5, x5, 1
+        bridge.poke(0x4000_0100, 0x0012_8293).unwrap();
+        bridge.poke(0x2000_0000, 0x0012_8293).unwrap();
+
+        assert_eq!(
+            gdb.insert_soft_breakpoint(&cpu, &bridge, 0x4000_0100, 4)
+                .unwrap(),
+            Patch::Patched
+        );
+        assert_eq!(bridge.peek(0x4000_0100).unwrap(), 0x0010_0073);
+        assert_eq!(
+            gdb.insert_soft_breakpoint(&cpu, &bridge, 0x2000_0000, 4)
+                .unwrap(),
+            Patch::Unwritable
+        );
+        assert_eq!(bridge.peek(0x2000_0000).unwrap(), 0x0012_8293);
+        assert_eq!(
+            gdb.insert_soft_breakpoint(&cpu, &bridge, 0x4000_0104, 3)
+                .unwrap(),
+            Patch::BadKind
+        );
+        assert_eq!(
+            gdb.patched,
+            vec![(0x4000_0100, 0x0012_8293u32.to_le_bytes().to_vec())]
+        );
+    }
+
+    #[test]
+    fn polled_watches_come_out() {
+        let (mut gdb, cpu, bridge, mut from_server, mut to_server) = server(&[]);
+        MockHart::new(0x4000_0100, 0).attach(&bridge);
+
+        // With no triggers the write watch is polled for
+        let reply = gdb
+            .add_breakpoint(&cpu, &bridge, BreakPointType::WatchWrite, 0x4000_0000, 4)
+            .unwrap();
+        assert_eq!(reply, "OK");
+        assert!(gdb
+            .process_monitor(&cpu, &bridge, "watch")
+            .contains("(for GDB)"));
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "watch clear 1"),
+            "no watch 1\n"
+        );
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "watch clear x"),
+            "no watch x\n"
+        );
+
+        to_server.write_all(&frame(b"z2,40000000,4")).unwrap();
+        gdb.process(&cpu, &bridge).unwrap();
+        gdb.connection.flush().unwrap();
+        let mut ack = [0; 1];
+        from_server.read_exact(&mut ack).unwrap();
+        assert_eq!(ack, *b"+");
+        assert_eq!(sent(&mut from_server), Some(b"OK".to_vec()));
+        assert!(gdb.poll_watches.is_empty());
+        assert!(gdb.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn bad_packets_are_answered() {
+        let (mut gdb, cpu, bridge, mut from_server, mut to_server) = server(&[]);
+        MockHart::new(0x4000_0100, 0).attach(&bridge);
+
+        // A packet we know that doesn't parse gets an error, and the
+        // session goes on to the next one.  A breakpoint type we don't know
+        // gets an empty reply, so GDB stops asking for it.
+        for packet in [&b"m40000000"[..], b"Z0", b"Z9,40000000,4", b"z9,40000000,4"] {
+            to_server.write_all(&frame(packet)).unwrap();
+        }
+        gdb.process(&cpu, &bridge).unwrap();
+        gdb.process(&cpu, &bridge).unwrap();
+        gdb.connection.flush().unwrap();
+        let mut ack = [0; 1];
+        for reply in [&b"E01"[..], b"E01", b"", b""] {
+            from_server.read_exact(&mut ack).unwrap();
+            assert_eq!(ack, *b"+");
+            assert_eq!(sent(&mut from_server), Some(reply.to_vec()));
+        }
+        assert!(gdb.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn non_stop_stops_are_notified() {
+        let (mut gdb, cpu, bridge, mut from_server, mut to_server) = server(&[]);
+        let hart = MockHart::new(0x4000_0100, 0).attach(&bridge);
+        let mut request = |gdb: &mut GdbServer, from_server: &mut PipeReader, packet: &[u8]| {
+            to_server.write_all(&frame(packet)).unwrap();
+            gdb.process(&cpu, &bridge).unwrap();
+            gdb.connection.flush().unwrap();
+            let mut ack = [0; 1];
+            from_server.read_exact(&mut ack).unwrap();
+            assert_eq!(ack, *b"+");
+            sent(from_server).unwrap()
+        };
+
+        assert_eq!(request(&mut gdb, &mut from_server, b"QNonStop:1"), b"OK");
+        assert_eq!(request(&mut gdb, &mut from_server, b"vCont;c"), b"OK");
+        assert!(!hart.lock().unwrap().halted);
+
+        // A breakpoint is notified once, and collected with vStopped
+        {
+            let mut hart = hart.lock().unwrap();
+            hart.halted = true;
+            hart.halted_by_break = true;
+        }
+        gdb.process(&cpu, &bridge).unwrap();
+        gdb.connection.flush().unwrap();
+        assert_eq!(sent(&mut from_server).unwrap(), b"Stop:T05thread:1;");
+        assert_eq!(request(&mut gdb, &mut from_server, b"vStopped"), b"OK");
+        assert!(!gdb.awaiting_stop);
+
+        // vCtrlC is answered straight away, and the stop comes after
+        assert_eq!(request(&mut gdb, &mut from_server, b"vCont;c"), b"OK");
+        assert_eq!(request(&mut gdb, &mut from_server, b"vCtrlC"), b"OK");
+        let stop = sent(&mut from_server).unwrap();
+        assert!(stop.starts_with(b"Stop:T"), "{:?}", String::from_utf8_lossy(&stop));
+        assert!(stop.ends_with(b"thread:1;"));
+        assert!(hart.lock().unwrap().halted);
+        assert_eq!(request(&mut gdb, &mut from_server, b"vStopped"), b"OK");
+
+        // With nothing running there's nothing to stop, or to hear about
+        assert_eq!(request(&mut gdb, &mut from_server, b"vCtrlC"), b"OK");
+        assert_eq!(sent(&mut from_server), None);
+        assert_eq!(request(&mut gdb, &mut from_server, b"?"), b"T02thread:1;");
+        assert_eq!(request(&mut gdb, &mut from_server, b"vStopped"), b"OK");
+    }
+
+    #[test]
+    fn only_a_hangup_resumes() {
+        let ends = [
+            (
+                GdbServerError::BridgeError(BridgeError::NotConnected),
+                true,
+                true,
+            ),
+            (
+                GdbServerError::CpuError(RiscvCpuError::InvalidRegister(99)),
+                false,
+                true,
+            ),
+            (GdbServerError::ConnectionClosed, false, false),
+        ];
+        for (e, patched, halted) in ends {
+            let (mut gdb, cpu, bridge, _, _) = server(&[]);
+            let hart = MockHart::new(0x4000_0100, 0).attach(&bridge);
+            // addi x5, x5, 1, and a loop to run in once resumed
+            bridge.poke(0x4000_0100, 0x0012_8293).unwrap();
+            bridge.poke(0x4000_0104, 0x0000_006f).unwrap();
+            gdb.add_breakpoint(&cpu, &bridge, BreakPointType::BreakSoft, 0x4000_0100, 4)
+                .unwrap();
+
+            gdb.end_session(&cpu, &bridge, &e);
+            let word = bridge.peek(0x4000_0100).unwrap();
+            assert_eq!(word == 0x0010_0073, patched, "after {:?}", e);
+            assert_eq!(hart.lock().unwrap().halted, halted, "after {:?}", e);
+        }
+    }
+
+    #[test]
+    fn restored_breakpoints_are_put_back() {
+        let path = std::env::temp_dir().join(format!("session-test-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let (mut gdb, cpu, bridge, _, _) = server(&[]);
+        let mut hart = MockHart::new(0x4000_0100, 0);
+        hart.read_only.push(0x2000_0000..0x2000_1000);
+        let hart = hart.attach(&bridge);
+        bridge.poke(0x4000_0100, 0x0012_8293).unwrap();
+
+        let reply = gdb
+            .add_breakpoint(&cpu, &bridge, BreakPointType::BreakSoft, 0x4000_0100, 4)
+            .unwrap();
+        assert_eq!(reply, "OK");
+        assert!(gdb
+            .process_monitor(&cpu, &bridge, &format!("save-session {}", path))
+            .starts_with("Saved session with 1 breakpoints"));
+        gdb.end_session(&cpu, &bridge, &GdbServerError::ConnectionClosed);
+        assert_eq!(bridge.peek(0x4000_0100).unwrap(), 0x0012_8293);
+
+        let mut session = Session::load(path).unwrap();
+        let _ = std::fs::remove_file(path);
+        // One that can't go back in any more, and one of a type that
+        // doesn't exist
+        session.breakpoints.push(Breakpoint {
+            kind: BreakPointType::BreakSoft as u32,
+            addr: 0x2000_0000,
+            len: 4,
+        });
+        session.breakpoints.push(Breakpoint {
+            kind: 9,
+            addr: 0x4000_0200,
+            len: 4,
+        });
+        hart.lock().unwrap().halted = false;
+        let (mut gdb, cpu, _, _, _) = server_with_session(&[], Some(&session));
+        gdb.install_breakpoints(&cpu, &bridge).unwrap();
+        assert_eq!(bridge.peek(0x4000_0100).unwrap(), 0x0010_0073);
+        assert_eq!(bridge.peek(0x2000_0000).unwrap(), 0);
+        assert_eq!(gdb.breakpoints, vec![session.breakpoints[0]]);
+        assert!(!hart.lock().unwrap().halted);
+    }
+
+    #[test]
+    fn memory_map_comes_from_csr_csv() {
+        let path = std::env::temp_dir().join(format!("csr-test-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "csr_register,ctrl_scratch,0xf0000004,1,rw\n\
+             csr_register,timer0_load,0xf0002800,2,rw\n\
+             memory_region,rom,0x00000000,0x00008000,cached\n\
+             memory_region,main_ram,0x40000000,0x01000000,cached\n\
+             memory_region,csr,0xf0000000,0x00010000,io\n",
+        )
+        .unwrap();
+        let ask = |args: &[&str]| {
+            let (mut gdb, cpu, bridge, mut from_server, mut to_server) = server(args);
+            MockHart::new(0x4000_0100, 0).attach(&bridge);
+            to_server.write_all(&frame(b"qXfer:memory-map:read::0,fff")).unwrap();
+            gdb.process(&cpu, &bridge).unwrap();
+            gdb.connection.flush().unwrap();
+            let mut ack = [0; 1];
+            from_server.read_exact(&mut ack).unwrap();
+            (gdb, cpu, bridge, sent(&mut from_server).unwrap())
+        };
+
+        // Without a memory map GDB gets none at all, rather than an empty
+        // one that would keep it away from every address
+        assert_eq!(ask(&[]).3, b"");
+
+        let (mut gdb, cpu, bridge, reply) = ask(&["--csr-csv", path.to_str().unwrap()]);
+        std::fs::remove_file(&path).unwrap();
+        let reply = String::from_utf8(rle_decode(&reply)).unwrap();
+        assert!(reply.starts_with("l<?xml"), "{}", reply);
+        let regions: Vec<&str> = reply.lines().filter(|l| l.starts_with("<memory ")).collect();
+        assert_eq!(
+            regions,
+            [
+                "<memory type=\"rom\" start=\"0x0\" length=\"0x8000\"/>",
+                "<memory type=\"ram\" start=\"0x40000000\" length=\"0x1000000\"/>",
+                "<memory type=\"ram\" start=\"0xf0000000\" length=\"0x10000\"/>",
+            ]
+        );
+
+        // Registers can be named, and addresses given instead
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "write timer0_load 0x100000002"),
+            "timer0_load = 0x100000002\n"
+        );
+        assert_eq!(bridge.peek(0xf000_2800).unwrap(), 1);
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "read 0xf0002804"),
+            "f0002804: 0x00000002\n"
+        );
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "write 0xf0000004 timer0_load + 4"),
+            "f0000004: 0xf0002804\n"
+        );
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "read ctrl_scratch"),
+            "ctrl_scratch = 0xf0002804\n"
+        );
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "read"),
+            "usage: read <register|address>\n"
+        );
+    }
+
+    #[test]
+    fn bootmode_reboots() {
+        let path = std::env::temp_dir().join(format!("boot-test-{}.csv", std::process::id()));
+        std::fs::write(
+            &path,
+            "csr_register,ctrl_reset,0xf0000000,1,rw\n\
+             csr_register,ctrl_boot_address,0xf0000010,1,rw\n\
+             constant,flash_boot_address,0x20100000,,\n\
+             memory_region,rom,0x00000000,0x00008000,cached\n\
+             memory_region,spiflash,0x20000000,0x01000000,cached\n\
+             memory_region,main_ram,0x40000000,0x01000000,cached\n",
+        )
+        .unwrap();
+        let csv = path.to_str().unwrap();
+        let args = ["--csr-csv", csv, "--boot-register", "ctrl_boot_address"];
+        let (mut gdb, cpu, bridge, _, _) = server(&args);
+        std::fs::remove_file(&path).unwrap();
+        MockHart::new(0x4000_0100, 0).attach(&bridge);
+        bridge.poke(0x4000_0100, 0x0012_8293).unwrap();
+
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "bootmode"),
+            "Boots from serial at 00000000\n"
+        );
+        for args in ["bootmode jtag", "bootmode flash serial"] {
+            assert_eq!(
+                gdb.process_monitor(&cpu, &bridge, args),
+                "usage: bootmode [flash|serial|netboot]\n"
+            );
+        }
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "bootmode netboot"),
+            "no address for netboot boot; give one with --boot-register\n"
+        );
+
+        // The breakpoint comes out before the reset, and GDB's list of
+        // them is kept for it to put back
+        let reply = gdb
+            .add_breakpoint(&cpu, &bridge, BreakPointType::BreakSoft, 0x4000_0100, 4)
+            .unwrap();
+        assert_eq!(reply, "OK");
+        assert_eq!(
+            gdb.process_monitor(&cpu, &bridge, "bootmode flash"),
+            "Rebooting to boot from flash at 20100000\n"
+        );
+        assert_eq!(bridge.peek(0xf000_0010).unwrap(), 0x2010_0000);
+        assert_eq!(bridge.peek(0xf000_0000).unwrap(), 1);
+        assert_eq!(bridge.peek(0x4000_0100).unwrap(), 0x0012_8293);
+        assert_eq!(gdb.breakpoints.len(), 1);
+    }
 }
@@ -0,0 +1,147 @@
+//! A GDB command file for the SoC being debugged, written with
+//! `--emit-gdbinit`.  Sourcing it with `gdb -x` connects to the adapter,
+//! tells GDB which regions may be cached and which are peripherals that
+//! have to be read a word at a time every time, and names the SoC's
+//! addresses as convenience variables so `x/x $ctrl_scratch` works.
+
+use std::fmt::Write;
+
+use super::board::{MemoryKind, MemoryRegion};
+use super::csr_map::CsrMap;
+use super::riscv::register_number;
+use super::utils::parse_u32;
+
+/// The command file's text.  `target` is what to give `target remote`,
+/// if GDB should connect as soon as the file is read.
+pub fn generate(
+    regions: &[MemoryRegion],
+    csr_map: Option<&CsrMap>,
+    target: Option<&str>,
+) -> String {
+    let mut out = String::new();
+    out.push_str(
+        "# Written with --emit-gdbinit for the SoC being debugged; load it with \"gdb -x\"\n\n",
+    );
+
+    if !regions.is_empty() {
+        // Once there are regions, GDB refuses to touch anything outside
+        // them, which would hide memory the map doesn't know about
+        out.push_str("set mem inaccessible-by-default off\n");
+        for region in regions {
+            let attributes = match region.kind {
+                MemoryKind::Ram => "rw cache",
+                MemoryKind::Rom | MemoryKind::Flash => "ro cache",
+                // Registers can change under GDB and may not like being
+                // read a byte at a time
+                MemoryKind::Io => "rw 32 nocache",
+            };
+            let _ = writeln!(
+                out,
+                "mem 0x{:08x} 0x{:08x} {}",
+                region.base,
+                region.base as u64 + region.size as u64,
+                attributes
+            );
+        }
+        out.push('\n');
+    }
+
+    let mut variables = vec![];
+    for region in regions {
+        variables.push((format!("{}_base", region.name), region.base));
+        variables.push((format!("{}_size", region.name), region.size));
+    }
+    if let Some(map) = csr_map {
+        for (name, base) in &map.bases {
+            variables.push((format!("{}_base", name), *base));
+        }
+        for (name, reg) in &map.registers {
+            variables.push((name.clone(), reg.addr));
+        }
+        for (name, value) in &map.constants {
+            if let Ok(value) = parse_u32(value) {
+                variables.push((name.clone(), value));
+            }
+        }
+    }
+    variables.sort();
+    variables.dedup_by(|a, b| a.0 == b.0);
+    for (name, value) in &variables {
+        // $pc and friends are the registers themselves, and setting them
+        // would change the target
+        if register_number(name).is_none() {
+            let _ = writeln!(out, "set ${} = 0x{:x}", name, value);
+        }
+    }
+    if !variables.is_empty() {
+        out.push('\n');
+    }
+
+    if csr_map.is_some() {
+        out.push_str(CSR_COMMANDS);
+        out.push('\n');
+    }
+
+    if let Some(target) = target {
+        let _ = writeln!(out, "target remote {}", target);
+    }
+    out
+}
+
+/// Read and write CSRs by name through `monitor`, which knows how LiteX
+/// splits wide ones across several words
+const CSR_COMMANDS: &str = "define csr_read
+  monitor read $arg0
+end
+document csr_read
+Read a CSR by its name in csr.csv: csr_read ctrl_scratch
+end
+
+define csr_write
+  monitor write $arg0 $arg1
+end
+document csr_write
+Write a CSR by its name in csr.csv: csr_write ctrl_scratch 0x1234
+end
+";
+
+#[cfg(test)]
+mod test {
+    use super::generate;
+    use crate::csr_map::CsrMap;
+
+    #[test]
+    fn command_file() {
+        let map = CsrMap::parse(
+            "csr_base,ctrl,0xf0000000,,\n\
+             csr_register,ctrl_scratch,0xf0000004,1,rw\n\
+             csr_register,pc,0xf0000100,1,rw\n\
+             constant,config_clock_frequency,48000000,,\n\
+             constant,config_cpu_type,vexriscv,,\n\
+             memory_region,sram,0x10000000,0x2000,cached\n\
+             memory_region,csr,0xf0000000,0x10000,io\n",
+        )
+        .unwrap();
+        let file = generate(&map.memory_map(), Some(&map), Some("127.0.0.1:3333"));
+        for line in &[
+            "set mem inaccessible-by-default off",
+            "mem 0x10000000 0x10002000 rw cache",
+            "mem 0xf0000000 0xf0010000 rw 32 nocache",
+            "set $ctrl_scratch = 0xf0000004",
+            "set $ctrl_base = 0xf0000000",
+            "set $sram_size = 0x2000",
+            "set $config_clock_frequency = 0x2dc6c00",
+            "define csr_read",
+            "target remote 127.0.0.1:3333",
+        ] {
+            assert!(
+                file.lines().any(|l| l == *line),
+                "no {:?} in\n{}",
+                line,
+                file
+            );
+        }
+        assert!(!file.contains("$pc"));
+        assert!(!file.contains("vexriscv"));
+    }
+}
@@ -0,0 +1,183 @@
+//! The most recent bridge transactions, kept in memory with `--postmortem`
+//! whether or not anything is being logged.  When the adapter hits an
+//! error it can't recover from, they're written to the file it names, so
+//! that an intermittent failure comes with what led up to it.
+
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, Write};
+use std::panic;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::bridge::BridgeError;
+use super::config::Config;
+use super::logging;
+use super::trace::Access;
+
+struct Entry {
+    at: Instant,
+    access: Access,
+
+    /// How long the transaction took.  The ones after the first in a batch
+    /// went in the same round trip and have no time of their own.
+    took: Option<Duration>,
+
+    /// What went wrong, for a transaction that failed
+    error: Option<String>,
+}
+
+struct History {
+    entries: VecDeque<Entry>,
+    depth: usize,
+    path: String,
+}
+
+static HISTORY: Mutex<Option<History>> = Mutex::new(None);
+
+/// Start keeping the history if the config asks for it with
+/// `--postmortem`.  A panic anywhere after this also writes it out.
+pub fn init(cfg: &Config) {
+    let path = match cfg.postmortem {
+        Some(ref path) if cfg.postmortem_depth > 0 => path.clone(),
+        _ => return,
+    };
+    *HISTORY.lock().unwrap() = Some(History {
+        entries: VecDeque::with_capacity(cfg.postmortem_depth),
+        depth: cfg.postmortem_depth,
+        path,
+    });
+    let report = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        report(info);
+        dump(&info.to_string());
+    }));
+}
+
+/// Add a finished transaction, pushing out the oldest once there are enough
+pub fn record(access: Access, took: Option<Duration>, error: Option<&BridgeError>) {
+    let mut history = HISTORY.lock().unwrap_or_else(|e| e.into_inner());
+    let history = match *history {
+        Some(ref mut h) => h,
+        None => return,
+    };
+    if history.entries.len() == history.depth {
+        history.entries.pop_front();
+    }
+    history.entries.push_back(Entry {
+        at: Instant::now(),
+        access,
+        took,
+        error: error.map(|e| format!("{:?}", e)),
+    });
+}
+
+/// Write the history out, saying what went wrong.  This is the last thing
+/// that happens before giving up, so failing to write it is only reported.
+pub fn dump(reason: &str) {
+    // A panic while the history was being added to would deadlock here
+    let history = match HISTORY.try_lock() {
+        Ok(history) => history,
+        Err(_) => return,
+    };
+    let history = match *history {
+        Some(ref h) if !h.entries.is_empty() => h,
+        _ => return,
+    };
+    let result = File::create(&history.path)
+        .and_then(|mut file| write_entries(&mut file, &history.entries, Instant::now(), reason));
+    match result {
+        Ok(()) => ui_info!(
+            "The last {} bridge transactions are in {}",
+            history.entries.len(),
+            history.path
+        ),
+        Err(e) => ui_error!("Couldn't write {}: {}", history.path, e),
+    }
+}
+
+fn write_entries(
+    out: &mut impl Write,
+    entries: &VecDeque<Entry>,
+    now: Instant,
+    reason: &str,
+) -> io::Result<()> {
+    // A panic's message runs onto a second line
+    let reason = reason.replace('\n', " ");
+    writeln!(out, "# Bridge transactions leading up to: {}", reason)?;
+    writeln!(out, "# Written at {}", logging::timestamp())?;
+    writeln!(out, "# seconds-before dir address value took")?;
+    for entry in entries {
+        let before = now.saturating_duration_since(entry.at).as_secs_f64();
+        let (dir, addr, value) = match entry.access {
+            Access::Read(addr, value) => ("R", addr, format!("{:08x}", value)),
+            Access::Write(addr, value) => ("W", addr, format!("{:08x}", value)),
+            Access::Error(addr, write) => {
+                let error = entry.error.as_deref().unwrap_or("failed");
+                (
+                    if write { "W" } else { "R" },
+                    addr,
+                    format!("error: {}", error),
+                )
+            }
+        };
+        let took = match entry.took {
+            Some(took) => format!("{:.3} ms", took.as_secs_f64() * 1000.0),
+            None => "batched".to_owned(),
+        };
+        writeln!(
+            out,
+            "-{:.6} {} {:08x} {} {}",
+            before, dir, addr, value, took
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_entries, Entry};
+    use crate::trace::Access;
+    use std::collections::VecDeque;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn entries_written_oldest_first() {
+        let now = Instant::now();
+        let entry = |before_ms, access, took, error: Option<&str>| Entry {
+            at: now - Duration::from_millis(before_ms),
+            access,
+            took,
+            error: error.map(|e| e.to_owned()),
+        };
+        let entries: VecDeque<Entry> = vec![
+            entry(
+                20,
+                Access::Read(0xf00f0000, 2),
+                Some(Duration::from_micros(250)),
+                None,
+            ),
+            entry(10, Access::Write(0xf00f0004, 0x13), None, None),
+            entry(
+                0,
+                Access::Error(0xf00f0008, false),
+                Some(Duration::from_millis(500)),
+                Some("Timeout"),
+            ),
+        ]
+        .into();
+        let mut out = vec![];
+        write_entries(&mut out, &entries, now, "testing").unwrap();
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            lines,
+            [
+                "-0.020000 R f00f0000 00000002 0.250 ms",
+                "-0.010000 W f00f0004 00000013 batched",
+                "-0.000000 R f00f0008 error: Timeout 500.000 ms",
+            ]
+        );
+        assert!(text.starts_with("# Bridge transactions leading up to: testing\n"));
+    }
+}
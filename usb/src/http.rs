@@ -0,0 +1,488 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpListener;
+use std::time::Duration;
+
+use super::bridge::{Bridge, BridgeError};
+use super::config::Config;
+use super::csr_map::{CsrMap, CsrMapError};
+use super::events::{self, TargetEvent};
+use super::framebuffer::Framebuffer;
+use super::lock;
+use super::riscv::RiscvCpu;
+use super::target::{RunState, SharedTargetState};
+use super::utils::{parse_u32, parse_u64};
+
+/// Largest request body we'll accept.  Bodies only ever hold a number.
+const MAX_BODY_LEN: usize = 64;
+
+/// How long a client gets to send its request.  Requests are answered
+/// one at a time, so one that never finishes would hold up every other.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A tiny HTTP/1.1 server so scripts can poke the target with nothing more
+/// than `curl`:
+///
+///   GET  /mem/<addr>            read a word
+///   PUT  /mem/<addr>            write the word in the body
+///   GET  /csr/<name>            read a register named in csr.csv
+///   PUT  /csr/<name>            write a register named in csr.csv
+///   POST /cpu/halt              halt the CPU
+///   POST /cpu/resume            let the CPU run
+///   GET  /framebuffer.png       the framebuffer given with --framebuffer
+///   GET  /framebuffer           a page showing it, refreshed as fast as
+///                               it can be read
+///
+/// Every connection carries exactly one request.  That's why the
+/// framebuffer page polls for a new PNG rather than being sent a stream:
+/// a stream would hold the server, and with it every other request, for
+/// as long as the page stayed open.
+pub struct HttpServer {
+    listener: TcpListener,
+    csr_map: Option<CsrMap>,
+    target: SharedTargetState,
+    framebuffer: Option<Framebuffer>,
+
+    /// Turn off Nagle's algorithm on each connection
+    nodelay: bool,
+    request_timeout: Duration,
+}
+
+#[derive(Debug)]
+pub enum HttpServerError {
+    /// An error with TCP
+    IoError(io::Error),
+
+    /// The request wasn't HTTP we understand
+    BadRequest(String),
+
+    /// The csr.csv couldn't be loaded
+    CsrMapError(CsrMapError),
+}
+
+impl std::convert::From<io::Error> for HttpServerError {
+    fn from(e: io::Error) -> HttpServerError {
+        HttpServerError::IoError(e)
+    }
+}
+
+impl std::convert::From<CsrMapError> for HttpServerError {
+    fn from(e: CsrMapError) -> HttpServerError {
+        HttpServerError::CsrMapError(e)
+    }
+}
+
+impl fmt::Display for HttpServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpServerError::IoError(e) => write!(f, "{}", e),
+            HttpServerError::BadRequest(why) => write!(f, "bad request: {}", why),
+            HttpServerError::CsrMapError(e) => write!(f, "csr.csv: {}", e),
+        }
+    }
+}
+
+/// A parsed request line plus its body
+struct Request {
+    method: String,
+    path: String,
+    body: String,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn ok(body: String) -> Response {
+        Response {
+            status: 200,
+            content_type: "text/plain",
+            body: body.into_bytes(),
+        }
+    }
+
+    fn png(body: Vec<u8>) -> Response {
+        Response {
+            status: 200,
+            content_type: "image/png",
+            body,
+        }
+    }
+
+    fn html(body: String) -> Response {
+        Response {
+            status: 200,
+            content_type: "text/html",
+            body: body.into_bytes(),
+        }
+    }
+
+    fn no_content() -> Response {
+        Response {
+            status: 204,
+            content_type: "text/plain",
+            body: vec![],
+        }
+    }
+
+    fn error(status: u16, msg: impl fmt::Display) -> Response {
+        Response {
+            status,
+            content_type: "text/plain",
+            body: format!("{}\n", msg).into_bytes(),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            204 => "No Content",
+            400 => "Bad Request",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            500 => "Internal Server Error",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            _ => "Error",
+        }
+    }
+}
+
+impl std::convert::From<BridgeError> for Response {
+    fn from(e: BridgeError) -> Response {
+        match e {
+            BridgeError::ResourceBusy(why) => Response::error(503, why),
+            e => Response::error(502, format!("bridge error: {:?}", e)),
+        }
+    }
+}
+
+impl std::convert::From<CsrMapError> for Response {
+    fn from(e: CsrMapError) -> Response {
+        match e {
+            CsrMapError::UnknownRegister(_) => Response::error(404, e),
+            CsrMapError::BridgeError(e) => e.into(),
+            e => Response::error(500, e),
+        }
+    }
+}
+
+impl HttpServer {
+    pub fn new(cfg: &Config, target: SharedTargetState) -> Result<HttpServer, HttpServerError> {
+        let csr_map = match &cfg.csr_csv {
+            Some(path) => Some(CsrMap::load(path)?),
+            None => None,
+        };
+        let listener = TcpListener::bind(format!("{}:{}", cfg.bind_addr, cfg.bind_port))?;
+        ui_info!("Serving HTTP on {}:{}", cfg.bind_addr, cfg.bind_port);
+        Ok(HttpServer {
+            listener,
+            csr_map,
+            target,
+            framebuffer: cfg.framebuffer,
+            nodelay: cfg.tuning.nodelay,
+            request_timeout: REQUEST_TIMEOUT,
+        })
+    }
+
+    /// Accept one connection and answer its request
+    pub fn process(&mut self, cpu: &RiscvCpu, bridge: &Bridge) -> Result<(), HttpServerError> {
+        let (mut connection, sockaddr) = self.listener.accept()?;
+        connection.set_nodelay(self.nodelay)?;
+        connection.set_read_timeout(Some(self.request_timeout))?;
+        let response = match read_request(&connection) {
+            Ok(request) => {
+                log_adapter!("HTTP {} {} from {}", request.method, request.path, sockaddr);
+                self.handle(cpu, bridge, &request)
+                    .unwrap_or_else(|response| response)
+            }
+            Err(HttpServerError::BadRequest(msg)) => Response::error(400, msg),
+            Err(e) => return Err(e),
+        };
+        write!(
+            connection,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            response.status,
+            response.reason(),
+            response.content_type,
+            response.body.len(),
+        )?;
+        connection.write_all(&response.body)?;
+        Ok(())
+    }
+
+    fn handle(
+        &self,
+        cpu: &RiscvCpu,
+        bridge: &Bridge,
+        request: &Request,
+    ) -> Result<Response, Response> {
+        // A query string is only ever there to get past a browser's cache
+        let path = request.path.split('?').next().unwrap_or("");
+        let mut parts = path.trim_matches('/').splitn(2, '/');
+        let resource = parts.next().unwrap_or("");
+        let arg = parts.next().unwrap_or("");
+        let method = request.method.as_str();
+        if matches!((resource, method), ("mem" | "csr" | "cpu", "PUT" | "POST")) {
+            self.target.write().unwrap().changed();
+        }
+
+        match (resource, method) {
+            ("mem", "GET") => {
+                let addr = parse_number(arg)?;
+                let _lock = lock::read_flash(addr, 4)?;
+                Ok(Response::ok(format!("0x{:08x}\n", bridge.peek(addr)?)))
+            }
+            ("mem", "PUT") | ("mem", "POST") => {
+                let addr = parse_number(arg)?;
+                bridge.poke(addr, parse_number(&request.body)?)?;
+                Ok(Response::no_content())
+            }
+            ("csr", "GET") => {
+                let value = self.csr_map()?.read_register(bridge, arg)?;
+                Ok(Response::ok(format!("0x{:x}\n", value)))
+            }
+            ("csr", "PUT") | ("csr", "POST") => {
+                let value = parse_u64(request.body.trim())
+                    .map_err(|_| Response::error(400, "expected a number in the body"))?;
+                self.csr_map()?.write_register(bridge, arg, value)?;
+                Ok(Response::no_content())
+            }
+            ("cpu", "PUT") | ("cpu", "POST") => match arg {
+                "halt" => {
+                    cpu.halt(bridge)?;
+                    self.target.write().unwrap().run_state = RunState::Halted;
+                    for hart in 0..cpu.hart_count() {
+                        events::halted(cpu, bridge, hart);
+                    }
+                    Ok(Response::no_content())
+                }
+                "resume" => {
+                    cpu.resume(bridge)?;
+                    self.target.write().unwrap().run_state = RunState::Running;
+                    events::emit(TargetEvent::Resumed);
+                    Ok(Response::no_content())
+                }
+                _ => Err(Response::error(404, "no such CPU action")),
+            },
+            ("framebuffer.png", "GET") => {
+                let fb = self.framebuffer()?;
+                Ok(Response::png(fb.grab_png(bridge)?))
+            }
+            ("framebuffer", "GET") => Ok(Response::html(format!(
+                "<!DOCTYPE html>\n<title>{}</title>\n<img id=\"fb\" src=\"/framebuffer.png\">\n<script>\n\
+                 const fb = document.getElementById(\"fb\");\n\
+                 fb.onload = fb.onerror = () => setTimeout(() => fb.src = \"/framebuffer.png?\" + Date.now(), 100);\n\
+                 </script>\n",
+                self.framebuffer()?
+            ))),
+            ("mem", _) | ("csr", _) | ("cpu", _) | ("framebuffer.png", _) | ("framebuffer", _) => {
+                Err(Response::error(405, "method not allowed"))
+            }
+            _ => Err(Response::error(404, "not found")),
+        }
+    }
+
+    fn framebuffer(&self) -> Result<&Framebuffer, Response> {
+        self.framebuffer
+            .as_ref()
+            .ok_or_else(|| Response::error(404, "no framebuffer was given (see --framebuffer)"))
+    }
+
+    fn csr_map(&self) -> Result<&CsrMap, Response> {
+        self.csr_map
+            .as_ref()
+            .ok_or_else(|| Response::error(404, "no csr.csv was loaded (see --csr-csv)"))
+    }
+}
+
+fn parse_number(s: &str) -> Result<u32, Response> {
+    parse_u32(s.trim()).map_err(|_| Response::error(400, format!("not a number: {}", s.trim())))
+}
+
+/// Read the request line, skip past the headers and pick up the body
+fn read_request(connection: impl Read) -> Result<Request, HttpServerError> {
+    let mut reader = BufReader::new(connection);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut words = line.split_whitespace();
+    let (method, path) = match (words.next(), words.next()) {
+        (Some(method), Some(path)) => (method.to_owned(), path.to_owned()),
+        _ => {
+            return Err(HttpServerError::BadRequest(
+                "malformed request line".to_owned(),
+            ))
+        }
+    };
+
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            break;
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| HttpServerError::BadRequest("bad Content-Length".to_owned()))?;
+            }
+        }
+    }
+    if content_length > MAX_BODY_LEN {
+        return Err(HttpServerError::BadRequest("body too large".to_owned()));
+    }
+
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body)?;
+    Ok(Request {
+        method,
+        path,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{read_request, HttpServer, HttpServerError, Response};
+    use crate::bridge::{Bridge, BridgeError};
+    use crate::config::Config;
+    use crate::csr_map::CsrMap;
+    use crate::riscv::RiscvCpu;
+    use crate::target::TargetState;
+
+    fn server() -> (HttpServer, RiscvCpu, Bridge) {
+        let cfg = Config::from_args(["http", "--mock"]).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        let server = HttpServer {
+            listener: TcpListener::bind("127.0.0.1:0").unwrap(),
+            csr_map: Some(CsrMap::parse("csr_register,ctrl_scratch,0xf0000004,1,rw\n").unwrap()),
+            target: TargetState::new_shared(),
+            framebuffer: None,
+            nodelay: true,
+            request_timeout: Duration::from_millis(200),
+        };
+        (server, RiscvCpu::new(&cfg).unwrap(), bridge)
+    }
+
+    /// Send `request` and return the status line and body of the response
+    fn exchange(http: &mut HttpServer, cpu: &RiscvCpu, bridge: &Bridge, request: &str) -> String {
+        let addr = http.listener.local_addr().unwrap();
+        let request = request.to_owned();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        });
+        http.process(cpu, bridge).unwrap();
+        let response = client.join().unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        format!("{} {}", head.lines().next().unwrap(), body)
+    }
+
+    #[test]
+    fn memory_and_csrs() {
+        let (mut http, cpu, bridge) = server();
+        let mut request = |req: &str| exchange(&mut http, &cpu, &bridge, req);
+        assert_eq!(
+            request("PUT /mem/0x40000000 HTTP/1.1\r\nContent-Length: 6\r\n\r\n0x1234"),
+            "HTTP/1.1 204 No Content "
+        );
+        assert_eq!(
+            request("GET /mem/0x40000000 HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 200 OK 0x00001234\n"
+        );
+        assert_eq!(
+            request("PUT /csr/ctrl_scratch HTTP/1.1\r\nContent-Length: 2\r\n\r\n42"),
+            "HTTP/1.1 204 No Content "
+        );
+        assert_eq!(
+            request("GET /csr/ctrl_scratch?now HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 200 OK 0x2a\n"
+        );
+        assert!(request("GET /csr/nothing HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+        assert!(request("DELETE /mem/0 HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+        assert!(request("GET /mem/zz HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 400"));
+        assert!(request("nonsense\r\n\r\n").starts_with("HTTP/1.1 400"));
+        assert_eq!(bridge.peek(0xf000_0004).unwrap(), 42);
+    }
+
+    #[test]
+    fn only_writes_change_the_target() {
+        let (mut http, cpu, bridge) = server();
+        let before = http.target.read().unwrap().generation;
+        exchange(&mut http, &cpu, &bridge, "GET /mem/0 HTTP/1.1\r\n\r\n");
+        exchange(&mut http, &cpu, &bridge, "DELETE /mem/0 HTTP/1.1\r\n\r\n");
+        assert_eq!(http.target.read().unwrap().generation, before);
+        exchange(
+            &mut http,
+            &cpu,
+            &bridge,
+            "PUT /mem/0 HTTP/1.1\r\nContent-Length: 1\r\n\r\n1",
+        );
+        assert_ne!(http.target.read().unwrap().generation, before);
+    }
+
+    #[test]
+    fn stalled_requests_time_out() {
+        let (mut http, cpu, bridge) = server();
+        let addr = http.listener.local_addr().unwrap();
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /mem/0 HTTP/1.1\r\n").unwrap();
+        assert!(matches!(
+            http.process(&cpu, &bridge),
+            Err(HttpServerError::IoError(_))
+        ));
+        // The next client gets an answer
+        assert!(
+            exchange(&mut http, &cpu, &bridge, "GET /mem/0 HTTP/1.1\r\n\r\n")
+                .starts_with("HTTP/1.1 200")
+        );
+    }
+
+    #[test]
+    fn request_bodies() {
+        let request = read_request(
+            &b"POST /cpu/halt HTTP/1.1\r\nHost: x\r\ncontent-length: 3\r\n\r\nabcdef"[..],
+        )
+        .unwrap();
+        assert_eq!(
+            (&request.method[..], &request.path[..], &request.body[..]),
+            ("POST", "/cpu/halt", "abc")
+        );
+        for bad in [
+            &b"GET\r\n\r\n"[..],
+            b"PUT /mem/0 HTTP/1.1\r\nContent-Length: x\r\n\r\n",
+            b"PUT /mem/0 HTTP/1.1\r\nContent-Length: 65\r\n\r\n",
+        ] {
+            assert!(matches!(
+                read_request(bad),
+                Err(HttpServerError::BadRequest(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn busy_is_unavailable() {
+        let response = Response::from(BridgeError::ResourceBusy("flashing".to_owned()));
+        assert_eq!(response.status, 503);
+        assert_eq!(response.reason(), "Service Unavailable");
+        assert_eq!(Response::from(BridgeError::NotConnected).reason(), "Bad Gateway");
+    }
+}
@@ -0,0 +1,283 @@
+use std::fmt;
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+
+use super::bridge::{Bridge, BridgeError};
+use super::config::ConfigError;
+use super::mmu;
+
+/// Marks a manifest in flash
+const MANIFEST_MAGIC: &[u8; 4] = b"LXIM";
+const MANIFEST_VERSION: u32 = 1;
+
+/// Magic, version, image address and length, the image's SHA-256, then an
+/// HMAC-SHA256 of everything before it
+pub const MANIFEST_LENGTH: usize = 16 + 32 + 32;
+const MANIFEST_SIGNED_LENGTH: usize = 16 + 32;
+
+#[derive(Debug)]
+pub enum ImageError {
+    /// The bridge failed while reading the image back
+    BridgeError(BridgeError),
+
+    /// What's there doesn't hash to what was expected
+    Mismatch(ImageHash, ImageHash),
+
+    /// There's no manifest at the address given
+    NoManifest(u32),
+
+    /// The manifest wasn't signed with this key
+    BadSignature,
+}
+
+impl std::convert::From<BridgeError> for ImageError {
+    fn from(e: BridgeError) -> Self {
+        ImageError::BridgeError(e)
+    }
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::BridgeError(e) => write!(f, "bridge error: {:?}", e),
+            ImageError::Mismatch(want, got) => write!(f, "expected {}, got {}", want, got),
+            ImageError::NoManifest(addr) => write!(f, "no manifest at {:08x}", addr),
+            ImageError::BadSignature => write!(f, "manifest signature doesn't match the key"),
+        }
+    }
+}
+
+/// A checksum that an image is expected to have.  Given on the command
+/// line as `ALGORITHM:HEX`, e.g. `sha256:9f86d0...`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ImageHash {
+    Sha256([u8; 32]),
+    Crc32(u32),
+}
+
+impl ImageHash {
+    pub fn parse(spec: &str) -> Result<ImageHash, ConfigError> {
+        let error = || ConfigError::InvalidHashSpec(spec.to_owned());
+        let (algorithm, hex) = match spec.find(':') {
+            Some(idx) => (&spec[..idx], &spec[idx + 1..]),
+            None => return Err(error()),
+        };
+        let bytes = parse_hex(hex).ok_or_else(error)?;
+        match (algorithm, bytes.len()) {
+            ("sha256", 32) => {
+                let mut digest = [0; 32];
+                digest.copy_from_slice(&bytes);
+                Ok(ImageHash::Sha256(digest))
+            }
+            ("crc32", 4) => Ok(ImageHash::Crc32(u32::from_be_bytes([
+                bytes[0], bytes[1], bytes[2], bytes[3],
+            ]))),
+            _ => Err(error()),
+        }
+    }
+
+    /// The same kind of hash, of `data`
+    pub fn of(&self, data: &[u8]) -> ImageHash {
+        match self {
+            ImageHash::Sha256(_) => ImageHash::Sha256(sha256(data)),
+            ImageHash::Crc32(_) => ImageHash::Crc32(crc32(data)),
+        }
+    }
+
+    /// Check that `data` hashes to this
+    pub fn verify(&self, data: &[u8]) -> Result<(), ImageError> {
+        let actual = self.of(data);
+        if actual == *self {
+            Ok(())
+        } else {
+            Err(ImageError::Mismatch(self.clone(), actual))
+        }
+    }
+}
+
+impl fmt::Display for ImageHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageHash::Sha256(digest) => write!(f, "sha256:{}", to_hex(digest)),
+            ImageHash::Crc32(crc) => write!(f, "crc32:{:08x}", crc),
+        }
+    }
+}
+
+/// A record of what was loaded into flash, so that it can be checked in
+/// the field later on.  It's signed with HMAC-SHA256 using a key that the
+/// deployment scripts share, so a manifest can't simply be rewritten to
+/// match a different image.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub addr: u32,
+    pub len: u32,
+    pub sha256: [u8; 32],
+}
+
+impl Manifest {
+    pub fn new(addr: u32, image: &[u8]) -> Manifest {
+        Manifest {
+            addr,
+            len: image.len() as u32,
+            sha256: sha256(image),
+        }
+    }
+
+    pub fn to_bytes(&self, key: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(MANIFEST_LENGTH);
+        out.extend_from_slice(MANIFEST_MAGIC);
+        out.extend_from_slice(&MANIFEST_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.addr.to_le_bytes());
+        out.extend_from_slice(&self.len.to_le_bytes());
+        out.extend_from_slice(&self.sha256);
+        let signature = hmac_sha256(key, &out);
+        out.extend_from_slice(&signature);
+        out
+    }
+
+    /// Read the manifest stored at `addr` and check its signature
+    pub fn read(bridge: &Bridge, addr: u32, key: &[u8]) -> Result<Manifest, ImageError> {
+        let bytes = mmu::read_physical(bridge, addr, MANIFEST_LENGTH as u32)?;
+        let word = |offset: usize| {
+            u32::from_le_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        if &bytes[0..4] != MANIFEST_MAGIC || word(4) != MANIFEST_VERSION {
+            return Err(ImageError::NoManifest(addr));
+        }
+        let (signed, signature) = bytes.split_at(MANIFEST_SIGNED_LENGTH);
+        if !hmac_sha256_verify(key, signed, signature) {
+            return Err(ImageError::BadSignature);
+        }
+        let mut sha256 = [0; 32];
+        sha256.copy_from_slice(&bytes[16..48]);
+        Ok(Manifest {
+            addr: word(8),
+            len: word(12),
+            sha256,
+        })
+    }
+
+    /// Read the image the manifest describes back off the bus and check
+    /// that it's still what was loaded
+    pub fn verify(&self, bridge: &Bridge) -> Result<(), ImageError> {
+        let image = mmu::read_physical(bridge, self.addr, self.len)?;
+        ImageHash::Sha256(self.sha256).verify(&image)
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// CRC-32 as used by zlib and Ethernet
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32fast::hash(data)
+}
+
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+fn hmac(key: &[u8]) -> Hmac<Sha256> {
+    // HMAC takes a key of any length
+    Hmac::new_from_slice(key).unwrap()
+}
+
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    hmac(key).chain_update(data).finalize().into_bytes().into()
+}
+
+/// Check an HMAC-SHA256 signature in constant time, so how long it takes
+/// doesn't give away how much of it was right
+fn hmac_sha256_verify(key: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    hmac(key).chain_update(data).verify_slice(signature).is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sha256_known_answers() {
+        assert_eq!(
+            to_hex(&sha256(b"")),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+        assert_eq!(
+            to_hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // Two blocks once padded
+        assert_eq!(
+            to_hex(&sha256(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+    }
+
+    #[test]
+    fn hmac_sha256_known_answers() {
+        // RFC 4231 test cases 2 and 6
+        assert_eq!(
+            to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            to_hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn signatures_are_checked() {
+        let signature = hmac_sha256(b"key", b"manifest");
+        assert!(hmac_sha256_verify(b"key", b"manifest", &signature));
+        assert!(!hmac_sha256_verify(b"other key", b"manifest", &signature));
+        let mut flipped = signature;
+        flipped[31] ^= 1;
+        assert!(!hmac_sha256_verify(b"key", b"manifest", &flipped));
+        assert!(!hmac_sha256_verify(b"key", b"manifest", &signature[..16]));
+    }
+
+    #[test]
+    fn crc32_known_answer() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn hash_specs() {
+        let spec = "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let hash = ImageHash::parse(spec).unwrap();
+        assert!(hash.verify(b"abc").is_ok());
+        assert!(hash.verify(b"abd").is_err());
+        assert_eq!(hash.to_string(), spec);
+        assert!(ImageHash::parse("crc32:CBF43926")
+            .unwrap()
+            .verify(b"123456789")
+            .is_ok());
+        assert!(ImageHash::parse("sha256:abcd").is_err());
+        assert!(ImageHash::parse("md5:cbf43926").is_err());
+        assert!(ImageHash::parse("cbf43926").is_err());
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+
+use super::bridge::Bridge;
+use super::mmu::{self, MmuError};
+use super::utils::parse_u32;
+
+/// GDB thread IDs for kernel tasks are the task's PID plus this, which
+/// keeps them clear of the hart threads
+pub const TASK_TID_BASE: i64 = 0x10000;
+
+/// Longest task name, including the terminator
+const TASK_COMM_LEN: u32 = 16;
+
+/// Give up walking the task list after this many, in case it's corrupt
+const MAX_TASKS: usize = 4096;
+
+/// `struct printk_log` from kernels before 5.10: a u64 timestamp, then u16
+/// record, text and dict lengths, then facility and flags
+const PRINTK_HEADER_LENGTH: usize = 16;
+
+#[derive(Debug)]
+pub enum KernelError {
+    /// Couldn't read the symbols file
+    IoError(io::Error),
+
+    /// A line of the symbols file didn't make sense
+    ParseError(usize /* line */, String),
+
+    /// The symbols file left out something that's needed
+    MissingSymbol(&'static str),
+
+    /// Kernel memory couldn't be read
+    MmuError(MmuError),
+
+    /// A field at this offset from this address would be past the end of
+    /// memory, which a corrupt task list can point at
+    OutOfRange(u32 /* base */, u32 /* offset */),
+}
+
+impl std::convert::From<io::Error> for KernelError {
+    fn from(e: io::Error) -> Self {
+        KernelError::IoError(e)
+    }
+}
+
+impl std::convert::From<MmuError> for KernelError {
+    fn from(e: MmuError) -> Self {
+        KernelError::MmuError(e)
+    }
+}
+
+impl fmt::Display for KernelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KernelError::IoError(e) => write!(f, "{}", e),
+            KernelError::ParseError(line, text) => write!(f, "line {}: {}", line, text),
+            KernelError::MissingSymbol(name) => write!(f, "no {} in the kernel symbols", name),
+            KernelError::MmuError(e) => write!(f, "{}", e),
+            KernelError::OutOfRange(base, offset) => {
+                write!(f, "{:08x} + {:x} is past the end of memory", base, offset)
+            }
+        }
+    }
+}
+
+/// Where things are in the running kernel.  These come from a text file
+/// with one `name value` pair per line, which can be pulled out of a
+/// `vmlinux` with GDB:
+///
+///   init_task    0xc0a01e40   # print &init_task
+///   task.tasks   0x1d8        # print &((struct task_struct *)0)->tasks
+///   task.pid     0x2a8        # ... ->pid
+///   task.comm    0x4a4        # ... ->comm
+///   task.thread  0x600        # ... ->thread, optional
+///   __log_buf    0xc0b3c9a4   # print &__log_buf, optional
+///   log_buf_len  0x4000       # print sizeof(__log_buf), optional
+#[derive(Clone, Debug)]
+pub struct KernelSymbols {
+    init_task: u32,
+    tasks: u32,
+    pid: u32,
+    comm: u32,
+    thread: Option<u32>,
+    log_buf: Option<u32>,
+    log_buf_len: Option<u32>,
+
+    /// Everything in the file, for expressions
+    values: HashMap<String, u32>,
+}
+
+/// A kernel task, found by walking the task list
+#[derive(Clone, Debug)]
+pub struct Task {
+    /// Virtual address of its `task_struct`
+    pub addr: u32,
+    pub pid: u32,
+    pub comm: String,
+}
+
+impl Task {
+    pub fn tid(&self) -> i64 {
+        TASK_TID_BASE + self.pid as i64
+    }
+}
+
+impl KernelSymbols {
+    pub fn load(path: &str) -> Result<KernelSymbols, KernelError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parse `name value` lines, skipping blank ones and `#` comments
+    pub fn parse(text: &str) -> Result<KernelSymbols, KernelError> {
+        let mut values = HashMap::new();
+        for (line_idx, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = || KernelError::ParseError(line_idx + 1, line.to_owned());
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [name, value] => {
+                    values.insert(name.to_string(), parse_u32(value).map_err(|_| error())?);
+                }
+                _ => return Err(error()),
+            }
+        }
+        let required = |name: &'static str| {
+            values
+                .get(name)
+                .cloned()
+                .ok_or(KernelError::MissingSymbol(name))
+        };
+        Ok(KernelSymbols {
+            init_task: required("init_task")?,
+            tasks: required("task.tasks")?,
+            pid: required("task.pid")?,
+            comm: required("task.comm")?,
+            thread: values.get("task.thread").cloned(),
+            log_buf: values.get("__log_buf").cloned(),
+            log_buf_len: values.get("log_buf_len").cloned(),
+            values,
+        })
+    }
+
+    /// The value of any symbol in the file
+    pub fn lookup(&self, name: &str) -> Option<u32> {
+        self.values.get(name).cloned()
+    }
+
+    /// Walk the circular task list starting from `init_task`.  Addresses
+    /// are kernel virtual addresses, translated with `satp`.
+    pub fn tasks(&self, bridge: &Bridge, satp: u32) -> Result<Vec<Task>, KernelError> {
+        let mut tasks = vec![];
+        let mut addr = self.init_task;
+        loop {
+            let comm_addr = field(addr, self.comm, TASK_COMM_LEN)?;
+            let comm = mmu::read_virtual(bridge, satp, comm_addr, TASK_COMM_LEN)?;
+            let comm = comm.split(|&b| b == 0).next().unwrap_or_default();
+            tasks.push(Task {
+                addr,
+                pid: read_word(bridge, satp, field(addr, self.pid, 4)?)?,
+                comm: String::from_utf8_lossy(comm).into_owned(),
+            });
+            // `tasks.next` points at the next task's `tasks` member
+            let next = read_word(bridge, satp, field(addr, self.tasks, 4)?)?;
+            addr = next
+                .checked_sub(self.tasks)
+                .ok_or(KernelError::OutOfRange(next, self.tasks))?;
+            if addr == self.init_task || tasks.len() >= MAX_TASKS {
+                return Ok(tasks);
+            }
+        }
+    }
+
+    /// The registers `__switch_to` saved when the task was last switched
+    /// out, in GDB's numbering.  Only ra, sp and s0-s11 are saved, and the
+    /// task carries on from ra, so that's its pc.
+    pub fn saved_registers(
+        &self,
+        bridge: &Bridge,
+        satp: u32,
+        task: &Task,
+    ) -> Result<Vec<Option<u32>>, KernelError> {
+        let thread = self
+            .thread
+            .ok_or(KernelError::MissingSymbol("task.thread"))?;
+        let bytes = mmu::read_virtual(bridge, satp, field(task.addr, thread, 14 * 4)?, 14 * 4)?;
+        let saved: Vec<u32> = bytes
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let mut registers = vec![None; 33];
+        registers[0] = Some(0);
+        registers[1] = Some(saved[0]);
+        registers[2] = Some(saved[1]);
+        // s0 and s1 are x8 and x9, and s2-s11 are x18-x27
+        for (idx, &value) in saved[2..].iter().enumerate() {
+            let regnum = if idx < 2 { 8 + idx } else { 16 + idx };
+            registers[regnum] = Some(value);
+        }
+        registers[32] = Some(saved[0]);
+        Ok(registers)
+    }
+
+    /// The kernel log, from the pre-5.10 record-based `__log_buf`.  Records
+    /// are read from the start of the buffer until an empty one, so after
+    /// the buffer has wrapped the oldest messages shown may be cut short.
+    pub fn dmesg(&self, bridge: &Bridge, satp: u32) -> Result<String, KernelError> {
+        let log_buf = self
+            .log_buf
+            .ok_or(KernelError::MissingSymbol("__log_buf"))?;
+        let len = self
+            .log_buf_len
+            .ok_or(KernelError::MissingSymbol("log_buf_len"))?;
+        field(log_buf, 0, len)?;
+        let buffer = mmu::read_virtual(bridge, satp, log_buf, len)?;
+        Ok(format_log(&buffer))
+    }
+}
+
+/// The address of a field `offset` bytes into something at `base`, as
+/// long as all `len` bytes of it are below the top of memory
+fn field(base: u32, offset: u32, len: u32) -> Result<u32, KernelError> {
+    match base.checked_add(offset) {
+        Some(addr) if addr as u64 + len as u64 <= 1 << 32 => Ok(addr),
+        _ => Err(KernelError::OutOfRange(base, offset)),
+    }
+}
+
+/// Format the records in a pre-5.10 `__log_buf`, one line each
+fn format_log(buffer: &[u8]) -> String {
+    let mut out = String::new();
+    let mut offset = 0;
+    while offset + PRINTK_HEADER_LENGTH <= buffer.len() {
+        let header = &buffer[offset..offset + PRINTK_HEADER_LENGTH];
+        let ts_nsec = u64::from_le_bytes([
+            header[0], header[1], header[2], header[3], header[4], header[5], header[6], header[7],
+        ]);
+        let record_len = u16::from_le_bytes([header[8], header[9]]) as usize;
+        let text_len = u16::from_le_bytes([header[10], header[11]]) as usize;
+        if record_len < PRINTK_HEADER_LENGTH || offset + record_len > buffer.len() {
+            break;
+        }
+        let text_start = offset + PRINTK_HEADER_LENGTH;
+        let text_end = (text_start + text_len).min(offset + record_len);
+        out.push_str(&format!(
+            "[{:5}.{:06}] {}\n",
+            ts_nsec / 1_000_000_000,
+            (ts_nsec % 1_000_000_000) / 1000,
+            String::from_utf8_lossy(&buffer[text_start..text_end])
+        ));
+        offset += record_len;
+    }
+    out
+}
+
+fn read_word(bridge: &Bridge, satp: u32, addr: u32) -> Result<u32, MmuError> {
+    let bytes = mmu::read_virtual(bridge, satp, addr, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{field, format_log, KernelError, KernelSymbols};
+    use crate::bridge::Bridge;
+    use crate::config::Config;
+
+    const SYMBOLS: &str = "init_task 0x1000   # &init_task\n\
+                           task.tasks 0x10\n\
+                           task.pid 0x18\n\
+                           task.comm 0x20\n\
+                           task.thread 0x40\n";
+
+    fn bridge() -> Bridge {
+        let cfg = Config::from_args(["kernel", "--mock"]).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        bridge
+    }
+
+    /// A task at `addr` whose `tasks.next` points at the one at `next`
+    fn task(bridge: &Bridge, addr: u32, next: u32, pid: u32, comm: &[u8; 4]) {
+        bridge.poke(addr + 0x10, next + 0x10).unwrap();
+        bridge.poke(addr + 0x18, pid).unwrap();
+        bridge.poke(addr + 0x20, u32::from_le_bytes(*comm)).unwrap();
+    }
+
+    #[test]
+    fn symbols() {
+        let symbols = KernelSymbols::parse(SYMBOLS).unwrap();
+        assert_eq!(symbols.lookup("task.thread"), Some(0x40));
+        assert_eq!(symbols.lookup("__log_buf"), None);
+        assert!(matches!(
+            KernelSymbols::parse("init_task 0x1000\n"),
+            Err(KernelError::MissingSymbol("task.tasks"))
+        ));
+        assert!(matches!(
+            KernelSymbols::parse("init_task\n"),
+            Err(KernelError::ParseError(1, _))
+        ));
+    }
+
+    #[test]
+    fn task_list() {
+        let bridge = bridge();
+        let symbols = KernelSymbols::parse(SYMBOLS).unwrap();
+        task(&bridge, 0x1000, 0x2000, 0, b"swap");
+        task(&bridge, 0x2000, 0x1000, 1, b"init");
+        let tasks = symbols.tasks(&bridge, 0).unwrap();
+        let found: Vec<(u32, u32, &str)> = tasks
+            .iter()
+            .map(|t| (t.addr, t.pid, t.comm.as_str()))
+            .collect();
+        assert_eq!(found, vec![(0x1000, 0, "swap"), (0x2000, 1, "init")]);
+
+        // ra, sp, s0 and s1, then s2-s11
+        for idx in 0..14 {
+            bridge.poke(0x2040 + idx * 4, 0x100 + idx).unwrap();
+        }
+        let registers = symbols.saved_registers(&bridge, 0, &tasks[1]).unwrap();
+        assert_eq!(registers[1], Some(0x100));
+        assert_eq!(registers[2], Some(0x101));
+        assert_eq!(registers[9], Some(0x103));
+        assert_eq!(registers[18], Some(0x104));
+        assert_eq!(registers[27], Some(0x10d));
+        assert_eq!(registers[32], Some(0x100));
+        assert_eq!(registers[5], None);
+    }
+
+    #[test]
+    fn corrupt_task_lists_stop() {
+        let bridge = bridge();
+        let symbols = KernelSymbols::parse(SYMBOLS).unwrap();
+        // A `tasks.next` below the offset of `tasks` itself
+        task(&bridge, 0x1000, 0, 0, b"swap");
+        bridge.poke(0x1010, 4).unwrap();
+        assert!(matches!(
+            symbols.tasks(&bridge, 0),
+            Err(KernelError::OutOfRange(4, 0x10))
+        ));
+
+        assert_eq!(field(0xffff_ff00, 0xfc, 4).unwrap(), 0xffff_fffc);
+        assert!(field(0xffff_ff00, 0xfd, 4).is_err());
+        assert!(field(0xffff_ff00, 0x100, 0).is_err());
+    }
+
+    #[test]
+    fn log_records() {
+        let record = |ts: u64, text: &[u8]| {
+            let len = (16 + text.len() + 3) & !3;
+            let mut record = ts.to_le_bytes().to_vec();
+            record.extend_from_slice(&(len as u16).to_le_bytes());
+            record.extend_from_slice(&(text.len() as u16).to_le_bytes());
+            record.extend_from_slice(&[0; 4]);
+            record.extend_from_slice(text);
+            record.resize(len, 0);
+            record
+        };
+        let mut buffer = record(1_500_000_000, b"hello");
+        buffer.extend(record(2_000_001_000, b"world"));
+        buffer.extend([0; 32]);
+        assert_eq!(
+            format_log(&buffer),
+            "[    1.500000] hello\n[    2.000001] world\n"
+        );
+
+        // A record claiming to run off the end isn't read
+        buffer[24 + 9] = 0xff;
+        assert_eq!(format_log(&buffer), "[    1.500000] hello\n");
+    }
+}
@@ -0,0 +1,118 @@
+//! The adapter as a library, for programs that want to run the GDB server
+//! over their own transport (see `embed`).  Only what that takes is
+//! public.  The `litex-usb-wishbone-bridge` binary is a thin layer over
+//! `run`.
+
+#[macro_use]
+extern crate bitflags;
+extern crate clap;
+extern crate libc;
+#[cfg(feature = "usb")]
+extern crate libusb;
+extern crate rand;
+
+#[macro_use]
+mod logging;
+#[macro_use]
+mod ui;
+
+mod app;
+mod board;
+mod bootmode;
+mod bridge;
+#[cfg(feature = "server")]
+mod call;
+mod capabilities;
+mod cli;
+#[cfg(feature = "server")]
+mod clock;
+mod config;
+#[cfg(all(test, feature = "server"))]
+mod conformance;
+#[cfg(feature = "server")]
+mod coredump;
+mod csr_map;
+#[cfg(feature = "server")]
+mod csr_snapshot;
+#[cfg(feature = "server")]
+mod ddr;
+mod doorbell;
+#[cfg(feature = "server")]
+mod ecc;
+#[cfg(feature = "server")]
+pub mod embed;
+#[cfg(feature = "ethernet")]
+mod etherbone_bridge;
+mod events;
+mod expr;
+mod fault_bridge;
+#[cfg(feature = "server")]
+mod fileio;
+#[cfg(feature = "flash")]
+mod flash;
+mod framebuffer;
+#[cfg(feature = "server")]
+mod gdb;
+#[cfg(feature = "server")]
+mod gdbinit;
+mod history;
+#[cfg(feature = "server")]
+mod http;
+mod image;
+#[cfg(feature = "server")]
+mod kernel;
+mod lock;
+mod memory;
+mod mmap_bridge;
+mod mmu;
+mod mock_bridge;
+#[cfg(test)]
+mod mock_cpu;
+#[cfg(feature = "server")]
+mod poll_watch;
+mod power;
+#[cfg(feature = "server")]
+mod proxy;
+mod riscv;
+#[cfg(feature = "server")]
+mod rsp;
+#[cfg(feature = "server")]
+mod sanity;
+#[cfg(feature = "server")]
+mod scratch;
+#[cfg(feature = "serial")]
+mod serial_bridge;
+#[cfg(feature = "server")]
+mod serialboot;
+#[cfg(feature = "server")]
+mod session;
+#[cfg(feature = "server")]
+mod stats;
+mod steptrace;
+#[cfg(any(feature = "usb", all(feature = "usbfs", target_os = "linux")))]
+mod suspend;
+#[cfg(feature = "server")]
+mod target;
+#[cfg(feature = "server")]
+mod terminal;
+mod trace;
+#[cfg(feature = "server")]
+mod transport;
+#[cfg(feature = "usb")]
+mod usb_bridge;
+#[cfg(all(feature = "usbfs", target_os = "linux"))]
+mod usbfs_bridge;
+mod utils;
+mod virtual_register;
+#[cfg(feature = "server")]
+mod wishbone;
+#[cfg(feature = "server")]
+mod xmodem;
+
+pub use bridge::{Bridge, BridgeError};
+pub use config::{Config, ConfigError};
+#[cfg(feature = "server")]
+pub use gdb::GdbServerError;
+
+#[doc(hidden)]
+pub use app::run;
@@ -0,0 +1,230 @@
+use std::fs::{File, OpenOptions, TryLockError};
+#[cfg(feature = "flash")]
+use std::io::Write;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::process;
+use std::sync::Mutex;
+
+use super::board::MemoryKind;
+use super::bridge::{BridgeBackend, BridgeError};
+use super::config::Config;
+
+/// Keeps debug accesses out of the flash while it's being programmed.
+/// While a sector is erased and programmed the flash is driven by hand,
+/// so anything reading it through the memory-mapped interface meanwhile
+/// gets garbage.
+///
+/// The lock is an advisory lock on a file named after the target, so it
+/// also keeps apart two adapters talking to the same board, such as a
+/// `--load` in one and a GDB server in another.  Programming holds it
+/// exclusively, and reads of the flash region hold it shared.  Whoever
+/// holds it exclusively writes what they're doing into the file, for the
+/// error the others get.
+#[derive(Clone)]
+struct FlashLock {
+    path: PathBuf,
+
+    /// (base, size) of each flash region in the memory map
+    ranges: Vec<(u32, u32)>,
+
+    /// Wait for the flash to be free rather than failing
+    wait: bool,
+}
+
+static FLASH_LOCK: Mutex<Option<FlashLock>> = Mutex::new(None);
+
+/// A held lock, released when it's dropped
+pub struct Guard {
+    file: File,
+    exclusive: bool,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if self.exclusive {
+            let _ = self.file.set_len(0);
+        }
+        let _ = self.file.unlock();
+    }
+}
+
+/// Set up the lock if the memory map has any flash in it
+pub fn init(cfg: &Config) {
+    let ranges: Vec<(u32, u32)> = cfg
+        .memory_map
+        .iter()
+        .filter(|r| r.kind == MemoryKind::Flash)
+        .map(|r| (r.base, r.size))
+        .collect();
+    if ranges.is_empty() {
+        return;
+    }
+    let name: String = target_name(cfg)
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+        .collect();
+    *FLASH_LOCK.lock().unwrap() = Some(FlashLock {
+        path: std::env::temp_dir().join(format!("litex-bridge-{}.flash.lock", name)),
+        ranges,
+        wait: cfg.wait_for_flash,
+    });
+}
+
+/// What the lock file is named after, so that adapters talking to the
+/// same board share it
+fn target_name(cfg: &Config) -> String {
+    let id = |id: Option<u16>| id.map_or("any".to_owned(), |id| format!("{:04x}", id));
+    match cfg.bridge_backend {
+        BridgeBackend::Usb | BridgeBackend::Usbfs => {
+            format!("usb-{}-{}", id(cfg.usb_vid), id(cfg.usb_pid))
+        }
+        BridgeBackend::Etherbone => format!("eb-{}", cfg.etherbone.as_deref().unwrap_or("")),
+        BridgeBackend::Serial => format!("serial-{}", cfg.serial.as_deref().unwrap_or("")),
+        BridgeBackend::Mmap => format!("mmap-{}", cfg.mmap_file.as_deref().unwrap_or("")),
+        // Nothing else can see a mock target
+        BridgeBackend::Mock => format!("mock-{}", process::id()),
+    }
+}
+
+/// Hold the flash for programming, saying what's being done to it.  This
+/// waits for anything reading the flash or programming it already.
+#[cfg(feature = "flash")]
+pub fn hold_flash(doing: &str) -> Result<Option<Guard>, BridgeError> {
+    let lock = match flash_lock() {
+        Some(lock) => lock,
+        None => return Ok(None),
+    };
+    let mut guard = lock.take(true)?;
+    guard.file.set_len(0)?;
+    write!(guard.file, "pid {} is {}", process::id(), doing)?;
+    Ok(Some(guard))
+}
+
+/// Make sure the flash isn't being programmed before reading `len` bytes
+/// at `addr`, and keep it that way until the guard is dropped.  Reads
+/// that don't touch the flash don't need a guard.
+pub fn read_flash(addr: u32, len: u32) -> Result<Option<Guard>, BridgeError> {
+    let lock = match flash_lock() {
+        Some(lock) => lock,
+        None => return Ok(None),
+    };
+    if !lock.overlaps(addr, len) {
+        return Ok(None);
+    }
+    lock.take(false).map(Some)
+}
+
+/// A copy of the lock's settings, so nothing is held while waiting on it
+fn flash_lock() -> Option<FlashLock> {
+    FLASH_LOCK.lock().unwrap().clone()
+}
+
+impl FlashLock {
+    /// Whether `len` bytes at `addr` take in any of the flash.  Neither
+    /// range can run past the top of the address space.
+    fn overlaps(&self, addr: u32, len: u32) -> bool {
+        self.ranges.iter().any(|&(base, size)| {
+            len > 0
+                && match addr.checked_sub(base) {
+                    Some(offset) => offset < size,
+                    None => base - addr < len,
+                }
+        })
+    }
+
+    /// Lock the file, waiting for it if that's allowed.  Programming always
+    /// waits, since readers don't hold on to it for long.
+    fn take(&self, exclusive: bool) -> Result<Guard, BridgeError> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // Keep whatever the holder wrote, it's what tells others why
+            .truncate(false)
+            .open(&self.path)?;
+        let taken = if exclusive {
+            file.try_lock()
+        } else {
+            file.try_lock_shared()
+        };
+        match taken {
+            Ok(()) => (),
+            Err(TryLockError::Error(e)) => return Err(e.into()),
+            Err(TryLockError::WouldBlock) => {
+                let mut holder = String::new();
+                file.seek(SeekFrom::Start(0))?;
+                file.read_to_string(&mut holder)?;
+                if holder.is_empty() {
+                    holder = "someone else is using it".to_owned();
+                }
+                if !self.wait && !exclusive {
+                    return Err(BridgeError::ResourceBusy(format!(
+                        "flash in progress: {}",
+                        holder
+                    )));
+                }
+                log_adapter!("Waiting for the flash: {}", holder);
+                if exclusive {
+                    file.lock()?;
+                } else {
+                    file.lock_shared()?;
+                }
+            }
+        }
+        Ok(Guard { file, exclusive })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::FlashLock;
+    use crate::bridge::BridgeError;
+    use std::io::Write;
+
+    fn flash_lock(name: &str, wait: bool) -> FlashLock {
+        FlashLock {
+            path: std::env::temp_dir().join(format!(
+                "litex-bridge-test-{}-{}.flash.lock",
+                name,
+                std::process::id()
+            )),
+            ranges: vec![(0x2000_0000, 0x0100_0000), (0xffff_f000, 0x1000)],
+            wait,
+        }
+    }
+
+    #[test]
+    fn reads_that_touch_the_flash() {
+        let lock = flash_lock("overlaps", false);
+        assert!(lock.overlaps(0x2000_0000, 4));
+        assert!(lock.overlaps(0x1fff_fffc, 8));
+        assert!(lock.overlaps(0x20ff_fffc, 4));
+        assert!(!lock.overlaps(0x1fff_fffc, 4));
+        assert!(!lock.overlaps(0x2100_0000, 4));
+        assert!(!lock.overlaps(0x2000_0000, 0));
+        // Neither end can wrap around past the top of the address space
+        assert!(lock.overlaps(0xffff_fffc, u32::MAX));
+        assert!(lock.overlaps(0x0000_1000, u32::MAX));
+        assert!(!lock.overlaps(0x2100_0000, 0xdeff_f000));
+    }
+
+    #[test]
+    fn programming_keeps_readers_out() {
+        let lock = flash_lock("contention", false);
+        let mut programming = lock.take(true).unwrap();
+        write!(programming.file, "pid 1 is erasing").unwrap();
+        match lock.take(false) {
+            Err(BridgeError::ResourceBusy(why)) => assert!(why.contains("pid 1 is erasing")),
+            Err(e) => panic!("{:?}", e),
+            Ok(_) => panic!("read the flash while it was being programmed"),
+        }
+        drop(programming);
+
+        // Readers don't keep each other out
+        let first = lock.take(false).unwrap();
+        let second = lock.take(false).unwrap();
+        drop((first, second));
+        let _ = std::fs::remove_file(&lock.path);
+    }
+}
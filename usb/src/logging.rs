@@ -0,0 +1,370 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::config::Config;
+use super::ui::{self, Verbosity};
+
+/// Log an entry to the GDB packet trace channel.  Like the other `log_`
+/// macros, the entry is at the channel's own level unless one is given
+/// first, as in `log_gdb!(@Verbose, "...")`.
+#[cfg(feature = "server")]
+macro_rules! log_gdb {
+    (@$level:ident, $($arg:tt)*) => ($crate::logging::log_at($crate::logging::LogChannel::Gdb, $crate::ui::Verbosity::$level, format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::logging::log($crate::logging::LogChannel::Gdb, format_args!($($arg)*)))
+}
+
+/// Log an entry to the bridge transaction channel
+macro_rules! log_bridge {
+    (@$level:ident, $($arg:tt)*) => ($crate::logging::log_at($crate::logging::LogChannel::Bridge, $crate::ui::Verbosity::$level, format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::logging::log($crate::logging::LogChannel::Bridge, format_args!($($arg)*)))
+}
+
+/// Log an entry to the target terminal channel
+#[cfg(feature = "server")]
+macro_rules! log_terminal {
+    (@$level:ident, $($arg:tt)*) => ($crate::logging::log_at($crate::logging::LogChannel::Terminal, $crate::ui::Verbosity::$level, format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::logging::log($crate::logging::LogChannel::Terminal, format_args!($($arg)*)))
+}
+
+/// Log an entry to the adapter diagnostics channel
+macro_rules! log_adapter {
+    (@$level:ident, $($arg:tt)*) => ($crate::logging::log_at($crate::logging::LogChannel::Adapter, $crate::ui::Verbosity::$level, format_args!($($arg)*)));
+    ($($arg:tt)*) => ($crate::logging::log($crate::logging::LogChannel::Adapter, format_args!($($arg)*)))
+}
+
+/// Without the server, nothing is logged to the gdb or terminal channels,
+/// though their files can still be given
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub enum LogChannel {
+    /// GDB remote serial protocol packet traces
+    Gdb,
+
+    /// Individual reads and writes issued to the bridge
+    Bridge,
+
+    /// Output coming from the target's terminal
+    Terminal,
+
+    /// General adapter diagnostics
+    Adapter,
+}
+
+impl LogChannel {
+    #[cfg(feature = "server")]
+    pub const ALL: [LogChannel; 4] = [
+        LogChannel::Gdb,
+        LogChannel::Bridge,
+        LogChannel::Terminal,
+        LogChannel::Adapter,
+    ];
+
+    /// Look a channel up by name.  GDB calls its packet trace `remote`, so
+    /// that works too.
+    #[cfg(feature = "server")]
+    pub fn from_name(name: &str) -> Option<LogChannel> {
+        match name {
+            "remote" => Some(LogChannel::Gdb),
+            name => LogChannel::ALL.iter().copied().find(|c| c.name() == name),
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            LogChannel::Gdb => 0,
+            LogChannel::Bridge => 1,
+            LogChannel::Terminal => 2,
+            LogChannel::Adapter => 3,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LogChannel::Gdb => "gdb",
+            LogChannel::Bridge => "bridge",
+            LogChannel::Terminal => "terminal",
+            LogChannel::Adapter => "adapter",
+        }
+    }
+
+    /// The level of entries that aren't given one
+    pub fn level(self) -> Verbosity {
+        match self {
+            LogChannel::Gdb => Verbosity::Debug,
+            LogChannel::Bridge => Verbosity::Debug,
+            LogChannel::Terminal => Verbosity::Normal,
+            LogChannel::Adapter => Verbosity::Verbose,
+        }
+    }
+
+    /// Whether the channel goes to the console when it has no file and
+    /// hasn't been given a level.  Bridge transactions are far too noisy
+    /// for that.
+    fn on_console(self) -> bool {
+        self != LogChannel::Bridge
+    }
+}
+
+/// What a channel has been switched to while running, over what the
+/// config says.  Only `monitor log` switches them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub enum Filter {
+    /// Log to the channel's file, or to the console as far as the
+    /// verbosity goes
+    Default,
+
+    /// Log entries up to this level whatever the verbosity, to the console
+    /// if the channel has no file
+    Level(Verbosity),
+
+    /// Drop everything
+    Off,
+}
+
+struct LogFile {
+    path: String,
+    file: File,
+    size: u64,
+}
+
+impl LogFile {
+    fn open(path: &str) -> io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {
+            path: path.to_owned(),
+            file,
+            size,
+        })
+    }
+
+    /// Shift `file.1` to `file.2` and so on, dropping anything past `keep`,
+    /// then start over with an empty file.
+    fn rotate(&mut self, keep: u32) -> io::Result<()> {
+        if keep > 0 {
+            for n in (1..keep).rev() {
+                let _ = fs::rename(
+                    format!("{}.{}", self.path, n),
+                    format!("{}.{}", self.path, n + 1),
+                );
+            }
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+struct Logger {
+    files: [Option<LogFile>; 4],
+
+    filters: [Filter; 4],
+
+    /// Rotate a file once it grows past this many bytes (0 means never)
+    max_size: u64,
+
+    /// How many rotated files to keep around
+    keep: u32,
+}
+
+static LOGGER: Mutex<Option<Logger>> = Mutex::new(None);
+
+/// Open the log files requested in the config.  Anything logged before this
+/// is called goes to the console.
+pub fn init(cfg: &Config) -> io::Result<()> {
+    let open = |path: &Option<String>| -> io::Result<Option<LogFile>> {
+        match path {
+            Some(p) => Ok(Some(LogFile::open(p)?)),
+            None => Ok(None),
+        }
+    };
+    let logger = Logger {
+        files: [
+            open(&cfg.log_gdb)?,
+            open(&cfg.log_bridge)?,
+            open(&cfg.log_terminal)?,
+            open(&cfg.log_adapter)?,
+        ],
+        filters: [Filter::Default; 4],
+        max_size: cfg.log_max_size,
+        keep: cfg.log_keep,
+    };
+    *LOGGER.lock().unwrap() = Some(logger);
+    Ok(())
+}
+
+#[cfg(feature = "server")]
+fn with_logger<T>(f: impl FnOnce(&mut Logger) -> T) -> T {
+    let mut logger = LOGGER.lock().unwrap();
+    f(logger.get_or_insert_with(|| Logger {
+        files: [None, None, None, None],
+        filters: [Filter::Default; 4],
+        max_size: 0,
+        keep: 0,
+    }))
+}
+
+/// Turn a channel on or off, or change its level, while running
+#[cfg(feature = "server")]
+pub fn set_filter(channel: LogChannel, filter: Filter) {
+    with_logger(|l| l.filters[channel.index()] = filter);
+}
+
+/// Send a channel to the file at `path`, appending to it, or back to the
+/// console
+#[cfg(feature = "server")]
+pub fn set_file(channel: LogChannel, path: Option<&str>) -> io::Result<()> {
+    let file = match path {
+        Some(p) => Some(LogFile::open(p)?),
+        None => None,
+    };
+    with_logger(|l| l.files[channel.index()] = file);
+    Ok(())
+}
+
+/// Where a channel is going, for showing to the user
+#[cfg(feature = "server")]
+pub fn describe(channel: LogChannel) -> String {
+    with_logger(|l| l.describe(channel))
+}
+
+#[cfg(feature = "server")]
+impl Logger {
+    fn describe(&self, channel: LogChannel) -> String {
+        let file = self.files[channel.index()]
+            .as_ref()
+            .map(|f| f.path.as_str());
+        match (self.filters[channel.index()], file) {
+            (Filter::Off, _) => "off".to_owned(),
+            (Filter::Level(level), file) => format!(
+                "{}, up to {} entries",
+                file.unwrap_or("console"),
+                level.name()
+            ),
+            (Filter::Default, Some(path)) => path.to_owned(),
+            (Filter::Default, None) if channel.on_console() => {
+                "console, as far as the verbosity goes".to_owned()
+            }
+            (Filter::Default, None) => "off unless given a file or a level".to_owned(),
+        }
+    }
+}
+
+/// The host time each log line is stamped with, in seconds since the epoch
+pub fn timestamp() -> String {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => format!("{}.{:03}", d.as_secs(), d.subsec_millis()),
+        Err(_) => "0.000".to_owned(),
+    }
+}
+
+/// Whether a channel is going to a file rather than the console
+#[cfg(feature = "server")]
+pub fn has_file(channel: LogChannel) -> bool {
+    match *LOGGER.lock().unwrap() {
+        Some(ref l) => l.files[channel.index()].is_some(),
+        None => false,
+    }
+}
+
+pub fn log(channel: LogChannel, args: fmt::Arguments) {
+    log_at(channel, channel.level(), args)
+}
+
+pub fn log_at(channel: LogChannel, level: Verbosity, args: fmt::Arguments) {
+    let mut logger = LOGGER.lock().unwrap();
+    let (max_size, keep, filter) = match *logger {
+        Some(ref l) => (l.max_size, l.keep, l.filters[channel.index()]),
+        None => (0, 0, Filter::Default),
+    };
+    match filter {
+        Filter::Off => return,
+        Filter::Level(max) if level > max => return,
+        _ => (),
+    }
+    let log_file = logger
+        .as_mut()
+        .and_then(|l| l.files[channel.index()].as_mut());
+
+    match log_file {
+        Some(f) => {
+            let line = format!("[{}] {}\n", timestamp(), args);
+            // Logging must never bring down a debug session, so errors
+            // writing to the log are dropped.
+            if f.file.write_all(line.as_bytes()).is_ok() {
+                f.size += line.len() as u64;
+            }
+            if max_size > 0 && f.size >= max_size {
+                let _ = f.rotate(keep);
+            }
+        }
+        None => match filter {
+            Filter::Level(_) => ui::log(Verbosity::Quiet, channel.name(), args),
+            _ if channel.on_console() => ui::log(level, channel.name(), args),
+            _ => (),
+        },
+    }
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::{Filter, LogChannel, LogFile, Logger};
+    use crate::ui::Verbosity;
+
+    #[test]
+    fn channel_names() {
+        for channel in LogChannel::ALL.iter() {
+            assert_eq!(LogChannel::from_name(channel.name()), Some(*channel));
+        }
+        assert_eq!(LogChannel::from_name("remote"), Some(LogChannel::Gdb));
+        assert_eq!(LogChannel::from_name("usb"), None);
+        assert_eq!(LogChannel::from_name(""), None);
+    }
+
+    #[test]
+    fn describe_settings() {
+        let path = std::env::temp_dir().join(format!("logging-test-{}.log", std::process::id()));
+        let path = path.to_str().unwrap();
+        let mut logger = Logger {
+            files: [None, Some(LogFile::open(path).unwrap()), None, None],
+            filters: [
+                Filter::Default,
+                Filter::Level(Verbosity::Verbose),
+                Filter::Off,
+                Filter::Level(Verbosity::Debug),
+            ],
+            max_size: 0,
+            keep: 0,
+        };
+        assert_eq!(
+            logger.describe(LogChannel::Gdb),
+            "console, as far as the verbosity goes"
+        );
+        assert_eq!(
+            logger.describe(LogChannel::Bridge),
+            format!("{}, up to verbose entries", path)
+        );
+        assert_eq!(logger.describe(LogChannel::Terminal), "off");
+        assert_eq!(
+            logger.describe(LogChannel::Adapter),
+            "console, up to debug entries"
+        );
+        logger.filters[1] = Filter::Default;
+        assert_eq!(logger.describe(LogChannel::Bridge), path);
+        logger.files[1] = None;
+        assert_eq!(
+            logger.describe(LogChannel::Bridge),
+            "off unless given a file or a level"
+        );
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,414 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use super::board::{MemoryKind, MemoryRegion};
+use super::bridge::{Bridge, BridgeError};
+use super::config::Config;
+#[cfg(feature = "flash")]
+use super::flash::{FlashError, FlashWriter};
+use super::image;
+use super::utils::parse_u32;
+
+/// How much of a load goes out between checkpoints.  This is a multiple of
+/// every sector size flash comes in, so checkpoints don't split sectors.
+pub const LOAD_CHUNK: usize = 64 * 1024;
+
+/// How many times a chunk is tried before the load gives up on it
+const CHUNK_ATTEMPTS: u32 = 3;
+
+/// How long to leave the bridge alone after a chunk fails
+const RETRY_DELAY: Duration = Duration::from_millis(500);
+
+#[derive(Debug)]
+pub enum WriteError {
+    BridgeError(BridgeError),
+
+    /// Programming the flash went wrong
+    #[cfg(feature = "flash")]
+    FlashError(FlashError),
+
+    /// The memory map says there's flash here, but it can't be programmed:
+    /// the board doesn't say how it's laid out, csr.csv doesn't say where
+    /// its controller is, or this build has no flash support
+    NotConfigured(u32),
+}
+
+impl std::convert::From<BridgeError> for WriteError {
+    fn from(e: BridgeError) -> Self {
+        WriteError::BridgeError(e)
+    }
+}
+
+#[cfg(feature = "flash")]
+impl std::convert::From<FlashError> for WriteError {
+    fn from(e: FlashError) -> Self {
+        match e {
+            // Worth trying again, the same as on the bus
+            FlashError::BridgeError(e) => WriteError::BridgeError(e),
+            e => WriteError::FlashError(e),
+        }
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WriteError::BridgeError(BridgeError::ResourceBusy(why)) => write!(f, "{}", why),
+            WriteError::BridgeError(e) => write!(f, "bridge error: {:?}", e),
+            #[cfg(feature = "flash")]
+            WriteError::FlashError(e) => write!(f, "{}", e),
+            WriteError::NotConfigured(addr) => write!(
+                f,
+                "{:08x} is flash, and programming it needs a board with a flash layout and a csr.csv with the spiflash registers",
+                addr
+            ),
+        }
+    }
+}
+
+/// Where a byte written to an address ends up
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Destination {
+    Bus,
+    #[cfg(feature = "flash")]
+    Flash,
+
+    /// The memory map says it's flash, but there's no way to program it
+    Unprogrammable,
+}
+
+/// Sends writes wherever they need to go.  Anything landing in the flash
+/// goes through the flash writer, and everything else goes straight out on
+/// the bus.
+pub struct MemoryWriter {
+    memory_map: Vec<MemoryRegion>,
+    #[cfg(feature = "flash")]
+    flash: Option<FlashWriter>,
+}
+
+impl MemoryWriter {
+    pub fn new(cfg: &Config) -> MemoryWriter {
+        MemoryWriter {
+            memory_map: cfg.memory_map.clone(),
+            #[cfg(feature = "flash")]
+            flash: match (cfg.flash, cfg.spiflash) {
+                (Some(geometry), Some(registers)) => Some(FlashWriter::new(geometry, registers)),
+                _ => None,
+            },
+        }
+    }
+
+    /// The flash writer's idea of where the flash is wins over the memory
+    /// map's, so the two can't disagree about where a write goes
+    fn destination(&self, addr: u32) -> Destination {
+        #[cfg(feature = "flash")]
+        if self
+            .flash
+            .as_ref()
+            .is_some_and(|flash| flash.contains(addr))
+        {
+            return Destination::Flash;
+        }
+        let in_flash_region = self.memory_map.iter().any(|r| {
+            r.kind == MemoryKind::Flash && addr.checked_sub(r.base).is_some_and(|o| o < r.size)
+        });
+        if in_flash_region {
+            Destination::Unprogrammable
+        } else {
+            Destination::Bus
+        }
+    }
+
+    pub fn write(&mut self, bridge: &Bridge, addr: u32, data: &[u8]) -> Result<(), WriteError> {
+        // Split the write into runs that all go to the same place
+        let mut start = 0;
+        while start < data.len() {
+            let run_addr = addr.wrapping_add(start as u32);
+            let destination = self.destination(run_addr);
+            let mut end = start + 1;
+            while end < data.len() && self.destination(addr.wrapping_add(end as u32)) == destination
+            {
+                end += 1;
+            }
+            match destination {
+                Destination::Bus => Self::write_bus(bridge, run_addr, &data[start..end])?,
+                #[cfg(feature = "flash")]
+                Destination::Flash => match self.flash {
+                    Some(ref mut flash) => flash.write(bridge, run_addr, &data[start..end])?,
+                    None => unreachable!("only the flash writer's addresses go to the flash"),
+                },
+                _ => return Err(WriteError::NotConfigured(run_addr)),
+            }
+            start = end;
+        }
+        Ok(())
+    }
+
+    /// Finish any flash sector that's still being gathered up
+    pub fn flush(&mut self, bridge: &Bridge) -> Result<(), WriteError> {
+        #[cfg(feature = "flash")]
+        if let Some(ref mut flash) = self.flash {
+            flash.flush(bridge)?;
+        }
+        #[cfg(not(feature = "flash"))]
+        let _ = bridge;
+        Ok(())
+    }
+
+    /// Write `data` to `addr` a chunk at a time, starting `start` bytes in.
+    /// A chunk that fails on the bridge is tried again, since a load of a
+    /// few megabytes can outlast a flaky cable.  Each chunk is flushed
+    /// before `checkpoint` is told how far the load has got, so everything
+    /// before that offset is on the target.
+    pub fn write_chunked(
+        &mut self,
+        bridge: &Bridge,
+        addr: u32,
+        data: &[u8],
+        start: usize,
+        checkpoint: &mut dyn FnMut(usize),
+    ) -> Result<(), WriteError> {
+        let mut offset = start;
+        while offset < data.len() {
+            let end = (offset + LOAD_CHUNK).min(data.len());
+            let chunk_addr = addr.wrapping_add(offset as u32);
+            let mut attempt = 1;
+            loop {
+                let result = self
+                    .write(bridge, chunk_addr, &data[offset..end])
+                    .and_then(|_| self.flush(bridge));
+                match result {
+                    Ok(()) => break,
+                    Err(WriteError::BridgeError(e)) if attempt < CHUNK_ATTEMPTS => {
+                        ui_info!(
+                            "Writing {} bytes at {:08x} failed ({:?}), trying again",
+                            end - offset,
+                            chunk_addr,
+                            e
+                        );
+                        thread::sleep(RETRY_DELAY);
+                        attempt += 1;
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            offset = end;
+            checkpoint(offset);
+        }
+        Ok(())
+    }
+
+    pub fn flash_sectors_written(&self) -> u32 {
+        #[cfg(feature = "flash")]
+        if let Some(ref flash) = self.flash {
+            return flash.sectors_written();
+        }
+        0
+    }
+
+    /// Write a run of bytes a word at a time.  Words that are only partly
+    /// covered are read first so their other bytes are left alone.  Whole
+    /// words go out in batches as big as the bridge's bursts.
+    fn write_bus(bridge: &Bridge, addr: u32, data: &[u8]) -> Result<(), BridgeError> {
+        let burst = (bridge.max_burst() / 4).max(1);
+        let mut batch = bridge.batch();
+        let mut batched = 0;
+        let mut offset = 0;
+        while offset < data.len() {
+            let byte_addr = addr.wrapping_add(offset as u32);
+            let word_addr = byte_addr & !3;
+            let skip = (byte_addr - word_addr) as usize;
+            let count = (4 - skip).min(data.len() - offset);
+            let mut word = if count == 4 {
+                [0; 4]
+            } else {
+                bridge.peek(word_addr)?.to_le_bytes()
+            };
+            word[skip..skip + count].copy_from_slice(&data[offset..offset + count]);
+            batch = batch.write(word_addr, u32::from_le_bytes(word));
+            batched += 1;
+            if batched == burst {
+                batch.commit()?;
+                batch = bridge.batch();
+                batched = 0;
+            }
+            offset += count;
+        }
+        if batched > 0 {
+            batch.commit()?;
+        }
+        Ok(())
+    }
+}
+
+/// How far a load got, kept in a file so that a load that fails can be
+/// carried on by running it again.  The file is a few lines of text:
+///
+///   addr 0x40000000
+///   len 1048576
+///   crc32 0x1c291ca3
+///   done 655360
+///
+/// The image's length and CRC are there so a different file loaded to
+/// the same place starts over.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LoadState {
+    pub addr: u32,
+    pub len: u32,
+    pub crc32: u32,
+
+    /// Bytes from the start that are known to be written
+    pub done: u32,
+}
+
+impl LoadState {
+    pub fn new(addr: u32, data: &[u8]) -> LoadState {
+        LoadState {
+            addr,
+            len: data.len() as u32,
+            crc32: image::crc32(data),
+            done: 0,
+        }
+    }
+
+    /// Read the state saved at `path`, if there is any that makes sense
+    pub fn load(path: &str) -> io::Result<Option<LoadState>> {
+        match fs::read_to_string(path) {
+            Ok(text) => Ok(Self::parse(&text)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.to_string())
+    }
+
+    pub fn parse(text: &str) -> Option<LoadState> {
+        let mut fields = [None; 4];
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let idx = match words.next()? {
+                "addr" => 0,
+                "len" => 1,
+                "crc32" => 2,
+                "done" => 3,
+                _ => return None,
+            };
+            fields[idx] = Some(parse_u32(words.next()?).ok()?);
+        }
+        Some(LoadState {
+            addr: fields[0]?,
+            len: fields[1]?,
+            crc32: fields[2]?,
+            done: fields[3]?,
+        })
+    }
+
+    /// How far into loading `data` to `addr` this got, if it was loading
+    /// the same thing
+    pub fn resume_offset(&self, addr: u32, data: &[u8]) -> Option<usize> {
+        let fresh = LoadState::new(addr, data);
+        if (self.addr, self.len, self.crc32) == (fresh.addr, fresh.len, fresh.crc32)
+            && self.done <= self.len
+        {
+            Some(self.done as usize)
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Display for LoadState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "# litex-usb-wishbone-bridge load")?;
+        writeln!(f, "addr 0x{:08x}", self.addr)?;
+        writeln!(f, "len {}", self.len)?;
+        writeln!(f, "crc32 0x{:08x}", self.crc32)?;
+        writeln!(f, "done {}", self.done)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LoadState;
+    #[cfg(feature = "flash")]
+    use super::{MemoryWriter, WriteError};
+    #[cfg(feature = "flash")]
+    use crate::board::{MemoryKind, MemoryRegion};
+    #[cfg(feature = "flash")]
+    use crate::flash::test::{bridge_with_flash, GEOMETRY, REGISTERS};
+    #[cfg(feature = "flash")]
+    use crate::flash::FlashWriter;
+
+    /// A memory map whose flash region is bigger than the flash the
+    /// writer knows how to program
+    #[cfg(feature = "flash")]
+    fn writer(flash: bool) -> MemoryWriter {
+        MemoryWriter {
+            memory_map: vec![MemoryRegion {
+                name: "spiflash".to_owned(),
+                base: GEOMETRY.base,
+                size: GEOMETRY.size * 2,
+                kind: MemoryKind::Flash,
+            }],
+            flash: Some(FlashWriter::new(GEOMETRY, REGISTERS)).filter(|_| flash),
+        }
+    }
+
+    #[cfg(feature = "flash")]
+    #[test]
+    fn writes_are_routed_to_the_flash() {
+        let bridge = bridge_with_flash(vec![0xff; GEOMETRY.size as usize]);
+        let mut writer = writer(true);
+        writer
+            .write(&bridge, 0x1fff_fffc, &[1, 2, 3, 4, 5, 6, 7, 8])
+            .unwrap();
+        writer.flush(&bridge).unwrap();
+        assert_eq!(bridge.peek(0x1fff_fffc).unwrap(), 0x0403_0201);
+        assert_eq!(bridge.peek(0x2000_0000).unwrap(), 0x0807_0605);
+        assert_eq!(writer.flash_sectors_written(), 1);
+    }
+
+    #[cfg(feature = "flash")]
+    #[test]
+    fn flash_that_cant_be_programmed() {
+        let bridge = bridge_with_flash(vec![0xff; GEOMETRY.size as usize]);
+        // Past the end of the flash the writer knows, but still in the
+        // memory map's flash region
+        assert!(matches!(
+            writer(true).write(&bridge, 0x2000_1ffc, &[0; 8]),
+            Err(WriteError::NotConfigured(0x2000_2000))
+        ));
+        assert!(matches!(
+            writer(false).write(&bridge, 0x2000_0000, &[0; 4]),
+            Err(WriteError::NotConfigured(0x2000_0000))
+        ));
+    }
+
+    #[test]
+    fn load_state_round_trip() {
+        let data = vec![0x5a; 1000];
+        let mut state = LoadState::new(0x4000_0000, &data);
+        state.done = 512;
+        let parsed = LoadState::parse(&state.to_string()).unwrap();
+        assert_eq!(parsed, state);
+        assert_eq!(parsed.resume_offset(0x4000_0000, &data), Some(512));
+        assert_eq!(parsed.resume_offset(0x4000_1000, &data), None);
+        assert_eq!(parsed.resume_offset(0x4000_0000, &data[1..]), None);
+    }
+
+    #[test]
+    fn load_state_rejects_junk() {
+        assert_eq!(LoadState::parse("addr 0x0\nlen 4\ncrc32 0\n"), None);
+        assert_eq!(LoadState::parse("addr 0x0\nlen 4\ncrc32 0\ndone x\n"), None);
+        assert_eq!(LoadState::parse("hart 0\n"), None);
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs::OpenOptions;
+use std::io;
+
+use super::bridge::{self, BatchOp, BridgeError};
+use super::config::Config;
+
+/// A bridge backed by a shared-memory file whose contents mirror the SoC
+/// address space, as written by some co-simulation environments.  Offset 0
+/// in the file corresponds to `base` on the bus.  Accesses go straight to
+/// the mapping, so there's no round trip to wait for.
+pub struct MmapBridge {
+    base: u32,
+    len: usize,
+    ptr: *mut u8,
+
+    /// The profile's cap on burst sizes, in bytes
+    burst_limit: usize,
+}
+
+// The mapping is shared memory by design; every access is a single
+// volatile word read or write, which is as safe from any thread as it is
+// from the simulator on the other side.
+unsafe impl Send for MmapBridge {}
+unsafe impl Sync for MmapBridge {}
+
+impl MmapBridge {
+    pub fn new(cfg: &Config) -> Result<Self, BridgeError> {
+        let path = match cfg.mmap_file {
+            Some(ref p) => p,
+            None => return Err(BridgeError::NotConnected),
+        };
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        let len = file.metadata()?.len() as usize;
+        let ptr = Self::map(&file, len)?;
+        Ok(MmapBridge {
+            base: cfg.mmap_base,
+            len,
+            ptr,
+            burst_limit: cfg.tuning.burst_limit.unwrap_or(usize::MAX),
+        })
+    }
+
+    #[cfg(unix)]
+    fn map(file: &std::fs::File, len: usize) -> io::Result<*mut u8> {
+        use std::os::unix::io::AsRawFd;
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ptr as *mut u8)
+    }
+
+    #[cfg(not(unix))]
+    fn map(_file: &std::fs::File, _len: usize) -> io::Result<*mut u8> {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            "memory-mapped bridges are only supported on unix",
+        ))
+    }
+
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        Ok(())
+    }
+
+    /// There's no transport, so any size is as good as any other
+    pub fn max_burst(&self) -> usize {
+        self.burst_limit.min(4096)
+    }
+
+    /// Find the word backing `addr`, as long as it's aligned and inside
+    /// the file.  Anything else is treated like an unmapped bus access.
+    fn word(&self, addr: u32) -> Result<*mut u32, BridgeError> {
+        let offset = addr.wrapping_sub(self.base) as usize;
+        if addr < self.base || addr & 3 != 0 || offset + 4 > self.len {
+            return Err(BridgeError::BusError);
+        }
+        Ok(unsafe { self.ptr.add(offset) } as *mut u32)
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        let word = self.word(addr)?;
+        unsafe { word.write_volatile(value.to_le()) };
+        Ok(())
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        let word = self.word(addr)?;
+        Ok(u32::from_le(unsafe { word.read_volatile() }))
+    }
+
+    pub fn execute(&self, ops: &[BatchOp]) -> Result<Vec<u32>, BridgeError> {
+        bridge::execute_each(ops, |a| self.peek(a), |a, v| self.poke(a, v))
+    }
+}
+
+impl Drop for MmapBridge {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len);
+        }
+    }
+}
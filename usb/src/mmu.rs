@@ -0,0 +1,221 @@
+#[cfg(feature = "server")]
+use std::fmt;
+
+use super::bridge::{Bridge, BridgeError};
+use super::lock;
+
+/// Supervisor address translation and protection
+#[cfg(feature = "server")]
+pub const CSR_SATP: u32 = 0x180;
+
+/// `satp.MODE`: set for Sv32, clear for bare addressing
+#[cfg(feature = "server")]
+const SATP_MODE_SV32: u32 = 1 << 31;
+#[cfg(feature = "server")]
+const SATP_PPN_MASK: u32 = 0x003f_ffff;
+
+#[cfg(feature = "server")]
+pub const PAGE_SIZE: u32 = 4096;
+
+#[cfg(feature = "server")]
+const PTE_V: u32 = 1 << 0;
+#[cfg(feature = "server")]
+const PTE_R: u32 = 1 << 1;
+#[cfg(feature = "server")]
+const PTE_W: u32 = 1 << 2;
+#[cfg(feature = "server")]
+const PTE_X: u32 = 1 << 3;
+
+#[cfg(feature = "server")]
+#[derive(Debug)]
+pub enum MmuError {
+    /// The bridge failed while reading the page tables or memory
+    BridgeError(BridgeError),
+
+    /// The page tables don't map this address
+    PageFault(u32),
+}
+
+#[cfg(feature = "server")]
+impl std::convert::From<BridgeError> for MmuError {
+    fn from(e: BridgeError) -> Self {
+        MmuError::BridgeError(e)
+    }
+}
+
+#[cfg(feature = "server")]
+impl fmt::Display for MmuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MmuError::BridgeError(e) => write!(f, "bridge error: {:?}", e),
+            MmuError::PageFault(va) => write!(f, "{:08x} isn't mapped", va),
+        }
+    }
+}
+
+/// Whether `satp` turns translation on
+#[cfg(feature = "server")]
+pub fn is_enabled(satp: u32) -> bool {
+    satp & SATP_MODE_SV32 != 0
+}
+
+/// Walk the Sv32 page tables rooted at `satp` to find where `va` lives.
+/// The tables are read straight off the bus, so this works whatever mode
+/// the hart stopped in.  With translation off, addresses map to themselves.
+/// Physical addresses above 4 GiB can't be reached over the bridge, and are
+/// treated as unmapped.
+#[cfg(feature = "server")]
+pub fn translate(bridge: &Bridge, satp: u32, va: u32) -> Result<u32, MmuError> {
+    if !is_enabled(satp) {
+        return Ok(va);
+    }
+    let fault = MmuError::PageFault(va);
+    let vpn = [(va >> 12) & 0x3ff, va >> 22];
+    let mut table = (satp & SATP_PPN_MASK) as u64 * PAGE_SIZE as u64;
+    for level in (0..2).rev() {
+        let pte_addr = table + vpn[level] as u64 * 4;
+        if pte_addr > u32::MAX as u64 {
+            return Err(fault);
+        }
+        let pte = bridge.peek(pte_addr as u32)?;
+        if pte & PTE_V == 0 || (pte & PTE_R == 0 && pte & PTE_W != 0) {
+            return Err(fault);
+        }
+        let ppn = (pte >> 10) as u64;
+        if pte & (PTE_R | PTE_X) == 0 {
+            // A pointer to the next level
+            table = ppn * PAGE_SIZE as u64;
+            continue;
+        }
+        // A leaf.  Superpages must be aligned to their size.
+        let pa = if level == 1 {
+            if ppn & 0x3ff != 0 {
+                return Err(fault);
+            }
+            (ppn << 12) | (va & 0x003f_ffff) as u64
+        } else {
+            (ppn << 12) | (va & (PAGE_SIZE - 1)) as u64
+        };
+        if pa > u32::MAX as u64 {
+            return Err(fault);
+        }
+        return Ok(pa as u32);
+    }
+    Err(fault)
+}
+
+/// Read `len` bytes at virtual address `addr`, translating each page
+/// separately and reading the physical memory straight off the bus
+#[cfg(feature = "server")]
+pub fn read_virtual(bridge: &Bridge, satp: u32, addr: u32, len: u32) -> Result<Vec<u8>, MmuError> {
+    let mut data = Vec::with_capacity(len as usize);
+    let end = addr as u64 + len as u64;
+    let mut va = addr as u64;
+    while va < end {
+        let page_end = ((va | (PAGE_SIZE as u64 - 1)) + 1).min(end);
+        let pa = translate(bridge, satp, va as u32)?;
+        data.extend(read_physical(bridge, pa, (page_end - va) as u32)?);
+        va = page_end;
+    }
+    Ok(data)
+}
+
+/// Read bytes from the bus in bursts, trimming the ends to fit
+pub fn read_physical(bridge: &Bridge, addr: u32, len: u32) -> Result<Vec<u8>, BridgeError> {
+    let _lock = lock::read_flash(addr, len)?;
+    let start = addr & !3;
+    let end = (addr as u64 + len as u64 + 3) & !3;
+    let words = bridge.read_block(start, ((end - start as u64) / 4) as usize)?;
+    let data: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+    let skip = (addr - start) as usize;
+    Ok(data[skip..skip + len as usize].to_vec())
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::{read_physical, read_virtual, translate, MmuError, SATP_MODE_SV32};
+    use crate::bridge::Bridge;
+    use crate::config::Config;
+
+    const ROOT: u32 = 0x0001_0000;
+    const TABLE: u32 = 0x0001_1000;
+    const SATP: u32 = SATP_MODE_SV32 | (ROOT >> 12);
+
+    /// Valid and readable, writable and executable
+    const LEAF: u32 = 0xf;
+
+    fn pte(pa: u64, flags: u32) -> u32 {
+        ((pa >> 12) << 10) as u32 | flags
+    }
+
+    /// Page tables mapping 0x80005000 and 0x80006000 to two pages that
+    /// aren't next to each other, 0xc0000000 to a 4 MiB superpage at
+    /// 0x40000000, and some broken entries
+    fn mapped() -> Bridge {
+        let bridge = Bridge::new(&Config::from_args(["mmu", "--mock"]).unwrap()).unwrap();
+        bridge.connect().unwrap();
+        bridge.poke(ROOT + 0x200 * 4, pte(TABLE as u64, 1)).unwrap();
+        bridge.poke(TABLE + 5 * 4, pte(0x4012_3000, LEAF)).unwrap();
+        bridge.poke(TABLE + 6 * 4, pte(0x4020_0000, LEAF)).unwrap();
+        // Writable but not readable is reserved
+        bridge.poke(TABLE + 7 * 4, pte(0x4030_0000, 0x5)).unwrap();
+        // A physical address past 4 GiB
+        bridge
+            .poke(TABLE + 8 * 4, pte(0x1_0000_0000, LEAF))
+            .unwrap();
+        bridge
+            .poke(ROOT + 0x300 * 4, pte(0x4000_0000, LEAF))
+            .unwrap();
+        // A superpage that isn't aligned to 4 MiB
+        bridge
+            .poke(ROOT + 0x301 * 4, pte(0x4000_1000, LEAF))
+            .unwrap();
+        bridge
+    }
+
+    fn fault(result: Result<u32, MmuError>) -> Option<u32> {
+        match result {
+            Err(MmuError::PageFault(va)) => Some(va),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn translation() {
+        let bridge = mapped();
+        assert_eq!(translate(&bridge, 0, 0x8000_5abc).unwrap(), 0x8000_5abc);
+        assert_eq!(translate(&bridge, SATP, 0x8000_5abc).unwrap(), 0x4012_3abc);
+        assert_eq!(translate(&bridge, SATP, 0x8000_6000).unwrap(), 0x4020_0000);
+        assert_eq!(translate(&bridge, SATP, 0xc012_3456).unwrap(), 0x4012_3456);
+        for va in [
+            0x8000_4000,
+            0x8000_7000,
+            0x8000_8000,
+            0xc040_0000,
+            0x0000_1000,
+        ] {
+            assert_eq!(fault(translate(&bridge, SATP, va)), Some(va), "{:08x}", va);
+        }
+    }
+
+    #[test]
+    fn reads_cross_pages() {
+        let bridge = mapped();
+        bridge.poke(0x4012_3ffc, 0x4433_2211).unwrap();
+        bridge.poke(0x4020_0000, 0x8877_6655).unwrap();
+        assert_eq!(
+            read_virtual(&bridge, SATP, 0x8000_5ffe, 4).unwrap(),
+            [0x33, 0x44, 0x55, 0x66]
+        );
+        assert!(matches!(
+            read_virtual(&bridge, SATP, 0x8000_6ffe, 4),
+            Err(MmuError::PageFault(0x8000_7000))
+        ));
+        // Physical memory carries straight on into the next page
+        bridge.poke(0x4012_4000, 0xaaaa_bbcc).unwrap();
+        assert_eq!(
+            read_physical(&bridge, 0x4012_3ffd, 5).unwrap(),
+            [0x22, 0x33, 0x44, 0xcc, 0xbb]
+        );
+    }
+}
@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use super::bridge::{BatchOp, BridgeError};
+use super::config::Config;
+
+/// Something on the bus in front of the memory, such as a CPU's debug
+/// unit, for tests that need more than memory.  It sees every access
+/// first, along with the memory behind it.
+#[cfg(test)]
+pub trait Device: Send {
+    /// Answer a read, or return `None` to leave it to memory
+    fn read(&mut self, memory: &mut HashMap<u32, u32>, addr: u32) -> Option<u32>;
+
+    /// Take a write, returning whether it was taken
+    fn write(&mut self, memory: &mut HashMap<u32, u32>, addr: u32, value: u32) -> bool;
+}
+
+/// A bridge with nothing on the other end but a sparse block of memory.
+/// Unwritten addresses read back as zero.  Useful for exercising the rest
+/// of the adapter without any hardware attached.
+pub struct MockBridge {
+    memory: Mutex<HashMap<u32, u32>>,
+    #[cfg(test)]
+    device: Mutex<Option<Box<dyn Device>>>,
+
+    /// The profile's cap on burst sizes, in bytes
+    burst_limit: usize,
+}
+
+impl MockBridge {
+    pub fn new(cfg: &Config) -> Result<Self, BridgeError> {
+        Ok(MockBridge {
+            memory: Mutex::new(HashMap::new()),
+            #[cfg(test)]
+            device: Mutex::new(None),
+            burst_limit: cfg.tuning.burst_limit.unwrap_or(usize::MAX),
+        })
+    }
+
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        Ok(())
+    }
+
+    pub fn max_burst(&self) -> usize {
+        self.burst_limit.min(4096)
+    }
+
+    /// Put `device` in front of the memory
+    #[cfg(test)]
+    pub fn attach(&self, device: Box<dyn Device>) {
+        *self.device.lock().unwrap() = Some(device);
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        self.write(&mut self.memory.lock().unwrap(), addr, value);
+        Ok(())
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        Ok(self.read(&mut self.memory.lock().unwrap(), addr))
+    }
+
+    pub fn execute(&self, ops: &[BatchOp]) -> Result<Vec<u32>, BridgeError> {
+        let mut memory = self.memory.lock().unwrap();
+        let mut results = vec![];
+        for op in ops {
+            match *op {
+                BatchOp::Write(addr, value) => self.write(&mut memory, addr, value),
+                BatchOp::Read(addr) => results.push(self.read(&mut memory, addr)),
+            }
+        }
+        Ok(results)
+    }
+
+    fn read(&self, memory: &mut HashMap<u32, u32>, addr: u32) -> u32 {
+        #[cfg(test)]
+        if let Some(device) = self.device.lock().unwrap().as_mut() {
+            if let Some(value) = device.read(memory, addr) {
+                return value;
+            }
+        }
+        *memory.get(&addr).unwrap_or(&0)
+    }
+
+    fn write(&self, memory: &mut HashMap<u32, u32>, addr: u32, value: u32) {
+        #[cfg(test)]
+        if let Some(device) = self.device.lock().unwrap().as_mut() {
+            if device.write(memory, addr, value) {
+                return;
+            }
+        }
+        memory.insert(addr, value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bridge::Bridge;
+    use crate::config::Config;
+
+    #[test]
+    fn bursts_are_capped_per_bridge() {
+        let bridge = |args: &[&str]| Bridge::new(&Config::from_args(args).unwrap()).unwrap();
+        let low_latency = bridge(&["mock", "--mock", "--low-latency"]);
+        let throughput = bridge(&["mock", "--mock", "--throughput"]);
+        assert_eq!(low_latency.max_burst(), 4);
+        assert_eq!(throughput.max_burst(), 4096);
+        // Making another bridge doesn't change the first one's
+        let _ = bridge(&["mock", "--mock"]);
+        assert_eq!(low_latency.max_burst(), 4);
+    }
+}
@@ -0,0 +1,239 @@
+//! A VexRiscv hart and its debug unit, for tests, in front of the mock
+//! bridge's memory.  It runs the instructions the adapter feeds the debug
+//! unit and steps through code in memory, which is enough to check what
+//! the adapter leaves behind on a hart without any hardware.  Only the
+//! instructions the adapter uses itself are understood.  Anything else,
+//! and any CSR the hart wasn't given, traps the way it would on a core
+//! built without it.
+
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+
+use super::bridge::Bridge;
+use super::mock_bridge::Device;
+
+/// Where the debug unit is, which is where the adapter looks by default
+pub const DEBUG_BASE: u32 = 0xf00f_0000;
+
+const HALT: u32 = 1 << 1;
+const HALTED_BY_BREAK: u32 = 1 << 3;
+const STEP: u32 = 1 << 4;
+const HALT_SET: u32 = 1 << 17;
+const HALT_CLEAR: u32 = 1 << 25;
+
+const COMPARATOR_BASE: u32 = 0x40;
+
+const MTVEC: u32 = 0x305;
+const MEPC: u32 = 0x341;
+const MCAUSE: u32 = 0x342;
+
+/// mstatus, misa, mie, mtvec, mscratch, mepc, mcause and mtval.  A misa of
+/// zero says nothing about which extensions there are.
+const MACHINE_CSRS: &[u32] = &[0x300, 0x301, 0x304, MTVEC, 0x340, MEPC, MCAUSE, 0x343];
+
+const ILLEGAL_INSTRUCTION: u32 = 2;
+
+pub struct MockHart {
+    pub halted: bool,
+    pub halted_by_break: bool,
+    pub pc: u32,
+    pub x: [u32; 32],
+
+    /// The CSRs the core was built with
+    pub csrs: HashMap<u32, u32>,
+
+    /// The breakpoint comparators, holding the pc of the enabled ones
+    pub comparators: Vec<Option<u32>>,
+
+    /// The address of every instruction stepped through
+    pub stepped: Vec<u32>,
+
+    /// Memory that stores leave alone, as ROM and flash do
+    pub read_only: Vec<Range<u32>>,
+
+    /// What the last instruction fed to the debug unit wrote to its
+    /// destination register, which is what reading the unit back gives
+    result: u32,
+}
+
+impl MockHart {
+    /// A halted hart at `pc` with `comparators` hardware breakpoints and
+    /// only the basic machine-mode CSRs
+    pub fn new(pc: u32, comparators: usize) -> MockHart {
+        MockHart {
+            halted: true,
+            halted_by_break: false,
+            pc,
+            x: [0; 32],
+            csrs: MACHINE_CSRS.iter().map(|&csr| (csr, 0)).collect(),
+            comparators: vec![None; comparators],
+            stepped: vec![],
+            read_only: vec![],
+            result: 0,
+        }
+    }
+
+    /// Put the hart on `bridge`, which has to be the mock one, where the
+    /// adapter expects the first hart's debug unit
+    pub fn attach(self, bridge: &Bridge) -> Arc<Mutex<MockHart>> {
+        let hart = Arc::new(Mutex::new(self));
+        bridge.attach(Box::new(Attached(hart.clone())));
+        hart
+    }
+
+    fn status(&self) -> u32 {
+        let mut status = 0;
+        if self.halted {
+            status |= HALT;
+        }
+        if self.halted_by_break {
+            status |= HALTED_BY_BREAK;
+        }
+        status
+    }
+
+    fn control(&mut self, memory: &mut HashMap<u32, u32>, value: u32) {
+        if value & HALT_SET != 0 {
+            self.halted = true;
+        }
+        if value & HALT_CLEAR == 0 {
+            return;
+        }
+        self.halted_by_break = false;
+        if value & STEP == 0 {
+            self.halted = false;
+        } else if self.comparators.contains(&Some(self.pc)) {
+            self.halted_by_break = true;
+        } else {
+            let pc = self.pc;
+            let inst = load(memory, pc, 4);
+            self.stepped.push(pc);
+            if !self.execute(memory, inst) {
+                self.pc = pc.wrapping_add(4);
+            }
+        }
+    }
+
+    /// Run `inst` at pc, returning whether it moved pc itself
+    fn execute(&mut self, memory: &mut HashMap<u32, u32>, inst: u32) -> bool {
+        let rd = (inst >> 7) & 31;
+        let funct3 = (inst >> 12) & 7;
+        let rs1 = self.x[((inst >> 15) & 31) as usize];
+        let rs2 = self.x[((inst >> 20) & 31) as usize];
+        let imm_i = (inst as i32 >> 20) as u32;
+        let imm_s = ((inst as i32 >> 25) << 5) as u32 | ((inst >> 7) & 31);
+        let csr = inst >> 20;
+        let mut jumped = false;
+        let value = match (inst & 0x7f, funct3) {
+            // LUI, AUIPC
+            (0x37, _) => inst & 0xffff_f000,
+            (0x17, _) => self.pc.wrapping_add(inst & 0xffff_f000),
+            // ADDI, ORI
+            (0x13, 0) => rs1.wrapping_add(imm_i),
+            (0x13, 6) => rs1 | imm_i,
+            // LB, LH, LW, LBU, LHU
+            (0x03, 0 | 1 | 2 | 4 | 5) => {
+                let size = 1 << (funct3 & 3);
+                let value = load(memory, rs1.wrapping_add(imm_i), size);
+                match funct3 {
+                    0 => value as i8 as u32,
+                    1 => value as i16 as u32,
+                    _ => value,
+                }
+            }
+            // SB, SH, SW
+            (0x23, 0..=2) => {
+                let addr = rs1.wrapping_add(imm_s);
+                if !self.read_only.iter().any(|r| r.contains(&addr)) {
+                    store(memory, addr, 1 << funct3, rs2);
+                }
+                return false;
+            }
+            // JALR
+            (0x67, 0) => {
+                jumped = true;
+                let link = self.pc.wrapping_add(4);
+                self.pc = rs1.wrapping_add(imm_i) & !1;
+                link
+            }
+            // FENCE.I
+            (0x0f, 1) => return false,
+            // CSRRW, CSRRS
+            (0x73, 1 | 2) if self.csrs.contains_key(&csr) => {
+                let old = self.csrs[&csr];
+                let new = if funct3 == 1 { rs1 } else { old | rs1 };
+                if funct3 == 1 || (inst >> 15) & 31 != 0 {
+                    self.csrs.insert(csr, new);
+                }
+                old
+            }
+            _ => {
+                self.trap(ILLEGAL_INSTRUCTION);
+                return true;
+            }
+        };
+        self.result = value;
+        if rd != 0 {
+            self.x[rd as usize] = value;
+        }
+        jumped
+    }
+
+    fn trap(&mut self, cause: u32) {
+        self.csrs.insert(MEPC, self.pc);
+        self.csrs.insert(MCAUSE, cause);
+        self.pc = self.csrs[&MTVEC] & !3;
+    }
+}
+
+fn load(memory: &HashMap<u32, u32>, addr: u32, size: u32) -> u32 {
+    let word = *memory.get(&(addr & !3)).unwrap_or(&0) >> ((addr & 3) * 8);
+    match size {
+        4 => word,
+        size => word & ((1 << (size * 8)) - 1),
+    }
+}
+
+fn store(memory: &mut HashMap<u32, u32>, addr: u32, size: u32, value: u32) {
+    let shift = (addr & 3) * 8;
+    let mask = match size {
+        4 => u32::MAX,
+        size => ((1 << (size * 8)) - 1) << shift,
+    };
+    let word = memory.entry(addr & !3).or_insert(0);
+    *word = (*word & !mask) | ((value << shift) & mask);
+}
+
+struct Attached(Arc<Mutex<MockHart>>);
+
+impl Device for Attached {
+    fn read(&mut self, _memory: &mut HashMap<u32, u32>, addr: u32) -> Option<u32> {
+        let hart = self.0.lock().unwrap();
+        match addr.checked_sub(DEBUG_BASE)? {
+            0 => Some(hart.status()),
+            4 => Some(hart.result),
+            offset if offset < 0x100 => Some(0),
+            _ => None,
+        }
+    }
+
+    fn write(&mut self, memory: &mut HashMap<u32, u32>, addr: u32, value: u32) -> bool {
+        let mut hart = self.0.lock().unwrap();
+        match addr.checked_sub(DEBUG_BASE) {
+            Some(0) => hart.control(memory, value),
+            Some(4) if hart.halted => {
+                hart.execute(memory, value);
+            }
+            Some(offset) if (COMPARATOR_BASE..0x100).contains(&offset) => {
+                let index = ((offset - COMPARATOR_BASE) / 4) as usize;
+                if let Some(comparator) = hart.comparators.get_mut(index) {
+                    *comparator = Some(value & !1).filter(|_| value & 1 != 0);
+                }
+            }
+            Some(offset) if offset < 0x100 => (),
+            _ => return false,
+        }
+        true
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt;
+
+use super::bridge::{Bridge, BridgeError};
+
+/// What makes a polled watch fire
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Condition {
+    /// The masked value is different from the last sample
+    Changed,
+
+    /// The masked value has become this
+    Matches(u32),
+}
+
+/// A word the adapter reads over the bus while the CPU runs, for targets
+/// whose debug unit has no triggers to spare.  This only sees the value
+/// each time it's sampled, so a write that's undone before the next
+/// sample goes unnoticed, and the core is halted some time after the
+/// write that did it rather than on it.
+pub struct PollWatch {
+    /// Word-aligned address that's sampled
+    pub addr: u32,
+    pub mask: u32,
+    pub condition: Condition,
+
+    /// The address to report when it fires
+    pub report: u32,
+
+    /// The `Z2` this stands in for, as (address, length), or `None` for a
+    /// watch set with `monitor watch`
+    pub gdb: Option<(u32, u32)>,
+
+    /// Masked value at the last sample
+    last: u32,
+}
+
+impl fmt::Display for PollWatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:08x} mask {:08x}", self.addr, self.mask)?;
+        if let Condition::Matches(value) = self.condition {
+            write!(f, " value {:08x}", value)?;
+        }
+        if self.gdb.is_some() {
+            write!(f, " (for GDB)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Every polled watch that's set
+#[derive(Default)]
+pub struct PollWatches {
+    watches: Vec<PollWatch>,
+}
+
+impl PollWatches {
+    pub fn is_empty(&self) -> bool {
+        self.watches.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PollWatch> {
+        self.watches.iter()
+    }
+
+    /// Watch the word holding `addr`, taking the current value as where
+    /// it starts from
+    pub fn add(
+        &mut self,
+        bridge: &Bridge,
+        addr: u32,
+        mask: u32,
+        condition: Condition,
+    ) -> Result<(), BridgeError> {
+        self.push(bridge, addr & !3, mask, condition, addr, None)
+    }
+
+    /// Stand in for a write watchpoint GDB asked for.  Each word the range
+    /// touches is watched for a change in the bytes that are in range.
+    pub fn add_gdb(&mut self, bridge: &Bridge, addr: u32, len: u32) -> Result<(), BridgeError> {
+        let end = addr as u64 + len.max(1) as u64;
+        let mut word = addr & !3;
+        while (word as u64) < end {
+            let mut mask = 0;
+            for byte in 0..4 {
+                let a = word as u64 + byte;
+                if a >= addr as u64 && a < end {
+                    mask |= 0xff << (8 * byte);
+                }
+            }
+            self.push(
+                bridge,
+                word,
+                mask,
+                Condition::Changed,
+                addr,
+                Some((addr, len)),
+            )?;
+            word = match word.checked_add(4) {
+                Some(w) => w,
+                None => break,
+            };
+        }
+        Ok(())
+    }
+
+    fn push(
+        &mut self,
+        bridge: &Bridge,
+        addr: u32,
+        mask: u32,
+        condition: Condition,
+        report: u32,
+        gdb: Option<(u32, u32)>,
+    ) -> Result<(), BridgeError> {
+        let last = bridge.peek(addr)? & mask;
+        self.watches.push(PollWatch {
+            addr,
+            mask,
+            condition,
+            report,
+            gdb,
+            last,
+        });
+        Ok(())
+    }
+
+    /// Stop standing in for a `Z2`, returning whether this was doing so
+    pub fn remove_gdb(&mut self, addr: u32, len: u32) -> bool {
+        let before = self.watches.len();
+        self.watches.retain(|w| w.gdb != Some((addr, len)));
+        self.watches.len() != before
+    }
+
+    /// Remove the watch numbered `idx` in the listing
+    pub fn remove(&mut self, idx: usize) -> bool {
+        if idx < self.watches.len() {
+            self.watches.remove(idx);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.watches.clear();
+    }
+
+    /// Take fresh samples before the CPU runs, since the debugger may have
+    /// changed memory while it was halted
+    pub fn rearm(&mut self, bridge: &Bridge) -> Result<(), BridgeError> {
+        for w in &mut self.watches {
+            w.last = bridge.peek(w.addr)? & w.mask;
+        }
+        Ok(())
+    }
+
+    /// Sample every watch, returning the address to report for the first
+    /// one that fired
+    pub fn check(&mut self, bridge: &Bridge) -> Result<Option<u32>, BridgeError> {
+        let mut fired = None;
+        for w in &mut self.watches {
+            let value = bridge.peek(w.addr)? & w.mask;
+            let hit = match w.condition {
+                Condition::Changed => value != w.last,
+                Condition::Matches(want) => value == want & w.mask && w.last != value,
+            };
+            w.last = value;
+            if hit && fired.is_none() {
+                fired = Some(w.report);
+            }
+        }
+        Ok(fired)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Condition, PollWatches};
+    use crate::bridge::Bridge;
+    use crate::config::Config;
+
+    fn bridge() -> Bridge {
+        let cfg = Config::from_args(["poll_watch", "--mock"]).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        bridge
+    }
+
+    #[test]
+    fn gdb_ranges_are_split_into_words() {
+        let bridge = bridge();
+        let mut watches = PollWatches::default();
+        watches.add_gdb(&bridge, 0x4000_0002, 4).unwrap();
+        let words: Vec<(u32, u32)> = watches.iter().map(|w| (w.addr, w.mask)).collect();
+        assert_eq!(
+            words,
+            vec![(0x4000_0000, 0xffff_0000), (0x4000_0004, 0x0000_ffff)]
+        );
+        assert!(watches.iter().all(|w| w.report == 0x4000_0002));
+
+        // The top of the address space doesn't wrap around
+        let mut watches = PollWatches::default();
+        watches.add_gdb(&bridge, 0xffff_fffe, 4).unwrap();
+        assert_eq!(watches.iter().count(), 1);
+    }
+
+    #[test]
+    fn only_bytes_in_range_fire() {
+        let bridge = bridge();
+        let mut watches = PollWatches::default();
+        watches.add_gdb(&bridge, 0x4000_0001, 1).unwrap();
+        bridge.poke(0x4000_0000, 0xff00_00ff).unwrap();
+        assert_eq!(watches.check(&bridge).unwrap(), None);
+        bridge.poke(0x4000_0000, 0xff00_01ff).unwrap();
+        assert_eq!(watches.check(&bridge).unwrap(), Some(0x4000_0001));
+
+        // Each sample is compared with the one before
+        assert_eq!(watches.check(&bridge).unwrap(), None);
+    }
+
+    #[test]
+    fn matches_fire_on_becoming_the_value() {
+        let bridge = bridge();
+        let mut watches = PollWatches::default();
+        watches
+            .add(&bridge, 0x4000_0010, 0xff, Condition::Matches(0x42))
+            .unwrap();
+        bridge.poke(0x4000_0010, 0x1234_5642).unwrap();
+        assert_eq!(watches.check(&bridge).unwrap(), Some(0x4000_0010));
+        bridge.poke(0x4000_0010, 0x42).unwrap();
+        assert_eq!(watches.check(&bridge).unwrap(), None);
+
+        // Rearming takes what the debugger left as the starting point
+        bridge.poke(0x4000_0010, 0).unwrap();
+        watches.rearm(&bridge).unwrap();
+        bridge.poke(0x4000_0010, 0x42).unwrap();
+        assert_eq!(watches.check(&bridge).unwrap(), Some(0x4000_0010));
+    }
+
+    #[test]
+    fn removal() {
+        let bridge = bridge();
+        let mut watches = PollWatches::default();
+        watches
+            .add(&bridge, 0x4000_0000, !0, Condition::Changed)
+            .unwrap();
+        watches.add_gdb(&bridge, 0x4000_0006, 4).unwrap();
+        assert!(!watches.remove_gdb(0x4000_0006, 2));
+        assert!(watches.remove_gdb(0x4000_0006, 4));
+        assert!(!watches.remove_gdb(0x4000_0006, 4));
+        assert_eq!(watches.iter().count(), 1);
+        assert!(!watches.remove(1));
+        assert!(watches.remove(0));
+        assert!(watches.is_empty());
+    }
+}
@@ -0,0 +1,409 @@
+use std::fmt;
+use std::io;
+use std::thread;
+use std::time::Duration;
+
+use super::config::ConfigError;
+use super::utils::{parse_u16, parse_u32};
+
+/// How long the board is left off when its power is cycled, which is long
+/// enough for regulators to drain and the FPGA to lose its configuration
+const CYCLE_OFF_TIME: Duration = Duration::from_secs(1);
+
+/// HID relay boards sold as "USBRelay1", "USBRelay2" and so on
+const RELAY_VID: u16 = 0x16c0;
+const RELAY_PID: u16 = 0x05df;
+
+/// FT230X, as found on many FPGA boards' programming interface
+const FTDI_VID: u16 = 0x0403;
+const FTDI_PID: u16 = 0x6015;
+
+/// What switches the board's power, parsed from a string such as
+/// `gpio:17`, `relay:1` or `ftdi:2@0403:6015`
+#[derive(Clone, Debug)]
+pub enum PowerSpec {
+    /// A GPIO exported through sysfs, driven high for on
+    Gpio(u32),
+
+    /// A channel of a HID USB relay board, closed for on
+    Relay { channel: u8, vid: u16, pid: u16 },
+
+    /// One of an FTDI chip's CBUS pins in bit-bang mode, driven high for on
+    Ftdi { bit: u8, vid: u16, pid: u16 },
+}
+
+impl PowerSpec {
+    pub fn parse(spec: &str) -> Result<PowerSpec, ConfigError> {
+        let invalid = || ConfigError::InvalidPowerSpec(spec.to_owned());
+        let mut fields = spec.splitn(2, ':');
+        let kind = fields.next().unwrap_or_default();
+        let rest = fields.next().ok_or_else(invalid)?;
+        let mut fields = rest.splitn(2, '@');
+        let number = parse_u32(fields.next().unwrap_or_default())?;
+        let (vid, pid) = match fields.next() {
+            Some(ids) => {
+                let mut ids = ids.splitn(2, ':');
+                let vid = parse_u16(ids.next().unwrap_or_default())?;
+                let pid = parse_u16(ids.next().ok_or_else(invalid)?)?;
+                (Some(vid), Some(pid))
+            }
+            None => (None, None),
+        };
+        match kind {
+            "gpio" if vid.is_none() => Ok(PowerSpec::Gpio(number)),
+            "relay" if (1..=8).contains(&number) => Ok(PowerSpec::Relay {
+                channel: number as u8,
+                vid: vid.unwrap_or(RELAY_VID),
+                pid: pid.unwrap_or(RELAY_PID),
+            }),
+            "ftdi" if number < 4 => Ok(PowerSpec::Ftdi {
+                bit: number as u8,
+                vid: vid.unwrap_or(FTDI_VID),
+                pid: pid.unwrap_or(FTDI_PID),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for PowerSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PowerSpec::Gpio(num) => write!(f, "GPIO {}", num),
+            PowerSpec::Relay { channel, vid, pid } => {
+                write!(f, "relay {} on {:04x}:{:04x}", channel, vid, pid)
+            }
+            PowerSpec::Ftdi { bit, vid, pid } => {
+                write!(f, "CBUS{} on {:04x}:{:04x}", bit, vid, pid)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PowerError {
+    /// Nothing with these IDs is plugged in
+    #[cfg(target_os = "linux")]
+    NotFound(u16, u16),
+
+    /// This kind of switch isn't supported on this platform or build
+    #[cfg(not(all(feature = "usbfs", target_os = "linux")))]
+    NotBuiltIn(&'static str),
+
+    IoError(io::Error),
+}
+
+impl std::convert::From<io::Error> for PowerError {
+    fn from(e: io::Error) -> Self {
+        PowerError::IoError(e)
+    }
+}
+
+impl fmt::Display for PowerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            #[cfg(target_os = "linux")]
+            PowerError::NotFound(vid, pid) => write!(f, "no {:04x}:{:04x} device found", vid, pid),
+            #[cfg(not(all(feature = "usbfs", target_os = "linux")))]
+            PowerError::NotBuiltIn(what) => write!(f, "{} isn't supported in this build", what),
+            PowerError::IoError(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Switches the board's power, so a hung board in a remote lab can be
+/// brought back without anyone touching it.  Nothing is cached: the
+/// state is read back from the switch each time it's asked for.
+pub struct PowerSwitch {
+    spec: PowerSpec,
+}
+
+impl PowerSwitch {
+    pub fn new(spec: &PowerSpec) -> PowerSwitch {
+        PowerSwitch { spec: spec.clone() }
+    }
+
+    #[cfg(feature = "server")]
+    pub fn spec(&self) -> &PowerSpec {
+        &self.spec
+    }
+
+    pub fn set(&self, on: bool) -> Result<(), PowerError> {
+        log_adapter!("Turning {} {}", self.spec, if on { "on" } else { "off" });
+        match self.spec {
+            PowerSpec::Gpio(num) => gpio::set(num, on),
+            PowerSpec::Relay { channel, vid, pid } => relay::set(vid, pid, channel, on),
+            PowerSpec::Ftdi { bit, vid, pid } => ftdi::set(vid, pid, bit, on),
+        }
+    }
+
+    /// Whether the board is powered, as far as the switch knows
+    #[cfg(feature = "server")]
+    pub fn is_on(&self) -> Result<bool, PowerError> {
+        match self.spec {
+            PowerSpec::Gpio(num) => gpio::get(num),
+            PowerSpec::Relay { channel, vid, pid } => relay::get(vid, pid, channel),
+            PowerSpec::Ftdi { bit, vid, pid } => ftdi::get(vid, pid, bit),
+        }
+    }
+
+    /// Turn the board off, wait for it to go down, and turn it back on
+    pub fn cycle(&self) -> Result<(), PowerError> {
+        self.set(false)?;
+        thread::sleep(CYCLE_OFF_TIME);
+        self.set(true)
+    }
+}
+
+/// GPIOs through the sysfs interface
+#[cfg(target_os = "linux")]
+mod gpio {
+    use std::fs;
+    use std::path::Path;
+
+    use super::PowerError;
+
+    const SYSFS_GPIO: &str = "/sys/class/gpio";
+
+    fn export(num: u32) -> Result<String, PowerError> {
+        let path = format!("{}/gpio{}", SYSFS_GPIO, num);
+        if !Path::new(&path).exists() {
+            fs::write(format!("{}/export", SYSFS_GPIO), num.to_string())?;
+        }
+        Ok(path)
+    }
+
+    pub fn set(num: u32, on: bool) -> Result<(), PowerError> {
+        let path = export(num)?;
+        // Setting the direction to a level makes it an output without
+        // glitching to the wrong level first
+        fs::write(
+            format!("{}/direction", path),
+            if on { "high" } else { "low" },
+        )?;
+        Ok(())
+    }
+
+    #[cfg(feature = "server")]
+    pub fn get(num: u32) -> Result<bool, PowerError> {
+        let path = export(num)?;
+        Ok(fs::read_to_string(format!("{}/value", path))?.trim() == "1")
+    }
+}
+
+/// HID relay boards, through the kernel's hidraw driver.  Each channel is
+/// switched with a feature report, and another feature report reads back
+/// a byte with a bit for each channel.
+#[cfg(target_os = "linux")]
+mod relay {
+    use std::fs::{self, File, OpenOptions};
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+
+    use super::PowerError;
+
+    const SYSFS_HIDRAW: &str = "/sys/class/hidraw";
+
+    /// A report number followed by the board's 8-byte report
+    const REPORT_LENGTH: usize = 9;
+
+    const CMD_ON: u8 = 0xff;
+    const CMD_OFF: u8 = 0xfd;
+
+    /// `HIDIOCSFEATURE(len)` and `HIDIOCGFEATURE(len)` from <linux/hidraw.h>
+    const HIDIOCSFEATURE: u64 =
+        (3 << 30) | ((REPORT_LENGTH as u64) << 16) | ((b'H' as u64) << 8) | 0x06;
+    #[cfg(feature = "server")]
+    const HIDIOCGFEATURE: u64 =
+        (3 << 30) | ((REPORT_LENGTH as u64) << 16) | ((b'H' as u64) << 8) | 0x07;
+
+    /// Find the hidraw node for the board by the IDs in its uevent
+    fn open(vid: u16, pid: u16) -> Result<File, PowerError> {
+        let id = format!("HID_ID=0003:{:08X}:{:08X}", vid, pid);
+        for entry in fs::read_dir(SYSFS_HIDRAW)? {
+            let entry = entry?;
+            let uevent = fs::read_to_string(entry.path().join("device/uevent")).unwrap_or_default();
+            if uevent.lines().any(|line| line == id) {
+                let node = format!("/dev/{}", entry.file_name().to_string_lossy());
+                return Ok(OpenOptions::new().read(true).write(true).open(node)?);
+            }
+        }
+        Err(PowerError::NotFound(vid, pid))
+    }
+
+    fn feature(file: &File, request: u64, report: &mut [u8; REPORT_LENGTH]) -> io::Result<()> {
+        if unsafe { libc::ioctl(file.as_raw_fd(), request as _, report.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn set(vid: u16, pid: u16, channel: u8, on: bool) -> Result<(), PowerError> {
+        let mut report = [0; REPORT_LENGTH];
+        report[1] = if on { CMD_ON } else { CMD_OFF };
+        report[2] = channel;
+        Ok(feature(&open(vid, pid)?, HIDIOCSFEATURE, &mut report)?)
+    }
+
+    #[cfg(feature = "server")]
+    pub fn get(vid: u16, pid: u16, channel: u8) -> Result<bool, PowerError> {
+        let mut report = [0; REPORT_LENGTH];
+        feature(&open(vid, pid)?, HIDIOCGFEATURE, &mut report)?;
+        Ok(report[8] & (1 << (channel - 1)) != 0)
+    }
+}
+
+/// FTDI CBUS pins, with vendor requests over usbfs.  The pins have to be
+/// set up as GPIOs in the chip's EEPROM for this to do anything, and the
+/// UART carries on as it was.  The bit mode sets the direction and level
+/// of all four pins at once, and the direction can't be read back, so the
+/// pins driven so far are remembered and driven again each time.
+#[cfg(all(feature = "usbfs", target_os = "linux"))]
+mod ftdi {
+    use std::collections::BTreeMap;
+    use std::fs::OpenOptions;
+    use std::sync::Mutex;
+
+    use super::PowerError;
+    use crate::usbfs_bridge::{control_transfer, device_path};
+
+    /// The pins driven on each chip, by vendor and product ID, as an
+    /// output mask and their levels
+    static DRIVEN: Mutex<BTreeMap<(u16, u16), (u8, u8)>> = Mutex::new(BTreeMap::new());
+
+    const SIO_SET_BITMODE: u8 = 0x0b;
+    #[cfg(feature = "server")]
+    const SIO_READ_PINS: u8 = 0x0c;
+    const BITMODE_CBUS: u16 = 0x20;
+
+    fn open(vid: u16, pid: u16) -> Result<std::fs::File, PowerError> {
+        let path = device_path(Some(vid), Some(pid))?.ok_or(PowerError::NotFound(vid, pid))?;
+        Ok(OpenOptions::new().read(true).write(true).open(path)?)
+    }
+
+    pub fn set(vid: u16, pid: u16, bit: u8, on: bool) -> Result<(), PowerError> {
+        let file = open(vid, pid)?;
+        let mut driven = DRIVEN.lock().unwrap();
+        let (mask, levels) = drive(driven.get(&(vid, pid)).copied(), bit, on);
+        let value = (BITMODE_CBUS << 8) | ((mask as u16) << 4) | levels as u16;
+        control_transfer(&file, 0x40, SIO_SET_BITMODE, value, 1, &mut [])?;
+        driven.insert((vid, pid), (mask, levels));
+        Ok(())
+    }
+
+    /// The output mask and levels to go from `driven` to `bit` being
+    /// driven `on`, leaving the other pins as they were
+    pub(super) fn drive(driven: Option<(u8, u8)>, bit: u8, on: bool) -> (u8, u8) {
+        let (mask, levels) = driven.unwrap_or_default();
+        let levels = if on {
+            levels | (1 << bit)
+        } else {
+            levels & !(1 << bit)
+        };
+        (mask | (1 << bit), levels)
+    }
+
+    #[cfg(feature = "server")]
+    pub fn get(vid: u16, pid: u16, bit: u8) -> Result<bool, PowerError> {
+        let mut pins = [0];
+        control_transfer(&open(vid, pid)?, 0xc0, SIO_READ_PINS, 0, 1, &mut pins)?;
+        Ok(pins[0] & (1 << bit) != 0)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod gpio {
+    use super::PowerError;
+
+    pub fn set(_num: u32, _on: bool) -> Result<(), PowerError> {
+        Err(PowerError::NotBuiltIn("sysfs GPIO"))
+    }
+
+    #[cfg(feature = "server")]
+    pub fn get(_num: u32) -> Result<bool, PowerError> {
+        Err(PowerError::NotBuiltIn("sysfs GPIO"))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod relay {
+    use super::PowerError;
+
+    pub fn set(_vid: u16, _pid: u16, _channel: u8, _on: bool) -> Result<(), PowerError> {
+        Err(PowerError::NotBuiltIn("HID relays"))
+    }
+
+    #[cfg(feature = "server")]
+    pub fn get(_vid: u16, _pid: u16, _channel: u8) -> Result<bool, PowerError> {
+        Err(PowerError::NotBuiltIn("HID relays"))
+    }
+}
+
+#[cfg(not(all(feature = "usbfs", target_os = "linux")))]
+mod ftdi {
+    use super::PowerError;
+
+    pub fn set(_vid: u16, _pid: u16, _bit: u8, _on: bool) -> Result<(), PowerError> {
+        Err(PowerError::NotBuiltIn("FTDI CBUS"))
+    }
+
+    #[cfg(feature = "server")]
+    pub fn get(_vid: u16, _pid: u16, _bit: u8) -> Result<bool, PowerError> {
+        Err(PowerError::NotBuiltIn("FTDI CBUS"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PowerSpec;
+
+    #[test]
+    fn specs() {
+        assert!(matches!(
+            PowerSpec::parse("gpio:17"),
+            Ok(PowerSpec::Gpio(17))
+        ));
+        assert!(matches!(
+            PowerSpec::parse("relay:2"),
+            Ok(PowerSpec::Relay {
+                channel: 2,
+                vid: 0x16c0,
+                pid: 0x05df
+            })
+        ));
+        assert!(matches!(
+            PowerSpec::parse("ftdi:3@0x0403:0x6001"),
+            Ok(PowerSpec::Ftdi {
+                bit: 3,
+                vid: 0x0403,
+                pid: 0x6001
+            })
+        ));
+        for bad in [
+            "",
+            "gpio",
+            "gpio:",
+            "gpio:x",
+            "gpio:1@0403:6015",
+            "relay:0",
+            "relay:9",
+            "ftdi:4",
+            "ftdi:1@0403",
+            "ftdi:1@0403:",
+            "usb:1",
+        ] {
+            assert!(PowerSpec::parse(bad).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[cfg(all(feature = "usbfs", target_os = "linux"))]
+    #[test]
+    fn other_cbus_pins_stay_driven() {
+        use super::ftdi::drive;
+
+        assert_eq!(drive(None, 2, true), (0b0100, 0b0100));
+        let driven = drive(Some((0b0100, 0b0100)), 0, false);
+        assert_eq!(driven, (0b0101, 0b0100));
+        assert_eq!(drive(Some(driven), 2, false), (0b0101, 0));
+    }
+}
@@ -0,0 +1,333 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use super::board::MemoryKind;
+use super::bridge::{Bridge, BridgeError};
+use super::config::Config;
+use super::memory::MemoryWriter;
+use super::mmu;
+use super::rsp::{self, Decoder};
+use super::transport::{Connection, GdbListener};
+use super::ui::Event;
+use super::utils::parse_u32;
+
+#[derive(Debug)]
+pub enum ProxyError {
+    /// Something went wrong talking to GDB or the upstream server
+    IoError(io::Error),
+
+    /// The upstream server wouldn't take the connection
+    UpstreamUnavailable(String, io::Error),
+}
+
+impl std::convert::From<io::Error> for ProxyError {
+    fn from(e: io::Error) -> Self {
+        ProxyError::IoError(e)
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProxyError::IoError(e) => write!(f, "{}", e),
+            ProxyError::UpstreamUnavailable(addr, e) => {
+                write!(f, "couldn't connect to {}: {}", addr, e)
+            }
+        }
+    }
+}
+
+/// Acknowledgement state shared by the two directions of a session.  Each
+/// side's acks are handled here rather than passed through, since packets
+/// answered locally never reach the upstream server.
+#[derive(Default)]
+struct AckState {
+    /// GDB asked for no-ack mode, and we're waiting to hear if upstream
+    /// agrees
+    pending: AtomicBool,
+
+    /// Neither side acknowledges packets any more
+    disabled: AtomicBool,
+}
+
+/// Sits between GDB and another GDB server, such as QEMU or OpenOCD,
+/// passing everything through except memory accesses that fall inside the
+/// intercepted ranges.  Those go out over the bridge instead, so a
+/// simulated CPU can be pointed at real peripherals.
+pub struct GdbProxy {
+    upstream: String,
+
+    /// (base, size) of each range served from the bridge
+    ranges: Vec<(u32, u32)>,
+
+    writer: MemoryWriter,
+}
+
+impl GdbProxy {
+    /// Intercept the ranges given with `--proxy-range`, or every I/O region
+    /// of the memory map if there weren't any.  Empty ranges can't catch
+    /// anything, and are left out.
+    pub fn new(cfg: &Config, upstream: &str) -> GdbProxy {
+        let ranges: Vec<(u32, u32)> = if cfg.proxy_ranges.is_empty() {
+            cfg.memory_map
+                .iter()
+                .filter(|r| r.kind == MemoryKind::Io)
+                .map(|r| (r.base, r.size))
+                .filter(|&(_, size)| size > 0)
+                .collect()
+        } else {
+            cfg.proxy_ranges
+                .iter()
+                .copied()
+                .filter(|&(_, size)| size > 0)
+                .collect()
+        };
+        if ranges.is_empty() {
+            ui_error!("Warning: no ranges to intercept, so everything goes upstream");
+        }
+        for &(base, size) in &ranges {
+            ui_info!(
+                "Serving {:08x}-{:08x} from the bridge",
+                base,
+                base as u64 + size as u64 - 1
+            );
+        }
+        GdbProxy {
+            upstream: upstream.to_owned(),
+            ranges,
+            writer: MemoryWriter::new(cfg),
+        }
+    }
+
+    /// Wait for GDB, connect it to the upstream server, and relay between
+    /// them until either one goes away
+    pub fn serve(&mut self, listener: &GdbListener, bridge: &Bridge) -> Result<(), ProxyError> {
+        let (connection, peer) = listener.accept()?;
+        ui_event!(Event::Attach, "GDB connected from {}", peer);
+        let upstream = TcpStream::connect(&self.upstream)
+            .map_err(|e| ProxyError::UpstreamUnavailable(self.upstream.clone(), e))?;
+        upstream.set_nodelay(true)?;
+        ui_info!("Relaying to {}", self.upstream);
+
+        let gdb = Arc::new(Mutex::new(connection.try_clone()?));
+        let acks = Arc::new(AckState::default());
+        let relay = {
+            let gdb = gdb.clone();
+            let acks = acks.clone();
+            let upstream = upstream.try_clone()?;
+            thread::spawn(move || relay_upstream(upstream, gdb, acks))
+        };
+        let result = self.relay_gdb(connection, &upstream, &gdb, &acks, bridge);
+
+        // Whichever side ended it, make sure the other one does too
+        let _ = upstream.shutdown(Shutdown::Both);
+        gdb.lock().unwrap().shutdown();
+        let _ = relay.join();
+        result
+    }
+
+    /// Take packets from GDB, answering the ones we intercept and passing
+    /// the rest upstream
+    fn relay_gdb(
+        &mut self,
+        mut connection: Connection,
+        mut upstream: &TcpStream,
+        gdb: &Mutex<Connection>,
+        acks: &AckState,
+        bridge: &Bridge,
+    ) -> Result<(), ProxyError> {
+        let mut decoder = Decoder::default();
+        let mut buffer = [0; 4096];
+        loop {
+            let len = connection.read(&mut buffer)?;
+            if len == 0 {
+                return Ok(());
+            }
+            for &byte in &buffer[..len] {
+                match decoder.push(byte) {
+                    Some(rsp::Event::Packet(packet)) => {
+                        if !acks.disabled.load(Ordering::SeqCst) {
+                            gdb.lock().unwrap().send(b"+")?;
+                        }
+                        if let Some(reply) = self.intercept(bridge, &packet) {
+                            log_gdb!("proxy <- {}", String::from_utf8_lossy(&reply));
+                            gdb.lock().unwrap().send(&rsp::frame(&reply))?;
+                            continue;
+                        }
+                        if packet == b"QStartNoAckMode" {
+                            acks.pending.store(true, Ordering::SeqCst);
+                        }
+                        upstream.write_all(&rsp::frame(&packet))?;
+                    }
+                    Some(rsp::Event::BadChecksum(_)) | Some(rsp::Event::Overflow) => {
+                        gdb.lock().unwrap().send(b"-")?;
+                    }
+                    Some(rsp::Event::Interrupt) => upstream.write_all(&[0x03])?,
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    fn intercepts(&self, addr: u32, len: u32) -> bool {
+        let end = addr as u64 + len as u64;
+        len > 0
+            && self
+                .ranges
+                .iter()
+                .any(|&(base, size)| addr >= base && end <= base as u64 + size as u64)
+    }
+
+    /// The reply to a memory access inside an intercepted range, or `None`
+    /// if the packet should go upstream.  Accesses that only partly
+    /// overlap a range go upstream whole.
+    fn intercept(&mut self, bridge: &Bridge, packet: &[u8]) -> Option<Vec<u8>> {
+        let (&kind, body) = packet.split_first()?;
+        if !b"mMX".contains(&kind) {
+            return None;
+        }
+        let body = match body.iter().position(|&b| b == b':') {
+            Some(idx) => (&body[..idx], Some(&body[idx + 1..])),
+            None => (body, None),
+        };
+        let header = String::from_utf8_lossy(body.0);
+        let mut fields = header.split(',');
+        let addr = parse_u32(&format!("0x{}", fields.next()?)).ok()?;
+        let len = parse_u32(&format!("0x{}", fields.next()?)).ok()?;
+        if !self.intercepts(addr, len) {
+            return None;
+        }
+        let result = match (kind, body.1) {
+            (b'm', None) => mmu::read_physical(bridge, addr, len).map(|data| {
+                data.iter()
+                    .map(|b| format!("{:02x}", b))
+                    .collect::<String>()
+                    .into_bytes()
+            }),
+            (b'M', Some(hex)) => match rsp::decode_hex(hex) {
+                Some(data) => self.write(bridge, addr, &data),
+                None => return Some(b"E01".to_vec()),
+            },
+            (b'X', Some(binary)) => self.write(bridge, addr, &rsp::unescape(binary)),
+            _ => return None,
+        };
+        Some(result.unwrap_or_else(|e| {
+            log_bridge!(@Verbose, "proxy: access to {:08x} failed: {:?}", addr, e);
+            b"E01".to_vec()
+        }))
+    }
+
+    fn write(&mut self, bridge: &Bridge, addr: u32, data: &[u8]) -> Result<Vec<u8>, BridgeError> {
+        self.writer
+            .write(bridge, addr, data)
+            .and_then(|_| self.writer.flush(bridge))
+            .map_err(|e| {
+                log_bridge!(@Verbose, "proxy: write to {:08x} failed: {}", addr, e);
+                BridgeError::BusError
+            })?;
+        Ok(b"OK".to_vec())
+    }
+}
+
+/// Pass packets from the upstream server back to GDB
+fn relay_upstream(mut upstream: TcpStream, gdb: Arc<Mutex<Connection>>, acks: Arc<AckState>) {
+    let mut decoder = Decoder::default();
+    let mut buffer = [0; 4096];
+    loop {
+        let len = match upstream.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(len) => len,
+        };
+        for &byte in &buffer[..len] {
+            let packet = match decoder.push(byte) {
+                Some(rsp::Event::Packet(packet)) => packet,
+                Some(rsp::Event::BadChecksum(_)) => {
+                    let _ = upstream.write_all(b"-");
+                    continue;
+                }
+                _ => continue,
+            };
+            if !acks.disabled.load(Ordering::SeqCst) && upstream.write_all(b"+").is_err() {
+                break;
+            }
+            // The OK to QStartNoAckMode is the last packet either side acks
+            if acks.pending.swap(false, Ordering::SeqCst) && packet == b"OK" {
+                acks.disabled.store(true, Ordering::SeqCst);
+            }
+            if gdb.lock().unwrap().send(&rsp::frame(&packet)).is_err() {
+                break;
+            }
+        }
+    }
+    log_adapter!("proxy: upstream connection closed");
+    gdb.lock().unwrap().shutdown();
+}
+
+#[cfg(test)]
+mod test {
+    use super::GdbProxy;
+    use crate::bridge::Bridge;
+    use crate::config::Config;
+
+    fn proxy(ranges: &str) -> (GdbProxy, Bridge) {
+        let cfg = Config::from_args([
+            "proxy",
+            "--mock",
+            "--gdb-proxy",
+            "localhost:3333",
+            "--proxy-range",
+            ranges,
+        ])
+        .unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        (GdbProxy::new(&cfg, "localhost:3333"), bridge)
+    }
+
+    #[test]
+    fn ranges() {
+        let (proxy, _) = proxy("0:0,0xf0000000:0x100,0xffffff00:0x100");
+        assert_eq!(proxy.ranges, [(0xf000_0000, 0x100), (0xffff_ff00, 0x100)]);
+        assert!(proxy.intercepts(0xf000_0000, 4));
+        assert!(proxy.intercepts(0xf000_00fc, 4));
+        assert!(!proxy.intercepts(0xf000_00fe, 4));
+        assert!(!proxy.intercepts(0xefff_fffc, 8));
+        assert!(!proxy.intercepts(0xf000_0000, 0));
+        assert!(proxy.intercepts(0xffff_fffc, 4));
+        assert!(!proxy.intercepts(0xffff_fffc, 8));
+    }
+
+    #[test]
+    fn packets() {
+        let (mut proxy, bridge) = proxy("0xf0000000:0x100");
+        bridge.poke(0xf000_0000, 0x4433_2211).unwrap();
+        let mut intercept = |packet: &[u8]| {
+            proxy
+                .intercept(&bridge, packet)
+                .map(|reply| String::from_utf8(reply).unwrap())
+        };
+        assert_eq!(intercept(b"mf0000001,3").as_deref(), Some("223344"));
+        assert_eq!(intercept(b"Mf0000004,2:abcd").as_deref(), Some("OK"));
+        assert_eq!(intercept(b"Xf0000008,2:}]#").as_deref(), Some("OK"));
+        assert_eq!(intercept(b"Mf0000000,2:zz").as_deref(), Some("E01"));
+
+        // Anything else, or anything not wholly inside, goes upstream
+        for packet in [
+            &b"m40000000,4"[..],
+            b"mf00000fe,4",
+            b"mf0000000",
+            b"mzz,4",
+            b"Mf0000000,4",
+            b"qSupported",
+            b"",
+        ] {
+            assert_eq!(intercept(packet), None, "{:?}", packet);
+        }
+        assert_eq!(bridge.peek(0xf000_0004).unwrap(), 0xcdab);
+        assert_eq!(bridge.peek(0xf000_0008).unwrap(), 0x237d);
+    }
+}
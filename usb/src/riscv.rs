@@ -1,4 +1,14 @@
+// Without the server, the only thing driving the CPU is `--capabilities`,
+// which needs a handful of what's here
+#![cfg_attr(not(feature = "server"), allow(dead_code))]
+
+use std::sync::Mutex;
+
+use super::board::{MemoryKind, MemoryRegion};
 use super::bridge::{Bridge, BridgeError};
+use super::config::Config;
+use super::lock;
+use super::virtual_register::VirtualRegister;
 
 bitflags! {
     struct VexRiscvFlags: u32 {
@@ -18,13 +28,288 @@ bitflags! {
 pub enum RiscvCpuError {
     /// Someone tried to request an unrecognized feature file
     UnrecognizedFile(String /* requested filename */),
+
+    /// A run-control group named a hart that doesn't exist, or a hart
+    /// was put in more than one group
+    InvalidHartGroup(usize),
+
+    /// GDB asked for a register number we don't have
+    InvalidRegister(u32),
+
+    /// The bridge failed while talking to the debug unit
+    BridgeError(BridgeError),
+}
+
+impl std::convert::From<BridgeError> for RiscvCpuError {
+    fn from(e: BridgeError) -> Self {
+        RiscvCpuError::BridgeError(e)
+    }
+}
+
+/// GDB numbers x0-x31 as 0-31 and pc as 32
+const PC_REGNUM: u32 = 32;
+
+/// ABI names of x0-x31
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// GDB's number for a general register or the pc, by its ABI name, its
+/// `x` name, or `fp`
+pub fn register_number(name: &str) -> Option<u32> {
+    match name {
+        "pc" => Some(PC_REGNUM),
+        "fp" => Some(8),
+        _ => name
+            .strip_prefix('x')
+            .and_then(|n| n.parse::<u32>().ok())
+            .filter(|n| *n < 32 && format!("x{}", n) == name)
+            .or_else(|| ABI_NAMES.iter().position(|n| *n == name).map(|n| n as u32)),
+    }
+}
+
+/// GDB register number of the first CSR
+const CSR_REGNUM_BASE: u32 = 65;
+
+/// GDB register number of v0.  v1-v31 follow it.
+const VECTOR_REGNUM_BASE: u32 = 4162;
+
+/// `misa` and the bit that says the vector extension is implemented
+const CSR_MISA: u32 = 0x301;
+const MISA_V: u32 = 1 << 21;
+
+/// Vector CSRs, which only exist if `misa` says so
+const CSR_VL: u32 = 0xc20;
+const CSR_VTYPE: u32 = 0xc21;
+const CSR_VLENB: u32 = 0xc22;
+const VECTOR_CSRS: &[(u32, &str)] = &[
+    (0x008, "vstart"),
+    (0x009, "vxsat"),
+    (0x00a, "vxrm"),
+    (0x00f, "vcsr"),
+    (CSR_VL, "vl"),
+    (CSR_VTYPE, "vtype"),
+    (CSR_VLENB, "vlenb"),
+];
+
+/// Ways of looking at a vector register: union field, vector type,
+/// element type, and element size in bytes
+const VECTOR_ELEMENT_TYPES: &[(&str, &str, &str, u32)] = &[
+    ("b", "bytes", "uint8", 1),
+    ("s", "shorts", "uint16", 2),
+    ("w", "words", "uint32", 4),
+    ("l", "longs", "uint64", 8),
+];
+
+/// Whether the CPU implements the vector extension
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum VectorSupport {
+    /// Nobody has asked yet
+    Unknown,
+
+    /// `misa` doesn't have the V bit set
+    Absent,
+
+    /// Present, with vector registers `vlenb` bytes wide
+    Present { vlenb: u32 },
+}
+
+impl VectorSupport {
+    /// GDB register number of the first virtual register, which follows
+    /// v31 if there are vector registers and the last CSR if there aren't
+    fn virtual_regnum_base(self) -> u32 {
+        match self {
+            VectorSupport::Present { .. } => VECTOR_REGNUM_BASE + 32,
+            _ => VECTOR_REGNUM_BASE,
+        }
+    }
+}
+
+/// Trap vector base and the cause of the most recent trap
+const CSR_MTVEC: u32 = 0x305;
+const CSR_MCAUSE: u32 = 0x342;
+
+/// The machine-mode CSRs that a call into firmware can disturb, and so are
+/// saved along with the registers.  `mstatus` goes last when they're put
+/// back, so interrupts aren't turned on before the rest is in place.
+const CONTEXT_CSRS: &[u32] = &[0x305, 0x340, 0x341, 0x342, 0x343, 0x304, 0x300];
+
+/// The trigger module, from the RISC-V debug spec.  `tselect` picks a
+/// trigger and `tdata1` and `tdata2` configure it.
+const CSR_TSELECT: u32 = 0x7a0;
+const CSR_TDATA1: u32 = 0x7a1;
+const CSR_TDATA2: u32 = 0x7a2;
+
+/// Most triggers looked for
+const MAX_TRIGGERS: u32 = 32;
+
+/// `tdata1.type` for an address/data match trigger.  A type of 0 means
+/// there's no trigger at that index.
+const TRIGGER_TYPE_MCONTROL: u32 = 2;
+
+/// `mcontrol` fields
+const MCONTROL_DMODE: u32 = 1 << 27;
+const MCONTROL_HIT: u32 = 1 << 20;
+const MCONTROL_ACTION_DEBUG: u32 = 1 << 12;
+const MCONTROL_MATCH_NAPOT: u32 = 1 << 7;
+const MCONTROL_M: u32 = 1 << 6;
+const MCONTROL_S: u32 = 1 << 4;
+const MCONTROL_U: u32 = 1 << 3;
+const MCONTROL_EXECUTE: u32 = 1 << 2;
+const MCONTROL_STORE: u32 = 1 << 1;
+const MCONTROL_LOAD: u32 = 1 << 0;
+
+/// Names for the standard exception causes, as used by `monitor catch`
+pub const TRAP_CAUSES: &[(u32, &str)] = &[
+    (0, "misaligned-fetch"),
+    (1, "fetch-fault"),
+    (2, "illegal"),
+    (3, "breakpoint"),
+    (4, "misaligned-load"),
+    (5, "load-fault"),
+    (6, "misaligned-store"),
+    (7, "store-fault"),
+    (8, "ecall-u"),
+    (9, "ecall-s"),
+    (11, "ecall-m"),
+    (12, "fetch-page-fault"),
+    (13, "load-page-fault"),
+    (15, "store-page-fault"),
+];
+
+/// Offset of the first hardware breakpoint register in the debug unit.
+/// Each one holds a PC, with bit 0 set when it's enabled.
+const HW_BREAKPOINT_BASE: u32 = 0x40;
+
+/// Hardware breakpoint kept on the trap vector while traps are being caught.
+/// GDB gets the comparators after it.
+const TRAP_BREAKPOINT: u32 = 0;
+
+/// Most comparators the debug unit's register decoding has room for
+const MAX_HW_BREAKPOINTS: u32 = 48;
+
+/// How many times to check for a step to finish before giving up on it
+const STEP_POLL_LIMIT: u32 = 100;
+
+/// Furthest a load instruction can reach from its base register
+const MAX_LOAD_OFFSET: u32 = 2047;
+
+/// How much code around pc is read ahead when a hart halts.  GDB looks
+/// at the instructions on either side of pc to decode and step them.
+const PREFETCH_CODE_WINDOW: u32 = 128;
+
+/// How much of the stack above sp is read ahead when a hart halts, which
+/// covers the frames GDB unwinds for a backtrace
+const PREFETCH_STACK_WINDOW: u32 = 256;
+
+/// Why a hart most recently stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HaltReason {
+    /// GDB (or the adapter) asked for it to stop
+    Interrupted,
+
+    /// It hit a breakpoint
+    Breakpoint,
+
+    /// It finished a single step
+    Step,
+
+    /// It broke on entry to the trap handler, having taken this `mcause`
+    Trap(u32),
+
+    /// A watchpoint on this address fired
+    Watchpoint(WatchKind, u32),
+}
+
+/// What a trigger fires on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WatchKind {
+    Execute,
+    Write,
+    Read,
+    Access,
+}
+
+/// A trigger that GDB has set
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Trigger {
+    pub kind: WatchKind,
+    pub addr: u32,
+    pub len: u32,
 }
 
-const THREADS_XML: &str = r#"<?xml version="1.0"?>
-<threads>
-</threads>"#;
+/// The index of each trigger that can match addresses, along with what
+/// GDB has set it to
+type TriggerSlots = Vec<(u32, Option<Trigger>)>;
+
+impl Trigger {
+    /// The `tdata1` and `tdata2` that make a trigger fire on this, or
+    /// `None` if it's not something a trigger can match.  Single bytes are
+    /// matched exactly, and anything longer has to be a naturally aligned
+    /// power of two.
+    fn mcontrol(&self) -> Option<(u32, u32)> {
+        let (tdata2, matching) = match self.len {
+            0 | 1 => (self.addr, 0),
+            len if len.is_power_of_two() && self.addr & (len - 1) == 0 => {
+                (self.addr | (len / 2 - 1), MCONTROL_MATCH_NAPOT)
+            }
+            _ => return None,
+        };
+        let access = match self.kind {
+            WatchKind::Execute => MCONTROL_EXECUTE,
+            WatchKind::Write => MCONTROL_STORE,
+            WatchKind::Read => MCONTROL_LOAD,
+            WatchKind::Access => MCONTROL_LOAD | MCONTROL_STORE,
+        };
+        let tdata1 = (TRIGGER_TYPE_MCONTROL << 28)
+            | MCONTROL_DMODE
+            | MCONTROL_ACTION_DEBUG
+            | matching
+            | MCONTROL_M
+            | MCONTROL_S
+            | MCONTROL_U
+            | access;
+        Some((tdata1, tdata2))
+    }
+}
+
+impl HaltReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            HaltReason::Interrupted => "interrupted",
+            HaltReason::Breakpoint => "breakpoint",
+            HaltReason::Step => "step",
+            HaltReason::Trap(_) => "trap",
+            HaltReason::Watchpoint(..) => "watchpoint",
+        }
+    }
+
+    /// The signal number GDB should see for this stop.  These are GDB's
+    /// own numbers, which aren't always the same as the host's.
+    pub fn signal(self) -> u8 {
+        match self {
+            HaltReason::Interrupted => 2,
+            HaltReason::Breakpoint | HaltReason::Step | HaltReason::Watchpoint(..) => 5,
+            HaltReason::Trap(mcause) if mcause & 0x8000_0000 != 0 => 5,
+            HaltReason::Trap(mcause) => match mcause {
+                // Misaligned fetch, load or store: SIGBUS
+                0 | 4 | 6 => 10,
+                // Access and page faults: SIGSEGV
+                1 | 5 | 7 | 12 | 13 | 15 => 11,
+                // Illegal instruction: SIGILL
+                2 => 4,
+                // Environment calls: SIGSYS
+                8 | 9 | 11 => 12,
+                _ => 5,
+            },
+        }
+    }
+}
 
 #[derive(PartialEq)]
+#[allow(clippy::upper_case_acronyms)]
 enum RiscvRegisterType {
     /// Normal CPU registers
     General,
@@ -65,6 +350,14 @@ struct RiscvRegister {
 }
 
 impl RiscvRegister {
+    /// The register number GDB uses for this register
+    fn gdb_regnum(&self) -> u32 {
+        match self.register_type {
+            RiscvRegisterType::General => self.index,
+            RiscvRegisterType::CSR => self.index + CSR_REGNUM_BASE,
+        }
+    }
+
     pub fn general(index: u32, name: &str) -> RiscvRegister {
         RiscvRegister {
             register_type: RiscvRegisterType::General,
@@ -84,28 +377,222 @@ impl RiscvRegister {
     }
 }
 
+/// How often reads were answered from what was cached while halted
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub register_hits: u64,
+    pub register_misses: u64,
+    pub memory_hits: u64,
+    pub memory_misses: u64,
+    pub prefetched_bytes: u64,
+}
+
+/// What we know about a hart while it's halted.  Everything here is thrown
+/// away when the hart resumes.
+struct HartState {
+    /// Cached values of x0-x31 and pc
+    registers: Vec<Option<u32>>,
+
+    /// Registers that were overwritten while running debug instructions,
+    /// and need their cached value put back before the hart resumes
+    dirty: Vec<bool>,
+
+    /// Why the hart stopped, if we know
+    halt_reason: Option<HaltReason>,
+
+    /// Memory that was read ahead when the hart halted, as (address, data)
+    memory: Vec<(u32, Vec<u8>)>,
+}
+
+impl HartState {
+    fn new() -> HartState {
+        HartState {
+            registers: vec![None; PC_REGNUM as usize + 1],
+            dirty: vec![false; PC_REGNUM as usize + 1],
+            halt_reason: None,
+            memory: vec![],
+        }
+    }
+}
+
+/// A hart's state as of some moment, to be put back later, such as after
+/// calling a function on the target with made-up arguments
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuContext {
+    /// x0-x31 and pc, in GDB's numbering
+    pub registers: Vec<u32>,
+
+    /// (number, value) of each CSR that was saved
+    pub csrs: Vec<(u32, u32)>,
+}
+
+impl CpuContext {
+    pub fn pc(&self) -> u32 {
+        self.registers[PC_REGNUM as usize]
+    }
+}
+
+struct Hart {
+    /// The memory offset of this hart's debug register
+    debug_offset: u32,
+
+    /// The run-control group this hart belongs to.  Harts in the same group
+    /// are halted and resumed together.
+    group: usize,
+
+    /// Register cache and halt reason
+    state: Mutex<HartState>,
+}
+
+/// The target description served through qXfer:features.  target.xml
+/// only includes the feature files, which GDB then asks for one at a time
+/// by name.
+#[derive(Default)]
+pub struct TargetDescription {
+    /// File name and contents of each feature, in the order they're
+    /// included
+    features: Vec<(String, String)>,
+}
+
+impl TargetDescription {
+    /// Add a file describing the feature `name`, holding `body`
+    pub fn add_feature(&mut self, file: &str, name: &str, body: String) {
+        let xml = format!(
+            "<?xml version=\"1.0\"?>\n<!DOCTYPE feature SYSTEM \"gdb-target.dtd\">\n<feature name=\"{}\">\n{}</feature>\n",
+            name, body
+        );
+        self.features.push((file.to_owned(), xml));
+    }
+
+    /// The file called `name`, if there is one
+    pub fn file(&self, name: &str) -> Option<String> {
+        if name == "target.xml" {
+            let mut xml = "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n".to_string();
+            for (file, _) in &self.features {
+                xml.push_str(&format!("<xi:include href=\"{}\"/>\n", file));
+            }
+            xml.push_str("</target>\n");
+            return Some(xml);
+        }
+        self.features
+            .iter()
+            .find(|(file, _)| file == name)
+            .map(|(_, xml)| xml.clone())
+    }
+}
+
 pub struct RiscvCpu {
     /// A list of all available registers on this CPU
     registers: Vec<RiscvRegister>,
 
-    /// An XML representation of the register mapping
-    target_xml: String,
+    /// Whether there are vector registers to describe, which can only be
+    /// found out by asking a halted hart
+    vector: Mutex<VectorSupport>,
 
-    /// The memory offset of the debug register
-    debug_offset: u32,
+    /// Every hart we can debug, indexed by hart number
+    harts: Vec<Hart>,
+
+    /// Keep a breakpoint on the trap vector, so that traps halt the hart
+    catch_traps: Mutex<bool>,
+
+    /// The address GDB has put in each hardware breakpoint comparator, or
+    /// `None` until the debug unit has been probed for how many it has.
+    /// The trap breakpoint's comparator is never handed out.
+    hw_breakpoints: Mutex<Option<Vec<Option<u32>>>>,
+
+    /// Triggers that can match addresses.  `None` until the trigger module
+    /// has been probed.
+    triggers: Mutex<Option<TriggerSlots>>,
+
+    /// Read the code around pc and the top of the stack when a hart halts
+    prefetch: Mutex<bool>,
+
+    /// Answer reads from the register and memory caches.  When this is off
+    /// everything is read fresh from the hart, except registers that debug
+    /// instructions have clobbered, whose cached value is the real one.
+    caching: Mutex<bool>,
+
+    cache_stats: Mutex<CacheStats>,
+
+    /// Where it's safe to read ahead.  Empty if the board isn't known.
+    memory_map: Vec<MemoryRegion>,
+
+    /// Variables in memory shown as registers after the real ones
+    virtual_registers: Vec<VirtualRegister>,
 }
 
 impl RiscvCpu {
-    pub fn new() -> Result<RiscvCpu, RiscvCpuError> {
+    pub fn new(cfg: &Config) -> Result<RiscvCpu, RiscvCpuError> {
         let registers = Self::make_registers();
-        let target_xml = Self::make_target_xml(&registers);
+        let harts = Self::make_harts(&cfg.hart_debug_offsets, &cfg.smp_groups)?;
         Ok(RiscvCpu {
             registers,
-            target_xml,
-            debug_offset: 0xf00f0000,
+            vector: Mutex::new(VectorSupport::Unknown),
+            harts,
+            catch_traps: Mutex::new(false),
+            hw_breakpoints: Mutex::new(None),
+            triggers: Mutex::new(None),
+            prefetch: Mutex::new(cfg.tuning.prefetch),
+            caching: Mutex::new(cfg.tuning.caching),
+            cache_stats: Mutex::new(CacheStats::default()),
+            memory_map: cfg.memory_map.clone(),
+            virtual_registers: cfg.virtual_registers.clone(),
         })
     }
 
+    /// Assign each hart to a run-control group.  With no groups configured
+    /// every hart is in one group, so halting any of them halts them all.
+    /// Otherwise each listed group is used as-is and any unlisted hart runs
+    /// on its own.
+    fn make_harts(offsets: &[u32], groups: &[Vec<usize>]) -> Result<Vec<Hart>, RiscvCpuError> {
+        let mut harts: Vec<Hart> = offsets
+            .iter()
+            .map(|&debug_offset| Hart {
+                debug_offset,
+                group: 0,
+                state: Mutex::new(HartState::new()),
+            })
+            .collect();
+        if groups.is_empty() {
+            return Ok(harts);
+        }
+
+        let mut assigned = vec![false; harts.len()];
+        for (group_idx, group) in groups.iter().enumerate() {
+            for &hart in group {
+                if hart >= harts.len() || assigned[hart] {
+                    return Err(RiscvCpuError::InvalidHartGroup(hart));
+                }
+                harts[hart].group = group_idx;
+                assigned[hart] = true;
+            }
+        }
+        let mut next_group = groups.len();
+        for (hart, was_assigned) in harts.iter_mut().zip(assigned) {
+            if !was_assigned {
+                hart.group = next_group;
+                next_group += 1;
+            }
+        }
+        Ok(harts)
+    }
+
+    pub fn memory_map(&self) -> &[MemoryRegion] {
+        &self.memory_map
+    }
+
+    pub fn hart_count(&self) -> usize {
+        self.harts.len()
+    }
+
+    /// All harts that share a run-control group with `hart`, including itself
+    pub fn group_of(&self, hart: usize) -> Vec<usize> {
+        let group = self.harts[hart].group;
+        (0..self.harts.len())
+            .filter(|&h| self.harts[h].group == group)
+            .collect()
+    }
+
     fn make_registers() -> Vec<RiscvRegister> {
         let mut registers = vec![];
 
@@ -247,43 +734,183 @@ impl RiscvCpu {
         registers
     }
 
-    fn make_target_xml(registers: &Vec<RiscvRegister>) -> String {
-        let mut target_xml = "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n".to_string();
+    /// Describe the registers as target.xml and a file for each feature it
+    /// includes
+    fn make_target_description(
+        registers: &[RiscvRegister],
+        vector: VectorSupport,
+        virtual_registers: &[VirtualRegister],
+    ) -> TargetDescription {
+        let mut description = TargetDescription::default();
 
         // Add in general-purpose registers
-        for ft in &[RiscvRegisterType::General, RiscvRegisterType::CSR] {
-            target_xml.push_str(&format!("<feature name=\"{}\">\n", ft.feature_name()));
+        for (file, ft) in &[
+            ("riscv-cpu.xml", RiscvRegisterType::General),
+            ("riscv-csr.xml", RiscvRegisterType::CSR),
+        ] {
+            let mut feature = String::new();
             for reg in registers {
                 if !reg.present || reg.register_type != *ft {
                     continue;
                 }
-                target_xml.push_str(
+                feature.push_str(
                     &format!("<reg name=\"{}\" bitsize=\"32\" regnum=\"{}\" save-restore=\"no\" type=\"int\" group=\"{}\"/>\n",
-                        reg.name, reg.index, reg.register_type.group())
+                        reg.name, reg.gdb_regnum(), reg.register_type.group())
+                );
+            }
+            if let (RiscvRegisterType::CSR, VectorSupport::Present { .. }) = (ft, vector) {
+                for (csr, name) in VECTOR_CSRS {
+                    feature.push_str(
+                        &format!("<reg name=\"{}\" bitsize=\"32\" regnum=\"{}\" save-restore=\"no\" type=\"int\" group=\"vector\"/>\n",
+                            name, csr + CSR_REGNUM_BASE)
+                    );
+                }
+            }
+            description.add_feature(file, ft.feature_name(), feature);
+        }
+
+        // Vector registers are described as a union of every element width,
+        // which is how GDB expects to find them
+        if let VectorSupport::Present { vlenb } = vector {
+            let mut feature = String::new();
+            let widths = VECTOR_ELEMENT_TYPES
+                .iter()
+                .filter(|(_, _, _, width)| vlenb >= *width);
+            for (_, id, ty, width) in widths.clone() {
+                feature.push_str(&format!(
+                    "<vector id=\"{}\" type=\"{}\" count=\"{}\"/>\n",
+                    id,
+                    ty,
+                    vlenb / width
+                ));
+            }
+            feature.push_str("<union id=\"riscv_vector\">\n");
+            for (field, id, _, _) in widths {
+                feature.push_str(&format!("<field name=\"{}\" type=\"{}\"/>\n", field, id));
+            }
+            feature.push_str("</union>\n");
+            for n in 0..32 {
+                feature.push_str(
+                    &format!("<reg name=\"v{}\" bitsize=\"{}\" regnum=\"{}\" save-restore=\"no\" type=\"riscv_vector\" group=\"vector\"/>\n",
+                        n, vlenb * 8, VECTOR_REGNUM_BASE + n)
+                );
+            }
+            description.add_feature("riscv-vector.xml", "org.gnu.gdb.riscv.vector", feature);
+        }
+
+        if !virtual_registers.is_empty() {
+            let mut feature = String::new();
+            for (n, reg) in virtual_registers.iter().enumerate() {
+                feature.push_str(
+                    &format!("<reg name=\"{}\" bitsize=\"32\" regnum=\"{}\" save-restore=\"no\" type=\"uint32\" group=\"virtual\"/>\n",
+                        reg.name, vector.virtual_regnum_base() + n as u32)
                 );
             }
-            target_xml.push_str("</feature>\n");
+            description.add_feature("virtual.xml", "org.litex.virtual", feature);
         }
-        target_xml.push_str("</target>\n");
 
-        target_xml
+        description
     }
 
-    pub fn get_feature(&self, name: &str) -> Result<Vec<u8>, RiscvCpuError> {
-        if name == "target.xml" {
-            let xml = self.target_xml.to_string().into_bytes();
-            Ok(xml)
-        } else {
-            Err(RiscvCpuError::UnrecognizedFile(name.to_string()))
+    /// Read one of the target description files: target.xml, or any of
+    /// the feature files it includes
+    pub fn get_feature(&self, bridge: &Bridge, name: &str) -> Result<Vec<u8>, RiscvCpuError> {
+        let vector = self.probe_vector(bridge)?;
+        Self::make_target_description(&self.registers, vector, &self.virtual_registers)
+            .file(name)
+            .map(String::into_bytes)
+            .ok_or_else(|| RiscvCpuError::UnrecognizedFile(name.to_string()))
+    }
+
+    /// Find out whether the CPU has vector registers, and how wide they
+    /// are.  This needs a halted hart, so if the first hart is running it's
+    /// stopped just long enough to read `misa` and `vlenb`.  The answer is
+    /// remembered for the rest of the session.
+    fn probe_vector(&self, bridge: &Bridge) -> Result<VectorSupport, BridgeError> {
+        let mut vector = self.vector.lock().unwrap();
+        if *vector != VectorSupport::Unknown {
+            return Ok(*vector);
+        }
+        let support = self.with_hart_halted(bridge, 0, || {
+            let misa = self.read_csr(bridge, 0, CSR_MISA)?;
+            Ok(if misa & MISA_V != 0 {
+                VectorSupport::Present {
+                    vlenb: self.read_csr(bridge, 0, CSR_VLENB)?,
+                }
+            } else {
+                VectorSupport::Absent
+            })
+        })?;
+        log_adapter!("vector extension: {:?}", support);
+        *vector = support;
+        Ok(support)
+    }
+
+    /// Read `misa`, which says which extensions the CPU implements
+    pub fn read_misa(&self, bridge: &Bridge) -> Result<u32, BridgeError> {
+        self.with_hart_halted(bridge, 0, || self.read_csr(bridge, 0, CSR_MISA))
+    }
+
+    /// The vector register width in bytes, if there are vector registers
+    pub fn vector_length(&self, bridge: &Bridge) -> Result<Option<u32>, BridgeError> {
+        match self.probe_vector(bridge)? {
+            VectorSupport::Present { vlenb } => Ok(Some(vlenb)),
+            _ => Ok(None),
+        }
+    }
+
+    /// CSRs can only be read from a halted hart, so stop `hart` for long
+    /// enough to run `f` if it's running
+    fn with_hart_halted<T>(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        f: impl FnOnce() -> Result<T, BridgeError>,
+    ) -> Result<T, BridgeError> {
+        let was_running = !self.is_halted(bridge, hart)?;
+        if was_running {
+            self.write_status(bridge, hart, VexRiscvFlags::HALT_SET)?;
         }
+        let result = f();
+        if was_running {
+            self.resume_single(bridge, hart)?;
+        }
+        result
     }
 
-    pub fn get_threads(&self) -> Result<Vec<u8>, RiscvCpuError> {
-        Ok(THREADS_XML.to_string().into_bytes())
+    /// Describe each hart as a GDB thread, followed by any `extra` threads
+    /// that aren't running on a hart.  Hart thread IDs start at 1.
+    pub fn get_threads(&self, extra: &[(i64, String)]) -> Result<Vec<u8>, RiscvCpuError> {
+        let mut threads_xml = "<?xml version=\"1.0\"?>\n<threads>\n".to_string();
+        for hart in 0..self.harts.len() {
+            threads_xml.push_str(&format!(
+                "<thread id=\"{:x}\" core=\"{}\" name=\"hart {}\"/>\n",
+                hart + 1,
+                hart,
+                hart
+            ));
+        }
+        for (tid, name) in extra {
+            let name = name
+                .replace('&', "&amp;")
+                .replace('<', "&lt;")
+                .replace('>', "&gt;")
+                .replace('"', "&quot;");
+            threads_xml.push_str(&format!("<thread id=\"{:x}\" name=\"{}\"/>\n", tid, name));
+        }
+        threads_xml.push_str("</threads>\n");
+        Ok(threads_xml.into_bytes())
     }
 
-    pub fn read_memory(&self, bridge: &Bridge, addr: u32, sz: u32) -> Result<u32, BridgeError> {
-        self.write_register(bridge, 1, addr)?;
+    pub fn read_memory(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        addr: u32,
+        sz: u32,
+    ) -> Result<u32, BridgeError> {
+        self.save_register(bridge, hart, 1)?;
+        self.write_register(bridge, hart, 1, addr)?;
 
         let inst = match sz {
             // LW x1, 0(x1)
@@ -297,70 +924,1506 @@ impl RiscvCpu {
 
             x => panic!("Unrecognized memory size: {}", x),
         };
-        self.write_instruction(bridge, inst)?;
-        self.read_result(bridge)
+        self.run_instruction(bridge, hart, inst)
+    }
+
+    /// Read `len` bytes starting at `addr`, in bursts no longer than the
+    /// bridge likes.  Single bytes and aligned halfwords are read with a
+    /// load of that size, since some peripherals care.  Everything else is
+    /// read a word at a time and trimmed to fit.
+    pub fn read_memory_range(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        addr: u32,
+        len: u32,
+    ) -> Result<Vec<u8>, BridgeError> {
+        if let Some(data) = self.prefetched(hart, addr, len) {
+            self.cache_stats.lock().unwrap().memory_hits += 1;
+            return Ok(data);
+        }
+        self.cache_stats.lock().unwrap().memory_misses += 1;
+        self.read_memory_uncached(bridge, hart, addr, len)
+    }
+
+    fn read_memory_uncached(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        addr: u32,
+        len: u32,
+    ) -> Result<Vec<u8>, BridgeError> {
+        let _lock = lock::read_flash(addr, len)?;
+        match len {
+            1 => return Ok(vec![self.read_memory(bridge, hart, addr, 1)? as u8]),
+            2 if addr & 1 == 0 => {
+                let value = self.read_memory(bridge, hart, addr, 2)? as u16;
+                return Ok(value.to_le_bytes().to_vec());
+            }
+            _ => (),
+        }
+
+        // A read that runs past the top of the address space stops there
+        let start = addr & !3;
+        let end = ((addr as u64 + len as u64 + 3) & !3).min(1 << 32);
+        let skip = (addr - start) as usize;
+        let len = (len as usize).min((end - addr as u64) as usize);
+        if self.is_bus_memory(start, end) {
+            let words = bridge.read_block(start, ((end - start as u64) / 4) as usize)?;
+            let data: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+            return Ok(data[skip..skip + len].to_vec());
+        }
+        let burst = (bridge.max_burst() as u64).clamp(4, MAX_LOAD_OFFSET as u64 + 1) & !3;
+        let mut data = Vec::with_capacity((end - start as u64) as usize);
+        let mut base = start as u64;
+        while base < end {
+            let words = ((end - base).min(burst) / 4) as u32;
+            data.extend(self.read_burst(bridge, hart, base as u32, words)?);
+            base += burst;
+        }
+        Ok(data[skip..skip + len].to_vec())
+    }
+
+    /// Write `data` to `addr` with stores run on the hart, so it goes through
+    /// the same caches as the hart's own accesses, then run `fence.i` so the
+    /// instruction cache sees it too.  Each piece is written with the widest
+    /// aligned store that fits, which keeps a halfword patch from touching
+    /// the halfword next to it.
+    pub fn write_memory(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<(), BridgeError> {
+        self.save_register(bridge, hart, 1)?;
+        self.save_register(bridge, hart, 2)?;
+        let mut offset = 0;
+        while offset < data.len() {
+            let at = addr.wrapping_add(offset as u32);
+            let left = data.len() - offset;
+            let (size, funct3) = if at & 3 == 0 && left >= 4 {
+                (4, 0x2)
+            } else if at & 1 == 0 && left >= 2 {
+                (2, 0x1)
+            } else {
+                (1, 0x0)
+            };
+            let mut value = [0; 4];
+            value[..size].copy_from_slice(&data[offset..offset + size]);
+            self.write_register(bridge, hart, 1, at)?;
+            self.write_register(bridge, hart, 2, u32::from_le_bytes(value))?;
+            // SB/SH/SW x2, 0(x1)
+            self.run_instruction(bridge, hart, (2 << 20) | (1 << 15) | (funct3 << 12) | 0x23)?;
+            offset += size;
+        }
+        // FENCE.I
+        self.run_instruction(bridge, hart, 0x0000_100f)?;
+
+        // Memory is shared, so every hart's copy of it is now stale
+        let end = addr as u64 + data.len() as u64;
+        for hart in &self.harts {
+            hart.state.lock().unwrap().memory.retain(|(base, bytes)| {
+                *base as u64 >= end || *base as u64 + bytes.len() as u64 <= addr as u64
+            });
+        }
+        Ok(())
+    }
+
+    /// Read the code around pc and the top of the stack, so that the reads
+    /// GDB makes as soon as it hears about a stop don't have to go over the
+    /// bridge.  Reads are kept to RAM, ROM and flash when the memory map is
+    /// known, and anything that can't be read is just left out.
+    pub fn prefetch(&self, bridge: &Bridge, hart: usize) -> Result<(), BridgeError> {
+        if !*self.prefetch.lock().unwrap() || !self.caching() {
+            return Ok(());
+        }
+        let pc = self.read_cached_register(bridge, hart, PC_REGNUM)?;
+        let sp = self.read_cached_register(bridge, hart, 2)?;
+        let windows = [
+            (
+                pc,
+                pc.saturating_sub(PREFETCH_CODE_WINDOW / 2) & !3,
+                PREFETCH_CODE_WINDOW,
+            ),
+            (sp, sp & !3, PREFETCH_STACK_WINDOW),
+        ];
+        for &(anchor, addr, len) in windows.iter() {
+            let (addr, len) = match self.prefetch_window(anchor, addr, len) {
+                Some(window) => window,
+                None => continue,
+            };
+            match self.read_memory_uncached(bridge, hart, addr, len) {
+                Ok(data) => {
+                    self.cache_stats.lock().unwrap().prefetched_bytes += data.len() as u64;
+                    self.harts[hart]
+                        .state
+                        .lock()
+                        .unwrap()
+                        .memory
+                        .push((addr, data));
+                }
+                Err(e) => log_bridge!(@Verbose, "prefetch of {:08x} failed: {:?}", addr, e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Trim a window to the region of memory that `anchor` is in.  A
+    /// window around an address that's in no region, or in I/O space, isn't
+    /// read at all.
+    fn prefetch_window(&self, anchor: u32, addr: u32, len: u32) -> Option<(u32, u32)> {
+        let mut start = addr as u64;
+        let mut end = (addr as u64 + len as u64).min(1 << 32);
+        if !self.memory_map.is_empty() {
+            let region = self.memory_map.iter().find(|r| {
+                r.kind != MemoryKind::Io
+                    && anchor >= r.base
+                    && (anchor as u64) < r.base as u64 + r.size as u64
+            })?;
+            start = start.max(region.base as u64);
+            end = end.min(region.base as u64 + region.size as u64);
+        }
+        Some((start as u32, (end - start) as u32))
+    }
+
+    /// Whether `start..end` lies in one region of the memory map that can be
+    /// read straight off the bus rather than with loads run on the hart,
+    /// which is far quicker for anything bigger than a word.  That's RAM,
+    /// ROM and flash: the data cache is write-through, so what's on the
+    /// bus is what the hart would see.  Peripherals always get loads.
+    fn is_bus_memory(&self, start: u32, end: u64) -> bool {
+        self.memory_map.iter().any(|r| {
+            r.kind != MemoryKind::Io && start >= r.base && end <= r.base as u64 + r.size as u64
+        })
+    }
+
+    /// Memory that was read ahead when the hart halted, if all of
+    /// `addr..addr+len` was
+    fn prefetched(&self, hart: usize, addr: u32, len: u32) -> Option<Vec<u8>> {
+        if !self.caching() {
+            return None;
+        }
+        let state = self.harts[hart].state.lock().unwrap();
+        state.memory.iter().find_map(|(base, data)| {
+            let offset = addr.checked_sub(*base)? as usize;
+            data.get(offset..offset.checked_add(len as usize)?)
+                .map(|d| d.to_vec())
+        })
+    }
+
+    /// Read `words` consecutive words starting at `base`.  The address is
+    /// loaded into x2 once and each word is reached with an offset from it,
+    /// rather than loading a fresh address for every word.
+    fn read_burst(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        base: u32,
+        words: u32,
+    ) -> Result<Vec<u8>, BridgeError> {
+        self.save_register(bridge, hart, 1)?;
+        self.save_register(bridge, hart, 2)?;
+        let instruction_addr = self.harts[hart].debug_offset + 4;
+        let mut batch = bridge.batch();
+        for inst in Self::load_immediate(2, base) {
+            batch = batch.write(instruction_addr, inst);
+        }
+        for word in 0..words {
+            // LW x1, offset(x2)
+            batch = batch
+                .write(
+                    instruction_addr,
+                    ((word * 4) << 20) | (2 << 15) | (0x2 << 12) | (1 << 7) | 0x3,
+                )
+                .read(instruction_addr);
+        }
+        let mut data = Vec::with_capacity(words as usize * 4);
+        for value in batch.commit()? {
+            data.extend_from_slice(&value.to_le_bytes());
+        }
+        Ok(data)
+    }
+
+    /// Read a CSR by having the CPU execute `csrr x1, csr`.  The CPU must be halted.
+    pub fn read_csr(&self, bridge: &Bridge, hart: usize, csr: u32) -> Result<u32, BridgeError> {
+        self.save_register(bridge, hart, 1)?;
+        // CSRRS x1, csr, x0
+        self.run_instruction(bridge, hart, (csr << 20) | (0x2 << 12) | (1 << 7) | 0x73)
+    }
+
+    /// Write a CSR by having the CPU execute `csrw csr, x1`.  The CPU must be halted.
+    pub fn write_csr(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        csr: u32,
+        value: u32,
+    ) -> Result<(), BridgeError> {
+        self.save_register(bridge, hart, 1)?;
+        self.write_register(bridge, hart, 1, value)?;
+        // CSRRW x0, csr, x1
+        self.write_instruction(bridge, hart, (csr << 20) | (1 << 15) | (0x1 << 12) | 0x73)
+    }
+
+    /// Save everything needed to put `hart` back the way it is now, in one
+    /// batch.  Registers that debug instructions have clobbered are taken
+    /// from the cache, which holds their real value.  The hart must be
+    /// halted.
+    pub fn save_context(&self, bridge: &Bridge, hart: usize) -> Result<CpuContext, BridgeError> {
+        let csrs: Vec<u32> = CONTEXT_CSRS
+            .iter()
+            .copied()
+            .filter(|&csr| self.has_csr(csr))
+            .collect();
+        let instruction_addr = self.harts[hart].debug_offset + 4;
+        let mut batch = bridge.batch();
+        for reg in 1..PC_REGNUM {
+            // ADDI x0, reg, 0
+            batch = batch
+                .write(instruction_addr, 0x13 | (reg << 15))
+                .read(instruction_addr);
+        }
+        // AUIPC x0, 0
+        batch = batch.write(instruction_addr, 0x17).read(instruction_addr);
+        for &csr in &csrs {
+            // CSRRS x1, csr, x0
+            batch = batch
+                .write(
+                    instruction_addr,
+                    (csr << 20) | (0x2 << 12) | (1 << 7) | 0x73,
+                )
+                .read(instruction_addr);
+        }
+        let values = batch.commit()?;
+
+        let mut state = self.harts[hart].state.lock().unwrap();
+        let mut registers = vec![0];
+        for (reg, &value) in values[..PC_REGNUM as usize].iter().enumerate() {
+            let reg = reg + 1;
+            if !state.dirty[reg] {
+                state.registers[reg] = Some(value);
+            }
+            registers.push(state.registers[reg].unwrap_or(value));
+        }
+        // Reading the CSRs went through x1
+        state.dirty[1] = true;
+        let csrs = csrs
+            .into_iter()
+            .zip(values[PC_REGNUM as usize..].iter().copied())
+            .collect();
+        Ok(CpuContext { registers, csrs })
+    }
+
+    /// Put `hart` back the way it was when `context` was saved.  The CSRs
+    /// are written in one batch, and the registers reach the hart along
+    /// with everything else when it resumes.  The hart must be halted.
+    pub fn load_context(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        context: &CpuContext,
+    ) -> Result<(), BridgeError> {
+        let instruction_addr = self.harts[hart].debug_offset + 4;
+        let mut batch = bridge.batch();
+        for &(csr, value) in &context.csrs {
+            for inst in Self::load_immediate(1, value) {
+                batch = batch.write(instruction_addr, inst);
+            }
+            // CSRRW x0, csr, x1
+            batch = batch.write(
+                instruction_addr,
+                (csr << 20) | (1 << 15) | (0x1 << 12) | 0x73,
+            );
+        }
+        batch.commit()?;
+
+        let mut state = self.harts[hart].state.lock().unwrap();
+        for (reg, &value) in context
+            .registers
+            .iter()
+            .enumerate()
+            .take(PC_REGNUM as usize + 1)
+            .skip(1)
+        {
+            state.registers[reg] = Some(value);
+            state.dirty[reg] = true;
+        }
+        Ok(())
+    }
+
+    fn has_csr(&self, csr: u32) -> bool {
+        self.registers
+            .iter()
+            .any(|r| r.present && r.register_type == RiscvRegisterType::CSR && r.index == csr)
+    }
+
+    /// Read a register using GDB's numbering.  x0-x31 and pc are cached
+    /// until the hart resumes, so switching between harts doesn't force
+    /// them to be read again.  The hart must be halted.
+    pub fn read_register(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        regnum: u32,
+    ) -> Result<u32, RiscvCpuError> {
+        if regnum <= PC_REGNUM {
+            return Ok(self.read_cached_register(bridge, hart, regnum)?);
+        }
+        if let Some(reg) = self.virtual_register(bridge, regnum)? {
+            return Ok(reg.read(bridge)?);
+        }
+        let csr = self.csr_for(bridge, regnum)?;
+        Ok(self.read_csr(bridge, hart, csr)?)
+    }
+
+    /// The pc of `hart` if it's been read since the hart stopped, without
+    /// going to the bridge for it
+    pub fn cached_pc(&self, hart: usize) -> Option<u32> {
+        self.harts[hart].state.lock().unwrap().registers[PC_REGNUM as usize]
+    }
+
+    /// Write a register using GDB's numbering.  x1-x31 and pc only change
+    /// in the cache, and reach the hart when it resumes along with any
+    /// registers debug instructions clobbered.  CSRs are written straight
+    /// away.  Writes to x0 are ignored.  The hart must be halted.
+    pub fn set_register(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        regnum: u32,
+        value: u32,
+    ) -> Result<(), RiscvCpuError> {
+        if regnum == 0 {
+            return Ok(());
+        }
+        if regnum <= PC_REGNUM {
+            if regnum == PC_REGNUM {
+                // Setting pc takes x1, so make sure it's put back too
+                self.save_register(bridge, hart, 1)?;
+            }
+            let mut state = self.harts[hart].state.lock().unwrap();
+            state.registers[regnum as usize] = Some(value);
+            state.dirty[regnum as usize] = true;
+            return Ok(());
+        }
+        if let Some(reg) = self.virtual_register(bridge, regnum)? {
+            reg.write(bridge, value)?;
+            // The variable may be in memory that's been cached
+            self.flush_cache();
+            return Ok(());
+        }
+        let csr = self.csr_for(bridge, regnum)?;
+        Ok(self.write_csr(bridge, hart, csr, value)?)
+    }
+
+    /// The virtual register GDB register `regnum` refers to.  Where they
+    /// start depends on whether there are vector registers before them.
+    fn virtual_register(
+        &self,
+        bridge: &Bridge,
+        regnum: u32,
+    ) -> Result<Option<&VirtualRegister>, BridgeError> {
+        if self.virtual_registers.is_empty() || regnum < VECTOR_REGNUM_BASE {
+            return Ok(None);
+        }
+        let base = self.probe_vector(bridge)?.virtual_regnum_base();
+        Ok(regnum
+            .checked_sub(base)
+            .and_then(|index| self.virtual_registers.get(index as usize)))
+    }
+
+    /// The CSR that GDB register `regnum` refers to, if the CPU has it
+    fn csr_for(&self, bridge: &Bridge, regnum: u32) -> Result<u32, RiscvCpuError> {
+        let csr = regnum.wrapping_sub(CSR_REGNUM_BASE);
+        let is_vector_csr = VECTOR_CSRS.iter().any(|&(index, _)| index == csr)
+            && matches!(self.probe_vector(bridge)?, VectorSupport::Present { .. });
+        if !is_vector_csr && !self.has_csr(csr) {
+            return Err(RiscvCpuError::InvalidRegister(regnum));
+        }
+        Ok(csr)
+    }
+
+    /// Read a register of any width as a list of little-endian words.
+    /// Everything is one word except the vector registers.
+    pub fn read_register_words(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        regnum: u32,
+    ) -> Result<Vec<u32>, RiscvCpuError> {
+        let is_vector = (VECTOR_REGNUM_BASE..VECTOR_REGNUM_BASE + 32).contains(&regnum)
+            && matches!(self.probe_vector(bridge)?, VectorSupport::Present { .. });
+        if is_vector {
+            self.read_vector_register(bridge, hart, regnum - VECTOR_REGNUM_BASE)
+        } else {
+            Ok(vec![self.read_register(bridge, hart, regnum)?])
+        }
+    }
+
+    /// Read `vN` one 32-bit element at a time.  Each `vmv.x.s` copies out
+    /// element 0 and each `vslide1down` rotates the register down by one
+    /// element, so after `vlenb / 4` rounds the register is back the way
+    /// it started.  `vl` and `vtype` have to be changed to do this and are
+    /// put back afterwards.
+    fn read_vector_register(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        n: u32,
+    ) -> Result<Vec<u32>, RiscvCpuError> {
+        let vlenb = match self.probe_vector(bridge)? {
+            VectorSupport::Present { vlenb } => vlenb,
+            _ => return Err(RiscvCpuError::InvalidRegister(VECTOR_REGNUM_BASE + n)),
+        };
+        let elements = vlenb / 4;
+        let vl = self.read_csr(bridge, hart, CSR_VL)?;
+        let vtype = self.read_csr(bridge, hart, CSR_VTYPE)?;
+        self.save_register(bridge, hart, 2)?;
+
+        // VSETVLI x0, x1, e32, m1
+        self.write_register(bridge, hart, 1, elements)?;
+        self.write_instruction(bridge, hart, (0x10 << 20) | (1 << 15) | (0x7 << 12) | 0x57)?;
+
+        let mut values = vec![];
+        for _ in 0..elements {
+            // VMV.X.S x1, vN
+            values.push(self.run_instruction(
+                bridge,
+                hart,
+                (0x10 << 26) | (1 << 25) | (n << 20) | (0x2 << 12) | (1 << 7) | 0x57,
+            )?);
+
+            // VSLIDE1DOWN.VX vN, vN, x1
+            self.write_instruction(
+                bridge,
+                hart,
+                (0x0f << 26) | (1 << 25) | (n << 20) | (1 << 15) | (0x6 << 12) | (n << 7) | 0x57,
+            )?;
+        }
+
+        // VSETVL x0, x1, x2
+        self.write_register(bridge, hart, 1, vl)?;
+        self.write_register(bridge, hart, 2, vtype)?;
+        self.write_instruction(
+            bridge,
+            hart,
+            (0x40 << 25) | (2 << 20) | (1 << 15) | (0x7 << 12) | 0x57,
+        )?;
+        Ok(values)
+    }
+
+    fn read_cached_register(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        regnum: u32,
+    ) -> Result<u32, BridgeError> {
+        {
+            let state = self.harts[hart].state.lock().unwrap();
+            if let Some(value) = state.registers[regnum as usize] {
+                if self.caching() || state.dirty[regnum as usize] {
+                    self.cache_stats.lock().unwrap().register_hits += 1;
+                    return Ok(value);
+                }
+            }
+        }
+        self.cache_stats.lock().unwrap().register_misses += 1;
+        let value = match regnum {
+            0 => 0,
+            PC_REGNUM => {
+                // AUIPC x0, 0
+                self.run_instruction(bridge, hart, 0x17)?
+            }
+            reg => {
+                // ADDI x0, reg, 0
+                self.run_instruction(bridge, hart, 0x13 | (reg << 15))?
+            }
+        };
+        self.harts[hart].state.lock().unwrap().registers[regnum as usize] = Some(value);
+        Ok(value)
+    }
+
+    /// Make sure `reg` is cached before running a debug instruction that
+    /// overwrites it, and remember to restore it before resuming.
+    fn save_register(&self, bridge: &Bridge, hart: usize, reg: u32) -> Result<(), BridgeError> {
+        self.read_cached_register(bridge, hart, reg)?;
+        self.harts[hart].state.lock().unwrap().dirty[reg as usize] = true;
+        Ok(())
+    }
+
+    pub fn caching(&self) -> bool {
+        *self.caching.lock().unwrap()
+    }
+
+    pub fn set_caching(&self, enabled: bool) {
+        *self.caching.lock().unwrap() = enabled;
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        *self.cache_stats.lock().unwrap()
+    }
+
+    /// Forget cached registers and memory, so they're read again.  Registers
+    /// that debug instructions have clobbered are kept, since the hart no
+    /// longer holds their real value.
+    pub fn flush_cache(&self) {
+        for hart in &self.harts {
+            let mut state = hart.state.lock().unwrap();
+            let HartState {
+                registers, dirty, ..
+            } = &mut *state;
+            for (value, dirty) in registers.iter_mut().zip(dirty.iter()) {
+                if !dirty {
+                    *value = None;
+                }
+            }
+            state.memory.clear();
+        }
+    }
+
+    /// Put back any registers we clobbered and forget everything cached
+    /// about the hart, since it's about to run again.
+    fn restore_context(&self, bridge: &Bridge, hart: usize) -> Result<(), BridgeError> {
+        let state = std::mem::replace(
+            &mut *self.harts[hart].state.lock().unwrap(),
+            HartState::new(),
+        );
+        // pc goes first, since setting it uses x1
+        let order = std::iter::once(PC_REGNUM as usize).chain(0..PC_REGNUM as usize);
+        for reg in order {
+            if let (true, Some(value)) = (state.dirty[reg], state.registers[reg]) {
+                self.write_register(bridge, hart, reg as u32, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn halt_reason(&self, hart: usize) -> Option<HaltReason> {
+        self.harts[hart].state.lock().unwrap().halt_reason
+    }
+
+    fn set_halt_reason(&self, hart: usize, reason: HaltReason) {
+        self.harts[hart].state.lock().unwrap().halt_reason = Some(reason);
+    }
+
+    /// Whether `hart`'s debug unit looks like a VexRiscv one, going by its
+    /// status word having nothing set outside the bits the debug plugin
+    /// reports.  Returns the status word if it doesn't.  Addresses with
+    /// nothing behind them tend to read as all ones, or as whatever was
+    /// last written there, and neither passes.
+    pub fn check_debug_unit(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+    ) -> Result<Option<u32>, BridgeError> {
+        let readable = VexRiscvFlags::RESET
+            | VexRiscvFlags::HALT
+            | VexRiscvFlags::PIP_BUSY
+            | VexRiscvFlags::HALTED_BY_BREAK
+            | VexRiscvFlags::STEP;
+        let status = bridge.peek(self.harts[hart].debug_offset)?;
+        if status & !readable.bits != 0 {
+            Ok(Some(status))
+        } else {
+            Ok(None)
+        }
+    }
+
+    pub fn debug_offset(&self, hart: usize) -> u32 {
+        self.harts[hart].debug_offset
+    }
+
+    pub fn is_halted(&self, bridge: &Bridge, hart: usize) -> Result<bool, BridgeError> {
+        Ok(self
+            .read_status(bridge, hart)?
+            .contains(VexRiscvFlags::HALT))
     }
 
+    /// Halt every hart because the adapter saw the word at `addr` change,
+    /// blaming `hart` for it since there's no telling which one wrote it
+    pub fn halt_on_watch(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        addr: u32,
+    ) -> Result<(), BridgeError> {
+        self.halt(bridge)?;
+        self.set_halt_reason(hart, HaltReason::Watchpoint(WatchKind::Write, addr));
+        Ok(())
+    }
+
+    /// Halt every hart
     pub fn halt(&self, bridge: &Bridge) -> Result<(), BridgeError> {
-        self.write_status(bridge, VexRiscvFlags::HALT_SET)
+        for hart in 0..self.harts.len() {
+            self.write_status(bridge, hart, VexRiscvFlags::HALT_SET)?;
+            if self.halt_reason(hart).is_none() {
+                self.set_halt_reason(hart, HaltReason::Interrupted);
+            }
+        }
+        Ok(())
     }
 
+    /// Resume every hart
     pub fn resume(&self, bridge: &Bridge) -> Result<(), BridgeError> {
+        for hart in 0..self.harts.len() {
+            self.resume_single(bridge, hart)?;
+        }
+        Ok(())
+    }
+
+    /// Halt `hart` along with the rest of its run-control group
+    pub fn halt_hart(&self, bridge: &Bridge, hart: usize) -> Result<(), BridgeError> {
+        for h in self.group_of(hart) {
+            self.write_status(bridge, h, VexRiscvFlags::HALT_SET)?;
+            if self.halt_reason(h).is_none() {
+                self.set_halt_reason(h, HaltReason::Interrupted);
+            }
+        }
+        Ok(())
+    }
+
+    /// Resume `hart` along with the rest of its run-control group
+    pub fn resume_hart(&self, bridge: &Bridge, hart: usize) -> Result<(), BridgeError> {
+        for h in self.group_of(hart) {
+            self.resume_single(bridge, h)?;
+        }
+        Ok(())
+    }
+
+    /// Step just `hart`.  The rest of its group stays where it is.
+    pub fn step_hart(&self, bridge: &Bridge, hart: usize) -> Result<(), BridgeError> {
+        self.arm_trap_breakpoint(bridge, hart)?;
+        self.restore_context(bridge, hart)?;
+        self.write_status(
+            bridge,
+            hart,
+            VexRiscvFlags::HALT_CLEAR | VexRiscvFlags::STEP,
+        )?;
+        self.set_halt_reason(hart, HaltReason::Step);
+        Ok(())
+    }
+
+    /// Look for a hart that stopped on its own (i.e. hit a breakpoint), and
+    /// if there is one, halt the rest of its group to match.
+    pub fn poll_halted(&self, bridge: &Bridge) -> Result<Option<usize>, BridgeError> {
+        self.find_halted(bridge, false)
+    }
+
+    /// The same as `poll_halted`, except that harts already known to be
+    /// halted aren't found again.  They keep their break flag until they
+    /// resume, and in non-stop mode they stay halted while the rest run.
+    pub fn poll_newly_halted(&self, bridge: &Bridge) -> Result<Option<usize>, BridgeError> {
+        self.find_halted(bridge, true)
+    }
+
+    fn find_halted(&self, bridge: &Bridge, skip_known: bool) -> Result<Option<usize>, BridgeError> {
+        for hart in 0..self.harts.len() {
+            if skip_known && self.halt_reason(hart).is_some() {
+                continue;
+            }
+            if self
+                .read_status(bridge, hart)?
+                .contains(VexRiscvFlags::HALTED_BY_BREAK)
+            {
+                let reason = self.classify_break(bridge, hart)?;
+                self.set_halt_reason(hart, reason);
+                self.halt_hart(bridge, hart)?;
+                return Ok(Some(hart));
+            }
+        }
+        Ok(None)
+    }
+
+    /// A watchpoint trigger that fired says so with its hit bit.  Otherwise
+    /// a break right at the start of the trap handler means the hart took
+    /// a trap, and `mcause` says which.
+    fn classify_break(&self, bridge: &Bridge, hart: usize) -> Result<HaltReason, BridgeError> {
+        if let Some(watch) = self.fired_watchpoint(bridge, hart)? {
+            return Ok(watch);
+        }
+        let pc = self.read_cached_register(bridge, hart, PC_REGNUM)?;
+        let mtvec = self.read_csr(bridge, hart, CSR_MTVEC)?;
+        if pc == mtvec & !3 {
+            Ok(HaltReason::Trap(self.read_csr(bridge, hart, CSR_MCAUSE)?))
+        } else {
+            Ok(HaltReason::Breakpoint)
+        }
+    }
+
+    /// Start or stop halting harts whenever they take a trap.  The VexRiscv
+    /// debug unit can't do that by itself, so this is done with a hardware
+    /// breakpoint on the trap vector.  Since firmware can move the vector,
+    /// the breakpoint is put back each time a hart is resumed.
+    pub fn set_trap_catching(&self, bridge: &Bridge, enabled: bool) -> Result<(), BridgeError> {
+        *self.catch_traps.lock().unwrap() = enabled;
+        if !enabled {
+            for hart in 0..self.harts.len() {
+                self.write_hw_breakpoint(bridge, hart, TRAP_BREAKPOINT, None)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Point the trap breakpoint at the current trap vector.  If the hart
+    /// is sitting on the vector now, step it off first, or the breakpoint
+    /// would fire again straight away.
+    fn arm_trap_breakpoint(&self, bridge: &Bridge, hart: usize) -> Result<(), BridgeError> {
+        if !*self.catch_traps.lock().unwrap() {
+            return Ok(());
+        }
+        if let Some(HaltReason::Trap(_)) = self.halt_reason(hart) {
+            self.write_hw_breakpoint(bridge, hart, TRAP_BREAKPOINT, None)?;
+            self.restore_context(bridge, hart)?;
+            self.write_status(
+                bridge,
+                hart,
+                VexRiscvFlags::HALT_CLEAR | VexRiscvFlags::STEP,
+            )?;
+            for _ in 0..STEP_POLL_LIMIT {
+                if self.is_halted(bridge, hart)? {
+                    break;
+                }
+            }
+        }
+        let mtvec = self.read_csr(bridge, hart, CSR_MTVEC)?;
+        self.write_hw_breakpoint(bridge, hart, TRAP_BREAKPOINT, Some(mtvec & !3))
+    }
+
+    fn write_hw_breakpoint(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        index: u32,
+        pc: Option<u32>,
+    ) -> Result<(), BridgeError> {
+        let addr = self.harts[hart].debug_offset + HW_BREAKPOINT_BASE + index * 4;
+        bridge.poke(addr, pc.map(|pc| pc | 1).unwrap_or(0))
+    }
+
+    /// How many hardware breakpoint comparators the debug unit has, which
+    /// depends on how the CPU was built.  There are none until they've been
+    /// probed for.
+    pub fn hw_breakpoint_count(&self) -> u32 {
+        self.hw_breakpoints
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map_or(0, |s| s.len() as u32)
+    }
+
+    pub fn hw_breakpoints_probed(&self) -> bool {
+        self.hw_breakpoints.lock().unwrap().is_some()
+    }
+
+    /// Count the comparators, using the word at `nop_at` to step a `nop`
+    /// in.  It has to be RAM the firmware isn't using, such as a word of
+    /// scratch, and is left holding the `nop`.
+    pub fn probe_hw_breakpoints(&self, bridge: &Bridge, nop_at: u32) -> Result<u32, BridgeError> {
+        let mut slots = self.hw_breakpoints.lock().unwrap();
+        if slots.is_none() {
+            let count =
+                self.with_hart_halted(bridge, 0, || self.probe_comparators(bridge, nop_at))?;
+            log_adapter!("{} hardware breakpoints", count);
+            *slots = Some(vec![None; count as usize]);
+        }
+        Ok(slots.as_ref().map_or(0, |s| s.len() as u32))
+    }
+    /// The addresses of the hardware breakpoints GDB has placed
+    pub fn hw_breakpoints_in_use(&self) -> Vec<u32> {
+        match *self.hw_breakpoints.lock().unwrap() {
+            Some(ref slots) => slots.iter().filter_map(|s| *s).collect(),
+            None => vec![],
+        }
+    }
+
+    /// Put a hardware breakpoint at `addr` on every hart, in one of the
+    /// debug unit's comparators or, once they run out, in a trigger.
+    /// Returns `false` if there's nothing free to put it in.
+    pub fn add_hw_breakpoint(&self, bridge: &Bridge, addr: u32) -> Result<bool, BridgeError> {
+        let index = {
+            let mut slots = self.hw_breakpoints.lock().unwrap();
+            let mut unprobed = vec![];
+            let slots = slots.as_mut().unwrap_or(&mut unprobed);
+            if slots.contains(&Some(addr)) {
+                return Ok(true);
+            }
+            let free =
+                (0..slots.len()).find(|&i| i as u32 != TRAP_BREAKPOINT && slots[i].is_none());
+            if let Some(i) = free {
+                slots[i] = Some(addr);
+            }
+            free
+        };
+        let index = match index {
+            Some(index) => index as u32,
+            None => {
+                let trigger = Trigger {
+                    kind: WatchKind::Execute,
+                    addr,
+                    len: 1,
+                };
+                return self.add_trigger(bridge, trigger);
+            }
+        };
+        for hart in 0..self.harts.len() {
+            self.write_hw_breakpoint(bridge, hart, index, Some(addr))?;
+        }
+        Ok(true)
+    }
+
+    pub fn remove_hw_breakpoint(&self, bridge: &Bridge, addr: u32) -> Result<(), BridgeError> {
+        let index = {
+            let mut slots = self.hw_breakpoints.lock().unwrap();
+            let index = slots
+                .as_ref()
+                .and_then(|slots| slots.iter().position(|s| *s == Some(addr)));
+            if let (Some(slots), Some(i)) = (slots.as_mut(), index) {
+                slots[i] = None;
+            }
+            index
+        };
+        match index {
+            Some(index) => {
+                for hart in 0..self.harts.len() {
+                    self.write_hw_breakpoint(bridge, hart, index as u32, None)?;
+                }
+                Ok(())
+            }
+            None => {
+                let trigger = Trigger {
+                    kind: WatchKind::Execute,
+                    addr,
+                    len: 1,
+                };
+                self.remove_trigger(bridge, trigger)
+            }
+        }
+    }
+
+    /// How many triggers can match addresses.  The first call probes the
+    /// trigger module for them.
+    pub fn trigger_count(&self, bridge: &Bridge) -> Result<u32, BridgeError> {
+        let mut triggers = self.triggers.lock().unwrap();
+        if triggers.is_none() {
+            let found = self.with_hart_halted(bridge, 0, || self.probe_triggers(bridge))?;
+            log_adapter!("{} address triggers", found.len());
+            *triggers = Some(found.into_iter().map(|index| (index, None)).collect());
+        }
+        Ok(triggers.as_ref().map_or(0, |t| t.len() as u32))
+    }
+
+    /// The triggers GDB has set
+    pub fn triggers_in_use(&self) -> Vec<Trigger> {
+        match *self.triggers.lock().unwrap() {
+            Some(ref triggers) => triggers.iter().filter_map(|(_, t)| *t).collect(),
+            None => vec![],
+        }
+    }
+
+    /// Set a trigger on every hart.  Returns `false` if they're all in use
+    /// or `trigger` isn't something a trigger can match.
+    pub fn add_trigger(&self, bridge: &Bridge, trigger: Trigger) -> Result<bool, BridgeError> {
+        let (tdata1, tdata2) = match trigger.mcontrol() {
+            Some(control) => control,
+            None => return Ok(false),
+        };
+        self.trigger_count(bridge)?;
+        let index = {
+            let mut triggers = self.triggers.lock().unwrap();
+            let triggers = triggers.as_mut().unwrap();
+            if triggers.iter().any(|(_, t)| *t == Some(trigger)) {
+                return Ok(true);
+            }
+            match triggers.iter_mut().find(|(_, t)| t.is_none()) {
+                Some((index, slot)) => {
+                    *slot = Some(trigger);
+                    *index
+                }
+                None => return Ok(false),
+            }
+        };
+        for hart in 0..self.harts.len() {
+            self.with_hart_halted(bridge, hart, || {
+                self.write_csr(bridge, hart, CSR_TSELECT, index)?;
+                // Turn it off while it's half set up
+                self.write_csr(bridge, hart, CSR_TDATA1, 0)?;
+                self.write_csr(bridge, hart, CSR_TDATA2, tdata2)?;
+                self.write_csr(bridge, hart, CSR_TDATA1, tdata1)
+            })?;
+        }
+        Ok(true)
+    }
+
+    pub fn remove_trigger(&self, bridge: &Bridge, trigger: Trigger) -> Result<(), BridgeError> {
+        let index = {
+            let mut triggers = self.triggers.lock().unwrap();
+            let slot = triggers
+                .as_mut()
+                .and_then(|t| t.iter_mut().find(|(_, t)| *t == Some(trigger)));
+            match slot {
+                Some((index, slot)) => {
+                    *slot = None;
+                    *index
+                }
+                None => return Ok(()),
+            }
+        };
+        for hart in 0..self.harts.len() {
+            self.with_hart_halted(bridge, hart, || {
+                self.write_csr(bridge, hart, CSR_TSELECT, index)?;
+                self.write_csr(bridge, hart, CSR_TDATA1, 0)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Look for a watchpoint trigger with its hit bit set, clearing it.
+    /// Cores that don't set the bit have their watchpoints reported as
+    /// plain breakpoints.
+    fn fired_watchpoint(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+    ) -> Result<Option<HaltReason>, BridgeError> {
+        let watching: Vec<(u32, Trigger)> = match *self.triggers.lock().unwrap() {
+            Some(ref triggers) => triggers
+                .iter()
+                .filter_map(|&(index, t)| t.map(|t| (index, t)))
+                .filter(|(_, t)| t.kind != WatchKind::Execute)
+                .collect(),
+            None => return Ok(None),
+        };
+        for (index, trigger) in watching {
+            self.write_csr(bridge, hart, CSR_TSELECT, index)?;
+            let tdata1 = self.read_csr(bridge, hart, CSR_TDATA1)?;
+            if tdata1 & MCONTROL_HIT != 0 {
+                self.write_csr(bridge, hart, CSR_TDATA1, tdata1 & !MCONTROL_HIT)?;
+                return Ok(Some(HaltReason::Watchpoint(trigger.kind, trigger.addr)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Walk `tselect` up until it stops sticking or lands on a trigger of
+    /// type 0, which means there are no more.  Only address match triggers
+    /// are any use for breakpoints and watchpoints.  A core built without
+    /// a trigger module traps on `tselect`, which takes the hart to its
+    /// trap vector, so everything a trap changes is put back afterwards.
+    fn probe_triggers(&self, bridge: &Bridge) -> Result<Vec<u32>, BridgeError> {
+        let hart = 0;
+        let context = self.save_context(bridge, hart)?;
+        let result = self.walk_triggers(bridge, hart, context.pc());
+        let restored = self.load_context(bridge, hart, &context);
+        let found = result?;
+        restored?;
+        Ok(found)
+    }
+
+    fn walk_triggers(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        pc: u32,
+    ) -> Result<Vec<u32>, BridgeError> {
+        let mut found = vec![];
+        for index in 0..MAX_TRIGGERS {
+            self.write_csr(bridge, hart, CSR_TSELECT, index)?;
+            // AUIPC x0, 0 gives pc, which only moves if that trapped
+            if index == 0 && self.run_instruction(bridge, hart, 0x17)? != pc {
+                log_adapter!(@Verbose, "tselect traps, so there's no trigger module");
+                break;
+            }
+            if self.read_csr(bridge, hart, CSR_TSELECT)? != index {
+                break;
+            }
+            match self.read_csr(bridge, hart, CSR_TDATA1)? >> 28 {
+                0 => break,
+                TRIGGER_TYPE_MCONTROL => found.push(index),
+                _ => (),
+            }
+        }
+        Ok(found)
+    }
+
+    /// The comparators can be written but not read back, so each one is
+    /// tried in turn: it's pointed at the `nop`, pc is moved there, and the
+    /// hart is stepped.  One that's there stops the hart before the `nop`
+    /// runs, and the first one that isn't lets it run, which changes
+    /// nothing but pc.  None of the firmware's own code is run, and every
+    /// register is put back afterwards.
+    fn probe_comparators(&self, bridge: &Bridge, nop_at: u32) -> Result<u32, BridgeError> {
+        let hart = 0;
+        let context = self.save_context(bridge, hart)?;
+        let result = self.step_comparators(bridge, hart, nop_at);
+        let restored = self.load_context(bridge, hart, &context);
+        let count = result?;
+        restored?;
+        Ok(count)
+    }
+
+    fn step_comparators(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        nop_at: u32,
+    ) -> Result<u32, BridgeError> {
+        // ADDI x0, x0, 0
+        self.write_memory(bridge, hart, nop_at, &0x13u32.to_le_bytes())?;
+        let mut count = 0;
+        while count < MAX_HW_BREAKPOINTS {
+            self.write_register(bridge, hart, PC_REGNUM, nop_at)?;
+            self.write_hw_breakpoint(bridge, hart, count, Some(nop_at))?;
+            self.write_status(
+                bridge,
+                hart,
+                VexRiscvFlags::HALT_CLEAR | VexRiscvFlags::STEP,
+            )?;
+            let mut status = VexRiscvFlags::empty();
+            for _ in 0..STEP_POLL_LIMIT {
+                status = self.read_status(bridge, hart)?;
+                if status.contains(VexRiscvFlags::HALT) {
+                    break;
+                }
+            }
+            self.write_hw_breakpoint(bridge, hart, count, None)?;
+            // AUIPC x0, 0
+            let stopped_at = self.run_instruction(bridge, hart, 0x17)?;
+            if !status.contains(VexRiscvFlags::HALTED_BY_BREAK) || stopped_at != nop_at {
+                break;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Resume just `hart`, ignoring its group
+    pub fn resume_single(&self, bridge: &Bridge, hart: usize) -> Result<(), BridgeError> {
+        self.arm_trap_breakpoint(bridge, hart)?;
+        self.restore_context(bridge, hart)?;
         self.write_status(
             bridge,
+            hart,
             VexRiscvFlags::HALT_CLEAR | VexRiscvFlags::RESET_CLEAR,
         )
     }
 
-    pub fn step(&self, bridge: &Bridge) -> Result<(), BridgeError> {
-        self.write_status(bridge, VexRiscvFlags::HALT_CLEAR | VexRiscvFlags::STEP)
+    /* --- */
+
+    /// Put `value` in `reg` on the hart.  pc is set by loading x1 and
+    /// jumping to it, which leaves x1 clobbered.
+    fn write_register(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        reg: u32,
+        value: u32,
+    ) -> Result<(), BridgeError> {
+        let instruction_addr = self.harts[hart].debug_offset + 4;
+        let mut batch = bridge.batch();
+        if reg == PC_REGNUM {
+            for inst in Self::load_immediate(1, value) {
+                batch = batch.write(instruction_addr, inst);
+            }
+            // JALR x0, 0(x1)
+            batch = batch.write(instruction_addr, (1 << 15) | 0x67);
+        } else {
+            for inst in Self::load_immediate(reg, value) {
+                batch = batch.write(instruction_addr, inst);
+            }
+        }
+        batch.commit()?;
+        Ok(())
     }
 
-    /* --- */
-    fn write_register(&self, bridge: &Bridge, reg: u32, value: u32) -> Result<(), BridgeError> {
-        assert!(reg <= 32);
+    /// The instructions that put `value` into `reg`
+    fn load_immediate(reg: u32, value: u32) -> Vec<u32> {
+        assert!(reg < 32);
         // Use LUI instruction if necessary
         if (value & 0xffff_f800) != 0 {
             let low = value & 0x0000_0fff;
+            // ADDI sign-extends, so the upper part is rounded up to make
+            // up for it, wrapping to 0 in the last 2K of the address space
             let high = if (low & 0x800) != 0 {
-                (value & 0xffff_f000) + 0x1000
+                (value & 0xffff_f000).wrapping_add(0x1000)
             } else {
                 value & 0xffff_f000
             };
 
             // Also issue ADDI
             if low != 0 {
-                // LUI regId, high
-                self.write_instruction(bridge, 0x37 | (reg << 7) | high)?;
-
-                // ADDI regId, regId, low
-                self.write_instruction(bridge, 0x13 | (reg << 7) | (reg << 15) | (low << 20))
+                vec![
+                    // LUI regId, high
+                    0x37 | (reg << 7) | high,
+                    // ADDI regId, regId, low
+                    0x13 | (reg << 7) | (reg << 15) | (low << 20),
+                ]
             } else {
                 // LUI regId, high
-                self.write_instruction(bridge, 0x37 | (reg << 7) | high)
+                vec![0x37 | (reg << 7) | high]
             }
         } else {
             // ORI regId, x0, value
-            self.write_instruction(bridge, 0x13 | (reg << 7) | (6 << 12) | (value << 20))
+            vec![0x13 | (reg << 7) | (6 << 12) | (value << 20)]
         }
     }
     /* --- */
-    fn write_status(&self, bridge: &Bridge, value: VexRiscvFlags) -> Result<(), BridgeError> {
-        bridge.poke(self.debug_offset, value.bits)
+    fn write_status(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        value: VexRiscvFlags,
+    ) -> Result<(), BridgeError> {
+        bridge.poke(self.harts[hart].debug_offset, value.bits)
     }
 
-    fn read_status(&self, bridge: &Bridge) -> Result<VexRiscvFlags, BridgeError> {
-        match bridge.peek(self.debug_offset) {
+    fn read_status(&self, bridge: &Bridge, hart: usize) -> Result<VexRiscvFlags, BridgeError> {
+        match bridge.peek(self.harts[hart].debug_offset) {
             Err(e) => Err(e),
             Ok(bits) => Ok(VexRiscvFlags { bits }),
         }
     }
 
-    fn write_instruction(&self, bridge: &Bridge, value: u32) -> Result<(), BridgeError> {
-        bridge.poke(self.debug_offset + 4, value)
+    fn write_instruction(
+        &self,
+        bridge: &Bridge,
+        hart: usize,
+        value: u32,
+    ) -> Result<(), BridgeError> {
+        bridge.poke(self.harts[hart].debug_offset + 4, value)
+    }
+
+    /// Have the hart execute `inst` and return what it left in the result
+    /// register, in a single batch
+    fn run_instruction(&self, bridge: &Bridge, hart: usize, inst: u32) -> Result<u32, BridgeError> {
+        let instruction_addr = self.harts[hart].debug_offset + 4;
+        let results = bridge
+            .batch()
+            .write(instruction_addr, inst)
+            .read(instruction_addr)
+            .commit()?;
+        Ok(results[0])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::{RiscvCpu, TargetDescription, Trigger, VectorSupport, WatchKind};
+    use crate::board::{MemoryKind, MemoryRegion};
+    use crate::bridge::Bridge;
+    use crate::config::Config;
+    use crate::mock_cpu::MockHart;
+    use crate::virtual_register::VirtualRegister;
+
+    const FIRMWARE: u32 = 0x4000_0100;
+    const SCRATCH: u32 = 0x1000_0000;
+
+    /// A halted hart at `FIRMWARE`, which holds `addi x5, x5, 1`, with
+    /// every register set to something of its own
+    fn halted_hart(comparators: usize) -> (RiscvCpu, Bridge, Arc<Mutex<MockHart>>) {
+        let cfg = Config::from_args(["riscv", "--mock"]).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        bridge.poke(FIRMWARE, 0x0012_8293).unwrap();
+        let mut hart = MockHart::new(FIRMWARE, comparators);
+        for (reg, value) in hart.x.iter_mut().enumerate().skip(1) {
+            *value = 0x100 * reg as u32;
+        }
+        let hart = hart.attach(&bridge);
+        (RiscvCpu::new(&cfg).unwrap(), bridge, hart)
+    }
+
+    #[test]
+    fn reads_stop_at_the_top_of_memory() {
+        let mut cfg = Config::from_args(["riscv", "--mock"]).unwrap();
+        cfg.memory_map = vec![MemoryRegion {
+            name: "top".to_owned(),
+            base: 0xffff_f000,
+            size: 0x1000,
+            kind: MemoryKind::Ram,
+        }];
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        bridge.poke(0xffff_fff8, 0x0403_0201).unwrap();
+        bridge.poke(0xffff_fffc, 0x0807_0605).unwrap();
+        MockHart::new(FIRMWARE, 0).attach(&bridge);
+
+        // Straight off the bus, and through the hart where there's no map
+        let on_bus = RiscvCpu::new(&cfg).unwrap();
+        cfg.memory_map.clear();
+        let through_hart = RiscvCpu::new(&cfg).unwrap();
+        for cpu in [on_bus, through_hart] {
+            assert_eq!(
+                cpu.read_memory_range(&bridge, 0, 0xffff_fffa, 16).unwrap(),
+                [3, 4, 5, 6, 7, 8]
+            );
+            assert_eq!(
+                cpu.read_memory_range(&bridge, 0, 0xffff_fff8, 8).unwrap(),
+                [1, 2, 3, 4, 5, 6, 7, 8]
+            );
+        }
+    }
+
+    #[test]
+    fn probing_comparators_runs_no_firmware() {
+        for &comparators in &[0, 1, 4] {
+            let (cpu, bridge, hart) = halted_hart(comparators);
+            let before = hart.lock().unwrap().x;
+            assert!(!cpu.hw_breakpoints_probed());
+            assert_eq!(cpu.hw_breakpoint_count(), 0);
+
+            assert_eq!(
+                cpu.probe_hw_breakpoints(&bridge, SCRATCH).unwrap(),
+                comparators as u32
+            );
+            assert_eq!(cpu.hw_breakpoint_count(), comparators as u32);
+            cpu.restore_context(&bridge, 0).unwrap();
+
+            let hart = hart.lock().unwrap();
+            assert!(hart.stepped.iter().all(|&pc| pc == SCRATCH));
+            assert_eq!(hart.pc, FIRMWARE);
+            assert_eq!(hart.x, before);
+            assert!(hart.comparators.iter().all(Option::is_none));
+        }
     }
 
-    fn read_result(&self, bridge: &Bridge) -> Result<u32, BridgeError> {
-        bridge.peek(self.debug_offset + 4)
+    #[test]
+    fn csr_access_puts_x1_back() {
+        let (cpu, bridge, hart) = halted_hart(0);
+        hart.lock().unwrap().csrs.insert(0x305, 0x4000_0000);
+        assert_eq!(cpu.read_csr(&bridge, 0, 0x305).unwrap(), 0x4000_0000);
+        cpu.write_csr(&bridge, 0, 0x340, 0x1234_5678).unwrap();
+        cpu.restore_context(&bridge, 0).unwrap();
+
+        let hart = hart.lock().unwrap();
+        assert_eq!(hart.csrs[&0x340], 0x1234_5678);
+        assert_eq!(hart.x[1], 0x100);
+        assert_eq!(hart.pc, FIRMWARE);
+    }
+
+    #[test]
+    fn trigger_probe_survives_a_trap() {
+        let (cpu, bridge, hart) = halted_hart(0);
+        hart.lock().unwrap().csrs.insert(0x305, 0x4000_1000);
+        let before = hart.lock().unwrap().csrs.clone();
+        assert_eq!(cpu.trigger_count(&bridge).unwrap(), 0);
+        cpu.restore_context(&bridge, 0).unwrap();
+
+        let hart = hart.lock().unwrap();
+        assert_eq!(hart.pc, FIRMWARE);
+        assert_eq!(hart.csrs, before);
+        assert_eq!(hart.x[1], 0x100);
+    }
+
+    #[test]
+    fn triggers_are_found() {
+        let (cpu, bridge, hart) = halted_hart(0);
+        {
+            let mut hart = hart.lock().unwrap();
+            hart.csrs.insert(0x7a0, 0);
+            hart.csrs.insert(0x7a1, 2 << 28);
+        }
+        // Every index reads back as an address match trigger
+        assert_eq!(cpu.trigger_count(&bridge).unwrap(), 32);
+        assert_eq!(hart.lock().unwrap().pc, FIRMWARE);
+    }
+
+    #[test]
+    fn mcontrol_encoding() {
+        let trigger = |kind, addr, len| Trigger { kind, addr, len }.mcontrol();
+        // type 2, dmode, action 1, m, s and u
+        let base = (2 << 28) | (1 << 27) | (1 << 12) | (1 << 6) | (1 << 4) | (1 << 3);
+        assert_eq!(
+            trigger(WatchKind::Execute, 0x4000_0100, 1),
+            Some((base | (1 << 2), 0x4000_0100))
+        );
+        assert_eq!(
+            trigger(WatchKind::Write, 0x4000_0100, 0),
+            Some((base | (1 << 1), 0x4000_0100))
+        );
+        // Anything longer is a NAPOT range, with its size in the low bits
+        assert_eq!(
+            trigger(WatchKind::Read, 0x4000_0100, 4),
+            Some((base | (1 << 7) | 1, 0x4000_0101))
+        );
+        assert_eq!(
+            trigger(WatchKind::Access, 0x4000_0100, 16),
+            Some((base | (1 << 7) | 3, 0x4000_0107))
+        );
+        assert_eq!(trigger(WatchKind::Write, 0x4000_0102, 4), None);
+        assert_eq!(trigger(WatchKind::Write, 0x4000_0100, 3), None);
+    }
+
+    #[test]
+    fn virtual_registers_follow_the_last_described_register() {
+        let virtual_registers = [
+            VirtualRegister {
+                name: "state".to_owned(),
+                addr: 0x4000_0000,
+                width: 4,
+            },
+            VirtualRegister {
+                name: "mode".to_owned(),
+                addr: 0x4000_0004,
+                width: 1,
+            },
+        ];
+        for (vector, first) in [
+            (VectorSupport::Absent, 4162),
+            (VectorSupport::Present { vlenb: 16 }, 4194),
+        ] {
+            let description = RiscvCpu::make_target_description(&[], vector, &virtual_registers);
+            assert!(description
+                .file("target.xml")
+                .unwrap()
+                .contains("<xi:include href=\"virtual.xml\"/>"));
+            let feature = description.file("virtual.xml").unwrap();
+            assert!(feature.contains("<feature name=\"org.litex.virtual\">"));
+            for (n, name) in ["state", "mode"].iter().enumerate() {
+                let reg = format!(
+                    "<reg name=\"{}\" bitsize=\"32\" regnum=\"{}\" save-restore=\"no\" \
+                     type=\"uint32\" group=\"virtual\"/>",
+                    name,
+                    first + n
+                );
+                assert!(feature.contains(&reg), "{} not in {}", reg, feature);
+            }
+        }
+
+        let description = RiscvCpu::make_target_description(&[], VectorSupport::Absent, &[]);
+        assert!(description.file("virtual.xml").is_none());
+        assert!(!description
+            .file("target.xml")
+            .unwrap()
+            .contains("virtual.xml"));
+    }
+
+    #[test]
+    fn virtual_registers_are_read_from_memory() {
+        let cfg = Config::from_args([
+            "riscv",
+            "--mock",
+            "--virtual-register",
+            "state=0x40000000",
+            "--virtual-register",
+            "mode=0x40000005:8",
+        ])
+        .unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        MockHart::new(FIRMWARE, 0).attach(&bridge);
+        let cpu = RiscvCpu::new(&cfg).unwrap();
+        bridge.poke(0x4000_0000, 0x1234_5678).unwrap();
+        bridge.poke(0x4000_0004, 0xaabb_ccdd).unwrap();
+
+        // The mock hart has no vector registers, so they follow the CSRs
+        assert_eq!(cpu.read_register(&bridge, 0, 4162).unwrap(), 0x1234_5678);
+        assert_eq!(cpu.read_register(&bridge, 0, 4163).unwrap(), 0xcc);
+        assert_eq!(cpu.read_register_words(&bridge, 0, 4163).unwrap(), [0xcc]);
+        cpu.set_register(&bridge, 0, 4163, 0x11).unwrap();
+        assert_eq!(bridge.peek(0x4000_0004).unwrap(), 0xaabb_11dd);
+        assert!(cpu.read_register(&bridge, 0, 4164).is_err());
+    }
+
+    #[test]
+    fn target_description_files() {
+        let mut description = TargetDescription::default();
+        assert_eq!(
+            description.file("target.xml").unwrap(),
+            "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+             <target version=\"1.0\">\n</target>\n"
+        );
+
+        description.add_feature("a.xml", "org.example.a", "<reg name=\"a\"/>\n".to_owned());
+        description.add_feature("b.xml", "org.example.b", String::new());
+        assert_eq!(
+            description.file("target.xml").unwrap(),
+            "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+             <target version=\"1.0\">\n<xi:include href=\"a.xml\"/>\n\
+             <xi:include href=\"b.xml\"/>\n</target>\n"
+        );
+        assert_eq!(
+            description.file("a.xml").unwrap(),
+            "<?xml version=\"1.0\"?>\n<!DOCTYPE feature SYSTEM \"gdb-target.dtd\">\n\
+             <feature name=\"org.example.a\">\n<reg name=\"a\"/>\n</feature>\n"
+        );
+        assert!(description
+            .file("b.xml")
+            .unwrap()
+            .ends_with("<feature name=\"org.example.b\">\n</feature>\n"));
+        assert!(description.file("c.xml").is_none());
+    }
+
+    #[test]
+    fn target_xml_includes_every_feature() {
+        let (cpu, bridge, _hart) = halted_hart(0);
+        let target = String::from_utf8(cpu.get_feature(&bridge, "target.xml").unwrap()).unwrap();
+        let includes: Vec<&str> = target
+            .split("<xi:include href=\"")
+            .skip(1)
+            .map(|rest| rest.split('"').next().unwrap())
+            .collect();
+        // The mock hart has no vector registers, and there are no virtual
+        // ones
+        assert_eq!(includes, ["riscv-cpu.xml", "riscv-csr.xml"]);
+        for (file, name) in includes
+            .iter()
+            .zip(["org.gnu.gdb.riscv.cpu", "org.gnu.gdb.riscv.csr"])
+        {
+            let feature = String::from_utf8(cpu.get_feature(&bridge, file).unwrap()).unwrap();
+            assert!(
+                feature.contains(&format!("<feature name=\"{}\">", name)),
+                "{}",
+                feature
+            );
+        }
+        let cpu_feature =
+            String::from_utf8(cpu.get_feature(&bridge, "riscv-cpu.xml").unwrap()).unwrap();
+        assert!(cpu_feature.contains("<reg name=\"pc\" bitsize=\"32\" regnum=\"32\""));
+        assert!(cpu.get_feature(&bridge, "riscv-vector.xml").is_err());
+    }
+
+    #[test]
+    fn contexts_are_saved_and_loaded() {
+        let (cpu, bridge, hart) = halted_hart(0);
+        {
+            let mut hart = hart.lock().unwrap();
+            hart.csrs.insert(0x305, 0x4000_1000);
+            hart.csrs.insert(0x341, 0x4000_0200);
+            hart.csrs.insert(0x342, 11);
+        }
+        // A register GDB has changed is saved as it was changed to, not as
+        // the hart still has it
+        cpu.set_register(&bridge, 0, 6, 0x6666).unwrap();
+        let context = cpu.save_context(&bridge, 0).unwrap();
+        assert_eq!(context.pc(), FIRMWARE);
+        assert_eq!(context.registers.len(), 33);
+        assert_eq!(context.registers[0], 0);
+        assert_eq!(context.registers[5], 0x500);
+        assert_eq!(context.registers[6], 0x6666);
+        assert_eq!(context.registers[31], 0x1f00);
+        assert!(context.csrs.contains(&(0x305, 0x4000_1000)));
+        assert!(context.csrs.contains(&(0x341, 0x4000_0200)));
+        assert!(context.csrs.contains(&(0x342, 11)));
+
+        // Make a mess of the hart, then put it back
+        cpu.set_register(&bridge, 0, 5, 0xdead).unwrap();
+        cpu.set_register(&bridge, 0, 32, 0x4000_0800).unwrap();
+        cpu.write_csr(&bridge, 0, 0x341, 0).unwrap();
+        cpu.write_csr(&bridge, 0, 0x342, 2).unwrap();
+        cpu.load_context(&bridge, 0, &context).unwrap();
+        assert_eq!(cpu.save_context(&bridge, 0).unwrap(), context);
+        cpu.restore_context(&bridge, 0).unwrap();
+
+        let hart = hart.lock().unwrap();
+        assert_eq!(hart.pc, FIRMWARE);
+        assert_eq!(hart.x[1], 0x100);
+        assert_eq!(hart.x[5], 0x500);
+        assert_eq!(hart.x[6], 0x6666);
+        assert_eq!(hart.csrs[&0x341], 0x4000_0200);
+        assert_eq!(hart.csrs[&0x342], 11);
+        assert_eq!(hart.csrs[&0x305], 0x4000_1000);
     }
 }
@@ -0,0 +1,480 @@
+//! Framing for the GDB remote serial protocol.  A packet travels as
+//! `$payload#xx`, where `xx` is the modulo-256 sum of the payload bytes in
+//! hex.  Outside of packets, `+` and `-` acknowledge them and 0x03 asks
+//! the target to stop.  Notifications go the other way, unasked for, as
+//! `%name:payload#xx`, and aren't acknowledged.
+
+use std::collections::VecDeque;
+
+/// The largest payload we accept, matching the `PacketSize` we advertise
+pub const MAX_PACKET_SIZE: usize = 0x3fff;
+
+/// Characters that have to be escaped inside binary data
+const SPECIAL: &[u8] = b"$#}*";
+
+/// Something that arrived from GDB
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A packet whose checksum matched, with the framing removed
+    Packet(Vec<u8>),
+
+    /// A packet whose checksum didn't match, or wasn't hex
+    BadChecksum(Vec<u8>),
+
+    /// A packet longer than `MAX_PACKET_SIZE`, which was thrown away
+    Overflow,
+
+    Ack,
+    Nak,
+    Interrupt,
+
+    /// A byte outside of a packet that means nothing
+    Junk(u8),
+}
+
+enum State {
+    Idle,
+    Body,
+    Checksum,
+    ChecksumLow(u8),
+}
+
+/// Splits a byte stream into packets and control characters.  Feed it one
+/// byte at a time with `push()`.
+pub struct Decoder {
+    state: State,
+    buffer: Vec<u8>,
+    checksum: u8,
+    overflow: bool,
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder {
+            state: State::Idle,
+            buffer: vec![],
+            checksum: 0,
+            overflow: false,
+        }
+    }
+}
+
+impl Decoder {
+    /// Take the next byte, returning whatever it completes
+    pub fn push(&mut self, byte: u8) -> Option<Event> {
+        match self.state {
+            State::Idle => match byte {
+                b'$' => {
+                    self.start();
+                    None
+                }
+                b'+' => Some(Event::Ack),
+                b'-' => Some(Event::Nak),
+                0x03 => Some(Event::Interrupt),
+                other => Some(Event::Junk(other)),
+            },
+            State::Body => {
+                match byte {
+                    b'#' => self.state = State::Checksum,
+                    // GDB gave up on the last packet and started again
+                    b'$' => self.start(),
+                    other => {
+                        self.checksum = self.checksum.wrapping_add(other);
+                        if self.buffer.len() < MAX_PACKET_SIZE {
+                            self.buffer.push(other);
+                        } else {
+                            self.overflow = true;
+                        }
+                    }
+                }
+                None
+            }
+            // GDB gave up waiting and started again
+            State::Checksum | State::ChecksumLow(_) if byte == b'$' => {
+                let event = self.finish(None);
+                self.start();
+                Some(event)
+            }
+            State::Checksum => match hex_digit(byte) {
+                Some(high) => {
+                    self.state = State::ChecksumLow(high);
+                    None
+                }
+                None => Some(self.finish(None)),
+            },
+            State::ChecksumLow(high) => {
+                let remote = hex_digit(byte).map(|low| high << 4 | low);
+                Some(self.finish(remote))
+            }
+        }
+    }
+
+    fn start(&mut self) {
+        self.state = State::Body;
+        self.buffer.clear();
+        self.checksum = 0;
+        self.overflow = false;
+    }
+
+    fn finish(&mut self, remote: Option<u8>) -> Event {
+        self.state = State::Idle;
+        let payload = std::mem::take(&mut self.buffer);
+        if self.overflow {
+            Event::Overflow
+        } else if remote == Some(self.checksum) {
+            Event::Packet(payload)
+        } else {
+            Event::BadChecksum(payload)
+        }
+    }
+}
+
+fn hex_digit(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|d| d as u8)
+}
+
+pub fn checksum(payload: &[u8]) -> u8 {
+    payload.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+/// Wrap a payload up as `$payload#xx`
+pub fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(payload.len() + 4);
+    out.push(b'$');
+    out.extend_from_slice(payload);
+    out.extend_from_slice(format!("#{:02x}", checksum(payload)).as_bytes());
+    out
+}
+
+/// Wrap a payload up as the notification `%name:payload#xx`.  The checksum
+/// covers the name as well.
+pub fn frame_notification(name: &str, payload: &[u8]) -> Vec<u8> {
+    let mut body = Vec::with_capacity(name.len() + 1 + payload.len());
+    body.extend_from_slice(name.as_bytes());
+    body.push(b':');
+    body.extend_from_slice(payload);
+    let mut out = frame(&body);
+    out[0] = b'%';
+    out
+}
+
+/// Events waiting to be collected by GDB.  Only the first is sent as a
+/// notification.  GDB then sends the matching ack packet (`vStopped` for
+/// `Stop`) until it's answered with `OK`, and each ack is answered with
+/// the next event, so events that happen while GDB is busy are queued up
+/// rather than lost.
+pub struct Notifications {
+    name: &'static str,
+
+    /// The event GDB was last sent comes first, until it's acked
+    pending: VecDeque<Vec<u8>>,
+}
+
+impl Notifications {
+    pub fn new(name: &'static str) -> Notifications {
+        Notifications {
+            name,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Queue an event.  If nothing was waiting, GDB has to be told, and
+    /// this returns the notification to send.
+    pub fn push(&mut self, payload: Vec<u8>) -> Option<Vec<u8>> {
+        self.pending.push_back(payload);
+        if self.pending.len() == 1 {
+            Some(frame_notification(self.name, &self.pending[0]))
+        } else {
+            None
+        }
+    }
+
+    /// The event GDB was last sent, if it hasn't been acked yet
+    pub fn first(&self) -> Option<&[u8]> {
+        self.pending.front().map(|p| p.as_slice())
+    }
+
+    /// GDB has seen the last event.  Returns the next, to send as the
+    /// reply to the ack, or `None` if the reply is `OK` because there are
+    /// no more.
+    pub fn ack(&mut self) -> Option<&[u8]> {
+        self.pending.pop_front();
+        self.first()
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+    }
+}
+
+/// Escape the characters that can't appear raw in binary data: `$`, `#`,
+/// `}` and `*` become `}` followed by the character XORed with 0x20.
+pub fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &byte in data {
+        if SPECIAL.contains(&byte) {
+            out.push(b'}');
+            out.push(byte ^ 0x20);
+        } else {
+            out.push(byte);
+        }
+    }
+    out
+}
+
+/// Undo `escape()`.  A `}` at the very end has nothing to escape and is
+/// dropped.
+pub fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'}' {
+            if let Some(&next) = bytes.next() {
+                out.push(next ^ 0x20);
+            }
+        } else {
+            out.push(byte);
+        }
+    }
+    out
+}
+
+/// Decode a string of hex digit pairs, as used for memory contents and
+/// file names.  Returns `None` if any pair isn't hex.
+pub fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    hex.chunks(2)
+        .map(|pair| {
+            std::str::from_utf8(pair)
+                .ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+        })
+        .collect()
+}
+
+/// Run-length encode a packet body.  A run is written as the character,
+/// `*`, and the number of extra repeats plus 29.  Counts that would encode
+/// as `#` or `$` are shortened, and special characters are never repeated
+/// so that escape sequences stay intact.
+pub fn rle_encode(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        let byte = data[i];
+        let mut run = 1;
+        while i + run < data.len() && data[i + run] == byte && run < 98 {
+            run += 1;
+        }
+        out.push(byte);
+        let mut repeats = run - 1;
+        if repeats >= 3 && !SPECIAL.contains(&byte) {
+            // 6 and 7 would encode as '#' and '$'
+            if repeats == 6 || repeats == 7 {
+                repeats = 5;
+            }
+            out.push(b'*');
+            out.push(repeats as u8 + 29);
+            i += repeats + 1;
+        } else {
+            i += 1;
+        }
+    }
+    out
+}
+
+/// Undo `rle_encode()`, the way GDB does.  A `*` with nothing before it
+/// or after it is kept as-is.  The server never has to, but the tests
+/// read its replies with this.
+#[cfg(test)]
+pub fn rle_decode(data: &[u8]) -> Vec<u8> {
+    let mut out: Vec<u8> = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        match (data[i], out.last().cloned(), data.get(i + 1)) {
+            (b'*', Some(prev), Some(&count)) if count >= 29 => {
+                for _ in 0..count - 29 {
+                    out.push(prev);
+                }
+                i += 2;
+            }
+            (byte, _, _) => {
+                out.push(byte);
+                i += 1;
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::prelude::*;
+    use proptest::sample::{select, Index};
+
+    /// Data that favours the bytes the framing treats specially, and runs
+    /// of repeated bytes for the RLE
+    fn payload() -> impl Strategy<Value = Vec<u8>> {
+        let byte = prop_oneof![1 => select(b"$#}*+-\x03".to_vec()), 3 => any::<u8>()];
+        let run = prop_oneof![4 => Just(1), 1 => 1..120usize];
+        prop::collection::vec((byte, run), 0..100).prop_map(|runs| {
+            runs.into_iter()
+                .flat_map(|(byte, run)| std::iter::repeat_n(byte, run))
+                .collect()
+        })
+    }
+
+    fn decode_all(decoder: &mut Decoder, bytes: &[u8]) -> Vec<Event> {
+        bytes.iter().filter_map(|&b| decoder.push(b)).collect()
+    }
+
+    proptest! {
+        #[test]
+        fn escape_round_trip(data in payload()) {
+            let escaped = escape(&data);
+            prop_assert!(!escaped.iter().any(|b| b"$#*".contains(b)));
+            prop_assert_eq!(unescape(&escaped), data);
+        }
+
+        #[test]
+        fn rle_round_trip(data in payload()) {
+            let data = escape(&data);
+            let encoded = rle_encode(&data);
+            prop_assert!(encoded.len() <= data.len());
+            prop_assert!(!encoded.iter().any(|b| b"$#".contains(b)));
+            prop_assert_eq!(rle_decode(&encoded), data);
+        }
+
+        #[test]
+        fn frame_round_trip(data in payload()) {
+            let mut decoder = Decoder::default();
+            let payload = rle_encode(&escape(&data));
+            let events = decode_all(&mut decoder, &frame(&payload));
+            prop_assert_eq!(events, vec![Event::Packet(payload)]);
+        }
+
+        #[test]
+        fn corruption_is_detected(data in payload(), idx in any::<Index>(), replacement: u8) {
+            let mut decoder = Decoder::default();
+            let mut framed = frame(&escape(&data));
+            // Change one byte of the payload or checksum to something that
+            // isn't framing
+            let idx = 1 + idx.index(framed.len() - 1);
+            prop_assume!(framed[idx] != b'#');
+            prop_assume!(!replacement.eq_ignore_ascii_case(&framed[idx]));
+            prop_assume!(!b"$#".contains(&replacement));
+            framed[idx] = replacement;
+            // A checksum that isn't hex ends the packet early, and what's
+            // left of it is junk
+            let events = decode_all(&mut decoder, &framed);
+            prop_assert!(
+                matches!(events[0], Event::BadChecksum(_)),
+                "{:?} accepted",
+                framed
+            );
+            prop_assert!(events[1..].iter().all(|e| matches!(e, Event::Junk(_))));
+        }
+
+        /// Whatever arrives, the decoder never panics, and it finds every
+        /// packet that's framed properly afterwards
+        #[test]
+        fn garbage_then_packet(garbage in payload()) {
+            let mut decoder = Decoder::default();
+            decode_all(&mut decoder, &garbage);
+            // Anything left half-finished is ended by the packet's `$`
+            let events = decode_all(&mut decoder, &frame(b"qSupported"));
+            prop_assert_eq!(events.last(), Some(&Event::Packet(b"qSupported".to_vec())));
+        }
+    }
+
+    #[test]
+    fn hex_decoding() {
+        assert_eq!(decode_hex(b"2f746d70"), Some(b"/tmp".to_vec()));
+        assert_eq!(decode_hex(b"00FF"), Some(vec![0x00, 0xff]));
+        assert_eq!(decode_hex(b""), Some(vec![]));
+        assert_eq!(decode_hex(b"0g"), None);
+    }
+
+    #[test]
+    fn checksum_case_is_ignored() {
+        let mut decoder = Decoder::default();
+        let events = decode_all(&mut decoder, b"$\xff\xfb#FA");
+        assert_eq!(events, vec![Event::Packet(b"\xff\xfb".to_vec())]);
+    }
+
+    #[test]
+    fn control_characters_between_packets() {
+        let mut decoder = Decoder::default();
+        let mut stream = b"+-\x03".to_vec();
+        stream.extend_from_slice(&frame(b"g"));
+        stream.push(b'+');
+        assert_eq!(
+            decode_all(&mut decoder, &stream),
+            vec![
+                Event::Ack,
+                Event::Nak,
+                Event::Interrupt,
+                Event::Packet(b"g".to_vec()),
+                Event::Ack,
+            ]
+        );
+    }
+
+    #[test]
+    fn restarted_packet() {
+        let mut decoder = Decoder::default();
+        let mut stream = b"$m1000,".to_vec();
+        stream.extend_from_slice(&frame(b"g"));
+        assert_eq!(
+            decode_all(&mut decoder, &stream),
+            vec![Event::Packet(b"g".to_vec())]
+        );
+    }
+
+    #[test]
+    fn oversized_packet() {
+        let mut decoder = Decoder::default();
+        let payload = vec![b'a'; MAX_PACKET_SIZE + 1];
+        assert_eq!(
+            decode_all(&mut decoder, &frame(&payload)),
+            vec![Event::Overflow]
+        );
+        let payload = vec![b'a'; MAX_PACKET_SIZE];
+        assert_eq!(
+            decode_all(&mut decoder, &frame(&payload)),
+            vec![Event::Packet(payload)]
+        );
+    }
+
+    #[test]
+    fn notification_framing() {
+        let framed = frame_notification("Stop", b"T05thread:1;");
+        assert_eq!(framed, b"%Stop:T05thread:1;#b7".to_vec());
+        // GDB's decoder sees the same checksum as for a packet
+        let mut decoder = Decoder::default();
+        let mut packet = framed.clone();
+        packet[0] = b'$';
+        assert_eq!(
+            decode_all(&mut decoder, &packet),
+            vec![Event::Packet(b"Stop:T05thread:1;".to_vec())]
+        );
+    }
+
+    #[test]
+    fn notifications_queue_until_acked() {
+        let mut stops = Notifications::new("Stop");
+        assert_eq!(
+            stops.push(b"T05thread:1;".to_vec()),
+            Some(frame_notification("Stop", b"T05thread:1;"))
+        );
+        // GDB hasn't acked the first, so it's already going to ask
+        assert_eq!(stops.push(b"T05thread:2;".to_vec()), None);
+        assert_eq!(stops.push(b"T02thread:3;".to_vec()), None);
+        assert_eq!(stops.ack(), Some(&b"T05thread:2;"[..]));
+        assert_eq!(stops.ack(), Some(&b"T02thread:3;"[..]));
+        assert_eq!(stops.ack(), None);
+        // An ack with nothing waiting is answered with OK too
+        assert_eq!(stops.ack(), None);
+        // Once drained, the next event is sent straight away again
+        assert!(stops.push(b"T05thread:1;".to_vec()).is_some());
+    }
+}
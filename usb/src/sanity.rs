@@ -0,0 +1,287 @@
+use std::thread;
+use std::time::Duration;
+
+use super::bridge::{Bridge, BridgeBackend, BridgeError};
+use super::config::Config;
+use super::csr_map::CsrMap;
+use super::riscv::RiscvCpu;
+
+/// How many times to look at a hart after asking it to halt or resume,
+/// a millisecond apart
+const RUN_CONTROL_POLL_LIMIT: u32 = 100;
+
+pub enum Outcome {
+    Passed(String),
+    Failed(String),
+    Skipped(String),
+}
+
+/// One startup check, how it went, and what to try if it failed
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: Outcome,
+    pub hint: &'static str,
+}
+
+pub struct Report {
+    checks: Vec<Check>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        !self
+            .checks
+            .iter()
+            .any(|c| matches!(c.outcome, Outcome::Failed(_)))
+    }
+
+    pub fn print(&self) {
+        for check in &self.checks {
+            match check.outcome {
+                Outcome::Passed(ref detail) => ui_info!("{:<12} ok       {}", check.name, detail),
+                Outcome::Skipped(ref why) => ui_info!("{:<12} skipped  {}", check.name, why),
+                Outcome::Failed(ref detail) => {
+                    ui_error!("{:<12} FAILED   {}", check.name, detail);
+                    ui_error!("{:<12}          {}", "", check.hint);
+                }
+            }
+        }
+    }
+}
+
+/// Make sure the target is in a state a server can work with, so that a
+/// problem shows up as a diagnostic now rather than as GDB hanging later.
+/// The CPU is only checked when `check_cpu` is set, since the Wishbone
+/// and terminal servers work without one.  Checks that can't mean
+/// anything after an earlier one failed are skipped.
+pub fn run(cfg: &Config, cpu: &RiscvCpu, bridge: &Bridge, check_cpu: bool) -> Report {
+    let mut checks = vec![];
+
+    let bridge_ok = check_bridge(&mut checks, cpu, bridge);
+    check_csr_map(&mut checks, cfg, bridge, bridge_ok);
+
+    let why_not = if !check_cpu {
+        Some("this server doesn't use the CPU")
+    } else if let BridgeBackend::Mock = cfg.bridge_backend {
+        Some("the mock bridge has no CPU behind it")
+    } else if !bridge_ok {
+        Some("the bridge isn't answering")
+    } else {
+        None
+    };
+    match why_not {
+        Some(why) => {
+            for name in &["debug unit", "run control"] {
+                checks.push(Check {
+                    name,
+                    outcome: Outcome::Skipped(why.to_owned()),
+                    hint: "",
+                });
+            }
+        }
+        None => {
+            if check_debug_unit(&mut checks, cpu, bridge) {
+                check_run_control(&mut checks, cpu, bridge);
+            } else {
+                checks.push(Check {
+                    name: "run control",
+                    outcome: Outcome::Skipped("no debug unit to drive".to_owned()),
+                    hint: "",
+                });
+            }
+        }
+    }
+
+    Report { checks }
+}
+
+fn check_bridge(checks: &mut Vec<Check>, cpu: &RiscvCpu, bridge: &Bridge) -> bool {
+    let addr = cpu.debug_offset(0);
+    let outcome = match bridge.peek(addr) {
+        Ok(_) => Outcome::Passed(format!(
+            "{} answered a read of {:08x}",
+            bridge.backend_name(),
+            addr
+        )),
+        Err(e) => Outcome::Failed(format!("reading {:08x} failed: {:?}", addr, e)),
+    };
+    let passed = matches!(outcome, Outcome::Passed(_));
+    checks.push(Check {
+        name: "bridge",
+        outcome,
+        hint: "check the device is attached and shows up in --list, and that --pid, --vid, --etherbone or --serial pick it",
+    });
+    passed
+}
+
+fn check_csr_map(checks: &mut Vec<Check>, cfg: &Config, bridge: &Bridge, bridge_ok: bool) {
+    let hint =
+        "use the csr.csv from the build the device is running, or load the matching bitstream";
+    let outcome = match cfg.csr_csv {
+        None => Outcome::Skipped("no --csr-csv given".to_owned()),
+        Some(_) if !bridge_ok => Outcome::Skipped("the bridge isn't answering".to_owned()),
+        Some(ref path) => match CsrMap::load(path) {
+            Err(e) => Outcome::Failed(format!("couldn't load {}: {}", path, e)),
+            Ok(map) => match map.check_identifier(bridge) {
+                Ok(None) => Outcome::Passed(format!("{} agrees with the identifier ROM", path)),
+                Ok(Some(warning)) => Outcome::Failed(warning),
+                Err(e) => Outcome::Failed(format!("couldn't read the identifier ROM: {:?}", e)),
+            },
+        },
+    };
+    checks.push(Check {
+        name: "csr map",
+        outcome,
+        hint,
+    });
+}
+
+fn check_debug_unit(checks: &mut Vec<Check>, cpu: &RiscvCpu, bridge: &Bridge) -> bool {
+    let mut outcome = Outcome::Passed(format!(
+        "{} hart{} answering",
+        cpu.hart_count(),
+        if cpu.hart_count() == 1 { "" } else { "s" }
+    ));
+    for hart in 0..cpu.hart_count() {
+        let addr = cpu.debug_offset(hart);
+        match cpu.check_debug_unit(bridge, hart) {
+            Ok(None) => (),
+            Ok(Some(status)) => {
+                outcome = Outcome::Failed(format!(
+                    "hart {} status at {:08x} reads {:08x}, which isn't a VexRiscv debug unit",
+                    hart, addr, status
+                ));
+                break;
+            }
+            Err(e) => {
+                outcome = Outcome::Failed(format!("hart {} at {:08x}: {:?}", hart, addr, e));
+                break;
+            }
+        }
+    }
+    let passed = matches!(outcome, Outcome::Passed(_));
+    checks.push(Check {
+        name: "debug unit",
+        outcome,
+        hint: "check --debug-offset against the SoC, and that the CPU was built with its debug plugin",
+    });
+    passed
+}
+
+/// Halt each hart that's running and let it go again.  Harts that were
+/// already halted are left that way.
+fn check_run_control(checks: &mut Vec<Check>, cpu: &RiscvCpu, bridge: &Bridge) {
+    let outcome = match halt_and_resume(cpu, bridge) {
+        Ok(0) => Outcome::Passed("every hart was already halted, so none were touched".to_owned()),
+        Ok(1) => Outcome::Passed("halted and resumed 1 hart".to_owned()),
+        Ok(count) => Outcome::Passed(format!("halted and resumed {} harts", count)),
+        Err(problem) => Outcome::Failed(problem),
+    };
+    checks.push(Check {
+        name: "run control",
+        outcome,
+        hint: "the CPU may be held in reset or have no clock; try reloading the bitstream",
+    });
+}
+
+/// The number of harts that were halted and resumed, or what went wrong
+fn halt_and_resume(cpu: &RiscvCpu, bridge: &Bridge) -> Result<usize, String> {
+    let failed = |e: BridgeError| format!("{:?}", e);
+    let mut running = vec![];
+    for hart in 0..cpu.hart_count() {
+        if !cpu.is_halted(bridge, hart).map_err(failed)? {
+            running.push(hart);
+        }
+    }
+    for &hart in &running {
+        cpu.halt_hart(bridge, hart).map_err(failed)?;
+        if !wait_for(|| cpu.is_halted(bridge, hart)).map_err(failed)? {
+            return Err(format!("hart {} didn't halt", hart));
+        }
+    }
+    for &hart in &running {
+        cpu.resume_single(bridge, hart).map_err(failed)?;
+        if !wait_for(|| cpu.is_halted(bridge, hart).map(|halted| !halted)).map_err(failed)? {
+            return Err(format!("hart {} didn't resume", hart));
+        }
+    }
+    Ok(running.len())
+}
+
+fn wait_for(mut done: impl FnMut() -> Result<bool, BridgeError>) -> Result<bool, BridgeError> {
+    for _ in 0..RUN_CONTROL_POLL_LIMIT {
+        if done()? {
+            return Ok(true);
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_debug_unit, halt_and_resume, run, Outcome};
+    use crate::bridge::Bridge;
+    use crate::config::Config;
+    use crate::mock_cpu::{MockHart, DEBUG_BASE};
+    use crate::riscv::RiscvCpu;
+
+    fn target() -> (Config, RiscvCpu, Bridge) {
+        let cfg = Config::from_args(["sanity", "--mock"]).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        let cpu = RiscvCpu::new(&cfg).unwrap();
+        (cfg, cpu, bridge)
+    }
+
+    #[test]
+    fn mock_targets_skip_the_cpu() {
+        let (cfg, cpu, bridge) = target();
+        let report = run(&cfg, &cpu, &bridge, true);
+        assert!(report.passed());
+        let outcomes: Vec<(&str, bool)> = report
+            .checks
+            .iter()
+            .map(|c| (c.name, matches!(c.outcome, Outcome::Skipped(_))))
+            .collect();
+        assert_eq!(
+            outcomes,
+            [
+                ("bridge", false),
+                ("csr map", true),
+                ("debug unit", true),
+                ("run control", true)
+            ]
+        );
+    }
+
+    #[test]
+    fn debug_units_are_recognised() {
+        let (_, cpu, bridge) = target();
+        let hart = MockHart::new(0x4000_0100, 0).attach(&bridge);
+        let mut checks = vec![];
+        assert!(check_debug_unit(&mut checks, &cpu, &bridge));
+
+        // Whatever answers has to look like a VexRiscv status word
+        drop(hart);
+        let (_, cpu, bridge) = target();
+        bridge.poke(DEBUG_BASE, 0xffff_ffff).unwrap();
+        assert!(!check_debug_unit(&mut checks, &cpu, &bridge));
+        match checks[1].outcome {
+            Outcome::Failed(ref why) => assert!(why.contains("reads ffffffff"), "{}", why),
+            _ => panic!("a bad status word passed"),
+        }
+    }
+
+    #[test]
+    fn running_harts_are_halted_and_resumed() {
+        let (_, cpu, bridge) = target();
+        let hart = MockHart::new(0x4000_0100, 0).attach(&bridge);
+        assert_eq!(halt_and_resume(&cpu, &bridge), Ok(0));
+        assert!(hart.lock().unwrap().halted);
+
+        hart.lock().unwrap().halted = false;
+        assert_eq!(halt_and_resume(&cpu, &bridge), Ok(1));
+        assert!(!hart.lock().unwrap().halted);
+    }
+}
@@ -0,0 +1,230 @@
+use std::fmt;
+
+use super::board::MemoryRegion;
+use super::bridge::{Bridge, BridgeError};
+use super::config::Config;
+
+#[derive(Debug)]
+pub enum ScratchError {
+    /// There's no scratch area to allocate from
+    NoScratch,
+
+    /// Not enough room left for this many bytes
+    Full(u32),
+
+    /// The address wasn't handed out by the allocator
+    NotAllocated(u32),
+
+    BridgeError(BridgeError),
+}
+
+impl std::convert::From<BridgeError> for ScratchError {
+    fn from(e: BridgeError) -> ScratchError {
+        ScratchError::BridgeError(e)
+    }
+}
+
+impl fmt::Display for ScratchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ScratchError::*;
+        match self {
+            NoScratch => write!(f, "no scratch area; give one with --scratch"),
+            Full(len) => write!(f, "no room for {} bytes of scratch", len),
+            NotAllocated(addr) => write!(f, "{:08x} isn't allocated", addr),
+            BridgeError(e) => write!(f, "bridge error: {:?}", e),
+        }
+    }
+}
+
+/// A piece of the scratch area that's been handed out, along with what
+/// was there before
+struct Allocation {
+    addr: u32,
+    len: u32,
+    original: Vec<u32>,
+}
+
+/// A small area of target RAM that the adapter borrows for its own code
+/// and data.  Whatever the firmware had there is read back before a piece
+/// is handed out and written back when it's freed, so as long as
+/// everything is released on detach the target never knows.
+pub struct Scratch {
+    base: u32,
+    size: u32,
+
+    /// Live allocations, sorted by address
+    allocations: Vec<Allocation>,
+}
+
+impl Scratch {
+    /// The area given with `--scratch`, or one found in the memory map
+    pub fn new(cfg: &Config) -> Option<Scratch> {
+        let (base, size) = cfg.scratch.or_else(|| find(&cfg.memory_map))?;
+        Self::with_region(base, size)
+    }
+
+    /// An area of `size` bytes at `base`, if any whole words of it lie
+    /// within the address space
+    fn with_region(base: u32, size: u32) -> Option<Scratch> {
+        // Everything is handed out in whole words
+        let base_aligned = base.checked_add(3)? & !3;
+        let size = size.saturating_sub(base_aligned - base) & !3;
+        if size == 0 || base_aligned.checked_add(size).is_none() {
+            return None;
+        }
+        Some(Scratch {
+            base: base_aligned,
+            size,
+            allocations: vec![],
+        })
+    }
+
+    /// (base, size) of the whole area
+    pub fn region(&self) -> (u32, u32) {
+        (self.base, self.size)
+    }
+
+    /// (address, length) of everything that's handed out
+    pub fn in_use(&self) -> Vec<(u32, u32)> {
+        self.allocations.iter().map(|a| (a.addr, a.len)).collect()
+    }
+
+    /// Hand out `len` bytes, rounded up to whole words, saving what's
+    /// there first
+    pub fn alloc(&mut self, bridge: &Bridge, len: u32) -> Result<u32, ScratchError> {
+        let len = len.checked_add(3).ok_or(ScratchError::Full(len))? & !3;
+        if len == 0 {
+            return Err(ScratchError::Full(len));
+        }
+        // First fit, looking in the gaps between allocations
+        let mut addr = self.base;
+        let mut idx = 0;
+        for a in &self.allocations {
+            if a.addr - addr >= len {
+                break;
+            }
+            addr = a.addr + a.len;
+            idx += 1;
+        }
+        if self.base + self.size - addr < len {
+            return Err(ScratchError::Full(len));
+        }
+        let original = bridge.read_block(addr, len as usize / 4)?;
+        self.allocations.insert(
+            idx,
+            Allocation {
+                addr,
+                len,
+                original,
+            },
+        );
+        Ok(addr)
+    }
+
+    /// Put back what was at `addr` before it was handed out
+    pub fn free(&mut self, bridge: &Bridge, addr: u32) -> Result<(), ScratchError> {
+        let idx = self
+            .allocations
+            .iter()
+            .position(|a| a.addr == addr)
+            .ok_or(ScratchError::NotAllocated(addr))?;
+        bridge.write_block(addr, &self.allocations[idx].original)?;
+        self.allocations.remove(idx);
+        Ok(())
+    }
+
+    /// Free everything, such as when the debugger goes away
+    pub fn release_all(&mut self, bridge: &Bridge) -> Result<(), ScratchError> {
+        while let Some(a) = self.allocations.last() {
+            let addr = a.addr;
+            self.free(bridge, addr)?;
+        }
+        Ok(())
+    }
+}
+
+/// Find scratch space in the memory map, which has to be a region named
+/// "scratch".  No other RAM can be assumed to be free: even the on-chip
+/// `sram` is where the firmware's stack usually lives.
+fn find(regions: &[MemoryRegion]) -> Option<(u32, u32)> {
+    regions
+        .iter()
+        .find(|r| r.name == "scratch")
+        .map(|r| (r.base, r.size))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find, Scratch, ScratchError};
+    use crate::board::{MemoryKind, MemoryRegion};
+    use crate::bridge::Bridge;
+    use crate::config::Config;
+
+    fn region(name: &str, base: u32, size: u32) -> MemoryRegion {
+        MemoryRegion {
+            name: name.to_owned(),
+            base,
+            size,
+            kind: MemoryKind::Ram,
+        }
+    }
+
+    #[test]
+    fn only_a_scratch_region_is_found() {
+        let sram = region("sram", 0x1000_0000, 0x2000);
+        let main_ram = region("main_ram", 0x4000_0000, 0x1000_0000);
+        assert_eq!(find(&[sram.clone(), main_ram.clone()]), None);
+        assert_eq!(
+            find(&[sram, region("scratch", 0x2000_0000, 0x100), main_ram]),
+            Some((0x2000_0000, 0x100))
+        );
+    }
+
+    #[test]
+    fn areas_are_whole_words_in_the_address_space() {
+        assert_eq!(
+            Scratch::with_region(0x1000_0001, 0x103).unwrap().region(),
+            (0x1000_0004, 0x100)
+        );
+        assert!(Scratch::with_region(0x1000_0000, 3).is_none());
+        assert!(Scratch::with_region(0xffff_fffe, 0x10).is_none());
+        assert!(Scratch::with_region(0xffff_ff00, 0x200).is_none());
+        assert_eq!(
+            Scratch::with_region(0xffff_ff00, 0xfc).unwrap().region(),
+            (0xffff_ff00, 0xfc)
+        );
+    }
+
+    #[test]
+    fn allocations_are_put_back() {
+        let cfg = Config::from_args(["scratch", "--mock"]).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        bridge.poke(0x1000_0000, 0x1234_5678).unwrap();
+        let mut scratch = Scratch::with_region(0x1000_0000, 0x10).unwrap();
+
+        let a = scratch.alloc(&bridge, 5).unwrap();
+        let b = scratch.alloc(&bridge, 4).unwrap();
+        assert_eq!((a, b), (0x1000_0000, 0x1000_0008));
+        assert!(matches!(
+            scratch.alloc(&bridge, 8),
+            Err(ScratchError::Full(8))
+        ));
+        assert!(matches!(
+            scratch.alloc(&bridge, u32::MAX),
+            Err(ScratchError::Full(_))
+        ));
+
+        bridge.poke(a, 0).unwrap();
+        scratch.free(&bridge, a).unwrap();
+        assert_eq!(bridge.peek(a).unwrap(), 0x1234_5678);
+        assert!(matches!(
+            scratch.free(&bridge, a),
+            Err(ScratchError::NotAllocated(_))
+        ));
+        // The gap left behind is used first
+        assert_eq!(scratch.alloc(&bridge, 8).unwrap(), a);
+        scratch.release_all(&bridge).unwrap();
+        assert!(scratch.in_use().is_empty());
+    }
+}
@@ -0,0 +1,341 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use super::bridge::{BatchOp, BridgeError};
+use super::config::Config;
+
+/// Frame types understood by LiteX's `uartwishbone`.  The type byte
+/// starts every frame, and is followed by a word count and the big-endian
+/// word address of the first word.  Writes carry their words after that
+/// and get no answer, while reads are answered with just the words.
+const CMD_WRITE_BURST_INCR: u8 = 0x01;
+const CMD_READ_BURST_INCR: u8 = 0x02;
+
+/// The count is a single byte
+const MAX_FRAME_WORDS: usize = 255;
+
+/// How long the target gets to start answering a read before the line is
+/// given up on.  At 115200 baud a full frame of reads takes about 90ms.
+const TIMEOUT: Duration = Duration::from_millis(500);
+
+/// A bridge to a LiteX `uartwishbone` core over a serial port.  There's no
+/// sequence numbering or checksum in the framing, so a read that times out
+/// leaves the line in an unknown state; anything still arriving is thrown
+/// away and the port is reopened before the next transaction.
+pub struct SerialBridge {
+    path: String,
+    baud: u32,
+    port: Mutex<Option<File>>,
+
+    /// The profile's cap on burst sizes, in bytes
+    burst_limit: usize,
+}
+
+impl SerialBridge {
+    pub fn new(cfg: &Config) -> Result<Self, BridgeError> {
+        let path = match cfg.serial {
+            Some(ref p) => p.clone(),
+            None => return Err(BridgeError::NotConnected),
+        };
+        // Catch a baud rate the port can't do before anything is opened
+        baud_constant(cfg.serial_baud)?;
+        Ok(SerialBridge {
+            path,
+            baud: cfg.serial_baud,
+            port: Mutex::new(None),
+            burst_limit: cfg.tuning.burst_limit.unwrap_or(usize::MAX),
+        })
+    }
+
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        let mut port = self.port.lock().unwrap();
+        if port.is_none() {
+            *port = Some(open(&self.path, self.baud)?);
+        }
+        Ok(())
+    }
+
+    /// One frame's worth of reads
+    pub fn max_burst(&self) -> usize {
+        self.burst_limit.min(MAX_FRAME_WORDS * 4)
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        self.execute(&[BatchOp::Write(addr, value)]).map(|_| ())
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        Ok(self.execute(&[BatchOp::Read(addr)])?[0])
+    }
+
+    /// Runs of reads or writes to consecutive addresses go out as one
+    /// frame.  The whole batch is sent before any of the answers are
+    /// collected, since the core handles frames strictly in order.
+    pub fn execute(&self, ops: &[BatchOp]) -> Result<Vec<u32>, BridgeError> {
+        let (request, reads) = encode(ops);
+        let mut port = self.port.lock().unwrap();
+        if port.is_none() {
+            *port = Some(open(&self.path, self.baud)?);
+            log_bridge!(@Verbose, "serial: reopened {}", self.path);
+        }
+        let result = exchange(port.as_mut().unwrap(), &request, reads);
+        if result.is_err() {
+            // Whatever is still on its way belongs to the failed batch
+            *port = None;
+        }
+        result
+    }
+}
+
+/// Build the frames for a batch, along with how many words of answer they
+/// will get back
+fn encode(ops: &[BatchOp]) -> (Vec<u8>, usize) {
+    let mut request = vec![];
+    let mut reads = 0;
+    let mut idx = 0;
+    while idx < ops.len() {
+        let (cmd, base) = match ops[idx] {
+            BatchOp::Write(addr, _) => (CMD_WRITE_BURST_INCR, addr),
+            BatchOp::Read(addr) => (CMD_READ_BURST_INCR, addr),
+        };
+        let mut words = vec![];
+        let mut count = 0;
+        while count < MAX_FRAME_WORDS {
+            let next = base.wrapping_add(4 * count as u32);
+            match ops.get(idx) {
+                Some(&BatchOp::Write(addr, value))
+                    if cmd == CMD_WRITE_BURST_INCR && addr == next =>
+                {
+                    words.push(value)
+                }
+                Some(&BatchOp::Read(addr)) if cmd == CMD_READ_BURST_INCR && addr == next => (),
+                _ => break,
+            }
+            count += 1;
+            idx += 1;
+        }
+        if cmd == CMD_READ_BURST_INCR {
+            reads += count;
+        }
+        request.extend_from_slice(&[cmd, count as u8]);
+        let mut word = [0; 4];
+        for &value in [base >> 2].iter().chain(&words) {
+            BigEndian::write_u32(&mut word, value);
+            request.extend_from_slice(&word);
+        }
+    }
+    (request, reads)
+}
+
+/// Send a request and read back `reads` words of answer
+fn exchange<P: Read + Write>(
+    port: &mut P,
+    request: &[u8],
+    reads: usize,
+) -> Result<Vec<u32>, BridgeError> {
+    port.write_all(request)?;
+    let mut answer = vec![0; reads * 4];
+    let mut got = 0;
+    while got < answer.len() {
+        match port.read(&mut answer[got..]) {
+            // A read that returns nothing means the port timed out
+            Ok(0) => {
+                log_bridge!(@Verbose, "serial: got {} of {} bytes", got, answer.len());
+                return Err(if got == 0 {
+                    BridgeError::NotConnected
+                } else {
+                    BridgeError::LengthError(answer.len(), got)
+                });
+            }
+            Ok(len) => got += len,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(answer.chunks(4).map(BigEndian::read_u32).collect())
+}
+
+/// Open the port raw at `baud`, with reads that give up after `TIMEOUT`
+#[cfg(unix)]
+fn open(path: &str, baud: u32) -> Result<File, BridgeError> {
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::OpenOptionsExt;
+    use std::os::unix::io::AsRawFd;
+
+    let speed = baud_constant(baud)?;
+    let port = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .custom_flags(libc::O_NOCTTY)
+        .open(path)?;
+    let fd = port.as_raw_fd();
+    unsafe {
+        let mut termios = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut termios) != 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        libc::cfmakeraw(&mut termios);
+        termios.c_cflag |= libc::CLOCAL | libc::CREAD;
+        termios.c_cflag &= !(libc::CSTOPB | libc::CRTSCTS);
+        termios.c_cc[libc::VMIN] = 0;
+        termios.c_cc[libc::VTIME] = (TIMEOUT.as_millis() / 100) as libc::cc_t;
+        if libc::cfsetispeed(&mut termios, speed) != 0
+            || libc::cfsetospeed(&mut termios, speed) != 0
+            || libc::tcsetattr(fd, libc::TCSANOW, &termios) != 0
+        {
+            return Err(io::Error::last_os_error().into());
+        }
+        // Drop anything left over from before we were here
+        libc::tcflush(fd, libc::TCIOFLUSH);
+    }
+    Ok(port)
+}
+
+#[cfg(not(unix))]
+fn open(_path: &str, _baud: u32) -> Result<File, BridgeError> {
+    Err(BridgeError::NotBuiltIn("serial"))
+}
+
+#[cfg(unix)]
+fn baud_constant(baud: u32) -> Result<libc::speed_t, BridgeError> {
+    Ok(match baud {
+        9600 => libc::B9600,
+        19200 => libc::B19200,
+        38400 => libc::B38400,
+        57600 => libc::B57600,
+        115_200 => libc::B115200,
+        230_400 => libc::B230400,
+        #[cfg(target_os = "linux")]
+        460_800 => libc::B460800,
+        #[cfg(target_os = "linux")]
+        921_600 => libc::B921600,
+        #[cfg(target_os = "linux")]
+        1_000_000 => libc::B1000000,
+        #[cfg(target_os = "linux")]
+        1_500_000 => libc::B1500000,
+        #[cfg(target_os = "linux")]
+        2_000_000 => libc::B2000000,
+        #[cfg(target_os = "linux")]
+        3_000_000 => libc::B3000000,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported baud rate {}", baud),
+            )
+            .into())
+        }
+    })
+}
+
+#[cfg(not(unix))]
+fn baud_constant(_baud: u32) -> Result<u32, BridgeError> {
+    Err(BridgeError::NotBuiltIn("serial"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{encode, exchange, MAX_FRAME_WORDS};
+    use crate::bridge::{BatchOp, BridgeError};
+    use std::io::{self, Read, Write};
+
+    /// A port that records what it's sent and answers from a canned buffer
+    struct FakePort {
+        sent: Vec<u8>,
+        answer: io::Cursor<Vec<u8>>,
+    }
+
+    impl Read for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            // Trickle the answer out, the way a slow line would
+            let len = buf.len().min(3);
+            self.answer.read(&mut buf[..len])
+        }
+    }
+
+    impl Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn port(answer: &[u8]) -> FakePort {
+        FakePort {
+            sent: vec![],
+            answer: io::Cursor::new(answer.to_vec()),
+        }
+    }
+
+    #[test]
+    fn frames() {
+        let (request, reads) = encode(&[BatchOp::Read(0xe000_0800)]);
+        assert_eq!(request, [0x02, 1, 0x38, 0x00, 0x02, 0x00]);
+        assert_eq!(reads, 1);
+
+        // Consecutive words share a frame, and a gap or a change of
+        // direction starts a new one
+        let (request, reads) = encode(&[
+            BatchOp::Write(0x10, 0x1122_3344),
+            BatchOp::Write(0x14, 0x5566_7788),
+            BatchOp::Write(0x20, 1),
+            BatchOp::Read(0x24),
+            BatchOp::Read(0x28),
+        ]);
+        assert_eq!(
+            request,
+            [
+                0x01, 2, 0, 0, 0, 0x04, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, //
+                0x01, 1, 0, 0, 0, 0x08, 0, 0, 0, 1, //
+                0x02, 2, 0, 0, 0, 0x09,
+            ]
+        );
+        assert_eq!(reads, 2);
+
+        assert_eq!(encode(&[]), (vec![], 0));
+    }
+
+    #[test]
+    fn long_runs_are_split() {
+        let ops: Vec<BatchOp> = (0..MAX_FRAME_WORDS as u32 + 2)
+            .map(|i| BatchOp::Read(0x100 + 4 * i))
+            .collect();
+        let (request, reads) = encode(&ops);
+        assert_eq!(reads, MAX_FRAME_WORDS + 2);
+        assert_eq!(request.len(), 12);
+        assert_eq!(request[..6], [0x02, 255, 0, 0, 0, 0x40]);
+        // The second frame picks up at word 0x40 + 255
+        assert_eq!(request[6..], [0x02, 2, 0, 0, 0x01, 0x3f]);
+    }
+
+    #[test]
+    fn answers() {
+        let (request, reads) = encode(&[BatchOp::Read(0), BatchOp::Read(4)]);
+        let mut fake = port(&[0xde, 0xad, 0xbe, 0xef, 0, 0, 0, 7]);
+        assert_eq!(
+            exchange(&mut fake, &request, reads).unwrap(),
+            [0xdead_beef, 7]
+        );
+        assert_eq!(fake.sent, request);
+
+        // Silence means nothing is there, and a short answer is a length error
+        match exchange(&mut port(&[]), &request, reads) {
+            Err(BridgeError::NotConnected) => (),
+            other => panic!("expected NotConnected, got {:?}", other),
+        }
+        match exchange(&mut port(&[1, 2, 3, 4, 5]), &request, reads) {
+            Err(BridgeError::LengthError(8, 5)) => (),
+            other => panic!("expected a length error, got {:?}", other),
+        }
+
+        // Writes aren't answered
+        let mut fake = port(&[]);
+        assert_eq!(exchange(&mut fake, &[0x01], 0).unwrap(), []);
+        assert_eq!(fake.sent, [0x01]);
+    }
+}
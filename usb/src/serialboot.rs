@@ -0,0 +1,142 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use super::bridge::{Bridge, BridgeError};
+use super::terminal::CrossoverUart;
+use super::xmodem::crc16;
+
+/// What the LiteX BIOS prints when it's ready for a serial upload, and the
+/// answer it expects before it starts listening for frames
+pub const MAGIC_REQ: &[u8] = b"sL5DdSMmkekro\n";
+const MAGIC_ACK: &[u8] = b"z6IHG7cYDID6o\n";
+
+/// Older BIOSes print a menu entry instead, and only want an ACK byte
+pub const PROMPT_REQ: &[u8] = b"F7:    boot from serial\n";
+const PROMPT_ACK: u8 = 0x06;
+
+/// Largest payload an SFL frame can carry.  Load frames spend four bytes
+/// of it on the address.
+const PAYLOAD_LENGTH: usize = 255;
+
+const CMD_LOAD: u8 = 0x01;
+const CMD_JUMP: u8 = 0x02;
+
+const ACK_SUCCESS: u8 = b'K';
+const ACK_CRCERROR: u8 = b'C';
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RETRIES: u32 = 10;
+
+#[derive(Debug)]
+pub enum SerialbootError {
+    /// The bridge failed while talking to the UART
+    BridgeError(BridgeError),
+
+    /// Couldn't read the image
+    IoError(io::Error),
+
+    /// The BIOS stopped answering
+    Timeout,
+
+    /// The BIOS kept reporting CRC errors
+    TooManyRetries,
+
+    /// The BIOS refused a frame, with this reply
+    Rejected(u8),
+}
+
+impl std::convert::From<BridgeError> for SerialbootError {
+    fn from(e: BridgeError) -> Self {
+        SerialbootError::BridgeError(e)
+    }
+}
+
+impl std::convert::From<io::Error> for SerialbootError {
+    fn from(e: io::Error) -> Self {
+        SerialbootError::IoError(e)
+    }
+}
+
+impl fmt::Display for SerialbootError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SerialbootError::BridgeError(e) => write!(f, "bridge error: {:?}", e),
+            SerialbootError::IoError(e) => write!(f, "{}", e),
+            SerialbootError::Timeout => write!(f, "the BIOS stopped responding"),
+            SerialbootError::TooManyRetries => write!(f, "too many CRC errors"),
+            SerialbootError::Rejected(reply) => {
+                write!(f, "the BIOS rejected a frame ({:?})", *reply as char)
+            }
+        }
+    }
+}
+
+/// Boots an image through the LiteX BIOS `serialboot` command, the same
+/// way `litex_term --kernel` does
+pub struct Serialboot {
+    path: String,
+    address: u32,
+}
+
+impl Serialboot {
+    pub fn new(path: &str, address: u32) -> Serialboot {
+        Serialboot {
+            path: path.to_owned(),
+            address,
+        }
+    }
+
+    /// Answer the old-style prompt, which is followed by the magic string
+    pub fn answer_prompt(&self, uart: &CrossoverUart, bridge: &Bridge) -> Result<(), BridgeError> {
+        uart.write(bridge, &[PROMPT_ACK])
+    }
+
+    /// Answer the magic string, upload the image and jump to it.  The image
+    /// is read afresh each time, so rebuilding it and resetting the board
+    /// boots the new one.
+    pub fn boot(&self, uart: &CrossoverUart, bridge: &Bridge) -> Result<(), SerialbootError> {
+        let image = std::fs::read(&self.path)?;
+        uart.write(bridge, MAGIC_ACK)?;
+        ui_info!(
+            "Uploading {} ({} bytes) to {:08x}",
+            self.path,
+            image.len(),
+            self.address
+        );
+        let chunk_size = PAYLOAD_LENGTH - 4;
+        for (idx, chunk) in image.chunks(chunk_size).enumerate() {
+            let addr = self.address + (idx * chunk_size) as u32;
+            let mut payload = addr.to_be_bytes().to_vec();
+            payload.extend_from_slice(chunk);
+            send_frame(uart, bridge, CMD_LOAD, &payload)?;
+        }
+        ui_info!("Booting from {:08x}", self.address);
+        send_frame(uart, bridge, CMD_JUMP, &self.address.to_be_bytes())
+    }
+}
+
+/// Send an SFL frame, resending it if the BIOS saw a CRC error
+fn send_frame(
+    uart: &CrossoverUart,
+    bridge: &Bridge,
+    cmd: u8,
+    payload: &[u8],
+) -> Result<(), SerialbootError> {
+    let mut body = vec![cmd];
+    body.extend_from_slice(payload);
+    let mut frame = vec![payload.len() as u8];
+    frame.extend_from_slice(&crc16(&body).to_be_bytes());
+    frame.extend_from_slice(&body);
+
+    for _ in 0..MAX_RETRIES {
+        uart.write(bridge, &frame)?;
+        match uart.read_timeout(bridge, REPLY_TIMEOUT)? {
+            Some(ACK_SUCCESS) => return Ok(()),
+            Some(ACK_CRCERROR) => (),
+            Some(other) => return Err(SerialbootError::Rejected(other)),
+            None => return Err(SerialbootError::Timeout),
+        }
+    }
+    Err(SerialbootError::TooManyRetries)
+}
@@ -0,0 +1,188 @@
+use std::fmt;
+use std::fs;
+use std::io;
+
+use super::utils::parse_u32;
+
+#[derive(Debug)]
+pub enum SessionError {
+    /// Couldn't read or write the file
+    IoError(io::Error),
+
+    /// A line didn't make sense
+    ParseError(usize /* line */, String),
+}
+
+impl std::convert::From<io::Error> for SessionError {
+    fn from(e: io::Error) -> Self {
+        SessionError::IoError(e)
+    }
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SessionError::IoError(e) => write!(f, "{}", e),
+            SessionError::ParseError(line, text) => write!(f, "line {}: {}", line, text),
+        }
+    }
+}
+
+/// A breakpoint or watchpoint, as GDB asked for it in a `Z` packet
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Breakpoint {
+    /// The `Z` type: 0 and 1 are breakpoints, 2 to 4 are watchpoints
+    pub kind: u32,
+    pub addr: u32,
+    pub len: u32,
+}
+
+/// The debugging context that should survive the adapter restarting.
+/// Sessions are saved as a short text file with one setting per line:
+///
+///   hart 0
+///   rle on
+///   escape off
+///   break 0 0x10000000 4
+///   catch 2
+#[derive(Clone, Debug)]
+pub struct Session {
+    /// Hart selected for register and memory accesses
+    pub hart: usize,
+    pub use_rle: bool,
+    pub use_escaping: bool,
+    pub breakpoints: Vec<Breakpoint>,
+
+    /// `mcause` values set with `monitor catch`
+    pub catch_causes: Vec<u32>,
+}
+
+impl Session {
+    pub fn load(path: &str) -> Result<Session, SessionError> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), SessionError> {
+        fs::write(path, self.to_string())?;
+        Ok(())
+    }
+
+    pub fn parse(text: &str) -> Result<Session, SessionError> {
+        let mut session = Session {
+            hart: 0,
+            use_rle: true,
+            use_escaping: true,
+            breakpoints: vec![],
+            catch_causes: vec![],
+        };
+        for (line_idx, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || SessionError::ParseError(line_idx + 1, line.to_owned());
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let number = |idx: usize| {
+                fields
+                    .get(idx)
+                    .and_then(|s| parse_u32(s).ok())
+                    .ok_or_else(error)
+            };
+            let on_off = |idx: usize| match fields.get(idx) {
+                Some(&"on") => Ok(true),
+                Some(&"off") => Ok(false),
+                _ => Err(error()),
+            };
+            match fields[0] {
+                "hart" => session.hart = number(1)? as usize,
+                "rle" => session.use_rle = on_off(1)?,
+                "escape" => session.use_escaping = on_off(1)?,
+                "break" => session.breakpoints.push(Breakpoint {
+                    kind: number(1)?,
+                    addr: number(2)?,
+                    len: number(3)?,
+                }),
+                "catch" => session.catch_causes.push(number(1)?),
+                _ => return Err(error()),
+            }
+        }
+        Ok(session)
+    }
+}
+
+impl fmt::Display for Session {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let on_off = |b| if b { "on" } else { "off" };
+        writeln!(f, "# litex-usb-wishbone-bridge session")?;
+        writeln!(f, "hart {}", self.hart)?;
+        writeln!(f, "rle {}", on_off(self.use_rle))?;
+        writeln!(f, "escape {}", on_off(self.use_escaping))?;
+        for bp in &self.breakpoints {
+            writeln!(f, "break {} 0x{:08x} {}", bp.kind, bp.addr, bp.len)?;
+        }
+        for cause in &self.catch_causes {
+            writeln!(f, "catch {}", cause)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Breakpoint, Session, SessionError};
+
+    #[test]
+    fn round_trip() {
+        let session = Session {
+            hart: 2,
+            use_rle: false,
+            use_escaping: true,
+            breakpoints: vec![
+                Breakpoint {
+                    kind: 0,
+                    addr: 0x4000_0100,
+                    len: 2,
+                },
+                Breakpoint {
+                    kind: 2,
+                    addr: 0xffff_fffc,
+                    len: 4,
+                },
+            ],
+            catch_causes: vec![2, 11],
+        };
+        let parsed = Session::parse(&session.to_string()).unwrap();
+        assert_eq!(parsed.hart, 2);
+        assert!(!parsed.use_rle);
+        assert!(parsed.use_escaping);
+        assert_eq!(parsed.breakpoints, session.breakpoints);
+        assert_eq!(parsed.catch_causes, session.catch_causes);
+    }
+
+    #[test]
+    fn comments_and_blank_lines() {
+        let session = Session::parse("# saved\n\n   \n  # indented\nhart 1\n\nrle off\n").unwrap();
+        assert_eq!(session.hart, 1);
+        assert!(!session.use_rle);
+        assert!(session.use_escaping);
+        assert!(session.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn bad_lines() {
+        let bad = [
+            ("hart 0\n\nrle maybe\n", 3, "rle maybe"),
+            ("# a\nbreak 0 0x40000000\n", 2, "break 0 0x40000000"),
+            ("catch two\n", 1, "catch two"),
+            ("hart 0\nwatch 0x40000000\n", 2, "watch 0x40000000"),
+        ];
+        for (text, line, contents) in bad {
+            match Session::parse(text) {
+                Err(SessionError::ParseError(l, c)) => {
+                    assert_eq!((l, c.as_str()), (line, contents), "{:?}", text)
+                }
+                other => panic!("{:?} parsed as {:?}", text, other),
+            }
+        }
+    }
+}
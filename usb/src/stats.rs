@@ -0,0 +1,230 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// Running statistics over a series of measured durations
+#[derive(Clone, Debug, Default)]
+pub struct LatencyStats {
+    count: u64,
+    total: Duration,
+    min: Option<Duration>,
+    max: Duration,
+}
+
+impl LatencyStats {
+    pub fn new() -> LatencyStats {
+        Default::default()
+    }
+
+    pub fn record(&mut self, d: Duration) {
+        self.count += 1;
+        self.total += d;
+        self.min = Some(match self.min {
+            Some(min) if min < d => min,
+            _ => d,
+        });
+        if d > self.max {
+            self.max = d;
+        }
+    }
+
+    pub fn average(&self) -> Duration {
+        if self.count == 0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(self.total.as_secs_f64() / self.count as f64)
+        }
+    }
+}
+
+fn ms(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+impl fmt::Display for LatencyStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.count == 0 {
+            return write!(f, "no samples");
+        }
+        write!(
+            f,
+            "{} samples, min {:.3} ms, avg {:.3} ms, max {:.3} ms",
+            self.count,
+            ms(self.min.unwrap_or_default()),
+            ms(self.average()),
+            ms(self.max)
+        )
+    }
+}
+
+/// What the adapter was waiting on.  Everything else is host time.
+#[derive(Clone, Copy)]
+pub enum Phase {
+    /// Transactions on the bridge, from the host to the bus and back
+    Bridge,
+
+    /// The target itself, such as a hart or agent that hasn't finished yet
+    Target,
+}
+
+thread_local! {
+    /// Time spent in each phase by this thread.  Each connection's
+    /// commands are handled on a thread of its own, so a command's share
+    /// is the difference across it, whatever other clients are doing.
+    static PHASE_TIME: Cell<[Duration; 2]> = const { Cell::new([Duration::ZERO; 2]) };
+}
+
+/// Count `d` towards `phase`, for whichever connection this thread serves
+pub fn charge(phase: Phase, d: Duration) {
+    PHASE_TIME.with(|time| {
+        let mut phases = time.get();
+        phases[phase as usize] += d;
+        time.set(phases);
+    });
+}
+
+/// Run `f`, counting the time it takes towards `phase`
+pub fn timed<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    charge(phase, start.elapsed());
+    result
+}
+
+/// Time this thread has spent in `phase` so far
+pub fn phase_time(phase: Phase) -> Duration {
+    PHASE_TIME.with(|time| time.get()[phase as usize])
+}
+
+/// Where the time handling one command went
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CommandTiming {
+    /// Turning the packet into a command
+    pub parse: Duration,
+
+    /// Waiting on the bridge
+    pub bridge: Duration,
+
+    /// Waiting on the target
+    pub target: Duration,
+
+    /// From the packet arriving to the reply going out
+    pub total: Duration,
+}
+
+impl CommandTiming {
+    /// Time spent in the adapter itself
+    pub fn host(&self) -> Duration {
+        self.total
+            .checked_sub(self.parse + self.bridge + self.target)
+            .unwrap_or_default()
+    }
+
+    fn add(&mut self, other: &CommandTiming) {
+        self.parse += other.parse;
+        self.bridge += other.bridge;
+        self.target += other.target;
+        self.total += other.total;
+    }
+}
+
+impl fmt::Display for CommandTiming {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:.3} ms: parse {:.3}, host {:.3}, bridge {:.3}, target {:.3}",
+            ms(self.total),
+            ms(self.parse),
+            ms(self.host()),
+            ms(self.bridge),
+            ms(self.target)
+        )
+    }
+}
+
+/// How long each kind of command has taken, by packet name
+#[derive(Default)]
+pub struct CommandTimings {
+    by_name: BTreeMap<String, (u64, CommandTiming)>,
+}
+
+impl CommandTimings {
+    pub fn record(&mut self, name: &str, timing: &CommandTiming) {
+        let entry = self.by_name.entry(name.to_owned()).or_default();
+        entry.0 += 1;
+        entry.1.add(timing);
+    }
+
+    pub fn clear(&mut self) {
+        self.by_name.clear();
+    }
+}
+
+/// A table of the commands that took the longest first, with how their
+/// time was split as percentages
+impl fmt::Display for CommandTimings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.by_name.is_empty() {
+            return writeln!(f, "No commands timed yet");
+        }
+        let mut rows: Vec<_> = self.by_name.iter().collect();
+        rows.sort_by_key(|(_, (_, timing))| std::cmp::Reverse(timing.total));
+        writeln!(
+            f,
+            "{:<16} {:>7} {:>10} {:>8}  {:>5} {:>5} {:>6} {:>6}",
+            "packet", "count", "total ms", "avg ms", "parse", "host", "bridge", "target"
+        )?;
+        let mut all = CommandTiming::default();
+        let mut count = 0;
+        for (name, (n, timing)) in rows {
+            write_row(f, name, *n, timing)?;
+            all.add(timing);
+            count += n;
+        }
+        write_row(f, "all", count, &all)
+    }
+}
+
+fn write_row(f: &mut fmt::Formatter, name: &str, count: u64, t: &CommandTiming) -> fmt::Result {
+    let share = |d: Duration| {
+        if t.total.as_nanos() == 0 {
+            0.0
+        } else {
+            d.as_secs_f64() * 100.0 / t.total.as_secs_f64()
+        }
+    };
+    writeln!(
+        f,
+        "{:<16} {:>7} {:>10.3} {:>8.3}  {:>4.0}% {:>4.0}% {:>5.0}% {:>5.0}%",
+        name,
+        count,
+        ms(t.total),
+        ms(t.total) / count.max(1) as f64,
+        share(t.parse),
+        share(t.host()),
+        share(t.bridge),
+        share(t.target)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{charge, phase_time, Phase};
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn connections_are_timed_apart() {
+        let before = phase_time(Phase::Bridge);
+        charge(Phase::Bridge, Duration::from_millis(3));
+        thread::spawn(|| {
+            charge(Phase::Bridge, Duration::from_secs(1));
+            assert_eq!(phase_time(Phase::Bridge), Duration::from_secs(1));
+        })
+        .join()
+        .unwrap();
+        assert_eq!(phase_time(Phase::Bridge) - before, Duration::from_millis(3));
+        assert_eq!(phase_time(Phase::Target), Duration::ZERO);
+    }
+}
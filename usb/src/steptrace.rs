@@ -0,0 +1,334 @@
+//! Traces of a hart single-stepped one instruction at a time, recorded with
+//! `monitor step-record` and compared with `--compare-steps`.  Recording
+//! the same stretch of code twice and comparing the runs shows the first
+//! instruction where they went different ways, which is where to look
+//! for a bug that only shows up some of the time.
+//!
+//! A trace starts with the magic, the hart number, and every register.
+//! Each step after that only holds what changed: how far pc is from the
+//! next instruction, a mask of the registers that changed, and each new
+//! value XORed with the old one, all as LEB128.  A step that only moves pc
+//! on and changes one register takes a few bytes rather than the 132 of a
+//! full set.
+
+use std::fmt;
+use std::fs;
+use std::io;
+#[cfg(feature = "server")]
+use std::io::Write;
+
+use super::riscv::ABI_NAMES;
+
+const MAGIC: &[u8; 8] = b"STEPTRC1";
+
+/// x0-x31 then pc
+pub const REGISTER_COUNT: usize = 33;
+const PC: usize = 32;
+
+pub type Registers = [u32; REGISTER_COUNT];
+
+#[derive(Debug)]
+pub enum StepTraceError {
+    IoError(io::Error),
+
+    /// The file isn't a trace, or stops partway through a step
+    BadTrace(String),
+}
+
+impl std::convert::From<io::Error> for StepTraceError {
+    fn from(e: io::Error) -> StepTraceError {
+        StepTraceError::IoError(e)
+    }
+}
+
+impl fmt::Display for StepTraceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StepTraceError::IoError(e) => write!(f, "{}", e),
+            StepTraceError::BadTrace(why) => write!(f, "not a step trace: {}", why),
+        }
+    }
+}
+
+/// Writes a trace one step at a time, for `monitor step-record`
+#[cfg(feature = "server")]
+pub struct TraceWriter<W: Write> {
+    out: W,
+    last: Registers,
+}
+
+#[cfg(feature = "server")]
+impl<W: Write> TraceWriter<W> {
+    /// Start a trace of `hart` from where its registers are now
+    pub fn new(mut out: W, hart: u8, registers: &Registers) -> io::Result<TraceWriter<W>> {
+        out.write_all(MAGIC)?;
+        out.write_all(&[hart])?;
+        for value in registers {
+            out.write_all(&value.to_le_bytes())?;
+        }
+        Ok(TraceWriter {
+            out,
+            last: *registers,
+        })
+    }
+
+    /// Add a step, given the registers after it
+    pub fn step(&mut self, registers: &Registers) -> io::Result<()> {
+        let mut record = vec![];
+        let skip = registers[PC].wrapping_sub(self.last[PC].wrapping_add(4)) as i32;
+        put_leb128(&mut record, zigzag(skip));
+        let changed = (1..PC)
+            .filter(|&reg| registers[reg] != self.last[reg])
+            .fold(0u32, |mask, reg| mask | 1 << reg);
+        put_leb128(&mut record, changed);
+        for reg in (1..PC).filter(|r| changed & (1 << r) != 0) {
+            put_leb128(&mut record, registers[reg] ^ self.last[reg]);
+        }
+        self.last = *registers;
+        self.out.write_all(&record)
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// A recorded trace: the hart, and the registers before the first step and
+/// after every one
+pub struct Trace {
+    pub hart: u8,
+    pub states: Vec<Registers>,
+}
+
+impl Trace {
+    pub fn load(path: &str) -> Result<Trace, StepTraceError> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Trace, StepTraceError> {
+        let bad = |why: &str| StepTraceError::BadTrace(why.to_owned());
+        let header = MAGIC.len() + 1 + REGISTER_COUNT * 4;
+        if data.len() < header || &data[..MAGIC.len()] != MAGIC {
+            return Err(bad("no header"));
+        }
+        let hart = data[MAGIC.len()];
+        let mut state = [0; REGISTER_COUNT];
+        for (reg, bytes) in data[MAGIC.len() + 1..header].chunks_exact(4).enumerate() {
+            state[reg] = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        let mut states = vec![state];
+        let mut rest = &data[header..];
+        let truncated = || bad("the last step is cut short");
+        while !rest.is_empty() {
+            let skip = unzigzag(take_leb128(&mut rest).ok_or_else(truncated)?);
+            state[PC] = state[PC].wrapping_add(4).wrapping_add(skip as u32);
+            let changed = take_leb128(&mut rest).ok_or_else(truncated)?;
+            for reg in (1..PC).filter(|r| changed & (1 << r) != 0) {
+                state[reg] ^= take_leb128(&mut rest).ok_or_else(truncated)?;
+            }
+            states.push(state);
+        }
+        Ok(Trace { hart, states })
+    }
+
+    /// Number of steps recorded
+    pub fn steps(&self) -> usize {
+        self.states.len() - 1
+    }
+}
+
+/// Where two runs stopped agreeing
+#[derive(Debug, PartialEq)]
+pub enum Comparison {
+    /// Both runs went the same way for as long as the shorter one
+    Same {
+        steps: usize,
+        old: usize,
+        new: usize,
+    },
+
+    /// After `step` steps, these registers held different values, as
+    /// (register, old, new).  Step 0 is where the runs started.
+    Diverged {
+        step: usize,
+        differences: Vec<(usize, u32, u32)>,
+    },
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Comparison::Same { steps, old, new } => {
+                write!(f, "The runs match for {} steps", steps)?;
+                if old != new {
+                    write!(f, ", after which only the {} one goes on", {
+                        if old > new {
+                            "old"
+                        } else {
+                            "new"
+                        }
+                    })?;
+                }
+                Ok(())
+            }
+            Comparison::Diverged { step, differences } => {
+                if *step == 0 {
+                    write!(f, "The runs start from different registers:")?;
+                } else {
+                    write!(f, "The runs diverge at step {}:", step)?;
+                }
+                for (reg, old, new) in differences {
+                    write!(
+                        f,
+                        "\n  {:<4} {:08x} in the old run, {:08x} in the new",
+                        register_name(*reg),
+                        old,
+                        new
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn register_name(reg: usize) -> &'static str {
+    if reg == PC {
+        "pc"
+    } else {
+        ABI_NAMES[reg]
+    }
+}
+
+/// Find the first step after which the two runs' registers differ
+pub fn compare(old: &Trace, new: &Trace) -> Comparison {
+    for (step, (a, b)) in old.states.iter().zip(&new.states).enumerate() {
+        let differences: Vec<(usize, u32, u32)> = (0..REGISTER_COUNT)
+            .filter(|&reg| a[reg] != b[reg])
+            .map(|reg| (reg, a[reg], b[reg]))
+            .collect();
+        if !differences.is_empty() {
+            return Comparison::Diverged { step, differences };
+        }
+    }
+    Comparison::Same {
+        steps: old.steps().min(new.steps()),
+        old: old.steps(),
+        new: new.steps(),
+    }
+}
+
+/// Map small negative numbers to small positive ones, so pc jumping back
+/// a little takes as few bytes as jumping forward
+#[cfg(feature = "server")]
+fn zigzag(value: i32) -> u32 {
+    ((value << 1) ^ (value >> 31)) as u32
+}
+
+fn unzigzag(value: u32) -> i32 {
+    ((value >> 1) as i32) ^ -((value & 1) as i32)
+}
+
+#[cfg(feature = "server")]
+fn put_leb128(out: &mut Vec<u8>, mut value: u32) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn take_leb128(data: &mut &[u8]) -> Option<u32> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let (&byte, rest) = data.split_first()?;
+        *data = rest;
+        value |= ((byte & 0x7f) as u32).checked_shl(shift)?;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+#[cfg(all(test, feature = "server"))]
+mod test {
+    use super::{compare, unzigzag, zigzag, Comparison, Registers, Trace, TraceWriter, PC};
+
+    fn record(states: &[Registers]) -> Vec<u8> {
+        let mut writer = TraceWriter::new(vec![], 1, &states[0]).unwrap();
+        for state in &states[1..] {
+            writer.step(state).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn run(len: usize, change_at: Option<usize>) -> Vec<Registers> {
+        let mut state: Registers = [0; 33];
+        state[PC] = 0x4000_0000;
+        let mut states = vec![state];
+        for step in 1..=len {
+            state[PC] = state[PC].wrapping_add(if step % 5 == 0 { -16i32 as u32 } else { 4 });
+            state[10] += step as u32 * 3;
+            if change_at == Some(step) {
+                state[11] = 0xdead_beef;
+            }
+            states.push(state);
+        }
+        states
+    }
+
+    #[test]
+    fn round_trip() {
+        let states = run(20, Some(7));
+        let data = record(&states);
+        // The 141-byte header, then a few bytes a step rather than 132
+        assert!(data.len() < 141 + 20 * 5, "{} bytes", data.len());
+        let trace = Trace::parse(&data).unwrap();
+        assert_eq!(trace.hart, 1);
+        assert_eq!(trace.steps(), 20);
+        assert_eq!(trace.states, states);
+    }
+
+    #[test]
+    fn truncated_traces() {
+        let data = record(&run(3, None));
+        assert!(Trace::parse(&data[..data.len() - 1]).is_err());
+        assert!(Trace::parse(&data[..20]).is_err());
+        assert!(Trace::parse(b"not a trace").is_err());
+    }
+
+    #[test]
+    fn first_divergence() {
+        let parse = |states: &[Registers]| Trace::parse(&record(states)).unwrap();
+        let old = parse(&run(20, None));
+        assert_eq!(
+            compare(&old, &parse(&run(12, None))),
+            Comparison::Same {
+                steps: 12,
+                old: 20,
+                new: 12
+            }
+        );
+        assert_eq!(
+            compare(&old, &parse(&run(20, Some(9)))),
+            Comparison::Diverged {
+                step: 9,
+                differences: vec![(11, 0, 0xdead_beef)]
+            }
+        );
+    }
+
+    #[test]
+    fn zigzag_round_trip() {
+        for value in &[0, 1, -1, -16, 0x7fff_ffff, i32::MIN] {
+            assert_eq!(unzigzag(zigzag(*value)), *value);
+        }
+        assert_eq!(zigzag(-1), 1);
+    }
+}
@@ -0,0 +1,227 @@
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use super::bridge::BridgeError;
+use super::events::{self, TargetEvent};
+
+/// How long a device gets to come back once the host has woken up.  It
+/// has to be enumerated again, which can take a while on a busy hub.
+pub const RESUME_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Less sleep than this is clock noise rather than a suspend
+const SLEEP_THRESHOLD: Duration = Duration::from_millis(100);
+
+/// Notices the host going to sleep between one USB request and the next.
+/// A laptop suspending takes the bus down with it, so the first request
+/// after it wakes up fails even though nothing is wrong with the device.
+/// Telling that apart from a real failure means the request can be tried
+/// again once the device is back, rather than ending the session.
+pub struct SleepDetector {
+    /// Time spent asleep as of the last request that worked
+    asleep: Mutex<Duration>,
+
+    /// Where the time spent asleep comes from
+    clock: fn() -> Duration,
+}
+
+impl Default for SleepDetector {
+    fn default() -> Self {
+        SleepDetector::with_clock(time_asleep)
+    }
+}
+
+impl SleepDetector {
+    fn with_clock(clock: fn() -> Duration) -> SleepDetector {
+        SleepDetector {
+            asleep: Mutex::new(clock()),
+            clock,
+        }
+    }
+
+    /// Run `request`.  If it fails and the host slept since the last one
+    /// that worked, wait up to `RESUME_TIMEOUT` for `reopen` to find the
+    /// device again and then run it once more.  A device that doesn't come
+    /// back is `Suspended`.  `request` sets the flag it's given once any
+    /// of its writes has gone through, and then it isn't run again, since
+    /// that would make those writes twice.
+    pub fn request<T>(
+        &self,
+        mut request: impl FnMut(&mut bool) -> Result<T, BridgeError>,
+        reopen: impl Fn(Instant) -> Result<(), BridgeError>,
+    ) -> Result<T, BridgeError> {
+        let mut landed = false;
+        let e = match request(&mut landed) {
+            Ok(v) => {
+                self.awake();
+                return Ok(v);
+            }
+            Err(e) => e,
+        };
+        let slept = {
+            let asleep = self.asleep.lock().unwrap();
+            (self.clock)().saturating_sub(*asleep)
+        };
+        if slept < SLEEP_THRESHOLD {
+            return Err(e);
+        }
+        ui_info!(
+            "The host slept for {:.1}s, waiting for the USB device to come back",
+            slept.as_secs_f32()
+        );
+        log_adapter!("First request after waking up failed: {:?}", e);
+        if reopen(Instant::now() + RESUME_TIMEOUT).is_err() {
+            return Err(BridgeError::Suspended);
+        }
+        ui_info!("USB device is back");
+        events::emit(TargetEvent::UsbReconnect);
+        if landed {
+            log_adapter!("Part of the request went through, so it isn't repeated");
+            return Err(e);
+        }
+        let result = request(&mut landed);
+        if result.is_ok() {
+            self.awake();
+        }
+        result
+    }
+
+    fn awake(&self) {
+        *self.asleep.lock().unwrap() = (self.clock)();
+    }
+}
+
+/// How long the host has spent suspended since it booted.  The monotonic
+/// clock stops while the system sleeps and the boot-time clock doesn't.
+#[cfg(target_os = "linux")]
+fn time_asleep() -> Duration {
+    let read = |clock| {
+        let mut ts: libc::timespec = unsafe { std::mem::zeroed() };
+        unsafe { libc::clock_gettime(clock, &mut ts) };
+        Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+    };
+    read(libc::CLOCK_BOOTTIME).saturating_sub(read(libc::CLOCK_MONOTONIC))
+}
+
+/// Without a clock that stops during sleep, a suspend can't be told
+/// apart from any other failure
+#[cfg(not(target_os = "linux"))]
+fn time_asleep() -> Duration {
+    Duration::from_secs(0)
+}
+
+/// Stop the kernel from suspending the device at `bus`/`address` when it's
+/// idle.  Whether that worked is only logged, since the device works
+/// either way.  The setting belongs to this
+/// instance of the device, so it has to be made again each time the
+/// device is enumerated.
+pub fn disable_autosuspend(bus: u32, address: u32) {
+    match keep_awake(bus, address) {
+        Ok(()) => log_adapter!("Autosuspend is off for {:03}/{:03}", bus, address),
+        Err(e) => ui_error!(
+            "Couldn't turn off autosuspend for {:03}/{:03}: {} (this needs root, or a udev rule setting ATTR{{power/control}}=\"on\")",
+            bus,
+            address,
+            e
+        ),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn keep_awake(bus: u32, address: u32) -> io::Result<()> {
+    use std::fs;
+
+    for entry in fs::read_dir("/sys/bus/usb/devices")? {
+        let path = entry?.path();
+        let read = |name: &str| {
+            fs::read_to_string(path.join(name))
+                .ok()
+                .and_then(|s| s.trim().parse::<u32>().ok())
+        };
+        if read("busnum") == Some(bus) && read("devnum") == Some(address) {
+            return fs::write(path.join("power/control"), "on");
+        }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, "not in sysfs"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn keep_awake(_bus: u32, _address: u32) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "autosuspend can only be turned off on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::SleepDetector;
+    use crate::bridge::BridgeError;
+    use std::cell::Cell;
+    use std::time::Duration;
+
+    thread_local! {
+        /// Seconds the host has slept, as far as the detector can tell
+        static ASLEEP: Cell<u64> = const { Cell::new(0) };
+    }
+
+    fn clock() -> Duration {
+        Duration::from_secs(ASLEEP.get())
+    }
+
+    fn sleep() {
+        ASLEEP.set(ASLEEP.get() + 5);
+    }
+
+    /// Run a request that fails the first time, after the host has slept
+    /// or not, and that says some of it went through if `partial`.
+    /// Returns the result and how many times it ran.
+    fn after_failure(slept: bool, partial: bool) -> (Result<u32, BridgeError>, usize) {
+        let detector = SleepDetector::with_clock(clock);
+        if slept {
+            sleep();
+        }
+        let runs = Cell::new(0);
+        let result = detector.request(
+            |landed| {
+                runs.set(runs.get() + 1);
+                if runs.get() > 1 {
+                    return Ok(7);
+                }
+                *landed = partial;
+                Err(BridgeError::NotConnected)
+            },
+            |_| Ok(()),
+        );
+        (result, runs.get())
+    }
+
+    #[test]
+    fn retried_only_after_sleeping() {
+        let (result, runs) = after_failure(false, false);
+        assert!(matches!(result, Err(BridgeError::NotConnected)));
+        assert_eq!(runs, 1);
+
+        let (result, runs) = after_failure(true, false);
+        assert_eq!(result.unwrap(), 7);
+        assert_eq!(runs, 2);
+    }
+
+    #[test]
+    fn writes_that_landed_are_not_repeated() {
+        let (result, runs) = after_failure(true, true);
+        assert!(matches!(result, Err(BridgeError::NotConnected)));
+        assert_eq!(runs, 1);
+    }
+
+    #[test]
+    fn a_device_that_stays_away_is_suspended() {
+        let detector = SleepDetector::with_clock(clock);
+        sleep();
+        let result: Result<(), _> = detector.request(
+            |_| Err(BridgeError::NotConnected),
+            |_| Err(BridgeError::NotConnected),
+        );
+        assert!(matches!(result, Err(BridgeError::Suspended)));
+    }
+}
@@ -0,0 +1,117 @@
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+
+/// Whether the target is running, as last seen by any service
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunState {
+    /// Nothing has halted or resumed it since the adapter started
+    Unknown,
+
+    Running,
+
+    Halted,
+}
+
+/// What the adapter knows about the target as a whole.  Every service that
+/// drives or watches the target shares one of these, so that a halt seen by
+/// one of them is seen by all of them.
+pub struct TargetState {
+    /// Signal reported for the most recent stop
+    pub last_signal: u8,
+
+    /// Whether the program is still alive, as opposed to having exited
+    pub is_alive: bool,
+
+    pub run_state: RunState,
+
+    /// Goes up whenever the target may have changed, so anything worked
+    /// out from how it was can tell that it's out of date
+    pub generation: u64,
+}
+
+pub type SharedTargetState = Arc<RwLock<TargetState>>;
+
+impl TargetState {
+    pub fn new_shared() -> SharedTargetState {
+        Arc::new(RwLock::new(TargetState {
+            last_signal: 0,
+            is_alive: true,
+            run_state: RunState::Unknown,
+            generation: 0,
+        }))
+    }
+
+    /// Note that the target may not be how it was
+    pub fn changed(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+    }
+}
+
+/// What a GDB client is allowed to do to the target
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Role {
+    /// Runs the target and changes it.  There's only ever one.
+    Controller,
+
+    /// Looks at memory and registers while the controller debugs, and
+    /// does nothing else
+    Observer,
+}
+
+/// The GDB clients connected at once.  The first one in is the controller,
+/// and anyone who connects while there is one is an observer.  Clients
+/// take turns with the target a packet at a time, so the debug unit never
+/// sees two of them interleaved.
+#[derive(Default)]
+pub struct Clients {
+    has_controller: Mutex<bool>,
+    turn: Mutex<()>,
+}
+
+pub type SharedClients = Arc<Clients>;
+
+impl Clients {
+    pub fn new_shared() -> SharedClients {
+        Arc::new(Clients::default())
+    }
+
+    /// Take a place as the controller if it's free, or as an observer
+    pub fn join(&self) -> Role {
+        let mut has_controller = self.has_controller.lock().unwrap();
+        if *has_controller {
+            Role::Observer
+        } else {
+            *has_controller = true;
+            Role::Controller
+        }
+    }
+
+    /// Give up a place, so the next client to join can take control
+    pub fn leave(&self, role: Role) {
+        if role == Role::Controller {
+            *self.has_controller.lock().unwrap() = false;
+        }
+    }
+
+    /// Wait until nobody else is using the target, and keep them off it
+    /// until the guard is dropped
+    pub fn take_turn(&self) -> MutexGuard<'_, ()> {
+        // A client that panicked mid-packet leaves nothing to clean up
+        self.turn.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_controller_at_a_time() {
+        let clients = Clients::default();
+        assert_eq!(clients.join(), Role::Controller);
+        assert_eq!(clients.join(), Role::Observer);
+        clients.leave(Role::Observer);
+        assert_eq!(clients.join(), Role::Observer);
+        clients.leave(Role::Controller);
+        assert_eq!(clients.join(), Role::Controller);
+    }
+}
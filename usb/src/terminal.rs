@@ -0,0 +1,327 @@
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::bridge::{Bridge, BridgeError};
+use super::config::Config;
+use super::csr_map::{CsrMap, CsrMapError};
+use super::logging::{self, LogChannel};
+use super::serialboot::{self, Serialboot, SerialbootError};
+use super::xmodem::{self, Protocol, TransferError};
+
+#[derive(Debug)]
+pub enum TerminalError {
+    /// Couldn't talk to the console
+    IoError(io::Error),
+
+    /// The bridge failed while talking to the UART
+    BridgeError(BridgeError),
+
+    /// The csr.csv couldn't be loaded
+    CsrMapError(CsrMapError),
+
+    /// The terminal needs a csr.csv to find the UART
+    NoCsrMap,
+
+    /// The gateware has no crossover UART
+    MissingRegister(&'static str),
+}
+
+impl std::convert::From<io::Error> for TerminalError {
+    fn from(e: io::Error) -> Self {
+        TerminalError::IoError(e)
+    }
+}
+
+impl std::convert::From<BridgeError> for TerminalError {
+    fn from(e: BridgeError) -> Self {
+        TerminalError::BridgeError(e)
+    }
+}
+
+impl std::convert::From<CsrMapError> for TerminalError {
+    fn from(e: CsrMapError) -> Self {
+        TerminalError::CsrMapError(e)
+    }
+}
+
+impl fmt::Display for TerminalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TerminalError::IoError(e) => write!(f, "{}", e),
+            TerminalError::BridgeError(e) => write!(f, "bridge error: {:?}", e),
+            TerminalError::CsrMapError(e) => write!(f, "couldn't load csr.csv: {}", e),
+            TerminalError::NoCsrMap => write!(f, "the terminal needs --csr-csv to find the UART"),
+            TerminalError::MissingRegister(name) => {
+                write!(f, "csr.csv has no {} register", name)
+            }
+        }
+    }
+}
+
+/// The host side of a LiteX crossover UART.  Whatever the CPU writes to its
+/// UART shows up here, and whatever is written here arrives at the CPU.
+pub struct CrossoverUart {
+    rxtx: u32,
+    txfull: u32,
+    rxempty: u32,
+}
+
+impl CrossoverUart {
+    pub fn new(map: &CsrMap) -> Result<CrossoverUart, TerminalError> {
+        let register = |name: &'static str| {
+            map.registers
+                .get(name)
+                .map(|r| r.addr)
+                .ok_or(TerminalError::MissingRegister(name))
+        };
+        Ok(CrossoverUart {
+            rxtx: register("uart_xover_rxtx")?,
+            txfull: register("uart_xover_txfull")?,
+            rxempty: register("uart_xover_rxempty")?,
+        })
+    }
+
+    /// Take one byte from the CPU, if it has sent one.  Reading `rxtx`
+    /// pops the FIFO.
+    pub fn try_read(&self, bridge: &Bridge) -> Result<Option<u8>, BridgeError> {
+        if bridge.peek(self.rxempty)? & 1 != 0 {
+            return Ok(None);
+        }
+        Ok(Some(bridge.peek(self.rxtx)? as u8))
+    }
+
+    /// Wait up to `timeout` for a byte from the CPU
+    pub fn read_timeout(
+        &self,
+        bridge: &Bridge,
+        timeout: Duration,
+    ) -> Result<Option<u8>, BridgeError> {
+        let start = Instant::now();
+        loop {
+            if let Some(byte) = self.try_read(bridge)? {
+                return Ok(Some(byte));
+            }
+            if start.elapsed() > timeout {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Send bytes to the CPU, waiting for room in the FIFO
+    pub fn write(&self, bridge: &Bridge, data: &[u8]) -> Result<(), BridgeError> {
+        for &byte in data {
+            while bridge.peek(self.txfull)? & 1 != 0 {}
+            bridge.poke(self.rxtx, byte as u32)?;
+        }
+        Ok(())
+    }
+}
+
+/// An interactive console on the crossover UART.  Lines typed on stdin are
+/// sent to the target, except for these, which start a file transfer:
+///
+///   ~xmodem-send <file>      ~xmodem-receive <file>
+///   ~ymodem-send <file>      ~ymodem-receive [file]
+///
+/// Given a `--kernel`, it also boots it whenever the LiteX BIOS offers a
+/// serial boot.
+pub struct Terminal {
+    uart: CrossoverUart,
+
+    serialboot: Option<Serialboot>,
+
+    /// The last few bytes from the target, to spot the BIOS asking for an
+    /// image
+    recent: Vec<u8>,
+
+    /// Target output that hasn't made a full line yet, for the log
+    line: Vec<u8>,
+
+    /// How long to sleep when neither side has anything to say
+    idle_delay: Duration,
+}
+
+impl Terminal {
+    pub fn new(cfg: &Config) -> Result<Terminal, TerminalError> {
+        let map = match &cfg.csr_csv {
+            Some(path) => CsrMap::load(path)?,
+            None => return Err(TerminalError::NoCsrMap),
+        };
+        Ok(Terminal {
+            uart: CrossoverUart::new(&map)?,
+            serialboot: cfg
+                .kernel
+                .as_ref()
+                .map(|path| Serialboot::new(path, cfg.kernel_address)),
+            recent: vec![],
+            line: vec![],
+            idle_delay: cfg.tuning.poll_interval,
+        })
+    }
+
+    pub fn run(&mut self, bridge: &Bridge) -> Result<(), TerminalError> {
+        ui_info!("Terminal on the crossover UART.  Type ~help for file transfers.");
+        let input = spawn_stdin_reader();
+        loop {
+            let mut idle = true;
+            while let Some(byte) = self.uart.try_read(bridge)? {
+                self.show(&[byte])?;
+                self.watch(bridge, byte)?;
+                idle = false;
+            }
+            match input.try_recv() {
+                Ok(line) => {
+                    idle = false;
+                    if line.starts_with('~') {
+                        self.escape(bridge, line.trim())?;
+                    } else {
+                        self.uart.write(bridge, line.as_bytes())?;
+                    }
+                }
+                Err(TryRecvError::Empty) => (),
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+            if idle {
+                thread::sleep(self.idle_delay);
+            }
+        }
+    }
+
+    /// Print target output as-is, and log it a line at a time
+    fn show(&mut self, data: &[u8]) -> io::Result<()> {
+        let stdout = io::stdout();
+        let mut out = stdout.lock();
+        out.write_all(data)?;
+        out.flush()?;
+        if !logging::has_file(LogChannel::Terminal) {
+            return Ok(());
+        }
+        for &byte in data {
+            match byte {
+                b'\n' => {
+                    log_terminal!("{}", String::from_utf8_lossy(&self.line));
+                    self.line.clear();
+                }
+                b'\r' => (),
+                b => self.line.push(b),
+            }
+        }
+        Ok(())
+    }
+
+    /// Look out for the BIOS offering to boot over serial
+    fn watch(&mut self, bridge: &Bridge, byte: u8) -> Result<(), TerminalError> {
+        let serialboot = match &self.serialboot {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        self.recent.push(byte);
+        let keep = serialboot::MAGIC_REQ
+            .len()
+            .max(serialboot::PROMPT_REQ.len());
+        if self.recent.len() > keep {
+            self.recent.remove(0);
+        }
+
+        if self.recent.ends_with(serialboot::PROMPT_REQ) {
+            self.recent.clear();
+            serialboot.answer_prompt(&self.uart, bridge)?;
+        } else if self.recent.ends_with(serialboot::MAGIC_REQ) {
+            self.recent.clear();
+            match serialboot.boot(&self.uart, bridge) {
+                Ok(()) => (),
+                Err(SerialbootError::BridgeError(e)) => return Err(e.into()),
+                Err(e) => ui_error!("Serial boot failed: {}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Handle a `~` command typed at the terminal
+    fn escape(&mut self, bridge: &Bridge, line: &str) -> Result<(), TerminalError> {
+        let args: Vec<&str> = line.trim_start_matches('~').split_whitespace().collect();
+        let result = match args.as_slice() {
+            ["xmodem-send", path] => self.send(bridge, Protocol::Xmodem, path),
+            ["ymodem-send", path] => self.send(bridge, Protocol::Ymodem, path),
+            ["xmodem-receive", path] => self.receive(bridge, Protocol::Xmodem, Some(path)),
+            ["ymodem-receive", path] => self.receive(bridge, Protocol::Ymodem, Some(path)),
+            ["ymodem-receive"] => self.receive(bridge, Protocol::Ymodem, None),
+            _ => {
+                ui_result!("~xmodem-send <file>     send a file with XMODEM-CRC");
+                ui_result!("~ymodem-send <file>     send a file with YMODEM");
+                ui_result!("~xmodem-receive <file>  receive a file with XMODEM-CRC");
+                ui_result!("~ymodem-receive [file]  receive a file with YMODEM");
+                return Ok(());
+            }
+        };
+        match result {
+            Ok(()) => Ok(()),
+            Err(TransferError::BridgeError(e)) => Err(e.into()),
+            Err(e) => {
+                ui_error!("Transfer failed: {}", e);
+                Ok(())
+            }
+        }
+    }
+
+    fn send(
+        &mut self,
+        bridge: &Bridge,
+        protocol: Protocol,
+        path: &str,
+    ) -> Result<(), TransferError> {
+        let data = std::fs::read(path)?;
+        let name = std::path::Path::new(path)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        ui_info!(
+            "Sending {} ({} bytes), waiting for the receiver",
+            path,
+            data.len()
+        );
+        xmodem::send(&self.uart, bridge, protocol, &name, &data)?;
+        ui_info!("Sent {}", path);
+        Ok(())
+    }
+
+    fn receive(
+        &mut self,
+        bridge: &Bridge,
+        protocol: Protocol,
+        path: Option<&str>,
+    ) -> Result<(), TransferError> {
+        ui_info!("Waiting for the sender");
+        let (name, data) = xmodem::receive(&self.uart, bridge, protocol)?;
+        let path = match (path, name) {
+            (Some(path), _) => path.to_owned(),
+            (None, Some(name)) => name,
+            (None, None) => "received.bin".to_owned(),
+        };
+        std::fs::write(&path, &data)?;
+        ui_info!("Received {} bytes into {}", data.len(), path);
+        Ok(())
+    }
+}
+
+/// Read stdin a line at a time on another thread, so the terminal can keep
+/// polling the UART while waiting for the user
+fn spawn_stdin_reader() -> Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let stdin = io::stdin();
+        for line in stdin.lock().lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => break,
+            };
+            if tx.send(format!("{}\n", line)).is_err() {
+                break;
+            }
+        }
+    });
+    rx
+}
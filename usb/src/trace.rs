@@ -0,0 +1,91 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use super::config::Config;
+
+/// A bus transaction as the adapter saw it complete
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read(u32 /* address */, u32 /* value */),
+    Write(u32 /* address */, u32 /* value */),
+
+    /// An access that failed, and whether it was a write
+    Error(u32, bool),
+}
+
+/// Bridge transactions written out as a VCD waveform, with the bus
+/// signals they stand for: address, write data, read data, write enable,
+/// cycle and error.  Each transaction is a pulse on `cyc`, so it can be
+/// lined up against a logic analyzer capture of the real bus in PulseView
+/// or GTKWave.  Times are in microseconds from when the trace started,
+/// which is given as a Unix time in the header.
+struct VcdTrace {
+    file: File,
+    start: Instant,
+
+    /// Time of the last change written, so that transactions that finish
+    /// in the same microsecond, such as those in a batch, still get a
+    /// pulse each
+    last: u64,
+}
+
+static TRACE: Mutex<Option<VcdTrace>> = Mutex::new(None);
+
+/// Start tracing if the config asks for it
+pub fn init(cfg: &Config) -> io::Result<()> {
+    let path = match cfg.trace_vcd {
+        Some(ref path) => path,
+        None => return Ok(()),
+    };
+    let mut file = File::create(path)?;
+    let started = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.0);
+    write!(
+        file,
+        "$date unix {:.6} $end\n\
+         $version {} {} $end\n\
+         $timescale 1 us $end\n\
+         $scope module wishbone $end\n\
+         $var wire 32 a adr $end\n\
+         $var wire 32 d dat_w $end\n\
+         $var wire 32 r dat_r $end\n\
+         $var wire 1 w we $end\n\
+         $var wire 1 c cyc $end\n\
+         $var wire 1 e err $end\n\
+         $upscope $end\n\
+         $enddefinitions $end\n\
+         #0\n\
+         $dumpvars\nb0 a\nb0 d\nb0 r\n0w\n0c\n0e\n$end\n",
+        started,
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    )?;
+    *TRACE.lock().unwrap() = Some(VcdTrace {
+        file,
+        start: Instant::now(),
+        last: 0,
+    });
+    Ok(())
+}
+
+/// Add a transaction to the trace, if there is one.  Like logging, a
+/// trace that can't be written is never allowed to fail the access.
+pub fn record(access: Access) {
+    let mut trace = TRACE.lock().unwrap();
+    let trace = match *trace {
+        Some(ref mut t) => t,
+        None => return,
+    };
+    let now = (trace.start.elapsed().as_micros() as u64).max(trace.last + 2);
+    trace.last = now + 1;
+    let changes = match access {
+        Access::Read(addr, value) => format!("b{:b} a\nb{:b} r\n0w\n0e\n", addr, value),
+        Access::Write(addr, value) => format!("b{:b} a\nb{:b} d\n1w\n0e\n", addr, value),
+        Access::Error(addr, write) => format!("b{:b} a\n{}w\n1e\n", addr, write as u8),
+    };
+    let _ = write!(trace.file, "#{}\n{}1c\n#{}\n0c\n", now, changes, now + 1);
+}
@@ -0,0 +1,510 @@
+#[cfg(unix)]
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::config::Config;
+
+/// Where GDB connects from.  The protocol engine doesn't care which.
+pub enum GdbListener {
+    /// GDB connects over the network, one connection at a time, with
+    /// Nagle's algorithm off if the profile asks for it
+    Tcp(TcpListener, bool),
+
+    /// GDB opens a pseudo-terminal as if it were a serial port
+    #[cfg(unix)]
+    Pty(Pty),
+}
+
+/// Replies are held until there's this much waiting, or until they're
+/// flushed
+const SEND_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How often a blocked write to a socket wakes up to see how long it's
+/// been stuck
+const SEND_POLL: Duration = Duration::from_secs(1);
+
+/// How long GDB can go without taking any of what it's sent before it's
+/// given up on
+const SEND_STALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// One GDB connection.  Writes are buffered, and whatever is buffered goes
+/// out on `flush()` or `send()`.  Short writes are carried on from where
+/// they stopped, so a congested link slows replies down rather than cutting
+/// them off.
+pub struct Connection {
+    link: Link,
+
+    /// Bytes written that the other end hasn't taken yet
+    pending: Vec<u8>,
+}
+
+enum Link {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Pty(File),
+    Stream(Stream),
+}
+
+/// A reader and writer handed over by a program that embeds the adapter.
+/// All that can be done with a plain `Read` is block on it, so a thread
+/// does that and passes along what it gets, which lets the server wait
+/// for GDB with a timeout as it does on a socket.
+struct Stream {
+    incoming: Mutex<Incoming>,
+    writer: Box<dyn Write + Send>,
+}
+
+struct Incoming {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+
+    /// What's been received and not read yet
+    buffer: Vec<u8>,
+
+    /// The reader failed with this, to be returned by the next read
+    error: Option<io::Error>,
+
+    /// The reader has hit the end
+    closed: bool,
+}
+
+impl GdbListener {
+    pub fn new(cfg: &Config) -> io::Result<GdbListener> {
+        if cfg.gdb_pty {
+            Self::pty()
+        } else {
+            let listener = TcpListener::bind(format!("{}:{}", cfg.bind_addr, cfg.bind_port))?;
+            ui_info!(
+                "Accepting connections on {}:{}",
+                cfg.bind_addr,
+                cfg.bind_port
+            );
+            Ok(GdbListener::Tcp(listener, cfg.tuning.nodelay))
+        }
+    }
+
+    #[cfg(unix)]
+    fn pty() -> io::Result<GdbListener> {
+        let pty = Pty::open()?;
+        ui_info!("GDB can connect with \"target remote {}\"", pty.path);
+        Ok(GdbListener::Pty(pty))
+    }
+
+    #[cfg(not(unix))]
+    fn pty() -> io::Result<GdbListener> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "GDB over a pty is only supported on unix",
+        ))
+    }
+
+    /// Wait for GDB, returning the connection and where it came from.  A
+    /// pty is always there, so it's handed out straight away.
+    pub fn accept(&self) -> io::Result<(Connection, String)> {
+        match self {
+            GdbListener::Tcp(listener, nodelay) => {
+                let (stream, addr) = listener.accept()?;
+                stream.set_nodelay(*nodelay)?;
+                stream.set_write_timeout(Some(SEND_POLL))?;
+                Ok((Connection::new(Link::Tcp(stream)), addr.to_string()))
+            }
+            #[cfg(unix)]
+            GdbListener::Pty(pty) => Ok((
+                Connection::new(Link::Pty(pty.master.try_clone()?)),
+                pty.path.clone(),
+            )),
+        }
+    }
+
+    /// What GDB gives `target remote` to connect.  A socket listening on
+    /// every interface is reached through the loopback one.
+    pub fn target(&self) -> io::Result<String> {
+        match self {
+            GdbListener::Tcp(listener, _) => {
+                let mut addr = listener.local_addr()?;
+                if addr.ip().is_unspecified() {
+                    addr.set_ip(Ipv4Addr::LOCALHOST.into());
+                }
+                Ok(addr.to_string())
+            }
+            #[cfg(unix)]
+            GdbListener::Pty(pty) => Ok(pty.path.clone()),
+        }
+    }
+
+    /// Whether more than one GDB can be connected at once.  A pty only
+    /// has the one end.
+    pub fn is_shared(&self) -> bool {
+        matches!(self, GdbListener::Tcp(..))
+    }
+}
+
+impl Connection {
+    fn new(link: Link) -> Connection {
+        Connection {
+            link,
+            pending: vec![],
+        }
+    }
+
+    /// A connection over any reader and writer, such as the two ends of a
+    /// pipe or a `UnixStream` pair.  The reader is read from on a thread
+    /// of its own, which finishes once it reaches the end or fails.
+    pub fn from_stream(
+        mut reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> Connection {
+        let (tx, chunks) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 4096];
+            loop {
+                let chunk = match reader.read(&mut buf) {
+                    Ok(0) => return,
+                    Ok(len) => Ok(buf[..len].to_vec()),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = chunk.is_err();
+                if tx.send(chunk).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Connection::new(Link::Stream(Stream {
+            incoming: Mutex::new(Incoming {
+                chunks,
+                buffer: vec![],
+                error: None,
+                closed: false,
+            }),
+            writer: Box::new(writer),
+        }))
+    }
+
+    /// Another handle on the same connection, with its own buffer.  A
+    /// caller's stream can't be shared this way.
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        Ok(Connection::new(match &self.link {
+            Link::Tcp(s) => Link::Tcp(s.try_clone()?),
+            #[cfg(unix)]
+            Link::Pty(f) => Link::Pty(f.try_clone()?),
+            Link::Stream(_) => return Err(io::Error::other("a caller's stream can't be cloned")),
+        }))
+    }
+
+    /// Write `data` and everything before it straight away
+    pub fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.pending.extend_from_slice(data);
+        self.drain()
+    }
+
+    /// Wait up to `timeout` for GDB to send something, returning whether
+    /// it did.  A hangup counts, so that the read that follows sees it.
+    #[cfg(unix)]
+    pub fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        use std::os::unix::io::AsRawFd;
+
+        match &self.link {
+            Link::Tcp(s) => poll_readable(s.as_raw_fd(), timeout),
+            Link::Pty(f) => poll_readable(f.as_raw_fd(), timeout),
+            Link::Stream(s) => Ok(s.wait_readable(timeout)),
+        }
+    }
+
+    /// Without `poll()` a socket is waited on by peeking at it with a
+    /// timeout.  There's no pty to wait on here.
+    #[cfg(not(unix))]
+    pub fn wait_readable(&self, timeout: Duration) -> io::Result<bool> {
+        match &self.link {
+            Link::Tcp(s) => {
+                let mut byte = [0; 1];
+                s.set_read_timeout(Some(timeout))?;
+                let result = s.peek(&mut byte);
+                s.set_read_timeout(None)?;
+                match result {
+                    Ok(_) => Ok(true),
+                    Err(ref e)
+                        if e.kind() == io::ErrorKind::WouldBlock
+                            || e.kind() == io::ErrorKind::TimedOut =>
+                    {
+                        Ok(false)
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Link::Stream(s) => Ok(s.wait_readable(timeout)),
+        }
+    }
+
+    /// Hang up on GDB.  A pty stays open for the next session, and a
+    /// caller's stream is theirs to close, so there's nothing to do for
+    /// either.
+    pub fn shutdown(&self) {
+        if let Link::Tcp(s) = &self.link {
+            let _ = s.shutdown(std::net::Shutdown::Both);
+        }
+    }
+
+    /// Write out everything that's pending, however many writes it takes.
+    /// A socket that takes nothing for `SEND_STALL_TIMEOUT` is given up on,
+    /// and whatever was pending is dropped along with the connection.  A
+    /// pty has no timeout, since GDB not reading one just blocks the write.
+    fn drain(&mut self) -> io::Result<()> {
+        let mut progress = Instant::now();
+        let mut warned = false;
+        while !self.pending.is_empty() {
+            let result = match &mut self.link {
+                Link::Tcp(s) => s.write(&self.pending),
+                #[cfg(unix)]
+                Link::Pty(f) => f.write(&self.pending),
+                Link::Stream(s) => s.writer.write(&self.pending),
+            };
+            match result {
+                Ok(0) => {
+                    self.pending.clear();
+                    return Err(io::ErrorKind::WriteZero.into());
+                }
+                Ok(len) => {
+                    self.pending.drain(..len);
+                    progress = Instant::now();
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(ref e)
+                    if e.kind() == io::ErrorKind::WouldBlock
+                        || e.kind() == io::ErrorKind::TimedOut =>
+                {
+                    if progress.elapsed() >= SEND_STALL_TIMEOUT {
+                        self.pending.clear();
+                        return Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "GDB stopped taking replies",
+                        ));
+                    }
+                    if !warned {
+                        log_gdb!(
+                            @Verbose,
+                            "GDB is slow to take replies, {} bytes waiting",
+                            self.pending.len()
+                        );
+                        warned = true;
+                    }
+                }
+                Err(e) => {
+                    self.pending.clear();
+                    return Err(e);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.link {
+            Link::Tcp(s) => s.read(buf),
+            #[cfg(unix)]
+            Link::Pty(f) => f.read(buf),
+            Link::Stream(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    /// Everything is taken, and only written out once enough is waiting
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(buf);
+        if self.pending.len() >= SEND_BUFFER_SIZE {
+            self.drain()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain()?;
+        match &mut self.link {
+            Link::Tcp(s) => s.flush(),
+            #[cfg(unix)]
+            Link::Pty(f) => f.flush(),
+            Link::Stream(s) => s.writer.flush(),
+        }
+    }
+}
+
+/// Wait up to `timeout` for `fd` to have something to read
+#[cfg(unix)]
+fn poll_readable(fd: std::os::unix::io::RawFd, timeout: Duration) -> io::Result<bool> {
+    let mut fd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+    match unsafe { libc::poll(&mut fd, 1, ms) } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(e)
+            }
+        }
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+impl Stream {
+    /// Wait up to `timeout` for something from the reader thread.  The
+    /// reader finishing counts, so that the read that follows sees it.
+    fn wait_readable(&self, timeout: Duration) -> bool {
+        let mut incoming = self.incoming.lock().unwrap();
+        if !incoming.buffer.is_empty() || incoming.error.is_some() || incoming.closed {
+            return true;
+        }
+        match incoming.chunks.recv_timeout(timeout) {
+            Ok(chunk) => incoming.take(chunk),
+            Err(RecvTimeoutError::Timeout) => return false,
+            Err(RecvTimeoutError::Disconnected) => incoming.closed = true,
+        }
+        true
+    }
+
+    fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut incoming = self.incoming.lock().unwrap();
+        while incoming.buffer.is_empty() {
+            if let Some(e) = incoming.error.take() {
+                return Err(e);
+            }
+            if incoming.closed {
+                return Ok(0);
+            }
+            match incoming.chunks.recv() {
+                Ok(chunk) => incoming.take(chunk),
+                Err(_) => incoming.closed = true,
+            }
+        }
+        let len = buf.len().min(incoming.buffer.len());
+        buf[..len].copy_from_slice(&incoming.buffer[..len]);
+        incoming.buffer.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Incoming {
+    fn take(&mut self, chunk: io::Result<Vec<u8>>) {
+        match chunk {
+            Ok(data) => self.buffer.extend_from_slice(&data),
+            Err(e) => self.error = Some(e),
+        }
+    }
+}
+
+/// A pseudo-terminal.  We keep the master side, and GDB opens the slave
+/// by name.  The slave is also held open here, in raw mode, so that GDB
+/// coming and going doesn't disturb the master, and nothing is echoed
+/// back before GDB has set the line up itself.
+#[cfg(unix)]
+pub struct Pty {
+    master: File,
+    path: String,
+
+    /// Only held, never read
+    _slave: File,
+}
+
+#[cfg(unix)]
+impl Pty {
+    fn open() -> io::Result<Pty> {
+        use std::ffi::CStr;
+        use std::fs::OpenOptions;
+        use std::os::unix::fs::OpenOptionsExt;
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+
+        let master = unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY) };
+        if master < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let master = unsafe { File::from_raw_fd(master) };
+        let fd = master.as_raw_fd();
+        if unsafe { libc::grantpt(fd) } != 0 || unsafe { libc::unlockpt(fd) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let name = unsafe { libc::ptsname(fd) };
+        if name.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let path = unsafe { CStr::from_ptr(name) }
+            .to_string_lossy()
+            .into_owned();
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+        unsafe {
+            let mut termios = std::mem::zeroed();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(Pty {
+            master,
+            path,
+            _slave: slave,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Connection, Link};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    use crate::embed::pipe::pipe;
+
+    const WAIT: Duration = Duration::from_millis(50);
+
+    #[test]
+    fn sockets_are_waited_on() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut gdb = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut connection = Connection::new(Link::Tcp(listener.accept().unwrap().0));
+        assert!(!connection.wait_readable(WAIT).unwrap());
+        gdb.write_all(b"+").unwrap();
+        assert!(connection.wait_readable(WAIT).unwrap());
+        let mut byte = [0; 1];
+        connection.read_exact(&mut byte).unwrap();
+
+        // GDB hanging up wakes the wait, and the read sees it
+        drop(gdb);
+        assert!(connection.wait_readable(WAIT).unwrap());
+        assert_eq!(connection.read(&mut byte).unwrap(), 0);
+    }
+
+    #[test]
+    fn streams_are_waited_on() {
+        let (reader, mut to_server) = pipe();
+        let (_, writer) = pipe();
+        let mut connection = Connection::from_stream(reader, writer);
+        assert!(!connection.wait_readable(WAIT).unwrap());
+        to_server.write_all(b"+").unwrap();
+        assert!(connection.wait_readable(WAIT).unwrap());
+        let mut byte = [0; 1];
+        connection.read_exact(&mut byte).unwrap();
+        drop(to_server);
+        assert!(connection.wait_readable(WAIT).unwrap());
+        assert_eq!(connection.read(&mut byte).unwrap(), 0);
+    }
+}
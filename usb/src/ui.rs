@@ -0,0 +1,219 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use super::config::Config;
+
+/// Print a status message at normal verbosity
+macro_rules! ui_info {
+    ($($arg:tt)*) => ($crate::ui::info(format_args!($($arg)*)))
+}
+
+/// Report something the user will want to notice, such as a debugger
+/// attaching or the target stopping
+macro_rules! ui_event {
+    ($event:expr, $($arg:tt)*) => ($crate::ui::event($event, format_args!($($arg)*)))
+}
+
+/// Print the answer to whatever the user asked for.  Shown even when quiet.
+macro_rules! ui_result {
+    ($($arg:tt)*) => ($crate::ui::result(format_args!($($arg)*)))
+}
+
+/// Report an error to stderr.  Shown even when quiet.
+macro_rules! ui_error {
+    ($($arg:tt)*) => ($crate::ui::error(format_args!($($arg)*)))
+}
+
+/// How much to print to the console.  Each level includes everything
+/// printed by the levels before it.
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    /// Only results and errors
+    Quiet,
+
+    /// Status messages and important events
+    Normal,
+
+    /// Adapter diagnostics
+    Verbose,
+
+    /// GDB packet traces
+    Debug,
+}
+
+impl Verbosity {
+    pub const ALL: [Verbosity; 4] = [
+        Verbosity::Quiet,
+        Verbosity::Normal,
+        Verbosity::Verbose,
+        Verbosity::Debug,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Verbosity> {
+        Verbosity::ALL.iter().copied().find(|v| v.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Verbosity::Quiet => "quiet",
+            Verbosity::Normal => "normal",
+            Verbosity::Verbose => "verbose",
+            Verbosity::Debug => "debug",
+        }
+    }
+}
+
+/// How console output is laid out
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// Plain sentences, with important events in colour on a terminal
+    Human,
+
+    /// One tab-separated `tag<TAB>message` record per line, for scripts
+    Machine,
+}
+
+/// Events that deserve to stand out from the rest of the output.  Most
+/// of them only come from the servers.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(not(feature = "server"), allow(dead_code))]
+pub enum Event {
+    /// A debugger connected
+    Attach,
+
+    /// A debugger went away
+    Detach,
+
+    /// The target stopped by itself
+    Halt,
+
+    /// Finished writing to flash
+    FlashComplete,
+
+    /// The board's power was switched
+    Power,
+
+    /// Memory reported errors, such as ones ECC caught
+    MemoryError,
+
+    /// The SoC was reset
+    Reset,
+
+    /// Firmware rang the doorbell
+    Doorbell,
+}
+
+impl Event {
+    fn tag(self) -> &'static str {
+        match self {
+            Event::Attach => "attach",
+            Event::Detach => "detach",
+            Event::Halt => "halt",
+            Event::FlashComplete => "flash-complete",
+            Event::Power => "power",
+            Event::MemoryError => "memory-error",
+            Event::Reset => "reset",
+            Event::Doorbell => "doorbell",
+        }
+    }
+
+    /// ANSI colour for the event
+    fn colour(self) -> &'static str {
+        match self {
+            Event::Attach => "\x1b[1;32m",
+            Event::Detach => "\x1b[1;33m",
+            Event::Halt => "\x1b[1;36m",
+            Event::FlashComplete => "\x1b[1;32m",
+            Event::Power => "\x1b[1;35m",
+            Event::MemoryError => "\x1b[1;31m",
+            Event::Reset => "\x1b[1;35m",
+            Event::Doorbell => "\x1b[1;34m",
+        }
+    }
+}
+
+struct Settings {
+    verbosity: Verbosity,
+    format: OutputFormat,
+    colour: bool,
+}
+
+static SETTINGS: Mutex<Settings> = Mutex::new(Settings {
+    verbosity: Verbosity::Normal,
+    format: OutputFormat::Human,
+    colour: false,
+});
+
+/// Pick up the verbosity and output format from the config.  Colour is only
+/// used for human-readable output going to a terminal.
+pub fn init(cfg: &Config) {
+    let mut settings = SETTINGS.lock().unwrap();
+    settings.verbosity = cfg.verbosity;
+    settings.format = cfg.output_format;
+    settings.colour = cfg.output_format == OutputFormat::Human && stdout_is_terminal();
+}
+
+#[cfg(feature = "server")]
+pub fn verbosity() -> Verbosity {
+    SETTINGS.lock().unwrap().verbosity
+}
+
+/// Change how much is printed while running
+#[cfg(feature = "server")]
+pub fn set_verbosity(verbosity: Verbosity) {
+    SETTINGS.lock().unwrap().verbosity = verbosity;
+}
+
+#[cfg(unix)]
+fn stdout_is_terminal() -> bool {
+    unsafe { libc::isatty(libc::STDOUT_FILENO) == 1 }
+}
+
+#[cfg(not(unix))]
+fn stdout_is_terminal() -> bool {
+    false
+}
+
+fn print(level: Verbosity, tag: &str, colour: Option<&str>, args: fmt::Arguments) {
+    let settings = SETTINGS.lock().unwrap();
+    if level > settings.verbosity {
+        return;
+    }
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    // A closed stdout shouldn't take the adapter down with it
+    let _ = match (settings.format, colour) {
+        (OutputFormat::Machine, _) => writeln!(out, "{}\t{}", tag, args),
+        (OutputFormat::Human, Some(c)) if settings.colour => writeln!(out, "{}{}\x1b[0m", c, args),
+        (OutputFormat::Human, _) => writeln!(out, "{}", args),
+    };
+}
+
+pub fn info(args: fmt::Arguments) {
+    print(Verbosity::Normal, "info", None, args);
+}
+
+pub fn event(event: Event, args: fmt::Arguments) {
+    print(Verbosity::Normal, event.tag(), Some(event.colour()), args);
+}
+
+pub fn result(args: fmt::Arguments) {
+    print(Verbosity::Quiet, "result", None, args);
+}
+
+/// Print a log line that has no file of its own to go to
+pub fn log(level: Verbosity, channel: &str, args: fmt::Arguments) {
+    print(level, channel, None, args);
+}
+
+pub fn error(args: fmt::Arguments) {
+    let settings = SETTINGS.lock().unwrap();
+    let _ = match settings.format {
+        OutputFormat::Machine => writeln!(io::stderr(), "error\t{}", args),
+        OutputFormat::Human if settings.colour => {
+            writeln!(io::stderr(), "\x1b[1;31m{}\x1b[0m", args)
+        }
+        OutputFormat::Human => writeln!(io::stderr(), "{}", args),
+    };
+}
@@ -3,17 +3,35 @@ extern crate libusb;
 use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
 use std::sync::Mutex;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use super::bridge::BridgeError;
+use super::bridge::{self, BatchOp, BridgeError, UsbBursts};
 use super::config::Config;
+use super::suspend::{self, SleepDetector};
+
+/// The control endpoint's packet size, which is the most a burst carries
+const MAX_BURST: usize = 64;
+
+const TIMEOUT: Duration = Duration::from_millis(500);
 
 pub struct UsbBridge {
     usb_pid: Option<u16>,
     usb_vid: Option<u16>,
     main_tx: Sender<ConnectThreadRequests>,
-    main_rx: Receiver<ConnectThreadResponses>,
+
+    /// Behind a lock so the bridge can be shared between threads
+    main_rx: Mutex<Receiver<ConnectThreadResponses>>,
     connect_mutex: Mutex<()>,
+
+    /// Turn off autosuspend each time the device is opened
+    no_autosuspend: bool,
+
+    /// Requests that fail because the host slept are made again once the
+    /// device is back
+    sleep: SleepDetector,
+
+    /// The profile's cap on burst sizes, in bytes
+    burst_limit: usize,
 }
 
 enum ConnectThreadRequests {
@@ -21,12 +39,15 @@ enum ConnectThreadRequests {
     Exit,
     Poke(u32 /* addr */, u32 /* val */),
     Peek(u32 /* addr */),
+    Batch(Vec<BatchOp>),
 }
 
 enum ConnectThreadResponses {
-    OpenedDevice,
+    OpenedDevice(u8 /* bus */, u8 /* address */),
     PeekResult(Result<u32, BridgeError>),
     PokeResult(Result<(), BridgeError>),
+    /// What the batch read, and whether any of its writes went through
+    BatchResult(Result<Vec<u32>, BridgeError>, bool),
 }
 
 impl UsbBridge {
@@ -35,18 +56,22 @@ impl UsbBridge {
         let (thread_tx, main_rx) = channel();
         let (main_tx, thread_rx) = channel();
 
-        let thr_pid = cfg.usb_pid.clone();
-        let thr_vid = cfg.usb_vid.clone();
+        let thr_pid = cfg.usb_pid;
+        let thr_vid = cfg.usb_vid;
+        let retry = cfg.tuning.reconnect_interval;
         thread::spawn(move || {
-            Self::usb_connect_thread(usb_ctx, thread_tx, thread_rx, thr_pid, thr_vid, 0x43)
+            Self::usb_connect_thread(usb_ctx, thread_tx, thread_rx, thr_pid, thr_vid, 0x43, retry)
         });
 
         Ok(UsbBridge {
-            usb_pid: cfg.usb_pid.clone(),
-            usb_vid: cfg.usb_vid.clone(),
+            usb_pid: cfg.usb_pid,
+            usb_vid: cfg.usb_vid,
             main_tx,
-            main_rx,
+            main_rx: Mutex::new(main_rx),
             connect_mutex: Mutex::new(()),
+            no_autosuspend: cfg.no_autosuspend,
+            sleep: SleepDetector::default(),
+            burst_limit: cfg.tuning.burst_limit.unwrap_or(usize::MAX),
         })
     }
 
@@ -71,19 +96,50 @@ impl UsbBridge {
     pub fn connect(&self) -> Result<(), BridgeError> {
         self.main_tx
             .send(ConnectThreadRequests::StartPolling(
-                self.usb_pid.clone(),
-                self.usb_vid.clone(),
+                self.usb_pid,
+                self.usb_vid,
+            ))
+            .unwrap();
+        loop {
+            match self.main_rx.lock().unwrap().recv() {
+                Ok(ConnectThreadResponses::OpenedDevice(bus, address)) => {
+                    self.opened(bus, address);
+                    return Ok(());
+                }
+                Ok(_) => (),
+                Err(_) => return Err(BridgeError::NotConnected),
+            }
+        }
+    }
+
+    /// Wait until `deadline` for the connection thread to find the device
+    /// again, such as after the host wakes up and it's enumerated again
+    fn reopen(&self, deadline: Instant) -> Result<(), BridgeError> {
+        self.main_tx
+            .send(ConnectThreadRequests::StartPolling(
+                self.usb_pid,
+                self.usb_vid,
             ))
             .unwrap();
         loop {
-            match self.main_rx.recv() {
-                Ok(ConnectThreadResponses::OpenedDevice) => return Ok(()),
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.main_rx.lock().unwrap().recv_timeout(timeout) {
+                Ok(ConnectThreadResponses::OpenedDevice(bus, address)) => {
+                    self.opened(bus, address);
+                    return Ok(());
+                }
                 Ok(_) => (),
                 Err(_) => return Err(BridgeError::NotConnected),
             }
         }
     }
 
+    fn opened(&self, bus: u8, address: u8) {
+        if self.no_autosuspend {
+            suspend::disable_autosuspend(bus as u32, address as u32);
+        }
+    }
+
     fn usb_connect_thread(
         usb_ctx: libusb::Context,
         tx: Sender<ConnectThreadResponses>,
@@ -91,6 +147,7 @@ impl UsbBridge {
         pid: Option<u16>,
         vid: Option<u16>,
         debug_byte: u8,
+        retry: Duration,
     ) {
         let mut pid = pid;
         let mut vid = vid;
@@ -105,8 +162,13 @@ impl UsbBridge {
                     //     device.address()
                     // );
                     let usb = device.open().expect("Unable to open USB device");
-                    tx.send(ConnectThreadResponses::OpenedDevice)
-                        .expect("Couldn't post message to main thread");
+                    // The device may have come back with different gateware
+                    let mut bursts = UsbBursts::Unknown;
+                    tx.send(ConnectThreadResponses::OpenedDevice(
+                        device.bus_number(),
+                        device.address(),
+                    ))
+                    .expect("Couldn't post message to main thread");
                     let mut keep_going = true;
                     while keep_going {
                         let var = rx.recv();
@@ -118,8 +180,8 @@ impl UsbBridge {
                                     return;
                                 }
                                 ConnectThreadRequests::StartPolling(p, v) => {
-                                    pid = p.clone();
-                                    vid = v.clone();
+                                    pid = p;
+                                    vid = v;
                                 }
                                 ConnectThreadRequests::Peek(addr) => {
                                     let result = Self::do_peek(&usb, addr, debug_byte);
@@ -133,20 +195,53 @@ impl UsbBridge {
                                     tx.send(ConnectThreadResponses::PokeResult(result))
                                         .expect("Couldn't post poke response to main thread");
                                 }
+                                ConnectThreadRequests::Batch(ops) => {
+                                    let mut wrote = false;
+                                    let result = bridge::execute_usb(
+                                        &ops,
+                                        &mut bursts,
+                                        MAX_BURST / 4,
+                                        |a, data| {
+                                            Ok(usb.read_control(
+                                                0x80 | debug_byte,
+                                                0,
+                                                (a & 0xffff) as u16,
+                                                (a >> 16) as u16,
+                                                data,
+                                                TIMEOUT,
+                                            )?)
+                                        },
+                                        |a, data| {
+                                            let len = usb.write_control(
+                                                debug_byte,
+                                                0,
+                                                (a & 0xffff) as u16,
+                                                (a >> 16) as u16,
+                                                data,
+                                                TIMEOUT,
+                                            )?;
+                                            wrote = true;
+                                            Ok(len)
+                                        },
+                                    );
+                                    keep_going = result.is_ok();
+                                    tx.send(ConnectThreadResponses::BatchResult(result, wrote))
+                                        .expect("Couldn't post batch response to main thread");
+                                }
                             },
                         }
                     }
                 }
             }
-            println!("No device available, pausing");
-            thread::park_timeout(Duration::from_millis(500));
+            log_adapter!("No device available, pausing");
+            thread::park_timeout(retry);
             loop {
                 match rx.try_recv() {
                     Err(TryRecvError::Empty) => break,
                     Err(TryRecvError::Disconnected) => panic!("main thread disconnected"),
                     Ok(m) => match m {
                         ConnectThreadRequests::Exit => {
-                            println!("main thread requested exit");
+                            log_adapter!("main thread requested exit");
                             return;
                         }
                         ConnectThreadRequests::Peek(_addr) => tx
@@ -159,9 +254,15 @@ impl UsbBridge {
                                 BridgeError::NotConnected,
                             )))
                             .expect("Couldn't respond to poke request"),
+                        ConnectThreadRequests::Batch(_ops) => tx
+                            .send(ConnectThreadResponses::BatchResult(
+                                Err(BridgeError::NotConnected),
+                                false,
+                            ))
+                            .expect("Couldn't respond to batch request"),
                         ConnectThreadRequests::StartPolling(p, v) => {
-                            pid = p.clone();
-                            vid = v.clone();
+                            pid = p;
+                            vid = v;
                         }
                     },
                 }
@@ -176,17 +277,17 @@ impl UsbBridge {
         debug_byte: u8,
     ) -> Result<(), BridgeError> {
         let mut data_val = [0; 4];
-        data_val[0] = ((value >> 0) & 0xff) as u8;
+        data_val[0] = (value & 0xff) as u8;
         data_val[1] = ((value >> 8) & 0xff) as u8;
         data_val[2] = ((value >> 16) & 0xff) as u8;
         data_val[3] = ((value >> 24) & 0xff) as u8;
         match usb.write_control(
             debug_byte,
             0,
-            ((addr >> 0) & 0xffff) as u16,
+            (addr & 0xffff) as u16,
             ((addr >> 16) & 0xffff) as u16,
             &data_val,
-            Duration::from_millis(500),
+            TIMEOUT,
         ) {
             Err(e) => Err(BridgeError::USBError(e)),
             Ok(len) => {
@@ -204,10 +305,10 @@ impl UsbBridge {
         match usb.read_control(
             0x80 | debug_byte,
             0,
-            ((addr >> 0) & 0xffff) as u16,
+            (addr & 0xffff) as u16,
             ((addr >> 16) & 0xffff) as u16,
             &mut data_val,
-            Duration::from_millis(500),
+            TIMEOUT,
         ) {
             Err(e) => Err(BridgeError::USBError(e)),
             Ok(len) => {
@@ -217,19 +318,37 @@ impl UsbBridge {
                     Ok(((data_val[3] as u32) << 24)
                         | ((data_val[2] as u32) << 16)
                         | ((data_val[1] as u32) << 8)
-                        | ((data_val[0] as u32) << 0))
+                        | (data_val[0] as u32))
                 }
             }
         }
     }
 
+    /// Consecutive words in a batch share a control transfer if the
+    /// device can take it, and this keeps each to one packet.
+    pub fn max_burst(&self) -> usize {
+        self.burst_limit.min(MAX_BURST)
+    }
+
     pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        self.sleep
+            .request(|_| self.send_poke(addr, value), |d| self.reopen(d))
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        self.sleep
+            .request(|_| self.send_peek(addr), |d| self.reopen(d))
+    }
+
+    fn send_poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
         let _mtx = self.connect_mutex.lock().unwrap();
         self.main_tx
             .send(ConnectThreadRequests::Poke(addr, value))
             .expect("Unable to send poke to connect thread");
         let result = self
             .main_rx
+            .lock()
+            .unwrap()
             .recv()
             .expect("Unable to receive poke from connect thread");
         if let ConnectThreadResponses::PokeResult(r) = result {
@@ -239,13 +358,15 @@ impl UsbBridge {
         }
     }
 
-    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+    fn send_peek(&self, addr: u32) -> Result<u32, BridgeError> {
         let _mtx = self.connect_mutex.lock().unwrap();
         self.main_tx
             .send(ConnectThreadRequests::Peek(addr))
             .expect("Unable to send peek to connect thread");
         let result = self
             .main_rx
+            .lock()
+            .unwrap()
             .recv()
             .expect("Unable to receive peek from connect thread");
         if let ConnectThreadResponses::PeekResult(r) = result {
@@ -256,6 +377,36 @@ impl UsbBridge {
     }
 }
 
+impl UsbBridge {
+    /// The whole batch goes to the connection thread as one request, and
+    /// runs of consecutive words go in one control transfer each when the
+    /// device can take them.
+    pub fn execute(&self, ops: &[BatchOp]) -> Result<Vec<u32>, BridgeError> {
+        self.sleep
+            .request(|landed| self.send_batch(ops, landed), |d| self.reopen(d))
+    }
+
+    /// Run `ops`, setting `landed` if any of their writes went through
+    fn send_batch(&self, ops: &[BatchOp], landed: &mut bool) -> Result<Vec<u32>, BridgeError> {
+        let _mtx = self.connect_mutex.lock().unwrap();
+        self.main_tx
+            .send(ConnectThreadRequests::Batch(ops.to_vec()))
+            .expect("Unable to send batch to connect thread");
+        let result = self
+            .main_rx
+            .lock()
+            .unwrap()
+            .recv()
+            .expect("Unable to receive batch from connect thread");
+        if let ConnectThreadResponses::BatchResult(r, wrote) = result {
+            *landed |= wrote;
+            Ok(r?)
+        } else {
+            Err(BridgeError::WrongResponse)
+        }
+    }
+}
+
 impl Drop for UsbBridge {
     fn drop(&mut self) {
         let _mtx = self.connect_mutex.lock().unwrap();
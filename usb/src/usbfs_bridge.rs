@@ -0,0 +1,309 @@
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::bridge::{self, BatchOp, BridgeError, UsbBursts};
+use super::config::Config;
+use super::suspend::{self, SleepDetector};
+
+/// Where the kernel describes attached devices, and where their nodes live
+const SYSFS_DEVICES: &str = "/sys/bus/usb/devices";
+const DEVFS_ROOT: &str = "/dev/bus/usb";
+
+/// Vendor request the device's debug interface answers to
+const DEBUG_REQUEST: u8 = 0x43;
+
+const TIMEOUT_MS: u32 = 500;
+
+/// The control endpoint's packet size, which is the most a burst carries
+const MAX_BURST: usize = 64;
+
+/// `struct usbdevfs_ctrltransfer` from <linux/usbdevice_fs.h>
+#[repr(C)]
+struct CtrlTransfer {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+    timeout: u32,
+    data: *mut libc::c_void,
+}
+
+/// `USBDEVFS_CONTROL`, which is `_IOWR('U', 0, struct usbdevfs_ctrltransfer)`
+const USBDEVFS_CONTROL: u64 =
+    (3 << 30) | ((std::mem::size_of::<CtrlTransfer>() as u64) << 16) | ((b'U' as u64) << 8);
+
+/// A USB device found in sysfs
+pub struct DeviceInfo {
+    pub vid: u16,
+    pub pid: u16,
+    pub bus: u32,
+    pub address: u32,
+    pub product: Option<String>,
+    pub manufacturer: Option<String>,
+}
+
+/// Talks to the device through Linux's usbfs with nothing but system
+/// calls, so a statically linked binary doesn't need libusb installed.
+/// The device node is opened on `connect()`, and reopened on the next
+/// request after one fails, in case the device was unplugged and came
+/// back.  A request that fails because the host slept is made again once
+/// the device has been found.
+pub struct UsbfsBridge {
+    usb_pid: Option<u16>,
+    usb_vid: Option<u16>,
+    device: Mutex<Option<File>>,
+
+    /// Turn off autosuspend each time the device is opened
+    no_autosuspend: bool,
+
+    sleep: SleepDetector,
+
+    /// Whether the open device takes more than a word per transfer
+    bursts: Mutex<UsbBursts>,
+
+    /// How long to wait between looking for the device
+    retry: Duration,
+
+    /// The profile's cap on burst sizes, in bytes
+    burst_limit: usize,
+}
+
+impl UsbfsBridge {
+    pub fn new(cfg: &Config) -> Result<Self, BridgeError> {
+        Ok(UsbfsBridge {
+            usb_pid: cfg.usb_pid,
+            usb_vid: cfg.usb_vid,
+            device: Mutex::new(None),
+            no_autosuspend: cfg.no_autosuspend,
+            sleep: SleepDetector::default(),
+            bursts: Mutex::new(UsbBursts::Unknown),
+            retry: cfg.tuning.reconnect_interval,
+            burst_limit: cfg.tuning.burst_limit.unwrap_or(usize::MAX),
+        })
+    }
+
+    /// Wait for a matching device to turn up
+    pub fn connect(&self) -> Result<(), BridgeError> {
+        let mut device = self.device.lock().unwrap();
+        loop {
+            if let Some(file) = self.open()? {
+                *device = Some(file);
+                *self.bursts.lock().unwrap() = UsbBursts::Unknown;
+                return Ok(());
+            }
+            log_adapter!("No device available, pausing");
+            thread::sleep(self.retry);
+        }
+    }
+
+    fn open(&self) -> Result<Option<File>, BridgeError> {
+        let info = match find_device(self.usb_vid, self.usb_pid)? {
+            Some(info) => info,
+            None => return Ok(None),
+        };
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(info.node())?;
+        if self.no_autosuspend {
+            suspend::disable_autosuspend(info.bus, info.address);
+        }
+        Ok(Some(file))
+    }
+
+    /// Look for the device until `deadline`, such as after the host wakes
+    /// up and it's enumerated again
+    fn reopen(&self, deadline: Instant) -> Result<(), BridgeError> {
+        let mut device = self.device.lock().unwrap();
+        loop {
+            if let Some(file) = self.open()? {
+                *device = Some(file);
+                *self.bursts.lock().unwrap() = UsbBursts::Unknown;
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                return Err(BridgeError::NotConnected);
+            }
+            thread::sleep(self.retry);
+        }
+    }
+
+    /// Run `f` on the open device, opening it first if the last request
+    /// failed.  A device that has gone away is `NotConnected`.
+    fn with_device<T>(
+        &self,
+        f: impl FnOnce(&File) -> Result<T, BridgeError>,
+    ) -> Result<T, BridgeError> {
+        let mut device = self.device.lock().unwrap();
+        if device.is_none() {
+            *device = self.open()?;
+            // The device may have come back with different gateware
+            *self.bursts.lock().unwrap() = UsbBursts::Unknown;
+        }
+        let result = match *device {
+            Some(ref file) => f(file),
+            None => return Err(BridgeError::NotConnected),
+        };
+        if result.is_err() {
+            *device = None;
+        }
+        result
+    }
+
+    /// Consecutive words in a batch share a control transfer if the
+    /// device can take it, and this keeps each to one packet.
+    pub fn max_burst(&self) -> usize {
+        self.burst_limit.min(MAX_BURST)
+    }
+
+    pub fn poke(&self, addr: u32, value: u32) -> Result<(), BridgeError> {
+        self.sleep.request(
+            |_| self.with_device(|file| do_poke(file, addr, value)),
+            |deadline| self.reopen(deadline),
+        )
+    }
+
+    pub fn peek(&self, addr: u32) -> Result<u32, BridgeError> {
+        self.sleep.request(
+            |_| self.with_device(|file| do_peek(file, addr)),
+            |deadline| self.reopen(deadline),
+        )
+    }
+
+    pub fn execute(&self, ops: &[BatchOp]) -> Result<Vec<u32>, BridgeError> {
+        self.sleep.request(
+            |landed| {
+                self.with_device(|file| {
+                    let mut bursts = self.bursts.lock().unwrap();
+                    bridge::execute_usb(
+                        ops,
+                        &mut bursts,
+                        MAX_BURST / 4,
+                        |a, data| control(file, 0xc0, a, data),
+                        |a, data| {
+                            let len = control(file, 0x40, a, &mut data.to_vec())?;
+                            *landed = true;
+                            Ok(len)
+                        },
+                    )
+                })
+            },
+            |deadline| self.reopen(deadline),
+        )
+    }
+}
+
+fn control(
+    file: &File,
+    request_type: u8,
+    addr: u32,
+    data: &mut [u8],
+) -> Result<usize, BridgeError> {
+    Ok(control_transfer(
+        file,
+        request_type,
+        DEBUG_REQUEST,
+        (addr & 0xffff) as u16,
+        (addr >> 16) as u16,
+        data,
+    )?)
+}
+
+/// Make a control transfer on endpoint 0, returning how many bytes moved
+pub fn control_transfer(
+    file: &File,
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    data: &mut [u8],
+) -> io::Result<usize> {
+    use std::os::unix::io::AsRawFd;
+
+    let mut transfer = CtrlTransfer {
+        request_type,
+        request,
+        value,
+        index,
+        length: data.len() as u16,
+        timeout: TIMEOUT_MS,
+        data: data.as_mut_ptr() as *mut libc::c_void,
+    };
+    let result = unsafe { libc::ioctl(file.as_raw_fd(), USBDEVFS_CONTROL as _, &mut transfer) };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result as usize)
+    }
+}
+
+/// The device node of the first device with matching IDs
+pub fn device_path(vid: Option<u16>, pid: Option<u16>) -> io::Result<Option<String>> {
+    Ok(find_device(vid, pid)?.map(|info| info.node()))
+}
+
+/// The first device with matching IDs
+fn find_device(vid: Option<u16>, pid: Option<u16>) -> io::Result<Option<DeviceInfo>> {
+    Ok(list_devices()?.into_iter().find(|info| {
+        !(pid.is_some_and(|pid| pid != info.pid) || vid.is_some_and(|vid| vid != info.vid))
+    }))
+}
+
+impl DeviceInfo {
+    /// Where the device's usbfs node is
+    fn node(&self) -> String {
+        format!("{}/{:03}/{:03}", DEVFS_ROOT, self.bus, self.address)
+    }
+}
+
+fn do_poke(file: &File, addr: u32, value: u32) -> Result<(), BridgeError> {
+    match control(file, 0x40, addr, &mut value.to_le_bytes())? {
+        4 => Ok(()),
+        len => Err(BridgeError::LengthError(4, len)),
+    }
+}
+
+fn do_peek(file: &File, addr: u32) -> Result<u32, BridgeError> {
+    let mut data = [0; 4];
+    match control(file, 0xc0, addr, &mut data)? {
+        4 => Ok(u32::from_le_bytes(data)),
+        len => Err(BridgeError::LengthError(4, len)),
+    }
+}
+
+/// Every USB device sysfs knows about.  Interfaces and hubs' ports are
+/// listed alongside the devices, and are skipped since they have no IDs.
+pub fn list_devices() -> io::Result<Vec<DeviceInfo>> {
+    let mut devices = vec![];
+    for entry in fs::read_dir(SYSFS_DEVICES)? {
+        let path = entry?.path();
+        let read = |name: &str| {
+            fs::read_to_string(path.join(name))
+                .ok()
+                .map(|s| s.trim().to_owned())
+        };
+        let hex = |name: &str| read(name).and_then(|s| u16::from_str_radix(&s, 16).ok());
+        let dec = |name: &str| read(name).and_then(|s| s.parse::<u32>().ok());
+        if let (Some(vid), Some(pid), Some(bus), Some(address)) = (
+            hex("idVendor"),
+            hex("idProduct"),
+            dec("busnum"),
+            dec("devnum"),
+        ) {
+            devices.push(DeviceInfo {
+                vid,
+                pid,
+                bus,
+                address,
+                product: read("product"),
+                manufacturer: read("manufacturer"),
+            });
+        }
+    }
+    devices.sort_by_key(|d| (d.bus, d.address));
+    Ok(devices)
+}
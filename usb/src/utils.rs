@@ -24,4 +24,9 @@ pub fn parse_u16(value: &str) -> Result<u16, ParseIntError> {
 pub fn parse_u32(value: &str) -> Result<u32, ParseIntError> {
     let (value, base) = get_base(value);
     u32::from_str_radix(value, base)
-}
\ No newline at end of file
+}
+
+pub fn parse_u64(value: &str) -> Result<u64, ParseIntError> {
+    let (value, base) = get_base(value);
+    u64::from_str_radix(value, base)
+}
@@ -0,0 +1,149 @@
+//! Registers that are really variables in memory, given with
+//! `--virtual-register`, so that a firmware global such as a state machine's
+//! state shows up in GDB's register view next to the CPU's own.  They're
+//! read and written straight over the bridge rather than through a hart,
+//! but GDB only asks for registers while the target is stopped, so what it
+//! shows is the variable as it was when the target last stopped.  GDB sees
+//! every one as 32 bits wide: a narrower one reads as zero-extended, and
+//! only its own bytes are written.
+
+use super::bridge::{Bridge, BridgeError};
+use super::config::ConfigError;
+use super::expr;
+use super::riscv::register_number;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct VirtualRegister {
+    pub name: String,
+    pub addr: u32,
+
+    /// Size of the variable in bytes: 1, 2 or 4
+    pub width: u32,
+}
+
+impl VirtualRegister {
+    /// Parse `NAME=ADDR[:BITS]`, where `ADDR` can be an expression using
+    /// `lookup` and `BITS` is 8, 16 or 32, defaulting to 32
+    pub fn parse(
+        spec: &str,
+        lookup: &dyn Fn(&str) -> Option<u64>,
+    ) -> Result<VirtualRegister, ConfigError> {
+        let invalid = |why: &str| ConfigError::InvalidVirtualRegister(format!("{}: {}", spec, why));
+        let (name, rest) = spec
+            .split_once('=')
+            .ok_or_else(|| invalid("expected NAME=ADDR[:BITS]"))?;
+        let valid_name = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !valid_name {
+            return Err(invalid("the name has to be an identifier"));
+        }
+        if register_number(name).is_some() {
+            return Err(invalid("that's the name of a CPU register"));
+        }
+        let (addr, bits) = match rest.rsplit_once(':') {
+            Some((addr, bits)) => (addr, bits),
+            None => (rest, "32"),
+        };
+        let width = match bits {
+            "8" => 1,
+            "16" => 2,
+            "32" => 4,
+            _ => return Err(invalid("the width has to be 8, 16 or 32 bits")),
+        };
+        let addr = expr::eval_u32(addr, lookup)?;
+        if !addr.is_multiple_of(width) {
+            return Err(invalid("the address isn't aligned to the width"));
+        }
+        Ok(VirtualRegister {
+            name: name.to_owned(),
+            addr,
+            width,
+        })
+    }
+
+    pub fn read(&self, bridge: &Bridge) -> Result<u32, BridgeError> {
+        let word = bridge.peek(self.addr & !3)?;
+        Ok(extract(word, self.addr, self.width))
+    }
+
+    /// Write the register's bytes, leaving the rest of the word they're in
+    /// as it was
+    pub fn write(&self, bridge: &Bridge, value: u32) -> Result<(), BridgeError> {
+        let word = if self.width == 4 {
+            value
+        } else {
+            insert(bridge.peek(self.addr & !3)?, self.addr, self.width, value)
+        };
+        bridge.poke(self.addr & !3, word)
+    }
+}
+
+fn mask(width: u32) -> u32 {
+    u32::MAX.checked_shr(32 - width * 8).unwrap_or(0)
+}
+
+/// The `width` bytes at `addr` out of the word holding them
+fn extract(word: u32, addr: u32, width: u32) -> u32 {
+    (word >> ((addr & 3) * 8)) & mask(width)
+}
+
+/// `word` with the `width` bytes at `addr` replaced by `value`
+fn insert(word: u32, addr: u32, width: u32, value: u32) -> u32 {
+    let shift = (addr & 3) * 8;
+    let mask = mask(width) << shift;
+    (word & !mask) | ((value << shift) & mask)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{extract, insert, VirtualRegister};
+
+    fn lookup(name: &str) -> Option<u64> {
+        match name {
+            "sram" => Some(0x1000_0000),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parse_specs() {
+        assert_eq!(
+            VirtualRegister::parse("state=sram+0x42:8", &lookup).unwrap(),
+            VirtualRegister {
+                name: "state".to_owned(),
+                addr: 0x1000_0042,
+                width: 1,
+            }
+        );
+        let reg = VirtualRegister::parse("ticks=0x40001000", &lookup).unwrap();
+        assert_eq!((reg.addr, reg.width), (0x4000_1000, 4));
+    }
+
+    #[test]
+    fn bad_specs() {
+        for spec in &[
+            "state",
+            "a0=0x1000",
+            "2x=0x1000",
+            "state=0x1000:12",
+            "state=0x1001:16",
+            "state=nowhere",
+        ] {
+            assert!(
+                VirtualRegister::parse(spec, &lookup).is_err(),
+                "{} was accepted",
+                spec
+            );
+        }
+    }
+
+    #[test]
+    fn bytes_within_word() {
+        assert_eq!(extract(0x1122_3344, 0x1001, 1), 0x33);
+        assert_eq!(extract(0x1122_3344, 0x1002, 2), 0x1122);
+        assert_eq!(extract(0x1122_3344, 0x1000, 4), 0x1122_3344);
+        assert_eq!(insert(0x1122_3344, 0x1001, 1, 0x1ff), 0x1122_ff44);
+        assert_eq!(insert(0x1122_3344, 0x1002, 2, 0xabcd), 0xabcd_3344);
+        assert_eq!(insert(0x1122_3344, 0x1000, 4, 5), 5);
+    }
+}
@@ -1,12 +1,15 @@
 extern crate byteorder;
 
+use std::fmt;
 use std::io;
-use std::io::{Read, Write, Cursor};
-use std::net::{TcpListener, TcpStream};
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::thread;
 
 use super::Config;
 use super::bridge::{Bridge, BridgeError};
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use super::lock;
+use byteorder::{BigEndian, ByteOrder};
 
 /* The network protocol looks like this:
 
@@ -37,16 +40,59 @@ use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
     wb_buffer[17] = addr1;
     wb_buffer[18] = addr2;
     wb_buffer[19] = addr3;
+
+   A port size of 0x8 in wb_buffer[3] makes the data 64 bits wide, and then
+   every field after the record header is 8 bytes.  Reads are answered
+   with a record of writes to the return address, which is the field
+   before the read addresses, holding the values that were read.  Only
+   one record is handled per packet, which is all LiteX sends.
+
+   A header with the probe flag (0x01) in wb_buffer[2] and no record asks
+   what the server supports.  The reply has the probe-reply flag (0x02) set
+   and the supported sizes in wb_buffer[3], and uses the padding to say
+   how the bus is laid out:
+
+    wb_buffer[4] = 4 or 8;      // Bus data width in bytes
+    wb_buffer[5] = 0 or 1;      // Bus is little (0) or big (1) endian
 */
 
-pub struct WishboneServer {
+const FLAG_PROBE: u8 = 0x01;
+const FLAG_PROBE_REPLY: u8 = 0x02;
+
+/// Address and port size bits in wb_buffer[3]
+const SIZE_32: u8 = 0x4;
+const SIZE_64: u8 = 0x8;
+
+/// Write FIFO flag in wb_buffer[8]: every write goes to the base address
+/// rather than to consecutive ones
+const FLAG_WFF: u8 = 0x02;
+
+const HEADER_LENGTH: usize = 8;
+const RECORD_LENGTH: usize = 4;
+
+/// Serves the bridge to Etherbone clients, the way `litex_server` does,
+/// so that litex_cli, `RemoteClient` scripts and other tools built for it
+/// work unchanged.  Clients connect over TCP, one at a time, like they do
+/// to `litex_server`.  The same port also takes UDP packets, like a LiteX
+/// Etherbone core, so `litex_server --udp` or another adapter's
+/// `--etherbone` backend can be pointed at it too.
+pub struct EthServer {
     listener: TcpListener,
-    connection: Option<TcpStream>,
+    udp: UdpSocket,
+
+    /// Width of the bus data, in bytes
+    data_width: usize,
+
+    /// Whether the high word of a 64-bit value lives at the lower address
+    big_endian: bool,
+
+    /// Turn off Nagle's algorithm on each connection
+    nodelay: bool,
 }
 
 #[derive(Debug)]
-pub enum WishboneServerError {
-    /// An error with TCP
+pub enum EthServerError {
+    /// An error with TCP or UDP
     IoError(io::Error),
 
     /// There is no active connection
@@ -58,90 +104,361 @@ pub enum WishboneServerError {
     /// The remote side didn't ask for reading or writing
     UnsupportedOperation,
 
+    /// The remote side wants addresses or data of a size we don't handle
+    UnsupportedSize(u8),
+
+    /// The packet ended before its fields did
+    Truncated,
+
     /// There was a problem with the device bridge
     BridgeError(BridgeError),
 }
 
-impl std::convert::From<io::Error> for WishboneServerError {
-    fn from(e: io::Error) -> WishboneServerError {
-        WishboneServerError::IoError(e)
+impl std::convert::From<io::Error> for EthServerError {
+    fn from(e: io::Error) -> EthServerError {
+        EthServerError::IoError(e)
     }
 }
 
-impl std::convert::From<BridgeError> for WishboneServerError {
-    fn from(e: BridgeError) -> WishboneServerError {
-        WishboneServerError::BridgeError(e)
+impl std::convert::From<BridgeError> for EthServerError {
+    fn from(e: BridgeError) -> EthServerError {
+        EthServerError::BridgeError(e)
     }
 }
 
-impl WishboneServer {
-    pub fn new(cfg: &Config) -> Result<WishboneServer, WishboneServerError> {
-        Ok(WishboneServer {
-            connection: None,
-            listener: TcpListener::bind(format!("{}:{}", cfg.bind_addr, cfg.bind_port))?,
-        })
+impl fmt::Display for EthServerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use EthServerError::*;
+        match self {
+            IoError(e) => write!(f, "{}", e),
+            ConnectionClosed => write!(f, "connection closed"),
+            NoMagic => write!(f, "no Etherbone magic"),
+            UnsupportedOperation => write!(f, "neither a read nor a write"),
+            UnsupportedSize(sizes) => write!(f, "unsupported address or data size {:#x}", sizes),
+            Truncated => write!(f, "packet cut short"),
+            BridgeError(e) => write!(f, "bridge error: {:?}", e),
+        }
     }
+}
 
-    pub fn connect(&mut self) -> Result<(), WishboneServerError> {
-        let (connection, _sockaddr) = self.listener.accept()?;
-        self.connection = Some(connection);
-        Ok(())
+impl EthServer {
+    pub fn new(cfg: &Config) -> Result<EthServer, EthServerError> {
+        let listener = TcpListener::bind(format!("{}:{}", cfg.bind_addr, cfg.bind_port))?;
+        // The same port as TCP, even when the system picked it
+        let addr = listener.local_addr()?;
+        let udp = UdpSocket::bind(addr)?;
+        ui_info!("Serving Etherbone on {} over TCP and UDP", addr);
+        Ok(EthServer {
+            listener,
+            udp,
+            data_width: cfg.bus_data_width as usize / 8,
+            big_endian: cfg.bus_big_endian,
+            nodelay: cfg.tuning.nodelay,
+        })
     }
 
-    pub fn process(&mut self, bridge: &Bridge) -> Result<(), WishboneServerError> {
-        let mut buffer = [0; 20];
-        let mut buffer_offset = 0;
-        let mut byte = [0; 1];
+    /// Answer clients until something goes wrong with the listening
+    /// socket.  UDP packets are answered on a thread of their own, and
+    /// each side waits on its socket rather than polling it, so a request
+    /// is answered as soon as it arrives.  A client that sends something
+    /// we can't handle is dropped, or for UDP just not answered.
+    pub fn serve(&self, bridge: &Bridge) -> Result<(), EthServerError> {
+        thread::scope(|scope| {
+            scope.spawn(|| {
+                if let Err(e) = self.serve_udp(bridge) {
+                    ui_error!("Etherbone over UDP stopped: {}", e);
+                }
+            });
+            loop {
+                let (connection, sockaddr) = self.listener.accept()?;
+                log_adapter!("Etherbone client connected from {}", sockaddr);
+                if let Err(e) = self.serve_tcp(bridge, connection) {
+                    log_adapter!("Etherbone client dropped: {}", e);
+                }
+            }
+        })
+    }
 
-        if self.connection.is_none() {
-            return Err(WishboneServerError::ConnectionClosed);
+    /// Answer each whole packet the TCP client sends until it goes away
+    fn serve_tcp(&self, bridge: &Bridge, mut connection: TcpStream) -> Result<(), EthServerError> {
+        connection.set_nodelay(self.nodelay)?;
+        // What's come in that doesn't make a whole packet yet
+        let mut pending = vec![];
+        let mut buffer = [0; 4096];
+        loop {
+            match connection.read(&mut buffer)? {
+                0 => return Err(EthServerError::ConnectionClosed),
+                len => pending.extend_from_slice(&buffer[..len]),
+            }
+            while let Some(len) = packet_length(&pending, self.data_width)? {
+                let packet: Vec<u8> = pending.drain(..len).collect();
+                if let Some(reply) = self.answer(bridge, &packet)? {
+                    connection.write_all(&reply)?;
+                }
+            }
         }
+    }
 
-        let connection = &mut self.connection.as_mut().unwrap();
-
-        // XXX Replace this with a BufReader for performance
-        while buffer_offset < buffer.len() {
-            let len = connection.read(&mut byte)?;
-            if len == 0 {
-                return Err(WishboneServerError::ConnectionClosed);
+    /// Answer UDP packets as they come in
+    fn serve_udp(&self, bridge: &Bridge) -> Result<(), EthServerError> {
+        let mut buffer = [0; 2048];
+        loop {
+            let (len, peer) = self.udp.recv_from(&mut buffer)?;
+            match self.answer(bridge, &buffer[..len]) {
+                Ok(Some(reply)) => {
+                    self.udp.send_to(&reply, peer)?;
+                }
+                Ok(None) => (),
+                Err(e) => log_adapter!("Etherbone packet from {} ignored: {}", peer, e),
             }
-            buffer[buffer_offset] = byte[0];
-            buffer_offset = buffer_offset + 1;
         }
+    }
+
+    /// Carry out one packet, and return the reply to send back if it
+    /// needs one
+    fn answer(&self, bridge: &Bridge, packet: &[u8]) -> Result<Option<Vec<u8>>, EthServerError> {
+        let (data_width, big_endian) = (self.data_width, self.big_endian);
+        if packet.len() < HEADER_LENGTH {
+            return Err(EthServerError::Truncated);
+        }
+        let header = &packet[..HEADER_LENGTH];
 
         // Validate signature matches
-        if buffer[0] != 0x4e || buffer[1] != 0x6f {
-            return Err(WishboneServerError::NoMagic);
+        if header[0] != 0x4e || header[1] != 0x6f {
+            return Err(EthServerError::NoMagic);
         }
 
-        // Figure out if it's a read or a write
-        if buffer[10] == 1 {
-            // Write
-            let mut addr_vec = Cursor::new(vec![buffer[12], buffer[13], buffer[14], buffer[15]]);
-            let addr = addr_vec.read_u32::<BigEndian>()?;
+        // Say what we support
+        if header[2] & FLAG_PROBE != 0 {
+            let sizes = if data_width == 8 { SIZE_32 | SIZE_64 } else { SIZE_32 };
+            return Ok(Some(vec![
+                0x4e,
+                0x6f,
+                0x10 | FLAG_PROBE_REPLY,
+                (SIZE_32 << 4) | sizes,
+                data_width as u8,
+                big_endian as u8,
+                0,
+                0,
+            ]));
+        }
 
-            let mut value_vec = Cursor::new(vec![buffer[16], buffer[17], buffer[18], buffer[19]]);
-            let value = value_vec.read_u32::<BigEndian>()?;
+        // Every field after the record header is as wide as the data
+        let width = field_width(header[3], data_width)?;
+        if packet.len() < HEADER_LENGTH + RECORD_LENGTH {
+            return Err(EthServerError::Truncated);
+        }
+        let record = &packet[HEADER_LENGTH..HEADER_LENGTH + RECORD_LENGTH];
+        let (writes, reads) = (record[2] as usize, record[3] as usize);
+        if writes == 0 && reads == 0 {
+            return Err(EthServerError::UnsupportedOperation);
+        }
+        let fields = &packet[HEADER_LENGTH + RECORD_LENGTH..];
+        if fields.len() < field_count(writes, reads) * width {
+            return Err(EthServerError::Truncated);
+        }
+        let field = |idx: usize| read_field(&fields[idx * width..(idx + 1) * width]);
 
-            bridge.poke(addr, value)?;
-            Ok(())
+        // Writes and reads all go out in one batch, with the writes first
+        let mut batch = bridge.batch();
+        if writes > 0 {
+            let base = field(0) as u32;
+            for idx in 0..writes {
+                let addr = if record[0] & FLAG_WFF != 0 {
+                    base
+                } else {
+                    base.wrapping_add((idx * width) as u32)
+                };
+                for (word_addr, shift) in words(addr, width, big_endian) {
+                    batch = batch.write(word_addr, (field(idx + 1) >> shift) as u32);
+                }
+            }
+        }
+        let first = if writes > 0 { writes + 1 } else { 0 };
+        let read_addrs: Vec<u32> = (0..reads).map(|idx| field(first + 1 + idx) as u32).collect();
+        for &addr in &read_addrs {
+            for (word_addr, _) in words(addr, width, big_endian) {
+                batch = batch.read(word_addr);
+            }
+        }
+        let _lock = match (read_addrs.iter().min(), read_addrs.iter().max()) {
+            (Some(&low), Some(&high)) => {
+                lock::read_flash(low, (high - low).saturating_add(width as u32))?
+            }
+            _ => None,
+        };
+        let values = batch.commit()?;
+        if reads == 0 {
+            return Ok(None);
         }
-        else if buffer[11] == 1 {
-            let mut addr_vec = Cursor::new(vec![buffer[16], buffer[17], buffer[18], buffer[19]]);
-            let addr = addr_vec.read_u32::<BigEndian>()?;
-            // Read
-            let value = bridge.peek(addr)?;
-            let mut value_vec = vec![];
-            value_vec.write_u32::<BigEndian>(value)?;
-            buffer[16] = value_vec[0];
-            buffer[17] = value_vec[1];
-            buffer[18] = value_vec[2];
-            buffer[19] = value_vec[3];
-            connection.write(&buffer)?;
-            Ok(())
+
+        // The reply writes the values to the return address
+        let mut reply = header.to_vec();
+        reply.extend_from_slice(&[0, record[1], reads as u8, 0]);
+        reply.extend_from_slice(&fields[first * width..(first + 1) * width]);
+        let words_per_value = width / 4;
+        for (&addr, chunk) in read_addrs.iter().zip(values.chunks(words_per_value)) {
+            let value = words(addr, width, big_endian)
+                .iter()
+                .zip(chunk)
+                .fold(0, |acc, (&(_, shift), &word)| acc | (word as u64) << shift);
+            let mut bytes = [0; 8];
+            BigEndian::write_u64(&mut bytes, value);
+            reply.extend_from_slice(&bytes[8 - width..]);
         }
-        else {
-            return Err(WishboneServerError::UnsupportedOperation);
+        Ok(Some(reply))
+    }
+}
+
+/// How long the packet at the start of `data` is, or `None` if it hasn't
+/// all arrived.  A probe is just the header.
+fn packet_length(data: &[u8], data_width: usize) -> Result<Option<usize>, EthServerError> {
+    if data.len() < HEADER_LENGTH {
+        return Ok(None);
+    }
+    if data[2] & FLAG_PROBE != 0 {
+        return Ok(Some(HEADER_LENGTH));
+    }
+    let width = field_width(data[3], data_width)?;
+    if data.len() < HEADER_LENGTH + RECORD_LENGTH {
+        return Ok(None);
+    }
+    let record = &data[HEADER_LENGTH..];
+    let len = HEADER_LENGTH + RECORD_LENGTH + field_count(record[2] as usize, record[3] as usize) * width;
+    Ok(if data.len() < len { None } else { Some(len) })
+}
+
+fn field_width(sizes: u8, data_width: usize) -> Result<usize, EthServerError> {
+    match sizes {
+        0x44 => Ok(4),
+        0x48 if data_width == 8 => Ok(8),
+        other => Err(EthServerError::UnsupportedSize(other)),
+    }
+}
+
+/// The fields after the record header: a base address and the values
+/// for writes, then a return address and the addresses for reads
+fn field_count(writes: usize, reads: usize) -> usize {
+    writes.min(1) + writes + reads.min(1) + reads
+}
+
+/// Fields go over the wire big-endian, whatever the bus is
+fn read_field(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, &b| (acc << 8) | b as u64)
+}
+
+/// Split a value into the bridge's 32-bit words, in the order the bus
+/// keeps them
+fn words(addr: u32, width: usize, big_endian: bool) -> Vec<(u32, u32)> {
+    match (width, big_endian) {
+        (8, false) => vec![(addr, 0), (addr.wrapping_add(4), 32)],
+        (8, true) => vec![(addr, 32), (addr.wrapping_add(4), 0)],
+        _ => vec![(addr, 0)],
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::EthServer;
+    use crate::bridge::Bridge;
+    use crate::config::Config;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream, UdpSocket};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// A server on a port of its own in front of a mock bridge
+    fn server() -> SocketAddr {
+        let cfg = Config::from_args([
+            "wishbone",
+            "--mock",
+            "--bind-addr",
+            "127.0.0.1",
+            "--port",
+            "0",
+        ])
+        .unwrap();
+        let server = EthServer::new(&cfg).unwrap();
+        let addr = server.listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let bridge = Bridge::new(&cfg).unwrap();
+            bridge.connect().unwrap();
+            server.serve(&bridge)
+        });
+        addr
+    }
+
+    fn write(addr: u32, value: u32) -> Vec<u8> {
+        let mut packet = vec![0x4e, 0x6f, 0x10, 0x44, 0, 0, 0, 0, 0, 0x0f, 1, 0];
+        packet.extend_from_slice(&addr.to_be_bytes());
+        packet.extend_from_slice(&value.to_be_bytes());
+        packet
+    }
+
+    fn read(addr: u32) -> Vec<u8> {
+        let mut packet = vec![0x4e, 0x6f, 0x10, 0x44, 0, 0, 0, 0, 0, 0x0f, 0, 1];
+        packet.extend_from_slice(&0x1234u32.to_be_bytes());
+        packet.extend_from_slice(&addr.to_be_bytes());
+        packet
+    }
+
+    /// The value in a reply to a single read
+    fn value(reply: &[u8]) -> u32 {
+        assert_eq!(reply.len(), 20);
+        // One write, to the return address
+        assert_eq!(reply[10..12], [1, 0]);
+        assert_eq!(reply[12..16], 0x1234u32.to_be_bytes());
+        u32::from_be_bytes([reply[16], reply[17], reply[18], reply[19]])
+    }
+
+    #[test]
+    fn tcp_round_trips() {
+        let mut client = TcpStream::connect(server()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // A write and a read sent together, split mid-packet
+        let mut packets = write(0x4000_0000, 0xdead_beef);
+        packets.extend(read(0x4000_0000));
+        client.write_all(&packets[..15]).unwrap();
+        client.write_all(&packets[15..]).unwrap();
+        let mut reply = [0; 20];
+        client.read_exact(&mut reply).unwrap();
+        assert_eq!(value(&reply), 0xdead_beef);
+
+        // Each answer comes back as soon as it's ready, not on the next
+        // poll of the sockets
+        let start = Instant::now();
+        for _ in 0..50 {
+            client.write_all(&read(0x4000_0000)).unwrap();
+            client.read_exact(&mut reply).unwrap();
         }
+        assert!(
+            start.elapsed() < Duration::from_millis(250),
+            "{:?}",
+            start.elapsed()
+        );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn udp_alongside_tcp() {
+        let addr = server();
+        // A TCP client sitting idle doesn't hold up UDP ones
+        let _idle = TcpStream::connect(addr).unwrap();
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut reply = [0; 64];
+        client
+            .send_to(&[0x4e, 0x6f, 0x11, 0x44, 0, 0, 0, 0], addr)
+            .unwrap();
+        let len = client.recv(&mut reply).unwrap();
+        assert_eq!(reply[..len], [0x4e, 0x6f, 0x12, 0x44, 4, 0, 0, 0]);
+
+        client.send_to(&write(0x4000_0010, 7), addr).unwrap();
+        client.send_to(&read(0x4000_0010), addr).unwrap();
+        let len = client.recv(&mut reply).unwrap();
+        assert_eq!(value(&reply[..len]), 7);
+    }
+}
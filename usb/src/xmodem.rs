@@ -0,0 +1,520 @@
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use super::bridge::{Bridge, BridgeError};
+use super::terminal::CrossoverUart;
+
+const SOH: u8 = 0x01;
+const STX: u8 = 0x02;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1a;
+
+/// Sent by a receiver that wants 16-bit CRCs rather than checksums
+const CRC_REQUEST: u8 = b'C';
+
+/// How long a receiver gets to ask for the first block.  People start the
+/// sender first and then go and type a command on the target.
+const START_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long to wait for a block to be acknowledged, or for the next block
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long to wait between bytes within a block
+const BYTE_TIMEOUT: Duration = Duration::from_secs(1);
+
+const MAX_RETRIES: u32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    /// One file, 128-byte blocks, no name or size
+    Xmodem,
+
+    /// A block 0 carrying the name and size, then 1K blocks
+    Ymodem,
+}
+
+#[derive(Debug)]
+pub enum TransferError {
+    /// The bridge failed while talking to the UART
+    BridgeError(BridgeError),
+
+    /// Couldn't read or write the local file
+    IoError(io::Error),
+
+    /// The other side never answered
+    Timeout,
+
+    /// The other side cancelled the transfer
+    Cancelled,
+
+    /// Blocks kept getting corrupted or rejected
+    TooManyRetries,
+
+    /// Blocks arrived out of order
+    Sequence(u8 /* expected */, u8 /* got */),
+
+    /// The file's name and size don't fit in YMODEM's block 0
+    NameTooLong(String),
+}
+
+impl std::convert::From<BridgeError> for TransferError {
+    fn from(e: BridgeError) -> Self {
+        TransferError::BridgeError(e)
+    }
+}
+
+impl std::convert::From<io::Error> for TransferError {
+    fn from(e: io::Error) -> Self {
+        TransferError::IoError(e)
+    }
+}
+
+impl fmt::Display for TransferError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TransferError::BridgeError(e) => write!(f, "bridge error: {:?}", e),
+            TransferError::IoError(e) => write!(f, "{}", e),
+            TransferError::Timeout => write!(f, "the other side stopped responding"),
+            TransferError::Cancelled => write!(f, "cancelled by the other side"),
+            TransferError::TooManyRetries => write!(f, "too many retries"),
+            TransferError::Sequence(expected, got) => {
+                write!(f, "expected block {}, got block {}", expected, got)
+            }
+            TransferError::NameTooLong(name) => {
+                write!(f, "the name {} is too long to send with YMODEM", name)
+            }
+        }
+    }
+}
+
+/// The XMODEM CRC-16 (CCITT polynomial, starting from zero)
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0, |crc, &byte| {
+        let mut crc = crc ^ ((byte as u16) << 8);
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+        crc
+    })
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, &b| sum.wrapping_add(b))
+}
+
+/// Send one file.  YMODEM also sends its name and size, and ends the batch
+/// afterwards.
+pub fn send(
+    uart: &CrossoverUart,
+    bridge: &Bridge,
+    protocol: Protocol,
+    name: &str,
+    data: &[u8],
+) -> Result<(), TransferError> {
+    let block_size = match protocol {
+        Protocol::Xmodem => 128,
+        Protocol::Ymodem => 1024,
+    };
+    // Block 0 holds the name and size, and is never a 1K block
+    let header = format!("{}\0{}\0", name, data.len());
+    if protocol == Protocol::Ymodem && header.len() > 128 {
+        return Err(TransferError::NameTooLong(name.to_owned()));
+    }
+    let mut crc = wait_for_receiver(uart, bridge)?;
+
+    if protocol == Protocol::Ymodem {
+        send_block(uart, bridge, 0, header.as_bytes(), 128, 0, crc)?;
+        // The receiver asks again before the data starts
+        crc = wait_for_receiver(uart, bridge)?;
+    }
+
+    for (idx, chunk) in data.chunks(block_size).enumerate() {
+        let num = (idx + 1) as u8;
+        send_block(uart, bridge, num, chunk, block_size, SUB, crc)?;
+    }
+    send_eot(uart, bridge)?;
+
+    if protocol == Protocol::Ymodem {
+        // An empty block 0 says there are no more files
+        crc = wait_for_receiver(uart, bridge)?;
+        send_block(uart, bridge, 0, &[], 128, 0, crc)?;
+    }
+    Ok(())
+}
+
+/// Wait for the receiver to ask for data.  Returns whether it wants CRCs.
+fn wait_for_receiver(uart: &CrossoverUart, bridge: &Bridge) -> Result<bool, TransferError> {
+    loop {
+        match uart.read_timeout(bridge, START_TIMEOUT)? {
+            Some(CRC_REQUEST) => return Ok(true),
+            Some(NAK) => return Ok(false),
+            Some(CAN) => return Err(TransferError::Cancelled),
+            Some(_) => (),
+            None => return Err(TransferError::Timeout),
+        }
+    }
+}
+
+/// Send a block, padding it out to `size` bytes, until it's acknowledged
+fn send_block(
+    uart: &CrossoverUart,
+    bridge: &Bridge,
+    num: u8,
+    data: &[u8],
+    size: usize,
+    pad: u8,
+    crc: bool,
+) -> Result<(), TransferError> {
+    let mut payload = data.to_vec();
+    payload.resize(size, pad);
+
+    let mut packet = vec![if size == 1024 { STX } else { SOH }, num, !num];
+    packet.extend_from_slice(&payload);
+    if crc {
+        packet.extend_from_slice(&crc16(&payload).to_be_bytes());
+    } else {
+        packet.push(checksum(&payload));
+    }
+
+    for _ in 0..MAX_RETRIES {
+        uart.write(bridge, &packet)?;
+        match uart.read_timeout(bridge, BLOCK_TIMEOUT)? {
+            Some(ACK) => return Ok(()),
+            Some(CAN) => return Err(TransferError::Cancelled),
+            _ => (),
+        }
+    }
+    Err(TransferError::TooManyRetries)
+}
+
+/// Say the file is finished.  YMODEM receivers NAK the first EOT to make
+/// sure it wasn't line noise.
+fn send_eot(uart: &CrossoverUart, bridge: &Bridge) -> Result<(), TransferError> {
+    for _ in 0..MAX_RETRIES {
+        uart.write(bridge, &[EOT])?;
+        match uart.read_timeout(bridge, BLOCK_TIMEOUT)? {
+            Some(ACK) => return Ok(()),
+            Some(CAN) => return Err(TransferError::Cancelled),
+            _ => (),
+        }
+    }
+    Err(TransferError::TooManyRetries)
+}
+
+/// What came in when waiting for a block
+enum Packet {
+    Block(u8, Vec<u8>),
+    Eot,
+    Cancel,
+
+    /// Nothing arrived in time
+    Silence,
+
+    /// Something arrived, but it was damaged
+    Corrupt,
+}
+
+/// Read a block, always using CRCs
+fn read_packet(uart: &CrossoverUart, bridge: &Bridge) -> Result<Packet, TransferError> {
+    let size = match uart.read_timeout(bridge, BLOCK_TIMEOUT)? {
+        Some(SOH) => 128,
+        Some(STX) => 1024,
+        Some(EOT) => return Ok(Packet::Eot),
+        Some(CAN) => return Ok(Packet::Cancel),
+        Some(_) => return Ok(Packet::Corrupt),
+        None => return Ok(Packet::Silence),
+    };
+    // Block number, its complement, the data and the CRC
+    let mut rest = Vec::with_capacity(size + 4);
+    while rest.len() < size + 4 {
+        match uart.read_timeout(bridge, BYTE_TIMEOUT)? {
+            Some(b) => rest.push(b),
+            None => return Ok(Packet::Corrupt),
+        }
+    }
+    let (num, inverse) = (rest[0], rest[1]);
+    let payload = &rest[2..2 + size];
+    let crc = u16::from_be_bytes([rest[size + 2], rest[size + 3]]);
+    if num != !inverse || crc != crc16(payload) {
+        return Ok(Packet::Corrupt);
+    }
+    Ok(Packet::Block(num, payload.to_vec()))
+}
+
+/// Receive one file.  YMODEM also reports the file's name, and trims the
+/// data to the size the sender gave.  XMODEM has no size, so trailing
+/// padding is removed instead.
+pub fn receive(
+    uart: &CrossoverUart,
+    bridge: &Bridge,
+    protocol: Protocol,
+) -> Result<(Option<String>, Vec<u8>), TransferError> {
+    let mut name = None;
+    let mut size = None;
+
+    if protocol == Protocol::Ymodem {
+        let header = receive_header(uart, bridge)?;
+        let mut fields = header.split(|&b| b == 0);
+        name = fields
+            .next()
+            .map(|n| String::from_utf8_lossy(n).into_owned())
+            .filter(|n| !n.is_empty());
+        size = fields
+            .next()
+            .and_then(|s| {
+                String::from_utf8_lossy(s)
+                    .split(' ')
+                    .next()
+                    .map(str::to_owned)
+            })
+            .and_then(|s| s.parse::<usize>().ok());
+    }
+
+    let mut data = receive_blocks(uart, bridge, protocol)?;
+    match size {
+        Some(size) => data.truncate(size),
+        None => {
+            while data.last() == Some(&SUB) {
+                data.pop();
+            }
+        }
+    }
+
+    if protocol == Protocol::Ymodem {
+        // Accept the empty block 0 that ends the batch
+        receive_header(uart, bridge)?;
+    }
+    Ok((name, data))
+}
+
+/// Ask for and acknowledge a YMODEM block 0
+fn receive_header(uart: &CrossoverUart, bridge: &Bridge) -> Result<Vec<u8>, TransferError> {
+    for _ in 0..MAX_RETRIES {
+        uart.write(bridge, &[CRC_REQUEST])?;
+        match read_packet(uart, bridge)? {
+            Packet::Block(0, payload) => {
+                uart.write(bridge, &[ACK])?;
+                return Ok(payload);
+            }
+            Packet::Block(num, _) => return Err(TransferError::Sequence(0, num)),
+            Packet::Cancel => return Err(TransferError::Cancelled),
+            Packet::Eot | Packet::Silence | Packet::Corrupt => (),
+        }
+    }
+    Err(TransferError::TooManyRetries)
+}
+
+/// Ask for and acknowledge data blocks until the sender says it's done
+fn receive_blocks(
+    uart: &CrossoverUart,
+    bridge: &Bridge,
+    protocol: Protocol,
+) -> Result<Vec<u8>, TransferError> {
+    let mut data = vec![];
+    let mut expected: u8 = 1;
+    let mut errors = 0;
+    let mut seen_eot = false;
+
+    uart.write(bridge, &[CRC_REQUEST])?;
+    loop {
+        let reply = match read_packet(uart, bridge)? {
+            Packet::Block(num, payload) if num == expected => {
+                data.extend_from_slice(&payload);
+                expected = expected.wrapping_add(1);
+                errors = 0;
+                ACK
+            }
+            // Our ACK got lost and the sender tried again
+            Packet::Block(num, _) if num == expected.wrapping_sub(1) => ACK,
+            Packet::Block(num, _) => {
+                uart.write(bridge, &[CAN, CAN])?;
+                return Err(TransferError::Sequence(expected, num));
+            }
+            Packet::Eot if protocol == Protocol::Ymodem && !seen_eot => {
+                seen_eot = true;
+                NAK
+            }
+            Packet::Eot => {
+                uart.write(bridge, &[ACK])?;
+                return Ok(data);
+            }
+            Packet::Cancel => return Err(TransferError::Cancelled),
+            Packet::Silence | Packet::Corrupt => {
+                errors += 1;
+                if errors > MAX_RETRIES {
+                    uart.write(bridge, &[CAN, CAN])?;
+                    return Err(TransferError::TooManyRetries);
+                }
+                // Keep asking for CRCs until the first block turns up
+                if data.is_empty() && expected == 1 {
+                    CRC_REQUEST
+                } else {
+                    NAK
+                }
+            }
+        };
+        uart.write(bridge, &[reply])?;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::{HashMap, VecDeque};
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    use super::{crc16, receive, send, Protocol, TransferError};
+    use crate::bridge::Bridge;
+    use crate::config::Config;
+    use crate::csr_map::CsrMap;
+    use crate::mock_bridge::Device;
+    use crate::terminal::CrossoverUart;
+
+    /// Where each end's registers are: rxtx, then txfull, then rxempty
+    const ENDS: [u32; 2] = [0xf000_0000, 0xf000_1000];
+
+    /// Two UARTs wired to each other, which can damage chosen bytes on the
+    /// way across
+    struct Crossover {
+        queues: [VecDeque<u8>; 2],
+        written: Arc<Mutex<[usize; 2]>>,
+
+        /// The end, the number of the byte it writes, and what arrives
+        /// instead
+        damage: Vec<(usize, usize, u8)>,
+    }
+
+    impl Device for Crossover {
+        fn read(&mut self, _: &mut HashMap<u32, u32>, addr: u32) -> Option<u32> {
+            let end = ENDS.iter().position(|&base| addr & !0xff == base)?;
+            Some(match addr & 0xff {
+                0 => self.queues[end].pop_front().unwrap_or(0) as u32,
+                4 => 0,
+                _ => self.queues[end].is_empty() as u32,
+            })
+        }
+
+        fn write(&mut self, _: &mut HashMap<u32, u32>, addr: u32, value: u32) -> bool {
+            let end = match ENDS.iter().position(|&base| addr == base) {
+                Some(end) => end,
+                None => return false,
+            };
+            let count = self.written.lock().unwrap()[end];
+            self.written.lock().unwrap()[end] += 1;
+            let byte = self
+                .damage
+                .iter()
+                .find(|&&(e, n, _)| e == end && n == count)
+                .map_or(value as u8, |&(_, _, b)| b);
+            self.queues[1 - end].push_back(byte);
+            true
+        }
+    }
+
+    fn uart(end: usize) -> CrossoverUart {
+        let base = ENDS[end];
+        let map = CsrMap::parse(&format!(
+            "csr_register,uart_xover_rxtx,{:#x},1,rw\n\
+             csr_register,uart_xover_txfull,{:#x},1,ro\n\
+             csr_register,uart_xover_rxempty,{:#x},1,ro\n",
+            base,
+            base + 4,
+            base + 8
+        ))
+        .unwrap();
+        CrossoverUart::new(&map).unwrap()
+    }
+
+    /// Send `data` from one end and receive it at the other, returning what
+    /// arrived and how many bytes the sender wrote
+    fn transfer(
+        protocol: Protocol,
+        data: &[u8],
+        damage: Vec<(usize, usize, u8)>,
+    ) -> (Option<String>, Vec<u8>, usize) {
+        let written = Arc::new(Mutex::new([0; 2]));
+        let cfg = Config::from_args(["xmodem", "--mock"]).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        bridge.connect().unwrap();
+        bridge.attach(Box::new(Crossover {
+            queues: Default::default(),
+            written: written.clone(),
+            damage,
+        }));
+        let bridge = &bridge;
+        thread::scope(|scope| {
+            let sender = scope.spawn(move || send(&uart(0), bridge, protocol, "boot.bin", data));
+            let (name, received) = receive(&uart(1), bridge, protocol).unwrap();
+            sender.join().unwrap().unwrap();
+            (name, received, written.lock().unwrap()[0])
+        })
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(crc16(b""), 0);
+    }
+
+    #[test]
+    fn round_trips() {
+        let data: Vec<u8> = (0..3000u32).map(|i| (i * 7) as u8).collect();
+        let (name, received, sent) = transfer(Protocol::Xmodem, &data, vec![]);
+        assert_eq!((name, received), (None, data.clone()));
+        // 24 blocks and an EOT
+        assert_eq!(sent, 24 * 133 + 1);
+
+        // YMODEM trims to the size it was told, so trailing padding bytes
+        // in the file survive
+        let mut padded = data.clone();
+        padded.extend_from_slice(&[0x1a; 5]);
+        let (name, received, sent) = transfer(Protocol::Ymodem, &padded, vec![]);
+        assert_eq!((name.as_deref(), received), (Some("boot.bin"), padded));
+        // Block 0, three 1K blocks, an EOT that's turned away, another EOT
+        // and the empty block 0 that ends the batch
+        assert_eq!(sent, 133 + 3 * 1029 + 2 + 133);
+    }
+
+    #[test]
+    fn damage_is_recovered_from() {
+        let data: Vec<u8> = (0..1500u32).map(|i| (i * 3) as u8).collect();
+        // The sender writes a byte of block 1 wrong, so the receiver asks
+        // for it again
+        let clean = 12 * 133 + 1;
+        let (_, received, sent) = transfer(Protocol::Xmodem, &data, vec![(0, 10, 0xff)]);
+        assert_eq!((received.as_slice(), sent), (&data[..], clean + 133));
+        // The receiver's ACK for block 1 is garbled, so the sender repeats
+        // a block the receiver already has
+        let (_, received, sent) = transfer(Protocol::Xmodem, &data, vec![(1, 1, 0)]);
+        assert_eq!((received.as_slice(), sent), (&data[..], clean + 133));
+
+        // The same for YMODEM, whose block 0 is damaged too.  Block 0 goes
+        // twice, and the receiver asks for it twice and for the data once
+        // before it answers block 1.
+        let clean = 133 + 2 * 1029 + 2 + 133;
+        let damage = vec![(0, 20, 0xff), (0, 2 * 133 + 40, 0xff), (1, 5, 0)];
+        let (name, received, sent) = transfer(Protocol::Ymodem, &data, damage);
+        assert_eq!((name.as_deref(), received), (Some("boot.bin"), data));
+        assert_eq!(sent, clean + 133 + 2 * 1029);
+    }
+
+    #[test]
+    fn long_names_are_refused() {
+        let cfg = Config::from_args(["xmodem", "--mock"]).unwrap();
+        let bridge = Bridge::new(&cfg).unwrap();
+        // With the size, it would take 129 bytes of block 0
+        let name = "x".repeat(125);
+        match send(&uart(0), &bridge, Protocol::Ymodem, &name, &[0; 10]) {
+            Err(TransferError::NameTooLong(n)) => assert_eq!(n, name),
+            other => panic!("{:?}", other),
+        }
+    }
+}